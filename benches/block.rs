@@ -0,0 +1,44 @@
+//! point lookups in a single data block as the number of restart points grows
+//! - restart_interval 1 gives a restart per entry, the case a linear scan over
+//!   restarts handled worst
+//! - run with `cargo bench --bench block`
+
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+use kvstore::lsm::sstable::block::{Block, BlockBuilder};
+
+fn build_block(restart_interval: usize) -> (Block, Vec<Vec<u8>>) {
+    let mut builder = BlockBuilder::with_restart_interval(restart_interval);
+    let mut keys = Vec::new();
+    for i in 0.. {
+        let key = format!("key{:06}", i).into_bytes();
+        if !builder.add(&key, b"value").unwrap() {
+            break;
+        }
+        keys.push(key);
+    }
+    (builder.finish(), keys)
+}
+
+fn bench_block_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_get");
+    for restart_interval in [1, 4, 16] {
+        let (block, keys) = build_block(restart_interval);
+        let probes: Vec<&[u8]> = keys.iter().step_by(7).map(|k| k.as_slice()).collect();
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(restart_interval),
+            &probes,
+            |b, probes| {
+                b.iter(|| {
+                    for key in probes {
+                        black_box(block.get(black_box(key)).unwrap());
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_block_get);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! the same writes, reads and scans through KvEngine on each engine
+//! - a fresh store per engine in the temp directory, removed afterwards
+//! - run with `cargo bench --bench engines`
+
+use std::env;
+use std::fs;
+use std::ops::Bound;
+
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+use kvstore::info::EngineKind;
+use kvstore::{EngineOptions, KvEngine, engine};
+
+const KEYS: u32 = 10_000;
+
+fn key(i: u32) -> Vec<u8> {
+    format!("key{:08}", i.wrapping_mul(2_654_435_761) % KEYS).into_bytes()
+}
+
+fn bench_engines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engines");
+    group.sample_size(10);
+    for kind in [EngineKind::Lsm, EngineKind::Page] {
+        let path = env::temp_dir().join(format!("bench_engine_{:?}", kind));
+        fs::remove_dir_all(&path).ok();
+        fs::remove_file(&path).ok();
+        let options = EngineOptions::default().with_engine(kind);
+        let db: Box<dyn KvEngine> = engine::open(&path, options).unwrap();
+
+        group.bench_function(BenchmarkId::new("put", format!("{:?}", kind)), |b| {
+            b.iter(|| {
+                for i in 0..KEYS {
+                    db.put(&key(i), &[7; 100]).unwrap();
+                }
+                db.flush().unwrap();
+            })
+        });
+        group.bench_function(BenchmarkId::new("get", format!("{:?}", kind)), |b| {
+            b.iter(|| {
+                for i in 0..KEYS {
+                    black_box(db.get(&key(i)).unwrap());
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("scan", format!("{:?}", kind)), |b| {
+            b.iter(|| {
+                let range = db.range(Bound::Unbounded, Bound::Unbounded).unwrap();
+                black_box(range.count())
+            })
+        });
+
+        db.close().unwrap();
+        fs::remove_dir_all(&path).ok();
+        fs::remove_file(&path).ok();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_engines);
+criterion_main!(benches);
@@ -0,0 +1,92 @@
+//! Operator tool for inspecting a database on disk
+//!
+//! usage: kvctl manifest-diff [--json] <old> <new>
+//!        kvctl check [--json] <page file>
+//! - <old> and <new> are manifest files or database directories, e.g. a
+//!   copy of the manifest taken before a compaction and the live database
+//! - for a directory the manifest CURRENT names is read, or the older
+//!   MANIFEST / MANIFEST.json of databases not opened since
+//! - check verifies the B-tree of a page file as of its last commit and
+//!   fails if it finds problems or leaked pages
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use kvstore::lsm::Manifest;
+use kvstore::lsm::version_edit::current_manifest;
+use kvstore::storage::BTree;
+
+const USAGE: &str = "usage: kvctl manifest-diff [--json] <old> <new>
+       kvctl check [--json] <page file>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("manifest-diff") => manifest_diff(&args[1..]),
+        Some("check") => check(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn manifest_diff(args: &[String]) -> Result<(), String> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--json").collect();
+    let [old, new] = paths[..] else {
+        return Err(USAGE.to_string());
+    };
+
+    let diff = load(Path::new(old))?.diff(&load(Path::new(new))?);
+    if json {
+        println!("{}", diff.to_json());
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
+}
+
+fn check(args: &[String]) -> Result<(), String> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--json").collect();
+    let [path] = paths[..] else {
+        return Err(USAGE.to_string());
+    };
+    if !Path::new(path).is_file() {
+        return Err(format!("{}: not a file", path));
+    }
+
+    // leaves a writer's uncommitted pages alone
+    let mut tree = BTree::open_read_only(path).map_err(|e| format!("{}: {}", path, e))?;
+    let report = tree.check();
+    if json {
+        println!("{}", report.to_json());
+    } else {
+        print!("{}", report);
+    }
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(format!("{}: check failed", path))
+    }
+}
+
+fn load(path: &Path) -> Result<Manifest, String> {
+    let file = if path.is_dir() {
+        match current_manifest(path).map_err(|e| format!("{}: {}", path.display(), e))? {
+            Some(current) => current,
+            None if path.join("MANIFEST").exists() => path.join("MANIFEST"),
+            None => path.join("MANIFEST.json"),
+        }
+    } else {
+        PathBuf::from(path)
+    };
+    Manifest::load(&file).map_err(|e| format!("{}: {}", file.display(), e))
+}
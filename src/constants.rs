@@ -1,2 +1,2 @@
-pub const PAGE_SIZE: usize = 4096;
-pub const BLOCK_SIZE: usize = PAGE_SIZE;
\ No newline at end of file
+// moved to `format`; kept so existing paths keep working
+pub use crate::format::{BLOCK_SIZE, PAGE_SIZE};
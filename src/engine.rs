@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::info::EngineKind;
+use crate::lsm::{DB, DbError, LSMConfig};
+use crate::storage::bufferpool::DEFAULT_POOL_PAGES;
+use crate::storage::{BTree, BTreeError};
+
+/// entries a page engine range reads per lock of the tree
+const RANGE_BATCH: usize = 128;
+
+/// KvEngine: the operations both engines share, so the same code can run
+/// against either
+///    - methods take &self; the page engine serializes them on a lock
+///    - writes are durable once flush or close returns; before that the
+///      LSM engine recovers them from its WAL, the page engine loses them
+///    - range yields live key-value pairs in key order
+pub trait KvEngine: Send + Sync {
+    fn kind(&self) -> EngineKind;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn delete(&self, key: &[u8]) -> Result<()>;
+
+    fn range<'a>(&'a self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<Range<'a>>;
+
+    fn flush(&self) -> Result<()>;
+
+    fn close(self: Box<Self>) -> Result<()>;
+}
+
+pub type Range<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// which engine open() creates, and its settings
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    pub engine: EngineKind,
+
+    /// used by the LSM engine
+    pub lsm: LSMConfig,
+
+    /// buffer pool frames of the page engine
+    pub pool_pages: usize,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            engine: EngineKind::Lsm,
+            lsm: LSMConfig::default(),
+            pool_pages: DEFAULT_POOL_PAGES,
+        }
+    }
+}
+
+impl EngineOptions {
+    pub fn with_engine(mut self, engine: EngineKind) -> Self {
+        self.engine = engine;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum EngineError {
+    Lsm(DbError),
+    Page(BTreeError),
+}
+
+impl From<DbError> for EngineError {
+    fn from(err: DbError) -> Self {
+        EngineError::Lsm(err)
+    }
+}
+
+impl From<BTreeError> for EngineError {
+    fn from(err: BTreeError) -> Self {
+        EngineError::Page(err)
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::Lsm(e) => write!(f, "{}", e),
+            EngineError::Page(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+pub type Result<T> = std::result::Result<T, EngineError>;
+
+/// open the store at `path` with the engine `options` names: a directory
+/// for the LSM engine, a single file for the page engine
+pub fn open(path: impl AsRef<Path>, options: EngineOptions) -> Result<Box<dyn KvEngine>> {
+    Ok(match options.engine {
+        EngineKind::Lsm => Box::new(DB::open(path, options.lsm)?),
+        EngineKind::Page => Box::new(PageEngine::open(path, options.pool_pages)?),
+    })
+}
+
+impl KvEngine for DB {
+    fn kind(&self) -> EngineKind {
+        EngineKind::Lsm
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(DB::get(self, key)?)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        Ok(DB::put(self, key, value)?)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        Ok(DB::delete(self, key)?)
+    }
+
+    fn range<'a>(&'a self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<Range<'a>> {
+        let iter = DB::range::<&[u8]>(self, (lower, upper))?;
+        Ok(Box::new(iter.map(|entry| entry.map_err(EngineError::from))))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(DB::flush(self)?)
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        Ok(DB::close(*self)?)
+    }
+}
+
+/// PageEngine: a BTree behind KvEngine
+///    - flush commits the tree, and so do close and drop
+///    - range reads a batch of entries at a time, so it holds the lock only
+///      while reading and sees writes made between batches
+///    - buckets are left out; only top-level values are visible
+pub struct PageEngine {
+    tree: Mutex<BTree>,
+}
+
+impl PageEngine {
+    pub fn open(path: impl AsRef<Path>, pool_pages: usize) -> Result<Self> {
+        let tree = BTree::open_with_pool_pages(path, pool_pages)?;
+        Ok(Self {
+            tree: Mutex::new(tree),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTree> {
+        self.tree.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl KvEngine for PageEngine {
+    fn kind(&self) -> EngineKind {
+        EngineKind::Page
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.lock().get(key)?)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.lock().insert(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        self.lock().delete(key)?;
+        Ok(())
+    }
+
+    fn range<'a>(&'a self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<Range<'a>> {
+        Ok(Box::new(PageRange {
+            engine: self,
+            lower: lower.map(<[u8]>::to_vec),
+            upper: upper.map(<[u8]>::to_vec),
+            batch: VecDeque::new(),
+            done: false,
+        }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.lock().commit()?)
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        self.flush()
+    }
+}
+
+impl Drop for PageEngine {
+    fn drop(&mut self) {
+        let _ = self.lock().commit();
+    }
+}
+
+struct PageRange<'a> {
+    engine: &'a PageEngine,
+
+    /// where the next batch starts, past the last key read
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl PageRange<'_> {
+    fn read_batch(&mut self) -> Result<()> {
+        let mut tree = self.engine.lock();
+        let mut cursor = tree.cursor();
+        let mut valid = match &self.lower {
+            Bound::Included(key) | Bound::Excluded(key) => cursor.seek(key)?,
+            Bound::Unbounded => cursor.first()?,
+        };
+        if let Bound::Excluded(key) = &self.lower
+            && cursor.key() == Some(key.as_slice())
+        {
+            valid = cursor.next()?;
+        }
+
+        while valid && self.batch.len() < RANGE_BATCH {
+            let key = cursor.key().unwrap();
+            let below = match &self.upper {
+                Bound::Included(end) => key <= end.as_slice(),
+                Bound::Excluded(end) => key < end.as_slice(),
+                Bound::Unbounded => true,
+            };
+            if !below {
+                break;
+            }
+            if let Some(value) = cursor.value() {
+                self.batch.push_back((key.to_vec(), value.to_vec()));
+            }
+            self.lower = Bound::Excluded(key.to_vec());
+            valid = cursor.next()?;
+        }
+        self.done = self.batch.len() < RANGE_BATCH;
+        Ok(())
+    }
+}
+
+impl Iterator for PageRange<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty()
+            && !self.done
+            && let Err(e) = self.read_batch()
+        {
+            self.done = true;
+            return Some(Err(e));
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn exercise(engine: Box<dyn KvEngine>, path: &Path, options: EngineOptions) {
+        for i in 0..1000u32 {
+            let key = format!("key{:04}", i);
+            engine.put(key.as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        engine.delete(b"key0500").unwrap();
+        engine.put(b"key0001", b"one").unwrap();
+        assert_eq!(engine.get(b"key0001").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(engine.get(b"key0500").unwrap(), None);
+
+        let keys: Vec<Vec<u8>> = engine
+            .range(Bound::Excluded(b"key0100"), Bound::Included(b"key0700"))
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys.len(), 599);
+        assert_eq!(keys[0], b"key0101");
+        assert_eq!(keys.last().unwrap(), b"key0700");
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        engine.flush().unwrap();
+        engine.close().unwrap();
+
+        let engine = open(path, options).unwrap();
+        assert_eq!(
+            engine.get(b"key0999").unwrap(),
+            Some(999u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            engine
+                .range(Bound::Unbounded, Bound::Unbounded)
+                .unwrap()
+                .count(),
+            999
+        );
+        engine.close().unwrap();
+    }
+
+    #[test]
+    fn test_engines_behave_alike() {
+        let dir = env::temp_dir().join("test_engine_lsm");
+        fs::remove_dir_all(&dir).ok();
+        let options = EngineOptions::default();
+        let engine = open(&dir, options.clone()).unwrap();
+        assert_eq!(engine.kind(), EngineKind::Lsm);
+        exercise(engine, &dir, options);
+        fs::remove_dir_all(&dir).ok();
+
+        let file = env::temp_dir().join("test_engine_page.db");
+        fs::remove_file(&file).ok();
+        let options = EngineOptions::default().with_engine(EngineKind::Page);
+        let engine = open(&file, options.clone()).unwrap();
+        assert_eq!(engine.kind(), EngineKind::Page);
+        exercise(engine, &file, options);
+        fs::remove_file(&file).ok();
+    }
+}
@@ -0,0 +1,485 @@
+//! C API over DB, snapshots and iterators
+//! - every object crosses the boundary as an opaque u64 handle, never a
+//!   pointer; handles live in a process-wide registry and are never reused,
+//!   so a closed or made-up handle is an error code instead of undefined
+//!   behaviour
+//! - snapshot and iterator handles hold the DB they came from: kv_close only
+//!   drops the DB handle, and the database really closes once the last
+//!   snapshot and iterator on it are closed too
+//! - an iterator reads from tables loaded when it was created, so files
+//!   removed by a later compaction stay readable through it
+//! - buffers returned by kv_get belong to the caller and go back through
+//!   kv_free; key and value pointers from an iterator stay valid until the
+//!   next kv_iter_next or kv_iter_close on it
+//! - debug builds count live handles (kv_live_handles) so bindings can
+//!   assert nothing leaked in their tests
+
+use std::collections::BTreeMap;
+use std::ffi::{CStr, c_char};
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use crate::lsm::{DB, DbIterator, LSMConfig, ReadOptions, Snapshot};
+
+pub const KV_OK: i32 = 0;
+
+/// key absent, or iterator exhausted
+pub const KV_NOT_FOUND: i32 = 1;
+
+/// the database returned an error
+pub const KV_ERR_IO: i32 = -1;
+
+/// closed, unknown or wrong kind of handle
+pub const KV_ERR_INVALID_HANDLE: i32 = -2;
+
+/// null pointer or path that isn't UTF-8
+pub const KV_ERR_INVALID_ARGUMENT: i32 = -3;
+
+/// handle 0 is never issued; kv_get and kv_iter take it as "no snapshot"
+pub const KV_NO_SNAPSHOT: u64 = 0;
+
+static HANDLES: Mutex<Registry> = Mutex::new(Registry {
+    next: 1,
+    handles: BTreeMap::new(),
+});
+
+struct Registry {
+    next: u64,
+    handles: BTreeMap<u64, Handle>,
+}
+
+enum Handle {
+    Db(Arc<DB>),
+    Snapshot { db: Arc<DB>, snapshot: Snapshot },
+    Iter(Arc<Mutex<IterState>>),
+}
+
+/// key and value of one iterator entry
+type Entry = (Vec<u8>, Vec<u8>);
+
+struct IterState {
+    /// keeps the database open while the iterator is
+    _db: Arc<DB>,
+
+    iter: DbIterator,
+
+    /// entry the key and value pointers point into
+    current: Option<Entry>,
+}
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    HANDLES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn register(handle: Handle) -> u64 {
+    let mut registry = registry();
+    let id = registry.next;
+    registry.next += 1;
+    registry.handles.insert(id, handle);
+    id
+}
+
+fn db(handle: u64) -> Option<Arc<DB>> {
+    match registry().handles.get(&handle)? {
+        Handle::Db(db) => Some(Arc::clone(db)),
+        _ => None,
+    }
+}
+
+/// database and read options for `snapshot` on `handle`
+fn read_target(handle: u64, snapshot: u64) -> Option<(Arc<DB>, ReadOptions)> {
+    let db = db(handle)?;
+    if snapshot == KV_NO_SNAPSHOT {
+        return Some((db, ReadOptions::default()));
+    }
+    match registry().handles.get(&snapshot)? {
+        Handle::Snapshot {
+            db: owner,
+            snapshot,
+        } if Arc::ptr_eq(owner, &db) => {
+            Some((db, ReadOptions::new().with_snapshot(snapshot.clone())))
+        }
+        _ => None,
+    }
+}
+
+fn iter(handle: u64) -> Option<Arc<Mutex<IterState>>> {
+    match registry().handles.get(&handle)? {
+        Handle::Iter(state) => Some(Arc::clone(state)),
+        _ => None,
+    }
+}
+
+/// remove `handle` if `is_kind` accepts it
+fn close(handle: u64, is_kind: fn(&Handle) -> bool) -> i32 {
+    let mut registry = registry();
+    match registry.handles.get(&handle) {
+        Some(found) if is_kind(found) => {
+            // dropped after the registry lock is released
+            let removed = registry.handles.remove(&handle);
+            drop(registry);
+            drop(removed);
+            KV_OK
+        }
+        _ => KV_ERR_INVALID_HANDLE,
+    }
+}
+
+/// # Safety
+/// `ptr` must be null or point to `len` readable bytes
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    if ptr.is_null() {
+        return (len == 0).then_some(&[]);
+    }
+    // SAFETY: the caller guarantees `len` readable bytes at `ptr`
+    Some(unsafe { slice::from_raw_parts(ptr, len) })
+}
+
+/// open the database at `path` with the default config
+///
+/// # Safety
+/// `path` must be a NUL-terminated string and `out` writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_open(path: *const c_char, out: *mut u64) -> i32 {
+    if path.is_null() || out.is_null() {
+        return KV_ERR_INVALID_ARGUMENT;
+    }
+    // SAFETY: checked non-null; the caller guarantees NUL termination
+    let Ok(path) = unsafe { CStr::from_ptr(path) }.to_str() else {
+        return KV_ERR_INVALID_ARGUMENT;
+    };
+    match DB::open(path, LSMConfig::default()) {
+        Ok(db) => {
+            // SAFETY: checked non-null; the caller guarantees it is writable
+            unsafe { *out = register(Handle::Db(Arc::new(db))) };
+            KV_OK
+        }
+        Err(_) => KV_ERR_IO,
+    }
+}
+
+/// release a database handle; open snapshots and iterators keep it alive
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_close(db: u64) -> i32 {
+    close(db, |handle| matches!(handle, Handle::Db(_)))
+}
+
+/// # Safety
+/// `key` and `value` must point to `key_len` and `value_len` readable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_put(
+    db: u64,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> i32 {
+    // SAFETY: forwarded from the caller
+    let Some(key) = (unsafe { bytes(key, key_len) }) else {
+        return KV_ERR_INVALID_ARGUMENT;
+    };
+    // SAFETY: forwarded from the caller
+    let Some(value) = (unsafe { bytes(value, value_len) }) else {
+        return KV_ERR_INVALID_ARGUMENT;
+    };
+    let Some(db) = self::db(db) else {
+        return KV_ERR_INVALID_HANDLE;
+    };
+    match db.put(key, value) {
+        Ok(()) => KV_OK,
+        Err(_) => KV_ERR_IO,
+    }
+}
+
+/// # Safety
+/// `key` must point to `key_len` readable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_delete(db: u64, key: *const u8, key_len: usize) -> i32 {
+    // SAFETY: forwarded from the caller
+    let Some(key) = (unsafe { bytes(key, key_len) }) else {
+        return KV_ERR_INVALID_ARGUMENT;
+    };
+    let Some(db) = self::db(db) else {
+        return KV_ERR_INVALID_HANDLE;
+    };
+    match db.delete(key) {
+        Ok(()) => KV_OK,
+        Err(_) => KV_ERR_IO,
+    }
+}
+
+/// look `key` up, as of `snapshot` unless it is KV_NO_SNAPSHOT
+///
+/// on KV_OK `*value` is a buffer of `*value_len` bytes to pass to kv_free
+///
+/// # Safety
+/// `key` must point to `key_len` readable bytes; `value` and `value_len`
+/// must be writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_get(
+    db: u64,
+    snapshot: u64,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> i32 {
+    // SAFETY: forwarded from the caller
+    let Some(key) = (unsafe { bytes(key, key_len) }) else {
+        return KV_ERR_INVALID_ARGUMENT;
+    };
+    if value.is_null() || value_len.is_null() {
+        return KV_ERR_INVALID_ARGUMENT;
+    }
+    let Some((db, options)) = read_target(db, snapshot) else {
+        return KV_ERR_INVALID_HANDLE;
+    };
+    match db.get_opt(key, &options) {
+        Ok(Some(found)) => {
+            let found = found.into_boxed_slice();
+            // SAFETY: checked non-null; the caller guarantees they are writable
+            unsafe {
+                *value_len = found.len();
+                *value = Box::into_raw(found).cast();
+            }
+            KV_OK
+        }
+        Ok(None) => KV_NOT_FOUND,
+        Err(_) => KV_ERR_IO,
+    }
+}
+
+/// free a buffer returned by kv_get
+///
+/// # Safety
+/// `ptr` and `len` must come from one kv_get call, and be freed only once
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_free(ptr: *mut u8, len: usize) {
+    if !ptr.is_null() {
+        // SAFETY: kv_get leaked a boxed slice of exactly this length
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)) });
+    }
+}
+
+/// pin the current state of `db` for reads
+///
+/// # Safety
+/// `out` must be writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_snapshot(db: u64, out: *mut u64) -> i32 {
+    if out.is_null() {
+        return KV_ERR_INVALID_ARGUMENT;
+    }
+    let Some(db) = self::db(db) else {
+        return KV_ERR_INVALID_HANDLE;
+    };
+    let snapshot = db.snapshot();
+    // SAFETY: checked non-null; the caller guarantees it is writable
+    unsafe { *out = register(Handle::Snapshot { db, snapshot }) };
+    KV_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_snapshot_close(snapshot: u64) -> i32 {
+    close(snapshot, |handle| matches!(handle, Handle::Snapshot { .. }))
+}
+
+/// iterate every live key in order, as of `snapshot` unless it is KV_NO_SNAPSHOT
+///
+/// # Safety
+/// `out` must be writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_iter(db: u64, snapshot: u64, out: *mut u64) -> i32 {
+    if out.is_null() {
+        return KV_ERR_INVALID_ARGUMENT;
+    }
+    let Some((db, options)) = read_target(db, snapshot) else {
+        return KV_ERR_INVALID_HANDLE;
+    };
+    let iter = match db.range_opt::<&[u8]>(.., &options) {
+        Ok(iter) => iter,
+        Err(_) => return KV_ERR_IO,
+    };
+    let state = IterState {
+        _db: db,
+        iter,
+        current: None,
+    };
+    // SAFETY: checked non-null; the caller guarantees it is writable
+    unsafe { *out = register(Handle::Iter(Arc::new(Mutex::new(state)))) };
+    KV_OK
+}
+
+/// step to the next entry: KV_OK if there is one, KV_NOT_FOUND at the end
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_iter_next(iter: u64) -> i32 {
+    let Some(state) = self::iter(iter) else {
+        return KV_ERR_INVALID_HANDLE;
+    };
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    match state.iter.next() {
+        Some(Ok(entry)) => {
+            state.current = Some(entry);
+            KV_OK
+        }
+        Some(Err(_)) => {
+            state.current = None;
+            KV_ERR_IO
+        }
+        None => {
+            state.current = None;
+            KV_NOT_FOUND
+        }
+    }
+}
+
+/// key of the current entry, borrowed until the next kv_iter_next or close
+///
+/// # Safety
+/// `key` and `key_len` must be writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_iter_key(iter: u64, key: *mut *const u8, key_len: *mut usize) -> i32 {
+    // SAFETY: forwarded from the caller
+    unsafe { iter_current(iter, key, key_len, |(key, _)| key) }
+}
+
+/// value of the current entry, borrowed until the next kv_iter_next or close
+///
+/// # Safety
+/// `value` and `value_len` must be writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kv_iter_value(
+    iter: u64,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> i32 {
+    // SAFETY: forwarded from the caller
+    unsafe { iter_current(iter, value, value_len, |(_, value)| value) }
+}
+
+/// # Safety
+/// `ptr` and `len` must be writable
+unsafe fn iter_current(
+    iter: u64,
+    ptr: *mut *const u8,
+    len: *mut usize,
+    part: fn(&Entry) -> &Vec<u8>,
+) -> i32 {
+    if ptr.is_null() || len.is_null() {
+        return KV_ERR_INVALID_ARGUMENT;
+    }
+    let Some(state) = self::iter(iter) else {
+        return KV_ERR_INVALID_HANDLE;
+    };
+    let state = state.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(bytes) = state.current.as_ref().map(part) else {
+        return KV_NOT_FOUND;
+    };
+    // the bytes live in the registry's IterState until the next step or close
+    // SAFETY: checked non-null; the caller guarantees they are writable
+    unsafe {
+        *ptr = bytes.as_ptr();
+        *len = bytes.len();
+    }
+    KV_OK
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_iter_close(iter: u64) -> i32 {
+    close(iter, |handle| matches!(handle, Handle::Iter(_)))
+}
+
+/// handles opened and not yet closed, of every kind
+#[cfg(debug_assertions)]
+#[unsafe(no_mangle)]
+pub extern "C" fn kv_live_handles() -> u64 {
+    registry().handles.len() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::ffi::CString;
+    use std::fs;
+    use std::ptr;
+
+    fn get(db: u64, snapshot: u64, key: &[u8]) -> (i32, Option<Vec<u8>>) {
+        let (mut value, mut len) = (ptr::null_mut(), 0);
+        let code = unsafe { kv_get(db, snapshot, key.as_ptr(), key.len(), &mut value, &mut len) };
+        if code != KV_OK {
+            return (code, None);
+        }
+        let found = unsafe { slice::from_raw_parts(value, len) }.to_vec();
+        unsafe { kv_free(value, len) };
+        (code, Some(found))
+    }
+
+    fn current(iter: u64) -> (Vec<u8>, Vec<u8>) {
+        let (mut key, mut key_len) = (ptr::null(), 0);
+        let (mut value, mut value_len) = (ptr::null(), 0);
+        unsafe {
+            assert_eq!(kv_iter_key(iter, &mut key, &mut key_len), KV_OK);
+            assert_eq!(kv_iter_value(iter, &mut value, &mut value_len), KV_OK);
+            (
+                slice::from_raw_parts(key, key_len).to_vec(),
+                slice::from_raw_parts(value, value_len).to_vec(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_handles_outlive_close_and_reject_reuse() {
+        let dir = env::temp_dir().join("test_ffi_handles");
+        fs::remove_dir_all(&dir).ok();
+        let path = CString::new(dir.to_str().unwrap()).unwrap();
+        let live_before = kv_live_handles();
+
+        let mut db = 0;
+        assert_eq!(unsafe { kv_open(path.as_ptr(), &mut db) }, KV_OK);
+        let put = |key: &[u8], value: &[u8]| unsafe {
+            kv_put(db, key.as_ptr(), key.len(), value.as_ptr(), value.len())
+        };
+        assert_eq!(put(b"a", b"1"), KV_OK);
+        assert_eq!(put(b"b", b"2"), KV_OK);
+
+        let (mut snapshot, mut iter) = (0, 0);
+        assert_eq!(unsafe { kv_snapshot(db, &mut snapshot) }, KV_OK);
+        assert_eq!(put(b"a", b"changed"), KV_OK);
+        assert_eq!(unsafe { kv_delete(db, b"b".as_ptr(), 1) }, KV_OK);
+        assert_eq!(unsafe { kv_iter(db, snapshot, &mut iter) }, KV_OK);
+
+        assert_eq!(
+            get(db, KV_NO_SNAPSHOT, b"a"),
+            (KV_OK, Some(b"changed".to_vec()))
+        );
+        assert_eq!(get(db, KV_NO_SNAPSHOT, b"b").0, KV_NOT_FOUND);
+        assert_eq!(get(db, snapshot, b"b"), (KV_OK, Some(b"2".to_vec())));
+
+        // the iterator keeps the database open after its handle is gone
+        assert_eq!(kv_close(db), KV_OK);
+        assert_eq!(put(b"c", b"3"), KV_ERR_INVALID_HANDLE);
+        assert_eq!(kv_iter_next(iter), KV_OK);
+        assert_eq!(current(iter), (b"a".to_vec(), b"1".to_vec()));
+        assert_eq!(kv_iter_next(iter), KV_OK);
+        assert_eq!(current(iter), (b"b".to_vec(), b"2".to_vec()));
+        assert_eq!(kv_iter_next(iter), KV_NOT_FOUND);
+
+        // use after close and handles of the wrong kind are errors, not UB
+        assert_eq!(kv_iter_close(iter), KV_OK);
+        assert_eq!(kv_iter_next(iter), KV_ERR_INVALID_HANDLE);
+        assert_eq!(kv_iter_close(iter), KV_ERR_INVALID_HANDLE);
+        assert_eq!(kv_iter_close(snapshot), KV_ERR_INVALID_HANDLE);
+        assert_eq!(kv_close(snapshot), KV_ERR_INVALID_HANDLE);
+        assert_eq!(kv_snapshot_close(snapshot), KV_OK);
+        assert_eq!(get(db, snapshot, b"a").0, KV_ERR_INVALID_HANDLE);
+        assert_eq!(
+            unsafe { kv_open(ptr::null(), &mut db) },
+            KV_ERR_INVALID_ARGUMENT
+        );
+
+        assert_eq!(kv_live_handles(), live_before);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
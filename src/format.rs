@@ -0,0 +1,252 @@
+//! On-disk format constants and the byte helpers that go with them
+//! - every magic number, version and op code the storage engine writes lives here
+//! - integers are little-endian everywhere
+//! - the get_* helpers never panic on short input, so tooling and fuzz
+//!   targets can point them at arbitrary bytes
+
+/// unit of disk I/O
+pub const PAGE_SIZE: usize = 4096;
+
+/// target size of an SSTable data block
+pub const BLOCK_SIZE: usize = PAGE_SIZE;
+
+/// block entry: [shared_len(4B)][unshared_len(4B)][val_len(4B)][key suffix][value]
+/// - shared_len bytes of the key come from the previous entry; 0 at restart points
+pub const BLOCK_ENTRY_HEADER_SIZE: usize = 12;
+
+/// block entry before prefix compression: [key_len(4B)][val_len(4B)][key][value]
+pub const LEGACY_BLOCK_ENTRY_HEADER_SIZE: usize = 8;
+
+/// typed block entry: [shared_len(4B)][unshared_len(4B)][val_len(4B)][type(1B)][key suffix][value]
+/// - the type is one of the VALUE_* tags
+pub const TYPED_BLOCK_ENTRY_HEADER_SIZE: usize = 13;
+
+/// block trailer: [restart offsets(4B each)][num_restarts(4B)]
+pub const BLOCK_TRAILER_SIZE: usize = 4;
+
+/// WAL and batch operation codes
+pub const OP_PUT: u8 = 0x01;
+pub const OP_DELETE: u8 = 0x02;
+pub const OP_BATCH: u8 = 0x03;
+
+/// a batch for one column family: the key is the family id (4B) and the
+/// value a batch of its operations
+pub const OP_FAMILY: u8 = 0x04;
+
+/// a merge operand for the key, logged as its value
+pub const OP_MERGE: u8 = 0x05;
+
+/// a range deletion of [key, value)
+pub const OP_DELETE_RANGE: u8 = 0x06;
+
+/// a delete of a key written at most once since its last delete
+pub const OP_SINGLE_DELETE: u8 = 0x07;
+
+/// WAL record: [checksum(4B)][length(4B)] then the checksummed payload
+pub const WAL_HEADER_SIZE: usize = 8;
+
+/// WAL payload and batch operation: [op(1B)][key_len(4B)][value_len(4B)][key][value]
+pub const OP_HEADER_SIZE: usize = 9;
+
+/// WAL record format; version 2 records carry their sequence number
+pub const WAL_VERSION: u32 = 2;
+
+/// flags a version 2 record on its op byte; the op is followed by the
+/// sequence number of the record's first operation (8B), then as version 1
+pub const WAL_OP_SEQUENCED: u8 = 0x80;
+pub const WAL_SEQ_SIZE: usize = 8;
+
+/// batch value: [count(4B)] then its operations
+pub const BATCH_HEADER_SIZE: usize = 4;
+
+/// manifest log record: [checksum(4B)][length(4B)] then a version edit, which
+/// is a run of fields, each [tag(1B)] then its body
+pub const MANIFEST_RECORD_HEADER_SIZE: usize = 8;
+
+/// edit fields; the counters are [value(8B)], NUM_LEVELS is [levels(4B)]
+pub const EDIT_NUM_LEVELS: u8 = 0x01;
+pub const EDIT_VERSION: u8 = 0x02;
+pub const EDIT_NEXT_FILE: u8 = 0x03;
+pub const EDIT_WAL_SEQ: u8 = 0x04;
+pub const EDIT_LAST_SEQUENCE: u8 = 0x05;
+
+/// delete file: [level(4B)][id(8B)]
+pub const EDIT_DELETE_FILE: u8 = 0x06;
+
+/// add file: [level(4B)][id(8B)][size(8B)][entries(8B)][created_at(8B)][flags(1B)]
+/// then [len(4B)][bytes] for the path, min key and max key, then the prefix
+/// filter if flagged: [prefix_len(4B)][num_hashes(4B)][len(4B)][filter]
+pub const EDIT_ADD_FILE: u8 = 0x07;
+
+/// file deletes and adds after this belong to column family [id(4B)]
+pub const EDIT_FAMILY: u8 = 0x08;
+
+/// create family: [id(4B)][levels(4B)][len(4B)][name]
+pub const EDIT_CREATE_FAMILY: u8 = 0x09;
+
+/// drop family: [id(4B)]
+pub const EDIT_DROP_FAMILY: u8 = 0x0a;
+
+/// id the next column family gets: [id(4B)]
+pub const EDIT_NEXT_FAMILY: u8 = 0x0b;
+
+pub const EDIT_FILE_TOMBSTONE_ONLY: u8 = 0x01;
+pub const EDIT_FILE_PREFIX_FILTER: u8 = 0x02;
+
+/// last 8 bytes of every table file
+pub const TABLE_MAGIC: u64 = 0x4b56_5354_4142_4c45; // "KVSTABLE"
+pub const TABLE_VERSION: u32 = 5;
+
+/// first version whose data blocks end in a compression byte
+pub const TABLE_VERSION_COMPRESSED: u32 = 2;
+
+/// first version whose data blocks prefix-compress their keys
+pub const TABLE_VERSION_PREFIX_KEYS: u32 = 3;
+
+/// first version whose block entries carry their type, see
+/// TYPED_BLOCK_ENTRY_HEADER_SIZE; their values are [seq(8B)][value]
+pub const TABLE_VERSION_TYPED_ENTRIES: u32 = 4;
+
+/// first version that may hold range tombstones, in a block between the
+/// bloom filter and the footer; older readers would ignore them
+pub const TABLE_VERSION_RANGE_DELETES: u32 = 5;
+
+/// table footer: [index handle(16B)][bloom handle(16B)][version(4B)][magic(8B)]
+pub const FOOTER_SIZE: usize = 44;
+
+/// stored data block: [block bytes, possibly compressed][compression(1B)]
+pub const BLOCK_COMPRESSION_NONE: u8 = 0x00;
+pub const BLOCK_COMPRESSION_LZ4: u8 = 0x01;
+pub const BLOCK_COMPRESSION_SNAPPY: u8 = 0x02;
+pub const BLOCK_COMPRESSION_ZSTD: u8 = 0x03;
+
+/// tag byte in front of every table value, so tombstones survive a flush
+/// - typed blocks keep it in the entry header instead; values inlined in
+///   the index always lead with it
+pub const VALUE_PUT: u8 = 0x01;
+pub const VALUE_DELETE: u8 = 0x02;
+
+/// only typed blocks can hold these; a merge value is the operands oldest
+/// first, each [len(4B)][operand]
+pub const VALUE_MERGE: u8 = 0x03;
+pub const VALUE_RANGE_DELETE_START: u8 = 0x04;
+pub const VALUE_RANGE_DELETE_END: u8 = 0x05;
+pub const VALUE_SINGLE_DELETE: u8 = 0x06;
+
+/// table value: [tag(1B)][seq(8B)][value]
+pub const VALUE_HEADER_SIZE: usize = 9;
+
+/// table value in a typed block: [seq(8B)][value]
+pub const TYPED_VALUE_HEADER_SIZE: usize = 8;
+
+/// with LSMConfig::ttl, values and merge operands end in their write time:
+/// [value][unix seconds(8B)]
+pub const TIMESTAMP_SIZE: usize = 8;
+
+/// page file: PAGE_SIZE pages back to back, pages 0 and 1 being meta pages
+/// - page header: [checksum(4B)][type(1B)][reserved(1B)][count(2B)]; the
+///   checksum covers the rest of the page
+pub const PAGE_HEADER_SIZE: usize = 8;
+
+pub const PAGE_TYPE_META: u8 = 0x01;
+pub const PAGE_TYPE_BTREE_LEAF: u8 = 0x02;
+pub const PAGE_TYPE_BTREE_INTERNAL: u8 = 0x03;
+
+/// free list page after the header: [next list page(8B)] then count page
+/// ids (8B each), 0 ending the list
+/// - PageManager's own list links the free pages themselves, with no ids
+pub const PAGE_TYPE_FREE_LIST: u8 = 0x04;
+pub const FREE_LIST_HEADER_SIZE: usize = PAGE_HEADER_SIZE + 8;
+
+/// slotted page after the page header, where count is the number of cells:
+/// [content_start(2B)][fragmented(2B)][owner header][slot per cell]...[cells]
+/// - slot: [offset(2B)][len(2B)], in cell order; cells are packed from the
+///   end of the page down to content_start, with fragmented bytes of holes
+pub const SLOTTED_HEADER_SIZE: usize = PAGE_HEADER_SIZE + 4;
+pub const SLOT_SIZE: usize = 4;
+
+/// meta page after the header:
+/// [magic(8B)][version(4B)][page_size(4B)][root(8B)][free_list(8B)][txn(8B)]
+/// [num_pages(8B)], num_pages being 0 in files from before it was kept
+pub const PAGE_FILE_MAGIC: u64 = 0x4b56_5041_4745_5331; // "KVPAGES1"
+pub const PAGE_FILE_VERSION: u32 = 4;
+
+/// B-tree nodes are slotted pages with an 8 byte owner header
+/// - leaf: header unused; cell [key_len(2B)][val_len(2B)][key][value],
+///   the value leading with its kind
+/// - internal: header [first child(8B)]; cell [key_len(2B)][key][child(8B)]
+pub const BTREE_NODE_HEADER_SIZE: usize = SLOTTED_HEADER_SIZE + 8;
+pub const BTREE_LEAF_CELL_HEADER_SIZE: usize = 4;
+pub const BTREE_INTERNAL_CELL_HEADER_SIZE: usize = 10;
+
+/// leaf value kinds: [BTREE_VALUE][value] or [BTREE_BUCKET][bucket root(8B)]
+pub const BTREE_VALUE: u8 = 0x01;
+pub const BTREE_BUCKET: u8 = 0x02;
+
+pub fn get_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn get_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn get_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// CRC32 (IEEE) used for WAL record checksums
+pub fn crc32(data: &[u8]) -> u32 {
+    const POLYNOMIAL: u32 = 0xEDB88320;
+    let mut crc: u32 = 0xFFFFFFFF;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ POLYNOMIAL;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_put_roundtrip() {
+        let mut buf = Vec::new();
+        put_u32(&mut buf, 7);
+        put_u64(&mut buf, u64::MAX - 1);
+
+        assert_eq!(get_u32(&buf, 0), Some(7));
+        assert_eq!(get_u64(&buf, 4), Some(u64::MAX - 1));
+        assert_eq!(get_u32(&buf, 10), None);
+        assert_eq!(get_u64(&buf, usize::MAX), None);
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::format::{FOOTER_SIZE, PAGE_FILE_VERSION};
+use crate::lsm::manifest::ManifestError;
+use crate::lsm::sstable::writer::Footer;
+use crate::lsm::version_edit;
+use crate::lsm::{Manifest, db::LEGACY_MANIFEST_FILES};
+use crate::storage::page::{META_PAGES, MetaPage, PAGE_SIZE, PageError};
+
+/// DbInfo: what a store on disk holds, read without opening it
+///    - for an LSM directory only CURRENT, the manifest and the footer of
+///      each table are read; nothing is locked, replayed or written
+///    - for a page file only the meta pages are read
+///    - the key count is the sum of entries in tables, so it counts
+///      overwritten keys and tombstones and leaves out the WAL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DbInfo {
+    pub path: PathBuf,
+
+    pub engine: EngineKind,
+
+    /// "log" for a manifest log named by CURRENT, "legacy log" or "legacy
+    /// json" for one from before CURRENT that open would convert, "none"
+    /// for an empty directory; None for a page file
+    pub manifest_format: Option<String>,
+
+    /// distinct table format versions, oldest first
+    pub table_versions: Vec<u32>,
+
+    pub page_file_version: Option<u32>,
+
+    /// levels holding at least one table
+    pub levels: Vec<LevelInfo>,
+
+    pub approximate_keys: u64,
+
+    /// unix seconds of the oldest table that records it
+    pub created_at: Option<u64>,
+
+    /// sequence number of the last write in a table
+    pub last_sequence: Option<u64>,
+
+    /// commits made to a page file
+    pub txn: Option<u64>,
+
+    /// tables the manifest lists but the directory lacks, or whose footer
+    /// doesn't read
+    pub bad_tables: Vec<(PathBuf, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    /// a directory of tables and WAL segments, see lsm::DB
+    Lsm,
+
+    /// a single file of pages, see storage::BTree
+    Page,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LevelInfo {
+    pub level: usize,
+
+    pub files: usize,
+
+    pub bytes: u64,
+
+    pub entries: u64,
+}
+
+#[derive(Debug)]
+pub enum InfoError {
+    Io(io::Error),
+    Manifest(ManifestError),
+    Page(PageError),
+    NotADatabase(PathBuf),
+}
+
+impl From<io::Error> for InfoError {
+    fn from(err: io::Error) -> Self {
+        InfoError::Io(err)
+    }
+}
+
+impl From<ManifestError> for InfoError {
+    fn from(err: ManifestError) -> Self {
+        InfoError::Manifest(err)
+    }
+}
+
+impl fmt::Display for InfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfoError::Io(e) => write!(f, "I/O error: {}", e),
+            InfoError::Manifest(e) => write!(f, "{}", e),
+            InfoError::Page(e) => write!(f, "Meta page: {}", e),
+            InfoError::NotADatabase(path) => write!(f, "{} is not a database", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for InfoError {}
+
+pub type Result<T> = std::result::Result<T, InfoError>;
+
+/// describe the store at `path`: a directory is an LSM database, a file a
+/// page file
+pub fn describe(path: impl AsRef<Path>) -> Result<DbInfo> {
+    let path = path.as_ref();
+    if fs::metadata(path)?.is_dir() {
+        describe_lsm(path)
+    } else {
+        describe_page_file(path)
+    }
+}
+
+fn describe_lsm(dir: &Path) -> Result<DbInfo> {
+    let legacy = LEGACY_MANIFEST_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|legacy| legacy.exists());
+    let (manifest, manifest_format) = match (version_edit::current_manifest(dir)?, legacy) {
+        (Some(current), _) => (Manifest::load(current)?, "log"),
+        (None, Some(legacy)) => {
+            let format = if legacy.extension().is_some() {
+                "legacy json"
+            } else {
+                "legacy log"
+            };
+            (Manifest::load(&legacy)?, format)
+        }
+        (None, None) if fs::read_dir(dir)?.next().is_none() => {
+            return Ok(DbInfo {
+                path: dir.to_path_buf(),
+                engine: EngineKind::Lsm,
+                manifest_format: Some("none".to_string()),
+                table_versions: Vec::new(),
+                page_file_version: None,
+                levels: Vec::new(),
+                approximate_keys: 0,
+                created_at: None,
+                last_sequence: Some(0),
+                txn: None,
+                bad_tables: Vec::new(),
+            });
+        }
+        (None, None) => return Err(InfoError::NotADatabase(dir.to_path_buf())),
+    };
+
+    let mut table_versions = BTreeSet::new();
+    let mut bad_tables = Vec::new();
+    let mut levels = Vec::new();
+    for level in &manifest.levels {
+        if level.sstables.is_empty() {
+            continue;
+        }
+        for sst in &level.sstables {
+            match table_version(&dir.join(&sst.path)) {
+                Ok(version) => {
+                    table_versions.insert(version);
+                }
+                Err(e) => bad_tables.push((sst.path.clone(), e)),
+            }
+        }
+        levels.push(LevelInfo {
+            level: level.level,
+            files: level.sstables.len(),
+            bytes: level.sstables.iter().map(|sst| sst.size).sum(),
+            entries: level.sstables.iter().map(|sst| sst.num_entries).sum(),
+        });
+    }
+
+    Ok(DbInfo {
+        path: dir.to_path_buf(),
+        engine: EngineKind::Lsm,
+        manifest_format: Some(manifest_format.to_string()),
+        table_versions: table_versions.into_iter().collect(),
+        page_file_version: None,
+        approximate_keys: levels.iter().map(|level| level.entries).sum(),
+        levels,
+        created_at: manifest
+            .levels
+            .iter()
+            .flat_map(|level| &level.sstables)
+            .map(|sst| sst.created_at)
+            .filter(|&created| created > 0)
+            .min(),
+        last_sequence: Some(manifest.last_sequence),
+        txn: None,
+        bad_tables,
+    })
+}
+
+/// format version from the footer of the table at `path`
+fn table_version(path: &Path) -> std::result::Result<u32, String> {
+    let mut footer = [0; FOOTER_SIZE];
+    let read = File::open(path).and_then(|mut file| {
+        file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        file.read_exact(&mut footer)
+    });
+    read.map_err(|e| e.to_string())?;
+    Footer::decode(&footer)
+        .map(|footer| footer.version)
+        .map_err(|e| e.to_string())
+}
+
+fn describe_page_file(path: &Path) -> Result<DbInfo> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < META_PAGES * PAGE_SIZE as u64 || len % PAGE_SIZE as u64 != 0 {
+        return Err(InfoError::NotADatabase(path.to_path_buf()));
+    }
+
+    // the valid meta page with the highest txn, as BTree::open picks it
+    let mut current: Option<MetaPage> = None;
+    let mut error = None;
+    for _ in 0..META_PAGES {
+        let mut page = [0; PAGE_SIZE];
+        file.read_exact(&mut page)?;
+        match MetaPage::decode(&page) {
+            Ok(meta) if current.is_none_or(|current| meta.txn > current.txn) => {
+                current = Some(meta)
+            }
+            Ok(_) => {}
+            Err(e) => error = Some(e),
+        }
+    }
+    let meta = match (current, error) {
+        (Some(meta), _) => meta,
+        (None, Some(e)) => return Err(InfoError::Page(e)),
+        (None, None) => unreachable!(),
+    };
+
+    Ok(DbInfo {
+        path: path.to_path_buf(),
+        engine: EngineKind::Page,
+        manifest_format: None,
+        table_versions: Vec::new(),
+        page_file_version: Some(PAGE_FILE_VERSION),
+        levels: Vec::new(),
+        approximate_keys: 0,
+        created_at: None,
+        last_sequence: None,
+        txn: Some(meta.txn),
+        bad_tables: Vec::new(),
+    })
+}
+
+impl DbInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::TABLE_VERSION;
+    use crate::lsm::{DB, LSMConfig};
+    use crate::storage::BTree;
+    use std::env;
+
+    #[test]
+    fn test_describe_lsm() {
+        let dir = env::temp_dir().join("test_describe_lsm");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(
+            describe(&dir).unwrap().manifest_format.as_deref(),
+            Some("none")
+        );
+
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        db.put(b"unflushed", b"value").unwrap();
+        db.close().unwrap();
+
+        let info = describe(&dir).unwrap();
+        assert_eq!(info.engine, EngineKind::Lsm);
+        assert_eq!(info.manifest_format.as_deref(), Some("log"));
+        assert_eq!(info.table_versions, vec![TABLE_VERSION]);
+        assert_eq!(info.approximate_keys, 100);
+        assert_eq!(info.levels.len(), 1);
+        assert_eq!(info.last_sequence, Some(100));
+        assert!(info.created_at.is_some());
+        assert!(info.bad_tables.is_empty());
+        assert!(info.to_json().contains("\"engine\": \"lsm\""));
+
+        // a missing table is reported, not an error
+        let sst = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .unwrap();
+        fs::remove_file(&sst).unwrap();
+        assert_eq!(describe(&dir).unwrap().bad_tables.len(), 1);
+
+        fs::write(dir.join("CURRENT"), "garbage").unwrap();
+        assert!(describe(&dir).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_describe_page_file() {
+        let path = env::temp_dir().join("test_describe_page_file.db");
+        fs::remove_file(&path).ok();
+        let mut tree = BTree::open(&path).unwrap();
+        tree.insert(b"a", b"1").unwrap();
+        tree.commit().unwrap();
+        drop(tree);
+
+        let info = describe(&path).unwrap();
+        assert_eq!(info.engine, EngineKind::Page);
+        assert_eq!(info.page_file_version, Some(PAGE_FILE_VERSION));
+        assert_eq!(info.txn, Some(1));
+
+        fs::write(&path, b"not pages").unwrap();
+        assert!(matches!(describe(&path), Err(InfoError::NotADatabase(_))));
+        fs::remove_file(&path).ok();
+    }
+}
@@ -1,2 +1,15 @@
 pub mod lsm;
 pub mod constants;
+pub mod engine;
+pub mod format;
+pub mod info;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod storage;
+pub mod zorder;
+
+pub use engine::{EngineError, EngineOptions, KvEngine};
+pub use info::{DbInfo, describe};
+pub use lsm::DB;
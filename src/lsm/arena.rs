@@ -0,0 +1,236 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Deref;
+use std::ptr::{self, NonNull};
+
+/// default chunk size; a memtable of a few MB fills a few dozen
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Arena: bump allocator for memtable keys and values
+///    - bytes are copied into large chunks that never move, so a write costs
+///      a copy instead of an allocation per key and value
+///    - nothing is freed on its own: every chunk goes at once when the arena
+///      is dropped, i.e. when its memtable has been flushed
+///    - allocations over a quarter chunk get a chunk to themselves, so a big
+///      value doesn't strand the rest of the current chunk
+#[derive(Debug)]
+pub struct Arena {
+    /// every chunk handed out so far, the current one last
+    chunks: Vec<Chunk>,
+
+    /// bytes used in the current chunk
+    offset: usize,
+
+    chunk_size: usize,
+
+    /// bytes reserved by all chunks
+    allocated: usize,
+}
+
+#[derive(Debug)]
+struct Chunk {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+/// bytes stored in an arena, see Arena::alloc
+///    - nothing ties it to the arena, so whoever allocates it has to keep the
+///      arena alive for as long as it is read
+///    - neither Send nor Sync; the structure owning both the arena and its
+///      slices decides whether it can cross threads
+pub struct ArenaSlice {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// chunks are written only through &mut Arena, before any slice can see them
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    pub fn new() -> Self {
+        Self::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        Self {
+            chunks: Vec::new(),
+            offset: 0,
+            chunk_size: chunk_size.max(1),
+            allocated: 0,
+        }
+    }
+
+    /// copy `bytes` into the arena
+    ///
+    /// # Safety
+    /// the slice must not be read once the arena is dropped; moving the
+    /// arena is fine, its chunks stay where they are
+    pub unsafe fn alloc(&mut self, bytes: &[u8]) -> ArenaSlice {
+        if bytes.is_empty() {
+            return ArenaSlice {
+                ptr: NonNull::dangling(),
+                len: 0,
+            };
+        }
+
+        let dest = if bytes.len() > self.chunk_size / 4 {
+            // keep the current chunk last so small allocations go on filling it
+            let chunk = self.new_chunk(bytes.len());
+            let at = self.chunks.len().saturating_sub(1);
+            self.chunks.insert(at, chunk);
+            if self.chunks.len() == 1 {
+                // nothing small fits in a dedicated chunk
+                self.offset = self.chunk_size;
+            }
+            self.chunks[at].ptr
+        } else {
+            if self.chunks.is_empty() || self.offset + bytes.len() > self.chunk_size {
+                let chunk = self.new_chunk(self.chunk_size);
+                self.chunks.push(chunk);
+                self.offset = 0;
+            }
+            let current = &self.chunks[self.chunks.len() - 1];
+            // SAFETY: offset + len fits the current chunk, checked above
+            let dest = unsafe { current.ptr.add(self.offset) };
+            self.offset += bytes.len();
+            dest
+        };
+
+        // SAFETY: `dest` has room for `bytes` and no slice covers it yet
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), dest.as_ptr(), bytes.len()) };
+        ArenaSlice {
+            ptr: dest,
+            len: bytes.len(),
+        }
+    }
+
+    /// bytes reserved by the arena's chunks
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    fn new_chunk(&mut self, len: usize) -> Chunk {
+        self.allocated += len;
+        let chunk = Box::into_raw(vec![0u8; len].into_boxed_slice());
+        Chunk {
+            // SAFETY: Box::into_raw never returns null
+            ptr: unsafe { NonNull::new_unchecked(chunk as *mut u8) },
+            len,
+        }
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for chunk in &self.chunks {
+            let slice = ptr::slice_from_raw_parts_mut(chunk.ptr.as_ptr(), chunk.len);
+            // SAFETY: every chunk came from Box::into_raw in new_chunk
+            drop(unsafe { Box::from_raw(slice) });
+        }
+    }
+}
+
+impl Deref for ArenaSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the arena outlives every slice it handed out
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Borrow<[u8]> for ArenaSlice {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for ArenaSlice {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for ArenaSlice {}
+
+impl PartialOrd for ArenaSlice {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ArenaSlice {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl fmt::Debug for ArenaSlice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alloc(arena: &mut Arena, bytes: &[u8]) -> ArenaSlice {
+        // SAFETY: each test is done with its slices before its arena drops
+        unsafe { arena.alloc(bytes) }
+    }
+
+    #[test]
+    fn test_slices_stay_valid_across_chunks() {
+        let mut arena = Arena::with_chunk_size(64);
+        let slices: Vec<ArenaSlice> = (0..100u8).map(|i| alloc(&mut arena, &[i; 10])).collect();
+        let big = alloc(&mut arena, &[7; 1000]);
+        let after = alloc(&mut arena, b"after");
+        let empty = alloc(&mut arena, b"");
+
+        for (i, slice) in slices.iter().enumerate() {
+            assert_eq!(&**slice, &[i as u8; 10]);
+        }
+        assert_eq!(&*big, &[7; 1000][..]);
+        assert_eq!(&*after, b"after");
+        assert!(empty.is_empty());
+
+        // six 10 byte slices per chunk, plus one chunk for the big value
+        assert_eq!(arena.allocated(), 17 * 64 + 1000);
+    }
+
+    #[test]
+    fn test_big_allocation_keeps_current_chunk() {
+        let mut arena = Arena::with_chunk_size(64);
+        let first = alloc(&mut arena, b"first");
+        let _big = alloc(&mut arena, &[0; 100]);
+        let second = alloc(&mut arena, b"second");
+        assert_eq!(arena.allocated(), 64 + 100);
+        assert_eq!(second.ptr.as_ptr() as usize, first.ptr.as_ptr() as usize + 5);
+
+        // a big allocation into an empty arena leaves no room for small ones
+        let mut arena = Arena::with_chunk_size(64);
+        let big = alloc(&mut arena, &[1; 20]);
+        let small = alloc(&mut arena, b"small");
+        assert_eq!((&*big, &*small), (&[1; 20][..], &b"small"[..]));
+        assert_eq!(arena.allocated(), 20 + 64);
+    }
+
+    #[test]
+    fn test_ordering_by_bytes() {
+        let mut arena = Arena::new();
+        let (a, b) = (alloc(&mut arena, b"apple"), alloc(&mut arena, b"banana"));
+        assert!(a < b);
+        assert_eq!(a, alloc(&mut arena, b"apple"));
+        let borrowed: &[u8] = a.borrow();
+        assert_eq!(borrowed, b"apple");
+    }
+}
@@ -0,0 +1,557 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::db::{DB, DbError};
+use super::family::family_dir_name;
+use super::sstable::table::unix_now;
+use super::version_edit::ManifestLog;
+use crate::format::crc32;
+
+/// tables of every backup, each copied once
+const SHARED_DIR: &str = "shared";
+
+/// per backup, the manifest and WAL segments only it has
+const PRIVATE_DIR: &str = "private";
+
+/// per backup, what it holds; a backup exists once its meta file does
+const META_DIR: &str = "meta";
+
+/// BackupEngine: numbered backups of databases in one directory
+///    - tables are immutable, so a table already in an earlier backup is
+///      never copied again; a nightly backup copies what was flushed or
+///      compacted since the last one, plus the WAL
+///    - every backup is a consistent copy as of one moment, see
+///      DB::checkpoint; writes continue while tables are copied
+///    - deleting a backup deletes the tables no other backup holds
+///    - restoring checks every file against the size and checksum recorded
+///      when it was backed up
+///    - one BackupEngine at a time may use a directory, and it should only
+///      ever back up one database
+pub struct BackupEngine {
+    dir: PathBuf,
+}
+
+/// one backup, see BackupEngine::list_backups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u32,
+
+    /// unix seconds when the backup was taken
+    pub timestamp: u64,
+
+    /// bytes of every file restoring it needs, shared or not
+    pub size: u64,
+
+    pub num_files: usize,
+}
+
+/// meta file of a backup
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BackupMeta {
+    pub id: u32,
+
+    pub timestamp: u64,
+
+    /// directories of column families, relative to the database directory;
+    /// a family may have no tables yet
+    pub dirs: Vec<PathBuf>,
+
+    pub tables: Vec<BackupTable>,
+
+    /// files in the backup's private directory
+    pub private: Vec<BackupFile>,
+}
+
+/// a table of a backup: where the database keeps it and where the backup does
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BackupTable {
+    /// relative to the database directory
+    pub path: PathBuf,
+
+    /// file name in the shared directory
+    pub shared: String,
+
+    pub size: u64,
+
+    /// crc32 of the whole file
+    pub checksum: u32,
+}
+
+/// a file of a backup's private directory
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BackupFile {
+    pub name: String,
+
+    pub size: u64,
+
+    pub checksum: u32,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+    Db(DbError),
+    Corrupted(String),
+    NoSuchBackup(u32),
+    /// restore refuses to write into a directory holding anything, which
+    /// may be a live database
+    TargetNotEmpty(PathBuf),
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+impl From<DbError> for BackupError {
+    fn from(err: DbError) -> Self {
+        BackupError::Db(err)
+    }
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::Io(e) => write!(f, "Backup I/O error: {}", e),
+            BackupError::Db(e) => write!(f, "{}", e),
+            BackupError::Corrupted(msg) => write!(f, "Backup corrupted: {}", msg),
+            BackupError::NoSuchBackup(id) => write!(f, "No backup {}", id),
+            BackupError::TargetNotEmpty(path) => {
+                write!(f, "Restore target {} is not empty", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+pub type Result<T> = std::result::Result<T, BackupError>;
+
+impl BackupEngine {
+    /// use the backups in `dir`, creating it if needed
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        for sub in [SHARED_DIR, PRIVATE_DIR, META_DIR] {
+            fs::create_dir_all(dir.join(sub))?;
+        }
+        Ok(Self { dir })
+    }
+
+    /// back up `db` as it is now under the next id
+    pub fn create_backup(&self, db: &DB) -> Result<BackupInfo> {
+        let id = self.backup_ids()?.last().map_or(1, |id| id + 1);
+        let private = self.private_dir(id);
+        fs::remove_dir_all(&private).ok();
+        fs::create_dir(&private)?;
+        let meta = self.copy_files(db, id, &private);
+        let meta = meta.and_then(|meta| {
+            self.write_meta(&meta)?;
+            Ok(meta)
+        });
+        match meta {
+            Ok(meta) => Ok(info(&meta)),
+            Err(e) => {
+                fs::remove_dir_all(&private).ok();
+                Err(e)
+            }
+        }
+    }
+
+    /// every backup, oldest first
+    pub fn list_backups(&self) -> Result<Vec<BackupInfo>> {
+        self.backup_ids()?
+            .into_iter()
+            .map(|id| self.read_meta(id).map(|meta| info(&meta)))
+            .collect()
+    }
+
+    /// the newest backup, if there is one
+    pub fn latest(&self) -> Result<Option<BackupInfo>> {
+        Ok(self.list_backups()?.pop())
+    }
+
+    /// write backup `id` into `target` as a database DB::open accepts
+    ///
+    /// `target` must be missing or empty; every file is checked against
+    /// its recorded size and checksum first, and on any failure `target`
+    /// is left empty
+    pub fn restore(&self, id: u32, target: impl AsRef<Path>) -> Result<()> {
+        let target = target.as_ref();
+        let meta = self.read_meta(id)?;
+        match fs::read_dir(target) {
+            Ok(mut entries) => {
+                if entries.next().is_some() {
+                    return Err(BackupError::TargetNotEmpty(target.to_path_buf()));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir_all(target)?,
+            Err(e) => return Err(e.into()),
+        }
+
+        let result = self.restore_files(&meta, target);
+        if result.is_err()
+            && let Ok(entries) = fs::read_dir(target)
+        {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    fs::remove_dir_all(path).ok();
+                } else {
+                    fs::remove_file(path).ok();
+                }
+            }
+        }
+        result
+    }
+
+    fn restore_files(&self, meta: &BackupMeta, target: &Path) -> Result<()> {
+        for dir in &meta.dirs {
+            fs::create_dir_all(target.join(dir))?;
+        }
+        let private = self.private_dir(meta.id);
+        let tables = meta.tables.iter().map(|table| {
+            let from = self.dir.join(SHARED_DIR).join(&table.shared);
+            (from, target.join(&table.path), table.size, table.checksum)
+        });
+        let files = meta.private.iter().map(|file| {
+            let from = private.join(&file.name);
+            (from, target.join(&file.name), file.size, file.checksum)
+        });
+        for (from, to, size, checksum) in tables.chain(files) {
+            let data = fs::read(&from)?;
+            if data.len() as u64 != size || crc32(&data) != checksum {
+                return Err(BackupError::Corrupted(format!(
+                    "{} does not match backup {}",
+                    from.display(),
+                    meta.id
+                )));
+            }
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_synced(&to, &data)?;
+        }
+        Ok(())
+    }
+
+    /// delete backup `id` and the tables no other backup holds
+    pub fn delete_backup(&self, id: u32) -> Result<()> {
+        match fs::remove_file(self.meta_path(id)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(BackupError::NoSuchBackup(id));
+            }
+            result => result?,
+        }
+        fs::remove_dir_all(self.private_dir(id)).ok();
+        self.remove_unused_tables()
+    }
+
+    /// delete all but the newest `keep` backups; returns how many went
+    pub fn purge_old_backups(&self, keep: usize) -> Result<usize> {
+        let ids = self.backup_ids()?;
+        let old = &ids[..ids.len().saturating_sub(keep)];
+        for &id in old {
+            fs::remove_file(self.meta_path(id))?;
+            fs::remove_dir_all(self.private_dir(id)).ok();
+        }
+        self.remove_unused_tables()?;
+        Ok(old.len())
+    }
+
+    /// copy what backup `id` of `db` needs: its tables into the shared
+    /// directory unless already there, the rest into `private`
+    fn copy_files(&self, db: &DB, id: u32, private: &Path) -> Result<BackupMeta> {
+        // checksums of the tables earlier backups copied
+        let mut copied = HashMap::new();
+        for id in self.backup_ids()? {
+            let tables = self.read_meta(id)?.tables;
+            copied.extend(
+                tables
+                    .into_iter()
+                    .map(|table| (table.shared, table.checksum)),
+            );
+        }
+
+        let live = db.live_files(private)?;
+        let mut tables = Vec::new();
+        for sst in live.manifest.all_tables() {
+            // ids are unique within a database; the size guards against
+            // a table of the same id from another one
+            let shared = format!("{:06}_{}.sst", sst.id, sst.size);
+            let target = self.dir.join(SHARED_DIR).join(&shared);
+            let present = fs::metadata(&target).is_ok_and(|meta| meta.len() == sst.size);
+            let checksum = match copied.get(&shared) {
+                Some(&checksum) if present => checksum,
+                _ => {
+                    let data = fs::read(live.table_path(sst))?;
+                    write_synced(&target, &data)?;
+                    crc32(&data)
+                }
+            };
+            tables.push(BackupTable {
+                path: sst.path.clone(),
+                shared,
+                size: sst.size,
+                checksum,
+            });
+        }
+        let families = live.manifest.families.iter();
+        let dirs = families.map(|family| family_dir_name(family.id)).collect();
+        ManifestLog::create_current(private, 1, &live.manifest).map_err(DbError::from)?;
+        drop(live);
+
+        let mut private_files = Vec::new();
+        for entry in fs::read_dir(private)? {
+            let entry = entry?;
+            let data = fs::read(entry.path())?;
+            private_files.push(BackupFile {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: data.len() as u64,
+                checksum: crc32(&data),
+            });
+        }
+        private_files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(BackupMeta {
+            id,
+            timestamp: unix_now(),
+            dirs,
+            tables,
+            private: private_files,
+        })
+    }
+
+    /// backup `id`'s meta file
+    pub(crate) fn read_meta(&self, id: u32) -> Result<BackupMeta> {
+        let data = match fs::read(self.meta_path(id)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(BackupError::NoSuchBackup(id));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&data)
+            .map_err(|e| BackupError::Corrupted(format!("meta of backup {}: {}", id, e)))
+    }
+
+    fn write_meta(&self, meta: &BackupMeta) -> Result<()> {
+        let data = serde_json::to_vec(meta)
+            .map_err(|e| BackupError::Corrupted(format!("meta of backup {}: {}", meta.id, e)))?;
+        write_synced(&self.meta_path(meta.id), &data)?;
+        Ok(())
+    }
+
+    /// ids of the backups, ascending
+    fn backup_ids(&self) -> Result<Vec<u32>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.dir.join(META_DIR))? {
+            let name = entry?.file_name();
+            if let Some(id) = name.to_str().and_then(|name| name.parse().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// shared tables no backup holds any more, and copies cut short
+    fn remove_unused_tables(&self) -> Result<()> {
+        let mut used = HashSet::new();
+        for id in self.backup_ids()? {
+            used.extend(
+                self.read_meta(id)?
+                    .tables
+                    .into_iter()
+                    .map(|table| table.shared),
+            );
+        }
+        for entry in fs::read_dir(self.dir.join(SHARED_DIR))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !used.contains(&name) {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn private_dir(&self, id: u32) -> PathBuf {
+        self.dir.join(PRIVATE_DIR).join(id.to_string())
+    }
+
+    fn meta_path(&self, id: u32) -> PathBuf {
+        self.dir.join(META_DIR).join(id.to_string())
+    }
+}
+
+fn info(meta: &BackupMeta) -> BackupInfo {
+    let tables = meta.tables.iter().map(|table| table.size);
+    let private = meta.private.iter().map(|file| file.size);
+    BackupInfo {
+        id: meta.id,
+        timestamp: meta.timestamp,
+        size: tables.chain(private).sum(),
+        num_files: meta.tables.len() + meta.private.len(),
+    }
+}
+
+/// write `data` to `to` through a temp file, so `to` is whole or missing
+fn write_synced(to: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = to.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::config::LSMConfig;
+    use std::env;
+
+    fn config() -> LSMConfig {
+        LSMConfig {
+            memtable_size: 4096,
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        }
+    }
+
+    fn shared_files(dir: &Path) -> usize {
+        fs::read_dir(dir.join(SHARED_DIR)).unwrap().count()
+    }
+
+    #[test]
+    fn test_incremental_backups() {
+        let dir = env::temp_dir().join("test_backup_db");
+        let backups = env::temp_dir().join("test_backup_dir");
+        fs::remove_dir_all(&dir).ok();
+        fs::remove_dir_all(&backups).ok();
+        let db = DB::open(&dir, config()).unwrap();
+        let engine = BackupEngine::open(&backups).unwrap();
+        let key = |i: u32| format!("key{:04}", i).into_bytes();
+
+        for i in 0..200 {
+            db.put(&key(i), &[b'v'; 32]).unwrap();
+        }
+        let first = engine.create_backup(&db).unwrap();
+        assert_eq!(first.id, 1);
+        let tables = db
+            .status()
+            .levels
+            .iter()
+            .map(|level| level.files)
+            .sum::<usize>();
+        assert_eq!(shared_files(&backups), tables);
+
+        // only the newly flushed table is copied
+        db.put(b"later", b"value").unwrap();
+        let second = engine.create_backup(&db).unwrap();
+        assert_eq!(second.id, 2);
+        assert_eq!(shared_files(&backups), tables + 1);
+        assert!(second.size > first.size);
+        assert_eq!(
+            engine.list_backups().unwrap(),
+            vec![first.clone(), second.clone()]
+        );
+
+        // compaction replaces every table, so the first backup's go with it
+        db.compact_range::<&[u8]>(..).unwrap();
+        let third = engine.create_backup(&db).unwrap();
+        assert_eq!(engine.purge_old_backups(1).unwrap(), 2);
+        assert_eq!(engine.list_backups().unwrap(), vec![third.clone()]);
+        let live = db
+            .status()
+            .levels
+            .iter()
+            .map(|level| level.files)
+            .sum::<usize>();
+        assert_eq!(shared_files(&backups), live);
+        assert!(!engine.private_dir(1).exists());
+
+        assert!(matches!(
+            engine.delete_backup(1),
+            Err(BackupError::NoSuchBackup(1))
+        ));
+        engine.delete_backup(third.id).unwrap();
+        assert_eq!(shared_files(&backups), 0);
+        assert!(engine.list_backups().unwrap().is_empty());
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+        fs::remove_dir_all(&backups).ok();
+    }
+
+    #[test]
+    fn test_restore() {
+        let dir = env::temp_dir().join("test_restore_db");
+        let backups = env::temp_dir().join("test_restore_backups");
+        let target = env::temp_dir().join("test_restore_target");
+        for path in [&dir, &backups, &target] {
+            fs::remove_dir_all(path).ok();
+        }
+        let db = DB::open(&dir, config()).unwrap();
+        let engine = BackupEngine::open(&backups).unwrap();
+        assert_eq!(engine.latest().unwrap(), None);
+
+        let users = db.create_cf("users").unwrap();
+        let logs = db.create_cf("logs").unwrap();
+        for i in 0..200 {
+            db.put(format!("key{:04}", i).as_bytes(), &[b'v'; 32])
+                .unwrap();
+        }
+        users.put(b"alice", b"admin").unwrap();
+        let backup = engine.create_backup(&db).unwrap();
+        db.put(b"after", b"backup").unwrap();
+        drop((users, logs));
+        assert_eq!(engine.latest().unwrap(), Some(backup.clone()));
+
+        // never over a database, or anything else
+        assert!(matches!(
+            engine.restore(backup.id, &dir),
+            Err(BackupError::TargetNotEmpty(_))
+        ));
+        assert!(matches!(
+            engine.restore(7, &target),
+            Err(BackupError::NoSuchBackup(7))
+        ));
+
+        engine.restore(backup.id, &target).unwrap();
+        let restored = DB::open(&target, config()).unwrap();
+        assert_eq!(restored.iter().unwrap().count(), 200);
+        assert_eq!(restored.get(b"after").unwrap(), None);
+        assert_eq!(
+            restored.cf("users").unwrap().get(b"alice").unwrap(),
+            Some(b"admin".to_vec())
+        );
+        let logs = restored.cf("logs").unwrap();
+        logs.put(b"first", b"entry").unwrap();
+        restored.flush().unwrap();
+        drop(logs);
+        restored.close().unwrap();
+        fs::remove_dir_all(&target).ok();
+
+        // a damaged table fails the restore and leaves nothing behind
+        let shared = fs::read_dir(backups.join(SHARED_DIR)).unwrap();
+        let table = shared.map(|entry| entry.unwrap().path()).next().unwrap();
+        let mut data = fs::read(&table).unwrap();
+        data[0] ^= 0xff;
+        fs::write(&table, data).unwrap();
+        assert!(matches!(
+            engine.restore(backup.id, &target),
+            Err(BackupError::Corrupted(_))
+        ));
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 0);
+
+        db.close().unwrap();
+        for path in [&dir, &backups, &target] {
+            fs::remove_dir_all(path).ok();
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use crate::format::{
+    get_u32, BATCH_HEADER_SIZE, OP_DELETE, OP_DELETE_RANGE, OP_HEADER_SIZE, OP_MERGE, OP_PUT,
+    OP_SINGLE_DELETE,
+};
+
+/// WriteBatch: puts, deletes, range deletes and merges applied atomically
+///    - written to the WAL as a single checksummed record
+///    - inserted into the memtable under one lock, so readers never
+///      observe half a batch
+///    - operations apply in insertion order; later ones win on equal keys
+///    - operations are encoded straight into the WAL batch format, so the
+///      buffer is logged as-is and reused after clear()
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteBatch {
+    /// [count(4B)] then per op [OpType(1B)][Key Len(4B)][Value Len(4B)][Key][Value]
+    rep: Vec<u8>,
+
+    count: u32,
+}
+
+/// one operation of a batch, borrowed from its buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOp<'a> {
+    Put { key: &'a [u8], value: &'a [u8] },
+    Delete { key: &'a [u8] },
+    SingleDelete { key: &'a [u8] },
+    Merge { key: &'a [u8], value: &'a [u8] },
+    DeleteRange { start: &'a [u8], end: &'a [u8] },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOpType {
+    Put,
+    Delete,
+    SingleDelete,
+    Merge,
+    DeleteRange,
+}
+
+/// iterator over the operations of a batch, in insertion order
+#[derive(Clone)]
+pub struct BatchIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.push(OP_PUT, key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.push(OP_DELETE, key, &[]);
+    }
+
+    /// delete a key put at most once since it was last deleted, see
+    /// DB::single_delete
+    pub fn single_delete(&mut self, key: &[u8]) {
+        self.push(OP_SINGLE_DELETE, key, &[]);
+    }
+
+    /// a merge operand for `key`, see DB::merge
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.push(OP_MERGE, key, operand);
+    }
+
+    /// delete every key in [start, end), see DB::delete_range
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.push(OP_DELETE_RANGE, start, end);
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// drop all operations but keep the buffer for the next batch
+    pub fn clear(&mut self) {
+        self.rep.truncate(BATCH_HEADER_SIZE);
+        self.rep[..BATCH_HEADER_SIZE].fill(0);
+        self.count = 0;
+    }
+
+    /// bytes the buffer can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        self.rep.capacity()
+    }
+
+    /// encoded size of the batch, as it will be logged
+    pub fn size_in_bytes(&self) -> usize {
+        self.rep.len()
+    }
+
+    pub fn iter(&self) -> BatchIter<'_> {
+        BatchIter {
+            data: &self.rep,
+            offset: BATCH_HEADER_SIZE,
+        }
+    }
+
+    /// the encoded batch, ready to be logged as a WAL batch record or
+    /// shipped to another node and rebuilt with from_data()
+    pub fn data(&self) -> &[u8] {
+        &self.rep
+    }
+
+    /// rebuild a batch from data(), checking every operation fits the buffer
+    pub fn from_data(rep: Vec<u8>) -> Result<Self, BatchError> {
+        let count = get_u32(&rep, 0)
+            .ok_or_else(|| BatchError::Corrupted("missing batch header".to_string()))?;
+
+        let mut offset = BATCH_HEADER_SIZE;
+        let mut found = 0u32;
+        while offset < rep.len() {
+            let op_type = rep[offset];
+            let key_len = get_u32(&rep, offset + 1);
+            let value_len = get_u32(&rep, offset + 5);
+            let (Some(key_len), Some(value_len)) = (key_len, value_len) else {
+                return Err(BatchError::Corrupted(format!(
+                    "truncated operation at offset {}",
+                    offset
+                )));
+            };
+            let valid = match op_type {
+                OP_PUT | OP_MERGE | OP_DELETE_RANGE => true,
+                OP_DELETE | OP_SINGLE_DELETE => value_len == 0,
+                _ => false,
+            };
+            if !valid {
+                return Err(BatchError::Corrupted(format!(
+                    "bad operation type {} at offset {}",
+                    op_type, offset
+                )));
+            }
+
+            let end = (offset + OP_HEADER_SIZE)
+                .checked_add(key_len as usize)
+                .and_then(|end| end.checked_add(value_len as usize))
+                .filter(|&end| end <= rep.len())
+                .ok_or_else(|| {
+                    BatchError::Corrupted(format!("operation at offset {} overruns batch", offset))
+                })?;
+            offset = end;
+            found += 1;
+        }
+
+        if found != count {
+            return Err(BatchError::Corrupted(format!(
+                "header counts {} operations, found {}",
+                count, found
+            )));
+        }
+        Ok(Self { rep, count })
+    }
+
+    fn push(&mut self, op_type: u8, key: &[u8], value: &[u8]) {
+        self.rep.push(op_type);
+        self.rep.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.rep.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.rep.extend_from_slice(key);
+        self.rep.extend_from_slice(value);
+
+        self.count += 1;
+        self.rep[..BATCH_HEADER_SIZE].copy_from_slice(&self.count.to_le_bytes());
+    }
+}
+
+impl BatchOp<'_> {
+    pub fn op_type(&self) -> BatchOpType {
+        match self {
+            BatchOp::Put { .. } => BatchOpType::Put,
+            BatchOp::Delete { .. } => BatchOpType::Delete,
+            BatchOp::SingleDelete { .. } => BatchOpType::SingleDelete,
+            BatchOp::Merge { .. } => BatchOpType::Merge,
+            BatchOp::DeleteRange { .. } => BatchOpType::DeleteRange,
+        }
+    }
+}
+
+impl<'a> BatchOp<'a> {
+    /// the start of a range delete
+    pub fn key(&self) -> &'a [u8] {
+        match *self {
+            BatchOp::Put { key, .. }
+            | BatchOp::Delete { key }
+            | BatchOp::SingleDelete { key }
+            | BatchOp::Merge { key, .. } => key,
+            BatchOp::DeleteRange { start, .. } => start,
+        }
+    }
+
+    /// None for deletes; the operand of a merge, the end of a range delete
+    pub fn value(&self) -> Option<&'a [u8]> {
+        match *self {
+            BatchOp::Put { value, .. } | BatchOp::Merge { value, .. } => Some(value),
+            BatchOp::DeleteRange { end, .. } => Some(end),
+            BatchOp::Delete { .. } | BatchOp::SingleDelete { .. } => None,
+        }
+    }
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self {
+            rep: vec![0; BATCH_HEADER_SIZE],
+            count: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BatchError {
+    Corrupted(String),
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Corrupted(msg) => write!(f, "Batch corrupted: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+impl<'a> Iterator for BatchIter<'a> {
+    type Item = BatchOp<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the buffer is written by WriteBatch::push or checked by from_data
+        let header = self.data.get(self.offset..self.offset + OP_HEADER_SIZE)?;
+        let op_type = header[0];
+        let key_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let value_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+
+        let key_start = self.offset + OP_HEADER_SIZE;
+        let value_start = key_start + key_len;
+        self.offset = value_start + value_len;
+
+        let key = &self.data[key_start..value_start];
+        let value = &self.data[value_start..self.offset];
+        Some(match op_type {
+            OP_PUT => BatchOp::Put { key, value },
+            OP_MERGE => BatchOp::Merge { key, value },
+            OP_SINGLE_DELETE => BatchOp::SingleDelete { key },
+            OP_DELETE_RANGE => BatchOp::DeleteRange {
+                start: key,
+                end: value,
+            },
+            _ => BatchOp::Delete { key },
+        })
+    }
+}
+
+impl<'a> IntoIterator for &'a WriteBatch {
+    type Item = BatchOp<'a>;
+    type IntoIter = BatchIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_collects_operations() {
+        let mut batch = WriteBatch::new();
+        assert!(batch.is_empty());
+
+        batch.put(b"key1", b"value1");
+        batch.delete(b"key2");
+        assert_eq!(batch.len(), 2);
+
+        assert_eq!(
+            batch.iter().collect::<Vec<_>>(),
+            vec![
+                BatchOp::Put {
+                    key: b"key1",
+                    value: b"value1"
+                },
+                BatchOp::Delete { key: b"key2" },
+            ]
+        );
+
+        batch.clear();
+        assert!(batch.is_empty());
+        assert_eq!(batch.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_clear_keeps_buffer() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"key", &[b'v'; 1000]);
+        let capacity = batch.capacity();
+
+        batch.clear();
+        assert_eq!(batch.capacity(), capacity);
+        assert_eq!(batch, WriteBatch::new());
+
+        batch.put(b"key", b"small");
+        assert_eq!(batch.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_inspect_operations() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"key1", b"value1");
+        batch.delete(b"key2");
+        batch.merge(b"key3", b"+1");
+        batch.delete_range(b"a", b"b");
+        batch.single_delete(b"key6");
+        assert_eq!(batch.size_in_bytes(), BATCH_HEADER_SIZE + 5 * OP_HEADER_SIZE + 26);
+
+        let ops: Vec<_> = batch
+            .iter()
+            .map(|op| (op.op_type(), op.key(), op.value()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (BatchOpType::Put, &b"key1"[..], Some(&b"value1"[..])),
+                (BatchOpType::Delete, &b"key2"[..], None),
+                (BatchOpType::Merge, &b"key3"[..], Some(&b"+1"[..])),
+                (BatchOpType::DeleteRange, &b"a"[..], Some(&b"b"[..])),
+                (BatchOpType::SingleDelete, &b"key6"[..], None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_data_roundtrip() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"key1", b"value1");
+        batch.delete(b"key2");
+        batch.merge(b"key3", b"+1");
+        batch.delete_range(b"key4", b"key5");
+        batch.single_delete(b"key6");
+
+        let copy = WriteBatch::from_data(batch.data().to_vec()).unwrap();
+        assert_eq!(copy, batch);
+        assert_eq!(WriteBatch::from_data(vec![0; 4]).unwrap(), WriteBatch::new());
+
+        let data = batch.data();
+        assert!(WriteBatch::from_data(data[..data.len() - 1].to_vec()).is_err());
+        assert!(WriteBatch::from_data(data[..2].to_vec()).is_err());
+
+        let mut miscounted = data.to_vec();
+        miscounted[0] = 6;
+        assert!(WriteBatch::from_data(miscounted).is_err());
+
+        let mut bad_op = data.to_vec();
+        bad_op[BATCH_HEADER_SIZE] = 0x7f;
+        assert!(WriteBatch::from_data(bad_op).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::Serialize;
+
+use super::manifest::SSTableMetadata;
+use super::sstable::{Block, Result, SSTableReader};
+use super::stats::{Statistics, Ticker};
+
+/// tables whose hit counts are kept; older ones drop out of the ring
+const MAX_TRACKED_TABLES: usize = 64;
+
+/// TableCache: open table readers and the data blocks they read, up to
+/// `capacity` bytes between them
+///    - a reader holds the index, bloom filter and range tombstones of its
+///      table, so a table is charged that metadata; its data blocks are read
+///      on demand and charged one by one as they are cached
+///    - least recently used tables and blocks are evicted first; anything
+///      larger than the whole cache is read for each lookup and never kept
+///    - dropping a table drops its blocks with it
+///    - hits and misses are counted per table id for the most recently used
+///      tables, so operators can see which files dominate cache traffic
+///    - replace() hands a compaction's hot inputs over to its outputs, so a
+///      hot key range doesn't go cold when the files under it are rewritten
+pub struct TableCache {
+    capacity: usize,
+    state: Arc<Mutex<CacheState>>,
+
+    /// also counts hits and misses, see LSMConfig::statistics
+    statistics: Option<Arc<Statistics>>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    tables: HashMap<u64, CachedTable>,
+
+    /// decoded data blocks by table id and offset
+    blocks: HashMap<(u64, u64), CachedBlock>,
+
+    /// bytes charged by every cached table and block
+    usage: usize,
+
+    /// bumped on every lookup, orders tables for eviction
+    clock: u64,
+
+    hits: u64,
+    misses: u64,
+    warmed: u64,
+    block_hits: u64,
+    block_misses: u64,
+
+    /// per-table counters, most recently used last
+    recent: VecDeque<TableCacheStats>,
+}
+
+struct CachedTable {
+    reader: Arc<SSTableReader>,
+    charge: usize,
+    last_used: u64,
+
+    /// lookups served since it was cached
+    hits: u64,
+}
+
+struct CachedBlock {
+    block: Arc<Block>,
+    charge: usize,
+    last_used: u64,
+}
+
+/// one table's data blocks in a TableCache, handed to the reader it opens
+#[derive(Clone)]
+pub struct BlockCache {
+    id: u64,
+    capacity: usize,
+    state: Arc<Mutex<CacheState>>,
+}
+
+/// cache counters, see DB::cache_stats
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+
+    /// bytes held by cached tables and data blocks
+    pub usage: usize,
+
+    /// table lookups served from the cache
+    pub hits: u64,
+
+    pub misses: u64,
+
+    /// tables cached ahead of any read because they replaced hot ones
+    pub warmed: u64,
+
+    /// data block reads served from the cache
+    pub block_hits: u64,
+
+    pub block_misses: u64,
+
+    /// most recently used tables, busiest first
+    pub tables: Vec<TableCacheStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TableCacheStats {
+    pub id: u64,
+
+    pub hits: u64,
+
+    pub misses: u64,
+}
+
+impl TableCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Arc::new(Mutex::new(CacheState::default())),
+            statistics: None,
+        }
+    }
+
+    pub fn with_statistics(mut self, statistics: Arc<Statistics>) -> Self {
+        self.statistics = Some(statistics);
+        self
+    }
+
+    /// the cached reader for `sst`, opening it from `dir` on a miss
+    pub fn get(&self, dir: &Path, sst: &SSTableMetadata) -> Result<Arc<SSTableReader>> {
+        {
+            let mut state = self.lock();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(table) = state.tables.get_mut(&sst.id) {
+                table.last_used = clock;
+                table.hits += 1;
+                let reader = Arc::clone(&table.reader);
+                state.record(sst.id, true);
+                if let Some(stats) = &self.statistics {
+                    stats.add(Ticker::CacheHit, 1);
+                }
+                return Ok(reader);
+            }
+            state.record(sst.id, false);
+        }
+        if let Some(stats) = &self.statistics {
+            stats.add(Ticker::CacheMiss, 1);
+        }
+
+        // read the file without blocking other lookups
+        let reader = SSTableReader::open_cached(dir.join(&sst.path), self.blocks(sst.id))?;
+        let reader = Arc::new(reader);
+        self.admit(sst.id, Arc::clone(&reader));
+        Ok(reader)
+    }
+
+    /// the share of the cache a reader of table `id` keeps its blocks in
+    fn blocks(&self, id: u64) -> BlockCache {
+        BlockCache {
+            id,
+            capacity: self.capacity,
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// swap the inputs of a compaction for its outputs: every output
+    /// overlapping an input that served at least `min_hits` lookups while
+    /// cached is opened and cached now, then the inputs are evicted;
+    /// returns how many outputs were cached
+    pub fn replace(
+        &self,
+        dir: &Path,
+        inputs: &[SSTableMetadata],
+        outputs: &[SSTableMetadata],
+        min_hits: u64,
+    ) -> usize {
+        let hot: Vec<&SSTableMetadata> = {
+            let mut state = self.lock();
+            let hot = inputs
+                .iter()
+                .filter(|sst| {
+                    state
+                        .tables
+                        .get(&sst.id)
+                        .is_some_and(|table| table.hits >= min_hits)
+                })
+                .collect();
+            for sst in inputs {
+                state.remove(sst.id);
+            }
+            hot
+        };
+
+        let mut warmed = 0;
+        for sst in outputs {
+            if !hot
+                .iter()
+                .any(|input| input.min_key <= sst.max_key && sst.min_key <= input.max_key)
+            {
+                continue;
+            }
+            // the compaction already succeeded; a table that won't open now
+            // gets its error on the first read instead
+            if let Ok(reader) = SSTableReader::open_cached(dir.join(&sst.path), self.blocks(sst.id))
+                && self.admit(sst.id, Arc::new(reader))
+            {
+                warmed += 1;
+            }
+        }
+        self.lock().warmed += warmed as u64;
+        warmed
+    }
+
+    /// keep `reader`, evicting the least recently used tables to make room;
+    /// false if it is larger than the whole cache
+    fn admit(&self, id: u64, reader: Arc<SSTableReader>) -> bool {
+        let charge = reader.metadata_size() as usize;
+        if charge > self.capacity {
+            return false;
+        }
+
+        let mut state = self.lock();
+        state.make_room(charge, self.capacity);
+        let last_used = state.clock;
+        let previous = state.tables.insert(
+            id,
+            CachedTable {
+                reader,
+                charge,
+                last_used,
+                hits: 0,
+            },
+        );
+        state.usage += charge;
+        // another reader raced us to it
+        if let Some(previous) = previous {
+            state.usage -= previous.charge;
+        }
+        true
+    }
+
+    /// drop a table that is no longer part of the database
+    pub fn evict(&self, id: u64) {
+        self.lock().remove(id);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.lock();
+        let mut tables: Vec<TableCacheStats> = state.recent.iter().rev().cloned().collect();
+        tables.sort_by_key(|table| std::cmp::Reverse(table.hits + table.misses));
+        CacheStats {
+            capacity: self.capacity,
+            usage: state.usage,
+            hits: state.hits,
+            misses: state.misses,
+            warmed: state.warmed,
+            block_hits: state.block_hits,
+            block_misses: state.block_misses,
+            tables,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        lock(&self.state)
+    }
+}
+
+impl BlockCache {
+    /// the cached block at `offset` in this table
+    pub(crate) fn get(&self, offset: u64) -> Option<Arc<Block>> {
+        let mut state = lock(&self.state);
+        state.clock += 1;
+        let clock = state.clock;
+        let Some(cached) = state.blocks.get_mut(&(self.id, offset)) else {
+            state.block_misses += 1;
+            return None;
+        };
+        cached.last_used = clock;
+        let block = Arc::clone(&cached.block);
+        state.block_hits += 1;
+        Some(block)
+    }
+
+    /// keep a block just read, evicting the least recently used tables and
+    /// blocks to make room
+    pub(crate) fn insert(&self, offset: u64, block: Arc<Block>) {
+        let charge = block.size();
+        if charge > self.capacity {
+            return;
+        }
+
+        let mut state = lock(&self.state);
+        state.make_room(charge, self.capacity);
+        let last_used = state.clock;
+        let previous = state.blocks.insert(
+            (self.id, offset),
+            CachedBlock {
+                block,
+                charge,
+                last_used,
+            },
+        );
+        state.usage += charge;
+        if let Some(previous) = previous {
+            state.usage -= previous.charge;
+        }
+    }
+}
+
+fn lock(state: &Mutex<CacheState>) -> MutexGuard<'_, CacheState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl CacheState {
+    fn record(&mut self, id: u64, hit: bool) {
+        let mut table = match self.recent.iter().position(|table| table.id == id) {
+            Some(at) => self.recent.remove(at).unwrap(),
+            None => TableCacheStats {
+                id,
+                hits: 0,
+                misses: 0,
+            },
+        };
+        if hit {
+            self.hits += 1;
+            table.hits += 1;
+        } else {
+            self.misses += 1;
+            table.misses += 1;
+        }
+
+        if self.recent.len() == MAX_TRACKED_TABLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(table);
+    }
+
+    /// evict least recently used tables and blocks until `charge` more
+    /// bytes fit in `capacity`
+    fn make_room(&mut self, charge: usize, capacity: usize) {
+        while self.usage + charge > capacity {
+            let table = self
+                .tables
+                .iter()
+                .min_by_key(|(_, table)| table.last_used)
+                .map(|(&id, table)| (table.last_used, id));
+            let block = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, block)| block.last_used)
+                .map(|(&key, block)| (block.last_used, key));
+            match (table, block) {
+                (Some((table_used, id)), Some((block_used, _))) if table_used <= block_used => {
+                    self.remove(id)
+                }
+                (_, Some((_, key))) => {
+                    let block = self.blocks.remove(&key).unwrap();
+                    self.usage -= block.charge;
+                }
+                (Some((_, id)), None) => self.remove(id),
+                (None, None) => break,
+            }
+        }
+    }
+
+    /// drop a table and every block cached for it
+    fn remove(&mut self, id: u64) {
+        if let Some(table) = self.tables.remove(&id) {
+            self.usage -= table.charge;
+        }
+        let mut freed = 0;
+        self.blocks.retain(|&(table, _), block| {
+            if table == id {
+                freed += block.charge;
+            }
+            table != id
+        });
+        self.usage -= freed;
+    }
+}
+
+impl CacheStats {
+    /// share of lookups served from the cache, 0 before the first lookup
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+impl TableCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses).max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::sstable::SSTableWriter;
+    use crate::lsm::sstable::table::table_file_name;
+    use std::env;
+    use std::fs;
+
+    fn write_table(dir: &Path, id: u64) -> SSTableMetadata {
+        let mut writer = SSTableWriter::create(dir, &table_file_name(id), id, 0, 16, 10).unwrap();
+        writer.add(b"key", id, Some(&[0; 100])).unwrap();
+        writer.finish().unwrap()
+    }
+
+    /// what a cached reader of `sst` is charged
+    fn charge(dir: &Path, sst: &SSTableMetadata) -> usize {
+        SSTableReader::open(dir.join(&sst.path)).unwrap().metadata_size() as usize
+    }
+
+    #[test]
+    fn test_hits_misses_and_eviction() {
+        let dir = env::temp_dir().join("test_table_cache");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let tables: Vec<SSTableMetadata> = (1..=3).map(|id| write_table(&dir, id)).collect();
+        let size = charge(&dir, &tables[0]);
+        assert!(size < tables[0].size as usize);
+
+        // room for two tables
+        let cache = TableCache::new(2 * size + 1);
+        cache.get(&dir, &tables[0]).unwrap();
+        cache.get(&dir, &tables[0]).unwrap();
+        cache.get(&dir, &tables[1]).unwrap();
+        cache.get(&dir, &tables[0]).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.usage), (2, 2, 2 * size));
+        let busiest = &stats.tables[0];
+        assert_eq!((busiest.id, busiest.hits, busiest.misses), (1, 2, 1));
+        assert_eq!(stats.hit_rate(), 0.5);
+
+        // table 2 was used least recently
+        cache.get(&dir, &tables[2]).unwrap();
+        cache.get(&dir, &tables[0]).unwrap();
+        cache.get(&dir, &tables[1]).unwrap();
+        assert_eq!((cache.stats().hits, cache.stats().misses), (3, 4));
+
+        cache.evict(2);
+        cache.evict(3);
+        assert_eq!(cache.stats().usage, size);
+
+        // a cache too small for any table only counts misses
+        let cache = TableCache::new(0);
+        cache.get(&dir, &tables[0]).unwrap();
+        cache.get(&dir, &tables[0]).unwrap();
+        assert_eq!((cache.stats().misses, cache.stats().usage), (2, 0));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_replace_warms_outputs_of_hot_inputs() {
+        let dir = env::temp_dir().join("test_table_cache_replace");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let mut tables: Vec<SSTableMetadata> = (1..=4).map(|id| write_table(&dir, id)).collect();
+        // tables 1 and 3 cover "a".."c", 2 and 4 cover "x".."z"
+        for sst in &mut tables {
+            let range: (&[u8], &[u8]) = if sst.id % 2 == 1 {
+                (b"a", b"c")
+            } else {
+                (b"x", b"z")
+            };
+            sst.min_key = range.0.to_vec();
+            sst.max_key = range.1.to_vec();
+        }
+
+        let cache = TableCache::new(usize::MAX);
+        for _ in 0..3 {
+            cache.get(&dir, &tables[0]).unwrap();
+        }
+        cache.get(&dir, &tables[1]).unwrap();
+
+        // only table 1 served enough hits to count as hot
+        let warmed = cache.replace(&dir, &tables[..2], &tables[2..], 2);
+        assert_eq!(warmed, 1);
+        let stats = cache.stats();
+        assert_eq!((stats.warmed, stats.usage), (1, charge(&dir, &tables[2])));
+
+        // the warmed output is a hit from its first read
+        cache.get(&dir, &tables[2]).unwrap();
+        cache.get(&dir, &tables[3]).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_blocks_read_on_demand_and_cached() {
+        let dir = env::temp_dir().join("test_table_cache_blocks");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let tables: Vec<SSTableMetadata> = (1..=2).map(|id| write_table(&dir, id)).collect();
+        let size = charge(&dir, &tables[0]);
+
+        // opening a table reads no data block
+        let cache = TableCache::new(usize::MAX);
+        let reader = cache.get(&dir, &tables[0]).unwrap();
+        assert_eq!(cache.stats().usage, size);
+
+        // the first lookup reads its block, later ones find it cached
+        assert!(reader.get(b"key").unwrap().is_some());
+        assert!(reader.get(b"key").unwrap().is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.block_hits, stats.block_misses), (1, 1));
+        let block = stats.usage - size;
+        assert!(block > 0);
+
+        // the same table through another reader shares its blocks
+        let path = dir.join(&tables[0].path);
+        SSTableReader::open_cached(path, cache.blocks(1)).unwrap().iter().count();
+        assert_eq!(cache.stats().block_hits, 2);
+
+        // room for one table and one block: opening the second table evicts
+        // the first, and its block with it
+        let cache = TableCache::new(size + block);
+        cache.get(&dir, &tables[0]).unwrap().get(b"key").unwrap();
+        cache.get(&dir, &tables[1]).unwrap().get(b"key").unwrap();
+        assert_eq!(cache.stats().usage, size + block);
+        cache.get(&dir, &tables[1]).unwrap().get(b"key").unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.block_hits), (1, 1));
+
+        // dropping a table drops its blocks
+        let cache = TableCache::new(usize::MAX);
+        cache.get(&dir, &tables[0]).unwrap().get(b"key").unwrap();
+        cache.get(&dir, &tables[1]).unwrap().get(b"key").unwrap();
+        cache.evict(1);
+        assert_eq!(cache.stats().usage, size + block);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,1010 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use super::config::LSMConfig;
+use super::db::Result;
+use super::family::{DEFAULT_FAMILY, table_file_name};
+use super::iterator::{above_lower, below_upper};
+use super::job::CancelToken;
+use super::manifest::{Manifest, SSTableMetadata};
+use super::merge::{MergeFold, StoredValue};
+use super::range_del::{RangeTombstone, RangeTombstones};
+use super::sstable::table::{DEFAULT_RESTART_INTERVAL, TableEntry, unix_now};
+use super::sstable::{SSTableIterator, SSTableReader, SSTableWriter};
+use super::ttl::Expiry;
+
+/// why a compaction was picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionReason {
+    /// L0 reached `l0_compaction_trigger` files
+    L0FileCount,
+
+    /// a level outgrew `max_level_size`
+    LevelSize,
+
+    /// a file is older than `periodic_compaction_seconds`
+    Periodic,
+
+    /// requested for a key range through DB::compact_range
+    Manual,
+}
+
+/// one unit of compaction work: merge `inputs` from `level` with the
+/// `overlapping` files of `output_level`
+#[derive(Debug, Clone)]
+pub struct CompactionTask {
+    pub reason: CompactionReason,
+
+    /// column family whose tables these are
+    pub family: u32,
+
+    pub level: usize,
+
+    pub output_level: usize,
+
+    pub inputs: Vec<SSTableMetadata>,
+
+    pub overlapping: Vec<SSTableMetadata>,
+
+    /// no deeper level holds keys in the task's range, so tombstones can go
+    pub bottommost: bool,
+}
+
+impl CompactionTask {
+    fn new(
+        manifest: &Manifest,
+        reason: CompactionReason,
+        level: usize,
+        inputs: Vec<SSTableMetadata>,
+    ) -> Self {
+        let last_level = manifest.levels.len() - 1;
+        let output_level = (level + 1).min(last_level);
+
+        let (min, max) = key_range(&inputs);
+        let overlapping = if output_level == level {
+            Vec::new()
+        } else {
+            manifest.find_overlapping(output_level, &min, &max)
+        };
+
+        let (min, max) = key_range(inputs.iter().chain(&overlapping));
+        let bottommost = (output_level + 1..manifest.levels.len())
+            .all(|deeper| manifest.find_overlapping(deeper, &min, &max).is_empty());
+
+        Self {
+            reason,
+            family: DEFAULT_FAMILY,
+            level,
+            output_level,
+            inputs,
+            overlapping,
+            bottommost,
+        }
+    }
+
+    /// inputs can be relinked into the output level without rewriting:
+    /// nothing there overlaps and the inputs don't overlap each other
+    pub fn is_trivial_move(&self) -> bool {
+        if self.output_level == self.level || !self.overlapping.is_empty() {
+            return false;
+        }
+
+        let mut sorted: Vec<&SSTableMetadata> = self.inputs.iter().collect();
+        sorted.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+        sorted.windows(2).all(|w| w[0].max_key < w[1].min_key)
+    }
+
+    /// every file the compaction replaces
+    pub fn removed(&self) -> Vec<SSTableMetadata> {
+        self.inputs
+            .iter()
+            .chain(&self.overlapping)
+            .cloned()
+            .collect()
+    }
+}
+
+/// pick the most urgent compaction, if any
+/// - L0 is scored by file count, deeper levels by size against `max_level_size`
+/// - the highest score of at least 1 wins; the last level is never pushed down
+/// - with nothing over its limit, the oldest file past `periodic_compaction_seconds`
+///   is rewritten
+pub fn pick_compaction(
+    manifest: &Manifest,
+    config: &LSMConfig,
+    now_secs: u64,
+) -> Option<CompactionTask> {
+    if manifest.levels.len() < 2 {
+        return None;
+    }
+    let last_level = manifest.levels.len() - 1;
+
+    let l0_score = manifest.get_level(0).len() as f64 / config.l0_compaction_trigger.max(1) as f64;
+    let (score, level) = (1..last_level)
+        .map(|level| {
+            let score = manifest.level_size(level) as f64 / config.max_level_size(level) as f64;
+            (score, level)
+        })
+        .fold(
+            (l0_score, 0),
+            |best, next| if next.0 > best.0 { next } else { best },
+        );
+
+    if score >= 1.0 {
+        return Some(if level == 0 {
+            l0_task(manifest, CompactionReason::L0FileCount)
+        } else {
+            let oldest = manifest
+                .get_level(level)
+                .iter()
+                .min_by_key(|sst| (sst.created_at, sst.id))?
+                .clone();
+            CompactionTask::new(manifest, CompactionReason::LevelSize, level, vec![oldest])
+        });
+    }
+
+    let max_age = config.periodic_compaction_seconds?;
+    let sst = manifest
+        .files_older_than(now_secs, max_age)
+        .into_iter()
+        .next()?;
+    Some(if sst.level == 0 {
+        // L0 files shadow each other by age, so they always move together
+        l0_task(manifest, CompactionReason::Periodic)
+    } else {
+        CompactionTask::new(manifest, CompactionReason::Periodic, sst.level, vec![sst])
+    })
+}
+
+/// bytes compaction still has to rewrite before every level is within its
+/// limit: all of L0 once it reaches the trigger, plus what each deeper level
+/// holds beyond `max_level_size` (the last level has no limit)
+pub fn compaction_debt(manifest: &Manifest, config: &LSMConfig) -> u64 {
+    if manifest.levels.len() < 2 {
+        return 0;
+    }
+    let last_level = manifest.levels.len() - 1;
+
+    let l0 = if manifest.get_level(0).len() >= config.l0_compaction_trigger.max(1) {
+        manifest.level_size(0)
+    } else {
+        0
+    };
+    let deeper: u64 = (1..last_level)
+        .map(|level| manifest.level_size(level).saturating_sub(config.max_level_size(level)))
+        .sum();
+    l0 + deeper
+}
+
+/// push the files of `level` overlapping a key range one level down
+/// (the last level is rewritten in place); None if nothing there overlaps
+/// - all of L0 moves together, as L0 files shadow each other by age
+pub(crate) fn range_task(
+    manifest: &Manifest,
+    level: usize,
+    lower: &Bound<Vec<u8>>,
+    upper: &Bound<Vec<u8>>,
+) -> Option<CompactionTask> {
+    let files = manifest.get_level(level);
+    let overlaps = |sst: &&SSTableMetadata| {
+        above_lower(&sst.max_key, lower) && below_upper(&sst.min_key, upper)
+    };
+    if !files.iter().any(|sst| overlaps(&sst)) {
+        return None;
+    }
+
+    let inputs = if level == 0 {
+        files.to_vec()
+    } else {
+        files.iter().filter(overlaps).cloned().collect()
+    };
+    Some(CompactionTask::new(manifest, CompactionReason::Manual, level, inputs))
+}
+
+fn l0_task(manifest: &Manifest, reason: CompactionReason) -> CompactionTask {
+    CompactionTask::new(manifest, reason, 0, manifest.get_level(0).to_vec())
+}
+
+/// merge the task's files into new tables in its output level
+/// - versions newer than `oldest_snapshot` are kept, plus the newest version
+///   the oldest snapshot can see; older versions are shadowed and dropped
+/// - merge operands the oldest snapshot can see are folded onto the version
+///   below them; in the bottommost level, onto None if there is none
+/// - tombstones nobody can see past are dropped in the bottommost level; a
+///   single delete nobody can see past is dropped along with a put right
+///   below it, in any level
+/// - under LSMConfig::ttl expired puts become tombstones and expired merge
+///   operands are dropped, whatever the snapshots
+/// - versions under a range tombstone every snapshot sees are dropped; the
+///   tombstone itself only in the bottommost level. An output holding one
+///   isn't cut before the tombstone's end, so outputs never overlap
+/// - outputs are cut at key boundaries once they reach `target_file_size`
+/// - `cancel` is checked after every data block; on cancel or any other
+///   failure the outputs written so far are removed
+pub(crate) fn run_compaction(
+    dir: &Path,
+    config: &LSMConfig,
+    task: &CompactionTask,
+    oldest_snapshot: Option<u64>,
+    next_id: impl FnMut() -> u64,
+    cancel: &CancelToken,
+) -> Result<Vec<SSTableMetadata>> {
+    let mut created = Vec::new();
+    let result = merge_tables(dir, config, task, oldest_snapshot, next_id, cancel, &mut created);
+    if result.is_err() {
+        for path in &created {
+            fs::remove_file(path).ok();
+        }
+    }
+    result
+}
+
+fn merge_tables(
+    dir: &Path,
+    config: &LSMConfig,
+    task: &CompactionTask,
+    oldest_snapshot: Option<u64>,
+    next_id: impl FnMut() -> u64,
+    cancel: &CancelToken,
+    created: &mut Vec<PathBuf>,
+) -> Result<Vec<SSTableMetadata>> {
+    cancel.check()?;
+    let mut scanners = Vec::new();
+    let mut tombstones = RangeTombstones::new();
+    for sst in task.inputs.iter().chain(&task.overlapping) {
+        let reader = SSTableReader::open(dir.join(&sst.path))?;
+        tombstones.extend(reader.range_tombstones());
+        scanners.push(reader.iter());
+    }
+
+    let horizon = oldest_snapshot.unwrap_or(u64::MAX);
+    // range tombstones to write out, by start key
+    let mut carried = tombstones
+        .iter()
+        .filter(|t| !task.bottommost || t.seq > horizon)
+        .peekable();
+    let operator = config.merge_operator.as_deref();
+    let expiry = Expiry::new(config.ttl, unix_now());
+    let mut outputs = Outputs {
+        dir,
+        config,
+        task,
+        next_id,
+        cancel,
+        created,
+        writer: None,
+        range_end: None,
+        done: Vec::new(),
+    };
+    let mut current_key: Option<Vec<u8>> = None;
+    let mut covered = false;
+    // merge operands the horizon can see, with the newest one's sequence number
+    let mut pending: Option<(u64, MergeFold)> = None;
+    // sequence number of a single delete held back to see what is below it
+    let mut held: Option<u64> = None;
+
+    let mut merged = VersionMerge::new(scanners)?;
+    loop {
+        let next = merged.next().transpose()?;
+        let new_key = next.as_ref().is_none_or(|(key, ..)| current_key.as_ref() != Some(key));
+
+        // operands that ran out of versions to fold onto in this compaction
+        if new_key
+            && let Some((seq, fold)) = pending.take()
+            && let Some(key) = &current_key
+        {
+            let value = if task.bottommost {
+                fold.finish_version(key, operator)?
+            } else {
+                fold.into_merge()
+            };
+            outputs.add(key, seq, &value)?;
+        }
+        // a single delete with nothing left to cancel
+        if new_key
+            && let Some(seq) = held.take()
+            && let Some(key) = &current_key
+        {
+            outputs.add(key, seq, &StoredValue::SingleDelete)?;
+        }
+        let Some((key, seq, value)) = next else {
+            break;
+        };
+        if new_key {
+            while let Some(tombstone) = carried.next_if(|t| t.start <= key) {
+                outputs.add_range_tombstone(tombstone)?;
+            }
+            outputs.cut(&key)?;
+            current_key = Some(key.clone());
+            covered = false;
+        }
+
+        // everything below the newest version visible at the horizon is
+        // shadowed, except what its merge operands fold onto
+        if covered {
+            continue;
+        }
+        let value = match &expiry {
+            Some(expiry) => expiry.expire(value),
+            None => value,
+        };
+        if value == StoredValue::Merge(Vec::new()) {
+            continue;
+        }
+        // the put right below a single delete goes with it, leaving the
+        // version below that the newest; anything else the single delete
+        // shadows along with all below
+        if let Some(held_seq) = held.take() {
+            if !matches!(value, StoredValue::Put(_)) {
+                covered = true;
+                outputs.add(&key, held_seq, &StoredValue::SingleDelete)?;
+            }
+            continue;
+        }
+        if seq > horizon {
+            outputs.add(&key, seq, &value)?;
+            continue;
+        }
+        let range_deleted = seq < tombstones.covering_seq(&key, horizon);
+        let value = if range_deleted {
+            StoredValue::Delete
+        } else {
+            value
+        };
+        if let Some((_, fold)) = &mut pending {
+            if fold.push(value)
+                && let Some((seq, fold)) = pending.take()
+            {
+                covered = true;
+                let folded = fold.finish_version(&key, operator)?;
+                outputs.add(&key, seq, &folded)?;
+            }
+            continue;
+        }
+        // the range tombstone stays wherever older versions may remain
+        if range_deleted {
+            covered = true;
+            continue;
+        }
+        if value == StoredValue::SingleDelete && !task.bottommost {
+            held = Some(seq);
+            continue;
+        }
+        if value.is_merge() && operator.is_some() {
+            let mut fold = MergeFold::with_expiry(expiry);
+            fold.push(value);
+            pending = Some((seq, fold));
+            continue;
+        }
+
+        // without an operator operands are kept as they are, along with
+        // what they fold onto
+        covered = !value.is_merge();
+        if value.is_delete() && task.bottommost {
+            continue;
+        }
+        outputs.add(&key, seq, &value)?;
+    }
+
+    for tombstone in carried {
+        outputs.add_range_tombstone(tombstone)?;
+    }
+    outputs.finish()
+}
+
+/// the tables a compaction writes into its output level
+struct Outputs<'a, F> {
+    dir: &'a Path,
+    config: &'a LSMConfig,
+    task: &'a CompactionTask,
+    next_id: F,
+    cancel: &'a CancelToken,
+    created: &'a mut Vec<PathBuf>,
+    writer: Option<SSTableWriter>,
+
+    /// largest end of the range tombstones in `writer`
+    range_end: Option<Vec<u8>>,
+    done: Vec<SSTableMetadata>,
+}
+
+impl<F: FnMut() -> u64> Outputs<'_, F> {
+    fn add(&mut self, key: &[u8], seq: u64, value: &StoredValue) -> Result<()> {
+        let writer = self.writer()?;
+        let blocks = writer.num_blocks();
+        writer.add_value(key, seq, value)?;
+        if writer.num_blocks() != blocks {
+            self.cancel.check()?;
+        }
+        Ok(())
+    }
+
+    fn add_range_tombstone(&mut self, tombstone: &RangeTombstone) -> Result<()> {
+        self.writer()?.add_range_tombstone(tombstone.clone());
+        if self.range_end.as_ref().is_none_or(|end| *end < tombstone.end) {
+            self.range_end = Some(tombstone.end.clone());
+        }
+        Ok(())
+    }
+
+    /// the table being written, started if there is none
+    fn writer(&mut self) -> Result<&mut SSTableWriter> {
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => {
+                let id = (self.next_id)();
+                let file_name = table_file_name(self.task.family, id);
+                self.created.push(self.dir.join(&file_name));
+                let config = self.config;
+                let mut table = SSTableWriter::create(
+                    self.dir,
+                    &file_name,
+                    id,
+                    self.task.output_level,
+                    DEFAULT_RESTART_INTERVAL,
+                    config.bloom_bits_per_key,
+                )?
+                .with_compression(config.compression);
+                if let Some(threshold) = config.inline_value_threshold {
+                    table = table.with_inline_values(threshold);
+                }
+                if let Some(prefix_len) = config.prefix_filter_len {
+                    let bits = config.prefix_filter_bits_per_prefix;
+                    table = table.with_prefix_filter(prefix_len, bits);
+                }
+                table
+            }
+        };
+        Ok(self.writer.insert(writer))
+    }
+
+    /// start a new table if the current one reached target_file_size; only
+    /// called between keys, and never before `next_key` passes the end of a
+    /// range tombstone in the current one, which its key range includes
+    fn cut(&mut self, next_key: &[u8]) -> Result<()> {
+        if self.range_end.as_deref().is_some_and(|end| next_key <= end) {
+            return Ok(());
+        }
+        let target = self.config.target_file_size as u64;
+        if let Some(writer) = self.writer.take_if(|w| w.estimated_size() >= target) {
+            self.done.push(writer.finish()?);
+            self.range_end = None;
+            self.cancel.check()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<SSTableMetadata>> {
+        if let Some(writer) = self.writer.take() {
+            self.done.push(writer.finish()?);
+            self.cancel.check()?;
+        }
+        Ok(self.done)
+    }
+}
+
+/// heap entry ordered by key, then newest sequence number; the source index
+/// breaks ties so the value never takes part in the ordering
+type HeapEntry = Reverse<(Vec<u8>, Reverse<u64>, usize, StoredValue)>;
+
+/// k-way merge of table scans ordered by key, newest version first
+struct VersionMerge {
+    scanners: Vec<SSTableIterator>,
+    heap: BinaryHeap<HeapEntry>,
+}
+
+impl VersionMerge {
+    fn new(scanners: Vec<SSTableIterator>) -> Result<Self> {
+        let mut merge = Self {
+            heap: BinaryHeap::with_capacity(scanners.len()),
+            scanners,
+        };
+        for source in 0..merge.scanners.len() {
+            merge.advance(source)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        if let Some(entry) = self.scanners[source].next() {
+            let (key, seq, value) = entry?;
+            self.heap.push(Reverse((key, Reverse(seq), source, value)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for VersionMerge {
+    type Item = Result<TableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((key, Reverse(seq), source, value)) = self.heap.pop()?;
+        if let Err(e) = self.advance(source) {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+        Some(Ok((key, seq, value)))
+    }
+}
+
+fn key_range<'a>(files: impl IntoIterator<Item = &'a SSTableMetadata>) -> (Vec<u8>, Vec<u8>) {
+    let mut files = files.into_iter();
+    let Some(first) = files.next() else {
+        return (Vec::new(), Vec::new());
+    };
+
+    files.fold(
+        (first.min_key.clone(), first.max_key.clone()),
+        |(min, max), sst| (min.min(sst.min_key.clone()), max.max(sst.max_key.clone())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::db::DbError;
+    use crate::lsm::merge::StoredValue::{Delete, Put};
+    use crate::lsm::sstable::table::table_file_name;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn sst(id: u64, level: usize, min: &[u8], max: &[u8], size: u64) -> SSTableMetadata {
+        SSTableMetadata {
+            id,
+            level,
+            path: PathBuf::from(format!("{:06}.sst", id)),
+            size,
+            num_entries: 1,
+            min_key: min.to_vec(),
+            max_key: max.to_vec(),
+            created_at: 1000 + id,
+            tombstone_only: false,
+            prefix_filter: None,
+        }
+    }
+
+    /// key, sequence number and value, None for a tombstone
+    type Entry<'a> = (&'a [u8], u64, Option<&'a [u8]>);
+
+    fn write(dir: &Path, id: u64, level: usize, entries: &[Entry]) -> SSTableMetadata {
+        let mut writer = SSTableWriter::create(dir, &table_file_name(id), id, level, 16, 10).unwrap();
+        for (key, seq, value) in entries {
+            writer.add(key, *seq, *value).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read_all(dir: &Path, outputs: &[SSTableMetadata]) -> Vec<TableEntry> {
+        outputs
+            .iter()
+            .flat_map(|sst| SSTableReader::open(dir.join(&sst.path)).unwrap().iter())
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_pick_l0_by_file_count() {
+        let config = LSMConfig::default();
+        let mut manifest = Manifest::new(3);
+
+        manifest.add_sstable(0, sst(1, 0, b"a", b"f", 10));
+        manifest.add_sstable(0, sst(2, 0, b"d", b"k", 10));
+        assert!(pick_compaction(&manifest, &config, 0).is_none());
+
+        manifest.add_sstable(0, sst(3, 0, b"b", b"c", 10));
+        manifest.add_sstable(1, sst(4, 1, b"j", b"m", 10));
+        manifest.add_sstable(1, sst(5, 1, b"x", b"z", 10));
+        manifest.add_sstable(2, sst(6, 2, b"a", b"b", 10));
+
+        let task = pick_compaction(&manifest, &config, 0).unwrap();
+        assert_eq!(task.reason, CompactionReason::L0FileCount);
+        assert_eq!((task.level, task.output_level), (0, 1));
+        assert_eq!(task.inputs.len(), 3);
+        assert_eq!(
+            task.overlapping.iter().map(|s| s.id).collect::<Vec<_>>(),
+            vec![4]
+        );
+        assert!(!task.bottommost); // L2 still holds "a".."b"
+        assert!(!task.is_trivial_move());
+    }
+
+    #[test]
+    fn test_pick_level_by_size() {
+        let config = LSMConfig {
+            target_file_size: 100,
+            ..LSMConfig::default()
+        };
+        let mut manifest = Manifest::new(3);
+
+        // L1 limit is 100 * 10 = 1000 bytes
+        manifest.add_sstable(1, sst(2, 1, b"m", b"p", 600));
+        manifest.add_sstable(1, sst(1, 1, b"a", b"c", 600));
+        manifest.add_sstable(2, sst(3, 2, b"x", b"z", 10));
+
+        let task = pick_compaction(&manifest, &config, 0).unwrap();
+        assert_eq!(task.reason, CompactionReason::LevelSize);
+        assert_eq!((task.level, task.output_level), (1, 2));
+        assert_eq!(task.inputs[0].id, 1); // oldest file first
+        assert!(task.overlapping.is_empty());
+        assert!(task.is_trivial_move());
+    }
+
+    #[test]
+    fn test_compaction_debt() {
+        let config = LSMConfig {
+            target_file_size: 100,
+            l0_compaction_trigger: 2,
+            ..LSMConfig::default()
+        };
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(0, sst(1, 0, b"a", b"z", 50));
+        manifest.add_sstable(1, sst(2, 1, b"a", b"m", 600));
+        manifest.add_sstable(2, sst(3, 2, b"a", b"z", 50_000));
+        assert_eq!(compaction_debt(&manifest, &config), 0);
+
+        // L0 at its trigger owes all of itself, L1 its excess over 1000 bytes
+        manifest.add_sstable(0, sst(4, 0, b"a", b"z", 70));
+        manifest.add_sstable(1, sst(5, 1, b"n", b"z", 600));
+        assert_eq!(compaction_debt(&manifest, &config), 120 + 200);
+    }
+
+    #[test]
+    fn test_pick_periodic() {
+        let config = LSMConfig {
+            periodic_compaction_seconds: Some(500),
+            ..LSMConfig::default()
+        };
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(2, sst(1, 2, b"a", b"z", 10));
+
+        assert!(pick_compaction(&manifest, &config, 1200).is_none());
+
+        // a file in the last level is rewritten in place
+        let task = pick_compaction(&manifest, &config, 2000).unwrap();
+        assert_eq!(task.reason, CompactionReason::Periodic);
+        assert_eq!((task.level, task.output_level), (2, 2));
+        assert!(!task.is_trivial_move());
+    }
+
+    #[test]
+    fn test_trivial_move_needs_disjoint_inputs() {
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(0, sst(1, 0, b"a", b"c", 10));
+        manifest.add_sstable(0, sst(2, 0, b"d", b"f", 10));
+        assert!(l0_task(&manifest, CompactionReason::L0FileCount).is_trivial_move());
+
+        manifest.add_sstable(0, sst(3, 0, b"e", b"g", 10));
+        assert!(!l0_task(&manifest, CompactionReason::L0FileCount).is_trivial_move());
+    }
+
+    #[test]
+    fn test_run_compaction_drops_shadowed_versions() {
+        let dir = env::temp_dir().join("test_compaction_merge");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(
+            0,
+            write(
+                &dir,
+                1,
+                0,
+                &[(b"a", 1, Some(b"a1")), (b"b", 2, Some(b"b2"))],
+            ),
+        );
+        manifest.add_sstable(
+            0,
+            write(&dir, 2, 0, &[(b"a", 3, Some(b"a3")), (b"b", 4, None)]),
+        );
+        manifest.add_sstable(
+            0,
+            write(&dir, 3, 0, &[(b"a", 5, Some(b"a5")), (b"c", 6, None)]),
+        );
+
+        let task = l0_task(&manifest, CompactionReason::L0FileCount);
+        assert!(task.bottommost);
+
+        let mut next_id = 10;
+        let config = LSMConfig::default();
+        let mut id = || {
+            next_id += 1;
+            next_id
+        };
+
+        let cancel = CancelToken::new();
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].level, 1);
+        assert_eq!(
+            read_all(&dir, &outputs),
+            vec![(b"a".to_vec(), 5, Put(b"a5".to_vec()))]
+        );
+
+        // a snapshot at seq 3 keeps what it can see, including the tombstone for b
+        let outputs = run_compaction(&dir, &config, &task, Some(3), &mut id, &cancel).unwrap();
+        assert_eq!(
+            read_all(&dir, &outputs),
+            vec![
+                (b"a".to_vec(), 5, Put(b"a5".to_vec())),
+                (b"a".to_vec(), 3, Put(b"a3".to_vec())),
+                (b"b".to_vec(), 4, Delete),
+                (b"b".to_vec(), 2, Put(b"b2".to_vec())),
+                (b"c".to_vec(), 6, Delete),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_run_compaction_folds_merge_operands() {
+        use crate::lsm::merge::{AppendOperator, StoredValue::Merge};
+        use std::sync::Arc;
+
+        let dir = env::temp_dir().join("test_compaction_merge_operands");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let operand = |op: &[u8]| Merge(vec![op.to_vec()]);
+
+        let mut manifest = Manifest::new(3);
+        let mut writer = SSTableWriter::create(&dir, &table_file_name(1), 1, 0, 16, 10).unwrap();
+        writer.add(b"a", 1, Some(b"x")).unwrap();
+        writer.add_value(b"b", 2, &operand(b"1")).unwrap();
+        manifest.add_sstable(0, writer.finish().unwrap());
+        let mut writer = SSTableWriter::create(&dir, &table_file_name(2), 2, 0, 16, 10).unwrap();
+        writer.add_value(b"a", 4, &operand(b"z")).unwrap();
+        writer.add_value(b"a", 3, &operand(b"y")).unwrap();
+        writer.add_value(b"b", 5, &operand(b"2")).unwrap();
+        manifest.add_sstable(0, writer.finish().unwrap());
+
+        let task = l0_task(&manifest, CompactionReason::L0FileCount);
+        let config = LSMConfig {
+            merge_operator: Some(Arc::new(AppendOperator::new(b","))),
+            ..LSMConfig::default()
+        };
+        let mut next_id = 10;
+        let mut id = || {
+            next_id += 1;
+            next_id
+        };
+        let cancel = CancelToken::new();
+
+        // folded onto the put, or onto nothing in the bottommost level
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert_eq!(
+            read_all(&dir, &outputs),
+            vec![
+                (b"a".to_vec(), 4, Put(b"x,y,z".to_vec())),
+                (b"b".to_vec(), 5, Put(b"1,2".to_vec())),
+            ]
+        );
+
+        // above a snapshot operands stay apart; above a deeper level they
+        // are only concatenated
+        let mut task = task;
+        task.bottommost = false;
+        let outputs = run_compaction(&dir, &config, &task, Some(3), &mut id, &cancel).unwrap();
+        assert_eq!(
+            read_all(&dir, &outputs),
+            vec![
+                (b"a".to_vec(), 4, operand(b"z")),
+                (b"a".to_vec(), 3, Put(b"x,y".to_vec())),
+                (b"b".to_vec(), 5, operand(b"2")),
+                (b"b".to_vec(), 2, operand(b"1")),
+            ]
+        );
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert_eq!(
+            read_all(&dir, &outputs)[1],
+            (b"b".to_vec(), 5, Merge(vec![b"1".to_vec(), b"2".to_vec()]))
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_run_compaction_cancels_single_deletes() {
+        use crate::lsm::merge::StoredValue::SingleDelete;
+
+        let dir = env::temp_dir().join("test_compaction_single_deletes");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(
+            0,
+            write(
+                &dir,
+                1,
+                0,
+                &[
+                    (b"a", 1, Some(b"a1")),
+                    (b"c", 3, None),
+                    (b"d", 4, Some(b"d4")),
+                ],
+            ),
+        );
+        let mut writer = SSTableWriter::create(&dir, &table_file_name(2), 2, 0, 16, 10).unwrap();
+        for (key, seq) in [(b"a", 5), (b"c", 6), (b"d", 7), (b"e", 8)] {
+            writer.add_value(key, seq, &SingleDelete).unwrap();
+        }
+        manifest.add_sstable(0, writer.finish().unwrap());
+
+        let mut task = l0_task(&manifest, CompactionReason::L0FileCount);
+        task.bottommost = false;
+        let config = LSMConfig::default();
+        let mut next_id = 10;
+        let mut id = || {
+            next_id += 1;
+            next_id
+        };
+        let cancel = CancelToken::new();
+
+        // puts go with their single deletes; the others stay for deeper levels
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert_eq!(
+            read_all(&dir, &outputs),
+            vec![
+                (b"c".to_vec(), 6, SingleDelete),
+                (b"e".to_vec(), 8, SingleDelete),
+            ]
+        );
+
+        // not while a snapshot sees the put
+        let outputs = run_compaction(&dir, &config, &task, Some(5), &mut id, &cancel).unwrap();
+        assert_eq!(
+            read_all(&dir, &outputs),
+            vec![
+                (b"c".to_vec(), 6, SingleDelete),
+                (b"c".to_vec(), 3, Delete),
+                (b"d".to_vec(), 7, SingleDelete),
+                (b"d".to_vec(), 4, Put(b"d4".to_vec())),
+                (b"e".to_vec(), 8, SingleDelete),
+            ]
+        );
+
+        task.bottommost = true;
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert!(outputs.is_empty());
+
+        // put, delete, put, single delete: the delete below the cancelled
+        // pair still hides the first put
+        let mut manifest = Manifest::new(3);
+        let history: [Entry; 3] = [(b"b", 1, Some(b"b1")), (b"b", 2, None), (b"b", 3, Some(b"b3"))];
+        for (id, entry) in (20..).zip(history) {
+            manifest.add_sstable(0, write(&dir, id, 0, &[entry]));
+        }
+        let mut writer = SSTableWriter::create(&dir, &table_file_name(23), 23, 0, 16, 10).unwrap();
+        writer.add_value(b"b", 4, &SingleDelete).unwrap();
+        manifest.add_sstable(0, writer.finish().unwrap());
+        let mut task = l0_task(&manifest, CompactionReason::L0FileCount);
+        task.bottommost = false;
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert_eq!(read_all(&dir, &outputs), vec![(b"b".to_vec(), 2, Delete)]);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_run_compaction_splits_outputs() {
+        let dir = env::temp_dir().join("test_compaction_split");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let value = vec![b'v'; 100];
+        let keys: Vec<String> = (0..50).map(|i| format!("key{:03}", i)).collect();
+        let entries: Vec<Entry> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.as_bytes(), i as u64 + 1, Some(value.as_slice())))
+            .collect();
+
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(0, write(&dir, 1, 0, &entries));
+
+        let config = LSMConfig {
+            target_file_size: 1024,
+            ..LSMConfig::default()
+        };
+        let task = l0_task(&manifest, CompactionReason::L0FileCount);
+        let mut next_id = 1;
+        let next = || {
+            next_id += 1;
+            next_id
+        };
+        let cancel = CancelToken::new();
+        let outputs = run_compaction(&dir, &config, &task, None, next, &cancel).unwrap();
+
+        assert!(outputs.len() > 1);
+        assert!(outputs.windows(2).all(|w| w[0].max_key < w[1].min_key));
+        assert_eq!(read_all(&dir, &outputs).len(), 50);
+
+        // cancelled while writing its second output: both outputs are removed
+        let cancel = CancelToken::new();
+        let mut next_id = 100;
+        let next = || {
+            next_id += 1;
+            if next_id == 102 {
+                cancel.cancel();
+            }
+            next_id
+        };
+        let result = run_compaction(&dir, &config, &task, None, next, &cancel);
+        assert!(matches!(result, Err(DbError::Cancelled)));
+        assert!(!dir.join(table_file_name(101)).exists());
+        assert!(!dir.join(table_file_name(102)).exists());
+        assert!(dir.join(table_file_name(1)).exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_run_compaction_applies_range_tombstones() {
+        use crate::lsm::range_del::RangeTombstone;
+
+        let dir = env::temp_dir().join("test_compaction_range_tombstones");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let value = vec![b'v'; 100];
+        let keys: Vec<String> = (0..50).map(|i| format!("key{:03}", i)).collect();
+        let entries: Vec<Entry> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.as_bytes(), i as u64 + 1, Some(value.as_slice())))
+            .collect();
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(0, write(&dir, 1, 0, &entries));
+        let mut writer = SSTableWriter::create(&dir, &table_file_name(2), 2, 0, 16, 10).unwrap();
+        writer.add(b"key020", 101, Some(b"new")).unwrap();
+        writer.add_range_tombstone(RangeTombstone {
+            start: b"key010".to_vec(),
+            end: b"key030".to_vec(),
+            seq: 100,
+        });
+        let sst = writer.finish().unwrap();
+        assert_eq!((&sst.min_key[..], &sst.max_key[..]), (&b"key010"[..], &b"key030"[..]));
+        manifest.add_sstable(0, sst);
+
+        let config = LSMConfig {
+            target_file_size: 1024,
+            ..LSMConfig::default()
+        };
+        let mut task = l0_task(&manifest, CompactionReason::L0FileCount);
+        task.bottommost = false;
+        let mut next_id = 10;
+        let mut id = || {
+            next_id += 1;
+            next_id
+        };
+        let cancel = CancelToken::new();
+        let tombstones = |outputs: &[SSTableMetadata]| -> usize {
+            let open = |sst: &SSTableMetadata| SSTableReader::open(dir.join(&sst.path)).unwrap();
+            outputs.iter().map(|sst| open(sst).range_tombstones().len()).sum()
+        };
+
+        // covered keys go, the tombstone stays for the levels below, and no
+        // output is cut inside it
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert!(outputs.len() > 1);
+        assert!(outputs.windows(2).all(|w| w[0].max_key < w[1].min_key));
+        assert_eq!(read_all(&dir, &outputs).len(), 31);
+        assert_eq!(tombstones(&outputs), 1);
+
+        // a snapshot from before the tombstone keeps the covered keys
+        let outputs = run_compaction(&dir, &config, &task, Some(60), &mut id, &cancel).unwrap();
+        assert_eq!(read_all(&dir, &outputs).len(), 51);
+
+        // nothing is left for it to delete in the bottommost level
+        task.bottommost = true;
+        let outputs = run_compaction(&dir, &config, &task, None, &mut id, &cancel).unwrap();
+        assert_eq!(read_all(&dir, &outputs).len(), 31);
+        assert_eq!(tombstones(&outputs), 0);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
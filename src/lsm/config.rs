@@ -1,3 +1,35 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use super::listener::EventListener;
+use super::manifest::ManifestRecoveryMode;
+use super::merge::MergeOperator;
+use super::sstable::CompressionType;
+use super::stats::Statistics;
+use super::wal::WalRecoveryMode;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// settings DB::set_options can change on an open database
+pub const MUTABLE_OPTIONS: &[&str] = &[
+    "memtable_size",
+    "l0_compaction_trigger",
+    "level_multiplier",
+    "target_file_size",
+    "bloom_bits_per_key",
+    "compression",
+    "max_immutable_memtables",
+    "max_disk_bytes",
+    "periodic_compaction_seconds",
+    "cache_warm_min_hits",
+];
+
 #[derive(Debug, Clone)]
 pub struct LSMConfig {
     pub memtable_size: usize,
@@ -12,9 +44,151 @@ pub struct LSMConfig {
 
     pub block_cache_size: usize,
 
+    /// when a compaction rewrites a cached table that served at least this
+    /// many reads since it was cached, load the outputs covering its keys
+    /// into the cache right away instead of on their first reads; None
+    /// leaves outputs to be cached on demand
+    pub cache_warm_min_hits: Option<u64>,
+
     pub bloom_bits_per_key: usize,
 
     pub max_levels: usize,
+
+    pub append_mode: AppendMode,
+
+    pub compaction_schedule: CompactionSchedule,
+
+    /// rewrite files older than this even if no size trigger picks them,
+    /// so filters/TTLs and format upgrades eventually reach every file
+    pub periodic_compaction_seconds: Option<u64>,
+
+    /// run compactions on a background thread; DB::compact still works when off
+    pub auto_compaction: bool,
+
+    /// flush full memtables on a background thread; when off, the write
+    /// that fills a memtable flushes it before returning
+    pub background_flush: bool,
+
+    /// full memtables allowed to wait for a flush before writes stall
+    pub max_immutable_memtables: usize,
+
+    /// when writes reach the disk; WriteOptions::sync forces it per write
+    pub wal_sync: WalSyncPolicy,
+
+    /// what opening does about damaged WAL records
+    pub wal_recovery: WalRecoveryMode,
+
+    /// what opening does about a manifest that can't be read
+    pub manifest_recovery: ManifestRecoveryMode,
+
+    /// reject puts once tables plus buffered writes take this many bytes;
+    /// deletes still go through so space can be freed. In a
+    /// column_families entry it limits that family's bytes
+    pub max_disk_bytes: Option<u64>,
+
+    /// codec for new data blocks; anything but None needs its cargo feature
+    pub compression: CompressionType,
+
+    /// also store values of at most this many bytes in table indexes, so point
+    /// lookups for them skip the data block read at the cost of a larger index
+    pub inline_value_threshold: Option<usize>,
+
+    /// keep a filter over the first this-many bytes of every key of each
+    /// file in the manifest, so point reads and DB::prefix_iter skip files
+    /// without opening them; worth it for key schemes with a tenant or table
+    /// prefix
+    pub prefix_filter_len: Option<usize>,
+
+    pub prefix_filter_bits_per_prefix: usize,
+
+    /// bytes of edits the manifest log may gather before it is rewritten
+    /// as a fresh snapshot in a new file
+    pub max_manifest_file_size: u64,
+
+    /// read every table in full before open returns, failing on a size that
+    /// disagrees with the manifest or a block or entry that doesn't decode;
+    /// tables carry no checksums, so this is a full decode
+    pub paranoid_open: bool,
+
+    /// don't replay the WAL on open: segments are set aside as `.corrupt`
+    /// and writes that never reached a table are lost. For recovering from
+    /// logs too damaged to replay
+    pub skip_wal: bool,
+
+    /// on open, drop tables the manifest lists but the directory lacks,
+    /// reporting them on the status page, instead of failing
+    pub ignore_missing_files: bool,
+
+    /// folds the operands DB::merge writes; reads and compactions of keys
+    /// with operands fail without one
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    /// stamp values with their write time; versions older than this read
+    /// as absent and compaction drops them. A database written with a TTL
+    /// must keep being opened with one, and the TTL covers every family
+    pub ttl: Option<Duration>,
+
+    /// settings for the column families DB::create_cf makes and open
+    /// finds, by name; a family not listed uses this config. Only its
+    /// memtable, table, compaction and max_disk_bytes settings apply, the
+    /// rest are the DB's
+    pub column_families: HashMap<String, LSMConfig>,
+
+    /// counters and latency histograms to record into; None, the default,
+    /// records nothing. The DB's setting covers every family
+    pub statistics: Option<Arc<Statistics>>,
+
+    /// told about flushes, compactions, table files and WAL syncs, in
+    /// order; see EventListener
+    pub listeners: Vec<Arc<dyn EventListener>>,
+}
+
+/// when automatic compaction may run
+/// - blackout windows are daily UTC time-of-day ranges; a window whose end is
+///   before its start wraps past midnight
+/// - an optional callback can defer compaction on arbitrary conditions
+/// - once L0 holds `l0_override` files the schedule is ignored, so deferring
+///   never lets read amplification grow without bound
+/// - flushes are never deferred
+#[derive(Clone)]
+pub struct CompactionSchedule {
+    windows: Vec<(Duration, Duration)>,
+
+    defer_fn: Option<Arc<dyn Fn() -> bool + Send + Sync>>,
+
+    l0_override: usize,
+}
+
+/// when the WAL is fsynced
+/// - writes are always in the OS page cache before they return, so only a
+///   machine crash or power loss can lose unsynced ones
+/// - concurrent synced writes share fsyncs either way, see GroupCommit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalSyncPolicy {
+    /// every write returns once it is on disk
+    Always,
+
+    /// a background thread syncs this often; a crash loses at most the
+    /// last interval of writes
+    EveryNMillis(u64),
+
+    /// only when a memtable is sealed, on close, and for synced writes
+    #[default]
+    Never,
+}
+
+/// how the write path treats strictly increasing keys (logs, time series)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendMode {
+    /// always take the regular write path
+    Off,
+
+    /// detect increasing keys and use the append fast path while they last
+    #[default]
+    Auto,
+
+    /// caller promises increasing keys; out-of-order writes are rejected
+    Strict,
 }
 
 impl Default for LSMConfig {
@@ -26,17 +200,215 @@ impl Default for LSMConfig {
             target_file_size: 4 * 1024 * 1024,     // 4 MB
             block_size: 4096,                       // 4 KB
             block_cache_size: 4 * 1024 * 1024,     // 4 MB
+            cache_warm_min_hits: Some(2),
             bloom_bits_per_key: 10,                 // ~1% false positive
             max_levels: 5,                          // Supports ~400 MB
+            append_mode: AppendMode::Auto,
+            compaction_schedule: CompactionSchedule::default(),
+            periodic_compaction_seconds: None,
+            auto_compaction: true,
+            background_flush: true,
+            max_immutable_memtables: 2,
+            wal_sync: WalSyncPolicy::Never,
+            wal_recovery: WalRecoveryMode::TolerateCorruptedTail,
+            manifest_recovery: ManifestRecoveryMode::Fail,
+            max_disk_bytes: None,
+            compression: CompressionType::None,
+            inline_value_threshold: None,
+            prefix_filter_len: None,
+            prefix_filter_bits_per_prefix: 10,
+            max_manifest_file_size: 4 * 1024 * 1024, // 4 MB
+            paranoid_open: false,
+            skip_wal: false,
+            ignore_missing_files: false,
+            merge_operator: None,
+            ttl: None,
+            column_families: HashMap::new(),
+            statistics: None,
+            listeners: Vec::new(),
         }
     }
 }
 
+impl CompactionSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// defer compaction between `start` and `end` (offsets from UTC midnight)
+    pub fn with_window(mut self, start: Duration, end: Duration) -> Self {
+        self.windows.push((start, end));
+        self
+    }
+
+    /// defer compaction whenever `defer` returns true
+    pub fn with_defer_fn(mut self, defer: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        self.defer_fn = Some(Arc::new(defer));
+        self
+    }
+
+    /// L0 file count at which compaction runs regardless of the schedule
+    pub fn with_l0_override(mut self, l0_files: usize) -> Self {
+        self.l0_override = l0_files;
+        self
+    }
+
+    pub fn l0_override(&self) -> usize {
+        self.l0_override
+    }
+
+    /// true if automatic compaction should wait at `now` with `l0_files` in L0
+    pub fn should_defer(&self, now: SystemTime, l0_files: usize) -> bool {
+        if l0_files >= self.l0_override {
+            return false;
+        }
+
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let time_of_day = Duration::from_secs(since_epoch.as_secs() % SECONDS_PER_DAY);
+
+        let in_window = self.windows.iter().any(|&(start, end)| {
+            if start <= end {
+                time_of_day >= start && time_of_day < end
+            } else {
+                time_of_day >= start || time_of_day < end
+            }
+        });
+
+        in_window || self.defer_fn.as_ref().is_some_and(|defer| defer())
+    }
+}
+
+impl Default for CompactionSchedule {
+    fn default() -> Self {
+        Self {
+            windows: Vec::new(),
+            defer_fn: None,
+            l0_override: 12, // 4x the default L0 trigger
+        }
+    }
+}
+
+impl std::fmt::Debug for CompactionSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompactionSchedule")
+            .field("windows", &self.windows)
+            .field("defer_fn", &self.defer_fn.is_some())
+            .field("l0_override", &self.l0_override)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// the file isn't TOML, or a value has the wrong type or spelling
+    Parse(String),
+    /// settings that can't work together, see LSMConfig::validate
+    Invalid(String),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "Config I/O error: {}", e),
+            ConfigError::Parse(msg) => write!(f, "Config parse error: {}", msg),
+            ConfigError::Invalid(msg) => write!(f, "Invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// LSMConfigBuilder: LSMConfig::default() changed setting by setting, then
+/// checked by build()
+#[derive(Debug, Clone, Default)]
+pub struct LSMConfigBuilder {
+    config: LSMConfig,
+}
+
 impl LSMConfig {
     pub fn new() -> Self {
         Self::default()
     }
 
+    pub fn builder() -> LSMConfigBuilder {
+        LSMConfigBuilder::default()
+    }
+
+    /// load a config from a TOML file, see LSMConfig::from_toml_str
+    pub fn from_toml(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::from_toml_str(&fs::read_to_string(path)?)
+    }
+
+    /// a config from TOML text; keys are LSMConfig's field names and
+    /// missing ones keep their defaults
+    /// - compression is "none", "lz4", "snappy", "zstd" or "zstd:<level>"
+    /// - wal_sync is "always", "never" or an interval like "100ms"
+    /// - append_mode, wal_recovery and manifest_recovery are their variant
+    ///   names in snake case, e.g. "skip_any_corrupted"
+    /// - ttl is ttl_seconds; the compaction schedule, merge operator,
+    ///   statistics and listeners are code and can only be set on the result
+    /// - a [column_families.<name>] table starts from the settings above it
+    /// - unknown keys are an error, so typos don't go unnoticed
+    pub fn from_toml_str(text: &str) -> Result<Self, ConfigError> {
+        let file: ConfigFile =
+            toml::from_str(text).map_err(|e| ConfigError::Parse(e.message().to_string()))?;
+        let mut config = LSMConfig::default();
+        file.apply(&mut config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// check settings that are fine alone but not together, or not at all
+    /// - max_levels, l0_compaction_trigger, memtable_size and block_size
+    ///   are at least 1, level_multiplier at least 2
+    /// - block_size is at most target_file_size
+    /// - the compression codec is compiled in
+    /// - a prefix filter covers at least one byte
+    /// - the same holds for every column family's config
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+        let at_least = [
+            ("max_levels", self.max_levels, 1),
+            ("l0_compaction_trigger", self.l0_compaction_trigger, 1),
+            ("memtable_size", self.memtable_size, 1),
+            ("block_size", self.block_size, 1),
+            ("level_multiplier", self.level_multiplier, 2),
+        ];
+        for (name, value, min) in at_least {
+            if value < min {
+                return invalid(format!("{} is {}, it must be at least {}", name, value, min));
+            }
+        }
+        if self.block_size > self.target_file_size {
+            return invalid(format!(
+                "block_size {} is larger than target_file_size {}",
+                self.block_size, self.target_file_size
+            ));
+        }
+        if !self.compression.is_available() {
+            return invalid(format!("{:?} is not compiled in", self.compression));
+        }
+        if self.prefix_filter_len == Some(0) {
+            return invalid("prefix_filter_len is 0".to_string());
+        }
+        for (name, family) in &self.column_families {
+            family.validate().map_err(|e| match e {
+                ConfigError::Invalid(msg) => {
+                    ConfigError::Invalid(format!("column family {}: {}", name, msg))
+                }
+                e => e,
+            })?;
+        }
+        Ok(())
+    }
+
     pub fn max_level_size(&self, level: usize) -> u64 {
         if level == 0 {
             // L0 is based on number of files, not total size
@@ -46,6 +418,345 @@ impl LSMConfig {
                 * (self.level_multiplier as u64).pow(level as u32)
         }
     }
+
+    /// set one of MUTABLE_OPTIONS from its string form
+    /// - numbers are plain decimal; the optional ones also take "none"
+    /// - compression is "none", "lz4", "snappy", "zstd" or "zstd:<level>",
+    ///   and its codec must be compiled in
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let number = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} must be a number, not {:?}", name, value))
+        };
+        let optional = |value: &str| match value {
+            "none" => Ok(None),
+            _ => number(value).map(Some),
+        };
+        match name {
+            "memtable_size" => self.memtable_size = number(value)? as usize,
+            "l0_compaction_trigger" => self.l0_compaction_trigger = number(value)? as usize,
+            "level_multiplier" => self.level_multiplier = number(value)? as usize,
+            "target_file_size" => self.target_file_size = number(value)? as usize,
+            "bloom_bits_per_key" => self.bloom_bits_per_key = number(value)? as usize,
+            "max_immutable_memtables" => self.max_immutable_memtables = number(value)? as usize,
+            "max_disk_bytes" => self.max_disk_bytes = optional(value)?,
+            "periodic_compaction_seconds" => self.periodic_compaction_seconds = optional(value)?,
+            "cache_warm_min_hits" => self.cache_warm_min_hits = optional(value)?,
+            "compression" => {
+                let compression = parse_compression(value)?;
+                if !compression.is_available() {
+                    return Err(format!("{:?} is not compiled in", compression));
+                }
+                self.compression = compression;
+            }
+            _ => return Err(format!("{} can't be changed on an open database", name)),
+        }
+        Ok(())
+    }
+}
+
+impl LSMConfigBuilder {
+    pub fn with_memtable_size(mut self, bytes: usize) -> Self {
+        self.config.memtable_size = bytes;
+        self
+    }
+
+    pub fn with_l0_compaction_trigger(mut self, files: usize) -> Self {
+        self.config.l0_compaction_trigger = files;
+        self
+    }
+
+    pub fn with_level_multiplier(mut self, multiplier: usize) -> Self {
+        self.config.level_multiplier = multiplier;
+        self
+    }
+
+    pub fn with_target_file_size(mut self, bytes: usize) -> Self {
+        self.config.target_file_size = bytes;
+        self
+    }
+
+    pub fn with_block_size(mut self, bytes: usize) -> Self {
+        self.config.block_size = bytes;
+        self
+    }
+
+    pub fn with_block_cache_size(mut self, bytes: usize) -> Self {
+        self.config.block_cache_size = bytes;
+        self
+    }
+
+    pub fn with_cache_warm_min_hits(mut self, hits: Option<u64>) -> Self {
+        self.config.cache_warm_min_hits = hits;
+        self
+    }
+
+    pub fn with_bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.config.bloom_bits_per_key = bits;
+        self
+    }
+
+    pub fn with_max_levels(mut self, levels: usize) -> Self {
+        self.config.max_levels = levels;
+        self
+    }
+
+    pub fn with_append_mode(mut self, mode: AppendMode) -> Self {
+        self.config.append_mode = mode;
+        self
+    }
+
+    pub fn with_compaction_schedule(mut self, schedule: CompactionSchedule) -> Self {
+        self.config.compaction_schedule = schedule;
+        self
+    }
+
+    pub fn with_periodic_compaction_seconds(mut self, seconds: Option<u64>) -> Self {
+        self.config.periodic_compaction_seconds = seconds;
+        self
+    }
+
+    pub fn with_auto_compaction(mut self, enabled: bool) -> Self {
+        self.config.auto_compaction = enabled;
+        self
+    }
+
+    pub fn with_background_flush(mut self, enabled: bool) -> Self {
+        self.config.background_flush = enabled;
+        self
+    }
+
+    pub fn with_max_immutable_memtables(mut self, memtables: usize) -> Self {
+        self.config.max_immutable_memtables = memtables;
+        self
+    }
+
+    pub fn with_wal_sync(mut self, policy: WalSyncPolicy) -> Self {
+        self.config.wal_sync = policy;
+        self
+    }
+
+    pub fn with_wal_recovery(mut self, mode: WalRecoveryMode) -> Self {
+        self.config.wal_recovery = mode;
+        self
+    }
+
+    pub fn with_manifest_recovery(mut self, mode: ManifestRecoveryMode) -> Self {
+        self.config.manifest_recovery = mode;
+        self
+    }
+
+    pub fn with_max_disk_bytes(mut self, bytes: Option<u64>) -> Self {
+        self.config.max_disk_bytes = bytes;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    pub fn with_inline_value_threshold(mut self, bytes: Option<usize>) -> Self {
+        self.config.inline_value_threshold = bytes;
+        self
+    }
+
+    pub fn with_prefix_filter(mut self, prefix_len: usize, bits_per_prefix: usize) -> Self {
+        self.config.prefix_filter_len = Some(prefix_len);
+        self.config.prefix_filter_bits_per_prefix = bits_per_prefix;
+        self
+    }
+
+    pub fn with_max_manifest_file_size(mut self, bytes: u64) -> Self {
+        self.config.max_manifest_file_size = bytes;
+        self
+    }
+
+    pub fn with_paranoid_open(mut self, enabled: bool) -> Self {
+        self.config.paranoid_open = enabled;
+        self
+    }
+
+    pub fn with_skip_wal(mut self, enabled: bool) -> Self {
+        self.config.skip_wal = enabled;
+        self
+    }
+
+    pub fn with_ignore_missing_files(mut self, enabled: bool) -> Self {
+        self.config.ignore_missing_files = enabled;
+        self
+    }
+
+    pub fn with_merge_operator(mut self, operator: Arc<dyn MergeOperator>) -> Self {
+        self.config.merge_operator = Some(operator);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.config.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_statistics(mut self, statistics: Arc<Statistics>) -> Self {
+        self.config.statistics = Some(statistics);
+        self
+    }
+
+    pub fn with_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.config.listeners.push(listener);
+        self
+    }
+
+    pub fn with_column_family(mut self, name: &str, config: LSMConfig) -> Self {
+        self.config.column_families.insert(name.to_string(), config);
+        self
+    }
+
+    /// the config, once LSMConfig::validate passes
+    pub fn build(self) -> Result<LSMConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// the keys LSMConfig::from_toml_str reads, all optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    memtable_size: Option<usize>,
+    l0_compaction_trigger: Option<usize>,
+    level_multiplier: Option<usize>,
+    target_file_size: Option<usize>,
+    block_size: Option<usize>,
+    block_cache_size: Option<usize>,
+    cache_warm_min_hits: Option<u64>,
+    bloom_bits_per_key: Option<usize>,
+    max_levels: Option<usize>,
+    append_mode: Option<String>,
+    periodic_compaction_seconds: Option<u64>,
+    auto_compaction: Option<bool>,
+    background_flush: Option<bool>,
+    max_immutable_memtables: Option<usize>,
+    wal_sync: Option<String>,
+    wal_recovery: Option<String>,
+    manifest_recovery: Option<String>,
+    max_disk_bytes: Option<u64>,
+    compression: Option<String>,
+    inline_value_threshold: Option<usize>,
+    prefix_filter_len: Option<usize>,
+    prefix_filter_bits_per_prefix: Option<usize>,
+    max_manifest_file_size: Option<u64>,
+    paranoid_open: Option<bool>,
+    skip_wal: Option<bool>,
+    ignore_missing_files: Option<bool>,
+    ttl_seconds: Option<u64>,
+    #[serde(default)]
+    column_families: HashMap<String, ConfigFile>,
+}
+
+impl ConfigFile {
+    fn apply(self, config: &mut LSMConfig) -> Result<(), ConfigError> {
+        set(&mut config.memtable_size, self.memtable_size);
+        set(&mut config.l0_compaction_trigger, self.l0_compaction_trigger);
+        set(&mut config.level_multiplier, self.level_multiplier);
+        set(&mut config.target_file_size, self.target_file_size);
+        set(&mut config.block_size, self.block_size);
+        set(&mut config.block_cache_size, self.block_cache_size);
+        set(&mut config.bloom_bits_per_key, self.bloom_bits_per_key);
+        set(&mut config.max_levels, self.max_levels);
+        set(&mut config.auto_compaction, self.auto_compaction);
+        set(&mut config.background_flush, self.background_flush);
+        set(&mut config.max_immutable_memtables, self.max_immutable_memtables);
+        set(&mut config.prefix_filter_bits_per_prefix, self.prefix_filter_bits_per_prefix);
+        set(&mut config.max_manifest_file_size, self.max_manifest_file_size);
+        set(&mut config.paranoid_open, self.paranoid_open);
+        set(&mut config.skip_wal, self.skip_wal);
+        set(&mut config.ignore_missing_files, self.ignore_missing_files);
+        set(&mut config.cache_warm_min_hits, self.cache_warm_min_hits.map(Some));
+        set(&mut config.periodic_compaction_seconds, self.periodic_compaction_seconds.map(Some));
+        set(&mut config.max_disk_bytes, self.max_disk_bytes.map(Some));
+        set(&mut config.inline_value_threshold, self.inline_value_threshold.map(Some));
+        set(&mut config.prefix_filter_len, self.prefix_filter_len.map(Some));
+        set(&mut config.ttl, self.ttl_seconds.map(|s| Some(Duration::from_secs(s))));
+        let parse = ConfigError::Parse;
+        if let Some(value) = &self.compression {
+            config.compression = parse_compression(value).map_err(parse)?;
+        }
+        if let Some(value) = &self.append_mode {
+            config.append_mode = match value.as_str() {
+                "off" => AppendMode::Off,
+                "auto" => AppendMode::Auto,
+                "strict" => AppendMode::Strict,
+                _ => return Err(parse(format!("unknown append_mode {:?}", value))),
+            };
+        }
+        if let Some(value) = &self.wal_sync {
+            config.wal_sync = parse_wal_sync(value).map_err(parse)?;
+        }
+        if let Some(value) = &self.wal_recovery {
+            config.wal_recovery = match value.as_str() {
+                "tolerate_corrupted_tail" => WalRecoveryMode::TolerateCorruptedTail,
+                "absolute_consistency" => WalRecoveryMode::AbsoluteConsistency,
+                "skip_any_corrupted" => WalRecoveryMode::SkipAnyCorrupted,
+                _ => return Err(parse(format!("unknown wal_recovery {:?}", value))),
+            };
+        }
+        if let Some(value) = &self.manifest_recovery {
+            config.manifest_recovery = match value.as_str() {
+                "fail" => ManifestRecoveryMode::Fail,
+                "rebuild_from_tables" => ManifestRecoveryMode::RebuildFromTables,
+                _ => return Err(parse(format!("unknown manifest_recovery {:?}", value))),
+            };
+        }
+
+        let mut families = HashMap::new();
+        for (name, file) in self.column_families {
+            let mut family = LSMConfig {
+                column_families: HashMap::new(),
+                ..config.clone()
+            };
+            file.apply(&mut family)?;
+            if !family.column_families.is_empty() {
+                let msg = format!("column family {} has column families of its own", name);
+                return Err(ConfigError::Parse(msg));
+            }
+            families.insert(name, family);
+        }
+        config.column_families.extend(families);
+        Ok(())
+    }
+}
+
+/// replace `field` with `value`, if there is one
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+fn parse_wal_sync(value: &str) -> Result<WalSyncPolicy, String> {
+    match value {
+        "always" => Ok(WalSyncPolicy::Always),
+        "never" => Ok(WalSyncPolicy::Never),
+        _ => match value.strip_suffix("ms").map(str::parse) {
+            Some(Ok(millis)) => Ok(WalSyncPolicy::EveryNMillis(millis)),
+            _ => Err(format!("unknown wal_sync {:?}", value)),
+        },
+    }
+}
+
+fn parse_compression(value: &str) -> Result<CompressionType, String> {
+    match value {
+        "none" => Ok(CompressionType::None),
+        "lz4" => Ok(CompressionType::Lz4),
+        "snappy" => Ok(CompressionType::Snappy),
+        "zstd" => Ok(CompressionType::Zstd(3)),
+        _ => match value.strip_prefix("zstd:").map(str::parse) {
+            Some(Ok(level)) => Ok(CompressionType::Zstd(level)),
+            _ => Err(format!("unknown compression {:?}", value)),
+        },
+    }
 }
 
 #[cfg(test)]
@@ -73,4 +784,131 @@ mod tests {
         // L2: 4 MB × 10^2 = 400 MB
         assert_eq!(config.max_level_size(2), 400 * 1024 * 1024);
     }
+
+    #[test]
+    fn test_compaction_schedule_windows() {
+        let hour = |h: u64| Duration::from_secs(h * 3600);
+        let at = |h: u64| UNIX_EPOCH + hour(24 * 365 + h);
+
+        let schedule = CompactionSchedule::new()
+            .with_window(hour(9), hour(17))
+            .with_window(hour(22), hour(2)); // wraps midnight
+
+        assert!(!schedule.should_defer(at(8), 0));
+        assert!(schedule.should_defer(at(9), 0));
+        assert!(schedule.should_defer(at(16), 0));
+        assert!(!schedule.should_defer(at(17), 0));
+        assert!(schedule.should_defer(at(23), 0));
+        assert!(schedule.should_defer(at(1), 0));
+        assert!(!schedule.should_defer(at(2), 0));
+
+        assert!(!CompactionSchedule::default().should_defer(at(12), 0));
+    }
+
+    #[test]
+    fn test_compaction_schedule_l0_override() {
+        let schedule = CompactionSchedule::new()
+            .with_defer_fn(|| true)
+            .with_l0_override(5);
+
+        assert!(schedule.should_defer(SystemTime::now(), 4));
+        assert!(!schedule.should_defer(SystemTime::now(), 5));
+    }
+
+    #[test]
+    fn test_set_option() {
+        let mut config = LSMConfig::default();
+        config.set_option("memtable_size", "8388608").unwrap();
+        config.set_option("max_disk_bytes", "1000").unwrap();
+        config.set_option("compression", "none").unwrap();
+        assert_eq!(config.memtable_size, 8 * 1024 * 1024);
+        assert_eq!(config.max_disk_bytes, Some(1000));
+        config.set_option("max_disk_bytes", "none").unwrap();
+        assert_eq!(config.max_disk_bytes, None);
+        assert_eq!(parse_compression("zstd:7"), Ok(CompressionType::Zstd(7)));
+
+        assert!(config.set_option("memtable_size", "8MB").is_err());
+        assert!(config.set_option("compression", "gzip").is_err());
+        assert!(config.set_option("max_levels", "7").is_err());
+        assert!(config.set_option("no_such_option", "1").is_err());
+        for name in MUTABLE_OPTIONS {
+            let result = config.set_option(name, "2");
+            assert!(result.is_ok() || *name == "compression", "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = LSMConfig::builder()
+            .with_memtable_size(8 * 1024 * 1024)
+            .with_max_levels(7)
+            .with_wal_sync(WalSyncPolicy::Always)
+            .build()
+            .unwrap();
+        assert_eq!(config.memtable_size, 8 * 1024 * 1024);
+        assert_eq!(config.max_levels, 7);
+        assert_eq!(config.wal_sync, WalSyncPolicy::Always);
+
+        let invalid = |builder: LSMConfigBuilder| match builder.build() {
+            Err(ConfigError::Invalid(msg)) => msg,
+            other => panic!("expected ConfigError::Invalid, got {:?}", other),
+        };
+        assert!(invalid(LSMConfig::builder().with_max_levels(0)).contains("max_levels"));
+        let big_blocks = LSMConfig::builder()
+            .with_block_size(64 * 1024)
+            .with_target_file_size(16 * 1024);
+        assert!(invalid(big_blocks).contains("target_file_size"));
+        let family = LSMConfig {
+            level_multiplier: 1,
+            ..LSMConfig::default()
+        };
+        let with_family = LSMConfig::builder().with_column_family("users", family);
+        assert!(invalid(with_family).starts_with("column family users"));
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let config = LSMConfig::from_toml_str(
+            r#"
+            memtable_size = 8388608
+            max_disk_bytes = 1073741824
+            compression = "none"
+            wal_sync = "100ms"
+            wal_recovery = "skip_any_corrupted"
+            ttl_seconds = 3600
+
+            [column_families.logs]
+            append_mode = "strict"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.memtable_size, 8 * 1024 * 1024);
+        assert_eq!(config.max_disk_bytes, Some(1 << 30));
+        assert_eq!(config.wal_sync, WalSyncPolicy::EveryNMillis(100));
+        assert_eq!(config.wal_recovery, WalRecoveryMode::SkipAnyCorrupted);
+        assert_eq!(config.ttl, Some(Duration::from_secs(3600)));
+        assert_eq!(config.block_size, 4096);
+
+        // families start from the settings above them
+        let logs = &config.column_families["logs"];
+        assert_eq!(logs.append_mode, AppendMode::Strict);
+        assert_eq!(logs.memtable_size, 8 * 1024 * 1024);
+
+        let parse_error = |text: &str| {
+            matches!(LSMConfig::from_toml_str(text), Err(ConfigError::Parse(_)))
+        };
+        assert!(parse_error("memtable_sise = 1"));
+        assert!(parse_error("memtable_size = \"big\""));
+        assert!(parse_error("wal_sync = \"sometimes\""));
+        assert!(matches!(
+            LSMConfig::from_toml_str("max_levels = 0"),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        let path = std::env::temp_dir().join("test_config_from_toml.toml");
+        fs::write(&path, "max_levels = 3").unwrap();
+        assert_eq!(LSMConfig::from_toml(&path).unwrap().max_levels, 3);
+        fs::remove_file(&path).ok();
+        assert!(matches!(LSMConfig::from_toml(&path), Err(ConfigError::Io(_))));
+    }
 }
@@ -0,0 +1,6716 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::batch::{BatchOp, WriteBatch};
+use super::cache::{CacheStats, TableCache};
+use super::compaction::{
+    CompactionTask, compaction_debt, pick_compaction, range_task, run_compaction,
+};
+use super::config::{AppendMode, ConfigError, LSMConfig, WalSyncPolicy};
+use super::external::ExternalTable;
+use super::family::{self, ColumnFamily, DEFAULT_FAMILY};
+use super::job::{CancelToken, JobHandle};
+use super::listener::{
+    CompactionJobInfo, EventListener, FlushJobInfo, TableFileDeletedInfo, TableFileInfo,
+    WalSyncInfo,
+};
+use super::iterator::{
+    above_lower, below_upper, prefix_end, DbIterator, EntrySource, MergeIterator, VersionEntry,
+};
+use super::manifest::{
+    FamilyManifest, Manifest, ManifestError, ManifestRecoveryMode, SSTableMetadata,
+};
+use super::memtable::Memtable;
+use super::merge::{MergeFold, StoredValue};
+use super::options::{FlushOptions, ReadOptions, WriteOptions};
+use super::options_file::{OPTIONS_FILE, OptionsFile};
+use super::snapshot::{CommitToken, Snapshot, SnapshotList};
+use super::stats::{Latency, Statistics, Ticker};
+use super::status::{
+    AmplificationReport, CompactionStatus, DbStatus, FamilyStatus, LevelStatus,
+};
+use super::subscription::{CONSUMER_FAMILY, Subscription};
+use super::tailing::TailingIterator;
+use super::sstable::block::BlockError;
+use super::sstable::{SSTableError, SSTableReader, SSTableWriter};
+use super::sstable::table::{unix_now, TableIterator, DEFAULT_RESTART_INTERVAL};
+use super::range_del::{RangeTombstone, RangeTombstones};
+use super::ttl::{self, Expiry};
+use super::version_edit::{self, ManifestLog};
+use super::wal::{
+    self, GroupCommit, WalEntry, WalError, WalReader, WalRecoveryMode, WalWriter,
+};
+
+/// manifests from before CURRENT, newest format first: a lone manifest log,
+/// and the whole-manifest JSON before it; converted on open
+pub(crate) const LEGACY_MANIFEST_FILES: [&str; 2] = ["MANIFEST", "MANIFEST.json"];
+
+/// locked while the database is open, so a second open fails
+pub(crate) const LOCK_FILE: &str = "LOCK";
+
+/// where flushed WAL segments wait for change feed consumers, see
+/// Subscription; they are never replayed
+const WAL_ARCHIVE_DIR: &str = "archive";
+
+/// restart interval for tables flushed from purely sequential memtables;
+/// scans dominate those workloads, so fewer restarts beat faster seeks
+const APPEND_RESTART_INTERVAL: usize = 128;
+
+/// how long the compaction thread sleeps when no flush wakes it, so
+/// schedule windows and periodic compaction are rechecked
+const COMPACTION_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// background failures kept for DB::status
+const MAX_BACKGROUND_ERRORS: usize = 16;
+
+/// recent gets averaged into the read amplification of amplification_report()
+const READ_AMP_WINDOW: usize = 1024;
+
+/// key range of a manual compaction, as requested
+type OwnedRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// batches kept for take_batch; larger buffers are dropped instead of pooled
+const MAX_POOLED_BATCHES: usize = 16;
+const MAX_POOLED_BATCH_CAPACITY: usize = 1024 * 1024;
+
+/// LSM tree key-value store
+/// - writes go to the WAL, then the memtable
+/// - a full memtable is frozen along with its WAL and queued for a flush
+///   thread, which writes it to an L0 SSTable and then drops that WAL
+/// - reads check the memtable, then frozen memtables newest-first, then L0
+///   newest-first, then deeper levels
+/// - every write gets a sequence number; snapshots read as of one
+/// - a background thread compacts L0 into deeper levels
+/// - column families (DB::cf) keep their own memtables and tables; plain
+///   reads and writes use the default family
+pub struct DB {
+    path: PathBuf,
+
+    shared: Arc<Shared>,
+
+    compactor: Option<JoinHandle<()>>,
+
+    flusher: Option<JoinHandle<()>>,
+
+    /// syncs the WAL on a timer under WalSyncPolicy::EveryNMillis
+    syncer: Option<JoinHandle<()>>,
+
+    /// cleared batches returned by write(), handed out again by take_batch()
+    batch_pool: Mutex<Vec<WriteBatch>>,
+
+    /// threads running flush_async and compact_range_async jobs
+    job_threads: Mutex<Vec<JoinHandle<()>>>,
+
+    /// shut down by close(), so drop has nothing left to do
+    closed: bool,
+
+    /// LOCK, locked until shutdown; None when opened read-only
+    lock_file: Option<fs::File>,
+
+    /// opened with DB::open_read_only
+    read_only: bool,
+}
+
+/// state shared with the flush and compaction threads
+struct Shared {
+    inner: Mutex<DbInner>,
+
+    snapshots: Arc<SnapshotList>,
+
+    /// held while a compaction runs so only one runs at a time
+    compaction: Mutex<()>,
+
+    /// wakes the compaction thread after a flush or on shutdown
+    compaction_signal: Condvar,
+
+    /// held while a frozen memtable is written out, so tables land in order
+    flush: Mutex<()>,
+
+    /// wakes the flush thread after a memtable is frozen or on shutdown
+    flush_signal: Condvar,
+
+    /// wakes writers stalled on a full queue of frozen memtables
+    flush_done: Condvar,
+
+    /// wakes pause_background_work once no background flush or compaction
+    /// is running
+    background_idle: Condvar,
+
+    /// wakes reads waiting for a commit token to become visible
+    write_signal: Condvar,
+
+    /// fsyncs for synchronous writes, positioned by sequence number
+    group_commit: GroupCommit,
+
+    /// wakes the WAL sync thread on shutdown
+    sync_signal: Condvar,
+
+    /// manual jobs requested but not started yet, which new requests join
+    pending_jobs: Mutex<PendingJobs>,
+
+    /// held while a manual flush job runs, so flush jobs start one at a time
+    flush_jobs: Mutex<()>,
+
+    /// held while a manual compaction job runs
+    compaction_jobs: Mutex<()>,
+
+    /// cancelled on shutdown; every flush and compaction checks it, and
+    /// manual jobs check children of it
+    cancel: CancelToken,
+
+    /// LSMConfig::statistics
+    statistics: Option<Arc<Statistics>>,
+
+    /// LSMConfig::listeners
+    listeners: Vec<Arc<dyn EventListener>>,
+
+    /// open tables for reads, sized by LSMConfig::block_cache_size
+    table_cache: TableCache,
+}
+
+/// manual jobs that haven't started; once one starts, a new request for
+/// the same work may need data it didn't see, so it queues a new job
+#[derive(Default)]
+struct PendingJobs {
+    flush: Option<JobHandle>,
+
+    /// by column family and range
+    compactions: Vec<(u32, OwnedRange, JobHandle)>,
+}
+
+/// what a scan read, so a TailingIterator can tell whether the tables it
+/// reads are still current
+pub(crate) struct ScanState {
+    /// ids of the tables read
+    tables: Vec<u64>,
+
+    memtables: usize,
+
+    memtable_tombstones: usize,
+
+    /// range tombstones of every source
+    tombstones: Arc<RangeTombstones>,
+}
+
+struct DbInner {
+    /// the DB's config as DB::set_options left it, which flushes,
+    /// compactions, reads and writes go by
+    config: LSMConfig,
+
+    /// the default family's; its sequence numbers count writes to every
+    /// family
+    memtable: Memtable,
+
+    /// column families besides the default one
+    families: Vec<Family>,
+
+    /// None when opened read-only
+    wal: Option<WalWriter>,
+
+    /// frozen memtables waiting for a flush, oldest first
+    immutables: Vec<Immutable>,
+
+    /// last sequence number acked by every change feed consumer, by name
+    consumers: HashMap<String, u64>,
+
+    /// flushed WAL segments kept for consumers, with the last sequence
+    /// number in each, oldest first
+    archived_wals: Vec<(PathBuf, u64)>,
+
+    /// last background flush failure; stalled writers report it
+    flush_error: Option<String>,
+
+    /// recent flush and compaction failures for the status page, oldest first
+    background_errors: VecDeque<String>,
+
+    /// the compaction being run right now, if any
+    active_compaction: Option<CompactionStatus>,
+
+    manifest: Manifest,
+
+    /// where manifest changes are committed, see DbInner::commit_manifest
+    /// None when opened read-only, like `wal`
+    manifest_log: Option<ManifestLog>,
+
+    /// ids of tables still being written; obsolete file GC leaves them alone
+    pending_outputs: HashSet<u64>,
+
+    /// ids of tables a checkpoint or backup is copying, with how many are;
+    /// obsolete file GC leaves them alone too
+    pinned_tables: HashMap<u64, usize>,
+
+    /// largest key written so far, drives append-mode detection
+    max_key: Option<Vec<u8>>,
+
+    /// true while every memtable insert has been an append
+    memtable_sequential: bool,
+
+    append_stats: AppendStats,
+
+    read_stats: ReadStats,
+
+    amplification: AmplificationStats,
+
+    /// a flush happened since the compaction thread last looked
+    compaction_pending: bool,
+
+    /// pause_background_work calls not yet undone; the flush and compaction
+    /// threads start nothing while it isn't 0
+    background_paused: usize,
+
+    /// flush and compaction thread runs in progress
+    background_jobs: usize,
+
+    shutdown: bool,
+}
+
+/// a column family besides the default one, see ColumnFamily
+struct Family {
+    id: u32,
+
+    name: String,
+
+    /// its LSMConfig::column_families entry, or the DB's config
+    config: LSMConfig,
+
+    memtable: Memtable,
+}
+
+/// a full memtable and the WAL file that can rebuild it
+#[derive(Clone)]
+struct Immutable {
+    memtable: Arc<Memtable>,
+
+    /// memtables of the other column families frozen with it, the empty
+    /// ones left out
+    families: Vec<(u32, Arc<Memtable>)>,
+
+    wal_path: PathBuf,
+
+    /// every insert was an append, see DbInner::memtable_sequential
+    sequential: bool,
+}
+
+/// counters for point reads that reached the SSTables
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    /// tables whose bloom filter a point read consulted
+    pub tables_probed: u64,
+
+    /// probes the bloom filter answered without reading a block
+    pub bloom_negatives: u64,
+
+    /// tables skipped by their manifest prefix filter without being opened
+    pub prefix_filter_skips: u64,
+}
+
+/// bytes and block reads behind DB::amplification_report(), since open
+#[derive(Default)]
+struct AmplificationStats {
+    /// keys and values handed to writes
+    user_bytes: u64,
+
+    /// WAL records, flushed tables and compaction outputs
+    disk_bytes: u64,
+
+    /// data blocks read by each of the last READ_AMP_WINDOW gets
+    recent_gets: VecDeque<u64>,
+
+    /// sum of recent_gets
+    recent_blocks: u64,
+}
+
+/// outcome of DB::purge_namespace
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    pub deleted_keys: usize,
+
+    /// tables and memtables before and after, as counted against the quota
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+
+    pub reclaimed_bytes: u64,
+}
+
+/// counters for the increasing-key (append) fast path
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppendStats {
+    /// puts whose key sorted after every key written before
+    pub in_order: u64,
+
+    /// puts that broke the increasing-key assumption
+    pub out_of_order: u64,
+
+    /// first key that arrived out of order, if any
+    pub first_out_of_order: Option<Vec<u8>>,
+}
+
+/// what DB::live_files found; unpins its tables when dropped
+pub(crate) struct LiveFiles<'a> {
+    db: &'a DB,
+
+    /// every family's tables, with nothing pending
+    pub manifest: Manifest,
+}
+
+impl LiveFiles<'_> {
+    /// where the database keeps `sst`
+    pub fn table_path(&self, sst: &SSTableMetadata) -> PathBuf {
+        self.db.path.join(&sst.path)
+    }
+}
+
+impl Drop for LiveFiles<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.db.lock();
+        for sst in self.manifest.all_tables() {
+            if let Some(count) = inner.pinned_tables.get_mut(&sst.id) {
+                *count -= 1;
+                if *count == 0 {
+                    inner.pinned_tables.remove(&sst.id);
+                }
+            }
+        }
+        drop(inner);
+        // whatever compaction replaced in the meantime; read-only, nothing
+        // is deleted
+        purge_obsolete_files(&self.db.path, &self.db.shared).ok();
+    }
+}
+
+/// estimated size of a key range, see DB::approximate_size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApproximateSize {
+    /// table bytes holding the range's keys
+    pub bytes: u64,
+
+    /// versions and tombstones among them
+    pub entries: u64,
+}
+
+/// what DB::repair recovered, and what it couldn't
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// tables the new manifest lists once the WAL is flushed, over every
+    /// column family
+    pub tables: usize,
+
+    /// column families found, named after their directories
+    pub families: Vec<String>,
+
+    /// manifests and unreadable tables, renamed to `<name>.corrupt`
+    pub set_aside: Vec<PathBuf>,
+
+    /// WAL records and tables lost on the way, as DbStatus reports them
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Io(io::Error),
+    Wal(WalError),
+    Manifest(ManifestError),
+    Block(BlockError),
+    SSTable(SSTableError),
+    Memtable(String),
+    Flush(String),
+    /// a read's min_token wasn't visible in time, or never can be at its snapshot
+    TokenNotVisible(u64),
+    Corrupted(String),
+    OutOfOrder(Vec<u8>),
+    /// a put would grow the database past LSMConfig::max_disk_bytes
+    QuotaExceeded { used: u64, limit: u64 },
+    /// a put would grow a column family past the max_disk_bytes of its
+    /// LSMConfig::column_families entry
+    FamilyQuotaExceeded { family: String, used: u64, limit: u64 },
+    /// a flush or compaction job this request joined failed
+    Job(String),
+    /// the database is open, in this process or another
+    AlreadyLocked(PathBuf),
+    /// the config can't read what the OPTIONS file says was written
+    IncompatibleOptions(String),
+    /// DB::set_options got a setting it can't change or a value it can't
+    /// parse
+    InvalidOption(String),
+    /// rename without overwrite found its destination taken
+    KeyExists(Vec<u8>),
+    /// the operation's CancelToken was cancelled, e.g. by shutdown
+    Cancelled,
+    NoSuchFamily(String),
+    FamilyExists(String),
+    /// a ColumnFamily was used after DB::drop_cf dropped its family
+    FamilyDropped(u32),
+    /// a merge was written or read without LSMConfig::merge_operator
+    NoMergeOperator,
+    /// DB::ingest was handed files it can't take in
+    Ingest(String),
+    /// a read passed ReadOptions::deadline before it was answered
+    DeadlineExceeded,
+    /// a write, flush or compaction on a DB::open_read_only database
+    ReadOnly,
+    /// DB::ack for a consumer that never subscribed or unsubscribed
+    NoSuchConsumer(String),
+}
+
+impl From<io::Error> for DbError {
+    fn from(err: io::Error) -> Self {
+        DbError::Io(err)
+    }
+}
+
+impl From<WalError> for DbError {
+    fn from(err: WalError) -> Self {
+        DbError::Wal(err)
+    }
+}
+
+impl From<ManifestError> for DbError {
+    fn from(err: ManifestError) -> Self {
+        DbError::Manifest(err)
+    }
+}
+
+impl From<BlockError> for DbError {
+    fn from(err: BlockError) -> Self {
+        DbError::Block(err)
+    }
+}
+
+impl From<SSTableError> for DbError {
+    fn from(err: SSTableError) -> Self {
+        DbError::SSTable(err)
+    }
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Io(e) => write!(f, "DB I/O error: {}", e),
+            DbError::Wal(e) => write!(f, "{}", e),
+            DbError::Manifest(e) => write!(f, "{}", e),
+            DbError::Block(e) => write!(f, "{}", e),
+            DbError::SSTable(e) => write!(f, "{}", e),
+            DbError::Memtable(msg) => write!(f, "Memtable error: {}", msg),
+            DbError::Flush(msg) => write!(f, "Background flush failed: {}", msg),
+            DbError::TokenNotVisible(seq) => {
+                write!(f, "Commit token {} is not visible to this read", seq)
+            }
+            DbError::Corrupted(msg) => write!(f, "DB corrupted: {}", msg),
+            DbError::OutOfOrder(key) => write!(
+                f,
+                "Key {:?} is not greater than the last key (strict append mode)",
+                String::from_utf8_lossy(key)
+            ),
+            DbError::QuotaExceeded { used, limit } => {
+                write!(f, "Disk quota exceeded: {} of {} bytes used", used, limit)
+            }
+            DbError::FamilyQuotaExceeded { family, used, limit } => write!(
+                f,
+                "Disk quota of column family {} exceeded: {} of {} bytes used",
+                family, used, limit
+            ),
+            DbError::Job(msg) => write!(f, "Job failed: {}", msg),
+            DbError::AlreadyLocked(path) => {
+                write!(f, "Database {} is already open", path.display())
+            }
+            DbError::IncompatibleOptions(msg) => write!(f, "Incompatible options: {}", msg),
+            DbError::InvalidOption(msg) => write!(f, "Invalid option: {}", msg),
+            DbError::KeyExists(key) => {
+                write!(f, "Key {:?} already exists", String::from_utf8_lossy(key))
+            }
+            DbError::Cancelled => write!(f, "Operation cancelled"),
+            DbError::NoSuchFamily(name) => write!(f, "No column family {:?}", name),
+            DbError::FamilyExists(name) => write!(f, "Column family {:?} already exists", name),
+            DbError::FamilyDropped(id) => write!(f, "Column family {} was dropped", id),
+            DbError::NoMergeOperator => write!(f, "No merge operator configured"),
+            DbError::Ingest(msg) => write!(f, "Ingest failed: {}", msg),
+            DbError::DeadlineExceeded => write!(f, "Read deadline exceeded"),
+            DbError::ReadOnly => write!(f, "Database is open read-only"),
+            DbError::NoSuchConsumer(name) => write!(f, "No change feed consumer {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+pub type Result<T> = std::result::Result<T, DbError>;
+
+impl DB {
+    /// delete the database in `path`, then the directory unless something
+    /// else is left in it
+    /// - only files the database writes go: tables, WAL segments, manifests,
+    ///   column family directories and whatever repairs set aside
+    /// - fails with AlreadyLocked if the database is open
+    pub fn destroy(path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(());
+        }
+        let _lock = lock_dir(path)?;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?.path();
+            let Some(name) = entry.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if entry.is_dir() && family::parse_family_dir_name(name).is_some() {
+                for table in fs::read_dir(&entry)? {
+                    let table = table?.path();
+                    let name = table.file_name().and_then(|name| name.to_str());
+                    if name.is_some_and(is_database_file) {
+                        fs::remove_file(table)?;
+                    }
+                }
+                fs::remove_dir(&entry).ok();
+            } else if is_database_file(name) {
+                fs::remove_file(entry)?;
+            }
+        }
+        fs::remove_dir(path).ok();
+        Ok(())
+    }
+
+    /// rebuild the database in `path` when its manifest is lost or damaged,
+    /// then open it with `config` to replay the WAL
+    /// - every manifest is set aside, and a new one is rebuilt from the
+    ///   tables, see Manifest::rebuild; tables go into L0 and unreadable ones
+    ///   are set aside too
+    /// - column family directories come back as families named after them,
+    ///   e.g. `cf-1`, since only the manifest knew their names
+    /// - WAL segments are replayed under WalRecoveryMode::SkipAnyCorrupted,
+    ///   so damaged records are skipped, and flushed into tables
+    /// - fails with AlreadyLocked if the database is open
+    pub fn repair(path: impl AsRef<Path>, config: LSMConfig) -> Result<RepairReport> {
+        let path = path.as_ref();
+        let lock = lock_dir(path)?;
+        let mut report = RepairReport::default();
+        let mut number = 0;
+        let mut families = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?.path();
+            let Some(name) = entry.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if let Some(id) = family::parse_family_dir_name(name).filter(|_| entry.is_dir()) {
+                families.push(id);
+            } else if let Some(n) = version_edit::parse_manifest_file_name(name) {
+                number = number.max(n);
+                set_aside(&entry);
+                report.set_aside.push(entry);
+            } else if LEGACY_MANIFEST_FILES.contains(&name) {
+                set_aside(&entry);
+                report.set_aside.push(entry);
+            }
+        }
+        families.sort_unstable();
+
+        let (mut manifest, mut skipped) = Manifest::rebuild(path, config.max_levels)?;
+        for id in families {
+            let dir = family::family_dir_name(id);
+            let (mut tables, lost) = Manifest::rebuild(path.join(&dir), config.max_levels)?;
+            skipped.extend(lost);
+            for sst in tables.levels.iter_mut().flat_map(|level| &mut level.sstables) {
+                sst.path = dir.join(&sst.path);
+            }
+            manifest.next_sstable_id = manifest.next_sstable_id.max(tables.next_sstable_id);
+            manifest.last_sequence = manifest.last_sequence.max(tables.last_sequence);
+            manifest.next_family_id = manifest.next_family_id.max(id + 1);
+            let name = dir.display().to_string();
+            report.families.push(name.clone());
+            manifest.families.push(FamilyManifest { id, name, tables });
+        }
+        for (table, _) in skipped {
+            // GC would delete a table the manifest doesn't list
+            set_aside(&table);
+            report.set_aside.push(table);
+        }
+        // new WAL segments must not take the number of one still on disk
+        let newest_segment = wal_segments(path)?.into_iter().filter_map(|(number, _)| number).max();
+        if let Some(newest) = newest_segment {
+            manifest.wal_seq = manifest.wal_seq.max(newest + 1);
+        }
+        ManifestLog::create_current(path, number + 1, &manifest)?;
+        drop(lock);
+
+        let config = LSMConfig {
+            wal_recovery: WalRecoveryMode::SkipAnyCorrupted,
+            manifest_recovery: ManifestRecoveryMode::Fail,
+            ..config
+        };
+        let db = DB::open(path, config)?;
+        db.flush()?;
+        report.tables = db.lock().manifest.all_tables().count();
+        report.errors = db.status().background_errors;
+        db.close()?;
+        Ok(report)
+    }
+
+    /// open the database in `path`, creating it if needed and replaying the WAL
+    pub fn open(path: impl AsRef<Path>, config: LSMConfig) -> Result<Self> {
+        Self::open_with(path, config, false)
+    }
+
+    /// open the database in `path` for reading only, e.g. a checkpoint
+    /// - nothing in the directory is written, renamed or deleted: the WAL is
+    ///   replayed into memory, a torn tail left in place, and OPTIONS and the
+    ///   manifest are only read
+    /// - writes, flushes, compactions and anything else that would change
+    ///   the files fail with ReadOnly; no background threads run
+    /// - the directory isn't locked, so a writer may have it open too; this
+    ///   DB keeps seeing what was there when it opened
+    pub fn open_read_only(path: impl AsRef<Path>, config: LSMConfig) -> Result<Self> {
+        Self::open_with(path, config, true)
+    }
+
+    fn open_with(path: impl AsRef<Path>, config: LSMConfig, read_only: bool) -> Result<Self> {
+        if !config.compression.is_available() {
+            return Err(DbError::SSTable(SSTableError::Compression(format!(
+                "{:?} support is not compiled in",
+                config.compression
+            ))));
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let lock_file = if read_only {
+            fs::metadata(&path)?;
+            None
+        } else {
+            fs::create_dir_all(&path)?;
+            Some(lock_dir(&path)?)
+        };
+        let recorded = OptionsFile::load(&path)?;
+        if let Some(options) = &recorded {
+            options.check(&config).map_err(DbError::IncompatibleOptions)?;
+        }
+        if !read_only {
+            OptionsFile::succeeding(&config, recorded.as_ref()).save(&path)?;
+        }
+
+        // writes lost to damaged records show up on the status page
+        let mut background_errors = VecDeque::new();
+        let (mut manifest, mut manifest_log) = if read_only {
+            (read_manifest(&path, &config)?, None)
+        } else {
+            let (manifest, log) = open_manifest(&path, &config, &mut background_errors)?;
+            (manifest, Some(log.with_max_size(config.max_manifest_file_size)))
+        };
+        let log = manifest_log.as_mut();
+        check_tables(&path, &config, &mut manifest, log, &mut background_errors)?;
+
+        // every live segment belongs to a memtable that never made it to a table;
+        // all but the newest are sealed and go straight onto the flush queue
+        let mut segments = wal_segments(&path)?;
+        if config.skip_wal && !segments.is_empty() {
+            for (number, segment) in &segments {
+                if let Some(number) = number {
+                    manifest.wal_seq = manifest.wal_seq.max(number + 1);
+                }
+                if !read_only {
+                    set_aside(segment);
+                }
+            }
+            let error = format!("Skipped WAL replay of {} segments", segments.len());
+            push_error(&mut background_errors, error);
+            segments.clear();
+        }
+        let active = match segments.last() {
+            Some(&(Some(number), _)) => segments.pop().map(|(_, segment)| (number, segment)),
+            _ => None,
+        };
+
+        let mut last_sequence = manifest.last_sequence;
+        let mut families: Vec<Family> = manifest
+            .families
+            .iter()
+            .map(|family| Family::new(family.id, &family.name, &config, last_sequence))
+            .collect();
+        let mut immutables = Vec::new();
+        for (number, wal_path) in segments {
+            let errors = &mut background_errors;
+            let (memtable, frozen) =
+                replay_wal(&wal_path, &config, &families, last_sequence, read_only, errors)?;
+            last_sequence = memtable.seq_num();
+            if let Some(number) = number {
+                manifest.wal_seq = manifest.wal_seq.max(number + 1);
+            }
+            let ids = families.iter().map(|family| family.id);
+            immutables.push(Immutable {
+                memtable: Arc::new(memtable),
+                families: ids
+                    .zip(frozen)
+                    .filter(|(_, memtable)| !memtable.is_empty())
+                    .map(|(id, memtable)| (id, Arc::new(memtable)))
+                    .collect(),
+                wal_path,
+                sequential: false,
+            });
+        }
+
+        let (memtable, wal) = match active {
+            Some((number, wal_path)) => {
+                manifest.wal_seq = manifest.wal_seq.max(number + 1);
+                let errors = &mut background_errors;
+                let (memtable, replayed) =
+                    replay_wal(&wal_path, &config, &families, last_sequence, read_only, errors)?;
+                for (family, memtable) in families.iter_mut().zip(replayed) {
+                    family.memtable = memtable;
+                }
+                let wal = if read_only { None } else { Some(WalWriter::open(&wal_path)?) };
+                (memtable, wal)
+            }
+            None => {
+                let wal_path = path.join(wal_segment_name(manifest.next_wal_seq()));
+                let memtable = Memtable::with_start_seq(config.memtable_size, last_sequence);
+                let wal = if read_only { None } else { Some(WalWriter::create(&wal_path)?) };
+                (memtable, wal)
+            }
+        };
+
+        let frozen = immutables.iter().map(|imm| imm.memtable.as_ref());
+        let max_key = manifest
+            .levels
+            .iter()
+            .flat_map(|level| level.sstables.iter().map(|sst| sst.max_key.as_slice()))
+            .chain(frozen.chain([&memtable]).filter_map(|m| m.iter().last().map(|(key, _)| key)))
+            .max()
+            .map(<[u8]>::to_vec);
+        let memtable_sequential = memtable.is_empty();
+        // every replayed write was read back from disk
+        let durable = memtable.seq_num();
+        let (statistics, listeners) = (config.statistics.clone(), config.listeners.clone());
+        let mut table_cache = TableCache::new(config.block_cache_size);
+        if let Some(statistics) = &statistics {
+            table_cache = table_cache.with_statistics(Arc::clone(statistics));
+        }
+
+        let (background_flush, wal_sync) = (config.background_flush, config.wal_sync);
+        let auto_compaction = config.auto_compaction;
+        let mut db = Self {
+            path,
+            shared: Arc::new(Shared {
+                inner: Mutex::new(DbInner {
+                    config,
+                    memtable,
+                    families,
+                    wal,
+                    immutables,
+                    consumers: HashMap::new(),
+                    archived_wals: Vec::new(),
+                    flush_error: None,
+                    background_errors,
+                    active_compaction: None,
+                    manifest,
+                    manifest_log,
+                    pending_outputs: HashSet::new(),
+                    pinned_tables: HashMap::new(),
+                    max_key,
+                    memtable_sequential,
+                    append_stats: AppendStats::default(),
+                    read_stats: ReadStats::default(),
+                    amplification: AmplificationStats::default(),
+                    compaction_pending: true,
+                    background_paused: 0,
+                    background_jobs: 0,
+                    shutdown: false,
+                }),
+                snapshots: Arc::new(SnapshotList::default()),
+                compaction: Mutex::new(()),
+                compaction_signal: Condvar::new(),
+                flush: Mutex::new(()),
+                flush_signal: Condvar::new(),
+                flush_done: Condvar::new(),
+                background_idle: Condvar::new(),
+                write_signal: Condvar::new(),
+                group_commit: GroupCommit::new(durable),
+                sync_signal: Condvar::new(),
+                pending_jobs: Mutex::new(PendingJobs::default()),
+                flush_jobs: Mutex::new(()),
+                compaction_jobs: Mutex::new(()),
+                cancel: CancelToken::new(),
+                statistics,
+                listeners,
+                table_cache,
+            }),
+            compactor: None,
+            flusher: None,
+            syncer: None,
+            batch_pool: Mutex::new(Vec::new()),
+            job_threads: Mutex::new(Vec::new()),
+            closed: false,
+            lock_file,
+            read_only,
+        };
+        if read_only {
+            return Ok(db);
+        }
+        db.load_consumers()?;
+        // leftovers of jobs and deletes a crash cut short
+        purge_obsolete_files(&db.path, &db.shared)?;
+
+        if background_flush {
+            let (dir, shared) = (db.path.clone(), Arc::clone(&db.shared));
+            db.flusher = Some(
+                thread::Builder::new()
+                    .name("kvstore-flush".to_string())
+                    .spawn(move || flush_loop(dir, shared))?,
+            );
+        }
+
+        // a WAL larger than the memtable limit is frozen right away, and
+        // memtables frozen before a crash are flushed
+        let inner = db.lock();
+        db.maybe_flush(inner)?;
+
+        if let WalSyncPolicy::EveryNMillis(millis) = wal_sync {
+            let shared = Arc::clone(&db.shared);
+            let interval = Duration::from_millis(millis.max(1));
+            db.syncer = Some(
+                thread::Builder::new()
+                    .name("kvstore-wal-sync".to_string())
+                    .spawn(move || sync_loop(shared, interval))?,
+            );
+        }
+
+        if auto_compaction {
+            let (dir, shared) = (db.path.clone(), Arc::clone(&db.shared));
+            db.compactor = Some(
+                thread::Builder::new()
+                    .name("kvstore-compaction".to_string())
+                    .spawn(move || compaction_loop(dir, shared))?,
+            );
+        }
+
+        Ok(db)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_opt(key, value, &WriteOptions::default())
+    }
+
+    /// put with write options, e.g. synchronous
+    pub fn put_opt(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<()> {
+        self.write_ops(std::iter::once(BatchOp::Put { key, value }), options, |wal, seq| {
+            wal.append(seq, &WalEntry::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            })
+        })
+    }
+
+    /// how well the write stream matched the increasing-key assumption
+    pub fn append_stats(&self) -> AppendStats {
+        self.lock().append_stats.clone()
+    }
+
+    /// how many tables point reads had to probe
+    pub fn read_stats(&self) -> ReadStats {
+        self.lock().read_stats.clone()
+    }
+
+    /// write and read amplification measured since open
+    pub fn amplification_report(&self) -> AmplificationReport {
+        let inner = self.lock();
+        let stats = &inner.amplification;
+        let gets = stats.recent_gets.len() as u64;
+        AmplificationReport {
+            user_bytes: stats.user_bytes,
+            disk_bytes: stats.disk_bytes,
+            write_amplification: stats.disk_bytes as f64 / stats.user_bytes.max(1) as f64,
+            gets,
+            read_amplification: stats.recent_blocks as f64 / gets.max(1) as f64,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_opt(key, &ReadOptions::default())
+    }
+
+    /// get with read options, e.g. as of a snapshot
+    ///
+    /// stops at the first version found: memtables, then frozen memtables and
+    /// L0 newest first, then one file per deeper level. Tables whose key range
+    /// or prefix filter misses the key are skipped before their bloom filter
+    /// is read
+    pub fn get_opt(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        self.get_in(DEFAULT_FAMILY, key, options)
+    }
+
+    /// get_opt in column family `family`
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(family = family, key_len = key.len()))
+    )]
+    pub(crate) fn get_in(
+        &self,
+        family: u32,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let mut inner = self.lock_visible(options)?;
+        let value = self.lookup(&mut inner, family, key, read_seq(options), options.deadline)?;
+        drop(inner);
+        self.shared.tick(Ticker::KeysRead, 1);
+        self.shared.tick(Ticker::BytesRead, value.as_ref().map_or(0, Vec::len) as u64);
+        self.shared.record_latency(Latency::Get, start);
+        Ok(value)
+    }
+
+    /// get for many keys at once; values come in the order of `keys`
+    ///
+    /// the keys are looked up in key order under one lock, and each table
+    /// is opened once for all the keys it may hold, reading each of its data
+    /// blocks at most once for them
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.multi_get_opt(keys, &ReadOptions::default())
+    }
+
+    pub fn multi_get_opt<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        options: &ReadOptions,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        self.multi_get_in(DEFAULT_FAMILY, keys, options)
+    }
+
+    pub(crate) fn multi_get_in<K: AsRef<[u8]>>(
+        &self,
+        family: u32,
+        keys: &[K],
+        options: &ReadOptions,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        self.multi_get_partial_in(family, keys, options)?.into_iter().collect()
+    }
+
+    /// multi_get that answers what it can by ReadOptions::deadline: keys
+    /// still unresolved when it passes fail with DeadlineExceeded on their
+    /// own, the rest keep their values
+    pub fn multi_get_partial<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        options: &ReadOptions,
+    ) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        self.multi_get_partial_in(DEFAULT_FAMILY, keys, options)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(family = family, keys = keys.len()))
+    )]
+    pub(crate) fn multi_get_partial_in<K: AsRef<[u8]>>(
+        &self,
+        family: u32,
+        keys: &[K],
+        options: &ReadOptions,
+    ) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        let mut sorted: Vec<&[u8]> = keys.iter().map(AsRef::as_ref).collect();
+        sorted.sort_unstable();
+        sorted.dedup();
+
+        let mut inner = self.lock_visible(options)?;
+        let mut blocks = 0;
+        let seq = read_seq(options);
+        let deadline = options.deadline;
+        let values = self.find_many(&mut inner, family, &sorted, seq, deadline, &mut blocks);
+        inner.amplification.record_get(blocks);
+        let values = values?;
+        drop(inner);
+        self.shared.tick(Ticker::KeysRead, keys.len() as u64);
+        let bytes: usize = values.iter().flatten().flatten().map(Vec::len).sum();
+        self.shared.tick(Ticker::BytesRead, bytes as u64);
+
+        let value_of = |key: &[u8]| match sorted.binary_search(&key) {
+            Ok(i) => values[i].clone().ok_or(DbError::DeadlineExceeded),
+            Err(_) => Ok(None),
+        };
+        Ok(keys.iter().map(|key| value_of(key.as_ref())).collect())
+    }
+
+    /// whether `key` may have a value: false is certain, true may be a
+    /// false positive
+    ///
+    /// answered from the memtables, key ranges, prefix and bloom filters
+    /// alone, without reading a data block
+    pub fn may_contain(&self, key: &[u8]) -> Result<bool> {
+        self.may_contain_in(DEFAULT_FAMILY, key)
+    }
+
+    pub(crate) fn may_contain_in(&self, family: u32, key: &[u8]) -> Result<bool> {
+        let mut inner = self.lock();
+        Ok(self.probe(&mut inner, family, key)?.unwrap_or(true))
+    }
+
+    /// whether `key` has a value, as get would say
+    ///
+    /// may_contain first; the data blocks are read only when a table's
+    /// filters can't rule the key out and nothing newer decides it
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.contains_key_in(DEFAULT_FAMILY, key)
+    }
+
+    pub(crate) fn contains_key_in(&self, family: u32, key: &[u8]) -> Result<bool> {
+        let mut inner = self.lock();
+        match self.probe(&mut inner, family, key)? {
+            Some(found) => Ok(found),
+            None => Ok(self.lookup(&mut inner, family, key, u64::MAX, None)?.is_some()),
+        }
+    }
+
+    /// whether the latest `key` has a value, if the memtables or the
+    /// tables' filters can tell without reading a data block
+    fn probe(&self, inner: &mut DbInner, family: u32, key: &[u8]) -> Result<Option<bool>> {
+        // the newest memtable version decides, unless TTL may expire it
+        let mut deleted_below = 0;
+        let frozen = inner.immutables.iter().rev().filter_map(|imm| imm.memtable(family));
+        for memtable in std::iter::once(inner.memtable(family)?).chain(frozen) {
+            let covering = memtable.range_tombstones().covering_seq(key, u64::MAX);
+            deleted_below = deleted_below.max(covering);
+            if let Some(entry) = memtable.versions_at(key, u64::MAX).next() {
+                if entry.seq_num < deleted_below || entry.value.is_none() {
+                    return Ok(Some(false));
+                }
+                return Ok(inner.config.ttl.is_none().then_some(true));
+            }
+        }
+
+        let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+        for sst in manifest.files_for_key(key) {
+            if sst.tombstone_only && !manifest.overlaps_older(sst, key, key) {
+                continue;
+            }
+            if !sst.may_contain_prefix(key) {
+                inner.read_stats.prefix_filter_skips += 1;
+                continue;
+            }
+            let reader = self.shared.table_cache.get(&self.path, sst)?;
+            inner.read_stats.tables_probed += 1;
+            self.shared.tick(Ticker::BloomChecked, 1);
+            if reader.may_contain(key) {
+                return Ok(None);
+            }
+            inner.read_stats.bloom_negatives += 1;
+            self.shared.tick(Ticker::BloomUseful, 1);
+        }
+        Ok(Some(false))
+    }
+
+    /// move the value of `from` to `to`, deleting `from`, as one atomic batch
+    /// - the value is read under the write lock, so no other write can land
+    ///   between the read and the move
+    /// - returns false, writing nothing, if `from` doesn't exist
+    /// - with `overwrite` false an existing `to` fails with KeyExists
+    pub fn rename(&self, from: &[u8], to: &[u8], overwrite: bool) -> Result<bool> {
+        let mut inner = self.lock();
+        let Some(value) = self.lookup(&mut inner, DEFAULT_FAMILY, from, u64::MAX, None)? else {
+            return Ok(false);
+        };
+        if from == to {
+            return Ok(true);
+        }
+        if !overwrite && self.lookup(&mut inner, DEFAULT_FAMILY, to, u64::MAX, None)?.is_some() {
+            return Err(DbError::KeyExists(to.to_vec()));
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.delete(from);
+        batch.put(to, &value);
+        let options = WriteOptions::default();
+        self.write_locked(inner, DEFAULT_FAMILY, batch.iter(), &options, |wal, seq| {
+            wal.append_batch(seq, batch.data())
+        })?;
+        Ok(true)
+    }
+
+    /// write `value` to `key` only if its current value is `expected`, None
+    /// meaning absent; returns whether it wrote
+    ///
+    /// the check and the write happen under the write lock, so no other
+    /// write can land between them
+    pub fn put_if(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<bool> {
+        self.put_if_opt(key, expected, value, &WriteOptions::default())
+    }
+
+    pub fn put_if_opt(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+        options: &WriteOptions,
+    ) -> Result<bool> {
+        self.put_if_in(DEFAULT_FAMILY, key, expected, value, options)
+    }
+
+    pub(crate) fn put_if_in(
+        &self,
+        family: u32,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+        options: &WriteOptions,
+    ) -> Result<bool> {
+        let mut inner = self.lock();
+        if self.lookup(&mut inner, family, key, u64::MAX, None)?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write_locked(inner, family, batch.iter(), options, |wal, seq| {
+            wal.append_family_batch(seq, family, batch.data())
+        })?;
+        Ok(true)
+    }
+
+    /// newest value of `key` in column family `family` as of `seq`, with
+    /// the DB lock held; fails with DeadlineExceeded if a table still has to
+    /// be read once `deadline` has passed
+    fn lookup(
+        &self,
+        inner: &mut DbInner,
+        family: u32,
+        key: &[u8],
+        seq: u64,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut blocks = 0;
+        let value = self.find(inner, family, key, seq, deadline, &mut blocks);
+        inner.amplification.record_get(blocks);
+        value
+    }
+
+    /// lookup(), adding the data blocks read to `blocks`
+    fn find(
+        &self,
+        inner: &mut DbInner,
+        family: u32,
+        key: &[u8],
+        seq: u64,
+        deadline: Option<Instant>,
+        blocks: &mut u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let operator = inner.family_config(family).merge_operator.clone();
+        let operator = operator.as_deref();
+        // versions newest first, until one that isn't a merge operand
+        let mut fold = MergeFold::with_expiry(Expiry::new(inner.config.ttl, unix_now()));
+        // the newest range tombstone over the key so far; sources come newest
+        // first, so older ones can't delete what is yet to be read
+        let mut deleted_below = 0;
+        let frozen = inner.immutables.iter().rev().filter_map(|imm| imm.memtable(family));
+        for memtable in std::iter::once(inner.memtable(family)?).chain(frozen) {
+            let covering = memtable.range_tombstones().covering_seq(key, seq);
+            deleted_below = deleted_below.max(covering);
+            for entry in memtable.versions_at(key, seq) {
+                let value = if entry.seq_num < deleted_below {
+                    StoredValue::Delete
+                } else {
+                    entry.to_stored()
+                };
+                if fold.push(value) {
+                    return fold.finish(key, operator);
+                }
+            }
+        }
+
+        let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+        for sst in manifest.files_for_key(key) {
+            // a tombstone-only file with nothing older under the key can't change the answer
+            if sst.tombstone_only && !manifest.overlaps_older(sst, key, key) {
+                continue;
+            }
+            if !sst.may_contain_prefix(key) {
+                inner.read_stats.prefix_filter_skips += 1;
+                continue;
+            }
+            if deadline_passed(deadline) {
+                return Err(DbError::DeadlineExceeded);
+            }
+            let (covering, versions) =
+                self.table_get(sst, key, seq, &mut inner.read_stats, blocks)?;
+            deleted_below = deleted_below.max(covering);
+            for (version, value) in versions {
+                let value = if version < deleted_below {
+                    StoredValue::Delete
+                } else {
+                    value
+                };
+                if fold.push(value) {
+                    return fold.finish(key, operator);
+                }
+            }
+        }
+
+        fold.finish(key, operator)
+    }
+
+    /// find() for each of the sorted `keys`, visiting every table once for
+    /// all the keys it may hold; keys still unresolved when a table has to
+    /// be read past `deadline` come back as None
+    fn find_many(
+        &self,
+        inner: &mut DbInner,
+        family: u32,
+        keys: &[&[u8]],
+        seq: u64,
+        deadline: Option<Instant>,
+        blocks: &mut u64,
+    ) -> Result<Vec<Option<Option<Vec<u8>>>>> {
+        let operator = inner.family_config(family).merge_operator.clone();
+        let operator = operator.as_deref();
+        let expiry = Expiry::new(inner.config.ttl, unix_now());
+        let mut reads: Vec<_> = keys.iter().map(|_| KeyRead::new(expiry)).collect();
+
+        for memtable in inner.memtables(family)? {
+            for (read, key) in reads.iter_mut().zip(keys).filter(|(read, _)| !read.settled) {
+                read.cover(memtable.range_tombstones().covering_seq(key, seq));
+                read.push(memtable.versions_at(key, seq).map(|v| (v.seq_num, v.to_stored())));
+            }
+        }
+
+        // tables newest first, the order files_for_key visits them in
+        let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+        let l0 = manifest.get_level(0).iter().rev();
+        let deeper = (1..manifest.levels.len()).flat_map(|l| manifest.get_level(l));
+        let mut timed_out = false;
+        for sst in l0.chain(deeper) {
+            let start = keys.partition_point(|key| *key < sst.min_key.as_slice());
+            let end = keys.partition_point(|key| *key <= sst.max_key.as_slice());
+            let mut candidates = Vec::new();
+            for i in (start..end).filter(|&i| !reads[i].settled) {
+                let key = keys[i];
+                // as in find()
+                if sst.tombstone_only && !manifest.overlaps_older(sst, key, key) {
+                    continue;
+                }
+                if !sst.may_contain_prefix(key) {
+                    inner.read_stats.prefix_filter_skips += 1;
+                    continue;
+                }
+                candidates.push(i);
+            }
+            if candidates.is_empty() {
+                continue;
+            }
+            if deadline_passed(deadline) {
+                timed_out = true;
+                break;
+            }
+
+            let reader = self.shared.table_cache.get(&self.path, sst)?;
+            let probed = candidates.len() as u64;
+            for &i in &candidates {
+                reads[i].cover(reader.range_tombstones().covering_seq(keys[i], seq));
+            }
+            candidates.retain(|&i| reader.may_contain(keys[i]));
+            inner.read_stats.tables_probed += probed;
+            inner.read_stats.bloom_negatives += probed - candidates.len() as u64;
+            self.shared.tick(Ticker::BloomChecked, probed);
+            self.shared.tick(Ticker::BloomUseful, probed - candidates.len() as u64);
+
+            let probe: Vec<&[u8]> = candidates.iter().map(|&i| keys[i]).collect();
+            let found = reader.multi_versions_at_counting(&probe, seq, blocks)?;
+            for (i, versions) in candidates.into_iter().zip(found) {
+                reads[i].push(versions);
+            }
+        }
+
+        let mut values = Vec::with_capacity(keys.len());
+        for (read, key) in reads.into_iter().zip(keys) {
+            if timed_out && !read.settled {
+                values.push(None);
+            } else {
+                values.push(Some(read.fold.finish(key, operator)?));
+            }
+        }
+        Ok(values)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.delete_opt(key, &WriteOptions::default())
+    }
+
+    /// fold `operand` into the value of `key` with LSMConfig::merge_operator,
+    /// without reading it; fails with NoMergeOperator if none is configured
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.merge_opt(key, operand, &WriteOptions::default())
+    }
+
+    pub fn merge_opt(&self, key: &[u8], operand: &[u8], options: &WriteOptions) -> Result<()> {
+        let op = BatchOp::Merge { key, value: operand };
+        self.write_ops(std::iter::once(op), options, |wal, seq| {
+            wal.append(seq, &WalEntry::Merge {
+                key: key.to_vec(),
+                value: operand.to_vec(),
+            })
+        })
+    }
+
+    pub fn delete_opt(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        self.write_ops(std::iter::once(BatchOp::Delete { key }), options, |wal, seq| {
+            wal.append(seq, &WalEntry::Delete { key: key.to_vec() })
+        })
+    }
+
+    /// delete a key that was put at most once since it was last deleted
+    ///
+    /// the tombstone and that put cancel out in the memtable or the first
+    /// compaction that sees both, instead of the tombstone living on until
+    /// the bottom level; if the key was put more than once, or merged
+    /// into, older values may show through again
+    pub fn single_delete(&self, key: &[u8]) -> Result<()> {
+        self.single_delete_opt(key, &WriteOptions::default())
+    }
+
+    pub fn single_delete_opt(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        let op = BatchOp::SingleDelete { key };
+        self.write_ops(std::iter::once(op), options, |wal, seq| {
+            wal.append(seq, &WalEntry::SingleDelete { key: key.to_vec() })
+        })
+    }
+
+    /// delete every key in [start, end) with one range tombstone, however
+    /// many keys that is; an empty range deletes nothing
+    ///
+    /// reads skip the covered keys at once, compaction drops them once no
+    /// snapshot can see them
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.delete_range_opt(start, end, &WriteOptions::default())
+    }
+
+    pub fn delete_range_opt(&self, start: &[u8], end: &[u8], options: &WriteOptions) -> Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        let op = BatchOp::DeleteRange { start, end };
+        self.write_ops(std::iter::once(op), options, |wal, seq| {
+            wal.append(seq, &WalEntry::DeleteRange {
+                start: start.to_vec(),
+                end: end.to_vec(),
+            })
+        })
+    }
+
+    /// apply every operation in the batch atomically
+    ///
+    /// the batch's buffer goes back to the pool behind take_batch() afterwards
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_opt(batch, &WriteOptions::default())
+    }
+
+    pub fn write_opt(&self, mut batch: WriteBatch, options: &WriteOptions) -> Result<()> {
+        let result = if batch.is_empty() {
+            Ok(())
+        } else {
+            self.write_ops(batch.iter(), options, |wal, seq| {
+                wal.append_batch(seq, batch.data())
+            })
+        };
+
+        batch.clear();
+        let mut pool = self.batch_pool.lock().unwrap_or_else(|e| e.into_inner());
+        if pool.len() < MAX_POOLED_BATCHES && batch.capacity() <= MAX_POOLED_BATCH_CAPACITY {
+            pool.push(batch);
+        }
+
+        result
+    }
+
+    /// an empty batch, reusing the buffer of one passed to write() earlier
+    pub fn take_batch(&self) -> WriteBatch {
+        let mut pool = self.batch_pool.lock().unwrap_or_else(|e| e.into_inner());
+        pool.pop().unwrap_or_default()
+    }
+
+    /// delete every key starting with `prefix` in one atomic batch; returns
+    /// how many keys were deleted
+    ///
+    /// keys written under the prefix after the call starts may survive. With
+    /// `compact`, the affected files are compacted afterwards so the space
+    /// is reclaimed now rather than whenever compaction reaches them
+    pub fn delete_prefix(&self, prefix: &[u8], compact: bool) -> Result<usize> {
+        let range = (Bound::Included(prefix.to_vec()), prefix_end(prefix));
+
+        let mut batch = self.take_batch();
+        for entry in self.prefix_iter(prefix)? {
+            let (key, _) = entry?;
+            batch.delete(&key);
+        }
+        let deleted = batch.len();
+        self.write(batch)?;
+
+        if compact {
+            self.compact_range(range)?;
+        }
+        Ok(deleted)
+    }
+
+    /// delete a whole namespace and reclaim its space before returning
+    /// - delete_prefix, then compaction of every file overlapping the prefix
+    ///   down to the last level, then removal of the files that freed
+    /// - reclaimed bytes are measured against the quota's disk_bytes, so
+    ///   writes elsewhere while the purge runs blur the figure
+    pub fn purge_namespace(&self, prefix: &[u8]) -> Result<PurgeReport> {
+        let bytes_before = self.lock().disk_bytes();
+        let deleted_keys = self.delete_prefix(prefix, true)?;
+        self.purge_obsolete_files()?;
+        let bytes_after = self.lock().disk_bytes();
+        Ok(PurgeReport {
+            deleted_keys,
+            bytes_before,
+            bytes_after,
+            reclaimed_bytes: bytes_before.saturating_sub(bytes_after),
+        })
+    }
+
+    /// flush, then compact every file overlapping `range` level by level
+    /// down to the last level, ignoring the compaction schedule
+    ///
+    /// joins a queued compaction of the same range instead of running twice
+    ///
+    /// an open bound compacts from the first key or to the last, e.g.
+    /// `compact_range::<&[u8]>(..)` for everything
+    pub fn compact_range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<()> {
+        self.compact_range_in(DEFAULT_FAMILY, range)
+    }
+
+    pub(crate) fn compact_range_in<K: AsRef<[u8]>>(
+        &self,
+        family: u32,
+        range: impl RangeBounds<K>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let range = (owned_bound(range.start_bound()), owned_bound(range.end_bound()));
+        let (job, new) = self.shared.request_compaction(family, &range);
+        if new {
+            let shared = &self.shared;
+            return run_compaction_job(&self.path, shared, &job, family, &range);
+        }
+        job.wait()
+    }
+
+    /// compact_range on a background thread
+    pub fn compact_range_async<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<JobHandle> {
+        self.compact_range_async_in(DEFAULT_FAMILY, range)
+    }
+
+    pub(crate) fn compact_range_async_in<K: AsRef<[u8]>>(
+        &self,
+        family: u32,
+        range: impl RangeBounds<K>,
+    ) -> Result<JobHandle> {
+        self.check_writable()?;
+        let range = (owned_bound(range.start_bound()), owned_bound(range.end_bound()));
+        let (job, new) = self.shared.request_compaction(family, &range);
+        if new {
+            let handle = job.clone();
+            self.spawn_job(&job, move |dir, shared| {
+                let _ = run_compaction_job(dir, shared, &handle, family, &range);
+            })?;
+        }
+        Ok(job)
+    }
+
+    /// iterate live key-value pairs in `range`, merging the memtable and all SSTables
+    ///
+    /// the memtable part is copied when the iterator is created; SSTables
+    /// are read lazily block by block
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<DbIterator> {
+        self.range_opt(range, &ReadOptions::default())
+    }
+
+    /// range with read options, e.g. as of a snapshot
+    pub fn range_opt<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        self.range_in(DEFAULT_FAMILY, range, options)
+    }
+
+    /// iterate live key-value pairs in `range` from the last key down, e.g.
+    /// `range_rev(..before).take(n)` for the n keys before another
+    pub fn range_rev<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<DbIterator> {
+        self.range_rev_opt(range, &ReadOptions::default())
+    }
+
+    pub fn range_rev_opt<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        self.range_rev_in(DEFAULT_FAMILY, range, options)
+    }
+
+    /// iterate live key-value pairs whose key starts with `prefix`
+    ///
+    /// unlike an equivalent range, tables whose prefix filter rules the
+    /// prefix out are skipped without being opened; that needs a prefix at
+    /// least LSMConfig::prefix_filter_len long
+    pub fn prefix_iter(&self, prefix: &[u8]) -> Result<DbIterator> {
+        self.prefix_iter_opt(prefix, &ReadOptions::default())
+    }
+
+    pub fn prefix_iter_opt(&self, prefix: &[u8], options: &ReadOptions) -> Result<DbIterator> {
+        self.prefix_iter_in(DEFAULT_FAMILY, prefix, options)
+    }
+
+    pub(crate) fn prefix_iter_in(
+        &self,
+        family: u32,
+        prefix: &[u8],
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        let lower = Bound::Included(prefix.to_vec());
+        self.scan_in(family, lower, prefix_end(prefix), Some(prefix), false, options)
+    }
+
+    /// range_opt over column family `family`
+    pub(crate) fn range_in<K: AsRef<[u8]>>(
+        &self,
+        family: u32,
+        range: impl RangeBounds<K>,
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        let lower = owned_bound(range.start_bound());
+        let upper = owned_bound(range.end_bound());
+        self.scan_in(family, lower, upper, None, false, options)
+    }
+
+    /// range_rev_opt over column family `family`
+    pub(crate) fn range_rev_in<K: AsRef<[u8]>>(
+        &self,
+        family: u32,
+        range: impl RangeBounds<K>,
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        let lower = owned_bound(range.start_bound());
+        let upper = owned_bound(range.end_bound());
+        self.scan_in(family, lower, upper, None, true, options)
+    }
+
+    /// merged iterator over `lower..upper` of `family`, backwards if
+    /// `reverse`, skipping tables whose prefix filter rules out `filter_prefix`
+    fn scan_in(
+        &self,
+        family: u32,
+        lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
+        filter_prefix: Option<&[u8]>,
+        reverse: bool,
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        let (lower, upper) = options.clamp(lower, upper);
+        let seq = read_seq(options);
+        let mut inner = self.lock_visible(options)?;
+        let bounds = (&lower, &upper);
+        let (sources, _) =
+            self.scan_sources(&mut inner, family, bounds, filter_prefix, reverse, seq)?;
+
+        let operator = inner.family_config(family).merge_operator.clone();
+        if reverse {
+            let merged = MergeIterator::new_rev(sources)
+                .with_merge_operator(operator)
+                .with_ttl(inner.config.ttl);
+            return Ok(DbIterator::new_rev(merged, lower));
+        }
+        let merged = MergeIterator::new(sources)
+            .with_merge_operator(operator)
+            .with_ttl(inner.config.ttl);
+        Ok(DbIterator::new(merged, upper))
+    }
+
+    /// the sources of a scan over `bounds` at `seq`: memtables newest first,
+    /// then tables newest first
+    fn scan_sources(
+        &self,
+        inner: &mut DbInner,
+        family: u32,
+        (lower, upper): (&Bound<Vec<u8>>, &Bound<Vec<u8>>),
+        filter_prefix: Option<&[u8]>,
+        reverse: bool,
+        seq: u64,
+    ) -> Result<(Vec<EntrySource>, ScanState)> {
+        let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+        let mut readers = Vec::new();
+        let mut tables = Vec::new();
+        let mut skipped = 0;
+        for sst in scan_tables(manifest, (lower, upper)) {
+            if filter_prefix.is_some_and(|prefix| !sst.may_contain_keys_with(prefix)) {
+                skipped += 1;
+                continue;
+            }
+            readers.push(self.shared.table_cache.get(&self.path, sst)?);
+            tables.push(sst.id);
+        }
+        inner.read_stats.prefix_filter_skips += skipped;
+        let memtables = inner.memtables(family)?;
+
+        // a range tombstone can only delete versions older than itself, so
+        // those of every source apply to all of them
+        let mut tombstones = RangeTombstones::new();
+        for memtable in &memtables {
+            tombstones.extend(memtable.range_tombstones());
+        }
+        for reader in &readers {
+            tombstones.extend(reader.range_tombstones());
+        }
+        let state = ScanState {
+            tables,
+            memtables: memtables.len(),
+            memtable_tombstones: memtables.iter().map(|m| m.range_tombstones().len()).sum(),
+            tombstones: Arc::new(tombstones),
+        };
+
+        let mut sources: Vec<EntrySource> = Vec::new();
+        for memtable in memtables {
+            let tombstones = &state.tombstones;
+            sources.push(memtable_source(memtable, (lower, upper), reverse, seq, tombstones));
+        }
+        for reader in &readers {
+            let bounds = (lower.clone(), upper.clone());
+            let iter = if reverse {
+                TableIterator::new_rev(reader, bounds, seq)
+            } else {
+                TableIterator::new(reader, bounds, seq)
+            };
+            sources.push(Box::new(iter.with_range_tombstones(Arc::clone(&state.tombstones))));
+        }
+        Ok((sources, state))
+    }
+
+    /// a forward scan of `family` over `bounds` at the latest sequence
+    /// number, for a TailingIterator
+    pub(crate) fn tail_in(
+        &self,
+        family: u32,
+        bounds: (&Bound<Vec<u8>>, &Bound<Vec<u8>>),
+    ) -> Result<(MergeIterator, ScanState)> {
+        let mut inner = self.lock();
+        let (sources, state) =
+            self.scan_sources(&mut inner, family, bounds, None, false, u64::MAX)?;
+        let operator = inner.family_config(family).merge_operator.clone();
+        let merged = MergeIterator::new(sources)
+            .with_merge_operator(operator)
+            .with_ttl(inner.config.ttl);
+        Ok((merged, state))
+    }
+
+    /// fresh memtable sources for a tailing scan whose tables and range
+    /// tombstones are still those of `state`; None once they changed
+    pub(crate) fn tail_memtables_in(
+        &self,
+        family: u32,
+        bounds: (&Bound<Vec<u8>>, &Bound<Vec<u8>>),
+        state: &ScanState,
+    ) -> Result<Option<Vec<EntrySource>>> {
+        let inner = self.lock();
+        let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+        if !scan_tables(manifest, bounds).all(|sst| state.tables.contains(&sst.id)) {
+            return Ok(None);
+        }
+        let memtables = inner.memtables(family)?;
+        let tombstones: usize = memtables.iter().map(|m| m.range_tombstones().len()).sum();
+        if memtables.len() != state.memtables || tombstones != state.memtable_tombstones {
+            return Ok(None);
+        }
+
+        let sources = memtables
+            .into_iter()
+            .map(|memtable| memtable_source(memtable, bounds, false, u64::MAX, &state.tombstones))
+            .collect();
+        Ok(Some(sources))
+    }
+
+    /// iterate every live key-value pair in key order
+    pub fn iter(&self) -> Result<DbIterator> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// iterate live key-value pairs in `range` as they are written; see
+    /// TailingIterator
+    pub fn tailing_iter<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<TailingIterator<'_>> {
+        self.tailing_iter_in(DEFAULT_FAMILY, range)
+    }
+
+    pub(crate) fn tailing_iter_in<K: AsRef<[u8]>>(
+        &self,
+        family: u32,
+        range: impl RangeBounds<K>,
+    ) -> Result<TailingIterator<'_>> {
+        let lower = owned_bound(range.start_bound());
+        let upper = owned_bound(range.end_bound());
+        TailingIterator::new(self, family, lower, upper)
+    }
+
+    /// follow every write logged after consumer `name` last acked; see
+    /// Subscription
+    ///
+    /// a new consumer starts after the last write so far
+    pub fn subscribe(&self, name: &str) -> Result<Subscription<'_>> {
+        let family = self.consumer_family()?;
+        let inner = self.lock();
+        if let Some(&acked) = inner.consumers.get(name) {
+            return Ok(Subscription::new(self, name, family, acked));
+        }
+        let acked = inner.memtable.seq_num();
+        self.put_consumer(inner, family, name, acked)?;
+        self.lock().consumers.insert(name.to_string(), acked);
+        Ok(Subscription::new(self, name, family, acked))
+    }
+
+    /// record that consumer `name` processed every write up to `seq`, which
+    /// it resumes after from now on; WAL segments no consumer needs anymore
+    /// are deleted
+    ///
+    /// the ack is synced. An ack behind an earlier one is ignored
+    pub fn ack(&self, name: &str, seq: u64) -> Result<()> {
+        let family = self.consumer_family()?;
+        let inner = self.lock();
+        let acked = *inner
+            .consumers
+            .get(name)
+            .ok_or_else(|| DbError::NoSuchConsumer(name.to_string()))?;
+        let seq = seq.min(inner.memtable.seq_num());
+        if seq <= acked {
+            return Ok(());
+        }
+        self.put_consumer(inner, family, name, seq)?;
+
+        let mut inner = self.lock();
+        if let Some(acked) = inner.consumers.get_mut(name) {
+            *acked = (*acked).max(seq);
+        }
+        let released = inner.release_archived_wals();
+        drop(inner);
+        for path in released {
+            fs::remove_file(path).ok();
+        }
+        Ok(())
+    }
+
+    /// forget consumer `name`, so the WAL is no longer kept for it; false if
+    /// there was none
+    pub fn unsubscribe(&self, name: &str) -> Result<bool> {
+        let family = self.consumer_family()?;
+        if !self.lock().consumers.contains_key(name) {
+            return Ok(false);
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete(name.as_bytes());
+        self.write_in(family, &batch, &WriteOptions::new().with_sync(true))?;
+
+        let mut inner = self.lock();
+        inner.consumers.remove(name);
+        let released = inner.release_archived_wals();
+        drop(inner);
+        for path in released {
+            fs::remove_file(path).ok();
+        }
+        Ok(true)
+    }
+
+    /// id of the family consumer offsets live in, created on first use
+    fn consumer_family(&self) -> Result<u32> {
+        self.check_writable()?;
+        let inner = self.lock();
+        let found = inner.families.iter().find(|family| family.name == CONSUMER_FAMILY);
+        let found = found.map(|family| family.id);
+        drop(inner);
+        match found {
+            Some(id) => Ok(id),
+            None => Ok(self.create_cf(CONSUMER_FAMILY)?.id()),
+        }
+    }
+
+    /// persist that consumer `name` acked `seq`
+    fn put_consumer(
+        &self,
+        inner: MutexGuard<'_, DbInner>,
+        family: u32,
+        name: &str,
+        seq: u64,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(name.as_bytes(), &seq.to_le_bytes());
+        let options = WriteOptions::new().with_sync(true);
+        self.write_locked(inner, family, batch.iter(), &options, |wal, seq| {
+            wal.append_family_batch(seq, family, batch.data())
+        })
+    }
+
+    /// read back the consumers and the WAL segments kept for them, then
+    /// delete the segments none of them needs
+    fn load_consumers(&self) -> Result<()> {
+        let mut consumers = HashMap::new();
+        if let Ok(family) = self.cf(CONSUMER_FAMILY) {
+            for entry in family.iter()? {
+                let (name, acked) = entry?;
+                let acked = acked.try_into().map(u64::from_le_bytes).map_err(|_| {
+                    DbError::Corrupted(format!("Bad offset of consumer {:?}", name))
+                })?;
+                consumers.insert(String::from_utf8_lossy(&name).into_owned(), acked);
+            }
+        }
+        let archive = self.path.join(WAL_ARCHIVE_DIR);
+        let mut archived = Vec::new();
+        if archive.is_dir() {
+            for (_, path) in wal_segments(&archive)? {
+                let mut last = 0;
+                for record in WalReader::new(&path)? {
+                    last = last.max(record?.last_seq().unwrap_or(0));
+                }
+                archived.push((path, last));
+            }
+        }
+
+        let mut inner = self.lock();
+        inner.consumers = consumers;
+        inner.archived_wals = archived;
+        let released = inner.release_archived_wals();
+        drop(inner);
+        for path in released {
+            fs::remove_file(path).ok();
+        }
+        Ok(())
+    }
+
+    /// the oldest WAL segment, or the first one after segment `after`,
+    /// archived or not, opened for reading
+    pub(crate) fn open_wal_segment(&self, after: Option<u64>) -> Result<Option<(u64, WalReader)>> {
+        // a flush moves segments into the archive under the lock
+        let _inner = self.lock();
+        let mut segments = wal_segments(&self.path)?;
+        let archive = self.path.join(WAL_ARCHIVE_DIR);
+        if archive.is_dir() {
+            segments.extend(wal_segments(&archive)?);
+        }
+        let next = segments
+            .into_iter()
+            .filter_map(|(number, path)| Some((number?, path)))
+            .filter(|(number, _)| after.is_none_or(|after| *number > after))
+            .min_by_key(|(number, _)| *number);
+        match next {
+            Some((number, path)) => Ok(Some((number, WalReader::new(path)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// number of the WAL segment being written, and where its last record
+    /// ends
+    pub(crate) fn wal_written(&self) -> Result<(u64, u64)> {
+        let mut inner = self.lock();
+        let wal = inner.wal()?;
+        let number = wal_segment_number(wal.path())
+            .ok_or_else(|| DbError::Corrupted(format!("Bad WAL name {}", wal.path().display())))?;
+        Ok((number, wal.offset()))
+    }
+
+    /// iterate every live key-value pair from the last key down
+    pub fn iter_rev(&self) -> Result<DbIterator> {
+        self.range_rev::<&[u8]>(..)
+    }
+
+    /// pin the current state; reads with this snapshot ignore later writes
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.lock();
+        Snapshot::new(inner.memtable.seq_num(), Arc::clone(&self.shared.snapshots))
+    }
+
+    /// token for every write committed so far; see ReadOptions::with_min_token
+    pub fn commit_token(&self) -> CommitToken {
+        CommitToken::new(self.lock().memtable.seq_num())
+    }
+
+    /// write the memtable and every frozen one to L0, even if it isn't full
+    ///
+    /// concurrent calls share one flush: a call made while another flush
+    /// is running queues at most one more, which later calls join
+    pub fn flush(&self) -> Result<()> {
+        self.check_writable()?;
+        let (job, new) = self.shared.request_flush();
+        if new {
+            return run_flush_job(&self.path, &self.shared, &job);
+        }
+        job.wait()
+    }
+
+    /// write the memtable and every frozen one to L0; the flushed WAL
+    /// segments are deleted, and writes go on in a fresh one
+    /// - with FlushOptions::wait, returns once the tables are listed in the
+    ///   manifest, like flush()
+    /// - without, only starts the flush, like flush_async()
+    pub fn flush_opt(&self, options: &FlushOptions) -> Result<()> {
+        if options.wait {
+            self.flush()
+        } else {
+            self.flush_async().map(drop)
+        }
+    }
+
+    /// flush on a background thread
+    pub fn flush_async(&self) -> Result<JobHandle> {
+        self.check_writable()?;
+        let (job, new) = self.shared.request_flush();
+        if new {
+            let handle = job.clone();
+            self.spawn_job(&job, move |dir, shared| {
+                let _ = run_flush_job(dir, shared, &handle);
+            })?;
+        }
+        Ok(job)
+    }
+
+    /// stop the flush and compaction threads until continue_background_work,
+    /// e.g. to keep I/O down in a latency-critical window; calls nest
+    /// - returns once the flush or compaction they were running is done, so
+    ///   the files stay as they are, e.g. for a volume snapshot
+    /// - writes go on into memtables; with LSMConfig::background_flush they
+    ///   stall once max_immutable_memtables are waiting for a flush
+    /// - flush(), compact_range() and the other manual jobs still run
+    pub fn pause_background_work(&self) {
+        let mut inner = self.lock();
+        inner.background_paused += 1;
+        while inner.background_jobs > 0 {
+            inner = self
+                .shared
+                .background_idle
+                .wait(inner)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// undo one pause_background_work; returns false if background work
+    /// wasn't paused
+    pub fn continue_background_work(&self) -> bool {
+        let mut inner = self.lock();
+        if inner.background_paused == 0 {
+            return false;
+        }
+        inner.background_paused -= 1;
+        if inner.background_paused == 0 {
+            self.shared.flush_signal.notify_one();
+            self.shared.compaction_signal.notify_one();
+        }
+        true
+    }
+
+    /// run every compaction that is due, ignoring the compaction schedule
+    pub fn compact(&self) -> Result<()> {
+        self.check_writable()?;
+        while compact_once(&self.path, &self.shared, true, &self.shared.cancel)? {}
+        Ok(())
+    }
+
+    /// delete tables, WAL segments and manifests the database no longer uses;
+    /// returns how many files went
+    ///
+    /// already runs on open and after every compaction, so this only matters
+    /// for files a failed delete left behind
+    pub fn purge_obsolete_files(&self) -> Result<usize> {
+        purge_obsolete_files(&self.path, &self.shared)
+    }
+
+    /// write a consistent copy of the database into `dir`, which must not
+    /// exist yet, without stopping writes for longer than it takes to copy
+    /// the WAL
+    /// - memtables are flushed first, so little WAL is left to copy
+    /// - tables are hard-linked, or copied across file systems; they are
+    ///   never modified, so the copy and the database can't affect each other
+    /// - the copy gets a fresh manifest and the live WAL segments, and opens
+    ///   like any database, at the last write before the call returned;
+    ///   open_read_only reads it without changing a file
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::create_dir(dir)?;
+        let result = self.live_files(dir).and_then(|live| {
+            for family in &live.manifest.families {
+                fs::create_dir(dir.join(family::family_dir_name(family.id)))?;
+            }
+            for sst in live.manifest.all_tables() {
+                let (from, to) = (live.table_path(sst), dir.join(&sst.path));
+                if fs::hard_link(&from, &to).is_err() {
+                    fs::copy(&from, &to)?;
+                }
+            }
+            ManifestLog::create_current(dir, 1, &live.manifest)?;
+            Ok(())
+        });
+        if result.is_err() {
+            fs::remove_dir_all(dir).ok();
+        }
+        result
+    }
+
+    /// flush, then take the manifest as of one moment and copy the WAL
+    /// segments behind it and the OPTIONS file into `wal_dir`; the tables
+    /// it lists stay on disk until the LiveFiles is dropped
+    pub(crate) fn live_files(&self, wal_dir: &Path) -> Result<LiveFiles<'_>> {
+        if !self.read_only {
+            self.flush()?;
+        }
+        // flushes and compactions commit under the lock, so nothing the
+        // manifest names goes away while it is held
+        let mut inner = self.lock();
+        let mut manifest = inner.manifest.clone();
+        manifest.clear_pending();
+        for (_, segment) in wal_segments(&self.path)? {
+            let Some(name) = segment.file_name() else {
+                continue;
+            };
+            fs::copy(&segment, wal_dir.join(name))?;
+            fs::File::open(wal_dir.join(name))?.sync_all()?;
+        }
+        let recorded = OptionsFile::load(&self.path)?;
+        OptionsFile::succeeding(&inner.config, recorded.as_ref()).save(wal_dir)?;
+        for sst in manifest.all_tables() {
+            *inner.pinned_tables.entry(sst.id).or_default() += 1;
+        }
+        Ok(LiveFiles { db: self, manifest })
+    }
+
+    /// bulk-load table files built by SstFileWriter
+    /// - the files may come in any order but must not overlap one another
+    /// - memtables holding keys in the files' ranges are flushed first
+    /// - every entry is copied in at one new sequence number, so the files
+    ///   shadow what was there before, and snapshots taken before don't
+    ///   see them
+    /// - each file goes to the deepest level with nothing overlapping it in
+    ///   that level or above, L0 when L0 overlaps
+    /// - flushes and compactions wait while the files are copied, writes
+    ///   only once the memtables fill up; the originals are left as they are
+    pub fn ingest<P: AsRef<Path>>(&self, files: &[P]) -> Result<()> {
+        self.ingest_in(DEFAULT_FAMILY, files)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", skip_all, fields(family = family, files = files.len()))
+    )]
+    pub(crate) fn ingest_in<P: AsRef<Path>>(&self, family: u32, files: &[P]) -> Result<()> {
+        self.check_writable()?;
+        let mut tables = Vec::with_capacity(files.len());
+        for path in files {
+            let table = ExternalTable::open(path.as_ref())?;
+            if table.is_empty {
+                let msg = format!("{} holds no entries", table.path.display());
+                return Err(DbError::Ingest(msg));
+            }
+            tables.push(table);
+        }
+        if tables.is_empty() {
+            return Ok(());
+        }
+        tables.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+        if let Some(pair) = tables.windows(2).find(|pair| pair[1].min_key <= pair[0].max_key) {
+            let (a, b) = (pair[0].path.display(), pair[1].path.display());
+            return Err(DbError::Ingest(format!("{} overlaps {}", a, b)));
+        }
+
+        // memtables over the files' ranges are flushed first, without the
+        // flush and compaction locks; those are then taken in drop_cf's
+        // order and kept until the tables are listed, so the levels picked
+        // from the manifest now stay right and nothing newer lands in L0
+        let overlaps = |inner: &DbInner| -> Result<bool> {
+            let memtables = inner.memtables(family)?;
+            Ok(tables.iter().any(|table| memtables_overlap(&memtables, table)))
+        };
+        let (_flushing, _compacting, mut inner) = loop {
+            let overlap = overlaps(&self.lock())?;
+            if overlap {
+                self.flush()?;
+                continue;
+            }
+            let flushing = self.shared.flush.lock().unwrap_or_else(|e| e.into_inner());
+            let compacting = self.shared.compaction.lock().unwrap_or_else(|e| e.into_inner());
+            let inner = self.lock();
+            if !overlaps(&inner)? {
+                break (flushing, compacting, inner);
+            }
+        };
+
+        // the sequence number is taken now, so writes made while the files
+        // are copied land above it
+        let seq = inner.memtable.seq_num() + 1;
+        inner.memtable.advance_seq(seq);
+        let config = inner.family_config(family).clone();
+        let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+        let levels: Vec<usize> = tables
+            .iter()
+            .map(|table| ingest_level(manifest, &table.min_key, &table.max_key))
+            .collect();
+        let ids: Vec<u64> = tables.iter().map(|_| inner.allocate_table_id()).collect();
+        drop(inner);
+
+        let mut ingested = Vec::with_capacity(tables.len());
+        let mut failed = None;
+        for ((table, level), &id) in tables.iter().zip(levels).zip(&ids) {
+            match write_ingested(&self.path, family, id, level, table, seq, &config) {
+                Ok(metadata) => ingested.push(metadata),
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            }
+        }
+        let mut inner = self.lock();
+        for id in &ids {
+            inner.pending_outputs.remove(id);
+        }
+        if let Some(e) = failed {
+            for metadata in &ingested {
+                fs::remove_file(self.path.join(&metadata.path)).ok();
+            }
+            return Err(e);
+        }
+
+        inner.amplification.disk_bytes += ingested.iter().map(|sst| sst.size).sum::<u64>();
+        let manifest = inner.manifest.family_mut(family).ok_or(DbError::FamilyDropped(family))?;
+        for metadata in &ingested {
+            manifest.add_sstable(metadata.level, metadata.clone());
+        }
+        inner.manifest.last_sequence = inner.manifest.last_sequence.max(seq);
+        inner.commit_manifest()?;
+        inner.compaction_pending = true;
+        drop(inner);
+        self.shared.compaction_signal.notify_one();
+        #[cfg(feature = "tracing")]
+        for table in &ingested {
+            tracing::info!(
+                id = table.id,
+                level = table.level,
+                bytes = table.size,
+                "table ingested"
+            );
+        }
+        for table in ingested {
+            let info = TableFileInfo {
+                family,
+                path: self.path.join(&table.path),
+                table,
+            };
+            self.shared.notify(|listener| listener.on_table_file_created(&info));
+        }
+        Ok(())
+    }
+
+    /// up to `n` keys that cut the data into `n + 1` parts of about equal size,
+    /// e.g. for sharding on top of the store
+    /// - each key is the last key of its part; keys come out ascending
+    /// - sizes come from SSTable index blocks and memtable entries, so a part
+    ///   is only as fine as a data block, and overwritten or deleted data
+    ///   still counts until compaction drops it
+    /// - fewer keys come back when there are fewer blocks than parts
+    pub fn suggest_split_points(&self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut samples: Vec<(Vec<u8>, u64)> = Vec::new();
+        {
+            let inner = self.lock();
+            let frozen = inner.immutables.iter().map(|imm| imm.memtable.as_ref());
+            for memtable in std::iter::once(&inner.memtable).chain(frozen) {
+                for (key, entry) in memtable.iter() {
+                    let size = key.len() + entry.value.as_ref().map_or(0, |v| v.len());
+                    samples.push((key.to_vec(), size as u64));
+                }
+            }
+            for level in 0..inner.manifest.levels.len() {
+                for sst in inner.manifest.get_level(level) {
+                    let reader = SSTableReader::open(self.path.join(&sst.path))?;
+                    samples.extend(reader.block_sizes().map(|(key, size)| (key.to_vec(), size)));
+                }
+            }
+        }
+        samples.sort_unstable();
+
+        let total: u64 = samples.iter().map(|(_, size)| size).sum();
+        let parts = n as u64 + 1;
+        let mut points: Vec<Vec<u8>> = Vec::with_capacity(n);
+        let mut seen = 0;
+        for (key, size) in samples {
+            seen += size;
+            if points.len() == n {
+                break;
+            }
+            // the next cut falls at total * (points + 1) / parts
+            let due = seen as u128 * parts as u128 >= total as u128 * (points.len() as u128 + 1);
+            if due && points.last() != Some(&key) && seen < total {
+                points.push(key);
+            }
+        }
+        Ok(points)
+    }
+
+    /// estimate the on-disk bytes and entries of the keys in [start, end)
+    /// - tables wholly inside the range count from their metadata; tables
+    ///   it cuts through count the index blocks it touches, so an edge is
+    ///   only as fine as a data block
+    /// - like suggest_split_points, overwritten and deleted versions count
+    ///   until compaction drops them, and memtables don't count at all
+    pub fn approximate_size(&self, start: &[u8], end: &[u8]) -> Result<ApproximateSize> {
+        let mut size = ApproximateSize::default();
+        if start >= end {
+            return Ok(size);
+        }
+        let tables: Vec<SSTableMetadata> = {
+            let inner = self.lock();
+            (0..inner.manifest.levels.len())
+                .flat_map(|level| inner.manifest.get_level(level))
+                .filter(|sst| sst.max_key.as_slice() >= start && sst.min_key.as_slice() < end)
+                .cloned()
+                .collect()
+        };
+        for sst in &tables {
+            if sst.min_key.as_slice() >= start && sst.max_key.as_slice() < end {
+                size.bytes += sst.size;
+                size.entries += sst.num_entries;
+                continue;
+            }
+            // a block holds the keys after the previous block's last key
+            let reader = self.shared.table_cache.get(&self.path, sst)?;
+            let mut previous: Option<&[u8]> = None;
+            let (mut covered, mut total) = (0u64, 0u64);
+            for (last_key, bytes) in reader.block_sizes() {
+                total += bytes;
+                if last_key >= start && previous.is_none_or(|previous| previous < end) {
+                    covered += bytes;
+                }
+                previous = Some(last_key);
+            }
+            if covered > 0 {
+                size.bytes += covered;
+                size.entries += (sst.num_entries as u128 * covered as u128 / total as u128) as u64;
+            }
+        }
+        Ok(size)
+    }
+
+    /// shut the database down and report what went wrong, which drop can't
+    /// - flushes and compactions in flight, background or manual, are
+    ///   cancelled and their threads joined; whatever they had written is
+    ///   deleted
+    /// - the WAL and manifest are synced; unflushed writes are recovered from
+    ///   the WAL on open
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        self.shutdown()
+    }
+
+    /// table cache hits and misses, overall and for recently read tables
+    pub fn cache_stats(&self) -> CacheStats {
+        self.shared.table_cache.stats()
+    }
+
+    /// level layout, flush queue and background state, for operators
+    pub fn status(&self) -> DbStatus {
+        let inner = self.lock();
+        let families: Vec<FamilyStatus> = inner
+            .manifest
+            .family_ids()
+            .into_iter()
+            .map(|id| inner.family_status(id))
+            .collect();
+        let depth = families.iter().map(|family| family.levels.len()).max();
+        let levels = (0..depth.unwrap_or(0))
+            .map(|level| {
+                let levels = families.iter().filter_map(|family| family.levels.get(level));
+                levels.fold(
+                    LevelStatus {
+                        level,
+                        files: 0,
+                        bytes: 0,
+                        entries: 0,
+                    },
+                    |total, status| LevelStatus {
+                        files: total.files + status.files,
+                        bytes: total.bytes + status.bytes,
+                        entries: total.entries + status.entries,
+                        ..total
+                    },
+                )
+            })
+            .collect();
+
+        DbStatus {
+            path: self.path.clone(),
+            levels,
+            memtable_bytes: inner.memtable.size(),
+            disk_bytes: inner.disk_bytes(),
+            disk_quota: inner.config.max_disk_bytes,
+            compaction_debt_bytes: families.iter().map(|f| f.compaction_debt_bytes).sum(),
+            families,
+            immutable_memtables: inner.immutables.len(),
+            max_immutable_memtables: inner.config.max_immutable_memtables,
+            write_stalled: inner.immutables.len() > inner.config.max_immutable_memtables,
+            active_compaction: inner.active_compaction.clone(),
+            last_sequence: inner.memtable.seq_num(),
+            background_errors: inner.background_errors.iter().cloned().collect(),
+            cache: self.shared.table_cache.stats(),
+        }
+    }
+
+    /// create an empty column family `name`, configured by its
+    /// LSMConfig::column_families entry
+    pub fn create_cf(&self, name: &str) -> Result<ColumnFamily<'_>> {
+        self.check_writable()?;
+        let mut inner = self.lock();
+        if inner.families.iter().any(|family| family.name == name) {
+            return Err(DbError::FamilyExists(name.to_string()));
+        }
+        let family = Family::new(
+            inner.manifest.next_family_id,
+            name,
+            &inner.config,
+            inner.memtable.seq_num(),
+        );
+        fs::create_dir_all(self.path.join(family::family_dir_name(family.id)))?;
+        inner.manifest.create_family(name, family.config.max_levels);
+        inner.commit_manifest()?;
+
+        let handle = ColumnFamily::new(self, family.id, name.to_string());
+        inner.families.push(family);
+        Ok(handle)
+    }
+
+    /// the column family `name`
+    pub fn cf(&self, name: &str) -> Result<ColumnFamily<'_>> {
+        let inner = self.lock();
+        match inner.families.iter().find(|family| family.name == name) {
+            Some(family) => Ok(ColumnFamily::new(self, family.id, name.to_string())),
+            None => Err(DbError::NoSuchFamily(name.to_string())),
+        }
+    }
+
+    /// names of the column families besides the default one, oldest first
+    pub fn list_cfs(&self) -> Vec<String> {
+        let inner = self.lock();
+        let names = inner.families.iter().map(|family| family.name.clone());
+        names.filter(|name| name != CONSUMER_FAMILY).collect()
+    }
+
+    /// drop column family `name` and everything in it; false if there was
+    /// none
+    ///
+    /// waits for a running flush or compaction. Its writes still in the WAL
+    /// are skipped on replay; family ids are never reused
+    pub fn drop_cf(&self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        let _flushing = self.shared.flush.lock().unwrap_or_else(|e| e.into_inner());
+        let _compacting = self.shared.compaction.lock().unwrap_or_else(|e| e.into_inner());
+        let mut inner = self.lock();
+        let Some(i) = inner.families.iter().position(|family| family.name == name) else {
+            return Ok(false);
+        };
+        let id = inner.families.remove(i).id;
+        for imm in &mut inner.immutables {
+            imm.families.retain(|(family, _)| *family != id);
+        }
+        let dropped = inner.manifest.drop_family(id);
+        inner.commit_manifest()?;
+        drop(inner);
+
+        for sst in dropped.iter().flat_map(|family| family.tables.all_tables()) {
+            self.shared.table_cache.evict(sst.id);
+        }
+        // what a failed delete leaves, purge_obsolete_files removes later
+        fs::remove_dir_all(self.path.join(family::family_dir_name(id))).ok();
+        Ok(true)
+    }
+
+    /// write a batch to column family `family`, see ColumnFamily
+    pub(crate) fn write_in(
+        &self,
+        family: u32,
+        batch: &WriteBatch,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.write_locked(self.lock(), family, batch.iter(), options, |wal, seq| {
+            wal.append_family_batch(seq, family, batch.data())
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[deprecated(note = "use DB::options")]
+    pub fn config(&self) -> LSMConfig {
+        self.options()
+    }
+
+    /// the config as DB::set_options left it
+    pub fn options(&self) -> LSMConfig {
+        self.lock().config.clone()
+    }
+
+    /// change settings of the open database by name, e.g.
+    /// `("memtable_size", "8388608")`; see config::MUTABLE_OPTIONS
+    /// - all of them apply or, if one is invalid or the result fails
+    ///   LSMConfig::validate, none do
+    /// - the next memtable, flush and compaction go by the new values;
+    ///   files already written stay as they are
+    /// - column families without their own LSMConfig::column_families
+    ///   entry follow the DB's settings, the others keep theirs
+    /// - the OPTIONS file is rewritten with the new values
+    pub fn set_options(&self, options: &[(&str, &str)]) -> Result<()> {
+        self.check_writable()?;
+        let mut inner = self.lock();
+        let mut config = inner.config.clone();
+        for (name, value) in options {
+            config.set_option(name, value).map_err(DbError::InvalidOption)?;
+        }
+        if let Err(ConfigError::Invalid(msg)) = config.validate() {
+            return Err(DbError::InvalidOption(msg));
+        }
+        // the families' new configs are all worked out before any is set
+        let mut family_configs = Vec::new();
+        for family in &inner.families {
+            if config.column_families.contains_key(&family.name) {
+                continue;
+            }
+            let mut family_config = family.config.clone();
+            for (name, value) in options {
+                family_config.set_option(name, value).map_err(DbError::InvalidOption)?;
+            }
+            if let Err(ConfigError::Invalid(msg)) = family_config.validate() {
+                return Err(DbError::InvalidOption(format!("{}: {}", family.name, msg)));
+            }
+            family_configs.push((family.id, family_config));
+        }
+        let recorded = OptionsFile::load(&self.path)?;
+        OptionsFile::succeeding(&config, recorded.as_ref()).save(&self.path)?;
+
+        for (id, family_config) in family_configs {
+            if let Some(family) = inner.families.iter_mut().find(|family| family.id == id) {
+                family.config = family_config;
+            }
+        }
+        inner.config = config;
+        // a lower L0 trigger may make a compaction due right away
+        inner.compaction_pending = true;
+        drop(inner);
+        self.shared.compaction_signal.notify_one();
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DbInner> {
+        self.shared.lock()
+    }
+
+    /// fails with ReadOnly if opened with open_read_only
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(DbError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// lock once the read's min_token is visible, waiting up to its timeout
+    fn lock_visible(&self, options: &ReadOptions) -> Result<MutexGuard<'_, DbInner>> {
+        let mut inner = self.lock();
+        let Some(token) = options.min_token else {
+            return Ok(inner);
+        };
+        if read_seq(options) < token.seq() {
+            return Err(DbError::TokenNotVisible(token.seq()));
+        }
+
+        let deadline = Instant::now() + options.token_timeout;
+        while inner.memtable.seq_num() < token.seq() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DbError::TokenNotVisible(token.seq()));
+            }
+            inner = self
+                .shared
+                .write_signal
+                .wait_timeout(inner, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        Ok(inner)
+    }
+
+    /// run a manual job on its own thread; joined when the DB shuts down
+    fn spawn_job(
+        &self,
+        job: &JobHandle,
+        run: impl FnOnce(&Path, &Shared) + Send + 'static,
+    ) -> Result<()> {
+        let (dir, shared) = (self.path.clone(), Arc::clone(&self.shared));
+        let spawned = thread::Builder::new()
+            .name("kvstore-job".to_string())
+            .spawn(move || run(&dir, &shared));
+
+        match spawned {
+            Ok(handle) => {
+                let mut threads = self.job_threads.lock().unwrap_or_else(|e| e.into_inner());
+                threads.retain(|thread| !thread.is_finished());
+                threads.push(handle);
+                Ok(())
+            }
+            Err(e) => {
+                // nobody will run it, so fail everyone who joined
+                let error = DbError::Io(e);
+                self.shared.abandon(job);
+                job.finish(&Err(DbError::Job(error.to_string())));
+                Err(error)
+            }
+        }
+    }
+
+    /// see close()
+    fn shutdown(&mut self) -> Result<()> {
+        self.stop_background();
+
+        if self.read_only {
+            return Ok(());
+        }
+        let synced = {
+            let mut inner = self.lock();
+            inner.wal()?.sync().map_err(DbError::from).and_then(|()| inner.commit_manifest())
+        };
+        // outputs of the cancelled jobs
+        let purged = synced.and_then(|()| purge_obsolete_files(&self.path, &self.shared));
+        // the directory can be opened again, whatever went wrong
+        self.lock_file = None;
+        purged.map(drop)
+    }
+
+    fn stop_background(&mut self) {
+        self.shared.cancel.cancel();
+        self.lock().shutdown = true;
+        self.shared.compaction_signal.notify_all();
+        self.shared.flush_signal.notify_all();
+        self.shared.flush_done.notify_all();
+        self.shared.sync_signal.notify_all();
+
+        let threads = [self.flusher.take(), self.compactor.take(), self.syncer.take()];
+        for handle in threads.into_iter().flatten() {
+            let _ = handle.join();
+        }
+        let jobs = std::mem::take(&mut *self.job_threads.lock().unwrap_or_else(|e| e.into_inner()));
+        for handle in jobs {
+            let _ = handle.join();
+        }
+    }
+
+    /// log a record, apply its operations to the memtable and flush if it filled up
+    ///
+    /// a synchronous write then waits for its record to reach the disk
+    fn write_ops<'a>(
+        &self,
+        ops: impl Iterator<Item = BatchOp<'a>> + Clone,
+        options: &WriteOptions,
+        log: impl FnOnce(&mut WalWriter, u64) -> std::result::Result<(), WalError>,
+    ) -> Result<()> {
+        self.write_locked(self.lock(), DEFAULT_FAMILY, ops, options, log)
+    }
+
+    /// write_ops to column family `family`, with the DB lock already held
+    ///
+    /// under LSMConfig::ttl the operations are stamped and logged as a batch
+    /// instead of through `log`
+    fn write_locked<'a>(
+        &self,
+        inner: MutexGuard<'_, DbInner>,
+        family: u32,
+        ops: impl Iterator<Item = BatchOp<'a>> + Clone,
+        options: &WriteOptions,
+        log: impl FnOnce(&mut WalWriter, u64) -> std::result::Result<(), WalError>,
+    ) -> Result<()> {
+        if inner.config.ttl.is_none() {
+            return self.write_logged(inner, family, ops, options, log);
+        }
+        let stamped = ttl::stamp_batch(ops, unix_now());
+        self.write_logged(inner, family, stamped.iter(), options, |wal, seq| match family {
+            DEFAULT_FAMILY => wal.append_batch(seq, stamped.data()),
+            _ => wal.append_family_batch(seq, family, stamped.data()),
+        })
+    }
+
+    /// write_locked once the operations are final
+    ///
+    /// append mode applies to the default family only
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(family = family))
+    )]
+    fn write_logged<'a>(
+        &self,
+        mut inner: MutexGuard<'_, DbInner>,
+        family: u32,
+        ops: impl Iterator<Item = BatchOp<'a>> + Clone,
+        options: &WriteOptions,
+        log: impl FnOnce(&mut WalWriter, u64) -> std::result::Result<(), WalError>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        inner.memtable(family)?;
+        inner.wal()?;
+        let merges = ops.clone().any(|op| matches!(op, BatchOp::Merge { .. }));
+        if merges && inner.family_config(family).merge_operator.is_none() {
+            return Err(DbError::NoMergeOperator);
+        }
+        if let Some(limit) = inner.config.max_disk_bytes {
+            let used = inner.disk_bytes();
+            let puts = ops
+                .clone()
+                .any(|op| matches!(op, BatchOp::Put { .. } | BatchOp::Merge { .. }));
+            if used >= limit && puts {
+                return Err(DbError::QuotaExceeded { used, limit });
+            }
+        }
+        if let Some((name, limit)) = inner.family_quota(family) {
+            let used = inner.family_disk_bytes(family)?;
+            let puts = ops
+                .clone()
+                .any(|op| matches!(op, BatchOp::Put { .. } | BatchOp::Merge { .. }));
+            if used >= limit && puts {
+                return Err(DbError::FamilyQuotaExceeded {
+                    family: name.to_string(),
+                    used,
+                    limit,
+                });
+            }
+        }
+
+        if inner.config.append_mode == AppendMode::Strict && family == DEFAULT_FAMILY {
+            let mut max = inner.max_key.as_deref();
+            for op in ops.clone() {
+                check_append_order(op, &mut max)?;
+            }
+        }
+
+        // the record carries the sequence number of its first operation
+        let seq = inner.memtable.seq_num() + 1;
+        let wal = inner.wal()?;
+        let wal_offset = wal.offset();
+        log(wal, seq)?;
+        let user_bytes: usize = ops
+            .clone()
+            .map(|op| match op {
+                BatchOp::Put { key, value } | BatchOp::Merge { key, value } => {
+                    key.len() + value.len()
+                }
+                BatchOp::Delete { key } | BatchOp::SingleDelete { key } => key.len(),
+                BatchOp::DeleteRange { start, end } => start.len() + end.len(),
+            })
+            .sum();
+        let wal_bytes = inner.wal()?.offset() - wal_offset;
+        inner.amplification.user_bytes += user_bytes as u64;
+        inner.amplification.disk_bytes += wal_bytes;
+        self.shared.tick(Ticker::BytesWritten, user_bytes as u64);
+        self.shared.tick(Ticker::KeysWritten, ops.clone().count() as u64);
+        self.shared.tick(Ticker::WalBytesWritten, wal_bytes);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(seq, bytes = user_bytes, wal_bytes, "write logged");
+        let oldest_snapshot = self.shared.snapshots.oldest();
+        if family == DEFAULT_FAMILY {
+            inner.memtable.set_oldest_snapshot(oldest_snapshot);
+            let append_mode = inner.config.append_mode;
+            for op in ops {
+                inner.apply(op, append_mode)?;
+            }
+        } else {
+            inner.apply_to_family(family, ops, oldest_snapshot)?;
+        }
+        self.shared.write_signal.notify_all();
+
+        let position = inner.memtable.seq_num();
+        let sync = options.sync || inner.config.wal_sync == WalSyncPolicy::Always;
+        self.maybe_flush(inner)?;
+        if sync {
+            sync_wal(&self.shared, position)?;
+        }
+        self.shared.record_latency(Latency::Write, start);
+        Ok(())
+    }
+
+    /// freeze a full memtable, then flush inline or stall while the queue is full
+    fn maybe_flush(&self, mut inner: MutexGuard<'_, DbInner>) -> Result<()> {
+        let families = inner.families.iter().map(|family| &family.memtable);
+        if std::iter::once(&inner.memtable).chain(families).any(Memtable::is_full) {
+            freeze_memtable(&self.path, &self.shared, &mut inner)?;
+        }
+
+        if !inner.config.background_flush {
+            drop(inner);
+            while flush_once(&self.path, &self.shared, &self.shared.cancel)? {}
+            return Ok(());
+        }
+
+        while inner.immutables.len() > inner.config.max_immutable_memtables {
+            if let Some(e) = &inner.flush_error {
+                return Err(DbError::Flush(e.clone()));
+            }
+            if inner.shutdown {
+                break;
+            }
+            let stalled = Instant::now();
+            inner = self
+                .shared
+                .flush_done
+                .wait(inner)
+                .unwrap_or_else(|e| e.into_inner());
+            let micros = stalled.elapsed().as_micros() as u64;
+            self.shared.tick(Ticker::StallMicros, micros);
+            #[cfg(feature = "tracing")]
+            tracing::warn!(immutables = inner.immutables.len(), micros, "write stalled");
+        }
+        Ok(())
+    }
+
+    /// look a key up in one SSTable as of sequence number `seq`
+    ///
+    /// returns the newest of the table's range tombstones over the key (0 if
+    /// none), and the versions a read folds, newest first, see
+    /// SSTableReader::versions_at_counting; empty if none is visible
+    fn table_get(
+        &self,
+        sst: &SSTableMetadata,
+        key: &[u8],
+        seq: u64,
+        stats: &mut ReadStats,
+        blocks: &mut u64,
+    ) -> Result<(u64, Vec<(u64, StoredValue)>)> {
+        let reader = self.shared.table_cache.get(&self.path, sst)?;
+        stats.tables_probed += 1;
+        self.shared.tick(Ticker::BloomChecked, 1);
+        let covering = reader.range_tombstones().covering_seq(key, seq);
+        if !reader.may_contain(key) {
+            stats.bloom_negatives += 1;
+            self.shared.tick(Ticker::BloomUseful, 1);
+            return Ok((covering, Vec::new()));
+        }
+        Ok((covering, reader.versions_at_counting(key, seq, blocks)?))
+    }
+}
+
+impl AmplificationStats {
+    fn record_get(&mut self, blocks: u64) {
+        if self.recent_gets.len() == READ_AMP_WINDOW {
+            self.recent_blocks -= self.recent_gets.pop_front().unwrap();
+        }
+        self.recent_gets.push_back(blocks);
+        self.recent_blocks += blocks;
+    }
+}
+
+impl DbInner {
+    /// id for a new table; its file is safe from GC until the id leaves
+    /// pending_outputs
+    fn allocate_table_id(&mut self) -> u64 {
+        let id = self.manifest.next_sstable_id();
+        self.pending_outputs.insert(id);
+        id
+    }
+
+    /// log the manifest's changes since the last commit
+    fn commit_manifest(&mut self) -> Result<()> {
+        let log = self.manifest_log.as_mut().ok_or(DbError::ReadOnly)?;
+        Ok(log.commit(&mut self.manifest)?)
+    }
+
+    /// the active WAL segment; fails with ReadOnly when opened read-only
+    fn wal(&mut self) -> Result<&mut WalWriter> {
+        self.wal.as_mut().ok_or(DbError::ReadOnly)
+    }
+
+    /// drop the archived WAL segments every consumer has acked to the end
+    /// of; returns them for deleting outside the lock
+    fn release_archived_wals(&mut self) -> Vec<PathBuf> {
+        let slowest = self.consumers.values().min().copied().unwrap_or(u64::MAX);
+        let (released, kept) = std::mem::take(&mut self.archived_wals)
+            .into_iter()
+            .partition(|&(_, last)| last <= slowest);
+        self.archived_wals = kept;
+        released.into_iter().map(|(path, _)| path).collect()
+    }
+
+    /// table bytes plus memtable bytes, which their WAL segments mirror,
+    /// over every column family
+    fn disk_bytes(&self) -> u64 {
+        let tables: u64 = self.manifest.all_tables().map(|sst| sst.size).sum();
+        let frozen = self.immutables.iter().flat_map(|imm| {
+            let families = imm.families.iter().map(|(_, memtable)| memtable.as_ref());
+            std::iter::once(imm.memtable.as_ref()).chain(families)
+        });
+        let families = self.families.iter().map(|family| &family.memtable);
+        let memtables = std::iter::once(&self.memtable).chain(families).chain(frozen);
+        tables + memtables.map(Memtable::size).sum::<usize>() as u64
+    }
+
+    /// table and memtable bytes of column family `id`, frozen memtables
+    /// included
+    fn family_disk_bytes(&self, id: u32) -> Result<u64> {
+        let tables = self.manifest.family(id).ok_or(DbError::FamilyDropped(id))?;
+        let tables: u64 = (0..tables.levels.len()).map(|level| tables.level_size(level)).sum();
+        let memtables: usize = self.memtables(id)?.into_iter().map(Memtable::size).sum();
+        Ok(tables + memtables as u64)
+    }
+
+    /// name and max_disk_bytes of column family `id`, if its
+    /// LSMConfig::column_families entry sets one; a family without an
+    /// entry shares the DB-wide quota only
+    fn family_quota(&self, id: u32) -> Option<(&str, u64)> {
+        let family = self.families.iter().find(|family| family.id == id)?;
+        if !self.config.column_families.contains_key(&family.name) {
+            return None;
+        }
+        Some((&family.name, family.config.max_disk_bytes?))
+    }
+
+    fn family_status(&self, id: u32) -> FamilyStatus {
+        let name = self.families.iter().find(|family| family.id == id);
+        let manifest = self.manifest.family(id).unwrap_or(&self.manifest);
+        let levels: Vec<LevelStatus> = (0..manifest.levels.len())
+            .map(|level| {
+                let files = manifest.get_level(level);
+                LevelStatus {
+                    level,
+                    files: files.len(),
+                    bytes: files.iter().map(|sst| sst.size).sum(),
+                    entries: files.iter().map(|sst| sst.num_entries).sum(),
+                }
+            })
+            .collect();
+        FamilyStatus {
+            id,
+            name: name.map(|family| family.name.clone()),
+            files: levels.iter().map(|level| level.files).sum(),
+            levels,
+            disk_bytes: self.family_disk_bytes(id).unwrap_or(0),
+            disk_quota: self.family_quota(id).map(|(_, limit)| limit),
+            compaction_debt_bytes: compaction_debt(manifest, self.family_config(id)),
+        }
+    }
+
+    /// the live memtable of column family `id`
+    fn memtable(&self, id: u32) -> Result<&Memtable> {
+        if id == DEFAULT_FAMILY {
+            return Ok(&self.memtable);
+        }
+        let family = self.families.iter().find(|family| family.id == id);
+        family.map(|family| &family.memtable).ok_or(DbError::FamilyDropped(id))
+    }
+
+    /// memtables of family `id`, the active one then the frozen ones newest
+    /// first
+    fn memtables(&self, id: u32) -> Result<Vec<&Memtable>> {
+        let frozen = self.immutables.iter().rev().filter_map(|imm| imm.memtable(id));
+        Ok(std::iter::once(self.memtable(id)?).chain(frozen).collect())
+    }
+
+    /// the config of column family `id`
+    fn family_config(&self, id: u32) -> &LSMConfig {
+        let family = self.families.iter().find(|family| family.id == id);
+        family.map_or(&self.config, |family| &family.config)
+    }
+
+    /// insert logged operations into column family `id`'s memtable
+    ///
+    /// its sequence numbers continue from the default memtable's, which
+    /// then moves past them, so every family draws from one counter
+    fn apply_to_family<'a>(
+        &mut self,
+        id: u32,
+        ops: impl Iterator<Item = BatchOp<'a>>,
+        oldest_snapshot: Option<u64>,
+    ) -> Result<()> {
+        let seq = self.memtable.seq_num();
+        let family = self.families.iter_mut().find(|family| family.id == id);
+        let memtable = &mut family.ok_or(DbError::FamilyDropped(id))?.memtable;
+        memtable.advance_seq(seq);
+        memtable.set_oldest_snapshot(oldest_snapshot);
+        for op in ops {
+            match op {
+                BatchOp::Put { key, value } => memtable.put(key, value),
+                BatchOp::Delete { key } => memtable.delete(key),
+                BatchOp::SingleDelete { key } => memtable.single_delete(key),
+                BatchOp::Merge { key, value } => memtable.merge(key, value),
+                BatchOp::DeleteRange { start, end } => memtable.delete_range(start, end),
+            }
+            .map_err(DbError::Memtable)?;
+        }
+        let seq = memtable.seq_num();
+        self.memtable.advance_seq(seq);
+        Ok(())
+    }
+
+    /// insert one logged operation into the memtable
+    fn apply(&mut self, op: BatchOp, mode: AppendMode) -> Result<()> {
+        match op {
+            BatchOp::Put { key, value } => {
+                let in_order = self.max_key.as_deref().is_none_or(|max| key > max);
+                if mode != AppendMode::Off && in_order {
+                    self.memtable.append(key, value).map_err(DbError::Memtable)?;
+                } else {
+                    self.memtable.put(key, value).map_err(DbError::Memtable)?;
+                }
+                self.track_put(key, mode != AppendMode::Off);
+            }
+            BatchOp::Delete { key }
+            | BatchOp::SingleDelete { key }
+            | BatchOp::Merge { key, .. } => {
+                match op {
+                    BatchOp::Merge { value, .. } => self.memtable.merge(key, value),
+                    BatchOp::SingleDelete { .. } => self.memtable.single_delete(key),
+                    _ => self.memtable.delete(key),
+                }
+                .map_err(DbError::Memtable)?;
+
+                // deleting old keys is normal retention, not an ordering violation
+                if self.max_key.as_deref().is_some_and(|max| key <= max) {
+                    self.memtable_sequential = false;
+                } else {
+                    self.max_key = Some(key.to_vec());
+                }
+            }
+            // adds no keys, so the memtable stays as sequential as it was
+            BatchOp::DeleteRange { start, end } => {
+                self.memtable.delete_range(start, end).map_err(DbError::Memtable)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn track_put(&mut self, key: &[u8], detect: bool) {
+        let in_order = self.max_key.as_deref().is_none_or(|max| key > max);
+
+        if in_order {
+            self.max_key = Some(key.to_vec());
+        } else {
+            self.memtable_sequential = false;
+        }
+
+        if !detect {
+            return;
+        }
+        if in_order {
+            self.append_stats.in_order += 1;
+        } else {
+            self.append_stats.out_of_order += 1;
+            if self.append_stats.first_out_of_order.is_none() {
+                self.append_stats.first_out_of_order = Some(key.to_vec());
+            }
+        }
+    }
+}
+
+impl Family {
+    fn new(id: u32, name: &str, config: &LSMConfig, start_seq: u64) -> Self {
+        let mut family_config = config.column_families.get(name).unwrap_or(config).clone();
+        family_config.ttl = config.ttl;
+        let config = family_config;
+        Self {
+            id,
+            name: name.to_string(),
+            memtable: Memtable::with_start_seq(config.memtable_size, start_seq),
+            config,
+        }
+    }
+}
+
+impl Immutable {
+    fn memtable(&self, family: u32) -> Option<&Memtable> {
+        if family == DEFAULT_FAMILY {
+            return Some(&self.memtable);
+        }
+        let frozen = self.families.iter().find(|(id, _)| *id == family);
+        frozen.map(|(_, memtable)| memtable.as_ref())
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, DbInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// call `event` on every listener, in registration order
+    fn notify(&self, event: impl Fn(&dyn EventListener)) {
+        for listener in &self.listeners {
+            event(listener.as_ref());
+        }
+    }
+
+    /// add to a Statistics counter, if statistics are on
+    fn tick(&self, ticker: Ticker, value: u64) {
+        if let Some(statistics) = &self.statistics {
+            statistics.add(ticker, value);
+        }
+    }
+
+    /// record the time since `start` in a Statistics histogram, if on
+    fn record_latency(&self, latency: Latency, start: Instant) {
+        if let Some(statistics) = &self.statistics {
+            statistics.record_latency(latency, start.elapsed());
+        }
+    }
+
+    fn lock_jobs(&self) -> MutexGuard<'_, PendingJobs> {
+        self.pending_jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// the queued flush job, or a new one the caller must run (true)
+    fn request_flush(&self) -> (JobHandle, bool) {
+        let mut jobs = self.lock_jobs();
+        if let Some(job) = &jobs.flush {
+            return (job.clone(), false);
+        }
+        let job = JobHandle::new(self.cancel.child());
+        jobs.flush = Some(job.clone());
+        (job, true)
+    }
+
+    /// the queued compaction of exactly `range`, or a new one the caller must run
+    fn request_compaction(&self, family: u32, range: &OwnedRange) -> (JobHandle, bool) {
+        let mut jobs = self.lock_jobs();
+        let same = |(id, queued, _): &&(u32, OwnedRange, JobHandle)| {
+            *id == family && queued == range
+        };
+        if let Some((_, _, job)) = jobs.compactions.iter().find(same) {
+            return (job.clone(), false);
+        }
+        let job = JobHandle::new(self.cancel.child());
+        jobs.compactions.push((family, range.clone(), job.clone()));
+        (job, true)
+    }
+
+    /// stop new requests from joining `job`
+    fn abandon(&self, job: &JobHandle) {
+        let mut jobs = self.lock_jobs();
+        if jobs.flush.as_ref().is_some_and(|queued| queued.same_job(job)) {
+            jobs.flush = None;
+        }
+        jobs.compactions.retain(|(_, _, queued)| !queued.same_job(job));
+    }
+
+    /// a flush or compaction thread run is over
+    fn finish_background_job(&self) {
+        self.lock().background_jobs -= 1;
+        self.background_idle.notify_all();
+    }
+
+    fn record_error(&self, error: String) {
+        push_error(&mut self.lock().background_errors, error);
+    }
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.shutdown();
+        }
+    }
+}
+
+/// wait for a frozen memtable, then flush until the queue is empty
+///
+/// a failed flush is kept for stalled writers and retried on the next signal
+fn flush_loop(dir: PathBuf, shared: Arc<Shared>) {
+    loop {
+        {
+            let mut inner = shared.lock();
+            while (inner.immutables.is_empty() || inner.background_paused > 0) && !inner.shutdown {
+                inner = shared
+                    .flush_signal
+                    .wait(inner)
+                    .unwrap_or_else(|e| e.into_inner());
+            }
+            if inner.shutdown {
+                return;
+            }
+            inner.background_jobs += 1;
+        }
+
+        let result = flush_queued(&dir, &shared);
+        shared.finish_background_job();
+        match result {
+            Ok(()) => {}
+            Err(DbError::Cancelled) => return,
+            Err(e) => {
+                shared.record_error(format!("flush: {}", e));
+                shared.lock().flush_error = Some(e.to_string());
+                shared.flush_done.notify_all();
+
+                let inner = shared.lock();
+                let _ = shared
+                    .flush_signal
+                    .wait_timeout(inner, COMPACTION_POLL_INTERVAL)
+                    .unwrap_or_else(|e| e.into_inner());
+            }
+        }
+    }
+}
+
+/// flush frozen memtables until none is left, or until shutdown or
+/// pause_background_work
+fn flush_queued(dir: &Path, shared: &Shared) -> Result<()> {
+    while flush_once(dir, shared, &shared.cancel)? {
+        let inner = shared.lock();
+        if inner.shutdown || inner.background_paused > 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// move the memtable and its WAL onto the flush queue and start fresh ones
+///
+/// every column family's memtable goes with it, since they share the WAL
+fn freeze_memtable(dir: &Path, shared: &Shared, inner: &mut DbInner) -> Result<()> {
+    let families = inner.families.iter().map(|family| &family.memtable);
+    if std::iter::once(&inner.memtable).chain(families).all(Memtable::is_empty) {
+        return Ok(());
+    }
+
+    // seal the segment; it is deleted once its memtable is in a table
+    inner.wal()?.sync()?;
+    let segment = dir.join(wal_segment_name(inner.manifest.next_wal_seq()));
+    let fresh = WalWriter::create(segment)?;
+    let sealed = std::mem::replace(inner.wal()?, fresh);
+    let wal_path = sealed.path().to_path_buf();
+
+    let seq = inner.memtable.seq_num();
+    let families = inner
+        .families
+        .iter_mut()
+        .filter(|family| !family.memtable.is_empty())
+        .map(|family| {
+            let fresh = Memtable::with_start_seq(family.config.memtable_size, seq);
+            (family.id, Arc::new(std::mem::replace(&mut family.memtable, fresh)))
+        })
+        .collect();
+    let fresh = Memtable::with_start_seq(inner.config.memtable_size, seq);
+    let memtable = std::mem::replace(&mut inner.memtable, fresh);
+    inner.immutables.push(Immutable {
+        memtable: Arc::new(memtable),
+        families,
+        wal_path,
+        sequential: inner.memtable_sequential,
+    });
+    inner.memtable_sequential = true;
+    #[cfg(feature = "tracing")]
+    tracing::debug!(seq, immutables = inner.immutables.len(), "memtable frozen");
+
+    shared.flush_signal.notify_one();
+    Ok(())
+}
+
+/// run a manual flush once the previous one is done
+///
+/// the job leaves the pending slot as it starts, so later requests queue
+/// a new one for the writes this flush won't see
+fn run_flush_job(dir: &Path, shared: &Shared, job: &JobHandle) -> Result<()> {
+    let _running = shared.flush_jobs.lock().unwrap_or_else(|e| e.into_inner());
+    shared.abandon(job);
+    job.start();
+
+    let result = flush_all(dir, shared, job.cancel_token());
+    job.finish(&result);
+    result
+}
+
+/// flush the oldest frozen memtable, one table per column family in it
+///
+/// the tables are written without holding the DB lock; readers keep using the
+/// frozen memtables until the manifest lists their tables
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+fn flush_once(dir: &Path, shared: &Shared, cancel: &CancelToken) -> Result<bool> {
+    let _flushing = shared.flush.lock().unwrap_or_else(|e| e.into_inner());
+    let start = Instant::now();
+
+    let (imm, outputs) = {
+        let mut inner = shared.lock();
+        let Some(imm) = inner.immutables.first().cloned() else {
+            return Ok(false);
+        };
+        let mut outputs = Vec::new();
+        let default = (DEFAULT_FAMILY, &imm.memtable);
+        let families = imm.families.iter().map(|(family, memtable)| (*family, memtable));
+        for (family, memtable) in std::iter::once(default).chain(families) {
+            if !memtable.is_empty() {
+                let family_config = inner.family_config(family).clone();
+                let id = inner.allocate_table_id();
+                outputs.push((family, Arc::clone(memtable), family_config, id));
+            }
+        }
+        (imm, outputs)
+    };
+    let frozen_bytes: usize = imm.families.iter().map(|(_, memtable)| memtable.size()).sum();
+    let mut info = FlushJobInfo {
+        largest_seq: imm.memtable.seq_num(),
+        memtable_bytes: imm.memtable.size() + frozen_bytes,
+        tables: Vec::new(),
+        elapsed: Duration::ZERO,
+    };
+    shared.notify(|listener| listener.on_flush_begin(&info));
+
+    let written: Result<Vec<(u32, SSTableMetadata)>> = outputs
+        .iter()
+        .map(|(family, memtable, config, id)| {
+            let sequential = *family == DEFAULT_FAMILY && imm.sequential;
+            let restart_interval = if sequential && config.append_mode != AppendMode::Off {
+                APPEND_RESTART_INTERVAL
+            } else {
+                DEFAULT_RESTART_INTERVAL
+            };
+            write_table(dir, *family, *id, memtable, restart_interval, config, cancel)
+                .map(|metadata| (*family, metadata))
+        })
+        .collect();
+
+    let mut inner = shared.lock();
+    for (_, _, _, id) in &outputs {
+        inner.pending_outputs.remove(id);
+    }
+    for (family, metadata) in written? {
+        inner.amplification.disk_bytes += metadata.size;
+        shared.tick(Ticker::FlushBytesWritten, metadata.size);
+        if let Some(manifest) = inner.manifest.family_mut(family) {
+            manifest.add_sstable(0, metadata.clone());
+        }
+        info.tables.push(TableFileInfo {
+            family,
+            path: dir.join(&metadata.path),
+            table: metadata,
+        });
+    }
+    // an ingest may have moved it past the frozen memtable
+    inner.manifest.last_sequence = inner.manifest.last_sequence.max(imm.memtable.seq_num());
+    inner.commit_manifest()?;
+    inner.immutables.remove(0);
+    inner.flush_error = None;
+
+    // replaying a WAL whose table is already listed only rewrites the same
+    // values; one a change feed consumer still needs is moved out of the way
+    let last = imm.memtable.seq_num();
+    if inner.consumers.values().any(|&acked| acked < last) {
+        match archive_wal(dir, &imm.wal_path) {
+            Ok(path) => inner.archived_wals.push((path, last)),
+            Err(e) => {
+                fs::remove_file(&imm.wal_path).ok();
+                let error = format!("WAL archive failed, consumers miss writes: {}", e);
+                push_error(&mut inner.background_errors, error);
+            }
+        }
+    } else {
+        fs::remove_file(&imm.wal_path).ok();
+    }
+
+    inner.compaction_pending = true;
+    drop(inner);
+    shared.compaction_signal.notify_one();
+    shared.flush_done.notify_all();
+    shared.record_latency(Latency::Flush, start);
+
+    for table in &info.tables {
+        shared.notify(|listener| listener.on_table_file_created(table));
+    }
+    info.elapsed = start.elapsed();
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        seq = info.largest_seq,
+        memtable_bytes = info.memtable_bytes,
+        tables = ?info.tables.iter().map(|table| table.table.id).collect::<Vec<_>>(),
+        bytes = info.tables.iter().map(|table| table.table.size).sum::<u64>(),
+        micros = info.elapsed.as_micros() as u64,
+        "flush finished"
+    );
+    shared.notify(|listener| listener.on_flush_completed(&info));
+    Ok(true)
+}
+
+/// freeze the memtable, then flush it and every memtable frozen before it
+fn flush_all(dir: &Path, shared: &Shared, cancel: &CancelToken) -> Result<()> {
+    cancel.check()?;
+    freeze_memtable(dir, shared, &mut shared.lock())?;
+    while flush_once(dir, shared, cancel)? {}
+    Ok(())
+}
+
+/// flush, then compact `range` level by level, once the previous manual
+/// compaction is done
+fn run_compaction_job(
+    dir: &Path,
+    shared: &Shared,
+    job: &JobHandle,
+    family: u32,
+    (lower, upper): &OwnedRange,
+) -> Result<()> {
+    let _running = shared.compaction_jobs.lock().unwrap_or_else(|e| e.into_inner());
+    shared.abandon(job);
+    job.start();
+
+    let cancel = job.cancel_token();
+    let result = compact_range_now(dir, shared, family, lower, upper, cancel);
+    job.finish(&result);
+    result
+}
+
+fn compact_range_now(
+    dir: &Path,
+    shared: &Shared,
+    family: u32,
+    lower: &Bound<Vec<u8>>,
+    upper: &Bound<Vec<u8>>,
+    cancel: &CancelToken,
+) -> Result<()> {
+    flush_all(dir, shared, cancel)?;
+
+    let _compacting = shared.compaction.lock().unwrap_or_else(|e| e.into_inner());
+    let (num_levels, family_config) = {
+        let inner = shared.lock();
+        let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+        (manifest.levels.len(), inner.family_config(family).clone())
+    };
+    for level in 0..num_levels {
+        let task = {
+            let inner = shared.lock();
+            let manifest = inner.manifest.family(family).ok_or(DbError::FamilyDropped(family))?;
+            range_task(manifest, level, lower, upper)
+        };
+        if let Some(mut task) = task {
+            task.family = family;
+            let oldest_snapshot = shared.snapshots.oldest();
+            run_task(dir, &family_config, shared, &task, oldest_snapshot, cancel)?;
+        }
+    }
+    Ok(())
+}
+
+/// wait until the write at sequence `position` is on disk
+///
+/// one writer syncs for everyone queued behind it. Segments sealed since
+/// were synced when they were frozen, so syncing the active one covers
+/// every sequence number handed out so far
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(shared)))]
+fn sync_wal(shared: &Shared, position: u64) -> Result<()> {
+    shared.group_commit.wait(position, || {
+        let start = Instant::now();
+        let (covered, file, path) = {
+            let inner = shared.lock();
+            let wal = inner.wal.as_ref().ok_or(DbError::ReadOnly)?;
+            (inner.memtable.seq_num(), wal.sync_handle()?, wal.path().to_path_buf())
+        };
+        file.sync_all()?;
+        let info = WalSyncInfo {
+            path,
+            synced_seq: covered,
+            elapsed: start.elapsed(),
+        };
+        shared.notify(|listener| listener.on_wal_sync(&info));
+        Ok(covered)
+    })
+}
+
+/// sync whatever was written since the last sync, every `interval`
+fn sync_loop(shared: Arc<Shared>, interval: Duration) {
+    loop {
+        let position = {
+            let inner = shared.lock();
+            let (inner, _) = shared
+                .sync_signal
+                .wait_timeout_while(inner, interval, |inner| !inner.shutdown)
+                .unwrap_or_else(|e| e.into_inner());
+            if inner.shutdown {
+                return;
+            }
+            inner.memtable.seq_num()
+        };
+
+        if let Err(e) = sync_wal(&shared, position) {
+            shared.record_error(format!("WAL sync: {}", e));
+        }
+    }
+}
+
+/// wait for a flush (or the poll interval), then compact until nothing is due
+fn compaction_loop(dir: PathBuf, shared: Arc<Shared>) {
+    loop {
+        {
+            let mut inner = shared.lock();
+            if (!inner.compaction_pending || inner.background_paused > 0) && !inner.shutdown {
+                inner = shared
+                    .compaction_signal
+                    .wait_timeout(inner, COMPACTION_POLL_INTERVAL)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+            }
+            if inner.shutdown {
+                return;
+            }
+            if inner.background_paused > 0 {
+                continue;
+            }
+            inner.compaction_pending = false;
+            inner.background_jobs += 1;
+        }
+
+        let result = compact_due(&dir, &shared);
+        shared.finish_background_job();
+        match result {
+            Ok(()) => {}
+            Err(DbError::Cancelled) => return,
+            Err(e) => {
+                shared.record_error(format!("compaction: {}", e));
+            }
+        }
+    }
+}
+
+/// run compactions until none is due, or until shutdown or
+/// pause_background_work
+fn compact_due(dir: &Path, shared: &Shared) -> Result<()> {
+    while compact_once(dir, shared, false, &shared.cancel)? {
+        let inner = shared.lock();
+        if inner.shutdown || inner.background_paused > 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// run one compaction if one is due; returns false if there was nothing to do
+///
+/// input tables are immutable, so the merge runs without holding the DB lock;
+/// only picking the task and installing its result take it
+fn compact_once(
+    dir: &Path,
+    shared: &Shared,
+    manual: bool,
+    cancel: &CancelToken,
+) -> Result<bool> {
+    let _running = shared.compaction.lock().unwrap_or_else(|e| e.into_inner());
+
+    let (task, family_config, oldest_snapshot) = {
+        let inner = shared.lock();
+        let mut due = None;
+        let schedule = &inner.config.compaction_schedule;
+        for family in inner.manifest.family_ids() {
+            let manifest = inner.manifest.family(family).unwrap();
+            let l0_files = manifest.get_level(0).len();
+            if !manual && schedule.should_defer(SystemTime::now(), l0_files) {
+                continue;
+            }
+            let family_config = inner.family_config(family);
+            if let Some(mut task) = pick_compaction(manifest, family_config, unix_now()) {
+                task.family = family;
+                due = Some((task, family_config.clone()));
+                break;
+            }
+        }
+        match due {
+            Some((task, family_config)) => (task, family_config, shared.snapshots.oldest()),
+            None => return Ok(false),
+        }
+    };
+
+    run_task(dir, &family_config, shared, &task, oldest_snapshot, cancel)?;
+    Ok(true)
+}
+
+/// carry out one compaction task and install its result in the manifest
+///
+/// the caller holds the compaction lock, so the task's files are still current
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            family = task.family,
+            reason = ?task.reason,
+            level = task.level,
+            output_level = task.output_level,
+        )
+    )
+)]
+fn run_task(
+    dir: &Path,
+    config: &LSMConfig,
+    shared: &Shared,
+    task: &CompactionTask,
+    oldest_snapshot: Option<u64>,
+    cancel: &CancelToken,
+) -> Result<()> {
+    shared.lock().active_compaction = Some(CompactionStatus {
+        reason: format!("{:?}", task.reason),
+        level: task.level,
+        output_level: task.output_level,
+        input_files: task.inputs.len() + task.overlapping.len(),
+    });
+    let start = Instant::now();
+    let result = install_task(dir, config, shared, task, oldest_snapshot, cancel);
+    shared.lock().active_compaction = None;
+    let outputs = result?;
+    shared.record_latency(Latency::Compaction, start);
+    let info = CompactionJobInfo {
+        family: task.family,
+        reason: task.reason,
+        level: task.level,
+        output_level: task.output_level,
+        inputs: task.removed(),
+        outputs,
+        trivial_move: task.is_trivial_move(),
+        elapsed: start.elapsed(),
+    };
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        inputs = ?info.inputs.iter().map(|sst| sst.id).collect::<Vec<_>>(),
+        outputs = ?info.outputs.iter().map(|sst| sst.id).collect::<Vec<_>>(),
+        bytes_read = info.inputs.iter().map(|sst| sst.size).sum::<u64>(),
+        bytes_written = info.outputs.iter().map(|sst| sst.size).sum::<u64>(),
+        trivial_move = info.trivial_move,
+        micros = info.elapsed.as_micros() as u64,
+        "compaction finished"
+    );
+    shared.notify(|listener| listener.on_compaction_completed(&info));
+    Ok(())
+}
+
+/// run_task without the status bookkeeping; returns the tables that
+/// replaced the task's inputs
+fn install_task(
+    dir: &Path,
+    config: &LSMConfig,
+    shared: &Shared,
+    task: &CompactionTask,
+    oldest_snapshot: Option<u64>,
+    cancel: &CancelToken,
+) -> Result<Vec<SSTableMetadata>> {
+    if task.is_trivial_move() {
+        let moved: Vec<SSTableMetadata> = task
+            .inputs
+            .iter()
+            .map(|sst| SSTableMetadata {
+                level: task.output_level,
+                ..sst.clone()
+            })
+            .collect();
+
+        let mut inner = shared.lock();
+        let manifest = inner.manifest.family_mut(task.family);
+        let manifest = manifest.ok_or(DbError::FamilyDropped(task.family))?;
+        manifest.apply_edit(&task.inputs, moved.clone());
+        inner.commit_manifest()?;
+        return Ok(moved);
+    }
+
+    let mut allocated = Vec::new();
+    let next_id = || {
+        let id = shared.lock().allocate_table_id();
+        allocated.push(id);
+        id
+    };
+    let outputs = run_compaction(dir, config, task, oldest_snapshot, next_id, cancel);
+
+    let removed = task.removed();
+    let mut inner = shared.lock();
+    for id in &allocated {
+        inner.pending_outputs.remove(id);
+    }
+    let outputs = outputs?;
+    let written = outputs.iter().map(|sst| sst.size).sum::<u64>();
+    inner.amplification.disk_bytes += written;
+    shared.tick(Ticker::CompactionBytesRead, removed.iter().map(|sst| sst.size).sum());
+    shared.tick(Ticker::CompactionBytesWritten, written);
+    let manifest = inner.manifest.family_mut(task.family);
+    manifest.ok_or(DbError::FamilyDropped(task.family))?.apply_edit(&removed, outputs.clone());
+    inner.commit_manifest()?;
+    drop(inner);
+
+    match config.cache_warm_min_hits {
+        Some(min_hits) => {
+            shared.table_cache.replace(dir, &removed, &outputs, min_hits);
+        }
+        None => {
+            for sst in &removed {
+                shared.table_cache.evict(sst.id);
+            }
+        }
+    }
+    for sst in &outputs {
+        let info = TableFileInfo {
+            family: task.family,
+            path: dir.join(&sst.path),
+            table: sst.clone(),
+        };
+        shared.notify(|listener| listener.on_table_file_created(&info));
+    }
+    purge_obsolete_files(dir, shared)?;
+
+    Ok(outputs)
+}
+
+/// delete every file in `dir` the database no longer needs; returns how many
+/// - tables the manifest doesn't list and no running job is writing
+/// - WAL segments behind neither the memtable nor a frozen one
+/// - manifest logs CURRENT doesn't name, and temp files of cut-short writes
+/// - directories of dropped column families, with everything in them
+///
+/// readers only open tables under the lock and keep their contents, so no
+/// reader can still need a table that left the manifest; the files are
+/// picked under the lock and deleted after it
+fn purge_obsolete_files(dir: &Path, shared: &Shared) -> Result<usize> {
+    let obsolete = obsolete_files(dir, &shared.lock())?;
+    let mut deleted = 0;
+    for path in obsolete {
+        if path.is_dir() {
+            deleted += fs::remove_dir_all(&path).is_ok() as usize;
+            continue;
+        }
+        if fs::remove_file(&path).is_err() {
+            continue;
+        }
+        deleted += 1;
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        if let Some(id) = name.strip_suffix(".sst").and_then(|stem| stem.parse().ok()) {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(id, "table deleted");
+            let info = TableFileDeletedInfo { id, path };
+            shared.notify(|listener| listener.on_table_file_deleted(&info));
+        }
+    }
+    Ok(deleted)
+}
+
+fn obsolete_files(dir: &Path, inner: &DbInner) -> Result<Vec<PathBuf>> {
+    // without the WAL and manifest log, every segment and manifest would look
+    // unused
+    let (Some(wal), Some(manifest_log)) = (&inner.wal, &inner.manifest_log) else {
+        return Err(DbError::ReadOnly);
+    };
+    let live_tables: HashSet<&Path> = inner
+        .manifest
+        .all_tables()
+        .map(|sst| sst.path.as_path())
+        .collect();
+    let live_wals: HashSet<&Path> = inner
+        .immutables
+        .iter()
+        .map(|imm| imm.wal_path.as_path())
+        .chain([wal.path()])
+        .collect();
+    let manifest = manifest_log.path().file_name();
+
+    let mut obsolete: Vec<PathBuf> = wal_segments(dir)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .filter(|path| !live_wals.contains(path.as_path()))
+        .collect();
+    // the database directory, then those of live column families
+    let mut dirs = vec![PathBuf::new()];
+    let mut entries = Vec::new();
+    while let Some(relative) = dirs.pop() {
+        for entry in fs::read_dir(dir.join(&relative))? {
+            entries.push((relative.clone(), entry?.path()));
+        }
+        if relative.as_os_str().is_empty() {
+            let ids = inner.manifest.families.iter().map(|family| family.id);
+            dirs.extend(ids.map(family::family_dir_name));
+        }
+    }
+    for (relative, path) in entries {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let table_id = name.strip_suffix(".sst").and_then(|stem| stem.parse::<u64>().ok());
+        let family_id = family::parse_family_dir_name(name).filter(|_| path.is_dir());
+        let unused = if let Some(id) = table_id {
+            let path = relative.join(name);
+            let pinned =
+                inner.pending_outputs.contains(&id) || inner.pinned_tables.contains_key(&id);
+            !pinned && !live_tables.contains(path.as_path())
+        } else if let Some(id) = family_id {
+            // a directory at or past next_family_id may be a create_cf in progress
+            inner.manifest.family(id).is_none() && id < inner.manifest.next_family_id
+        } else if name.ends_with(".tmp") {
+            name.starts_with("MANIFEST") || name.starts_with(version_edit::CURRENT_FILE)
+        } else {
+            version_edit::parse_manifest_file_name(name).is_some()
+                && manifest != Some(name.as_ref())
+        };
+        if unused {
+            obsolete.push(path);
+        }
+    }
+    Ok(obsolete)
+}
+
+fn replay_entry(memtable: &mut Memtable, entry: &WalEntry) -> Result<()> {
+    match entry {
+        WalEntry::Put { key, value } => memtable.put(key, value).map_err(DbError::Memtable),
+        WalEntry::Delete { key } => memtable.delete(key).map_err(DbError::Memtable),
+        WalEntry::SingleDelete { key } => memtable.single_delete(key).map_err(DbError::Memtable),
+        WalEntry::Merge { key, value } => memtable.merge(key, value).map_err(DbError::Memtable),
+        WalEntry::DeleteRange { start, end } => {
+            memtable.delete_range(start, end).map_err(DbError::Memtable)
+        }
+        // replay_wal routes column family records; nested ones never occur
+        WalEntry::Batch { entries } | WalEntry::Family { entries, .. } => entries
+            .iter()
+            .try_for_each(|entry| replay_entry(memtable, entry)),
+    }
+}
+
+/// strict append mode: every put must sort after all keys written before it
+fn check_append_order<'a>(op: BatchOp<'a>, max: &mut Option<&'a [u8]>) -> Result<()> {
+    match op {
+        BatchOp::Put { key, .. } => {
+            if max.is_some_and(|max| key <= max) {
+                return Err(DbError::OutOfOrder(key.to_vec()));
+            }
+            *max = Some(key);
+        }
+        BatchOp::Delete { key } | BatchOp::SingleDelete { key } | BatchOp::Merge { key, .. } => {
+            if max.is_none_or(|max| key > max) {
+                *max = Some(key);
+            }
+        }
+        BatchOp::DeleteRange { .. } => {}
+    }
+    Ok(())
+}
+
+/// name of WAL segment `number`, handed out by the manifest's wal_seq
+fn wal_segment_name(number: u64) -> String {
+    format!("{:06}.log", number)
+}
+
+fn wal_segment_number(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    name.strip_suffix(".log")?.parse().ok()
+}
+
+/// move a flushed WAL segment into the archive; returns where it went
+fn archive_wal(dir: &Path, segment: &Path) -> Result<PathBuf> {
+    let archive = dir.join(WAL_ARCHIVE_DIR);
+    fs::create_dir_all(&archive)?;
+    let to = archive.join(segment.file_name().unwrap_or_default());
+    fs::rename(segment, &to)?;
+    Ok(to)
+}
+
+/// WAL segments in `dir`, oldest first
+/// - logs from before numbered segments (`wal-NNNNNN.log`, then `wal.log`)
+///   sort ahead of every segment and carry no number
+fn wal_segments(dir: &Path) -> Result<Vec<(Option<u64>, PathBuf)>> {
+    let mut legacy = Vec::new();
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let Some(stem) = name.strip_suffix(".log") else {
+            continue;
+        };
+        if stem == "wal" {
+            legacy.push((u64::MAX, path));
+        } else if let Some(number) = stem.strip_prefix("wal-").and_then(|n| n.parse().ok()) {
+            legacy.push((number, path));
+        } else if let Ok(number) = stem.parse::<u64>() {
+            segments.push((number, path));
+        }
+    }
+    legacy.sort();
+    segments.sort();
+
+    Ok(legacy
+        .into_iter()
+        .map(|(_, path)| (None, path))
+        .chain(segments.into_iter().map(|(number, path)| (Some(number), path)))
+        .collect())
+}
+
+/// rebuild the memtables logged in one WAL segment, under the configured
+/// recovery mode: the default family's, and one per entry of `families`
+/// in the same order; anything lost is reported in `errors`, and a torn
+/// tail is cut off the segment unless `read_only`
+fn replay_wal(
+    path: &Path,
+    config: &LSMConfig,
+    families: &[Family],
+    start_seq: u64,
+    read_only: bool,
+    errors: &mut VecDeque<String>,
+) -> Result<(Memtable, Vec<Memtable>)> {
+    let mut memtable = Memtable::with_start_seq(config.memtable_size, start_seq);
+    let mut memtables: Vec<Memtable> = families
+        .iter()
+        .map(|family| Memtable::with_start_seq(family.config.memtable_size, start_seq))
+        .collect();
+    let apply = |record: wal::WalRecord| -> Result<()> {
+        // records older than WAL_VERSION 2 keep counting from the manifest
+        if let Some(seq) = record.seq {
+            memtable.advance_seq(seq.saturating_sub(1));
+        }
+        let WalEntry::Family { id, entries } = &record.entry else {
+            return replay_entry(&mut memtable, &record.entry);
+        };
+        // a dropped family's writes still use up their sequence numbers
+        let Some(i) = families.iter().position(|family| family.id == *id) else {
+            memtable.advance_seq(memtable.seq_num() + entries.len() as u64);
+            return Ok(());
+        };
+        let family = &mut memtables[i];
+        family.advance_seq(memtable.seq_num());
+        entries.iter().try_for_each(|entry| replay_entry(family, entry))?;
+        memtable.advance_seq(family.seq_num());
+        Ok(())
+    };
+    let recovery = if read_only {
+        wal::replay(path, config.wal_recovery, apply)?
+    } else {
+        wal::recover(path, config.wal_recovery, apply)?
+    };
+
+    if recovery.is_lossy() {
+        let error = format!(
+            "WAL recovery: {}: skipped {} damaged records, truncated {} bytes",
+            path.display(),
+            recovery.skipped,
+            recovery.truncated
+        );
+        push_error(errors, error);
+    }
+    Ok((memtable, memtables))
+}
+
+/// make sure every table the manifest lists is there, and with
+/// paranoid_open that it reads back in full
+/// - missing tables fail the open unless ignore_missing_files drops them,
+///   committing that to `log` if there is one
+fn check_tables(
+    dir: &Path,
+    config: &LSMConfig,
+    manifest: &mut Manifest,
+    log: Option<&mut ManifestLog>,
+    errors: &mut VecDeque<String>,
+) -> Result<()> {
+    let (present, missing): (Vec<SSTableMetadata>, Vec<SSTableMetadata>) = manifest
+        .all_tables()
+        .cloned()
+        .partition(|sst| dir.join(&sst.path).exists());
+
+    if !missing.is_empty() {
+        let names: Vec<String> = missing.iter().map(|sst| sst.path.display().to_string()).collect();
+        if !config.ignore_missing_files {
+            return Err(DbError::Corrupted(format!(
+                "Tables listed in the manifest are missing: {}",
+                names.join(", ")
+            )));
+        }
+        for family in manifest.family_ids() {
+            let tables = manifest.family_mut(family).unwrap();
+            let gone: Vec<SSTableMetadata> = missing
+                .iter()
+                .filter(|sst| tables.get_level(sst.level).iter().any(|t| t.id == sst.id))
+                .cloned()
+                .collect();
+            if !gone.is_empty() {
+                tables.remove_sstables(&gone);
+            }
+        }
+        if let Some(log) = log {
+            log.commit(manifest)?;
+        }
+        let error = format!("Dropped {} missing tables: {}", missing.len(), names.join(", "));
+        push_error(errors, error);
+    }
+
+    if config.paranoid_open {
+        for sst in &present {
+            let path = dir.join(&sst.path);
+            let corrupted = |e: String| DbError::Corrupted(format!("{}: {}", path.display(), e));
+            let size = fs::metadata(&path)?.len();
+            if size != sst.size {
+                let e = format!("{} bytes, the manifest says {}", size, sst.size);
+                return Err(corrupted(e));
+            }
+            let reader = SSTableReader::open(&path).map_err(|e| corrupted(e.to_string()))?;
+            for entry in reader.iter() {
+                entry.map_err(|e| corrupted(e.to_string()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// keep `error` for the status page, dropping the oldest past MAX_BACKGROUND_ERRORS
+fn push_error(errors: &mut VecDeque<String>, error: String) {
+    if errors.len() == MAX_BACKGROUND_ERRORS {
+        errors.pop_front();
+    }
+    errors.push_back(error);
+}
+
+/// the manifest CURRENT names, or one from before CURRENT, as
+/// DB::open_read_only reads it: nothing is converted, rebuilt or cut short
+fn read_manifest(dir: &Path, config: &LSMConfig) -> Result<Manifest> {
+    if let Some(current) = version_edit::current_manifest(dir)? {
+        return Ok(ManifestLog::read(current)?);
+    }
+    let legacy = LEGACY_MANIFEST_FILES.iter().map(|name| dir.join(name)).find(|p| p.exists());
+    match legacy {
+        Some(legacy) => Ok(Manifest::load(legacy)?),
+        None => Ok(Manifest::new(config.max_levels)),
+    }
+}
+
+/// load the manifest CURRENT names, or convert one from before CURRENT
+///
+/// a damaged manifest is rebuilt from the tables if the config allows,
+/// with what happened reported in `errors`
+fn open_manifest(
+    dir: &Path,
+    config: &LSMConfig,
+    errors: &mut VecDeque<String>,
+) -> Result<(Manifest, ManifestLog)> {
+    let legacy = LEGACY_MANIFEST_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|legacy| legacy.exists());
+    let loaded = match version_edit::current_manifest(dir) {
+        Ok(Some(current)) => {
+            ManifestLog::open(&current).map(|(manifest, log)| (manifest, Some(log)))
+        }
+        Ok(None) => match &legacy {
+            Some(legacy) => Manifest::load(legacy).map(|manifest| (manifest, None)),
+            None => Ok((Manifest::new(config.max_levels), None)),
+        },
+        Err(e) => Err(e),
+    };
+
+    let rebuild = config.manifest_recovery == ManifestRecoveryMode::RebuildFromTables;
+    let manifest = match loaded {
+        Ok((manifest, Some(log))) => return Ok((manifest, log)),
+        Ok((manifest, None)) => manifest,
+        Err(ManifestError::Corrupted(reason)) if rebuild => {
+            rebuild_manifest(dir, config, legacy.as_deref(), &reason, errors)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // continue numbering after whatever CURRENT named
+    let number = version_edit::current_manifest(dir)
+        .ok()
+        .flatten()
+        .and_then(|current| {
+            let name = current.file_name()?.to_str()?;
+            version_edit::parse_manifest_file_name(name)
+        })
+        .map_or(1, |number| number + 1);
+    let log = ManifestLog::create_current(dir, number, &manifest)?;
+    // the new log holds everything the old manifest did
+    for name in LEGACY_MANIFEST_FILES {
+        fs::remove_file(dir.join(name)).ok();
+    }
+    Ok((manifest, log))
+}
+
+/// set the damaged manifest and any unreadable tables aside, then rebuild
+/// the manifest from the tables that are left
+fn rebuild_manifest(
+    dir: &Path,
+    config: &LSMConfig,
+    legacy: Option<&Path>,
+    reason: &str,
+    errors: &mut VecDeque<String>,
+) -> Result<Manifest> {
+    let damaged = match version_edit::current_manifest(dir) {
+        Ok(Some(current)) => Some(current),
+        _ => legacy.map(Path::to_path_buf),
+    };
+    if let Some(damaged) = damaged {
+        set_aside(&damaged);
+    }
+
+    let (mut manifest, skipped) = Manifest::rebuild(dir, config.max_levels)?;
+    for (table, why) in skipped {
+        // GC would delete a table the manifest doesn't list
+        set_aside(&table);
+        push_error(errors, format!("Manifest rebuild left out {}: {}", table.display(), why));
+    }
+    // new WAL segments must not take the number of one still on disk
+    let newest_segment = wal_segments(dir)?.into_iter().filter_map(|(number, _)| number).max();
+    if let Some(newest) = newest_segment {
+        manifest.wal_seq = manifest.wal_seq.max(newest + 1);
+    }
+
+    let error = format!(
+        "Manifest rebuilt from {} tables: {}",
+        manifest.get_level(0).len(),
+        reason
+    );
+    push_error(errors, error);
+    Ok(manifest)
+}
+
+/// create LOCK in `dir` and lock it; the lock goes with the file
+fn lock_dir(dir: &Path) -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => Err(DbError::AlreadyLocked(dir.to_path_buf())),
+        Err(fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// whether a file named `name` is one the database writes, see DB::destroy
+fn is_database_file(name: &str) -> bool {
+    let name = name.strip_suffix(".corrupt").unwrap_or(name);
+    let name = name.strip_suffix(".tmp").unwrap_or(name);
+    if let Some(stem) = name.strip_suffix(".log") {
+        let legacy = stem.strip_prefix("wal-").unwrap_or(stem);
+        return stem == "wal" || legacy.parse::<u64>().is_ok();
+    }
+    let table = name.strip_suffix(".sst").is_some_and(|stem| stem.parse::<u64>().is_ok());
+    table
+        || name == version_edit::CURRENT_FILE
+        || name == LOCK_FILE
+        || name == OPTIONS_FILE
+        || LEGACY_MANIFEST_FILES.contains(&name)
+        || version_edit::parse_manifest_file_name(name).is_some()
+}
+
+/// rename `path` to `<path>.corrupt`, where nothing reads or deletes it
+fn set_aside(path: &Path) {
+    let mut corrupt = path.as_os_str().to_owned();
+    corrupt.push(".corrupt");
+    fs::rename(path, corrupt).ok();
+}
+
+/// write every retained memtable version into a new L0 table of column
+/// family `family`
+///
+/// `cancel` is checked after every data block; a table that isn't finished
+/// is removed
+fn write_table(
+    dir: &Path,
+    family: u32,
+    id: u64,
+    memtable: &Memtable,
+    restart_interval: usize,
+    config: &LSMConfig,
+    cancel: &CancelToken,
+) -> Result<SSTableMetadata> {
+    let file_name = family::table_file_name(family, id);
+    let result = write_table_file(dir, &file_name, id, memtable, restart_interval, config, cancel);
+    if result.is_err() {
+        fs::remove_file(dir.join(file_name)).ok();
+    }
+    result
+}
+
+fn write_table_file(
+    dir: &Path,
+    file_name: &Path,
+    id: u64,
+    memtable: &Memtable,
+    restart_interval: usize,
+    config: &LSMConfig,
+    cancel: &CancelToken,
+) -> Result<SSTableMetadata> {
+    cancel.check()?;
+    let mut writer = table_writer(dir, file_name, id, 0, restart_interval, config)?;
+    for (key, entry) in memtable.iter_versions() {
+        let blocks = writer.num_blocks();
+        if entry.merge || entry.single_delete {
+            writer.add_value(key, entry.seq_num, &entry.to_stored())?;
+        } else {
+            writer.add(key, entry.seq_num, entry.value.as_deref())?;
+        }
+        if writer.num_blocks() != blocks {
+            cancel.check()?;
+        }
+    }
+    for tombstone in memtable.range_tombstones().iter() {
+        writer.add_range_tombstone(tombstone.clone());
+    }
+    Ok(writer.finish()?)
+}
+
+/// a writer for table `id` at `level`, with the blocks and filters `config`
+/// asks for
+fn table_writer(
+    dir: &Path,
+    file_name: &Path,
+    id: u64,
+    level: usize,
+    restart_interval: usize,
+    config: &LSMConfig,
+) -> Result<SSTableWriter> {
+    let mut writer = SSTableWriter::create(
+        dir,
+        file_name,
+        id,
+        level,
+        restart_interval,
+        config.bloom_bits_per_key,
+    )?
+    .with_compression(config.compression);
+    if let Some(threshold) = config.inline_value_threshold {
+        writer = writer.with_inline_values(threshold);
+    }
+    if let Some(prefix_len) = config.prefix_filter_len {
+        writer = writer.with_prefix_filter(prefix_len, config.prefix_filter_bits_per_prefix);
+    }
+    Ok(writer)
+}
+
+/// whether any of `memtables` holds a key or range tombstone in the key
+/// range of `table`
+fn memtables_overlap(memtables: &[&Memtable], table: &ExternalTable) -> bool {
+    let (min_key, max_key) = (table.min_key.as_slice(), table.max_key.as_slice());
+    memtables.iter().any(|memtable| {
+        memtable.range(min_key..=max_key).next().is_some()
+            || memtable.range_tombstones().iter().any(|tombstone| {
+                tombstone.start.as_slice() <= max_key && tombstone.end.as_slice() > min_key
+            })
+    })
+}
+
+/// the deepest level a table over `min_key..=max_key` can be ingested into:
+/// nothing in it or any level above may overlap the table, since that data
+/// is older yet would be read first
+fn ingest_level(manifest: &Manifest, min_key: &[u8], max_key: &[u8]) -> usize {
+    let overlaps = |level| !manifest.find_overlapping(level, min_key, max_key).is_empty();
+    if overlaps(0) {
+        return 0;
+    }
+    (1..manifest.levels.len()).take_while(|&level| !overlaps(level)).last().unwrap_or(0)
+}
+
+/// copy `table` into table `id` of column family `family` at `level`, its
+/// entries and range tombstones all at sequence number `seq`; a table that
+/// isn't finished is removed
+fn write_ingested(
+    dir: &Path,
+    family: u32,
+    id: u64,
+    level: usize,
+    table: &ExternalTable,
+    seq: u64,
+    config: &LSMConfig,
+) -> Result<SSTableMetadata> {
+    let file_name = family::table_file_name(family, id);
+    let result = table_writer(dir, &file_name, id, level, DEFAULT_RESTART_INTERVAL, config)
+        .and_then(|mut writer| {
+            for entry in table.reader.iter() {
+                let (key, _, value) = entry?;
+                writer.add_value(&key, seq, &value)?;
+            }
+            for tombstone in table.reader.range_tombstones().iter() {
+                writer.add_range_tombstone(RangeTombstone { seq, ..tombstone.clone() });
+            }
+            Ok(writer.finish()?)
+        });
+    if result.is_err() {
+        fs::remove_file(dir.join(file_name)).ok();
+    }
+    result
+}
+
+/// one key of a find_many(), folding versions from the sources newest first
+struct KeyRead {
+    fold: MergeFold,
+
+    /// the newest range tombstone over the key so far
+    deleted_below: u64,
+
+    /// the fold needs no older versions
+    settled: bool,
+}
+
+impl KeyRead {
+    fn new(expiry: Option<Expiry>) -> Self {
+        Self {
+            fold: MergeFold::with_expiry(expiry),
+            deleted_below: 0,
+            settled: false,
+        }
+    }
+
+    /// a source's range tombstones cover the key up to `seq`
+    fn cover(&mut self, seq: u64) {
+        self.deleted_below = self.deleted_below.max(seq);
+    }
+
+    /// a source's versions of the key, newest first
+    fn push(&mut self, versions: impl IntoIterator<Item = (u64, StoredValue)>) {
+        for (version, value) in versions {
+            let value = if version < self.deleted_below {
+                StoredValue::Delete
+            } else {
+                value
+            };
+            if self.fold.push(value) {
+                self.settled = true;
+                return;
+            }
+        }
+    }
+}
+
+/// tables of `manifest` a scan over the bounds reads, newest first: L0 in
+/// reverse flush order, then deeper levels
+fn scan_tables<'a>(
+    manifest: &'a Manifest,
+    (lower, upper): (&'a Bound<Vec<u8>>, &'a Bound<Vec<u8>>),
+) -> impl Iterator<Item = &'a SSTableMetadata> {
+    let l0 = manifest.get_level(0).iter().rev();
+    let deeper = (1..manifest.levels.len()).flat_map(|l| manifest.get_level(l));
+    l0.chain(deeper).filter(move |sst| {
+        let (min, max) = (&sst.min_key, &sst.max_key);
+        above_lower(max, lower)
+            && below_upper(min, upper)
+            && (!sst.tombstone_only || manifest.overlaps_older(sst, min, max))
+    })
+}
+
+/// the versions of `memtable` within the bounds a read at `seq` sees, copied
+/// out; those `tombstones` delete read as tombstones
+fn memtable_source(
+    memtable: &Memtable,
+    (lower, upper): (&Bound<Vec<u8>>, &Bound<Vec<u8>>),
+    reverse: bool,
+    seq: u64,
+    tombstones: &RangeTombstones,
+) -> EntrySource {
+    let bounds = (lower.as_ref().map(Vec::as_slice), upper.as_ref().map(Vec::as_slice));
+    let mut keys: Vec<_> = memtable.range_versions_at::<[u8]>(bounds, seq).collect();
+    if reverse {
+        keys.reverse();
+    }
+    let entries: Vec<Result<VersionEntry>> = keys
+        .into_iter()
+        .flat_map(|(key, versions)| versions.map(move |entry| (key, entry)))
+        .map(|(key, entry)| {
+            let value = if tombstones.covers(key, entry.seq_num, seq) {
+                StoredValue::Delete
+            } else {
+                entry.to_stored()
+            };
+            Ok((key.to_vec(), value))
+        })
+        .collect();
+    Box::new(entries.into_iter())
+}
+
+fn owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn read_seq(options: &ReadOptions) -> u64 {
+    options.snapshot.as_ref().map_or(u64::MAX, Snapshot::seq)
+}
+
+fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::external::SstFileWriter;
+    use crate::lsm::job::JobStatus;
+    use crate::lsm::merge::{AppendOperator, U64AddOperator};
+    use crate::lsm::sstable::table::table_file_name;
+    use crate::lsm::version_edit;
+    use crate::lsm::wal::WalRecoveryMode;
+    use std::env;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(name);
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn small_config() -> LSMConfig {
+        LSMConfig {
+            memtable_size: 256,
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_amplification_report() {
+        let dir = test_dir("test_db_amplification");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let report = db.amplification_report();
+        assert_eq!((report.user_bytes, report.gets), (0, 0));
+        assert_eq!(report.write_amplification, 0.0);
+
+        // overwritten keys leave overlapping tables for compaction to merge
+        for i in (0..100).chain(0..100) {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        let report = db.amplification_report();
+        assert_eq!(report.user_bytes, 200 * 11);
+        // every byte went to the WAL and then to a table
+        assert!(report.write_amplification > 2.0, "{:?}", report);
+
+        db.compact().unwrap();
+        let compacted = db.amplification_report();
+        assert!(compacted.disk_bytes > report.disk_bytes);
+
+        db.put(b"key000", b"fresh").unwrap();
+        db.get(b"key000").unwrap(); // memtable
+        db.get(b"key050").unwrap(); // one table block
+        db.get(b"missing").unwrap();
+        let report = db.amplification_report();
+        assert_eq!(report.gets, 3);
+        assert!(report.read_amplification > 0.0 && report.read_amplification <= 1.0);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_compaction_keeps_hot_ranges_cached() {
+        let dir = test_dir("test_db_cache_warming");
+        let db = DB::open(&dir, small_config()).unwrap();
+        for i in (0..100).chain(0..100) {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        for _ in 0..3 {
+            db.get(b"key042").unwrap();
+        }
+
+        db.compact().unwrap();
+        let stats = db.cache_stats();
+        assert!(stats.warmed > 0, "{:?}", stats);
+        db.get(b"key042").unwrap();
+        assert_eq!(db.cache_stats().misses, stats.misses);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_purge_namespace() {
+        let dir = test_dir("test_db_purge_namespace");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let value = [b'v'; 100];
+        for i in 0..200 {
+            db.put(format!("tenant1/{:03}", i).as_bytes(), &value).unwrap();
+            db.put(format!("tenant2/{:03}", i).as_bytes(), &value).unwrap();
+        }
+        db.flush().unwrap();
+
+        let report = db.purge_namespace(b"tenant1/").unwrap();
+        assert_eq!(report.deleted_keys, 200);
+        assert!(report.reclaimed_bytes > 200 * 100, "{:?}", report);
+        assert_eq!(report.bytes_after, db.lock().disk_bytes());
+        assert_eq!(db.range(b"tenant1/".to_vec()..b"tenant10".to_vec()).unwrap().count(), 0);
+        assert_eq!(db.get(b"tenant2/042").unwrap(), Some(value.to_vec()));
+
+        // nothing left to purge
+        let report = db.purge_namespace(b"tenant1/").unwrap();
+        assert_eq!((report.deleted_keys, report.reclaimed_bytes), (0, 0));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_delete_prefix() {
+        let dir = test_dir("test_db_delete_prefix");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        for i in 0..50 {
+            db.put(format!("a/{:02}", i).as_bytes(), b"value").unwrap();
+            db.put(format!("b/{:02}", i).as_bytes(), b"value").unwrap();
+        }
+        db.put(b"a", b"outside").unwrap();
+        db.put(b"a0", b"outside").unwrap();
+
+        assert_eq!(db.delete_prefix(b"a/", true).unwrap(), 50);
+        assert_eq!(db.get(b"a/07").unwrap(), None);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"outside".to_vec()));
+        assert_eq!(db.get(b"a0").unwrap(), Some(b"outside".to_vec()));
+        assert_eq!(db.range(b"a/".to_vec()..b"a0".to_vec()).unwrap().count(), 0);
+        assert_eq!(db.iter().unwrap().count(), 52);
+
+        // compacted down to the last level, where values and tombstones go
+        let inner = db.lock();
+        let last = inner.manifest.levels.len() - 1;
+        assert!((0..last).all(|level| inner.manifest.get_level(level).is_empty()));
+        let stored: u64 = inner.manifest.get_level(last).iter().map(|sst| sst.num_entries).sum();
+        assert_eq!(stored, 52);
+        drop(inner);
+
+        assert_eq!(db.delete_prefix(b"a/", false).unwrap(), 0);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = test_dir("test_db_rename");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        db.put(b"old", b"value").unwrap();
+        db.put(b"taken", b"other").unwrap();
+        db.flush().unwrap();
+
+        assert!(db.rename(b"old", b"new", false).unwrap());
+        assert_eq!(db.get(b"old").unwrap(), None);
+        assert_eq!(db.get(b"new").unwrap(), Some(b"value".to_vec()));
+        assert!(!db.rename(b"old", b"new", true).unwrap());
+
+        assert!(matches!(db.rename(b"new", b"taken", false), Err(DbError::KeyExists(_))));
+        assert_eq!(db.get(b"new").unwrap(), Some(b"value".to_vec()));
+        assert!(db.rename(b"new", b"taken", true).unwrap());
+        assert!(db.rename(b"taken", b"taken", false).unwrap());
+        db.close().unwrap();
+
+        // the move was logged as one batch
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert_eq!(db.get(b"new").unwrap(), None);
+        assert_eq!(db.get(b"taken").unwrap(), Some(b"value".to_vec()));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_put_if() {
+        let dir = test_dir("test_db_put_if");
+        let db = Arc::new(DB::open(&dir, small_config()).unwrap());
+
+        assert!(!db.put_if(b"lease", Some(b"me"), b"me").unwrap());
+        assert!(db.put_if(b"lease", None, b"me").unwrap());
+        assert!(!db.put_if(b"lease", None, b"you").unwrap());
+        db.flush().unwrap();
+        assert!(db.put_if(b"lease", Some(b"me"), b"you").unwrap());
+        assert_eq!(db.get(b"lease").unwrap(), Some(b"you".to_vec()));
+        db.delete(b"lease").unwrap();
+        assert!(db.put_if(b"lease", None, b"me").unwrap());
+
+        // a counter bumped by compare-and-swap loops loses no increments
+        let bump = |db: &DB| loop {
+            let current = db.get(b"counter").unwrap();
+            let n = current.as_deref().map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+            if db.put_if(b"counter", current.as_deref(), &(n + 1).to_le_bytes()).unwrap() {
+                return;
+            }
+        };
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || (0..50).for_each(|_| bump(&db)))
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(db.get(b"counter").unwrap(), Some(200u64.to_le_bytes().to_vec()));
+
+        drop(db);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_single_delete() {
+        let dir = test_dir("test_db_single_delete");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let key = |i: u32| format!("key{:03}", i).into_bytes();
+
+        // a put still in the memtable leaves nothing behind
+        db.put(b"fresh", b"value").unwrap();
+        db.single_delete(b"fresh").unwrap();
+        assert_eq!(db.get(b"fresh").unwrap(), None);
+        assert_eq!(db.lock().memtable.len(), 0);
+
+        for i in 0..40 {
+            db.put(&key(i), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..40 {
+            batch.single_delete(&key(i));
+        }
+        db.write(batch).unwrap();
+        assert_eq!(db.get(&key(7)).unwrap(), None);
+        db.close().unwrap();
+
+        // replayed from the WAL, then cancelled against the flushed puts
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert_eq!(db.get(&key(7)).unwrap(), None);
+        db.flush().unwrap();
+        assert_eq!(db.iter().unwrap().count(), 0);
+        db.compact_range::<&[u8]>(..).unwrap();
+        let entries = db
+            .status()
+            .levels
+            .iter()
+            .map(|level| level.entries)
+            .sum::<u64>();
+        assert_eq!(entries, 0);
+
+        // a single delete of a put made after a delete leaves the delete, so
+        // the flushed put from before stays deleted
+        db.put(b"again", b"v0").unwrap();
+        db.flush().unwrap();
+        db.delete(b"again").unwrap();
+        db.put(b"again", b"v1").unwrap();
+        db.single_delete(b"again").unwrap();
+        assert_eq!(db.get(b"again").unwrap(), None);
+        db.flush().unwrap();
+        db.compact_range::<&[u8]>(..).unwrap();
+        assert_eq!(db.get(b"again").unwrap(), None);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_compact_range_in_family() {
+        let dir = test_dir("test_db_compact_range_family");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let users = db.create_cf("users").unwrap();
+        let key = |i: u32| format!("user{:03}", i).into_bytes();
+        for i in 0..100 {
+            users.put(&key(i), b"value").unwrap();
+        }
+        db.put(b"default", b"value").unwrap();
+        db.flush().unwrap();
+        for i in 0..50 {
+            users.delete(&key(i)).unwrap();
+        }
+
+        // the family's tables end up in its last level, tombstones dropped;
+        // the default family's table stays where it is
+        users.compact_range::<&[u8]>(..).unwrap();
+        {
+            let inner = db.lock();
+            let id = inner.families[0].id;
+            let manifest = inner.manifest.family(id).unwrap();
+            let last = manifest.levels.len() - 1;
+            assert!((0..last).all(|level| manifest.get_level(level).is_empty()));
+            let entries: u64 = manifest.get_level(last).iter().map(|sst| sst.num_entries).sum();
+            assert_eq!(entries, 50);
+            assert_eq!(inner.manifest.get_level(0).len(), 1);
+        }
+        assert_eq!(users.iter().unwrap().count(), 50);
+        assert_eq!(db.get(b"default").unwrap(), Some(b"value".to_vec()));
+
+        users.compact_range_async(key(0)..key(10)).unwrap().wait().unwrap();
+        drop(users);
+        db.drop_cf("users").unwrap();
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_concurrent_manual_jobs_are_shared() {
+        let dir = test_dir("test_db_manual_jobs");
+        let config = LSMConfig {
+            memtable_size: 1 << 20,
+            ..small_config()
+        };
+        let db = Arc::new(DB::open(&dir, config).unwrap());
+        db.put(b"a", b"1").unwrap();
+
+        // with a flush job running, requests made meanwhile share one queued job
+        let running = db.shared.flush_jobs.lock().unwrap();
+        let first = db.flush_async().unwrap();
+        let second = db.flush_async().unwrap();
+        assert!(first.same_job(&second));
+        assert_eq!(first.status(), JobStatus::Queued);
+        let blocking = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || db.flush())
+        };
+        drop(running);
+
+        first.wait().unwrap();
+        blocking.join().unwrap().unwrap();
+        assert_eq!(second.status(), JobStatus::Done);
+        assert_eq!(db.lock().manifest.get_level(0).len(), 1);
+
+        // compactions are shared per range
+        let running = db.shared.compaction_jobs.lock().unwrap();
+        let first = db.compact_range_async(b"a".to_vec()..b"b".to_vec()).unwrap();
+        let same = db.compact_range_async(b"a".to_vec()..b"b".to_vec()).unwrap();
+        let other = db.compact_range_async(b"c".to_vec()..).unwrap();
+        assert!(first.same_job(&same) && !first.same_job(&other));
+        drop(running);
+
+        first.wait().unwrap();
+        other.wait().unwrap();
+        let inner = db.lock();
+        let last = inner.manifest.levels.len() - 1;
+        assert_eq!(inner.manifest.get_level(last).len(), 1);
+        drop(inner);
+
+        Arc::into_inner(db).unwrap().close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_cancelled_jobs() {
+        let dir = test_dir("test_db_cancelled_jobs");
+        let config = LSMConfig {
+            memtable_size: 1 << 20,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        // a job cancelled while queued fails without touching the memtable
+        let running = db.shared.flush_jobs.lock().unwrap();
+        let job = db.flush_async().unwrap();
+        job.cancel();
+        drop(running);
+        assert!(matches!(job.wait(), Err(DbError::Cancelled)));
+        assert_eq!(job.status(), JobStatus::Cancelled);
+        assert!(db.lock().immutables.is_empty());
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        // later requests get a fresh job
+        db.flush().unwrap();
+        assert_eq!(db.lock().manifest.get_level(0).len(), 1);
+
+        // shutdown cancels every job, queued or running
+        let running = db.shared.compaction_jobs.lock().unwrap();
+        let job = db.compact_range_async::<&[u8]>(..).unwrap();
+        db.shared.cancel.cancel();
+        drop(running);
+        assert!(matches!(job.wait(), Err(DbError::Cancelled)));
+        assert_eq!(db.lock().manifest.get_level(0).len(), 1);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_suggest_split_points() {
+        let dir = test_dir("test_db_split_points");
+        let config = LSMConfig {
+            memtable_size: 64 * 1024,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        assert!(db.suggest_split_points(3).unwrap().is_empty());
+
+        // two tables and a memtable, all with the same value size
+        let value = vec![b'v'; 100];
+        for i in 0..1200 {
+            db.put(format!("key{:05}", i).as_bytes(), &value).unwrap();
+            if i % 500 == 499 {
+                db.flush().unwrap();
+            }
+        }
+
+        let points = db.suggest_split_points(3).unwrap();
+        assert_eq!(points.len(), 3);
+        assert!(points.windows(2).all(|w| w[0] < w[1]));
+        for (i, point) in points.iter().enumerate() {
+            let index: usize = String::from_utf8_lossy(&point[3..]).parse().unwrap();
+            let expected = 300 * (i + 1);
+            assert!(index.abs_diff(expected) < 60, "{} is far from {}", index, expected);
+        }
+        assert!(db.suggest_split_points(0).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = test_dir("test_db_checkpoint");
+        let copy = test_dir("test_db_checkpoint_copy");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let users = db.create_cf("users").unwrap();
+        let key = |i: u32| format!("key{:03}", i).into_bytes();
+        for i in 0..100 {
+            db.put(&key(i), b"before").unwrap();
+        }
+        db.flush().unwrap();
+        db.delete(&key(5)).unwrap();
+        users.put(b"alice", b"admin").unwrap();
+
+        db.checkpoint(&copy).unwrap();
+        assert!(matches!(db.checkpoint(&copy), Err(DbError::Io(_))));
+
+        // the database moves on without the copy noticing
+        for i in 0..100 {
+            db.put(&key(i), b"after").unwrap();
+        }
+        db.flush().unwrap();
+        db.compact_range::<&[u8]>(..).unwrap();
+        drop(users);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        // read-only, the copy is read as it is and left untouched
+        let files = || {
+            let (mut files, mut dirs) = (Vec::new(), vec![copy.clone()]);
+            while let Some(dir) = dirs.pop() {
+                for path in fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()) {
+                    if path.is_dir() {
+                        dirs.push(path);
+                    } else {
+                        files.push((fs::read(&path).unwrap(), path));
+                    }
+                }
+            }
+            files.sort();
+            files
+        };
+        let before = files();
+        let db = DB::open_read_only(&copy, small_config()).unwrap();
+        assert_eq!(db.get(&key(7)).unwrap(), Some(b"before".to_vec()));
+        assert_eq!(db.get(&key(5)).unwrap(), None);
+        assert_eq!(db.cf("users").unwrap().get(b"alice").unwrap(), Some(b"admin".to_vec()));
+        assert!(matches!(db.put(b"key", b"value"), Err(DbError::ReadOnly)));
+        assert!(matches!(db.flush(), Err(DbError::ReadOnly)));
+        assert!(matches!(db.compact_range::<&[u8]>(..), Err(DbError::ReadOnly)));
+        assert!(matches!(db.purge_obsolete_files(), Err(DbError::ReadOnly)));
+        db.close().unwrap();
+        assert!(files() == before);
+
+        let db = DB::open(&copy, small_config()).unwrap();
+        assert_eq!(db.get(&key(7)).unwrap(), Some(b"before".to_vec()));
+        assert_eq!(db.get(&key(5)).unwrap(), None);
+        assert_eq!(db.iter().unwrap().count(), 99);
+        assert_eq!(db.cf("users").unwrap().get(b"alice").unwrap(), Some(b"admin".to_vec()));
+        db.close().unwrap();
+        fs::remove_dir_all(&copy).ok();
+    }
+
+    #[test]
+    fn test_approximate_size() {
+        let dir = test_dir("test_db_approximate_size");
+        let config = LSMConfig {
+            memtable_size: 64 * 1024,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        let key = |i: u32| format!("key{:05}", i).into_bytes();
+        assert_eq!(db.approximate_size(b"a", b"z").unwrap(), ApproximateSize::default());
+
+        // two tables; the memtable doesn't count
+        let value = vec![b'v'; 100];
+        for i in 0..1200 {
+            db.put(&key(i), &value).unwrap();
+            if i % 500 == 499 {
+                db.flush().unwrap();
+            }
+        }
+        let all = db.approximate_size(b"a", b"z").unwrap();
+        let on_disk: u64 = db.status().levels.iter().map(|level| level.bytes).sum();
+        assert_eq!(all, ApproximateSize { bytes: on_disk, entries: 1000 });
+
+        // a range cutting through a table counts the blocks it touches, about
+        // 35 entries each
+        let half = db.approximate_size(&key(0), &key(250)).unwrap();
+        assert!(half.entries.abs_diff(250) < 35, "{:?}", half);
+        assert!(half.bytes.abs_diff(on_disk / 4) < on_disk / 20, "{:?}", half);
+        let spanning = db.approximate_size(&key(250), &key(750)).unwrap();
+        assert!(spanning.entries.abs_diff(500) < 70, "{:?}", spanning);
+        assert_eq!(db.approximate_size(&key(1000), b"z").unwrap().entries, 0);
+        assert_eq!(db.approximate_size(b"z", b"a").unwrap(), ApproximateSize::default());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_ingest() {
+        let dir = test_dir("test_db_ingest");
+        let files = test_dir("test_db_ingest_files");
+        fs::create_dir_all(&files).unwrap();
+        let config = LSMConfig {
+            memtable_size: 64 * 1024,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config.clone()).unwrap();
+        let key = |i: u32| format!("key{:03}", i).into_bytes();
+        for i in 0..100 {
+            db.put(&key(i), b"old").unwrap();
+        }
+        db.flush().unwrap();
+        db.put(&key(50), b"memtable").unwrap();
+        let snapshot = db.snapshot();
+
+        let mut bulk = SstFileWriter::create(files.join("bulk.sst"), &config).unwrap();
+        for i in 50..60 {
+            bulk.put(&key(i), b"bulk").unwrap();
+        }
+        bulk.delete(&key(60)).unwrap();
+        let bulk = bulk.finish().unwrap().remove(0);
+        let mut fresh = SstFileWriter::create(files.join("fresh.sst"), &config).unwrap();
+        fresh.put(b"zebra", b"new").unwrap();
+        let fresh = fresh.finish().unwrap().remove(0);
+
+        let mut overlapping = SstFileWriter::create(files.join("overlap.sst"), &config).unwrap();
+        overlapping.put(&key(55), b"x").unwrap();
+        let overlapping = overlapping.finish().unwrap().remove(0);
+        let result = db.ingest(&[&bulk.path, &overlapping.path]);
+        assert!(matches!(result, Err(DbError::Ingest(_))));
+
+        // the memtable holding key050 is flushed first; the new file lands
+        // above the old table, the disjoint one at the bottom
+        db.ingest(&[&fresh.path, &bulk.path]).unwrap();
+        {
+            let inner = db.lock();
+            assert_eq!(inner.manifest.get_level(0).len(), 3);
+            assert_eq!(inner.manifest.get_level(config.max_levels - 1).len(), 1);
+        }
+        assert_eq!(db.get(&key(50)).unwrap(), Some(b"bulk".to_vec()));
+        assert_eq!(db.get(&key(60)).unwrap(), None);
+        assert_eq!(db.get(&key(61)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(b"zebra").unwrap(), Some(b"new".to_vec()));
+        let before = ReadOptions::default().with_snapshot(snapshot);
+        assert_eq!(db.get_opt(&key(50), &before).unwrap(), Some(b"memtable".to_vec()));
+        assert_eq!(db.get_opt(&key(60), &before).unwrap(), Some(b"old".to_vec()));
+        assert!(files.join("bulk.sst").exists());
+        drop(before);
+
+        // writes after the ingest win, across a reopen and compaction
+        db.put(&key(51), b"later").unwrap();
+        db.close().unwrap();
+        let db = DB::open(&dir, config).unwrap();
+        db.put(&key(52), b"reopened").unwrap();
+        db.compact_range::<&[u8]>(..).unwrap();
+        assert_eq!(db.get(&key(50)).unwrap(), Some(b"bulk".to_vec()));
+        assert_eq!(db.get(&key(51)).unwrap(), Some(b"later".to_vec()));
+        assert_eq!(db.get(&key(52)).unwrap(), Some(b"reopened".to_vec()));
+        assert_eq!(db.iter().unwrap().count(), 100);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+        fs::remove_dir_all(&files).ok();
+    }
+
+    #[test]
+    fn test_ingest_split_files() {
+        let dir = test_dir("test_db_ingest_split");
+        let files = test_dir("test_db_ingest_split_files");
+        fs::create_dir_all(&files).unwrap();
+        let db = DB::open(&dir, small_config()).unwrap();
+        let key = |i: u32| format!("key{:03}", i).into_bytes();
+        for i in 0..300 {
+            db.put(&key(i), b"old").unwrap();
+        }
+        db.flush().unwrap();
+
+        // the even keys replace everything in the range, spread over files
+        let mut bulk = SstFileWriter::create(files.join("bulk.sst"), &small_config())
+            .unwrap()
+            .with_target_file_size(1024);
+        bulk.delete_range(&key(0), &key(300));
+        for i in (0..300).step_by(2) {
+            bulk.put(&key(i), b"bulk").unwrap();
+        }
+        let infos = bulk.finish().unwrap();
+        assert!(infos.len() > 1);
+        let paths: Vec<_> = infos.iter().map(|info| &info.path).collect();
+        db.ingest(&paths).unwrap();
+
+        for i in 0..300 {
+            let expected = (i % 2 == 0).then(|| b"bulk".to_vec());
+            assert_eq!(db.get(&key(i)).unwrap(), expected, "key {}", i);
+        }
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+        fs::remove_dir_all(&files).ok();
+    }
+
+    #[test]
+    fn test_get_probes_only_covering_tables() {
+        let dir = test_dir("test_db_get_probes");
+        let config = LSMConfig {
+            memtable_size: 1 << 20,
+            target_file_size: 4096,
+            l0_compaction_trigger: 1,
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        };
+        let db = DB::open(&dir, config).unwrap();
+
+        // two overlapping flushes, so compaction merges and splits them
+        let value = vec![b'v'; 100];
+        for parity in 0..2 {
+            for i in (parity..200).step_by(2) {
+                db.put(format!("k{:03}", i).as_bytes(), &value).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        db.compact().unwrap();
+        assert!(db.lock().manifest.get_level(1).len() > 2);
+
+        // two narrow L0 files above the L1 run
+        db.put(b"k050", b"new").unwrap();
+        db.flush().unwrap();
+        db.put(b"z", b"z").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.lock().manifest.get_level(0).len(), 2);
+
+        let probes = |key: &[u8]| {
+            let before = db.read_stats();
+            let value = db.get(key).unwrap();
+            let after = db.read_stats();
+            (value, after.tables_probed - before.tables_probed)
+        };
+
+        // found in the second-newest L0 file; the newest doesn't cover it
+        assert_eq!(probes(b"k050"), (Some(b"new".to_vec()), 1));
+        // no L0 file covers it and exactly one L1 file does
+        assert_eq!(probes(b"k120"), (Some(value.clone()), 1));
+        // inside one L1 file's range, rejected by its bloom filter
+        assert_eq!(probes(b"k1205"), (None, 1));
+        // beyond every range: nothing probed
+        assert_eq!(probes(b"a"), (None, 0));
+        assert!(db.read_stats().bloom_negatives >= 1);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_prefix_filter_skips_files() {
+        let dir = test_dir("test_db_prefix_filter");
+        let config = LSMConfig {
+            prefix_filter_len: Some(4),
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        };
+        let db = DB::open(&dir, config).unwrap();
+
+        // one L0 file per tenant group, all spanning the same key range
+        for group in 0..4 {
+            for tenant in (group..40).step_by(4) {
+                db.put(format!("t{:02}/a", tenant).as_bytes(), b"1").unwrap();
+                db.put(format!("t{:02}/z", tenant).as_bytes(), b"2").unwrap();
+            }
+            db.flush().unwrap();
+        }
+        assert!(db.lock().manifest.get_level(0).iter().all(|sst| sst.prefix_filter.is_some()));
+
+        let before = db.read_stats();
+        assert_eq!(db.get(b"t05/z").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"t05/m").unwrap(), None);
+        let after = db.read_stats();
+        // every file covers both keys, but only t05's file is opened
+        assert!(after.prefix_filter_skips - before.prefix_filter_skips >= 4);
+        assert!(after.tables_probed - before.tables_probed <= 4);
+
+        // a prefix scan skips the same files; a shorter prefix skips none
+        let before = db.read_stats();
+        let keys: Vec<_> = db.prefix_iter(b"t05/").unwrap().map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, vec![b"t05/a".to_vec(), b"t05/z".to_vec()]);
+        assert_eq!(db.prefix_iter(b"t05/q").unwrap().count(), 0);
+        let after = db.read_stats();
+        assert!(after.prefix_filter_skips - before.prefix_filter_skips >= 4);
+        assert_eq!(db.prefix_iter(b"t0").unwrap().count(), 20);
+        assert_eq!(db.read_stats().prefix_filter_skips, after.prefix_filter_skips);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_disk_quota() {
+        let dir = test_dir("test_db_disk_quota");
+        let config = LSMConfig {
+            max_disk_bytes: Some(4096),
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+
+        let value = vec![b'v'; 100];
+        let mut written = 0;
+        let error = loop {
+            match db.put(format!("key{:04}", written).as_bytes(), &value) {
+                Ok(()) => written += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, DbError::QuotaExceeded { limit: 4096, .. }));
+        assert!(written > 0);
+
+        let status = db.status();
+        assert!(status.disk_bytes >= 4096);
+        assert_eq!(status.disk_quota, Some(4096));
+        let files: usize = status.levels.iter().map(|level| level.files).sum();
+        assert!(files > 0);
+
+        // deletes still go through, and batches with a put don't
+        db.delete(b"key0000").unwrap();
+        let mut batch = db.take_batch();
+        batch.delete(b"key0001");
+        batch.put(b"new", b"1");
+        assert!(matches!(db.write(batch), Err(DbError::QuotaExceeded { .. })));
+        assert_eq!(db.get(b"key0001").unwrap(), Some(value));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_put_get_delete() {
+        let dir = test_dir("test_db_put_get_delete");
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+
+        db.put(b"key1", b"value1").unwrap();
+        db.put(b"key2", b"value2").unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(db.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+        assert_eq!(db.get(b"key3").unwrap(), None);
+
+        db.delete(b"key1").unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), None);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reopen_replays_wal() {
+        let dir = test_dir("test_db_reopen_replays_wal");
+
+        {
+            let db = DB::open(&dir, LSMConfig::default()).unwrap();
+            db.put(b"key1", b"value1").unwrap();
+            db.put(b"key2", b"value2").unwrap();
+            db.delete(b"key2").unwrap();
+            db.close().unwrap();
+        }
+
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+        assert_eq!(db.get(b"key2").unwrap(), None);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_flush_on_full_memtable() {
+        let dir = test_dir("test_db_flush_on_full");
+
+        {
+            let db = DB::open(&dir, small_config()).unwrap();
+            for i in 0..50 {
+                let key = format!("key{:03}", i);
+                let value = format!("value{:03}", i);
+                db.put(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+
+            assert!(!db.lock().manifest.get_level(0).is_empty());
+            db.close().unwrap();
+        }
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        for i in 0..50 {
+            let key = format!("key{:03}", i);
+            let value = format!("value{:03}", i);
+            assert_eq!(db.get(key.as_bytes()).unwrap(), Some(value.into_bytes()));
+        }
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_flush_opt() {
+        let dir = test_dir("test_db_flush_opt");
+        let config = LSMConfig {
+            memtable_size: 64 * 1024,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        db.put(b"a", b"1").unwrap();
+        let segment = wal_segments(&dir).unwrap().pop().unwrap().1;
+
+        db.flush_opt(&FlushOptions::new()).unwrap();
+        assert_eq!(db.lock().manifest.get_level(0).len(), 1);
+        assert!(db.lock().memtable.is_empty());
+        // the flushed segment is gone, and writes go to a fresh one
+        let segments = wal_segments(&dir).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_ne!(segments[0].1, segment);
+
+        db.put(b"b", b"2").unwrap();
+        db.flush_opt(&FlushOptions::new().with_wait(false)).unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.lock().manifest.get_level(0).len(), 2);
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_purge_obsolete_files() {
+        let dir = test_dir("test_db_purge_obsolete");
+        let db = DB::open(&dir, small_config()).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.compact().unwrap();
+        assert_eq!(db.purge_obsolete_files().unwrap(), 0);
+
+        // strays from cut-short deletes and rotations, and a table being written
+        let stray = ["000900.sst", "000001.log", "MANIFEST-000000", "CURRENT.tmp"];
+        for name in stray {
+            fs::write(dir.join(name), b"stale").unwrap();
+        }
+        fs::write(dir.join("notes.txt"), b"kept").unwrap();
+        let pending = db.lock().allocate_table_id();
+        let pending_path = dir.join(table_file_name(pending));
+        fs::write(&pending_path, b"in progress").unwrap();
+
+        assert_eq!(db.purge_obsolete_files().unwrap(), stray.len());
+        assert!(stray.iter().all(|name| !dir.join(name).exists()));
+        assert!(pending_path.exists() && dir.join("notes.txt").exists());
+        assert_eq!(db.iter().unwrap().count(), 100);
+
+        // nothing is writing it once the database is reopened
+        db.close().unwrap();
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert!(!pending_path.exists());
+        assert_eq!(db.iter().unwrap().count(), 100);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_manifest_rebuilt_from_tables() {
+        let dir = test_dir("test_db_manifest_rebuild");
+        let db = DB::open(&dir, small_config()).unwrap();
+        for round in 0..3 {
+            for i in 0..60 {
+                let value = format!("value{}", round);
+                db.put(format!("key{:03}", i).as_bytes(), value.as_bytes()).unwrap();
+            }
+            if round == 1 {
+                db.compact().unwrap();
+            }
+        }
+        db.put(b"unflushed", b"value").unwrap();
+        db.close().unwrap();
+
+        // bit rot in the first record, with more records after it
+        let current = version_edit::current_manifest(&dir).unwrap().unwrap();
+        let mut bytes = fs::read(&current).unwrap();
+        bytes[12] ^= 0xff;
+        fs::write(&current, &bytes).unwrap();
+        assert!(matches!(
+            DB::open(&dir, small_config()),
+            Err(DbError::Manifest(ManifestError::Corrupted(_)))
+        ));
+
+        let config = || LSMConfig {
+            manifest_recovery: ManifestRecoveryMode::RebuildFromTables,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config()).unwrap();
+        assert!(db.status().background_errors[0].contains("Manifest rebuilt"));
+        for i in 0..60 {
+            let value = db.get(format!("key{:03}", i).as_bytes()).unwrap();
+            assert_eq!(value, Some(b"value2".to_vec()), "key{:03}", i);
+        }
+        assert_eq!(db.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+        db.compact().unwrap();
+        db.close().unwrap();
+
+        let mut corrupt = current.into_os_string();
+        corrupt.push(".corrupt");
+        assert!(Path::new(&corrupt).exists());
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert_eq!(db.iter().unwrap().count(), 61);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_repair_and_destroy() {
+        let dir = test_dir("test_db_repair");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let users = db.create_cf("users").unwrap();
+        for i in 0..60 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.compact().unwrap();
+        users.put(b"alice", b"admin").unwrap();
+        db.flush().unwrap();
+        db.put(b"unflushed", b"value").unwrap();
+        drop(users);
+        db.close().unwrap();
+
+        let current = version_edit::current_manifest(&dir).unwrap().unwrap();
+        fs::write(&current, b"garbage").unwrap();
+        let report = DB::repair(&dir, small_config()).unwrap();
+        assert_eq!(report.families.len(), 1);
+        assert_eq!(report.set_aside, vec![current]);
+        assert!(report.tables > 1);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        for i in 0..60 {
+            let value = db.get(format!("key{:03}", i).as_bytes()).unwrap();
+            assert_eq!(value, Some(b"value".to_vec()), "key{:03}", i);
+        }
+        assert_eq!(db.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+        let users = db.cf(&report.families[0]).unwrap();
+        assert_eq!(users.get(b"alice").unwrap(), Some(b"admin".to_vec()));
+        drop(users);
+        db.close().unwrap();
+
+        // only the database's own files go
+        fs::write(dir.join("notes.txt"), b"kept").unwrap();
+        DB::destroy(&dir).unwrap();
+        let left: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(left, vec!["notes.txt"]);
+        fs::remove_file(dir.join("notes.txt")).unwrap();
+        DB::destroy(&dir).unwrap();
+        assert!(!dir.exists());
+        DB::destroy(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let dir = test_dir("test_db_lock_file");
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert!(dir.join(LOCK_FILE).exists());
+        let opened = DB::open(&dir, small_config());
+        assert!(matches!(opened, Err(DbError::AlreadyLocked(path)) if path == dir));
+        assert!(matches!(DB::destroy(&dir), Err(DbError::AlreadyLocked(_))));
+        assert!(matches!(DB::repair(&dir, small_config()), Err(DbError::AlreadyLocked(_))));
+
+        // released by close and by drop alike
+        db.close().unwrap();
+        let db = DB::open(&dir, small_config()).unwrap();
+        drop(db);
+        DB::destroy(&dir).unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_options_file() {
+        let dir = test_dir("test_db_options_file");
+        let ttl_config = LSMConfig {
+            ttl: Some(Duration::from_secs(3600)),
+            ..small_config()
+        };
+        let db = DB::open(&dir, ttl_config.clone()).unwrap();
+        db.put(b"key", b"value").unwrap();
+        db.close().unwrap();
+        let options = OptionsFile::load(&dir).unwrap().unwrap();
+        assert_eq!(options.ttl_seconds, Some(3600));
+
+        // stamped values would read back with the stamp attached
+        let opened = DB::open(&dir, small_config());
+        assert!(matches!(opened, Err(DbError::IncompatibleOptions(msg)) if msg.contains("TTL")));
+
+        // tuning may change from one open to the next
+        let retuned = LSMConfig {
+            block_size: 512,
+            ..ttl_config.clone()
+        };
+        let db = DB::open(&dir, retuned).unwrap();
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+        db.close().unwrap();
+        assert_eq!(OptionsFile::load(&dir).unwrap().unwrap().block_size, 512);
+
+        let tampered = OptionsFile {
+            comparator: "reverse".to_string(),
+            ..options
+        };
+        tampered.save(&dir).unwrap();
+        match DB::open(&dir, ttl_config) {
+            Err(DbError::IncompatibleOptions(msg)) => assert!(msg.contains("reverse")),
+            other => panic!("expected IncompatibleOptions, got {:?}", other.map(|_| ())),
+        }
+        DB::destroy(&dir).unwrap();
+
+        // opening without the operator keeps its name on record, so another
+        // operator can't be slipped in after
+        let counter = LSMConfig {
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..small_config()
+        };
+        let db = DB::open(&dir, counter).unwrap();
+        db.merge(b"count", &1u64.to_le_bytes()).unwrap();
+        db.close().unwrap();
+        DB::open(&dir, small_config()).unwrap().close().unwrap();
+        assert_eq!(
+            OptionsFile::load(&dir).unwrap().unwrap().merge_operator.as_deref(),
+            Some("u64add")
+        );
+        let appender = LSMConfig {
+            merge_operator: Some(Arc::new(AppendOperator::new(b","))),
+            ..small_config()
+        };
+        let opened = DB::open(&dir, appender);
+        assert!(matches!(opened, Err(DbError::IncompatibleOptions(msg)) if msg.contains("u64add")));
+        DB::destroy(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_statistics() {
+        let dir = test_dir("test_db_statistics");
+        let statistics = Arc::new(Statistics::new());
+        let config = LSMConfig {
+            statistics: Some(Arc::clone(&statistics)),
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(db.get(b"key042").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"key042x").unwrap(), None);
+        db.compact_range::<&[u8]>(..).unwrap();
+
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.keys_written, 100);
+        assert_eq!(snapshot.bytes_written, 100 * 11);
+        assert!(snapshot.wal_bytes_written >= snapshot.bytes_written);
+        assert_eq!((snapshot.keys_read, snapshot.bytes_read), (2, 5));
+        assert!(snapshot.bloom_checked >= 2);
+        assert!(snapshot.cache_hits + snapshot.cache_misses >= 2);
+        assert!(snapshot.flush_bytes_written > 0);
+        assert!(snapshot.compaction_bytes_read > 0);
+        assert_eq!(snapshot.write_micros.count(), 100);
+        assert_eq!(snapshot.get_micros.count(), 2);
+        assert!(snapshot.flush_micros.count() >= 1);
+        assert!(snapshot.compaction_micros.count() >= 1);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_event_listener() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl Recorder {
+            fn push(&self, event: String) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        impl EventListener for Recorder {
+            fn on_flush_begin(&self, info: &FlushJobInfo) {
+                self.push(format!("flush begin {}", info.largest_seq));
+            }
+
+            fn on_flush_completed(&self, info: &FlushJobInfo) {
+                self.push(format!("flush completed {}", info.tables.len()));
+            }
+
+            fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+                let (inputs, outputs) = (info.inputs.len(), info.outputs.len());
+                self.push(format!("compaction {} -> {}", inputs, outputs));
+            }
+
+            fn on_table_file_created(&self, info: &TableFileInfo) {
+                assert!(info.path.exists());
+                self.push(format!("created {}", info.table.id));
+            }
+
+            fn on_table_file_deleted(&self, info: &TableFileDeletedInfo) {
+                assert!(!info.path.exists());
+                self.push(format!("deleted {}", info.id));
+            }
+
+            fn on_wal_sync(&self, info: &WalSyncInfo) {
+                self.push(format!("wal sync {}", info.synced_seq));
+            }
+        }
+
+        let dir = test_dir("test_db_event_listener");
+        let recorder = Arc::new(Recorder::default());
+        let config = LSMConfig {
+            listeners: vec![recorder.clone()],
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        let events = || std::mem::take(&mut *recorder.0.lock().unwrap());
+
+        db.put_opt(b"a", b"1", &WriteOptions::new().with_sync(true)).unwrap();
+        assert_eq!(events(), ["wal sync 1"]);
+
+        db.flush().unwrap();
+        let first = db.lock().manifest.levels[0].sstables[0].id;
+        assert_eq!(
+            events(),
+            ["flush begin 1".to_string(), format!("created {}", first), "flush completed 1".into()]
+        );
+
+        db.put(b"b", b"2").unwrap();
+        db.flush().unwrap();
+        events();
+        db.compact_range::<&[u8]>(..).unwrap();
+        let events = events();
+        assert!(events.contains(&"compaction 2 -> 1".to_string()), "{:?}", events);
+        assert!(events.contains(&format!("deleted {}", first)), "{:?}", events);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_set_options() {
+        let dir = test_dir("test_db_set_options");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let users = db.create_cf("users").unwrap();
+
+        // one bad setting rejects the whole call
+        let result = db.set_options(&[("memtable_size", "4096"), ("max_levels", "3")]);
+        assert!(matches!(result, Err(DbError::InvalidOption(_))));
+        let result = db.set_options(&[("l0_compaction_trigger", "0")]);
+        assert!(matches!(result, Err(DbError::InvalidOption(_))));
+        assert_eq!(db.options().memtable_size, 256);
+
+        db.set_options(&[("memtable_size", "4096"), ("bloom_bits_per_key", "12")]).unwrap();
+        assert_eq!(db.options().memtable_size, 4096);
+        assert_eq!(OptionsFile::load(&dir).unwrap().unwrap().bloom_bits_per_key, 12);
+
+        // the next memtable takes the new size, in families too
+        db.put(b"key", &[b'x'; 300]).unwrap();
+        users.put(b"key", &[b'x'; 300]).unwrap();
+        let l0_files = || db.lock().manifest.levels[0].sstables.len();
+        let before = l0_files();
+        for i in 0..8u8 {
+            db.put(&[i], &[b'x'; 200]).unwrap();
+            users.put(&[i], &[b'x'; 200]).unwrap();
+        }
+        assert_eq!(l0_files(), before);
+
+        db.set_options(&[("max_disk_bytes", "1")]).unwrap();
+        assert!(matches!(db.put(b"more", b"value"), Err(DbError::QuotaExceeded { .. })));
+        db.set_options(&[("max_disk_bytes", "none")]).unwrap();
+        db.put(b"more", b"value").unwrap();
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_startup_consistency_options() {
+        let dir = test_dir("test_db_startup_options");
+        let db = DB::open(&dir, small_config()).unwrap();
+        for i in 0..50 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        let tables: Vec<PathBuf> = db.lock().manifest.levels[0]
+            .sstables
+            .iter()
+            .map(|sst| dir.join(&sst.path))
+            .collect();
+        db.close().unwrap();
+        let config = |f: fn(&mut LSMConfig)| {
+            let mut config = small_config();
+            f(&mut config);
+            config
+        };
+
+        // a truncated table only shows up when read in full
+        let bytes = fs::read(&tables[0]).unwrap();
+        fs::write(&tables[0], &bytes[..bytes.len() - 10]).unwrap();
+        DB::open(&dir, small_config()).unwrap().close().unwrap();
+        let paranoid = DB::open(&dir, config(|c| c.paranoid_open = true));
+        assert!(matches!(paranoid, Err(DbError::Corrupted(_))));
+
+        fs::remove_file(&tables[0]).unwrap();
+        assert!(matches!(DB::open(&dir, small_config()), Err(DbError::Corrupted(_))));
+        let db = DB::open(&dir, config(|c| c.ignore_missing_files = true)).unwrap();
+        assert!(db.status().background_errors[0].contains("Dropped 1 missing tables"));
+        let remaining = db.iter().unwrap().count();
+        assert!(remaining < 50);
+        db.put(b"unflushed", b"value").unwrap();
+        drop(db);
+
+        // the dropped table stays dropped; skip_wal loses the unflushed put
+        let db = DB::open(&dir, config(|c| c.skip_wal = true)).unwrap();
+        assert_eq!(db.get(b"unflushed").unwrap(), None);
+        assert_eq!(db.iter().unwrap().count(), remaining);
+        assert!(db.status().background_errors[0].contains("Skipped WAL replay"));
+        db.put(b"after", b"value").unwrap();
+        db.close().unwrap();
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert_eq!(db.get(b"after").unwrap(), Some(b"value".to_vec()));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_manifest_rotation() {
+        let dir = test_dir("test_db_manifest_rotation");
+        let config = || LSMConfig {
+            max_manifest_file_size: 256,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config()).unwrap();
+        for i in 0..200 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.compact().unwrap();
+        let number = db.lock().manifest_log.as_ref().unwrap().number().unwrap();
+        assert!(number > 1);
+        db.close().unwrap();
+
+        let db = DB::open(&dir, config()).unwrap();
+        assert_eq!(db.lock().manifest_log.as_ref().unwrap().number(), Some(number));
+        assert_eq!(db.iter().unwrap().count(), 200);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_legacy_manifest_converted_to_log() {
+        let dir = test_dir("test_db_legacy_manifest");
+        let write = |db: &DB, range: std::ops::Range<usize>| {
+            for i in range {
+                db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+            }
+        };
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        write(&db, 0..50);
+        let files = db.lock().manifest.get_level(0).len();
+        db.close().unwrap();
+
+        // put the manifest back the way older versions kept it
+        let current = version_edit::current_manifest(&dir).unwrap().unwrap();
+        let manifest = Manifest::load(&current).unwrap();
+        manifest.save(dir.join("MANIFEST.json")).unwrap();
+        fs::remove_file(&current).unwrap();
+        fs::remove_file(dir.join(version_edit::CURRENT_FILE)).unwrap();
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert!(!dir.join("MANIFEST.json").exists());
+        assert_eq!(db.lock().manifest.get_level(0).len(), files);
+        write(&db, 50..100);
+        db.close().unwrap();
+
+        // later flushes were appended to the log
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert!(db.lock().manifest.get_level(0).len() > files);
+        for i in 0..100 {
+            assert!(db.get(format!("key{:03}", i).as_bytes()).unwrap().is_some());
+        }
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_newer_versions_shadow_flushed_ones() {
+        let dir = test_dir("test_db_shadowing");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        for round in 0..3 {
+            for i in 0..20 {
+                let key = format!("key{:03}", i);
+                let value = format!("value{}_{:03}", round, i);
+                db.put(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+        }
+        db.delete(b"key005").unwrap();
+
+        // pad the memtable so the tombstone itself gets flushed
+        for i in 0..20 {
+            db.put(format!("pad{:03}", i).as_bytes(), b"x").unwrap();
+        }
+
+        assert!(db.lock().manifest.get_level(0).len() > 1);
+        assert_eq!(db.get(b"key000").unwrap(), Some(b"value2_000".to_vec()));
+        assert_eq!(db.get(b"key019").unwrap(), Some(b"value2_019".to_vec()));
+        assert_eq!(db.get(b"key005").unwrap(), None);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_range_merges_memtable_and_tables() {
+        let dir = test_dir("test_db_range");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        for i in 0..40 {
+            let key = format!("key{:03}", i);
+            db.put(key.as_bytes(), b"old").unwrap();
+        }
+        for i in (0..40).step_by(2) {
+            let key = format!("key{:03}", i);
+            db.put(key.as_bytes(), b"new").unwrap();
+        }
+        for i in (0..40).step_by(5) {
+            db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        assert!(!db.lock().manifest.get_level(0).is_empty());
+
+        let entries: Vec<_> = db
+            .range(b"key010".as_slice()..b"key020".as_slice())
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+
+        let expected: Vec<_> = (10..20)
+            .filter(|i| i % 5 != 0)
+            .map(|i| {
+                let value = if i % 2 == 0 { b"new".to_vec() } else { b"old".to_vec() };
+                (format!("key{:03}", i).into_bytes(), value)
+            })
+            .collect();
+        assert_eq!(entries, expected);
+
+        let all: Vec<_> = db.iter().unwrap().map(|r| r.unwrap().0).collect();
+        assert_eq!(all.len(), 40 - 8);
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+
+        let inclusive: Vec<_> = db
+            .range(b"key038".as_slice()..=b"key039".as_slice())
+            .unwrap()
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(inclusive, vec![b"key038".to_vec(), b"key039".to_vec()]);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_range_rev() {
+        let dir = test_dir("test_db_range_rev");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let key = |i: u32| format!("key{:03}", i).into_bytes();
+
+        // versions spread over L1, L0 and the memtable
+        for i in 0..200 {
+            db.put(&key(i), b"old").unwrap();
+        }
+        db.compact_range::<&[u8]>(..).unwrap();
+        for i in (0..200).step_by(3) {
+            db.put(&key(i), b"new").unwrap();
+        }
+        db.flush().unwrap();
+        let snapshot = ReadOptions::new().with_snapshot(db.snapshot());
+        for i in (0..200).step_by(7) {
+            db.delete(&key(i)).unwrap();
+        }
+        db.put(&key(500), b"memtable").unwrap();
+
+        let mut forward: Vec<_> = db.iter().unwrap().map(|r| r.unwrap()).collect();
+        forward.reverse();
+        let backward: Vec<_> = db.iter_rev().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(backward, forward);
+
+        // the five live keys before key100
+        let before: Vec<_> = db
+            .range_rev(..key(100))
+            .unwrap()
+            .take(5)
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(before, vec![key(99), key(97), key(96), key(95), key(94)]);
+
+        let bounded: Vec<_> = db
+            .range_rev(key(10)..=key(14))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            bounded,
+            vec![
+                (key(13), b"old".to_vec()),
+                (key(12), b"new".to_vec()),
+                (key(11), b"old".to_vec()),
+                (key(10), b"old".to_vec()),
+            ]
+        );
+        assert_eq!(db.range_rev_opt(key(10)..=key(14), &snapshot).unwrap().count(), 5);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let dir = test_dir("test_db_multi_get");
+        let config = LSMConfig {
+            memtable_size: 4096,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        let key = |i: u32| format!("key{:04}", i).into_bytes();
+
+        // versions in L1, L0 and the memtable, with deletes of all kinds
+        for i in 0..1000 {
+            db.put(&key(i), b"old").unwrap();
+        }
+        db.compact_range::<&[u8]>(..).unwrap();
+        for i in (0..1000).step_by(3) {
+            db.put(&key(i), b"new").unwrap();
+        }
+        db.flush().unwrap();
+        let snapshot = ReadOptions::new().with_snapshot(db.snapshot());
+        for i in (0..1000).step_by(7) {
+            db.delete(&key(i)).unwrap();
+        }
+        db.delete_range(&key(500), &key(510)).unwrap();
+        db.put(&key(505), b"memtable").unwrap();
+
+        let mut keys: Vec<_> = (0..1200).step_by(2).rev().map(key).collect();
+        keys.push(key(4));
+        let values = db.multi_get(&keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (k, value) in keys.iter().zip(&values) {
+            assert_eq!(value, &db.get(k).unwrap());
+        }
+        assert_eq!(values[keys.len() - 1], Some(b"old".to_vec()));
+
+        let values = db.multi_get_opt(&keys, &snapshot).unwrap();
+        for (k, value) in keys.iter().zip(&values) {
+            assert_eq!(value, &db.get_opt(k, &snapshot).unwrap());
+        }
+
+        // each table is opened once for all the keys in it
+        let lookups = |stats: CacheStats| stats.hits + stats.misses;
+        let tables = db.lock().manifest.all_tables().count() as u64;
+        let before = lookups(db.shared.table_cache.stats());
+        db.multi_get(&keys).unwrap();
+        assert!(lookups(db.shared.table_cache.stats()) - before <= tables);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_read_deadline() {
+        let dir = test_dir("test_db_read_deadline");
+        let db = DB::open(&dir, small_config()).unwrap();
+        db.put(b"flushed", b"table").unwrap();
+        db.flush().unwrap();
+        db.put(b"fresh", b"memtable").unwrap();
+
+        // memtable hits never wait on I/O; anything in a table gives up
+        let expired = ReadOptions::new().with_deadline(Instant::now());
+        assert_eq!(db.get_opt(b"fresh", &expired).unwrap(), Some(b"memtable".to_vec()));
+        assert!(matches!(db.get_opt(b"flushed", &expired), Err(DbError::DeadlineExceeded)));
+        let keys: [&[u8]; 2] = [b"flushed", b"fresh"];
+        assert!(matches!(db.multi_get_opt(&keys, &expired), Err(DbError::DeadlineExceeded)));
+
+        let values = db.multi_get_partial(&keys, &expired).unwrap();
+        assert!(matches!(values[0], Err(DbError::DeadlineExceeded)));
+        assert_eq!(values[1].as_ref().unwrap(), &Some(b"memtable".to_vec()));
+
+        let later = ReadOptions::new().with_deadline(Instant::now() + Duration::from_secs(60));
+        assert_eq!(db.get_opt(b"flushed", &later).unwrap(), Some(b"table".to_vec()));
+        let keys: [&[u8]; 2] = [b"flushed", b"missing"];
+        let values = db.multi_get_partial(&keys, &later).unwrap();
+        assert_eq!(values[0].as_ref().unwrap(), &Some(b"table".to_vec()));
+        assert_eq!(values[1].as_ref().unwrap(), &None);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let dir = test_dir("test_db_contains_key");
+        let db = DB::open(&dir, small_config()).unwrap();
+        db.put(b"flushed", b"value").unwrap();
+        db.put(b"deleted", b"value").unwrap();
+        db.flush().unwrap();
+        db.put(b"fresh", b"value").unwrap();
+        db.delete(b"deleted").unwrap();
+
+        // the memtable decides without touching the table
+        let before = db.read_stats();
+        assert!(db.may_contain(b"fresh").unwrap());
+        assert!(!db.may_contain(b"deleted").unwrap());
+        assert!(db.contains_key(b"fresh").unwrap());
+        assert!(!db.contains_key(b"deleted").unwrap());
+        assert_eq!(db.read_stats().tables_probed, before.tables_probed);
+
+        // a bloom filter miss is a definite no; a hit needs the block
+        assert!(!db.may_contain(b"elsewhere").unwrap());
+        assert!(!db.contains_key(b"elsewhere").unwrap());
+        assert!(db.read_stats().bloom_negatives >= before.bloom_negatives + 2);
+        assert!(db.may_contain(b"flushed").unwrap());
+        assert!(db.contains_key(b"flushed").unwrap());
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_iterate_bounds() {
+        let dir = test_dir("test_db_iterate_bounds");
+        let db = DB::open(&dir, small_config()).unwrap();
+        let key = |i: u32| format!("key{:03}", i).into_bytes();
+
+        for i in 0..200 {
+            db.put(&key(i), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        let files = db.lock().manifest.get_level(0).len();
+        assert!(files > 4);
+
+        let options = ReadOptions::new().with_lower_bound(&key(50)).with_upper_bound(&key(60));
+        let keys: Vec<_> = db
+            .range_opt::<&[u8]>(.., &options)
+            .unwrap()
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(keys, (50..60).map(key).collect::<Vec<_>>());
+        let keys: Vec<_> = db
+            .range_rev_opt(key(55)..=key(70), &options)
+            .unwrap()
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(keys, (55..60).rev().map(key).collect::<Vec<_>>());
+        assert_eq!(db.range_opt(key(70)..key(80), &options).unwrap().count(), 0);
+
+        // only the tables holding key050..key060 are opened
+        let lookups = |stats: CacheStats| stats.hits + stats.misses;
+        let before = lookups(db.shared.table_cache.stats());
+        assert_eq!(db.range_opt::<&[u8]>(.., &options).unwrap().count(), 10);
+        let opened = lookups(db.shared.table_cache.stats()) - before;
+        assert!(opened > 0 && (opened as usize) < files);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_append_mode_detection() {
+        let dir = test_dir("test_db_append_detection");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        for i in 0..50 {
+            db.put(format!("ts{:06}", i).as_bytes(), b"sample").unwrap();
+        }
+        assert_eq!(db.append_stats().in_order, 50);
+        assert_eq!(db.append_stats().out_of_order, 0);
+
+        db.put(b"ts000010", b"late").unwrap();
+        db.delete(b"ts000001").unwrap();
+
+        let stats = db.append_stats();
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.first_out_of_order, Some(b"ts000010".to_vec()));
+        assert_eq!(db.get(b"ts000010").unwrap(), Some(b"late".to_vec()));
+        assert_eq!(db.get(b"ts000049").unwrap(), Some(b"sample".to_vec()));
+        assert_eq!(db.get(b"ts000001").unwrap(), None);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_strict_append_mode_rejects_out_of_order() {
+        let dir = test_dir("test_db_append_strict");
+        let config = LSMConfig {
+            append_mode: AppendMode::Strict,
+            ..small_config()
+        };
+
+        {
+            let db = DB::open(&dir, config.clone()).unwrap();
+            db.put(b"b", b"1").unwrap();
+            assert!(matches!(db.put(b"a", b"0"), Err(DbError::OutOfOrder(_))));
+            assert!(matches!(db.put(b"b", b"2"), Err(DbError::OutOfOrder(_))));
+            assert_eq!(db.get(b"a").unwrap(), None);
+            db.close().unwrap();
+        }
+
+        // the last key survives a reopen
+        let db = DB::open(&dir, config).unwrap();
+        assert!(matches!(db.put(b"a", b"0"), Err(DbError::OutOfOrder(_))));
+        db.put(b"c", b"3").unwrap();
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let dir = test_dir("test_db_write_batch");
+
+        {
+            let db = DB::open(&dir, LSMConfig::default()).unwrap();
+            db.put(b"key1", b"old").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put(b"key1", b"new");
+            batch.put(b"key2", b"value2");
+            batch.delete(b"key2");
+            batch.put(b"key3", b"value3");
+            db.write(batch).unwrap();
+
+            assert_eq!(db.get(b"key1").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(db.get(b"key2").unwrap(), None);
+            assert_eq!(db.get(b"key3").unwrap(), Some(b"value3".to_vec()));
+            db.close().unwrap();
+        }
+
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"key2").unwrap(), None);
+        assert_eq!(db.get(b"key3").unwrap(), Some(b"value3".to_vec()));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_take_batch_reuses_buffers() {
+        let dir = test_dir("test_db_batch_pool");
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+
+        let mut batch = db.take_batch();
+        for i in 0..100 {
+            batch.put(format!("key{:03}", i).as_bytes(), b"value");
+        }
+        let capacity = batch.capacity();
+        db.write(batch).unwrap();
+
+        let batch = db.take_batch();
+        assert!(batch.is_empty());
+        assert_eq!(batch.capacity(), capacity);
+        assert_eq!(db.take_batch().capacity(), WriteBatch::new().capacity());
+
+        assert_eq!(db.get(b"key042").unwrap(), Some(b"value".to_vec()));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_strict_append_mode_rejects_whole_batch() {
+        let dir = test_dir("test_db_batch_strict");
+        let config = LSMConfig {
+            append_mode: AppendMode::Strict,
+            ..LSMConfig::default()
+        };
+        let db = DB::open(&dir, config).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1");
+        batch.put(b"c", b"3");
+        batch.put(b"b", b"2");
+        assert!(matches!(db.write(batch), Err(DbError::OutOfOrder(_))));
+        assert_eq!(db.get(b"a").unwrap(), None);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_snapshot_ignores_later_writes() {
+        let dir = test_dir("test_db_snapshot");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        db.put(b"key1", b"v1").unwrap();
+        db.put(b"key2", b"v1").unwrap();
+        let snapshot = db.snapshot();
+        let at_snapshot = ReadOptions::new().with_snapshot(snapshot.clone());
+
+        db.put(b"key1", b"v2").unwrap();
+        db.delete(b"key2").unwrap();
+        db.put(b"key3", b"v2").unwrap();
+
+        assert_eq!(db.get_opt(b"key1", &at_snapshot).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(db.get_opt(b"key2", &at_snapshot).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(db.get_opt(b"key3", &at_snapshot).unwrap(), None);
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(db.get(b"key2").unwrap(), None);
+
+        // old versions survive a flush while the snapshot is alive
+        for i in 0..20 {
+            db.put(format!("pad{:03}", i).as_bytes(), b"x").unwrap();
+        }
+        assert!(!db.lock().manifest.get_level(0).is_empty());
+        assert_eq!(db.get_opt(b"key1", &at_snapshot).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(db.get_opt(b"key2", &at_snapshot).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"v2".to_vec()));
+
+        let keys: Vec<_> = db
+            .range_opt::<&[u8]>(.., &at_snapshot)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![(b"key1".to_vec(), b"v1".to_vec()), (b"key2".to_vec(), b"v1".to_vec())]
+        );
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_min_token_waits_for_write() {
+        let dir = test_dir("test_db_min_token");
+        let db = Arc::new(DB::open(&dir, small_config()).unwrap());
+
+        db.put(b"a", b"1").unwrap();
+        let token = db.commit_token();
+        let options = ReadOptions::new().with_min_token(token, Duration::ZERO);
+        assert_eq!(db.get_opt(b"a", &options).unwrap(), Some(b"1".to_vec()));
+
+        // a snapshot taken before the token can never see it
+        let snapshot = db.snapshot();
+        db.put(b"a", b"2").unwrap();
+        let later = db.commit_token();
+        let options = ReadOptions::new()
+            .with_snapshot(snapshot)
+            .with_min_token(later, Duration::ZERO);
+        assert!(matches!(db.get_opt(b"a", &options), Err(DbError::TokenNotVisible(_))));
+
+        // a token from another process that this one hasn't caught up with yet
+        let ahead = CommitToken::new(later.seq() + 1);
+        let options = ReadOptions::new().with_min_token(ahead, Duration::from_millis(10));
+        assert!(matches!(db.get_opt(b"a", &options), Err(DbError::TokenNotVisible(_))));
+
+        let writer = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                db.put(b"a", b"3").unwrap();
+            })
+        };
+        let options = ReadOptions::new().with_min_token(ahead, Duration::from_secs(10));
+        assert_eq!(db.get_opt(b"a", &options).unwrap(), Some(b"3".to_vec()));
+        writer.join().unwrap();
+
+        drop(db);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sync_writes_share_fsyncs() {
+        let dir = test_dir("test_db_group_commit");
+        let db = Arc::new(DB::open(&dir, LSMConfig::default()).unwrap());
+        let options = WriteOptions::new().with_sync(true);
+
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let (db, options) = (Arc::clone(&db), options.clone());
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        let key = format!("key{}-{:02}", t, i);
+                        db.put_opt(key.as_bytes(), b"value", &options).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let group_commit = &db.shared.group_commit;
+        assert_eq!(group_commit.durable(), 200);
+        assert!(group_commit.syncs() <= 200);
+
+        // writes without sync leave the durable mark alone
+        db.delete(b"key0-00").unwrap();
+        assert_eq!(group_commit.durable(), 200);
+        db.delete_opt(b"key0-01", &options).unwrap();
+        assert_eq!(group_commit.durable(), 202);
+
+        let db = Arc::try_unwrap(db).ok().unwrap();
+        db.close().unwrap();
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.iter().unwrap().count(), 198);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_wal_sync_policy() {
+        let dir = test_dir("test_db_wal_sync_policy");
+        let open = |wal_sync| {
+            DB::open(&dir, LSMConfig { wal_sync, ..LSMConfig::default() }).unwrap()
+        };
+
+        let db = open(WalSyncPolicy::Never);
+        db.put(b"a", b"1").unwrap();
+        assert_eq!(db.shared.group_commit.durable(), 0);
+        db.close().unwrap();
+
+        let db = open(WalSyncPolicy::Always);
+        db.put(b"b", b"1").unwrap();
+        db.put(b"c", b"1").unwrap();
+        assert_eq!(db.shared.group_commit.durable(), 3);
+        db.close().unwrap();
+
+        // the timer catches up with plain writes without any caller help
+        let db = open(WalSyncPolicy::EveryNMillis(5));
+        db.put(b"d", b"1").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while db.shared.group_commit.durable() < 4 {
+            assert!(Instant::now() < deadline, "WAL never synced");
+            thread::sleep(Duration::from_millis(1));
+        }
+        db.close().unwrap();
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_dropped_snapshot_releases_versions() {
+        let dir = test_dir("test_db_snapshot_release");
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+
+        db.put(b"key", b"v1").unwrap();
+        let snapshot = db.snapshot();
+        db.put(b"key", b"v2").unwrap();
+        assert_eq!(db.lock().memtable.iter_versions().count(), 2);
+
+        drop(snapshot);
+        db.put(b"key", b"v3").unwrap();
+        assert_eq!(db.lock().memtable.iter_versions().count(), 1);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sequence_survives_reopen() {
+        let dir = test_dir("test_db_sequence_reopen");
+
+        let last = {
+            let db = DB::open(&dir, small_config()).unwrap();
+            for i in 0..30 {
+                db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+            }
+            let last = db.snapshot().seq();
+            db.close().unwrap();
+            last
+        };
+        assert_eq!(last, 30);
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert_eq!(db.snapshot().seq(), last);
+        db.put(b"key999", b"value").unwrap();
+        assert_eq!(db.snapshot().seq(), last + 1);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_background_flush_serves_frozen_memtables() {
+        let dir = test_dir("test_db_background_flush");
+        let config = LSMConfig {
+            background_flush: true,
+            max_immutable_memtables: 1,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+
+        for i in 0..200 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+            assert!(db.lock().immutables.len() <= 1);
+        }
+        for i in 0..200 {
+            assert!(db.get(format!("key{:03}", i).as_bytes()).unwrap().is_some());
+        }
+        assert_eq!(db.iter().unwrap().count(), 200);
+
+        db.flush().unwrap();
+        {
+            let inner = db.lock();
+            assert!(inner.immutables.is_empty());
+            assert!(inner.manifest.get_level(0).len() > 1);
+        }
+        // only the active segment is left
+        assert_eq!(wal_segments(&dir).unwrap().len(), 1);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_pause_background_work() {
+        let dir = test_dir("test_db_pause_background");
+        let config = LSMConfig {
+            background_flush: true,
+            max_immutable_memtables: 16,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        assert!(!db.continue_background_work());
+
+        // calls nest; frozen memtables wait until the last pause is undone
+        db.pause_background_work();
+        db.pause_background_work();
+        let mut i = 0;
+        while db.lock().immutables.len() < 3 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+            i += 1;
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(db.lock().manifest.get_level(0).is_empty());
+        assert!(db.continue_background_work());
+        thread::sleep(Duration::from_millis(50));
+        assert!(db.lock().immutables.len() >= 3);
+
+        assert!(db.continue_background_work());
+        let start = Instant::now();
+        while !db.lock().immutables.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10), "flush thread never resumed");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!db.lock().manifest.get_level(0).is_empty());
+
+        // manual flushes still run while paused
+        db.pause_background_work();
+        db.put(b"manual", b"value").unwrap();
+        db.flush().unwrap();
+        assert!(db.lock().immutables.is_empty());
+        db.continue_background_work();
+        assert_eq!(db.iter().unwrap().count(), i + 1);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_close_cancels_jobs_in_flight() {
+        let dir = test_dir("test_db_close_in_flight");
+        let config = LSMConfig {
+            memtable_size: 16 * 1024,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config.clone()).unwrap();
+        let value = vec![b'v'; 100];
+        for i in 0..5000 {
+            db.put(format!("key{:05}", i).as_bytes(), &value).unwrap();
+        }
+        let job = db.compact_range_async::<&[u8]>(..).unwrap();
+        db.close().unwrap();
+        assert!(job.wait().is_ok() || job.status() == JobStatus::Cancelled);
+
+        // every table left on disk is one the manifest lists
+        let (manifest, _) = ManifestLog::open_current(&dir).unwrap().unwrap();
+        let listed: HashSet<PathBuf> = manifest.all_tables().map(|sst| sst.path.clone()).collect();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let name = PathBuf::from(entry.unwrap().file_name());
+            if name.extension().is_some_and(|ext| ext == "sst") {
+                assert!(listed.contains(&name), "{} left behind", name.display());
+            }
+        }
+
+        let db = DB::open(&dir, config).unwrap();
+        assert_eq!(db.iter().unwrap().count(), 5000);
+        drop(db);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sealed_segments_replayed_in_order() {
+        let dir = test_dir("test_db_sealed_segments");
+        let config = LSMConfig {
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        };
+        // two sealed segments whose flush never finished, then the active one
+        fs::create_dir_all(&dir).unwrap();
+        let put = |key: &[u8], value: &[u8]| WalEntry::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        let mut seq = 0;
+        for (number, entries) in [
+            (1, vec![put(b"a", b"1")]),
+            (2, vec![put(b"a", b"2"), put(b"b", b"1")]),
+            (3, vec![put(b"b", b"2")]),
+        ] {
+            let mut wal = WalWriter::create(dir.join(wal_segment_name(number))).unwrap();
+            for entry in &entries {
+                seq += 1;
+                wal.append(seq, entry).unwrap();
+            }
+            wal.sync().unwrap();
+        }
+
+        let db = DB::open(&dir, config).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        {
+            let inner = db.lock();
+            assert_eq!(inner.manifest.get_level(0).len(), 2);
+            assert_eq!(inner.manifest.wal_seq, 4);
+        }
+        // the sealed segments went away with their flush; the active one is reused
+        let segments = wal_segments(&dir).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].1.ends_with(wal_segment_name(3)));
+
+        db.put(b"c", b"1").unwrap();
+        db.close().unwrap();
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.get(b"c").unwrap(), Some(b"1".to_vec()));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sequence_restored_from_wal() {
+        let dir = test_dir("test_db_sequence_from_wal");
+        // the manifest says nothing was ever flushed; the log knows better
+        fs::create_dir_all(&dir).unwrap();
+        let mut wal = WalWriter::create(dir.join(wal_segment_name(1))).unwrap();
+        let batch = WalEntry::Batch {
+            entries: vec![
+                WalEntry::Put {
+                    key: b"a".to_vec(),
+                    value: b"1".to_vec(),
+                },
+                WalEntry::Delete { key: b"b".to_vec() },
+            ],
+        };
+        wal.append(41, &batch).unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.snapshot().seq(), 42);
+
+        // snapshots taken after recovery stay isolated from newer writes
+        let snapshot = db.snapshot();
+        db.put(b"a", b"2").unwrap();
+        let options = ReadOptions::new().with_snapshot(snapshot);
+        assert_eq!(db.get_opt(b"a", &options).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+        db.close().unwrap();
+
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.snapshot().seq(), 43);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_torn_wal_tail_tolerated_on_open() {
+        let dir = test_dir("test_db_torn_wal_tail");
+        {
+            let db = DB::open(&dir, LSMConfig::default()).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            db.close().unwrap();
+        }
+        // killed halfway through appending the second record
+        let (_, segment) = wal_segments(&dir).unwrap().pop().unwrap();
+        let len = fs::metadata(&segment).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&segment).unwrap().set_len(len - 4).unwrap();
+
+        let strict = LSMConfig {
+            wal_recovery: WalRecoveryMode::AbsoluteConsistency,
+            ..LSMConfig::default()
+        };
+        assert!(DB::open(&dir, strict).is_err());
+
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.status().background_errors.len(), 1);
+
+        // new writes land after the last good record and survive a reopen
+        db.put(b"c", b"3").unwrap();
+        db.close().unwrap();
+        let db = DB::open(&dir, LSMConfig::default()).unwrap();
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert!(db.status().background_errors.is_empty());
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_legacy_wals_replayed_on_open() {
+        let dir = test_dir("test_db_legacy_wal");
+        {
+            let db = DB::open(&dir, LSMConfig::default()).unwrap();
+            db.put(b"old", b"1").unwrap();
+            db.close().unwrap();
+        }
+        // a memtable frozen just before a crash, followed by newer writes
+        let active = |dir: &Path| wal_segments(dir).unwrap().pop().unwrap().1;
+        fs::rename(active(&dir), dir.join("wal-000007.log")).unwrap();
+        {
+            let db = DB::open(&dir, LSMConfig::default()).unwrap();
+            db.put(b"new", b"2").unwrap();
+            db.put(b"old", b"3").unwrap();
+            db.close().unwrap();
+        }
+        fs::rename(active(&dir), dir.join("wal.log")).unwrap();
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert_eq!(db.get(b"old").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"new").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.lock().manifest.get_level(0).len(), 2);
+        let segments = wal_segments(&dir).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].0.is_some());
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_compaction_merges_into_l1() {
+        let dir = test_dir("test_db_compaction");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        for round in 0..4 {
+            for i in 0..20 {
+                let key = format!("key{:03}", 19 - i);
+                db.put(key.as_bytes(), format!("value{}", round).as_bytes()).unwrap();
+            }
+            db.delete(format!("key{:03}", round).as_bytes()).unwrap();
+            db.flush().unwrap();
+        }
+        let l0_files: Vec<_> = db.lock().manifest.get_level(0).to_vec();
+        assert!(l0_files.len() >= 3);
+
+        db.compact().unwrap();
+        {
+            let inner = db.lock();
+            assert!(inner.manifest.get_level(0).is_empty());
+            // one version per live key, tombstones dropped in the bottommost level
+            let l1 = inner.manifest.get_level(1);
+            assert_eq!(l1.iter().map(|sst| sst.num_entries).sum::<u64>(), 20 - 1);
+        }
+        for sst in &l0_files {
+            assert!(!dir.join(&sst.path).exists());
+        }
+
+        assert_eq!(db.get(b"key000").unwrap(), Some(b"value3".to_vec()));
+        assert_eq!(db.get(b"key003").unwrap(), None);
+        assert_eq!(db.iter().unwrap().count(), 19);
+
+        db.close().unwrap();
+
+        let db = DB::open(&dir, small_config()).unwrap();
+        assert_eq!(db.get(b"key019").unwrap(), Some(b"value3".to_vec()));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_compaction_keeps_snapshot_versions() {
+        let dir = test_dir("test_db_compaction_snapshot");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        db.put(b"key", b"old").unwrap();
+        db.flush().unwrap();
+        let snapshot = ReadOptions::new().with_snapshot(db.snapshot());
+
+        for value in [b"v1", b"v2", b"v3"] {
+            db.put(b"key", value).unwrap();
+            db.flush().unwrap();
+        }
+        db.delete(b"key").unwrap();
+        db.flush().unwrap();
+
+        db.compact().unwrap();
+        assert!(db.lock().manifest.get_level(0).is_empty());
+        assert_eq!(db.get(b"key").unwrap(), None);
+        assert_eq!(db.get_opt(b"key", &snapshot).unwrap(), Some(b"old".to_vec()));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_tombstone_only_tables() {
+        let dir = test_dir("test_db_tombstone_only");
+        let config = LSMConfig {
+            memtable_size: 1 << 20,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+
+        for i in 0..10 {
+            db.put(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        for i in 0..10 {
+            db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        // deletes of keys that never existed shadow nothing
+        db.delete(b"zzz1").unwrap();
+        db.delete(b"zzz2").unwrap();
+        db.flush().unwrap();
+
+        {
+            let inner = db.lock();
+            let l0 = inner.manifest.get_level(0);
+            let (data, tombstones) = (&l0[0], &l0[1]);
+            assert!(!data.tombstone_only);
+            assert!(tombstones.tombstone_only);
+            assert!(inner.manifest.overlaps_older(tombstones, b"key005", b"key005"));
+            assert!(!inner.manifest.overlaps_older(tombstones, b"zzz1", b"zzz2"));
+        }
+
+        assert_eq!(db.get(b"key005").unwrap(), None);
+        assert_eq!(db.get(b"zzz1").unwrap(), None);
+        assert_eq!(db.iter().unwrap().count(), 0);
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sequential_tables_move_without_rewrite() {
+        let dir = test_dir("test_db_trivial_move");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        for i in 0..60 {
+            db.put(format!("ts{:06}", i).as_bytes(), b"sample").unwrap();
+        }
+        db.flush().unwrap();
+        let mut ids: Vec<u64> = db.lock().manifest.get_level(0).iter().map(|s| s.id).collect();
+        assert!(ids.len() >= 3);
+
+        db.compact().unwrap();
+        let mut moved: Vec<u64> = db.lock().manifest.get_level(1).iter().map(|s| s.id).collect();
+        ids.sort();
+        moved.sort();
+        assert_eq!(moved, ids);
+        assert_eq!(db.get(b"ts000042").unwrap(), Some(b"sample".to_vec()));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let dir = test_dir("test_db_background_compaction");
+        let config = LSMConfig {
+            auto_compaction: true,
+            ..small_config()
+        };
+        let db = DB::open(&dir, config).unwrap();
+
+        for round in 0..5 {
+            for i in 0..20 {
+                db.put(format!("key{:03}", i).as_bytes(), format!("v{}", round).as_bytes()).unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while db.lock().manifest.get_level(1).is_empty() {
+            assert!(std::time::Instant::now() < deadline, "compaction never ran");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(db.get(b"key007").unwrap(), Some(b"v4".to_vec()));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_large_values() {
+        let dir = test_dir("test_db_large_values");
+        let db = DB::open(&dir, small_config()).unwrap();
+
+        let value = vec![b'v'; 10_000];
+        db.put(b"big", &value).unwrap();
+        db.put(b"small", b"x").unwrap();
+
+        assert_eq!(db.get(b"big").unwrap(), Some(value));
+        assert_eq!(db.get(b"small").unwrap(), Some(b"x".to_vec()));
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// spans opened while it is the default subscriber, as their name
+    /// followed by their fields
+    #[cfg(feature = "tracing")]
+    struct SpanCapture(Arc<Mutex<Vec<String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanCapture {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            use tracing::field::{Field, Visit};
+            struct Fields(String);
+            impl Visit for Fields {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    self.0.push_str(&format!(" {}={:?}", field.name(), value));
+                }
+            }
+            let mut fields = Fields(attrs.metadata().name().to_string());
+            attrs.record(&mut fields);
+            let mut spans = self.0.lock().unwrap();
+            spans.push(fields.0);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() {
+        let dir = test_dir("test_db_tracing_spans");
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let capture = SpanCapture(Arc::clone(&spans));
+        // flush and compaction run on this thread, the only one capturing
+        tracing::subscriber::with_default(capture, || {
+            let db = DB::open(&dir, small_config()).unwrap();
+            for i in 0..3 {
+                db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+                db.flush().unwrap();
+            }
+            db.compact().unwrap();
+            db.close().unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        let flushes = spans.iter().filter(|span| *span == "flush_once").count();
+        assert!(flushes >= 3, "{:?}", spans);
+        let compaction = spans.iter().find(|span| span.starts_with("run_task"));
+        let compaction = compaction.unwrap_or_else(|| panic!("no compaction span: {:?}", spans));
+        assert!(compaction.contains("family=0"), "{}", compaction);
+        assert!(compaction.contains("output_level=1"), "{}", compaction);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,378 @@
+use std::path::{Path, PathBuf};
+
+use super::config::LSMConfig;
+use super::merge::StoredValue;
+use super::range_del::RangeTombstone;
+use super::sstable::table::{DEFAULT_RESTART_INTERVAL, unix_now};
+use super::sstable::{Result, SSTableError, SSTableReader, SSTableWriter};
+use super::ttl;
+
+/// SstFileWriter: builds table files outside any database, e.g. in a bulk
+/// load job, for DB::ingest to take in later
+///    - each file is an ordinary table: data blocks, index, bloom filter,
+///      range tombstones and footer, laid out as LSMConfig asks
+///    - keys must be added in strictly increasing order, one version each;
+///      range deletes may come in any order
+///    - entries are written with sequence number 0 and get a real one when
+///      ingested
+///    - with LSMConfig::ttl, values and operands are stamped as the
+///      database would stamp them, so they expire as if written now
+///    - with_target_file_size starts a new file once the current one
+///      reaches the target, at a key boundary; every file stays open until
+///      finish, which splits the range deletes between them so the files
+///      don't overlap and can be ingested together
+pub struct SstFileWriter {
+    config: LSMConfig,
+
+    /// the first file; later ones are named after it
+    path: PathBuf,
+
+    /// files written so far, the last one taking new keys
+    parts: Vec<Part>,
+
+    range_tombstones: Vec<RangeTombstone>,
+
+    target_file_size: Option<u64>,
+
+    last_key: Option<Vec<u8>>,
+
+    /// stamp for values when LSMConfig::ttl is set
+    stamp: Option<u64>,
+}
+
+/// one output file of an SstFileWriter
+struct Part {
+    writer: SSTableWriter,
+
+    path: PathBuf,
+
+    first_key: Option<Vec<u8>>,
+
+    last_key: Option<Vec<u8>>,
+}
+
+/// what SstFileWriter::finish wrote, per file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalFileInfo {
+    pub path: PathBuf,
+
+    /// smallest and largest key, widened to cover the range deletes
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+
+    /// puts, deletes and merges
+    pub num_entries: u64,
+
+    pub file_size: u64,
+}
+
+/// a table file DB::ingest is taking in, checked to hold one version per
+/// key in strictly increasing order
+pub(crate) struct ExternalTable {
+    pub path: PathBuf,
+
+    pub reader: SSTableReader,
+
+    /// bounds of the keys and range deletes, as SSTableMetadata has them
+    pub min_key: Vec<u8>,
+    pub max_key: Vec<u8>,
+
+    /// no keys and no range deletes
+    pub is_empty: bool,
+}
+
+impl SstFileWriter {
+    /// start the table file at `path`, replacing any file there
+    pub fn create(path: impl AsRef<Path>, config: &LSMConfig) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let part = Part::create(&path, config)?;
+        Ok(Self {
+            config: config.clone(),
+            path,
+            parts: vec![part],
+            range_tombstones: Vec::new(),
+            target_file_size: None,
+            last_key: None,
+            stamp: config.ttl.map(|_| unix_now()),
+        })
+    }
+
+    /// start a new file once the current one holds `bytes`; the second
+    /// file of `bulk.sst` is `bulk-000001.sst`, and so on
+    pub fn with_target_file_size(mut self, bytes: u64) -> Self {
+        self.target_file_size = Some(bytes);
+        self
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let value = self.stamped(value);
+        self.add(key, &StoredValue::Put(value))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.add(key, &StoredValue::Delete)
+    }
+
+    /// a merge operand, folded onto what the database holds for `key` once
+    /// ingested
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let operand = self.stamped(operand);
+        self.add(key, &StoredValue::Merge(vec![operand]))
+    }
+
+    /// delete every key in [start, end) the database holds when the files
+    /// are ingested; an empty range deletes nothing
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.range_tombstones.push(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq: 0,
+        });
+    }
+
+    /// entries added so far, across all files
+    pub fn num_entries(&self) -> u64 {
+        self.parts.iter().map(|part| part.writer.num_entries()).sum()
+    }
+
+    /// write out the index, filters and footer of every file and sync them,
+    /// in key order
+    ///
+    /// file i takes the range deletes from just past the last key of file
+    /// i - 1 up to its own last key; its own keys are written at the same
+    /// sequence number and shadow older versions anyway, so the files'
+    /// bounds never overlap
+    pub fn finish(self) -> Result<Vec<ExternalFileInfo>> {
+        let count = self.parts.len();
+        let mut lower: Option<Vec<u8>> = None;
+        let mut infos = Vec::with_capacity(count);
+        for (i, mut part) in self.parts.into_iter().enumerate() {
+            let upper = part.last_key.clone().filter(|_| i + 1 < count);
+            for tombstone in &self.range_tombstones {
+                let start = match &lower {
+                    Some(lower) => tombstone.start.as_slice().max(lower),
+                    None => &tombstone.start,
+                };
+                let end = match &upper {
+                    Some(upper) => tombstone.end.as_slice().min(upper),
+                    None => &tombstone.end,
+                };
+                if start < end {
+                    part.writer.add_range_tombstone(RangeTombstone {
+                        start: start.to_vec(),
+                        end: end.to_vec(),
+                        seq: 0,
+                    });
+                }
+            }
+            // the smallest key after this file's last
+            lower = part.last_key.clone().map(|mut key| {
+                key.push(0);
+                key
+            });
+
+            let metadata = part.writer.finish()?;
+            infos.push(ExternalFileInfo {
+                path: part.path,
+                smallest_key: metadata.min_key,
+                largest_key: metadata.max_key,
+                num_entries: metadata.num_entries,
+                file_size: metadata.size,
+            });
+        }
+        Ok(infos)
+    }
+
+    fn add(&mut self, key: &[u8], value: &StoredValue) -> Result<()> {
+        if self.last_key.as_deref().is_some_and(|last| key <= last) {
+            return Err(SSTableError::OutOfOrder(key.to_vec()));
+        }
+        let full = self.parts.last().is_some_and(|part| {
+            part.first_key.is_some()
+                && self.target_file_size.is_some_and(|t| part.writer.estimated_size() >= t)
+        });
+        if full {
+            let path = part_path(&self.path, self.parts.len());
+            self.parts.push(Part::create(&path, &self.config)?);
+        }
+        let part = self.parts.last_mut().expect("a writer always has a file");
+        part.writer.add_value(key, 0, value)?;
+        if part.first_key.is_none() {
+            part.first_key = Some(key.to_vec());
+        }
+        part.last_key = Some(key.to_vec());
+        self.last_key = Some(key.to_vec());
+        Ok(())
+    }
+
+    fn stamped(&self, value: &[u8]) -> Vec<u8> {
+        match self.stamp {
+            Some(now) => ttl::stamp(value, now),
+            None => value.to_vec(),
+        }
+    }
+}
+
+impl Part {
+    fn create(path: &Path, config: &LSMConfig) -> Result<Self> {
+        let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(SSTableError::Corrupted(format!(
+                "{} is not a file path",
+                path.display()
+            )));
+        };
+        let mut writer = SSTableWriter::create(
+            dir,
+            Path::new(file_name),
+            0,
+            0,
+            DEFAULT_RESTART_INTERVAL,
+            config.bloom_bits_per_key,
+        )?
+        .with_compression(config.compression);
+        if let Some(threshold) = config.inline_value_threshold {
+            writer = writer.with_inline_values(threshold);
+        }
+        if let Some(prefix_len) = config.prefix_filter_len {
+            writer = writer.with_prefix_filter(prefix_len, config.prefix_filter_bits_per_prefix);
+        }
+        Ok(Self {
+            writer,
+            path: path.to_path_buf(),
+            first_key: None,
+            last_key: None,
+        })
+    }
+}
+
+/// file `n` of a split output, `n` counting from 0 for `path` itself
+fn part_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{:06}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{:06}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+impl ExternalTable {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let reader = SSTableReader::open(path)?;
+        let mut bounds: Option<(Vec<u8>, Vec<u8>)> = None;
+        for entry in reader.iter() {
+            let (key, _, _) = entry?;
+            match &mut bounds {
+                Some((_, last)) if key <= *last => return Err(SSTableError::OutOfOrder(key)),
+                Some((_, last)) => *last = key,
+                None => bounds = Some((key.clone(), key)),
+            }
+        }
+        let tombstones = reader.range_tombstones().bounds();
+        let is_empty = bounds.is_none() && tombstones.is_none();
+        let (mut min_key, mut max_key) = bounds.unzip();
+        if let Some((start, end)) = tombstones {
+            min_key = Some(min_key.map_or(start.to_vec(), |min| min.min(start.to_vec())));
+            max_key = Some(max_key.map_or(end.to_vec(), |max| max.max(end.to_vec())));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader,
+            min_key: min_key.unwrap_or_default(),
+            max_key: max_key.unwrap_or_default(),
+            is_empty,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::sstable::SSTableReader;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_sst_file_writer() {
+        let dir = env::temp_dir().join("test_sst_file_writer");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bulk.sst");
+
+        let mut writer = SstFileWriter::create(&path, &LSMConfig::default()).unwrap();
+        for i in 0..500 {
+            let key = format!("key{:04}", i);
+            writer.put(key.as_bytes(), b"value").unwrap();
+        }
+        writer.delete(b"key9999").unwrap();
+        assert!(matches!(
+            writer.put(b"key0001", b"late"),
+            Err(SSTableError::OutOfOrder(_))
+        ));
+        assert!(writer.put(b"key9999", b"again").is_err());
+        writer.delete_range(b"a", b"b");
+        assert_eq!(writer.num_entries(), 501);
+
+        let info = writer.finish().unwrap().remove(0);
+        assert_eq!(info.smallest_key, b"a");
+        assert_eq!(info.largest_key, b"key9999");
+        assert_eq!(info.num_entries, 501);
+        assert_eq!(info.file_size, fs::metadata(&path).unwrap().len());
+
+        let reader = SSTableReader::open(&path).unwrap();
+        assert_eq!(
+            reader.get(b"key0042").unwrap(),
+            Some((0, StoredValue::Put(b"value".to_vec())))
+        );
+        assert_eq!(
+            reader.get(b"key9999").unwrap(),
+            Some((0, StoredValue::Delete))
+        );
+        assert!(reader.may_contain(b"key0499"));
+        assert_eq!(reader.range_tombstones().len(), 1);
+        assert_eq!(reader.iter().count(), 501);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_sst_file_writer_splits() {
+        let dir = env::temp_dir().join("test_sst_file_writer_splits");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut writer = SstFileWriter::create(dir.join("bulk.sst"), &LSMConfig::default())
+            .unwrap()
+            .with_target_file_size(8 * 1024);
+        writer.delete_range(b"key0100", b"zzz");
+        let value = [b'v'; 100];
+        for i in 0..500 {
+            writer.put(format!("key{:04}", i).as_bytes(), &value).unwrap();
+        }
+        writer.delete_range(b"a", b"key0001");
+        assert_eq!(writer.num_entries(), 500);
+
+        let infos = writer.finish().unwrap();
+        assert!(infos.len() > 2);
+        assert_eq!(infos[0].path, dir.join("bulk.sst"));
+        assert_eq!(infos[1].path, dir.join("bulk-000001.sst"));
+        assert_eq!(infos.iter().map(|info| info.num_entries).sum::<u64>(), 500);
+        assert!(infos.windows(2).all(|w| w[0].largest_key < w[1].smallest_key));
+        assert_eq!(infos[0].smallest_key, b"a");
+        assert_eq!(infos.last().unwrap().largest_key, b"zzz");
+
+        // every key past key0100 is under some file's range delete, except
+        // the last key of each file, which that file's own version shadows
+        let readers: Vec<_> = infos
+            .iter()
+            .map(|info| SSTableReader::open(&info.path).unwrap())
+            .collect();
+        let covered = |key: &[u8]| {
+            let mut tombstones = readers.iter().flat_map(|r| r.range_tombstones().iter());
+            tombstones.any(|tombstone| tombstone.contains(key))
+        };
+        assert!(covered(b"key0250") && covered(b"key0499a") && covered(b"yak"));
+        assert!(covered(b"a") && !covered(b"key0050"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,411 @@
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+use super::batch::WriteBatch;
+use super::db::{DB, Result};
+use super::iterator::DbIterator;
+use super::job::JobHandle;
+use super::options::{ReadOptions, WriteOptions};
+use super::sstable::table;
+use super::tailing::TailingIterator;
+
+/// id of the family DB::put and friends write to; it has no name and can't
+/// be dropped
+pub const DEFAULT_FAMILY: u32 = 0;
+
+/// ColumnFamily: a named keyspace of a DB, from DB::cf
+///    - keys of different families never collide, so a family is a namespace
+///      without prefixing keys
+///    - each family has its own memtable and tables, and its own config for
+///      them (LSMConfig::column_families); all share the WAL and manifest,
+///      so a batch is atomic and sequence numbers and snapshots are global
+///    - memtables of all families are frozen and flushed together
+///    - once its family is dropped, every call fails with FamilyDropped
+///    - DB::status breaks its counts down by family; suggest_split_points
+///      and approximate_size cover the default family only
+#[derive(Clone)]
+pub struct ColumnFamily<'a> {
+    db: &'a DB,
+
+    id: u32,
+
+    name: String,
+}
+
+impl<'a> ColumnFamily<'a> {
+    pub(crate) fn new(db: &'a DB, id: u32, name: String) -> Self {
+        Self { db, id, name }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.put_opt(key, value, &WriteOptions::default())
+    }
+
+    pub fn put_opt(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.db.write_in(self.id, &batch, options)
+    }
+
+    /// see DB::put_if
+    pub fn put_if(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<bool> {
+        self.put_if_opt(key, expected, value, &WriteOptions::default())
+    }
+
+    pub fn put_if_opt(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+        options: &WriteOptions,
+    ) -> Result<bool> {
+        self.db.put_if_in(self.id, key, expected, value, options)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.delete_opt(key, &WriteOptions::default())
+    }
+
+    pub fn delete_opt(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.db.write_in(self.id, &batch, options)
+    }
+
+    /// see DB::single_delete
+    pub fn single_delete(&self, key: &[u8]) -> Result<()> {
+        self.single_delete_opt(key, &WriteOptions::default())
+    }
+
+    pub fn single_delete_opt(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.single_delete(key);
+        self.db.write_in(self.id, &batch, options)
+    }
+
+    /// see DB::delete_range
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.delete_range_opt(start, end, &WriteOptions::default())
+    }
+
+    pub fn delete_range_opt(&self, start: &[u8], end: &[u8], options: &WriteOptions) -> Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        batch.delete_range(start, end);
+        self.db.write_in(self.id, &batch, options)
+    }
+
+    /// see DB::merge; the family's config supplies the merge operator
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.merge_opt(key, operand, &WriteOptions::default())
+    }
+
+    pub fn merge_opt(&self, key: &[u8], operand: &[u8], options: &WriteOptions) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key, operand);
+        self.db.write_in(self.id, &batch, options)
+    }
+
+    /// apply every operation in the batch to this family atomically
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_opt(batch, &WriteOptions::default())
+    }
+
+    pub fn write_opt(&self, batch: WriteBatch, options: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.db.write_in(self.id, &batch, options)
+    }
+
+    /// see DB::compact_range
+    pub fn compact_range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<()> {
+        self.db.compact_range_in(self.id, range)
+    }
+
+    pub fn compact_range_async<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<JobHandle> {
+        self.db.compact_range_async_in(self.id, range)
+    }
+
+    /// see DB::ingest
+    pub fn ingest<P: AsRef<Path>>(&self, files: &[P]) -> Result<()> {
+        self.db.ingest_in(self.id, files)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_opt(key, &ReadOptions::default())
+    }
+
+    pub fn get_opt(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        self.db.get_in(self.id, key, options)
+    }
+
+    /// see DB::may_contain
+    pub fn may_contain(&self, key: &[u8]) -> Result<bool> {
+        self.db.may_contain_in(self.id, key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.db.contains_key_in(self.id, key)
+    }
+
+    /// see DB::multi_get
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.multi_get_opt(keys, &ReadOptions::default())
+    }
+
+    pub fn multi_get_opt<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        options: &ReadOptions,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        self.db.multi_get_in(self.id, keys, options)
+    }
+
+    /// see DB::multi_get_partial
+    pub fn multi_get_partial<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        options: &ReadOptions,
+    ) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        self.db.multi_get_partial_in(self.id, keys, options)
+    }
+
+    /// iterate this family's live key-value pairs in `range`, see DB::range
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<DbIterator> {
+        self.range_opt(range, &ReadOptions::default())
+    }
+
+    pub fn range_opt<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        self.db.range_in(self.id, range, options)
+    }
+
+    /// see DB::range_rev
+    pub fn range_rev<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<DbIterator> {
+        self.range_rev_opt(range, &ReadOptions::default())
+    }
+
+    pub fn range_rev_opt<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+        options: &ReadOptions,
+    ) -> Result<DbIterator> {
+        self.db.range_rev_in(self.id, range, options)
+    }
+
+    /// see DB::tailing_iter
+    pub fn tailing_iter<K: AsRef<[u8]>>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<TailingIterator<'a>> {
+        self.db.tailing_iter_in(self.id, range)
+    }
+
+    /// see DB::prefix_iter; the family's config sets the prefix filter
+    pub fn prefix_iter(&self, prefix: &[u8]) -> Result<DbIterator> {
+        self.prefix_iter_opt(prefix, &ReadOptions::default())
+    }
+
+    pub fn prefix_iter_opt(&self, prefix: &[u8], options: &ReadOptions) -> Result<DbIterator> {
+        self.db.prefix_iter_in(self.id, prefix, options)
+    }
+
+    pub fn iter(&self) -> Result<DbIterator> {
+        self.range::<&[u8]>(..)
+    }
+}
+
+/// directory of column family `id`'s tables, relative to the database
+/// directory; the default family's tables sit in the database directory
+pub(crate) fn family_dir_name(id: u32) -> PathBuf {
+    PathBuf::from(format!("cf-{}", id))
+}
+
+/// the family named by a directory family_dir_name made
+pub(crate) fn parse_family_dir_name(name: &str) -> Option<u32> {
+    name.strip_prefix("cf-")?.parse().ok()
+}
+
+/// file name of table `id` of column family `family`, relative to the
+/// database directory
+pub(crate) fn table_file_name(family: u32, id: u64) -> PathBuf {
+    match family {
+        DEFAULT_FAMILY => table::table_file_name(id),
+        _ => family_dir_name(family).join(table::table_file_name(id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::config::LSMConfig;
+    use crate::lsm::db::DbError;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+
+    fn config() -> LSMConfig {
+        let small = LSMConfig {
+            memtable_size: 512,
+            max_levels: 3,
+            ..LSMConfig::default()
+        };
+        LSMConfig {
+            auto_compaction: false,
+            background_flush: false,
+            column_families: HashMap::from([("users".to_string(), small)]),
+            ..LSMConfig::default()
+        }
+    }
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("user{:04}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_column_families() {
+        let dir = env::temp_dir().join("test_db_column_families");
+        fs::remove_dir_all(&dir).ok();
+        let db = DB::open(&dir, config()).unwrap();
+        let users = db.create_cf("users").unwrap();
+        let logs = db.create_cf("logs").unwrap();
+        assert!(matches!(
+            db.create_cf("users"),
+            Err(DbError::FamilyExists(_))
+        ));
+        assert!(matches!(db.cf("missing"), Err(DbError::NoSuchFamily(_))));
+        assert_eq!(db.list_cfs(), vec!["users", "logs"]);
+
+        // the same key in three families
+        db.put(b"key", b"default").unwrap();
+        users.put(b"key", b"user").unwrap();
+        logs.put(b"key", b"log").unwrap();
+        assert_eq!(db.get(b"key").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(users.get(b"key").unwrap(), Some(b"user".to_vec()));
+        assert_eq!(
+            db.cf("logs").unwrap().get(b"key").unwrap(),
+            Some(b"log".to_vec())
+        );
+
+        // snapshots cover every family
+        let snapshot = ReadOptions::new().with_snapshot(db.snapshot());
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key");
+        batch.put(b"other", b"1");
+        users.write(batch).unwrap();
+        assert_eq!(users.get(b"key").unwrap(), None);
+        assert_eq!(
+            users.get_opt(b"key", &snapshot).unwrap(),
+            Some(b"user".to_vec())
+        );
+
+        // the small memtable of "users" fills and flushes into its own tables
+        for i in 0..300 {
+            users.put(&key(i), &[7; 20]).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact().unwrap();
+        let tables = fs::read_dir(dir.join(family_dir_name(1))).unwrap().count();
+        assert!(tables > 0);
+        users.put(&key(1000), b"in the WAL").unwrap();
+        assert_eq!(users.iter().unwrap().count(), 302);
+        assert_eq!(db.iter().unwrap().count(), 1);
+        drop((users, logs));
+        db.close().unwrap();
+
+        let db = DB::open(&dir, config()).unwrap();
+        assert_eq!(db.list_cfs(), vec!["users", "logs"]);
+        let users = db.cf("users").unwrap();
+        assert_eq!(users.get(&key(42)).unwrap(), Some(vec![7; 20]));
+        assert_eq!(users.get(&key(1000)).unwrap(), Some(b"in the WAL".to_vec()));
+        assert_eq!(
+            db.cf("logs").unwrap().get(b"key").unwrap(),
+            Some(b"log".to_vec())
+        );
+
+        // dropping a family takes its data and directory with it
+        let logs = db.cf("logs").unwrap();
+        db.flush().unwrap();
+        assert!(db.drop_cf("logs").unwrap());
+        assert!(!db.drop_cf("logs").unwrap());
+        assert!(!dir.join(family_dir_name(2)).exists());
+        assert!(matches!(logs.get(b"key"), Err(DbError::FamilyDropped(2))));
+        users.put(b"after", b"drop").unwrap();
+        drop((users, logs));
+        db.close().unwrap();
+
+        let db = DB::open(&dir, config()).unwrap();
+        assert_eq!(db.list_cfs(), vec!["users"]);
+        assert_eq!(
+            db.cf("users").unwrap().get(b"after").unwrap(),
+            Some(b"drop".to_vec())
+        );
+        let logs = db.create_cf("logs").unwrap();
+        assert_eq!(logs.get(b"key").unwrap(), None);
+        assert!(dir.join(family_dir_name(3)).exists());
+        drop(logs);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_family_status_and_quota() {
+        let dir = env::temp_dir().join("test_db_family_quota");
+        fs::remove_dir_all(&dir).ok();
+        let mut config = config();
+        let users = config.column_families.get_mut("users").unwrap();
+        users.max_disk_bytes = Some(2048);
+        let db = DB::open(&dir, config).unwrap();
+        let users = db.create_cf("users").unwrap();
+        let logs = db.create_cf("logs").unwrap();
+
+        let error = (0..1000)
+            .find_map(|i| users.put(&key(i), &[7; 50]).err())
+            .unwrap();
+        assert!(matches!(
+            error,
+            DbError::FamilyQuotaExceeded { ref family, limit: 2048, .. } if family == "users"
+        ));
+        // the other families and deletes go on
+        users.delete(&key(0)).unwrap();
+        logs.put(b"key", b"log").unwrap();
+        db.put(b"key", b"default").unwrap();
+        db.flush().unwrap();
+
+        let status = db.status();
+        let names: Vec<Option<&str>> = status.families.iter().map(|f| f.name.as_deref()).collect();
+        assert_eq!(names, vec![None, Some("users"), Some("logs")]);
+        let users_status = &status.families[1];
+        assert!(users_status.disk_bytes >= 2048);
+        assert_eq!(users_status.disk_quota, Some(2048));
+        assert_eq!(status.families[2].disk_quota, None);
+        assert_eq!(status.families[2].files, 1);
+        // the totals are over every family
+        let files: usize = status.levels.iter().map(|level| level.files).sum();
+        let family_files: usize = status.families.iter().map(|f| f.files).sum();
+        assert_eq!(files, family_files);
+        let debt: u64 = status.families.iter().map(|f| f.compaction_debt_bytes).sum();
+        assert_eq!(status.compaction_debt_bytes, debt);
+        assert!(users_status.compaction_debt_bytes > 0);
+        drop((users, logs));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,403 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::db::{DbError, Result};
+use super::merge::{MergeFold, MergeOperator, StoredValue};
+use super::sstable::table::unix_now;
+use super::ttl::Expiry;
+
+/// key plus value; a None value is a tombstone
+pub type KvEntry = (Vec<u8>, Option<Vec<u8>>);
+
+/// key plus one stored version of it
+pub type VersionEntry = (Vec<u8>, StoredValue);
+
+/// a sorted stream of entries from one memtable or SSTable
+/// - a key comes once, with its newest visible version, and again with
+///   older versions for as long as the newer are merge operands
+/// - keys ascend, or descend for MergeIterator::new_rev; versions of a key
+///   always come newest first
+pub type EntrySource = Box<dyn Iterator<Item = Result<VersionEntry>> + Send>;
+
+/// MergeIterator: k-way merge over sorted sources
+///    - sources are ordered newest first (index 0 wins on equal keys)
+///    - keys come in ascending order, or descending from new_rev
+///    - each key is yielded once, with the newest version, merge operands
+///      folded onto what they sit on by the merge operator
+///    - tombstones are passed through so callers can shadow older data
+pub struct MergeIterator {
+    sources: Vec<EntrySource>,
+    heap: BinaryHeap<HeapEntry>,
+    error: Option<DbError>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    expiry: Option<Expiry>,
+    reverse: bool,
+}
+
+/// Iterator over live key-value pairs of the whole database
+/// - skips tombstones
+/// - stops at the upper bound of the requested range, or at the lower one
+///   when iterating backwards
+pub struct DbIterator {
+    inner: MergeIterator,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+    done: bool,
+}
+
+struct HeapEntry {
+    key: Vec<u8>,
+    value: StoredValue,
+    source: usize,
+    reverse: bool,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap: invert so the smallest key (the largest
+        // in reverse), then the newest source, ends up on top
+        let keys = if self.reverse {
+            self.key.cmp(&other.key)
+        } else {
+            other.key.cmp(&self.key)
+        };
+        keys.then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.source == other.source
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl MergeIterator {
+    pub fn new(sources: Vec<EntrySource>) -> Self {
+        Self::with_direction(sources, false)
+    }
+
+    /// merge sources whose keys descend, yielding the last key first
+    pub fn new_rev(sources: Vec<EntrySource>) -> Self {
+        Self::with_direction(sources, true)
+    }
+
+    fn with_direction(sources: Vec<EntrySource>, reverse: bool) -> Self {
+        let mut iter = Self {
+            heap: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            error: None,
+            merge_operator: None,
+            expiry: None,
+            reverse,
+        };
+
+        for source in 0..iter.sources.len() {
+            iter.advance(source);
+        }
+
+        iter
+    }
+
+    /// fold merge operands with `operator`; without one they fail the read
+    pub fn with_merge_operator(mut self, operator: Option<Arc<dyn MergeOperator>>) -> Self {
+        self.merge_operator = operator;
+        self
+    }
+
+    /// read the sources as LSMConfig::ttl stamped them, as of now
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.expiry = Expiry::new(ttl, unix_now());
+        self
+    }
+
+    /// swap the first `sources.len()` sources for new ones, e.g. memtables
+    /// read again after writes; their entries already in the heap are dropped
+    pub(crate) fn replace_sources(&mut self, sources: Vec<EntrySource>) {
+        let replaced = sources.len();
+        self.heap.retain(|entry| entry.source >= replaced);
+        for (at, source) in sources.into_iter().enumerate() {
+            self.sources[at] = source;
+            self.advance(at);
+        }
+    }
+
+    /// pull the next entry of a source into the heap
+    fn advance(&mut self, source: usize) {
+        match self.sources[source].next() {
+            Some(Ok((key, value))) => {
+                let reverse = self.reverse;
+                self.heap.push(HeapEntry { key, value, source, reverse })
+            }
+            // keep the first error; later ones are usually consequences of it
+            Some(Err(e)) if self.error.is_none() => self.error = Some(e),
+            Some(Err(_)) | None => {}
+        }
+    }
+}
+
+impl Iterator for MergeIterator {
+    type Item = Result<KvEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+
+        let top = self.heap.pop()?;
+        self.advance(top.source);
+        let mut fold = MergeFold::with_expiry(self.expiry);
+        let mut settled = fold.push(top.value);
+
+        // fold older versions of the same key into merge operands, drop the rest
+        while self.heap.peek().is_some_and(|next| next.key == top.key) {
+            if let Some(shadowed) = self.heap.pop() {
+                self.advance(shadowed.source);
+                if !settled {
+                    settled = fold.push(shadowed.value);
+                }
+            }
+        }
+
+        let operator = self.merge_operator.as_deref();
+        Some(fold.finish(&top.key, operator).map(|value| (top.key, value)))
+    }
+}
+
+impl DbIterator {
+    pub fn new(inner: MergeIterator, upper: Bound<Vec<u8>>) -> Self {
+        Self {
+            inner,
+            end: upper,
+            reverse: false,
+            done: false,
+        }
+    }
+
+    /// iterate a MergeIterator::new_rev down to `lower`
+    pub fn new_rev(inner: MergeIterator, lower: Bound<Vec<u8>>) -> Self {
+        Self {
+            inner,
+            end: lower,
+            reverse: true,
+            done: false,
+        }
+    }
+
+    fn in_range(&self, key: &[u8]) -> bool {
+        if self.reverse {
+            above_lower(key, &self.end)
+        } else {
+            below_upper(key, &self.end)
+        }
+    }
+}
+
+impl Iterator for DbIterator {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.inner.next() {
+                Some(Ok((key, value))) => {
+                    if !self.in_range(&key) {
+                        self.done = true;
+                        return None;
+                    }
+                    if let Some(value) = value {
+                        return Some(Ok((key, value)));
+                    }
+                }
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => self.done = true,
+            }
+        }
+
+        None
+    }
+}
+
+pub(crate) fn above_lower(key: &[u8], lower: &Bound<Vec<u8>>) -> bool {
+    match lower {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+pub(crate) fn below_upper(key: &[u8], upper: &Bound<Vec<u8>>) -> bool {
+    match upper {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// exclusive upper bound of the keys starting with `prefix`
+///
+/// the prefix with trailing 0xff bytes dropped and its last byte incremented;
+/// unbounded when no such key exists (empty or all-0xff prefix)
+pub fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(entries: &[(&str, Option<&str>)]) -> EntrySource {
+        let entries: Vec<Result<VersionEntry>> = entries
+            .iter()
+            .map(|(k, v)| {
+                let value = v.map(|v| v.as_bytes().to_vec());
+                Ok((k.as_bytes().to_vec(), StoredValue::from(value)))
+            })
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"user/"), Bound::Excluded(b"user0".to_vec()));
+        assert_eq!(prefix_end(&[b'a', 0xff, 0xff]), Bound::Excluded(b"b".to_vec()));
+        assert_eq!(prefix_end(&[0xff, 0xff]), Bound::Unbounded);
+        assert_eq!(prefix_end(b""), Bound::Unbounded);
+    }
+
+    #[test]
+    fn test_merge_orders_keys() {
+        let iter = MergeIterator::new(vec![
+            source(&[("b", Some("2")), ("d", Some("4"))]),
+            source(&[("a", Some("1")), ("c", Some("3")), ("e", Some("5"))]),
+        ]);
+
+        let keys: Vec<_> = iter.map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, vec![b"a", b"b", b"c", b"d", b"e"]);
+    }
+
+    #[test]
+    fn test_merge_newest_source_wins() {
+        let iter = MergeIterator::new(vec![
+            source(&[("a", Some("new")), ("b", None)]),
+            source(&[("a", Some("old")), ("b", Some("old")), ("c", Some("old"))]),
+        ]);
+
+        let entries: Vec<_> = iter.map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), Some(b"new".to_vec())),
+                (b"b".to_vec(), None),
+                (b"c".to_vec(), Some(b"old".to_vec())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_folds_operands() {
+        use crate::lsm::merge::AppendOperator;
+
+        let operand = |op: &str| StoredValue::Merge(vec![op.as_bytes().to_vec()]);
+        let sources = || -> Vec<EntrySource> {
+            let newest: Vec<Result<VersionEntry>> = vec![
+                Ok((b"a".to_vec(), operand("3"))),
+                Ok((b"a".to_vec(), operand("2"))),
+                Ok((b"b".to_vec(), operand("x"))),
+            ];
+            vec![
+                Box::new(newest.into_iter()),
+                source(&[("a", Some("1")), ("b", None), ("c", Some("old"))]),
+                source(&[("a", Some("0"))]),
+            ]
+        };
+
+        let operator: Arc<dyn MergeOperator> = Arc::new(AppendOperator::new(b"+"));
+        let iter = MergeIterator::new(sources()).with_merge_operator(Some(operator));
+        let entries: Vec<_> = iter.map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), Some(b"1+2+3".to_vec())),
+                (b"b".to_vec(), Some(b"x".to_vec())),
+                (b"c".to_vec(), Some(b"old".to_vec())),
+            ]
+        );
+
+        let mut iter = MergeIterator::new(sources());
+        assert!(matches!(iter.next(), Some(Err(DbError::NoMergeOperator))));
+    }
+
+    #[test]
+    fn test_db_iterator_skips_tombstones_and_stops_at_upper() {
+        let merge = MergeIterator::new(vec![
+            source(&[("b", None)]),
+            source(&[("a", Some("1")), ("b", Some("2")), ("c", Some("3")), ("d", Some("4"))]),
+        ]);
+
+        let iter = DbIterator::new(merge, Bound::Excluded(b"d".to_vec()));
+        let entries: Vec<_> = iter.map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![(b"a".to_vec(), b"1".to_vec()), (b"c".to_vec(), b"3".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_merge_reverse() {
+        let merge = MergeIterator::new_rev(vec![
+            source(&[("d", Some("new")), ("b", None)]),
+            source(&[("e", Some("5")), ("d", Some("old")), ("b", Some("2")), ("a", Some("1"))]),
+        ]);
+
+        let iter = DbIterator::new_rev(merge, Bound::Excluded(b"a".to_vec()));
+        let entries: Vec<_> = iter.map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![(b"e".to_vec(), b"5".to_vec()), (b"d".to_vec(), b"new".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_merge_propagates_errors() {
+        let failing: EntrySource = Box::new(
+            vec![Err(DbError::Corrupted("bad block".to_string()))].into_iter(),
+        );
+        let mut iter = MergeIterator::new(vec![source(&[("a", Some("1"))]), failing]);
+
+        assert!(matches!(iter.next(), Some(Err(DbError::Corrupted(_)))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_bounds() {
+        let start = Bound::Included(b"b".to_vec());
+        assert!(!above_lower(b"a", &start));
+        assert!(above_lower(b"b", &start));
+
+        let end = Bound::Excluded(b"d".to_vec());
+        assert!(below_upper(b"c", &end));
+        assert!(!below_upper(b"d", &end));
+        assert!(below_upper(b"d", &Bound::Included(b"d".to_vec())));
+    }
+}
@@ -0,0 +1,221 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::db::{DbError, Result};
+
+/// JobHandle: completion handle for a manual flush or range compaction
+///    - clones refer to the same job; a request that joined an equivalent
+///      job still waiting to start gets a handle to that job
+///    - wait() blocks until the job is done; status() polls
+///    - cancel() stops the job at its next block boundary, for everyone who
+///      joined it
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    state: Arc<JobState>,
+
+    cancel: CancelToken,
+}
+
+/// CancelToken: asks a long-running operation to stop
+///    - checked at block boundaries; the operation removes the files it was
+///      writing and fails with DbError::Cancelled
+///    - a child token is also cancelled with its parent, e.g. every job of a
+///      database that shuts down
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+
+    parent: Option<Box<CancelToken>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobStatus {
+    /// waiting for an earlier job of the same kind
+    Queued,
+    Running,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct JobState {
+    status: Mutex<JobStatus>,
+    finished: Condvar,
+}
+
+impl JobHandle {
+    pub(crate) fn new(cancel: CancelToken) -> Self {
+        Self {
+            state: Arc::new(JobState {
+                status: Mutex::new(JobStatus::Queued),
+                finished: Condvar::new(),
+            }),
+            cancel,
+        }
+    }
+
+    pub(crate) fn start(&self) {
+        *self.lock() = JobStatus::Running;
+    }
+
+    pub(crate) fn finish(&self, result: &Result<()>) {
+        *self.lock() = match result {
+            Ok(()) => JobStatus::Done,
+            Err(DbError::Cancelled) => JobStatus::Cancelled,
+            Err(e) => JobStatus::Failed(e.to_string()),
+        };
+        self.state.finished.notify_all();
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.lock().clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        is_final(&self.status())
+    }
+
+    /// stop the job; a job still queued fails as soon as it starts
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// the token the job checks, e.g. to cancel it along with other work
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+
+    /// block until the job is done
+    pub fn wait(&self) -> Result<()> {
+        let status = self.lock();
+        let status = self
+            .state
+            .finished
+            .wait_while(status, |status| !is_final(status))
+            .unwrap_or_else(|e| e.into_inner());
+        outcome(&status)
+    }
+
+    /// wait up to `timeout`; None if the job is still queued or running
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<()>> {
+        let deadline = Instant::now() + timeout;
+        let mut status = self.lock();
+        while !is_final(&status) {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            status = self
+                .state
+                .finished
+                .wait_timeout(status, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        Some(outcome(&status))
+    }
+
+    /// both handles refer to the same job
+    pub fn same_job(&self, other: &JobHandle) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, JobStatus> {
+        self.state.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a token cancelled by its own cancel() or by this one's
+    pub fn child(&self) -> Self {
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+            || self.parent.as_ref().is_some_and(|parent| parent.is_cancelled())
+    }
+
+    /// Err(DbError::Cancelled) once cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(DbError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+fn is_final(status: &JobStatus) -> bool {
+    matches!(status, JobStatus::Done | JobStatus::Failed(_) | JobStatus::Cancelled)
+}
+
+fn outcome(status: &JobStatus) -> Result<()> {
+    match status {
+        JobStatus::Failed(msg) => Err(DbError::Job(msg.clone())),
+        JobStatus::Cancelled => Err(DbError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_wait_and_poll() {
+        let job = JobHandle::new(CancelToken::new());
+        let waiter = {
+            let job = job.clone();
+            thread::spawn(move || job.wait())
+        };
+        assert_eq!(job.status(), JobStatus::Queued);
+        assert!(job.wait_timeout(Duration::from_millis(10)).is_none());
+
+        job.start();
+        assert!(!job.is_finished());
+        job.finish(&Ok(()));
+        assert!(waiter.join().unwrap().is_ok());
+        assert!(job.is_finished());
+        assert!(job.wait_timeout(Duration::ZERO).unwrap().is_ok());
+
+        let failed = JobHandle::new(CancelToken::new());
+        failed.finish(&Err(DbError::Flush("disk full".to_string())));
+        assert!(matches!(failed.status(), JobStatus::Failed(msg) if msg.contains("disk full")));
+        assert!(matches!(failed.wait(), Err(DbError::Job(_))));
+        assert!(!failed.same_job(&job));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let shutdown = CancelToken::new();
+        let (first, second) = (shutdown.child(), shutdown.child());
+        assert!(first.check().is_ok());
+
+        first.cancel();
+        assert!(matches!(first.check(), Err(DbError::Cancelled)));
+        assert!(!second.is_cancelled() && !shutdown.is_cancelled());
+        shutdown.cancel();
+        assert!(second.is_cancelled());
+
+        let job = JobHandle::new(CancelToken::new());
+        job.cancel();
+        assert!(job.cancel_token().is_cancelled());
+        job.finish(&job.cancel_token().check());
+        assert_eq!(job.status(), JobStatus::Cancelled);
+        assert!(matches!(job.wait(), Err(DbError::Cancelled)));
+    }
+}
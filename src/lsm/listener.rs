@@ -0,0 +1,108 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::compaction::CompactionReason;
+use super::manifest::SSTableMetadata;
+
+/// EventListener: callbacks for what the database does in the background,
+/// registered through LSMConfig::listeners
+///    - every method defaults to doing nothing, so a listener implements
+///      only the events it cares about
+///    - callbacks run on the thread doing the work, flush and compaction
+///      threads included, after the DB lock is released; a slow callback
+///      holds up that work, so hand anything heavy to another thread
+///    - a callback may read from the database, but a flush or compaction
+///      called from one waits for the job that is calling it
+pub trait EventListener: Send + Sync {
+    /// a frozen memtable is about to be written out
+    fn on_flush_begin(&self, _info: &FlushJobInfo) {}
+
+    /// a flush's tables are listed in the manifest
+    fn on_flush_completed(&self, _info: &FlushJobInfo) {}
+
+    /// a compaction's outputs replaced its inputs in the manifest
+    fn on_compaction_completed(&self, _info: &CompactionJobInfo) {}
+
+    /// a flush, compaction or ingest added a table to the manifest
+    fn on_table_file_created(&self, _info: &TableFileInfo) {}
+
+    /// a table no longer listed was deleted from disk
+    fn on_table_file_deleted(&self, _info: &TableFileDeletedInfo) {}
+
+    /// a synced write or the periodic sync thread made the WAL durable
+    fn on_wal_sync(&self, _info: &WalSyncInfo) {}
+}
+
+impl fmt::Debug for dyn EventListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventListener")
+    }
+}
+
+/// one memtable flush; `tables` is empty and `elapsed` zero in
+/// on_flush_begin
+#[derive(Debug, Clone)]
+pub struct FlushJobInfo {
+    /// sequence number of the newest write in the memtable
+    pub largest_seq: u64,
+
+    /// bytes of the default family's memtable and those frozen with it
+    pub memtable_bytes: usize,
+
+    /// one table per column family with writes in the memtable
+    pub tables: Vec<TableFileInfo>,
+
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactionJobInfo {
+    pub family: u32,
+
+    pub reason: CompactionReason,
+
+    pub level: usize,
+
+    pub output_level: usize,
+
+    /// the tables of both levels that were rewritten
+    pub inputs: Vec<SSTableMetadata>,
+
+    /// the tables that replaced them; for a trivial move, the inputs at
+    /// their new level
+    pub outputs: Vec<SSTableMetadata>,
+
+    /// moved to the output level without being rewritten
+    pub trivial_move: bool,
+
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableFileInfo {
+    pub family: u32,
+
+    /// where the file is, inside the database directory
+    pub path: PathBuf,
+
+    pub table: SSTableMetadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableFileDeletedInfo {
+    pub id: u64,
+
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalSyncInfo {
+    /// the segment that was synced
+    pub path: PathBuf,
+
+    /// every write up to this sequence number is durable
+    pub synced_seq: u64,
+
+    pub elapsed: Duration,
+}
@@ -1,10 +1,23 @@
-use std::fs::{self, File, OpenOptions};
+use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
+use super::family::DEFAULT_FAMILY;
+use super::sstable::{BloomFilter, SSTableReader};
+use super::version_edit::{self, FamilyEdit, VersionEdit};
+
+/// largest prefix filter kept in the manifest; files with more distinct
+/// prefixes than fit go without one
+const MAX_PREFIX_FILTER_BYTES: usize = 1024;
+
 /// Manifest tracks all SSTable files and LSM state
+/// - kept on disk as a ManifestLog of version edits; `save` writes the
+///   older whole-manifest JSON format
+/// - file changes are collected as a pending edit until the log commits them
+/// - the levels are the default column family's; every other family keeps
+///   its levels in a manifest of its own inside this one, see FamilyManifest
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Manifest {
     pub version: u64,
@@ -13,7 +26,35 @@ pub struct Manifest {
 
     pub next_sstable_id: u64,
 
-    pub wal_seq: u64
+    pub wal_seq: u64,
+
+    /// sequence number of the last write persisted in an SSTable
+    #[serde(default)]
+    pub last_sequence: u64,
+
+    #[serde(default)]
+    pub families: Vec<FamilyManifest>,
+
+    /// ids are never reused, so WAL records of a dropped family can't land
+    /// in a new one
+    #[serde(default = "first_family_id")]
+    pub next_family_id: u32,
+
+    /// file changes not yet in the log
+    #[serde(skip)]
+    pending: VersionEdit,
+}
+
+/// a column family other than the default one
+///    - `tables` holds the family's levels and its pending file changes; its
+///      counters are unused, the outer manifest's count for every family
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FamilyManifest {
+    pub id: u32,
+
+    pub name: String,
+
+    pub tables: Manifest,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +79,96 @@ pub struct SSTableMetadata {
     pub min_key: Vec<u8>,
 
     pub max_key: Vec<u8>,
+
+    /// unix seconds when the file was written; 0 for files from older manifests
+    #[serde(default)]
+    pub created_at: u64,
+
+    /// every entry is a tombstone; reads skip the file when nothing older
+    /// overlaps it, since then it has nothing left to delete
+    #[serde(default)]
+    pub tombstone_only: bool,
+
+    /// coarse filter over the file's key prefixes, see PrefixFilter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix_filter: Option<PrefixFilter>,
+}
+
+/// PrefixFilter: bloom filter over the first `prefix_len` bytes of every key
+/// in a file, kept in the manifest
+///    - point reads consult it before opening the file, so a miss costs no
+///      I/O at all; the per-file bloom filter still answers for exact keys
+///    - keys shorter than `prefix_len` are filtered whole
+///    - the granularity is the prefix length: shorter prefixes mean fewer
+///      distinct entries and smaller filters, but fewer files ruled out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixFilter {
+    pub prefix_len: usize,
+
+    pub filter: BloomFilter,
+}
+
+/// what opening does about a manifest that can't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ManifestRecoveryMode {
+    /// fail to open, leaving the manifest as it is for inspection
+    #[default]
+    Fail,
+
+    /// set the damaged manifest aside as `<name>.corrupt` and rebuild one
+    /// from the tables in the directory, see Manifest::rebuild
+    RebuildFromTables,
+}
+
+impl SSTableMetadata {
+    /// false only if the prefix filter rules `key` out of this file
+    pub fn may_contain_prefix(&self, key: &[u8]) -> bool {
+        self.prefix_filter.as_ref().is_none_or(|filter| filter.may_contain(key))
+    }
+
+    /// false only if the prefix filter rules out every key starting with
+    /// `prefix`; prefixes shorter than the filtered length never are
+    pub fn may_contain_keys_with(&self, prefix: &[u8]) -> bool {
+        self.prefix_filter
+            .as_ref()
+            .is_none_or(|filter| prefix.len() < filter.prefix_len || filter.may_contain(prefix))
+    }
+}
+
+impl PrefixFilter {
+    /// filter the distinct prefixes of `keys`, which must be sorted
+    ///
+    /// None if the filter would exceed MAX_PREFIX_FILTER_BYTES
+    pub fn build<'a>(
+        keys: impl IntoIterator<Item = &'a [u8]>,
+        prefix_len: usize,
+        bits_per_prefix: usize,
+    ) -> Option<Self> {
+        let mut prefixes: Vec<&[u8]> = Vec::new();
+        for key in keys {
+            let prefix = &key[..key.len().min(prefix_len)];
+            if prefixes.last() != Some(&prefix) {
+                prefixes.push(prefix);
+            }
+        }
+        if prefixes.len() * bits_per_prefix > MAX_PREFIX_FILTER_BYTES * 8 {
+            return None;
+        }
+
+        let mut bloom = BloomFilter::new(prefixes.len(), bits_per_prefix);
+        for prefix in prefixes {
+            bloom.add(prefix);
+        }
+        Some(Self {
+            prefix_len,
+            filter: bloom,
+        })
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let prefix = &key[..key.len().min(self.prefix_len)];
+        self.filter.may_contain(prefix)
+    }
 }
 
 #[derive(Debug)]
@@ -89,6 +220,10 @@ impl Manifest {
             levels,
             next_sstable_id: 1,
             wal_seq: 1,
+            last_sequence: 0,
+            families: Vec::new(),
+            next_family_id: first_family_id(),
+            pending: VersionEdit::default(),
         }
     }
 
@@ -102,8 +237,21 @@ impl Manifest {
             )));
         }
 
-        let contents = fs::read_to_string(path)?;
-        let manifest: Manifest = serde_json::from_str(&contents)?;
+        Self::decode(&fs::read(path)?)
+    }
+
+    /// parse a manifest from the bytes of a manifest file, either a
+    /// ManifestLog or the older JSON format
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let json = bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{');
+        let manifest: Manifest = if json {
+            serde_json::from_slice(bytes).map_err(|e| {
+                let what = if e.is_eof() { "truncated" } else { "unreadable" };
+                ManifestError::Corrupted(format!("JSON manifest is {}: {}", what, e))
+            })?
+        } else {
+            version_edit::replay(bytes)?.0
+        };
 
         if manifest.levels.is_empty() {
             return Err(ManifestError::Corrupted(
@@ -111,10 +259,66 @@ impl Manifest {
             ));
         }
 
+        // levels are looked up by position, so the numbering must line up
+        let families = manifest.families.iter().map(|family| &family.tables);
+        for levels in std::iter::once(&manifest).chain(families).map(|m| &m.levels) {
+            for (i, level) in levels.iter().enumerate() {
+                if level.level != i || level.sstables.iter().any(|sst| sst.level != i) {
+                    return Err(ManifestError::Corrupted(format!(
+                        "Level {} is out of place",
+                        level.level
+                    )));
+                }
+            }
+        }
+
         Ok(manifest)
     }
 
     /// save manifest to disk atomically (write temp, sync, rename)
+    /// rebuild a manifest from the table files in `dir`, for when the manifest
+    /// itself is lost; returns it with every table left out and why
+    /// - a table is kept if its footer, index and every entry read back; it
+    ///   goes into L0, and tables are ordered by their newest sequence number
+    ///   so newer tables keep shadowing older ones
+    /// - compaction sorts the tables back into levels afterwards
+    /// - counters start past every table id and sequence number found; WAL
+    ///   segments are the caller's to account for
+    /// - prefix filters are not rebuilt
+    pub fn rebuild(
+        dir: impl AsRef<Path>,
+        num_levels: usize,
+    ) -> Result<(Self, Vec<(PathBuf, String)>)> {
+        let dir = dir.as_ref();
+        let mut manifest = Manifest::new(num_levels);
+        let mut tables = Vec::new();
+        let mut skipped = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let Some(id) = name.strip_suffix(".sst").and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            manifest.next_sstable_id = manifest.next_sstable_id.max(id + 1);
+            match rebuild_table(&path, id) {
+                Ok(table) => tables.push(table),
+                Err(e) => skipped.push((path, e)),
+            }
+        }
+        tables.sort_by_key(|(sst, max_seq): &(SSTableMetadata, u64)| (*max_seq, sst.id));
+
+        for (sst, max_seq) in tables {
+            manifest.last_sequence = manifest.last_sequence.max(max_seq);
+            manifest.add_sstable(0, sst);
+        }
+        // the tables are the starting point, not changes to log
+        manifest.clear_pending();
+        Ok((manifest, skipped))
+    }
+
     pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
         let path = path.as_ref();
         let temp_path = path.with_extension("tmp");
@@ -136,9 +340,17 @@ impl Manifest {
         Ok(())
     }
 
+    /// L0 keeps flush order; deeper levels stay sorted by key
     pub fn add_sstable(&mut self, level: usize, metadata: SSTableMetadata) {
         if level < self.levels.len() {
-            self.levels[level].sstables.push(metadata);
+            self.pending.added.push(metadata.clone());
+            let sstables = &mut self.levels[level].sstables;
+            let at = if level == 0 {
+                sstables.len()
+            } else {
+                sstables.partition_point(|sst| sst.min_key < metadata.min_key)
+            };
+            sstables.insert(at, metadata);
             self.version += 1;
         }
     }
@@ -149,11 +361,39 @@ impl Manifest {
                 self.levels[sst.level]
                     .sstables
                     .retain(|s| s.id != sst.id);
+                self.pending.deleted.push((sst.level, sst.id));
             }
         }
         self.version += 1;
     }
 
+    /// swap compaction inputs for outputs as a single version bump
+    ///
+    /// levels above L0 are kept sorted by key so their files stay in scan order
+    pub fn apply_edit(&mut self, removed: &[SSTableMetadata], added: Vec<SSTableMetadata>) {
+        for sst in removed {
+            if sst.level < self.levels.len() {
+                self.levels[sst.level].sstables.retain(|s| s.id != sst.id);
+                self.pending.deleted.push((sst.level, sst.id));
+            }
+        }
+        for sst in added {
+            if sst.level < self.levels.len() {
+                self.pending.added.push(sst.clone());
+                self.levels[sst.level].sstables.push(sst);
+            }
+        }
+        for level in self.levels.iter_mut().skip(1) {
+            level.sstables.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+        }
+        self.version += 1;
+    }
+
+    /// total bytes of all tables in a level
+    pub fn level_size(&self, level: usize) -> u64 {
+        self.get_level(level).iter().map(|sst| sst.size).sum()
+    }
+
     pub fn get_level(&self, level: usize) -> &[SSTableMetadata] {
         if level < self.levels.len() {
             &self.levels[level].sstables
@@ -182,6 +422,56 @@ impl Manifest {
             .collect()
     }
 
+    /// tables that may hold `key`, in the order a point read must probe them
+    /// - L0 newest first, since its files overlap and newer ones shadow older
+    /// - then at most one file per deeper level, found by binary search, as
+    ///   files there are sorted and disjoint
+    /// - files whose key range misses `key` are never returned, so a read
+    ///   skips them without touching their filters
+    pub fn files_for_key<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a SSTableMetadata> {
+        let covers = move |sst: &&SSTableMetadata| {
+            sst.min_key.as_slice() <= key && key <= sst.max_key.as_slice()
+        };
+
+        let l0 = self.get_level(0).iter().rev().filter(covers);
+        let deeper = (1..self.levels.len()).filter_map(move |level| {
+            let files = self.get_level(level);
+            let i = files.partition_point(|sst| sst.max_key.as_slice() < key);
+            files.get(i).filter(covers)
+        });
+        l0.chain(deeper)
+    }
+
+    /// whether a table holding data older than `sst` overlaps `min_key..=max_key`
+    ///
+    /// older means an earlier L0 file, or any file in a deeper level
+    pub fn overlaps_older(&self, sst: &SSTableMetadata, min_key: &[u8], max_key: &[u8]) -> bool {
+        let overlaps = |other: &SSTableMetadata| {
+            other.min_key.as_slice() <= max_key && other.max_key.as_slice() >= min_key
+        };
+
+        if sst.level == 0
+            && self.get_level(0).iter().take_while(|other| other.id != sst.id).any(overlaps)
+        {
+            return true;
+        }
+        (sst.level + 1..self.levels.len()).any(|level| self.get_level(level).iter().any(overlaps))
+    }
+
+    /// files written at least `max_age_secs` before `now_secs`, oldest first
+    pub fn files_older_than(&self, now_secs: u64, max_age_secs: u64) -> Vec<SSTableMetadata> {
+        let mut files: Vec<SSTableMetadata> = self
+            .levels
+            .iter()
+            .flat_map(|level| level.sstables.iter())
+            .filter(|sst| now_secs.saturating_sub(sst.created_at) >= max_age_secs)
+            .cloned()
+            .collect();
+
+        files.sort_by_key(|sst| (sst.created_at, sst.id));
+        files
+    }
+
     pub fn next_sstable_id(&mut self) -> u64 {
         let id = self.next_sstable_id;
         self.next_sstable_id += 1;
@@ -193,11 +483,395 @@ impl Manifest {
         self.wal_seq += 1;
         seq
     }
+
+    /// the levels of column family `id`, the default family's being this
+    /// manifest's own
+    pub fn family(&self, id: u32) -> Option<&Manifest> {
+        if id == DEFAULT_FAMILY {
+            return Some(self);
+        }
+        let family = self.families.iter().find(|family| family.id == id)?;
+        Some(&family.tables)
+    }
+
+    pub fn family_mut(&mut self, id: u32) -> Option<&mut Manifest> {
+        if id == DEFAULT_FAMILY {
+            return Some(self);
+        }
+        let family = self.families.iter_mut().find(|family| family.id == id)?;
+        Some(&mut family.tables)
+    }
+
+    /// add an empty column family `name` with `num_levels` levels; returns
+    /// its id
+    pub fn create_family(&mut self, name: &str, num_levels: usize) -> u32 {
+        let id = self.next_family_id;
+        self.next_family_id += 1;
+        self.families.push(FamilyManifest {
+            id,
+            name: name.to_string(),
+            tables: Manifest::new(num_levels),
+        });
+        self.pending.family_edit(id).created = Some((name.to_string(), num_levels));
+        self.version += 1;
+        id
+    }
+
+    /// remove column family `id`, returning it with the tables it held
+    pub fn drop_family(&mut self, id: u32) -> Option<FamilyManifest> {
+        let i = self.families.iter().position(|family| family.id == id)?;
+        let family = self.families.remove(i);
+        self.pending.family_edit(id).dropped = true;
+        self.version += 1;
+        Some(family)
+    }
+
+    /// ids of every column family, the default one first
+    pub fn family_ids(&self) -> Vec<u32> {
+        let families = self.families.iter().map(|family| family.id);
+        std::iter::once(DEFAULT_FAMILY).chain(families).collect()
+    }
+
+    /// tables of every column family
+    pub fn all_tables(&self) -> impl Iterator<Item = &SSTableMetadata> {
+        let families = self.families.iter().map(|family| &family.tables);
+        std::iter::once(self)
+            .chain(families)
+            .flat_map(|manifest| &manifest.levels)
+            .flat_map(|level| &level.sstables)
+    }
+
+    /// file changes since the last commit, with the current counters
+    pub fn pending_edit(&self) -> VersionEdit {
+        let mut edit = VersionEdit {
+            deleted: self.pending.deleted.clone(),
+            added: self.pending.added.clone(),
+            families: self.pending.families.clone(),
+            ..self.counters()
+        };
+        for family in &self.families {
+            let pending = &family.tables.pending;
+            if !pending.deleted.is_empty() || !pending.added.is_empty() {
+                let changes = edit.family_edit(family.id);
+                changes.deleted = pending.deleted.clone();
+                changes.added = pending.added.clone();
+            }
+        }
+        edit
+    }
+
+    /// forget changes the log now holds
+    pub fn clear_pending(&mut self) {
+        self.pending = VersionEdit::default();
+        for family in &mut self.families {
+            family.tables.clear_pending();
+        }
+    }
+
+    /// the whole manifest as one edit, to start a log with
+    pub fn snapshot_edit(&self) -> VersionEdit {
+        let tables = |manifest: &Manifest| {
+            let levels = manifest.levels.iter();
+            levels.flat_map(|level| level.sstables.clone()).collect()
+        };
+        let families = self.families.iter().map(|family| FamilyEdit {
+            id: family.id,
+            created: Some((family.name.clone(), family.tables.levels.len())),
+            added: tables(&family.tables),
+            ..FamilyEdit::default()
+        });
+        VersionEdit {
+            num_levels: Some(self.levels.len()),
+            added: tables(self),
+            families: families.collect(),
+            ..self.counters()
+        }
+    }
+
+    /// replay one logged edit; nothing becomes pending
+    pub fn apply(&mut self, edit: &VersionEdit) -> Result<()> {
+        self.apply_files(&edit.deleted, &edit.added)?;
+        for changes in &edit.families {
+            let id = changes.id;
+            if let Some((name, levels)) = &changes.created {
+                if id == DEFAULT_FAMILY || *levels == 0 || self.family(id).is_some() {
+                    return Err(ManifestError::Corrupted(format!(
+                        "Version edit creates family {} with {} levels",
+                        id, levels
+                    )));
+                }
+                self.families.push(FamilyManifest {
+                    id,
+                    name: name.clone(),
+                    tables: Manifest::new(*levels),
+                });
+            }
+            let tables = self
+                .family_mut(id)
+                .filter(|_| id != DEFAULT_FAMILY)
+                .ok_or_else(|| {
+                    ManifestError::Corrupted(format!("Version edit names unknown family {}", id))
+                })?;
+            tables.apply_files(&changes.deleted, &changes.added)?;
+            if changes.dropped {
+                self.families.retain(|family| family.id != id);
+            }
+        }
+
+        self.version = edit.version.unwrap_or(self.version + 1);
+        self.next_sstable_id = edit.next_sstable_id.unwrap_or(self.next_sstable_id);
+        self.wal_seq = edit.wal_seq.unwrap_or(self.wal_seq);
+        self.last_sequence = edit.last_sequence.unwrap_or(self.last_sequence);
+        self.next_family_id = edit.next_family_id.unwrap_or(self.next_family_id);
+        Ok(())
+    }
+
+    fn apply_files(&mut self, deleted: &[(usize, u64)], added: &[SSTableMetadata]) -> Result<()> {
+        let levels = self.levels.len();
+        let named = deleted.iter().map(|&(level, _)| level);
+        let added_to = added.iter().map(|sst| sst.level);
+        if let Some(level) = named.chain(added_to).find(|&level| level >= levels) {
+            return Err(ManifestError::Corrupted(format!(
+                "Version edit names level {} of {}",
+                level, levels
+            )));
+        }
+
+        for &(level, id) in deleted {
+            self.levels[level].sstables.retain(|s| s.id != id);
+        }
+        for sst in added {
+            self.levels[sst.level].sstables.push(sst.clone());
+        }
+        for level in self.levels.iter_mut().skip(1) {
+            level.sstables.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+        }
+        Ok(())
+    }
+
+    fn counters(&self) -> VersionEdit {
+        VersionEdit {
+            version: Some(self.version),
+            next_sstable_id: Some(self.next_sstable_id),
+            wal_seq: Some(self.wal_seq),
+            last_sequence: Some(self.last_sequence),
+            next_family_id: Some(self.next_family_id),
+            ..VersionEdit::default()
+        }
+    }
+}
+
+fn first_family_id() -> u32 {
+    DEFAULT_FAMILY + 1
+}
+
+/// describe table file `path` by reading it back; returns its metadata and
+/// newest sequence number
+fn rebuild_table(path: &Path, id: u64) -> std::result::Result<(SSTableMetadata, u64), String> {
+    let reader = SSTableReader::open(path).map_err(|e| e.to_string())?;
+    let mut min_key = None;
+    let mut max_key = Vec::new();
+    let mut max_seq = 0;
+    let mut num_entries = 0;
+    let mut tombstones = 0;
+    for entry in reader.iter() {
+        let (key, seq, value) = entry.map_err(|e| e.to_string())?;
+        min_key.get_or_insert_with(|| key.clone());
+        max_key = key;
+        max_seq = max_seq.max(seq);
+        num_entries += 1;
+        if value.is_delete() {
+            tombstones += 1;
+        }
+    }
+    // the key range covers what its range tombstones delete
+    let ranges = reader.range_tombstones();
+    if let Some((start, end)) = ranges.bounds() {
+        min_key = Some(min_key.map_or(start.to_vec(), |min| min.min(start.to_vec())));
+        max_key = max_key.max(end.to_vec());
+    }
+    max_seq = ranges.iter().map(|t| t.seq).fold(max_seq, u64::max);
+    let Some(min_key) = min_key else {
+        return Err("table holds no entries".to_string());
+    };
+
+    let created_at = fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |age| age.as_secs());
+    let sst = SSTableMetadata {
+        id,
+        level: 0,
+        path: PathBuf::from(path.file_name().unwrap_or_default()),
+        size: reader.file_size(),
+        num_entries,
+        min_key,
+        max_key,
+        created_at,
+        tombstone_only: tombstones == num_entries,
+        prefix_filter: None,
+    };
+    Ok((sst, max_seq))
+}
+
+/// what changed on disk between two manifests, e.g. across a compaction
+/// - files are matched by id; a file moved down a level shows up as
+///   removed from one level and added to the next
+/// - only levels with added or removed files are listed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ManifestDiff {
+    pub old_version: u64,
+
+    pub new_version: u64,
+
+    pub old_last_sequence: u64,
+
+    pub new_last_sequence: u64,
+
+    pub levels: Vec<LevelDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LevelDiff {
+    pub level: usize,
+
+    pub added: Vec<FileSummary>,
+
+    pub removed: Vec<FileSummary>,
+
+    pub old_bytes: u64,
+
+    pub new_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileSummary {
+    pub id: u64,
+
+    pub size: u64,
+
+    pub num_entries: u64,
+
+    pub min_key: Vec<u8>,
+
+    pub max_key: Vec<u8>,
+}
+
+impl Manifest {
+    /// report how `newer` differs from this manifest
+    pub fn diff(&self, newer: &Manifest) -> ManifestDiff {
+        let num_levels = self.levels.len().max(newer.levels.len());
+        let levels = (0..num_levels)
+            .filter_map(|level| {
+                let (old, new) = (self.get_level(level), newer.get_level(level));
+                let missing_from = |files: &[SSTableMetadata], other: &[SSTableMetadata]| {
+                    files
+                        .iter()
+                        .filter(|sst| !other.iter().any(|o| o.id == sst.id))
+                        .map(FileSummary::from)
+                        .collect::<Vec<_>>()
+                };
+                let added = missing_from(new, old);
+                let removed = missing_from(old, new);
+                if added.is_empty() && removed.is_empty() {
+                    return None;
+                }
+
+                Some(LevelDiff {
+                    level,
+                    added,
+                    removed,
+                    old_bytes: self.level_size(level),
+                    new_bytes: newer.level_size(level),
+                })
+            })
+            .collect();
+
+        ManifestDiff {
+            old_version: self.version,
+            new_version: newer.version,
+            old_last_sequence: self.last_sequence,
+            new_last_sequence: newer.last_sequence,
+            levels,
+        }
+    }
+}
+
+impl ManifestDiff {
+    /// no file was added or removed
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+impl LevelDiff {
+    pub fn bytes_delta(&self) -> i64 {
+        self.new_bytes as i64 - self.old_bytes as i64
+    }
+}
+
+impl From<&SSTableMetadata> for FileSummary {
+    fn from(sst: &SSTableMetadata) -> Self {
+        Self {
+            id: sst.id,
+            size: sst.size,
+            num_entries: sst.num_entries,
+            min_key: sst.min_key.clone(),
+            max_key: sst.max_key.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ManifestDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "version {} -> {}", self.old_version, self.new_version)?;
+        writeln!(
+            f,
+            "last sequence {} -> {} ({:+})",
+            self.old_last_sequence,
+            self.new_last_sequence,
+            self.new_last_sequence as i64 - self.old_last_sequence as i64
+        )?;
+        if self.is_empty() {
+            return writeln!(f, "no files changed");
+        }
+
+        for level in &self.levels {
+            writeln!(
+                f,
+                "L{}: {} -> {} bytes ({:+})",
+                level.level,
+                level.old_bytes,
+                level.new_bytes,
+                level.bytes_delta()
+            )?;
+            for (sign, files) in [('+', &level.added), ('-', &level.removed)] {
+                for file in files {
+                    writeln!(
+                        f,
+                        "  {} {} {} bytes {} entries [{} .. {}]",
+                        sign,
+                        file.id,
+                        file.size,
+                        file.num_entries,
+                        String::from_utf8_lossy(&file.min_key),
+                        String::from_utf8_lossy(&file.max_key)
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Sync directory metadata to disk (Unix/Linux)
 #[cfg(unix)]
-fn sync_dir(path: &Path) -> Result<()> {
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
     let dir = File::open(path)?;
     dir.sync_all()?;
     Ok(())
@@ -205,7 +879,8 @@ fn sync_dir(path: &Path) -> Result<()> {
 
 /// Sync directory metadata to disk (Windows)
 #[cfg(windows)]
-fn sync_dir(path: &Path) -> Result<()> {
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    use std::fs::OpenOptions;
     use std::os::windows::fs::OpenOptionsExt;
 
     // FILE_FLAG_BACKUP_SEMANTICS (0x02000000) allows opening directories on Windows
@@ -221,7 +896,7 @@ fn sync_dir(path: &Path) -> Result<()> {
 }
 
 #[cfg(not(any(unix, windows)))]
-fn sync_dir(_path: &Path) -> Result<()> {
+pub(crate) fn sync_dir(_path: &Path) -> Result<()> {
     // No-op on unsupported platforms
     Ok(())
 }
@@ -256,6 +931,9 @@ mod tests {
                 num_entries: 10,
                 min_key: b"a".to_vec(),
                 max_key: b"z".to_vec(),
+                created_at: 0,
+                tombstone_only: false,
+                prefix_filter: None,
             },
         );
 
@@ -269,6 +947,85 @@ mod tests {
         fs::remove_file(manifest_path).ok();
     }
 
+    #[test]
+    fn test_files_for_key() {
+        let sst = |id: u64, level: usize, min: &[u8], max: &[u8]| SSTableMetadata {
+            id,
+            level,
+            path: PathBuf::from(format!("{}.sst", id)),
+            size: 1024,
+            num_entries: 10,
+            min_key: min.to_vec(),
+            max_key: max.to_vec(),
+            created_at: 0,
+            tombstone_only: false,
+            prefix_filter: None,
+        };
+
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(0, sst(1, 0, b"a", b"z"));
+        manifest.add_sstable(0, sst(2, 0, b"x", b"y"));
+        manifest.add_sstable(0, sst(3, 0, b"c", b"m"));
+        // added out of key order; the level must still come out sorted
+        manifest.add_sstable(1, sst(5, 1, b"h", b"p"));
+        manifest.add_sstable(1, sst(4, 1, b"a", b"f"));
+        manifest.add_sstable(2, sst(6, 2, b"a", b"z"));
+
+        let ids = |key: &[u8]| -> Vec<u64> { manifest.files_for_key(key).map(|s| s.id).collect() };
+        assert_eq!(ids(b"d"), vec![3, 1, 4, 6]);
+        assert_eq!(ids(b"g"), vec![3, 1, 6]);
+        assert_eq!(ids(b"x"), vec![2, 1, 6]);
+        assert_eq!(ids(b"0"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_prefix_filter() {
+        let keys: Vec<Vec<u8>> = (0..50)
+            .flat_map(|tenant| (0..20).map(move |i| format!("t{:02}/{:04}", tenant, i)))
+            .map(String::into_bytes)
+            .collect();
+        let filter = PrefixFilter::build(keys.iter().map(Vec::as_slice), 4, 10).unwrap();
+
+        for key in &keys {
+            assert!(filter.may_contain(key));
+        }
+        assert!(filter.may_contain(b"t07/9999"));
+        let absent = (50..1050).filter(|t| filter.may_contain(format!("t{}/0", t).as_bytes()));
+        assert!(absent.count() < 50);
+        // short keys are filtered whole
+        let short = PrefixFilter::build([&b"ab"[..], b"abcdef"], 4, 10).unwrap();
+        assert!(short.may_contain(b"ab") && short.may_contain(b"abcd"));
+
+        // too many distinct prefixes for the manifest
+        let unique: Vec<Vec<u8>> = (0..10_000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert!(PrefixFilter::build(unique.iter().map(Vec::as_slice), 4, 10).is_none());
+
+        // survives a manifest round trip; files without one don't write the field
+        let mut manifest = Manifest::new(2);
+        let sst = |id: u64, prefix_filter| SSTableMetadata {
+            id,
+            level: 0,
+            path: PathBuf::from(format!("{:06}.sst", id)),
+            size: 1,
+            num_entries: 1,
+            min_key: b"t00/0000".to_vec(),
+            max_key: b"t49/0019".to_vec(),
+            created_at: 0,
+            tombstone_only: false,
+            prefix_filter,
+        };
+        manifest.add_sstable(0, sst(1, Some(filter.clone())));
+        manifest.add_sstable(0, sst(2, None));
+        let json = serde_json::to_vec(&manifest).unwrap();
+        assert_eq!(json.windows(13).filter(|w| w == b"prefix_filter").count(), 1);
+
+        let decoded = Manifest::decode(&json).unwrap();
+        let files = decoded.get_level(0);
+        assert_eq!(files[0].prefix_filter.as_ref(), Some(&filter));
+        assert!(files[0].may_contain_prefix(b"t07/0003"));
+        assert!(files[1].may_contain_prefix(b"zz99/0000"));
+    }
+
     #[test]
     fn test_find_overlapping() {
         let mut manifest = Manifest::new(3);
@@ -283,6 +1040,9 @@ mod tests {
                 num_entries: 10,
                 min_key: b"a".to_vec(),
                 max_key: b"c".to_vec(),
+                created_at: 0,
+                tombstone_only: false,
+                prefix_filter: None,
             },
         );
 
@@ -296,6 +1056,9 @@ mod tests {
                 num_entries: 10,
                 min_key: b"e".to_vec(),
                 max_key: b"g".to_vec(),
+                created_at: 0,
+                tombstone_only: false,
+                prefix_filter: None,
             },
         );
 
@@ -322,6 +1085,9 @@ mod tests {
             num_entries: 10,
             min_key: b"a".to_vec(),
             max_key: b"c".to_vec(),
+            created_at: 0,
+            tombstone_only: false,
+            prefix_filter: None,
         };
 
         let sst2 = SSTableMetadata {
@@ -332,6 +1098,9 @@ mod tests {
             num_entries: 10,
             min_key: b"d".to_vec(),
             max_key: b"f".to_vec(),
+            created_at: 0,
+            tombstone_only: false,
+            prefix_filter: None,
         };
 
         manifest.add_sstable(0, sst1.clone());
@@ -342,4 +1111,174 @@ mod tests {
         assert_eq!(manifest.levels[0].sstables.len(), 1);
         assert_eq!(manifest.levels[0].sstables[0].id, 2);
     }
+
+    #[test]
+    fn test_apply_edit() {
+        let mut manifest = Manifest::new(3);
+        let sst = |id: u64, level: usize, min: &[u8]| SSTableMetadata {
+            id,
+            level,
+            path: PathBuf::from(format!("sst{}.sst", id)),
+            size: 100,
+            num_entries: 10,
+            min_key: min.to_vec(),
+            max_key: min.to_vec(),
+            created_at: 0,
+            tombstone_only: false,
+            prefix_filter: None,
+        };
+
+        manifest.add_sstable(0, sst(1, 0, b"m"));
+        manifest.add_sstable(1, sst(2, 1, b"k"));
+        let version = manifest.version;
+
+        manifest.apply_edit(&[sst(1, 0, b"m"), sst(2, 1, b"k")], vec![sst(4, 1, b"x"), sst(3, 1, b"a")]);
+        assert_eq!(manifest.version, version + 1);
+        assert!(manifest.get_level(0).is_empty());
+        let ids: Vec<u64> = manifest.get_level(1).iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![3, 4]);
+        assert_eq!(manifest.level_size(1), 200);
+    }
+
+    #[test]
+    fn test_diff() {
+        let sst = |id: u64, level: usize, size: u64| SSTableMetadata {
+            id,
+            level,
+            path: PathBuf::from(format!("{}.sst", id)),
+            size,
+            num_entries: 10,
+            min_key: b"a".to_vec(),
+            max_key: b"z".to_vec(),
+            created_at: 0,
+            tombstone_only: false,
+            prefix_filter: None,
+        };
+
+        let mut old = Manifest::new(3);
+        old.add_sstable(0, sst(1, 0, 100));
+        old.add_sstable(0, sst(2, 0, 100));
+        old.add_sstable(1, sst(3, 1, 500));
+        old.last_sequence = 40;
+        assert!(old.diff(&old).is_empty());
+
+        // L0 and the overlapping L1 file compacted into one new L1 file
+        let mut new = old.clone();
+        new.apply_edit(&[sst(1, 0, 100), sst(2, 0, 100), sst(3, 1, 500)], vec![sst(4, 1, 650)]);
+        new.last_sequence = 55;
+
+        let diff = old.diff(&new);
+        assert_eq!((diff.old_version, diff.new_version), (old.version, new.version));
+        assert_eq!((diff.old_last_sequence, diff.new_last_sequence), (40, 55));
+        assert_eq!(diff.levels.len(), 2);
+
+        let l0 = &diff.levels[0];
+        assert!(l0.added.is_empty());
+        assert_eq!(l0.removed.iter().map(|f| f.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(l0.bytes_delta(), -200);
+
+        let l1 = &diff.levels[1];
+        assert_eq!((l1.added[0].id, l1.removed[0].id), (4, 3));
+        assert_eq!(l1.bytes_delta(), 150);
+
+        let report = diff.to_string();
+        assert!(report.contains("L0: 200 -> 0 bytes (-200)"));
+        assert!(report.contains("  + 4 650 bytes"));
+    }
+
+    #[test]
+    fn test_files_older_than() {
+        let mut manifest = Manifest::new(3);
+
+        for (id, level, created_at) in [(1, 0, 500), (2, 1, 100), (3, 2, 900)] {
+            manifest.add_sstable(
+                level,
+                SSTableMetadata {
+                    id,
+                    level,
+                    path: PathBuf::from(format!("sst{}.sst", id)),
+                    size: 1024,
+                    num_entries: 10,
+                    min_key: b"a".to_vec(),
+                    max_key: b"z".to_vec(),
+                    created_at,
+                    tombstone_only: false,
+                    prefix_filter: None,
+                },
+            );
+        }
+
+        let due: Vec<u64> = manifest.files_older_than(1000, 500).iter().map(|s| s.id).collect();
+        assert_eq!(due, vec![2, 1]);
+
+        assert!(manifest.files_older_than(1000, 1000).is_empty());
+    }
+
+    #[test]
+    fn test_load_manifest_without_created_at() {
+        let temp_dir = env::temp_dir();
+        let manifest_path = temp_dir.join("test_manifest_old_format.json");
+
+        let json = r#"{"version":2,"next_sstable_id":2,"wal_seq":1,"levels":[{"level":0,
+            "sstables":[{"id":1,"level":0,"path":"a.sst","size":1,"num_entries":1,
+            "min_key":[97],"max_key":[97]}]}]}"#;
+        fs::write(&manifest_path, json).unwrap();
+
+        let loaded = Manifest::load(&manifest_path).unwrap();
+        assert_eq!(loaded.levels[0].sstables[0].created_at, 0);
+
+        fs::remove_file(manifest_path).ok();
+    }
+
+    #[test]
+    fn test_decode_rejects_misnumbered_levels() {
+        assert!(Manifest::decode(b"").is_err());
+        assert!(Manifest::decode(br#"{"version":1,"next_sstable_id":1,"wal_seq":0,"levels":[]}"#).is_err());
+
+        let json = r#"{"version":1,"next_sstable_id":1,"wal_seq":0,
+            "levels":[{"level":3,"sstables":[]}]}"#;
+        assert!(matches!(
+            Manifest::decode(json.as_bytes()),
+            Err(ManifestError::Corrupted(_))
+        ));
+
+        // a JSON manifest cut short names the problem
+        let Err(ManifestError::Corrupted(reason)) = Manifest::decode(&json.as_bytes()[..40]) else {
+            panic!("truncated JSON decoded");
+        };
+        assert!(reason.contains("truncated"), "{}", reason);
+    }
+
+    #[test]
+    fn test_rebuild_from_tables() {
+        use crate::lsm::sstable::SSTableWriter;
+
+        let dir = env::temp_dir().join("test_manifest_rebuild");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let write = |id: u64, seqs: std::ops::Range<u64>| {
+            let name = PathBuf::from(format!("{:06}.sst", id));
+            let mut writer = SSTableWriter::create(&dir, &name, id, 1, 16, 10).unwrap();
+            for seq in seqs {
+                writer.add(format!("key{:03}", seq).as_bytes(), seq, Some(b"v")).unwrap();
+            }
+            writer.finish().unwrap();
+        };
+        // the older table has the higher id
+        write(7, 50..60);
+        write(9, 10..20);
+        fs::write(dir.join("000011.sst"), b"not a table").unwrap();
+        fs::write(dir.join("notes.txt"), b"ignored").unwrap();
+
+        let (manifest, skipped) = Manifest::rebuild(&dir, 3).unwrap();
+        let ids: Vec<u64> = manifest.get_level(0).iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![9, 7]);
+        assert_eq!((manifest.next_sstable_id, manifest.last_sequence), (12, 59));
+        let newest = &manifest.get_level(0)[1];
+        assert_eq!((newest.min_key.as_slice(), newest.num_entries), (&b"key050"[..], 10));
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, dir.join("000011.sst"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
 }
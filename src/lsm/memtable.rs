@@ -1,89 +1,187 @@
 use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+
+use super::arena::{Arena, ArenaSlice};
+use super::merge::StoredValue;
+use super::range_del::{RangeTombstone, RangeTombstones};
 
 /// in-memory sorted key-value store backed by BTreeMap
+/// - every write gets the next sequence number
+/// - older versions of a key are kept only while a snapshot can still see them,
+///   or while a newer version is a merge operand that folds onto them
+/// - keys and values live in an arena freed with the memtable, so a write
+///   allocates nothing but its BTreeMap slot
+/// - range deletes are kept apart as tombstones; reads apply them
+/// - a single delete of a key whose only version is a put nobody else can
+///   see drops the key outright
 #[derive(Debug)]
 pub struct Memtable {
-    data: BTreeMap<Vec<u8>, MemtableEntry>,
+    /// versions per key, newest first; keys and values point into `arena`
+    data: BTreeMap<ArenaSlice, Vec<MemtableEntry>>,
+
+    arena: Arena,
 
     size: usize,
 
     max_size: usize,
 
     seq_num: u64,
+
+    /// sequence number of the oldest live snapshot, if any
+    oldest_snapshot: Option<u64>,
+
+    range_tombstones: RangeTombstones,
 }
 
+// the only ArenaSlices are the keys and values in `data`, which point into
+// `arena` and go with it; the arena is written only through &mut self
+unsafe impl Send for Memtable {}
+unsafe impl Sync for Memtable {}
+
 /// entry in the memtable
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct MemtableEntry {
     /// value, none indicates deletion/tombstone
-    pub value: Option<Vec<u8>>,
+    pub(crate) value: Option<ArenaSlice>,
 
     pub seq_num: u64,
+
+    /// `value` is a merge operand, to fold onto the older versions
+    pub merge: bool,
+
+    /// a tombstone from single_delete
+    pub single_delete: bool,
+}
+
+impl MemtableEntry {
+    /// the value, None for a tombstone
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    /// the value copied out of the memtable, None for a tombstone
+    pub fn to_value(&self) -> Option<Vec<u8>> {
+        self.value.as_deref().map(<[u8]>::to_vec)
+    }
+
+    /// the version copied out of the memtable
+    pub fn to_stored(&self) -> StoredValue {
+        match self.to_value() {
+            Some(operand) if self.merge => StoredValue::Merge(vec![operand]),
+            None if self.single_delete => StoredValue::SingleDelete,
+            value => StoredValue::from(value),
+        }
+    }
 }
 
 impl Memtable {
     pub fn new(max_size: usize) -> Self {
+        Self::with_start_seq(max_size, 0)
+    }
+
+    /// memtable whose first write gets sequence number `last_seq + 1`
+    pub fn with_start_seq(max_size: usize, last_seq: u64) -> Self {
         Self {
             data: BTreeMap::new(),
+            arena: Arena::new(),
             size: 0,
             max_size,
-            seq_num: 0,
+            seq_num: last_seq,
+            oldest_snapshot: None,
+            range_tombstones: RangeTombstones::new(),
         }
     }
 
     pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.insert_version(key, Some(value), false, false);
+        Ok(())
+    }
+
+    /// add a merge operand as the key's newest version
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), String> {
+        self.insert_version(key, Some(operand), true, false);
+        Ok(())
+    }
+
+    /// insert a key that sorts after every key already in the memtable
+    ///
+    /// skips the existing-entry lookup put() needs for size accounting
+    pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        if let Some((last, _)) = self.data.last_key_value()
+            && **last >= *key
+        {
+            return Err("append key is not greater than the last key".to_string());
+        }
+
         self.seq_num += 1;
 
         let entry = MemtableEntry {
-            value: Some(value.to_vec()),
+            value: Some(store(&mut self.arena, value)),
             seq_num: self.seq_num,
+            merge: false,
+            single_delete: false,
         };
 
-        let key_vec = key.to_vec();
-        let size_delta = if let Some(old_entry) = self.data.get(&key_vec) {
-            let old_value_size = old_entry.value.as_ref().map(|v| v.len()).unwrap_or(0);
-            let new_value_size = value.len();
-
-            if new_value_size > old_value_size {
-                new_value_size - old_value_size
-            } else {
-                0 // don't decrease size on overwrites
-            }
-        } else {
-            key.len() + value.len() + 24 // 24 bytes overhead (seq_num, Option, Vec headers)
-        };
-
-        self.data.insert(key_vec, entry);
-        self.size += size_delta;
+        self.data.insert(store(&mut self.arena, key), vec![entry]);
+        self.size += key.len() + value.len() + 24;
 
         Ok(())
     }
 
+    /// newest version of a key
     pub fn get(&self, key: &[u8]) -> Option<&MemtableEntry> {
-        self.data.get(key)
+        self.data.get(key).and_then(|versions| versions.first())
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> Result<(), String> {
-        self.seq_num += 1;
+    /// newest version of a key with seq_num <= `seq`
+    pub fn get_at(&self, key: &[u8], seq: u64) -> Option<&MemtableEntry> {
+        self.data
+            .get(key)
+            .and_then(|versions| versions.iter().find(|v| v.seq_num <= seq))
+    }
 
-        let entry = MemtableEntry {
-            value: None,
-            seq_num: self.seq_num,
-        };
+    /// versions of a key a read at `seq` folds, newest first: the newest
+    /// visible one, then older ones while the newer are merge operands
+    pub fn versions_at(&self, key: &[u8], seq: u64) -> impl Iterator<Item = &MemtableEntry> {
+        self.data
+            .get(key)
+            .into_iter()
+            .flat_map(move |versions| merge_chain(versions, seq))
+    }
 
-        let key_vec = key.to_vec();
-        let size_delta = if self.data.contains_key(&key_vec) {
-            0 // already exists, just updating
-        } else {
-            key.len() + 24 // new tombstone
-        };
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), String> {
+        self.insert_version(key, None, false, false);
+        Ok(())
+    }
 
-        self.data.insert(key_vec, entry);
-        self.size += size_delta;
+    /// delete a key put at most once since its last delete; if that put is
+    /// here and no snapshot sees it, both go and leave no tombstone behind
+    pub fn single_delete(&mut self, key: &[u8]) -> Result<(), String> {
+        self.insert_version(key, None, false, true);
+        Ok(())
+    }
 
+    /// delete every key in [start, end) written before this call
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> Result<(), String> {
+        self.seq_num += 1;
+        self.range_tombstones.add(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq: self.seq_num,
+        });
+        self.size += start.len() + end.len() + 24;
         Ok(())
     }
 
+    pub fn range_tombstones(&self) -> &RangeTombstones {
+        &self.range_tombstones
+    }
+
+    /// let the memtable drop versions no snapshot older than the newest can see
+    pub fn set_oldest_snapshot(&mut self, seq: Option<u64>) {
+        self.oldest_snapshot = seq;
+    }
+
     pub fn is_full(&self) -> bool {
         self.size >= self.max_size
     }
@@ -92,34 +190,182 @@ impl Memtable {
         self.size
     }
 
+    /// number of distinct keys
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
+    /// no keys and no range tombstones
     pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
+        self.data.is_empty() && self.range_tombstones.is_empty()
+    }
+
+    /// newest version of every key, in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &MemtableEntry)> {
+        self.data
+            .iter()
+            .filter_map(|(key, versions)| versions.first().map(|v| (&**key, v)))
+    }
+
+    /// newest version of every key with seq_num <= `seq`, in key order
+    pub fn iter_at(&self, seq: u64) -> impl Iterator<Item = (&[u8], &MemtableEntry)> {
+        self.data.iter().filter_map(move |(key, versions)| {
+            versions.iter().find(|v| v.seq_num <= seq).map(|v| (&**key, v))
+        })
+    }
+
+    /// versions_at for every key, in key order
+    pub fn iter_versions_at(&self, seq: u64) -> impl Iterator<Item = (&[u8], &MemtableEntry)> {
+        self.data
+            .iter()
+            .flat_map(move |(key, versions)| merge_chain(versions, seq).map(move |v| (&**key, v)))
+    }
+
+    /// every retained version, in key order and newest first per key
+    pub fn iter_versions(&self) -> impl Iterator<Item = (&[u8], &MemtableEntry)> {
+        self.data
+            .iter()
+            .flat_map(|(key, versions)| versions.iter().map(move |v| (&**key, v)))
+    }
+
+    /// every key in `range` with the versions iter_versions_at yields for it,
+    /// in key order; `.rev()` walks it backwards
+    pub fn range_versions_at<K: AsRef<[u8]> + ?Sized>(
+        &self,
+        range: impl RangeBounds<K>,
+        seq: u64,
+    ) -> impl DoubleEndedIterator<Item = (&[u8], impl Iterator<Item = &MemtableEntry>)> {
+        let bounds = (
+            range.start_bound().map(AsRef::as_ref),
+            range.end_bound().map(AsRef::as_ref),
+        );
+        self.data
+            .range::<[u8], _>(bounds)
+            .map(move |(key, versions)| (&**key, merge_chain(versions, seq)))
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &MemtableEntry)> {
-        self.data.iter()
+    /// newest version of every key in `range`, in key order; `.rev()` walks
+    /// it backwards
+    ///
+    /// the bounds are only borrowed, e.g. `range::<&[u8]>(..)` or
+    /// `range(start..=end)` with slices or Vecs
+    pub fn range<K: AsRef<[u8]> + ?Sized>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> impl DoubleEndedIterator<Item = (&[u8], &MemtableEntry)> {
+        let bounds = (
+            range.start_bound().map(AsRef::as_ref),
+            range.end_bound().map(AsRef::as_ref),
+        );
+        self.data
+            .range::<[u8], _>(bounds)
+            .filter_map(|(key, versions)| versions.first().map(|v| (&**key, v)))
     }
 
-    pub fn range<'a>(
-        &'a self,
-        start: &'a [u8],
-        end: &'a [u8],
-    ) -> impl Iterator<Item = (&'a Vec<u8>, &'a MemtableEntry)> + 'a {
-        self.data.range(start.to_vec()..end.to_vec())
+    /// bytes reserved by the arena holding keys and values
+    pub fn arena_bytes(&self) -> usize {
+        self.arena.allocated()
     }
 
     pub fn seq_num(&self) -> u64 {
         self.seq_num
     }
+
+    /// make the next write get a sequence number above `last_seq`; never
+    /// moves the counter backwards
+    pub fn advance_seq(&mut self, last_seq: u64) {
+        self.seq_num = self.seq_num.max(last_seq);
+    }
+
+    fn insert_version(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        merge: bool,
+        single_delete: bool,
+    ) {
+        self.seq_num += 1;
+
+        let new_value_size = value.map(|v| v.len()).unwrap_or(0);
+        let entry = MemtableEntry {
+            value: value.map(|v| store(&mut self.arena, v)),
+            seq_num: self.seq_num,
+            merge,
+            single_delete,
+        };
+
+        let Some(versions) = self.data.get_mut(key) else {
+            self.data.insert(store(&mut self.arena, key), vec![entry]);
+            // 24 bytes overhead (seq_num, Option, Vec headers)
+            self.size += key.len() + new_value_size + 24;
+            return;
+        };
+
+        let old_value_size = versions[0].value.as_ref().map(|v| v.len()).unwrap_or(0);
+        versions.insert(0, entry);
+
+        // the newest version visible to the oldest snapshot is the last one
+        // anybody needs, along with what its merge operands fold onto
+        let mut keep = match self.oldest_snapshot {
+            Some(snapshot) => versions
+                .iter()
+                .position(|v| v.seq_num <= snapshot)
+                .map_or(versions.len(), |pos| pos + 1),
+            None => 1,
+        };
+        while keep < versions.len() && versions[keep - 1].merge {
+            keep += 1;
+        }
+        // a tombstone right below a put stays, for a single delete of the
+        // put to leave in its place
+        let is_put = |v: &MemtableEntry| v.value.is_some() && !v.merge;
+        if keep < versions.len() && is_put(&versions[keep - 1]) && versions[keep].value.is_none() {
+            keep += 1;
+        }
+
+        // the put this single delete cancels; with no tombstone below it,
+        // whatever older versions the tables hold are deletes, which
+        // single_delete requires
+        if single_delete && keep == 1 && versions.len() > 1 && is_put(&versions[1]) {
+            if versions.len() == 2 {
+                self.data.remove(key);
+            } else {
+                versions.drain(..2);
+                versions.truncate(1);
+            }
+            return;
+        }
+        versions.truncate(keep);
+
+        self.size += if versions.len() > 1 {
+            new_value_size + 24 // the old version is kept alongside
+        } else {
+            new_value_size.saturating_sub(old_value_size) // don't decrease size on overwrites
+        };
+    }
+}
+
+/// copy a key or value into the memtable's arena
+fn store(arena: &mut Arena, bytes: &[u8]) -> ArenaSlice {
+    // SAFETY: the slice goes into the memtable that owns `arena`, and is
+    // only ever lent out by reference, so it can't outlive the arena
+    unsafe { arena.alloc(bytes) }
+}
+
+/// versions visible at `seq`, newest first, up to and including the first
+/// that isn't a merge operand
+fn merge_chain(versions: &[MemtableEntry], seq: u64) -> impl Iterator<Item = &MemtableEntry> {
+    let mut more = true;
+    versions
+        .iter()
+        .filter(move |v| v.seq_num <= seq)
+        .take_while(move |v| std::mem::replace(&mut more, v.merge))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ops::Bound;
 
     #[test]
     fn test_put_and_get() {
@@ -129,10 +375,10 @@ mod tests {
         memtable.put(b"key2", b"value2").unwrap();
 
         let entry1 = memtable.get(b"key1").unwrap();
-        assert_eq!(entry1.value.as_ref().unwrap(), b"value1");
+        assert_eq!(entry1.value.as_deref().unwrap(), b"value1");
 
         let entry2 = memtable.get(b"key2").unwrap();
-        assert_eq!(entry2.value.as_ref().unwrap(), b"value2");
+        assert_eq!(entry2.value.as_deref().unwrap(), b"value2");
 
         assert!(memtable.get(b"key3").is_none());
     }
@@ -149,6 +395,39 @@ mod tests {
         assert!(entry.value.is_none()); // tombstone
     }
 
+    #[test]
+    fn test_single_delete() {
+        let mut memtable = Memtable::new(1024);
+
+        // the put and its single delete cancel out
+        memtable.put(b"key1", b"value1").unwrap();
+        memtable.single_delete(b"key1").unwrap();
+        assert!(memtable.get(b"key1").is_none());
+        assert_eq!(memtable.len(), 0);
+
+        // with no put here, or one a snapshot sees, the tombstone stays
+        memtable.single_delete(b"key2").unwrap();
+        memtable.put(b"key3", b"value3").unwrap();
+        memtable.set_oldest_snapshot(Some(memtable.seq_num()));
+        memtable.single_delete(b"key3").unwrap();
+        for key in [b"key2", b"key3"] {
+            let entry = memtable.get(key).unwrap();
+            assert!(entry.value.is_none() && entry.single_delete);
+            assert_eq!(entry.to_stored(), StoredValue::SingleDelete);
+        }
+        assert_eq!(memtable.iter_versions().count(), 3);
+
+        // over a delete, the pair gives way to the delete so nothing older
+        // in the tables shows through
+        memtable.set_oldest_snapshot(None);
+        memtable.delete(b"key4").unwrap();
+        memtable.put(b"key4", b"value4").unwrap();
+        memtable.single_delete(b"key4").unwrap();
+        let entry = memtable.get(b"key4").unwrap();
+        assert!(entry.value.is_none() && !entry.single_delete);
+        assert_eq!(entry.to_stored(), StoredValue::Delete);
+    }
+
     #[test]
     fn test_overwrite() {
         let mut memtable = Memtable::new(1024);
@@ -157,7 +436,7 @@ mod tests {
         memtable.put(b"key1", b"value2").unwrap();
 
         let entry = memtable.get(b"key1").unwrap();
-        assert_eq!(entry.value.as_ref().unwrap(), b"value2");
+        assert_eq!(entry.value.as_deref().unwrap(), b"value2");
     }
 
     #[test]
@@ -200,16 +479,16 @@ mod tests {
         let mut iter = memtable.iter();
 
         let (k, v) = iter.next().unwrap();
-        assert_eq!(k.as_slice(), b"a");
-        assert_eq!(v.value.as_ref().unwrap().as_slice(), b"1");
+        assert_eq!(k, b"a");
+        assert_eq!(v.value.as_deref().unwrap(), b"1");
 
         let (k, v) = iter.next().unwrap();
-        assert_eq!(k.as_slice(), b"b");
-        assert_eq!(v.value.as_ref().unwrap().as_slice(), b"2");
+        assert_eq!(k, b"b");
+        assert_eq!(v.value.as_deref().unwrap(), b"2");
 
         let (k, v) = iter.next().unwrap();
-        assert_eq!(k.as_slice(), b"c");
-        assert_eq!(v.value.as_ref().unwrap().as_slice(), b"3");
+        assert_eq!(k, b"c");
+        assert_eq!(v.value.as_deref().unwrap(), b"3");
 
         assert!(iter.next().is_none());
     }
@@ -223,10 +502,37 @@ mod tests {
         memtable.put(b"e", b"5").unwrap();
         memtable.put(b"g", b"7").unwrap();
 
-        let results: Vec<_> = memtable.range(b"b", b"f").collect();
+        let keys = |iter: &mut dyn Iterator<Item = (&[u8], &MemtableEntry)>| -> Vec<Vec<u8>> {
+            iter.map(|(k, _)| k.to_vec()).collect()
+        };
+        let results: Vec<_> = memtable.range(b"b".as_slice()..b"f".as_slice()).collect();
         assert_eq!(results.len(), 2);
-        assert_eq!(results[0].0.as_slice(), b"c");
-        assert_eq!(results[1].0.as_slice(), b"e");
+        assert_eq!(results[0].0, b"c");
+        assert_eq!(results[1].0, b"e");
+
+        assert_eq!(keys(&mut memtable.range(b"c".to_vec()..=b"e".to_vec())), [b"c", b"e"]);
+        assert_eq!(keys(&mut memtable.range::<&[u8]>(..)).len(), 4);
+        assert_eq!(keys(&mut memtable.range(b"d".as_slice()..)), [b"e", b"g"]);
+        assert_eq!(keys(&mut memtable.range(..b"c".as_slice()).rev()), [b"a"]);
+        assert_eq!(keys(&mut memtable.range(b"b".as_slice()..).rev()), [b"g", b"e", b"c"]);
+
+        // unsized bounds work too
+        let bounds: (Bound<&[u8]>, Bound<&[u8]>) = (Bound::Excluded(b"c"), Bound::Unbounded);
+        assert_eq!(keys(&mut memtable.range::<[u8]>(bounds)), [b"e", b"g"]);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut memtable = Memtable::new(1024);
+
+        memtable.append(b"key1", b"value1").unwrap();
+        memtable.append(b"key2", b"value2").unwrap();
+        assert_eq!(memtable.get(b"key2").unwrap().value.as_deref().unwrap(), b"value2");
+        assert_eq!(memtable.size(), 2 * (4 + 6 + 24));
+
+        assert!(memtable.append(b"key2", b"again").is_err());
+        assert!(memtable.append(b"key0", b"older").is_err());
+        assert_eq!(memtable.len(), 2);
     }
 
     #[test]
@@ -241,4 +547,85 @@ mod tests {
 
         assert!(seq2 > seq1);
     }
+
+    #[test]
+    fn test_start_seq() {
+        let mut memtable = Memtable::with_start_seq(1024, 41);
+
+        memtable.put(b"key1", b"value1").unwrap();
+        assert_eq!(memtable.get(b"key1").unwrap().seq_num, 42);
+        assert_eq!(memtable.seq_num(), 42);
+    }
+
+    #[test]
+    fn test_versions_kept_for_snapshots() {
+        let mut memtable = Memtable::new(1024);
+
+        memtable.put(b"key1", b"v1").unwrap(); // seq 1
+        memtable.set_oldest_snapshot(Some(1));
+        memtable.put(b"key1", b"v2").unwrap(); // seq 2
+        memtable.delete(b"key1").unwrap(); // seq 3
+
+        assert!(memtable.get(b"key1").unwrap().value.is_none());
+        assert_eq!(memtable.get_at(b"key1", 2).unwrap().value.as_deref(), Some(&b"v2"[..]));
+        assert_eq!(memtable.get_at(b"key1", 1).unwrap().value.as_deref(), Some(&b"v1"[..]));
+        assert!(memtable.get_at(b"key1", 0).is_none());
+        assert_eq!(memtable.iter_versions().count(), 3);
+        assert_eq!(memtable.iter().count(), 1);
+
+        // once the snapshot is gone the next write prunes the chain, down to
+        // the tombstone a single delete of the put would leave
+        memtable.set_oldest_snapshot(None);
+        memtable.put(b"key1", b"v4").unwrap();
+        assert_eq!(memtable.iter_versions().count(), 2);
+        memtable.put(b"key1", b"v5").unwrap();
+        assert_eq!(memtable.iter_versions().count(), 1);
+    }
+
+    #[test]
+    fn test_merge_operands_keep_their_base() {
+        let mut memtable = Memtable::new(1024);
+
+        memtable.put(b"key1", b"base").unwrap(); // seq 1
+        memtable.merge(b"key1", b"+1").unwrap(); // seq 2
+        memtable.merge(b"key1", b"+2").unwrap(); // seq 3
+        memtable.put(b"key2", b"v").unwrap(); // seq 4
+
+        // no snapshot, yet every version stays for the operands to fold onto
+        assert_eq!(memtable.iter_versions().count(), 4);
+        let chain: Vec<_> = memtable.versions_at(b"key1", 3).map(|v| v.to_stored()).collect();
+        assert_eq!(
+            chain,
+            vec![
+                StoredValue::Merge(vec![b"+2".to_vec()]),
+                StoredValue::Merge(vec![b"+1".to_vec()]),
+                StoredValue::Put(b"base".to_vec()),
+            ]
+        );
+        assert_eq!(memtable.versions_at(b"key1", 1).count(), 1);
+        assert_eq!(memtable.iter_versions_at(4).count(), 4);
+        assert_eq!(memtable.iter_at(4).count(), 2);
+
+        // a put over them needs none of them
+        memtable.put(b"key1", b"new").unwrap();
+        assert_eq!(memtable.iter_versions().count(), 2);
+    }
+
+    #[test]
+    fn test_values_live_in_arena() {
+        let mut memtable = Memtable::new(1 << 20);
+        assert_eq!(memtable.arena_bytes(), 0);
+
+        memtable.put(b"key1", &[1; 100]).unwrap();
+        memtable.put(b"key1", &[2; 100]).unwrap();
+        let reserved = memtable.arena_bytes();
+        assert!(reserved > 0);
+
+        // small writes share the chunk already reserved
+        for i in 0..100u32 {
+            memtable.put(&i.to_be_bytes(), b"v").unwrap();
+        }
+        assert_eq!(memtable.arena_bytes(), reserved);
+        assert_eq!(memtable.get(b"key1").unwrap().to_value(), Some(vec![2; 100]));
+    }
 }
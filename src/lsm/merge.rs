@@ -0,0 +1,379 @@
+use std::fmt;
+
+use super::db::{DbError, Result};
+use super::ttl::{self, Expiry};
+use crate::format::get_u32;
+
+/// MergeOperator: folds merge operands into a value, so a counter or a
+/// list can be updated without reading it first
+///    - DB::merge logs an operand; reads fold the operands written since
+///      the key's last put or delete onto that value, or onto None past a
+///      delete or the oldest version
+///    - compaction folds them once no snapshot needs them apart, and
+///      concatenates runs of operands it can't fold yet
+///    - operands are folded oldest first; folding a run in pieces must give
+///      what folding it at once does, and the result may not depend on
+///      anything but the arguments
+///    - a database written with merges must be opened with an operator to
+///      read those keys
+pub trait MergeOperator: Send + Sync {
+    /// shown in Debug output and errors
+    fn name(&self) -> &str;
+
+    /// `existing` with `operands` applied, oldest first; None if the key had
+    /// no value
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8>;
+}
+
+impl fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MergeOperator({})", self.name())
+    }
+}
+
+/// U64AddOperator: treats values and operands as little-endian u64 counters
+///    - a missing value counts as 0; sums wrap
+///    - anything but 8 bytes counts as 0 too
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64AddOperator;
+
+impl MergeOperator for U64AddOperator {
+    fn name(&self) -> &str {
+        "u64add"
+    }
+
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+        let read = |bytes: &[u8]| bytes.try_into().map_or(0, u64::from_le_bytes);
+        let sum = operands.iter().fold(existing.map_or(0, read), |sum, op| {
+            sum.wrapping_add(read(op))
+        });
+        sum.to_le_bytes().to_vec()
+    }
+}
+
+/// AppendOperator: appends operands to the value, separated by `delimiter`
+#[derive(Debug, Clone, Default)]
+pub struct AppendOperator {
+    pub delimiter: Vec<u8>,
+}
+
+impl AppendOperator {
+    pub fn new(delimiter: &[u8]) -> Self {
+        Self {
+            delimiter: delimiter.to_vec(),
+        }
+    }
+}
+
+impl MergeOperator for AppendOperator {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+        let mut value = existing.map(<[u8]>::to_vec);
+        for op in operands {
+            match &mut value {
+                Some(value) => {
+                    value.extend_from_slice(&self.delimiter);
+                    value.extend_from_slice(op);
+                }
+                None => value = Some(op.to_vec()),
+            }
+        }
+        value.unwrap_or_default()
+    }
+}
+
+/// one stored version of a key, as memtables and tables hand it out
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StoredValue {
+    Put(Vec<u8>),
+
+    Delete,
+
+    /// a delete that cancels out the single put below it in compaction;
+    /// reads treat it as Delete
+    SingleDelete,
+
+    /// operands written since the next older version, oldest first
+    Merge(Vec<Vec<u8>>),
+}
+
+impl StoredValue {
+    pub fn is_merge(&self) -> bool {
+        matches!(self, StoredValue::Merge(_))
+    }
+
+    /// a tombstone of either kind
+    pub fn is_delete(&self) -> bool {
+        matches!(self, StoredValue::Delete | StoredValue::SingleDelete)
+    }
+}
+
+impl From<Option<Vec<u8>>> for StoredValue {
+    /// a value, or a tombstone for None
+    fn from(value: Option<Vec<u8>>) -> Self {
+        value.map_or(StoredValue::Delete, StoredValue::Put)
+    }
+}
+
+/// the versions of one key a read folds, fed newest first
+#[derive(Default)]
+pub(crate) struct MergeFold {
+    /// operands so far, newest first
+    operands: Vec<Vec<u8>>,
+
+    /// the put or delete below them, once reached
+    base: Option<Option<Vec<u8>>>,
+
+    /// set when values carry LSMConfig::ttl stamps; versions are pushed and
+    /// kept stamped, and expired ones are left out
+    expiry: Option<Expiry>,
+}
+
+impl MergeFold {
+    pub fn with_expiry(expiry: Option<Expiry>) -> Self {
+        Self {
+            expiry,
+            ..Self::default()
+        }
+    }
+
+    /// take the next older version; true once a put or delete settles the
+    /// value and older versions no longer matter
+    pub fn push(&mut self, value: StoredValue) -> bool {
+        let value = match &self.expiry {
+            Some(expiry) => expiry.expire(value),
+            None => value,
+        };
+        match value {
+            StoredValue::Put(value) => self.base = Some(Some(value)),
+            StoredValue::Delete | StoredValue::SingleDelete => self.base = Some(None),
+            StoredValue::Merge(operands) => {
+                self.operands.extend(operands.into_iter().rev());
+                return false;
+            }
+        }
+        true
+    }
+
+    /// the value read: the operands folded onto the put they sit on, or onto
+    /// None past a delete or the oldest version
+    pub fn finish(
+        self,
+        key: &[u8],
+        operator: Option<&dyn MergeOperator>,
+    ) -> Result<Option<Vec<u8>>> {
+        let stamped = self.expiry.is_some();
+        let base = self.base.flatten();
+        if self.operands.is_empty() {
+            return Ok(base.map(|value| unstamp(&value, stamped).to_vec()));
+        }
+        let operator = operator.ok_or(DbError::NoMergeOperator)?;
+        let operands: Vec<&[u8]> = self
+            .operands
+            .iter()
+            .rev()
+            .map(|op| unstamp(op, stamped))
+            .collect();
+        let base = base.as_deref().map(|value| unstamp(value, stamped));
+        Ok(Some(operator.merge(key, base, &operands)))
+    }
+
+    /// finish() as a version to store; under a TTL the folded value is
+    /// stamped with the newest write time folded into it
+    pub fn finish_version(
+        self,
+        key: &[u8],
+        operator: Option<&dyn MergeOperator>,
+    ) -> Result<StoredValue> {
+        let newest = self
+            .operands
+            .first()
+            .or(self.base.as_ref().and_then(Option::as_ref));
+        let written = newest.and_then(|value| ttl::stamp_of(value));
+        let stamped = self.expiry.is_some();
+        let value = self.finish(key, operator)?;
+        Ok(match (value, written) {
+            (Some(value), Some(written)) if stamped => {
+                StoredValue::Put(ttl::stamp(&value, written))
+            }
+            (value, _) => StoredValue::from(value),
+        })
+    }
+
+    /// the operands gathered, as one version standing in for all of them
+    pub fn into_merge(mut self) -> StoredValue {
+        self.operands.reverse();
+        StoredValue::Merge(self.operands)
+    }
+}
+
+/// `value` without its TTL stamp, if values carry one
+fn unstamp(value: &[u8], stamped: bool) -> &[u8] {
+    if stamped { ttl::unstamp(value) } else { value }
+}
+
+/// merge operands as a table stores them: each [len(4B)][operand]
+pub(crate) fn encode_operands(operands: &[Vec<u8>]) -> Vec<u8> {
+    let size = operands.iter().map(|op| 4 + op.len()).sum();
+    let mut encoded = Vec::with_capacity(size);
+    for op in operands {
+        encoded.extend_from_slice(&(op.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(op);
+    }
+    encoded
+}
+
+/// None if the operands overrun `encoded`
+pub(crate) fn decode_operands(encoded: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut operands = Vec::new();
+    let mut offset = 0;
+    while offset < encoded.len() {
+        let len = get_u32(encoded, offset)? as usize;
+        let op = encoded.get(offset + 4..)?.get(..len)?;
+        operands.push(op.to_vec());
+        offset += 4 + len;
+    }
+    Some(operands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::batch::WriteBatch;
+    use crate::lsm::config::LSMConfig;
+    use crate::lsm::db::DB;
+    use crate::lsm::options::ReadOptions;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_fold_versions() {
+        let add = |n: u64| n.to_le_bytes().to_vec();
+        let operator: &dyn MergeOperator = &U64AddOperator;
+
+        // operands over a put, newest first
+        let mut fold = MergeFold::default();
+        assert!(!fold.push(StoredValue::Merge(vec![add(2), add(3)])));
+        assert!(!fold.push(StoredValue::Merge(vec![add(1)])));
+        assert!(fold.push(StoredValue::Put(add(10))));
+        assert_eq!(fold.finish(b"k", Some(operator)).unwrap(), Some(add(16)));
+
+        // past a delete, and without anything older
+        let mut fold = MergeFold::default();
+        fold.push(StoredValue::Merge(vec![add(5)]));
+        assert!(fold.push(StoredValue::Delete));
+        assert_eq!(fold.finish(b"k", Some(operator)).unwrap(), Some(add(5)));
+        let mut fold = MergeFold::default();
+        fold.push(StoredValue::Merge(vec![add(1), add(2)]));
+        fold.push(StoredValue::Merge(vec![add(0)]));
+        assert_eq!(
+            fold.into_merge(),
+            StoredValue::Merge(vec![add(0), add(1), add(2)])
+        );
+
+        // plain versions need no operator, operands do
+        let mut fold = MergeFold::default();
+        fold.push(StoredValue::Delete);
+        assert_eq!(fold.finish(b"k", None).unwrap(), None);
+        let mut fold = MergeFold::default();
+        fold.push(StoredValue::Merge(vec![add(1)]));
+        assert!(matches!(
+            fold.finish(b"k", None),
+            Err(DbError::NoMergeOperator)
+        ));
+
+        let append = AppendOperator::new(b",");
+        assert_eq!(append.merge(b"k", None, &[b"a", b"b"]), b"a,b");
+        assert_eq!(append.merge(b"k", Some(b"x"), &[b"y"]), b"x,y");
+    }
+
+    #[test]
+    fn test_encode_decode_operands() {
+        let operands = vec![b"one".to_vec(), Vec::new(), b"three".to_vec()];
+        let encoded = encode_operands(&operands);
+        assert_eq!(decode_operands(&encoded), Some(operands));
+        assert_eq!(decode_operands(&[]), Some(Vec::new()));
+        assert_eq!(decode_operands(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(decode_operands(b"+1"), None);
+    }
+
+    #[test]
+    fn test_db_merge() {
+        let dir = env::temp_dir().join("test_db_merge");
+        fs::remove_dir_all(&dir).ok();
+        let config = LSMConfig {
+            memtable_size: 256,
+            auto_compaction: false,
+            background_flush: false,
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..LSMConfig::default()
+        };
+        let count = |n: u64| Some(n.to_le_bytes().to_vec());
+        let one = 1u64.to_le_bytes();
+
+        let db = DB::open(&dir, config.clone()).unwrap();
+        db.put(b"hits", &10u64.to_le_bytes()).unwrap();
+        db.merge(b"hits", &one).unwrap();
+        db.merge(b"new", &one).unwrap();
+        assert_eq!(db.get(b"hits").unwrap(), count(11));
+        assert_eq!(db.get(b"new").unwrap(), count(1));
+
+        // operands spread over tables fold onto the put below them
+        db.flush().unwrap();
+        let snapshot = ReadOptions::new().with_snapshot(db.snapshot());
+        for _ in 0..5 {
+            db.merge(b"hits", &one).unwrap();
+            db.flush().unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        batch.merge(b"hits", &one);
+        batch.merge(b"new", &one);
+        db.write(batch).unwrap();
+        assert_eq!(db.get(b"hits").unwrap(), count(17));
+        assert_eq!(db.get_opt(b"hits", &snapshot).unwrap(), count(11));
+        let entries: Vec<_> = db.iter().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"hits".to_vec(), count(17).unwrap()),
+                (b"new".to_vec(), count(2).unwrap())
+            ]
+        );
+
+        // compaction folds what the snapshot doesn't need apart
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get(b"hits").unwrap(), count(17));
+        assert_eq!(db.get_opt(b"hits", &snapshot).unwrap(), count(11));
+        drop(snapshot);
+
+        // a delete resets the count
+        db.delete(b"new").unwrap();
+        db.merge(b"new", &one).unwrap();
+        assert_eq!(db.get(b"new").unwrap(), count(1));
+        db.close().unwrap();
+
+        let db = DB::open(&dir, config).unwrap();
+        assert_eq!(db.get(b"hits").unwrap(), count(17));
+        assert_eq!(db.get(b"new").unwrap(), count(1));
+        db.close().unwrap();
+
+        // without an operator merges are refused and merged keys unreadable
+        let config = LSMConfig {
+            auto_compaction: false,
+            ..LSMConfig::default()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        assert!(matches!(
+            db.merge(b"hits", &one),
+            Err(DbError::NoMergeOperator)
+        ));
+        assert!(matches!(db.get(b"new"), Err(DbError::NoMergeOperator)));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,10 +1,64 @@
+pub(crate) mod arena;
+pub mod backup;
+pub mod batch;
+pub mod cache;
+pub mod compaction;
 pub mod config;
+pub mod db;
+pub mod external;
+pub mod family;
+pub mod iterator;
+pub mod job;
+pub mod listener;
 pub mod manifest;
 pub mod memtable;
+pub mod merge;
+pub mod options;
+pub mod options_file;
+pub mod range_del;
+pub mod shadow;
+pub mod snapshot;
 pub mod sstable;
+pub mod stats;
+pub mod status;
+pub mod subscription;
+pub mod tailing;
+pub mod ttl;
+pub mod version_edit;
 pub mod wal;
 
-pub use config::LSMConfig;
-pub use manifest::{Manifest, SSTableMetadata};
+pub use backup::{BackupEngine, BackupError, BackupInfo};
+pub use batch::{BatchError, BatchOp, BatchOpType, WriteBatch};
+pub use cache::{CacheStats, TableCache, TableCacheStats};
+pub use compaction::{CompactionReason, CompactionTask};
+pub use config::{
+    AppendMode, CompactionSchedule, ConfigError, LSMConfig, LSMConfigBuilder, WalSyncPolicy,
+};
+pub use db::{
+    AppendStats, ApproximateSize, DbError, PurgeReport, ReadStats, RepairReport, DB,
+};
+pub use external::{ExternalFileInfo, SstFileWriter};
+pub use family::{ColumnFamily, DEFAULT_FAMILY};
+pub use iterator::{DbIterator, MergeIterator};
+pub use job::{CancelToken, JobHandle, JobStatus};
+pub use listener::{
+    CompactionJobInfo, EventListener, FlushJobInfo, TableFileDeletedInfo, TableFileInfo,
+    WalSyncInfo,
+};
+pub use manifest::{
+    FamilyManifest, FileSummary, LevelDiff, Manifest, ManifestDiff, ManifestRecoveryMode,
+    SSTableMetadata,
+};
 pub use memtable::Memtable;
-pub use wal::{WalEntry, WalReader, WalWriter};
+pub use merge::{AppendOperator, MergeOperator, StoredValue, U64AddOperator};
+pub use options::{FlushOptions, ReadOptions, WriteOptions};
+pub use options_file::OptionsFile;
+pub use range_del::{RangeTombstone, RangeTombstones};
+pub use shadow::{Divergence, ShadowDb};
+pub use snapshot::{CommitToken, Snapshot};
+pub use stats::{Histogram, HistogramSnapshot, Latency, Statistics, StatisticsSnapshot, Ticker};
+pub use status::{AmplificationReport, DbStatus, StatusServer};
+pub use subscription::Subscription;
+pub use tailing::TailingIterator;
+pub use version_edit::{FamilyEdit, ManifestLog, VersionEdit};
+pub use wal::{GroupCommit, WalEntry, WalReader, WalRecovery, WalRecoveryMode, WalWriter};
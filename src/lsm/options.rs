@@ -0,0 +1,122 @@
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use super::iterator::{above_lower, below_upper};
+use super::snapshot::{CommitToken, Snapshot};
+
+/// per-read settings for get and range
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    /// read as of this snapshot instead of the latest state
+    pub snapshot: Option<Snapshot>,
+
+    /// the write behind this token must be visible before the read runs
+    pub min_token: Option<CommitToken>,
+
+    /// how long to wait for `min_token`; zero fails right away
+    pub token_timeout: Duration,
+
+    /// scans start at this key, inclusive, whatever range they ask for;
+    /// point reads ignore it
+    pub iterate_lower_bound: Option<Vec<u8>>,
+
+    /// scans stop before this key, exclusive; tables wholly past it are
+    /// never opened
+    pub iterate_upper_bound: Option<Vec<u8>>,
+
+    /// point reads give up with DeadlineExceeded once this passes; checked
+    /// before each table's blocks are read, so memtable hits always return
+    pub deadline: Option<Instant>,
+}
+
+impl ReadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_snapshot(mut self, snapshot: Snapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    pub fn with_min_token(mut self, token: CommitToken, timeout: Duration) -> Self {
+        self.min_token = Some(token);
+        self.token_timeout = timeout;
+        self
+    }
+
+    pub fn with_lower_bound(mut self, key: &[u8]) -> Self {
+        self.iterate_lower_bound = Some(key.to_vec());
+        self
+    }
+
+    pub fn with_upper_bound(mut self, key: &[u8]) -> Self {
+        self.iterate_upper_bound = Some(key.to_vec());
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// the bounds of a scan narrowed to the iterate bounds
+    pub(crate) fn clamp(
+        &self,
+        lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
+    ) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let lower = match &self.iterate_lower_bound {
+            Some(key) if above_lower(key, &lower) => Bound::Included(key.clone()),
+            _ => lower,
+        };
+        let upper = match &self.iterate_upper_bound {
+            Some(key) if below_upper(key, &upper) => Bound::Excluded(key.clone()),
+            _ => upper,
+        };
+        (lower, upper)
+    }
+}
+
+/// per-write settings for put, delete and write
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// return only once the write is on disk; concurrent synchronous
+    /// writers share one fsync
+    pub sync: bool,
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+}
+
+/// settings for DB::flush_opt
+#[derive(Debug, Clone)]
+pub struct FlushOptions {
+    /// return once the memtables are in tables; false only starts the flush
+    pub wait: bool,
+}
+
+impl Default for FlushOptions {
+    fn default() -> Self {
+        Self { wait: true }
+    }
+}
+
+impl FlushOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_wait(mut self, wait: bool) -> Self {
+        self.wait = wait;
+        self
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::config::LSMConfig;
+use crate::format::{TABLE_VERSION, WAL_VERSION};
+
+/// file in the database directory recording the options it was opened with
+pub const OPTIONS_FILE: &str = "OPTIONS";
+
+/// the only key order there is: bytewise, shorter keys first on a tie
+pub const BYTEWISE_COMPARATOR: &str = "kvstore.BytewiseComparator";
+
+/// OptionsFile: the settings a database was last opened with, kept as JSON
+/// in OPTIONS and checked by DB::open before anything is read
+///    - settings that change how stored bytes read back must match: the
+///      comparator, TTL stamps and the merge operator
+///    - files written by a newer format than this build reads are refused
+///    - the rest (block size, bloom bits, compression, ...) are a record;
+///      changing them only affects files written from then on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OptionsFile {
+    pub comparator: String,
+
+    pub table_format: u32,
+
+    pub wal_format: u32,
+
+    /// values and merge operands carry a write time, see LSMConfig::ttl
+    pub ttl_seconds: Option<u64>,
+
+    /// MergeOperator::name of LSMConfig::merge_operator
+    pub merge_operator: Option<String>,
+
+    pub block_size: usize,
+
+    pub bloom_bits_per_key: usize,
+
+    pub compression: String,
+
+    pub max_levels: usize,
+
+    pub prefix_filter_len: Option<usize>,
+}
+
+impl OptionsFile {
+    pub fn from_config(config: &LSMConfig) -> Self {
+        Self {
+            comparator: BYTEWISE_COMPARATOR.to_string(),
+            table_format: TABLE_VERSION,
+            wal_format: WAL_VERSION,
+            ttl_seconds: config.ttl.map(|ttl| ttl.as_secs()),
+            merge_operator: config
+                .merge_operator
+                .as_ref()
+                .map(|op| op.name().to_string()),
+            block_size: config.block_size,
+            bloom_bits_per_key: config.bloom_bits_per_key,
+            compression: format!("{:?}", config.compression),
+            max_levels: config.max_levels,
+            prefix_filter_len: config.prefix_filter_len,
+        }
+    }
+
+    /// the options to record for `config` in place of `recorded`: a merge
+    /// operator name stays recorded while the config names none, since
+    /// operands written for it may still be on disk
+    pub fn succeeding(config: &LSMConfig, recorded: Option<&OptionsFile>) -> Self {
+        let mut options = Self::from_config(config);
+        if options.merge_operator.is_none() {
+            options.merge_operator = recorded.and_then(|recorded| recorded.merge_operator.clone());
+        }
+        options
+    }
+
+    /// the OPTIONS file in `dir`; None if there is none, as in databases
+    /// created before it existed
+    pub fn load(dir: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let data = match fs::read(dir.as_ref().join(OPTIONS_FILE)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// replace the OPTIONS file in `dir` atomically
+    pub fn save(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let temp_path = dir.join(format!("{}.tmp", OPTIONS_FILE));
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(&temp_path, data)?;
+        fs::File::open(&temp_path)?.sync_all()?;
+        fs::rename(&temp_path, dir.join(OPTIONS_FILE))
+    }
+
+    /// why a database last opened with these options can't be opened with
+    /// `config`, if it can't
+    pub fn check(&self, config: &LSMConfig) -> Result<(), String> {
+        if self.comparator != BYTEWISE_COMPARATOR {
+            return Err(format!(
+                "keys were ordered by comparator {:?}, this build only knows {:?}",
+                self.comparator, BYTEWISE_COMPARATOR
+            ));
+        }
+        if self.table_format > TABLE_VERSION || self.wal_format > WAL_VERSION {
+            return Err(format!(
+                "written with table format {} and WAL format {}, this build reads up to {} and {}",
+                self.table_format, self.wal_format, TABLE_VERSION, WAL_VERSION
+            ));
+        }
+        match (self.ttl_seconds, config.ttl) {
+            (Some(_), None) => {
+                return Err("values carry TTL stamps, so LSMConfig::ttl must be set".to_string());
+            }
+            (None, Some(_)) => {
+                return Err(
+                    "values carry no TTL stamps, so LSMConfig::ttl must be None".to_string()
+                );
+            }
+            _ => {}
+        }
+        let operator = config.merge_operator.as_ref().map(|op| op.name());
+        if let (Some(written), Some(operator)) = (&self.merge_operator, operator)
+            && written != operator
+        {
+            return Err(format!(
+                "merge operands were written for operator {:?}, not {:?}",
+                written, operator
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::merge::{AppendOperator, U64AddOperator};
+    use std::env;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_options_file() {
+        let dir = env::temp_dir().join("test_options_file");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(OptionsFile::load(&dir).unwrap(), None);
+
+        let config = LSMConfig {
+            ttl: Some(Duration::from_secs(60)),
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..LSMConfig::default()
+        };
+        let options = OptionsFile::from_config(&config);
+        options.save(&dir).unwrap();
+        assert_eq!(OptionsFile::load(&dir).unwrap(), Some(options.clone()));
+        assert!(options.check(&config).is_ok());
+
+        // tuning changes are fine, TTL and format changes aren't
+        let retuned = LSMConfig {
+            block_size: 16 * 1024,
+            ttl: Some(Duration::from_secs(3600)),
+            merge_operator: None,
+            ..config.clone()
+        };
+        assert!(options.check(&retuned).is_ok());
+        let rewritten = OptionsFile::succeeding(&retuned, Some(&options));
+        assert_eq!(rewritten.merge_operator.as_deref(), Some("u64add"));
+        let other_operator = LSMConfig {
+            merge_operator: Some(Arc::new(AppendOperator::new(b","))),
+            ..config.clone()
+        };
+        assert!(rewritten.check(&other_operator).unwrap_err().contains("operator"));
+        let no_ttl = LSMConfig {
+            ttl: None,
+            ..config.clone()
+        };
+        assert!(options.check(&no_ttl).unwrap_err().contains("TTL"));
+        let newer = OptionsFile {
+            table_format: TABLE_VERSION + 1,
+            ..options.clone()
+        };
+        assert!(newer.check(&config).unwrap_err().contains("table format"));
+        let other = OptionsFile {
+            comparator: "reverse".to_string(),
+            ..options
+        };
+        assert!(other.check(&config).unwrap_err().contains("comparator"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,245 @@
+use crate::format::{get_u32, get_u64};
+
+/// one delete_range: every version of a key in [start, end) older than `seq`
+/// is deleted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+
+    pub end: Vec<u8>,
+
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+}
+
+/// RangeTombstones: the range tombstones of a memtable, a table or a read
+///    - kept sorted by start key; tombstones may overlap
+///    - a version is deleted by the newest tombstone covering its key that
+///      the read can see, if that tombstone is newer than the version
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RangeTombstones {
+    tombstones: Vec<RangeTombstone>,
+}
+
+impl RangeTombstones {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add a tombstone; empty ranges delete nothing and are left out
+    pub fn add(&mut self, tombstone: RangeTombstone) {
+        if tombstone.start >= tombstone.end {
+            return;
+        }
+        let at = self
+            .tombstones
+            .partition_point(|t| t.start <= tombstone.start);
+        self.tombstones.insert(at, tombstone);
+    }
+
+    pub fn extend(&mut self, other: &RangeTombstones) {
+        for tombstone in other.iter() {
+            self.add(tombstone.clone());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tombstones.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tombstones.is_empty()
+    }
+
+    /// in start key order
+    pub fn iter(&self) -> impl Iterator<Item = &RangeTombstone> {
+        self.tombstones.iter()
+    }
+
+    /// sequence number of the newest tombstone covering `key` that a read at
+    /// `seq` sees; 0 if there is none
+    pub fn covering_seq(&self, key: &[u8], seq: u64) -> u64 {
+        let candidates = self
+            .tombstones
+            .partition_point(|t| t.start.as_slice() <= key);
+        self.tombstones[..candidates]
+            .iter()
+            .filter(|t| t.seq <= seq && t.contains(key))
+            .map(|t| t.seq)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// whether the version of `key` written at `version` is deleted for a
+    /// read at `seq`
+    pub fn covers(&self, key: &[u8], version: u64, seq: u64) -> bool {
+        version < self.covering_seq(key, seq)
+    }
+
+    /// smallest start and largest end, which bound every deleted key
+    pub fn bounds(&self) -> Option<(&[u8], &[u8])> {
+        let start = self.tombstones.first()?.start.as_slice();
+        let end = self.tombstones.iter().map(|t| t.end.as_slice()).max()?;
+        Some((start, end))
+    }
+
+    /// table block format: [count(4B)] then per tombstone
+    /// [seq(8B)][Start Len(4B)][End Len(4B)][Start][End]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.tombstones.len() as u32).to_le_bytes());
+        for t in &self.tombstones {
+            buf.extend_from_slice(&t.seq.to_le_bytes());
+            buf.extend_from_slice(&(t.start.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&(t.end.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&t.start);
+            buf.extend_from_slice(&t.end);
+        }
+        buf
+    }
+
+    /// None if the block is truncated; an empty block holds no tombstones
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut tombstones = Self::new();
+        if data.is_empty() {
+            return Some(tombstones);
+        }
+        let count = get_u32(data, 0)?;
+        let mut cursor = 4;
+        for _ in 0..count {
+            let seq = get_u64(data, cursor)?;
+            let start_len = get_u32(data, cursor + 8)? as usize;
+            let end_len = get_u32(data, cursor + 12)? as usize;
+            cursor += 16;
+            let start = data.get(cursor..)?.get(..start_len)?.to_vec();
+            cursor += start_len;
+            let end = data.get(cursor..)?.get(..end_len)?.to_vec();
+            cursor += end_len;
+            tombstones.add(RangeTombstone { start, end, seq });
+        }
+        Some(tombstones)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::config::LSMConfig;
+    use crate::lsm::db::DB;
+    use crate::lsm::options::ReadOptions;
+    use std::env;
+    use std::fs;
+
+    fn tombstone(start: &[u8], end: &[u8], seq: u64) -> RangeTombstone {
+        RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        }
+    }
+
+    #[test]
+    fn test_covering_seq() {
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add(tombstone(b"m", b"z", 5));
+        tombstones.add(tombstone(b"a", b"n", 3));
+        tombstones.add(tombstone(b"x", b"x", 9));
+        assert_eq!(tombstones.len(), 2);
+        assert_eq!(tombstones.bounds(), Some((&b"a"[..], &b"z"[..])));
+
+        assert_eq!(tombstones.covering_seq(b"b", 10), 3);
+        assert_eq!(tombstones.covering_seq(b"m", 10), 5);
+        assert_eq!(tombstones.covering_seq(b"m", 4), 3);
+        assert_eq!(tombstones.covering_seq(b"z", 10), 0);
+        assert!(tombstones.covers(b"p", 4, 10));
+        assert!(!tombstones.covers(b"p", 6, 10));
+        assert!(!tombstones.covers(b"p", 4, 4));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add(tombstone(b"a", b"c", 1));
+        tombstones.add(tombstone(b"b", b"zz", 2));
+        let encoded = tombstones.encode();
+        assert_eq!(RangeTombstones::decode(&encoded), Some(tombstones));
+        assert_eq!(RangeTombstones::decode(&[]), Some(RangeTombstones::new()));
+        assert_eq!(RangeTombstones::decode(&encoded[..encoded.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_db_delete_range() {
+        let dir = env::temp_dir().join("test_db_delete_range");
+        fs::remove_dir_all(&dir).ok();
+        let config = LSMConfig {
+            memtable_size: 4096,
+            target_file_size: 2048,
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        };
+        let key = |tenant: u32, i: u32| format!("tenant{}/{:04}", tenant, i).into_bytes();
+
+        let db = DB::open(&dir, config.clone()).unwrap();
+        for tenant in 0..3 {
+            for i in 0..200 {
+                db.put(&key(tenant, i), &[b'v'; 16]).unwrap();
+            }
+        }
+        db.flush().unwrap();
+        db.compact_range::<&[u8]>(..).unwrap();
+        let snapshot = ReadOptions::new().with_snapshot(db.snapshot());
+
+        // drop tenant 1, over data in tables and in the memtable
+        db.put(&key(1, 500), b"memtable").unwrap();
+        db.delete_range(b"tenant1/", b"tenant2/").unwrap();
+        db.put(&key(1, 7), b"after").unwrap();
+        assert_eq!(db.get(&key(1, 3)).unwrap(), None);
+        assert_eq!(db.get(&key(1, 500)).unwrap(), None);
+        assert_eq!(db.get(&key(1, 7)).unwrap(), Some(b"after".to_vec()));
+        assert_eq!(db.get(&key(2, 0)).unwrap(), Some(vec![b'v'; 16]));
+        assert_eq!(
+            db.get_opt(&key(1, 3), &snapshot).unwrap(),
+            Some(vec![b'v'; 16])
+        );
+        assert_eq!(db.iter().unwrap().count(), 401);
+
+        // the tombstone survives flushes, compactions and reopening
+        db.flush().unwrap();
+        assert_eq!(db.get(&key(1, 3)).unwrap(), None);
+        assert_eq!(db.range(key(1, 0)..key(2, 0)).unwrap().count(), 1);
+        db.compact_range::<&[u8]>(..).unwrap();
+        assert_eq!(db.get(&key(1, 3)).unwrap(), None);
+        assert_eq!(
+            db.get_opt(&key(1, 3), &snapshot).unwrap(),
+            Some(vec![b'v'; 16])
+        );
+        assert_eq!(db.iter().unwrap().count(), 401);
+        drop(snapshot);
+        db.close().unwrap();
+
+        let db = DB::open(&dir, config.clone()).unwrap();
+        assert_eq!(db.get(&key(1, 3)).unwrap(), None);
+        assert_eq!(db.iter().unwrap().count(), 401);
+
+        // without snapshots, compaction drops the deleted keys and the tombstone
+        db.compact_range::<&[u8]>(..).unwrap();
+        assert_eq!(db.get(&key(1, 3)).unwrap(), None);
+        assert_eq!(db.get(&key(1, 7)).unwrap(), Some(b"after".to_vec()));
+        assert_eq!(db.iter().unwrap().count(), 401);
+        let entries = db
+            .status()
+            .levels
+            .iter()
+            .map(|level| level.entries)
+            .sum::<u64>();
+        assert_eq!(entries, 401);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
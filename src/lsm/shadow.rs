@@ -0,0 +1,245 @@
+use std::ops::RangeBounds;
+
+use super::batch::WriteBatch;
+use super::db::{DB, Result};
+
+/// test harness that mirrors every write to two databases and compares reads
+/// - `primary` is the known-good side; its results are what callers get back
+/// - meant for stabilizing a new format or configuration against the old one
+/// - only the first divergence is kept, later ones are usually fallout
+pub struct ShadowDb {
+    primary: DB,
+
+    shadow: DB,
+
+    divergence: Option<Divergence>,
+}
+
+/// first point where the two databases disagreed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// operation that noticed it: get, range, put, delete or write
+    pub op: &'static str,
+
+    pub key: Vec<u8>,
+
+    pub primary: Option<Vec<u8>>,
+
+    pub shadow: Option<Vec<u8>>,
+}
+
+impl ShadowDb {
+    pub fn new(primary: DB, shadow: DB) -> Self {
+        Self {
+            primary,
+            shadow,
+            divergence: None,
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let shadow = self.shadow.put(key, value);
+        self.mirror("put", key, self.primary.put(key, value), shadow)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let shadow = self.shadow.delete(key);
+        self.mirror("delete", key, self.primary.delete(key), shadow)
+    }
+
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let shadow = self.shadow.write(batch.clone());
+        self.mirror("write", &[], self.primary.write(batch), shadow)
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let primary = self.primary.get(key)?;
+        let shadow = self.shadow.get(key)?;
+
+        if primary != shadow {
+            self.record("get", key, primary.clone(), shadow);
+        }
+        Ok(primary)
+    }
+
+    /// collect `range` from both sides and compare them entry by entry
+    pub fn range<K: AsRef<[u8]>>(
+        &mut self,
+        range: impl RangeBounds<K> + Clone,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let primary = self
+            .primary
+            .range(range.clone())?
+            .collect::<Result<Vec<_>>>()?;
+        let shadow = self.shadow.range(range)?.collect::<Result<Vec<_>>>()?;
+
+        let mut left = primary.iter();
+        let mut right = shadow.iter();
+        loop {
+            match (left.next(), right.next()) {
+                (None, None) => break,
+                (Some(a), Some(b)) if a == b => continue,
+                (a, b) => {
+                    // report the smaller key: that's the entry one side is missing or disagrees on
+                    let (key, primary, shadow) = match (a, b) {
+                        (Some(a), Some(b)) if a.0 < b.0 => (a.0.clone(), Some(a.1.clone()), None),
+                        (Some(a), Some(b)) if a.0 > b.0 => (b.0.clone(), None, Some(b.1.clone())),
+                        (Some(a), Some(b)) => (a.0.clone(), Some(a.1.clone()), Some(b.1.clone())),
+                        (Some(a), None) => (a.0.clone(), Some(a.1.clone()), None),
+                        (None, Some(b)) => (b.0.clone(), None, Some(b.1.clone())),
+                        (None, None) => unreachable!(),
+                    };
+                    self.record("range", &key, primary, shadow);
+                    break;
+                }
+            }
+        }
+
+        Ok(primary)
+    }
+
+    /// first divergence seen so far, if any
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    pub fn primary(&self) -> &DB {
+        &self.primary
+    }
+
+    pub fn shadow(&self) -> &DB {
+        &self.shadow
+    }
+
+    pub fn close(self) -> Result<()> {
+        let shadow = self.shadow.close();
+        self.primary.close()?;
+        shadow
+    }
+
+    /// a write that succeeds on one side only is a divergence too
+    fn mirror(
+        &mut self,
+        op: &'static str,
+        key: &[u8],
+        primary: Result<()>,
+        shadow: Result<()>,
+    ) -> Result<()> {
+        if primary.is_ok() != shadow.is_ok() {
+            let describe = |r: &Result<()>| r.as_ref().err().map(|e| e.to_string().into_bytes());
+            self.record(op, key, describe(&primary), describe(&shadow));
+        }
+        primary
+    }
+
+    fn record(
+        &mut self,
+        op: &'static str,
+        key: &[u8],
+        primary: Option<Vec<u8>>,
+        shadow: Option<Vec<u8>>,
+    ) {
+        if self.divergence.is_none() {
+            self.divergence = Some(Divergence {
+                op,
+                key: key.to_vec(),
+                primary,
+                shadow,
+            });
+        }
+    }
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |v: &Option<Vec<u8>>| match v {
+            Some(v) => format!("{:?}", String::from_utf8_lossy(v)),
+            None => "none".to_string(),
+        };
+        write!(
+            f,
+            "{} diverged at key {:?}: primary {}, shadow {}",
+            self.op,
+            String::from_utf8_lossy(&self.key),
+            show(&self.primary),
+            show(&self.shadow)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::config::{AppendMode, LSMConfig};
+    use std::env;
+    use std::fs;
+
+    fn open_pair(name: &str, shadow_config: LSMConfig) -> ShadowDb {
+        let dir = env::temp_dir().join(name);
+        fs::remove_dir_all(&dir).ok();
+
+        let config = LSMConfig {
+            memtable_size: 256,
+            auto_compaction: false,
+            ..LSMConfig::default()
+        };
+        let primary = DB::open(dir.join("primary"), config).unwrap();
+        let shadow = DB::open(dir.join("shadow"), shadow_config).unwrap();
+        ShadowDb::new(primary, shadow)
+    }
+
+    #[test]
+    fn test_shadow_configs_agree() {
+        let shadow_config = LSMConfig {
+            memtable_size: 1024,
+            append_mode: AppendMode::Off,
+            auto_compaction: false,
+            ..LSMConfig::default()
+        };
+        let mut db = open_pair("test_shadow_agree", shadow_config);
+
+        for i in 0..60 {
+            db.put(format!("key{:03}", (i * 7) % 60).as_bytes(), b"value")
+                .unwrap();
+        }
+        for i in (0..60).step_by(3) {
+            db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        db.primary().compact().unwrap();
+
+        for i in 0..60 {
+            db.get(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(db.range::<&[u8]>(..).unwrap().len(), 40);
+        assert_eq!(db.divergence(), None);
+
+        db.close().unwrap();
+        fs::remove_dir_all(env::temp_dir().join("test_shadow_agree")).ok();
+    }
+
+    #[test]
+    fn test_first_divergence_is_reported() {
+        let shadow_config = LSMConfig {
+            auto_compaction: false,
+            ..LSMConfig::default()
+        };
+        let mut db = open_pair("test_shadow_diverge", shadow_config);
+
+        db.put(b"a", b"1").unwrap();
+        db.put(b"c", b"3").unwrap();
+        db.shadow().put(b"b", b"2").unwrap();
+
+        assert_eq!(db.get(b"b").unwrap(), None);
+        db.shadow().delete(b"c").unwrap();
+        db.range::<&[u8]>(..).unwrap();
+
+        let divergence = db.divergence().unwrap();
+        assert_eq!(divergence.op, "get");
+        assert_eq!(divergence.key, b"b");
+        assert_eq!(divergence.shadow, Some(b"2".to_vec()));
+        assert!(divergence.to_string().contains("diverged at key \"b\""));
+
+        db.close().unwrap();
+        fs::remove_dir_all(env::temp_dir().join("test_shadow_diverge")).ok();
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// a consistent point-in-time view of the database
+/// - reads at a snapshot ignore every write with a larger sequence number
+/// - versions a live snapshot can see are kept until the snapshot is dropped
+pub struct Snapshot {
+    seq: u64,
+
+    list: Arc<SnapshotList>,
+}
+
+/// opaque marker of a committed write, for read-your-writes across processes
+/// - hand it to another process, which reads with ReadOptions::with_min_token
+/// - a read with a token waits until the write it marks is visible
+/// - unlike a snapshot it pins nothing, so holding one is free
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommitToken {
+    seq: u64,
+}
+
+/// sequence numbers pinned by live snapshots, with a refcount each
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotList {
+    live: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(seq: u64, list: Arc<SnapshotList>) -> Self {
+        list.acquire(seq);
+        Self { seq, list }
+    }
+
+    /// sequence number of the last write visible to this snapshot
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+const TOKEN_VERSION: u8 = 1;
+
+impl CommitToken {
+    pub(crate) fn new(seq: u64) -> Self {
+        Self { seq }
+    }
+
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// serialized form: [version(1B)][seq(8B)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![TOKEN_VERSION];
+        bytes.extend_from_slice(&self.seq.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [TOKEN_VERSION, seq @ ..] if seq.len() == 8 => Some(Self {
+                seq: u64::from_le_bytes(seq.try_into().unwrap()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Self {
+        Self::new(self.seq, Arc::clone(&self.list))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seq);
+    }
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot").field("seq", &self.seq).finish()
+    }
+}
+
+impl SnapshotList {
+    fn acquire(&self, seq: u64) {
+        let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        *live.entry(seq).or_insert(0) += 1;
+    }
+
+    fn release(&self, seq: u64) {
+        let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = live.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seq);
+            }
+        }
+    }
+
+    /// sequence number of the oldest live snapshot
+    pub(crate) fn oldest(&self) -> Option<u64> {
+        let live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        live.keys().next().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_list_tracks_oldest() {
+        let list = Arc::new(SnapshotList::default());
+        assert_eq!(list.oldest(), None);
+
+        let s5 = Snapshot::new(5, Arc::clone(&list));
+        let s9 = Snapshot::new(9, Arc::clone(&list));
+        let s5_again = s5.clone();
+        assert_eq!(list.oldest(), Some(5));
+
+        drop(s5);
+        assert_eq!(list.oldest(), Some(5));
+        drop(s5_again);
+        assert_eq!(list.oldest(), Some(9));
+        drop(s9);
+        assert_eq!(list.oldest(), None);
+    }
+
+    #[test]
+    fn test_commit_token_bytes() {
+        let token = CommitToken::new(42);
+        assert_eq!(CommitToken::from_bytes(&token.to_bytes()), Some(token));
+        assert!(CommitToken::new(41) < token);
+
+        assert_eq!(CommitToken::from_bytes(&[]), None);
+        assert_eq!(CommitToken::from_bytes(&token.to_bytes()[..8]), None);
+        assert_eq!(CommitToken::from_bytes(&[9; 9]), None);
+    }
+}
@@ -1,9 +1,18 @@
-use crate::constants::BLOCK_SIZE;
+use crate::format::{
+    BLOCK_ENTRY_HEADER_SIZE, BLOCK_SIZE, LEGACY_BLOCK_ENTRY_HEADER_SIZE,
+    TYPED_BLOCK_ENTRY_HEADER_SIZE, VALUE_DELETE, VALUE_MERGE, VALUE_PUT, VALUE_RANGE_DELETE_END,
+    VALUE_SINGLE_DELETE,
+    VALUE_RANGE_DELETE_START, get_u32,
+};
 use std::io::{self, Write};
+use std::ops::Range;
 
 /// Block - Immutable 4KB data unit
 ///    - Binary layout: [Entries...] [Restart Points...] [Num Restarts]
-///    - Each entry: [key_len(4B)][val_len(4B)][key][value]
+///    - Each entry: [shared_len(4B)][unshared_len(4B)][val_len(4B)][key suffix][value]
+///    - Typed blocks add the entry's type byte after the lengths, see EntryType
+///    - Keys share their prefix with the previous entry, except at restart
+///      points, which store the full key
 ///    - Restart points stored as u32 offsets
 ///    - from_bytes() - Deserialize from disk
 ///    - write_to() - Serialize to disk
@@ -13,29 +22,82 @@ use std::io::{self, Write};
 pub struct Block {
     data: Vec<u8>,
     restart_points: Vec<u32>,
+    encoding: KeyEncoding,
+}
+
+/// how entries store their keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEncoding {
+    /// [key_len(4B)][val_len(4B)][key][value], written before prefix compression
+    Full,
+
+    /// [shared_len(4B)][unshared_len(4B)][val_len(4B)][key suffix][value]
+    Prefix,
+
+    /// as Prefix with the entry type after the lengths:
+    /// [shared_len(4B)][unshared_len(4B)][val_len(4B)][type(1B)][key suffix][value]
+    Typed,
+}
+
+/// what an entry holds
+/// - only typed blocks record it; entries of older blocks read as Value and
+///   leave the distinction to their value bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    Value,
+    Tombstone,
+    MergeOperand,
+
+    /// a tombstone compaction drops along with the single put below it
+    SingleTombstone,
+
+    /// a range tombstone covers [start key, end key)
+    RangeTombstoneStart,
+    RangeTombstoneEnd,
 }
 
 ///  BlockBuilder: Constructs blocks incrementally
 ///    - Adds key-value pairs until block reaches ~4KB
 ///    - Automatically creates restart points every 16 entries
+///    - Stores only the part of each key not shared with the previous key
 ///    - Returns false when block is full (won't fit more data)
 ///    - finish() method packages everything into a Block
 pub struct BlockBuilder {
     data: Vec<u8>,
     restart_points: Vec<u32>,
+    last_key: Vec<u8>,
     counter: usize,          // Entries since last restart
     restart_interval: usize, // Entries between restarts (default: 16)
+    encoding: KeyEncoding,
 }
 
 /// Iterator over block entries
-/// - Returns (key, value) pairs sequentially
+/// - Returns (key, type, value) entries sequentially
 /// - Automatically stops at the end of entries
 pub struct BlockIterator {
     data: Vec<u8>,
-    restart_points: Vec<u32>,
+    entries_end: usize,
+    encoding: KeyEncoding,
+    key: Vec<u8>,
     current_offset: usize,
 }
 
+/// Iterator over block entries, last entry first
+/// - Decodes one restart interval at a time, the last one first, and yields
+///   its entries backwards
+pub struct BlockRevIterator {
+    data: Vec<u8>,
+    restart_points: Vec<u32>,
+    entries_end: usize,
+    encoding: KeyEncoding,
+
+    /// restart intervals before this one are still to be decoded
+    next_restart: usize,
+
+    /// entries of the current interval not yet yielded, in block order
+    interval: Vec<(Vec<u8>, EntryType, Vec<u8>)>,
+}
+
 #[derive(Debug)]
 pub enum BlockError {
     Io(io::Error),
@@ -65,37 +127,80 @@ pub type Result<T> = std::result::Result<T, BlockError>;
 
 impl BlockBuilder {
     pub fn new() -> Self {
+        Self::with_restart_interval(16)
+    }
+
+    /// sparser restarts shrink the block but make point lookups scan further
+    pub fn with_restart_interval(restart_interval: usize) -> Self {
         let mut builder = Self {
             data: Vec::new(),
             restart_points: Vec::new(),
+            last_key: Vec::new(),
             counter: 0,
-            restart_interval: 16,
+            restart_interval: restart_interval.max(1),
+            encoding: KeyEncoding::Prefix,
         };
         // first entry is always a restart point
         builder.restart_points.push(0);
         builder
     }
 
-    /// returns false if block is full and entry cannot be added
+    /// record each entry's type, see add_entry
+    pub fn typed(mut self) -> Self {
+        self.encoding = KeyEncoding::Typed;
+        self
+    }
+
+    /// add a Value entry; returns false if the block is full
     pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
-        let entry_size = 4 + 4 + key.len() + value.len(); // key_len(4) + val_len(4) + key + value
+        self.add_entry(key, EntryType::Value, value)
+    }
+
+    /// returns false if block is full and entry cannot be added
+    ///
+    /// an empty block always accepts its first entry, so entries larger than
+    /// BLOCK_SIZE end up alone in an oversized block instead of never fitting;
+    /// untyped blocks drop `entry_type`
+    pub fn add_entry(&mut self, key: &[u8], entry_type: EntryType, value: &[u8]) -> Result<bool> {
+        let restart = self.counter >= self.restart_interval;
+        let shared = if restart || self.is_empty() {
+            0
+        } else {
+            key.iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        let header_size = match self.encoding {
+            KeyEncoding::Typed => TYPED_BLOCK_ENTRY_HEADER_SIZE,
+            _ => BLOCK_ENTRY_HEADER_SIZE,
+        };
+        let entry_size = header_size + key.len() - shared + value.len();
 
         let restart_size = (self.restart_points.len() + 1) * 4 + 4; // offsets + count
 
-        if self.data.len() + entry_size + restart_size > BLOCK_SIZE {
+        if !self.is_empty() && self.data.len() + entry_size + restart_size > BLOCK_SIZE {
             return Ok(false);
         }
 
-        if self.counter >= self.restart_interval {
+        if restart {
             self.restart_points.push(self.data.len() as u32);
             self.counter = 0;
         }
 
-        self.data.extend_from_slice(&(key.len() as u32).to_le_bytes());
-        self.data.extend_from_slice(&(value.len() as u32).to_le_bytes());
-        self.data.extend_from_slice(key);
+        self.data.extend_from_slice(&(shared as u32).to_le_bytes());
+        self.data
+            .extend_from_slice(&((key.len() - shared) as u32).to_le_bytes());
+        self.data
+            .extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if self.encoding == KeyEncoding::Typed {
+            self.data.push(entry_type.tag());
+        }
+        self.data.extend_from_slice(&key[shared..]);
         self.data.extend_from_slice(value);
 
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
         self.counter += 1;
 
         Ok(true)
@@ -114,6 +219,7 @@ impl BlockBuilder {
         Block {
             data: self.data,
             restart_points: self.restart_points,
+            encoding: self.encoding,
         }
     }
 
@@ -134,6 +240,11 @@ impl Default for BlockBuilder {
 
 impl Block {
     pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        Self::from_bytes_with_encoding(data, KeyEncoding::Prefix)
+    }
+
+    /// decode a block whose entries use `encoding`; tables record which one
+    pub fn from_bytes_with_encoding(data: Vec<u8>, encoding: KeyEncoding) -> Result<Self> {
         if data.len() < 4 {
             return Err(BlockError::Corrupted(
                 "Block too small for restart count".to_string(),
@@ -154,11 +265,10 @@ impl Block {
             ));
         }
 
-        let restart_offset = num_restarts_offset - (num_restarts * 4);
-
-        if restart_offset > data.len() {
-            return Err(BlockError::Corrupted("Invalid restart offset".to_string()));
-        }
+        let restart_offset = num_restarts
+            .checked_mul(4)
+            .and_then(|size| num_restarts_offset.checked_sub(size))
+            .ok_or_else(|| BlockError::Corrupted("Invalid restart offset".to_string()))?;
 
         let mut restart_points = Vec::with_capacity(num_restarts);
         for i in 0..num_restarts {
@@ -169,12 +279,18 @@ impl Block {
                 data[offset + 2],
                 data[offset + 3],
             ]);
+            if restart_point as usize > restart_offset {
+                return Err(BlockError::Corrupted(
+                    "Restart point beyond entries".to_string(),
+                ));
+            }
             restart_points.push(restart_point);
         }
 
         Ok(Self {
             data,
             restart_points,
+            encoding,
         })
     }
 
@@ -194,29 +310,49 @@ impl Block {
     pub fn iter(&self) -> BlockIterator {
         BlockIterator {
             data: self.data.clone(),
-            restart_points: self.restart_points.clone(),
+            entries_end: self.entries_end(),
+            encoding: self.encoding,
+            key: Vec::new(),
             current_offset: 0,
         }
     }
 
+    pub fn iter_rev(&self) -> BlockRevIterator {
+        BlockRevIterator {
+            data: self.data.clone(),
+            restart_points: self.restart_points.clone(),
+            entries_end: self.entries_end(),
+            encoding: self.encoding,
+            next_restart: self.restart_points.len(),
+            interval: Vec::new(),
+        }
+    }
+
     /// binary search for a key in the block
+    ///
+    /// returns the first entry for the key, so with several versions of a
+    /// key stored newest first this is the newest one
     pub fn get(&self, target_key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_entry(target_key)?.map(|(_, value)| value))
+    }
+
+    /// as get, with the entry's type
+    pub fn get_entry(&self, target_key: &[u8]) -> Result<Option<(EntryType, Vec<u8>)>> {
         let restart_idx = self.find_restart_point(target_key)?;
 
         let start_offset = self.restart_points[restart_idx] as usize;
-        let end_offset = if restart_idx + 1 < self.restart_points.len() {
-            self.restart_points[restart_idx + 1] as usize
-        } else {
-            // end of entries is before restart points section
-            self.data.len() - (self.restart_points.len() * 4) - 4
-        };
+        // a run of equal keys may cross restart points, so scan to the end of
+        // the entries rather than the next restart
+        let end_offset = self.entries_end();
 
+        let mut key = Vec::new();
         let mut offset = start_offset;
         while offset < end_offset {
-            let (key, value, next_offset) = self.parse_entry(offset)?;
+            let (entry_type, value, next_offset) =
+                decode_entry(&self.data, offset, end_offset, self.encoding, &mut key)?;
 
             if key.as_slice() == target_key {
-                return Ok(Some(value));
+                return Ok(Some((entry_type, self.data[value].to_vec())));
             }
 
             if key.as_slice() > target_key {
@@ -230,106 +366,189 @@ impl Block {
         Ok(None)
     }
 
-    /// Returns the rightmost restart point whose key <= target_key
+    /// Returns the rightmost restart point whose key < target_key
+    ///
+    /// restart keys are sorted, so this binary-searches them and decodes
+    /// O(log n) restart entries instead of all of them
     fn find_restart_point(&self, target_key: &[u8]) -> Result<usize> {
-        let mut result = 0;
-
-        for (i, &offset) in self.restart_points.iter().enumerate() {
-            let (key, _, _) = self.parse_entry(offset as usize)?;
-
-            if key.as_slice() <= target_key {
-                result = i;
+        let end_offset = self.entries_end();
+        let mut key = Vec::new();
+
+        // restarts before `left` are below the target, those from `right` on are not
+        let mut left = 0;
+        let mut right = self.restart_points.len();
+        while left < right {
+            let mid = left + (right - left) / 2;
+            let offset = self.restart_points[mid] as usize;
+
+            // an empty trailing restart holds no key, so it is never below the target
+            let below = offset < end_offset && {
+                // restart entries hold their whole key
+                key.clear();
+                decode_entry(&self.data, offset, end_offset, self.encoding, &mut key)?;
+                key.as_slice() < target_key
+            };
+
+            if below {
+                left = mid + 1;
             } else {
-                break;
+                right = mid;
             }
         }
 
-        Ok(result)
+        Ok(left.saturating_sub(1))
+    }
+
+    fn entries_end(&self) -> usize {
+        self.data.len() - (self.restart_points.len() * 4) - 4
     }
+}
 
-    /// Parse an entry at the given offset
-    /// Returns (key, value, next_offset)
-    fn parse_entry(&self, offset: usize) -> Result<(Vec<u8>, Vec<u8>, usize)> {
-        if offset + 8 > self.data.len() {
-            return Err(BlockError::Corrupted("Entry offset out of bounds".to_string()));
+/// decode the entry at `offset`, rebuilding its key in place over the
+/// previous entry's key; returns its type, the value's range and the next offset
+fn decode_entry(
+    data: &[u8],
+    offset: usize,
+    end: usize,
+    encoding: KeyEncoding,
+    key: &mut Vec<u8>,
+) -> Result<(EntryType, Range<usize>, usize)> {
+    let field = |i: usize| {
+        get_u32(&data[..end], offset + i * 4)
+            .map(|n| n as usize)
+            .ok_or_else(|| BlockError::Corrupted("Entry offset out of bounds".to_string()))
+    };
+
+    let (shared, unshared, val_len, header_size) = match encoding {
+        KeyEncoding::Full => (0, field(0)?, field(1)?, LEGACY_BLOCK_ENTRY_HEADER_SIZE),
+        KeyEncoding::Prefix => (field(0)?, field(1)?, field(2)?, BLOCK_ENTRY_HEADER_SIZE),
+        KeyEncoding::Typed => (field(0)?, field(1)?, field(2)?, TYPED_BLOCK_ENTRY_HEADER_SIZE),
+    };
+    let entry_type = match encoding {
+        KeyEncoding::Typed => {
+            let tag = data[..end]
+                .get(offset + BLOCK_ENTRY_HEADER_SIZE)
+                .ok_or_else(|| BlockError::Corrupted("Entry offset out of bounds".to_string()))?;
+            EntryType::from_tag(*tag).ok_or_else(|| {
+                BlockError::Corrupted(format!("Unknown entry type: {}", tag))
+            })?
         }
+        _ => EntryType::Value,
+    };
+    if shared > key.len() {
+        return Err(BlockError::Corrupted(
+            "Entry shares more than the previous key".to_string(),
+        ));
+    }
 
-        let key_len = u32::from_le_bytes([
-            self.data[offset],
-            self.data[offset + 1],
-            self.data[offset + 2],
-            self.data[offset + 3],
-        ]) as usize;
+    let key_start = offset + header_size;
+    let val_start = key_start.saturating_add(unshared);
+    let next_offset = val_start.saturating_add(val_len);
+    if next_offset > end {
+        return Err(BlockError::Corrupted(
+            "Entry extends beyond block".to_string(),
+        ));
+    }
 
-        let val_len = u32::from_le_bytes([
-            self.data[offset + 4],
-            self.data[offset + 5],
-            self.data[offset + 6],
-            self.data[offset + 7],
-        ]) as usize;
+    key.truncate(shared);
+    key.extend_from_slice(&data[key_start..val_start]);
 
-        let key_start = offset + 8;
-        let val_start = key_start + key_len;
-        let next_offset = val_start + val_len;
+    Ok((entry_type, val_start..next_offset, next_offset))
+}
 
-        if next_offset > self.data.len() {
-            return Err(BlockError::Corrupted("Entry extends beyond block".to_string()));
+impl EntryType {
+    /// the VALUE_* tag stored for this type
+    pub fn tag(self) -> u8 {
+        match self {
+            EntryType::Value => VALUE_PUT,
+            EntryType::Tombstone => VALUE_DELETE,
+            EntryType::MergeOperand => VALUE_MERGE,
+            EntryType::SingleTombstone => VALUE_SINGLE_DELETE,
+            EntryType::RangeTombstoneStart => VALUE_RANGE_DELETE_START,
+            EntryType::RangeTombstoneEnd => VALUE_RANGE_DELETE_END,
         }
+    }
 
-        let key = self.data[key_start..val_start].to_vec();
-        let value = self.data[val_start..next_offset].to_vec();
-
-        Ok((key, value, next_offset))
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            VALUE_PUT => Some(EntryType::Value),
+            VALUE_DELETE => Some(EntryType::Tombstone),
+            VALUE_MERGE => Some(EntryType::MergeOperand),
+            VALUE_SINGLE_DELETE => Some(EntryType::SingleTombstone),
+            VALUE_RANGE_DELETE_START => Some(EntryType::RangeTombstoneStart),
+            VALUE_RANGE_DELETE_END => Some(EntryType::RangeTombstoneEnd),
+            _ => None,
+        }
     }
 }
 
 impl Iterator for BlockIterator {
-    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    type Item = Result<(Vec<u8>, EntryType, Vec<u8>)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // Calculate end of entries (before restart points)
-        let entries_end = self.data.len() - (self.restart_points.len() * 4) - 4;
-
-        if self.current_offset >= entries_end {
+        if self.current_offset >= self.entries_end {
             return None;
         }
 
-        if self.current_offset + 8 > self.data.len() {
-            return Some(Err(BlockError::Corrupted(
-                "Entry offset out of bounds".to_string(),
-            )));
+        match decode_entry(
+            &self.data,
+            self.current_offset,
+            self.entries_end,
+            self.encoding,
+            &mut self.key,
+        ) {
+            Ok((entry_type, value, next_offset)) => {
+                self.current_offset = next_offset;
+                Some(Ok((self.key.clone(), entry_type, self.data[value].to_vec())))
+            }
+            Err(e) => {
+                self.current_offset = self.entries_end;
+                Some(Err(e))
+            }
         }
+    }
+}
 
-        let key_len = u32::from_le_bytes([
-            self.data[self.current_offset],
-            self.data[self.current_offset + 1],
-            self.data[self.current_offset + 2],
-            self.data[self.current_offset + 3],
-        ]) as usize;
-
-        let val_len = u32::from_le_bytes([
-            self.data[self.current_offset + 4],
-            self.data[self.current_offset + 5],
-            self.data[self.current_offset + 6],
-            self.data[self.current_offset + 7],
-        ]) as usize;
-
-        let key_start = self.current_offset + 8;
-        let val_start = key_start + key_len;
-        let next_offset = val_start + val_len;
-
-        if next_offset > entries_end {
-            return Some(Err(BlockError::Corrupted(
-                "Entry extends beyond block".to_string(),
-            )));
+impl BlockRevIterator {
+    /// decode the restart interval before the current one
+    fn decode_interval(&mut self) -> Result<()> {
+        self.next_restart -= 1;
+        let start = self.restart_points[self.next_restart] as usize;
+        let end = self
+            .restart_points
+            .get(self.next_restart + 1)
+            .map_or(self.entries_end, |&end| end as usize);
+
+        // restart entries hold their whole key
+        let mut key = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            let (entry_type, value, next_offset) =
+                decode_entry(&self.data, offset, self.entries_end, self.encoding, &mut key)?;
+            self.interval.push((key.clone(), entry_type, self.data[value].to_vec()));
+            offset = next_offset;
         }
+        Ok(())
+    }
+}
 
-        let key = self.data[key_start..val_start].to_vec();
-        let value = self.data[val_start..next_offset].to_vec();
-
-        self.current_offset = next_offset;
+impl Iterator for BlockRevIterator {
+    type Item = Result<(Vec<u8>, EntryType, Vec<u8>)>;
 
-        Some(Ok((key, value)))
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.interval.pop() {
+                return Some(Ok(entry));
+            }
+            if self.next_restart == 0 {
+                return None;
+            }
+            if let Err(e) = self.decode_interval() {
+                self.next_restart = 0;
+                self.interval.clear();
+                return Some(Err(e));
+            }
+        }
     }
 }
 
@@ -359,15 +578,15 @@ mod tests {
         let block = builder.finish();
         let mut iter = block.iter();
 
-        let (k, v) = iter.next().unwrap().unwrap();
+        let (k, _, v) = iter.next().unwrap().unwrap();
         assert_eq!(k, b"apple");
         assert_eq!(v, b"red");
 
-        let (k, v) = iter.next().unwrap().unwrap();
+        let (k, _, v) = iter.next().unwrap().unwrap();
         assert_eq!(k, b"banana");
         assert_eq!(v, b"yellow");
 
-        let (k, v) = iter.next().unwrap().unwrap();
+        let (k, _, v) = iter.next().unwrap().unwrap();
         assert_eq!(k, b"cherry");
         assert_eq!(v, b"red");
 
@@ -430,6 +649,135 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_block_get_duplicate_keys() {
+        let mut builder = BlockBuilder::with_restart_interval(2);
+        builder.add(b"a", b"a").unwrap();
+        for i in (0..5).rev() {
+            builder.add(b"b", format!("v{}", i).as_bytes()).unwrap();
+        }
+        builder.add(b"c", b"c").unwrap();
+
+        let block = builder.finish();
+        assert_eq!(block.get(b"b").unwrap(), Some(b"v4".to_vec()));
+        assert_eq!(block.get(b"c").unwrap(), Some(b"c".to_vec()));
+    }
+
+    #[test]
+    fn test_block_prefix_compression() {
+        let keys: Vec<String> = (0..40)
+            .map(|i| format!("user/0000000042/events/{:04}", i))
+            .collect();
+        let full_size: usize = keys.iter().map(|k| 8 + k.len() + 1).sum();
+
+        let mut builder = BlockBuilder::with_restart_interval(16);
+        for key in &keys {
+            builder.add(key.as_bytes(), b"v").unwrap();
+        }
+        let block = Block::from_bytes(builder.finish().as_bytes().to_vec()).unwrap();
+        assert!(block.size() < full_size / 2);
+
+        let iterated: Vec<_> = block.iter().map(|r| r.unwrap().0).collect();
+        assert_eq!(iterated.len(), keys.len());
+        for (key, found) in keys.iter().zip(&iterated) {
+            assert_eq!(found, key.as_bytes());
+            assert_eq!(block.get(key.as_bytes()).unwrap(), Some(b"v".to_vec()));
+        }
+        assert_eq!(block.get(b"user/0000000042/events/9999").unwrap(), None);
+    }
+
+    #[test]
+    fn test_block_iter_rev() {
+        // 40 entries over three restart intervals, the last one partial
+        let keys: Vec<String> = (0..40).map(|i| format!("key{:03}", i)).collect();
+        let mut builder = BlockBuilder::with_restart_interval(16);
+        for key in &keys {
+            builder.add(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        let block = Block::from_bytes(builder.finish().as_bytes().to_vec()).unwrap();
+
+        let reversed: Vec<_> = block.iter_rev().map(|r| r.unwrap()).collect();
+        assert_eq!(reversed.len(), keys.len());
+        for ((key, _, value), expected) in reversed.iter().zip(keys.iter().rev()) {
+            assert_eq!(key, expected.as_bytes());
+            assert_eq!(value, expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_block_full_key_encoding() {
+        // [key_len][val_len][key][value] entries, one restart point
+        let mut data = Vec::new();
+        for (key, value) in [(&b"apple"[..], &b"red"[..]), (b"banana", b"yellow")] {
+            data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(key);
+            data.extend_from_slice(value);
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+
+        let block = Block::from_bytes_with_encoding(data, KeyEncoding::Full).unwrap();
+        assert_eq!(block.get(b"banana").unwrap(), Some(b"yellow".to_vec()));
+        assert_eq!(block.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_block_rejects_overlong_shared_prefix() {
+        let mut builder = BlockBuilder::new();
+        builder.add(b"key1", b"v").unwrap();
+        let mut data = builder.finish().as_bytes().to_vec();
+        // the first entry has no previous key to share with
+        data[0] = 2;
+
+        let block = Block::from_bytes(data).unwrap();
+        assert!(block.iter().next().unwrap().is_err());
+        assert!(block.get(b"key1").is_err());
+    }
+
+    #[test]
+    fn test_block_from_bytes_rejects_bad_trailer() {
+        // more restart points than the block has room for
+        assert!(Block::from_bytes(vec![0xff; 8]).is_err());
+        assert!(Block::from_bytes(vec![0, 0]).is_err());
+
+        // restart point past the end of the entries
+        let mut data = 100u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        assert!(Block::from_bytes(data).is_err());
+    }
+
+    #[test]
+    fn test_block_get_every_restart() {
+        // one restart per entry: every lookup goes through the binary search
+        let mut builder = BlockBuilder::with_restart_interval(1);
+        for i in (0..200).step_by(2) {
+            builder.add(format!("k{:04}", i).as_bytes(), b"v").unwrap();
+        }
+        let block = builder.finish();
+        assert_eq!(block.restart_points.len(), 100);
+
+        for i in 0..200 {
+            let found = block.get(format!("k{:04}", i).as_bytes()).unwrap();
+            assert_eq!(found.is_some(), i % 2 == 0, "key {}", i);
+        }
+        assert_eq!(block.get(b"a").unwrap(), None);
+        assert_eq!(block.get(b"z").unwrap(), None);
+    }
+
+    #[test]
+    fn test_block_custom_restart_interval() {
+        let mut builder = BlockBuilder::with_restart_interval(64);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            builder.add(key.as_bytes(), b"v").unwrap();
+        }
+
+        let block = builder.finish();
+        assert_eq!(block.restart_points.len(), 2);
+        assert_eq!(block.get(b"key099").unwrap(), Some(b"v".to_vec()));
+    }
+
     #[test]
     fn test_block_size_limit() {
         let mut builder = BlockBuilder::new();
@@ -454,4 +802,62 @@ mod tests {
 
         println!("Added {} entries, block size: {}", count, block.size());
     }
+
+    #[test]
+    fn test_block_oversized_entry() {
+        let mut builder = BlockBuilder::new();
+        let value = vec![b'x'; BLOCK_SIZE * 2];
+
+        // first entry always fits, even when larger than a block
+        assert!(builder.add(b"big", &value).unwrap());
+        assert!(!builder.add(b"next", b"value").unwrap());
+
+        let block = builder.finish();
+        assert_eq!(block.get(b"big").unwrap(), Some(value));
+    }
+
+    #[test]
+    fn test_block_typed_entries() {
+        let mut builder = BlockBuilder::with_restart_interval(2).typed();
+        let entries = [
+            (&b"a"[..], EntryType::Value),
+            (b"b", EntryType::Tombstone),
+            (b"c", EntryType::MergeOperand),
+            (b"d", EntryType::RangeTombstoneStart),
+            (b"e", EntryType::RangeTombstoneEnd),
+            (b"f", EntryType::SingleTombstone),
+        ];
+        for (key, entry_type) in entries {
+            builder.add_entry(key, entry_type, key).unwrap();
+        }
+        let block = Block::from_bytes_with_encoding(
+            builder.finish().as_bytes().to_vec(),
+            KeyEncoding::Typed,
+        )
+        .unwrap();
+
+        let found: Vec<_> = block.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(found.len(), entries.len());
+        for ((key, entry_type), (k, t, v)) in entries.iter().zip(&found) {
+            assert_eq!((&k[..], t, &v[..]), (*key, entry_type, *key));
+        }
+        assert_eq!(
+            block.get_entry(b"c").unwrap(),
+            Some((EntryType::MergeOperand, b"c".to_vec()))
+        );
+
+        // untyped blocks read every entry as a value
+        let mut builder = BlockBuilder::new();
+        builder.add_entry(b"a", EntryType::Tombstone, b"v").unwrap();
+        let block = builder.finish();
+        assert_eq!(block.get_entry(b"a").unwrap(), Some((EntryType::Value, b"v".to_vec())));
+
+        // an unknown type byte is corruption
+        let mut builder = BlockBuilder::new().typed();
+        builder.add(b"a", b"v").unwrap();
+        let mut data = builder.finish().as_bytes().to_vec();
+        data[BLOCK_ENTRY_HEADER_SIZE] = 0xee;
+        let block = Block::from_bytes_with_encoding(data, KeyEncoding::Typed).unwrap();
+        assert!(block.get(b"a").is_err());
+    }
 }
@@ -1,7 +1,9 @@
+use serde::{Deserialize, Serialize};
+
 /// Bloom filter for probabilistic membership testing
 /// - may say "yes" when it's actually "no"
 /// - never says "no" when it's actually "yes"
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BloomFilter {
     bits: Vec<u8>,
 
@@ -23,7 +25,7 @@ impl BloomFilter {
         let num_hashes = ((bits_per_key as f64) * 0.69).ceil() as u32;
         let num_hashes = num_hashes.clamp(1, 30);
 
-        let num_bytes = (total_bits + 7) / 8;
+        let num_bytes = total_bits.div_ceil(8);
 
         Self {
             bits: vec![0u8; num_bytes],
@@ -241,11 +243,11 @@ mod tests {
     fn test_bits_per_key_calculation() {
         // For 1% false positive rate
         let bits = bits_per_key_for_fp_rate(0.01);
-        assert!(bits >= 9 && bits <= 10);
+        assert!((9..=10).contains(&bits));
 
         // For 0.1% false positive rate
         let bits = bits_per_key_for_fp_rate(0.001);
-        assert!(bits >= 14 && bits <= 15);
+        assert!((14..=15).contains(&bits));
     }
 
     #[test]
@@ -0,0 +1,141 @@
+use super::{Result, SSTableError};
+use crate::format::{
+    BLOCK_COMPRESSION_LZ4, BLOCK_COMPRESSION_NONE, BLOCK_COMPRESSION_SNAPPY, BLOCK_COMPRESSION_ZSTD,
+};
+
+/// codec applied to SSTable data blocks
+/// - each codec sits behind a cargo feature (`lz4`, `snappy`, `zstd`) so the
+///   default build pulls in no compression crates
+/// - the codec is recorded per block, so tables written with different
+///   settings stay readable as long as their codecs are compiled in
+/// - blocks that don't shrink are stored uncompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionType {
+    #[default]
+    None,
+
+    Lz4,
+
+    Snappy,
+
+    /// zstd at the given level
+    Zstd(i32),
+}
+
+impl CompressionType {
+    /// trailer byte identifying this codec
+    pub fn id(&self) -> u8 {
+        match self {
+            CompressionType::None => BLOCK_COMPRESSION_NONE,
+            CompressionType::Lz4 => BLOCK_COMPRESSION_LZ4,
+            CompressionType::Snappy => BLOCK_COMPRESSION_SNAPPY,
+            CompressionType::Zstd(_) => BLOCK_COMPRESSION_ZSTD,
+        }
+    }
+
+    /// whether this build can compress and decompress with the codec
+    pub fn is_available(&self) -> bool {
+        match self {
+            CompressionType::None => true,
+            CompressionType::Lz4 => cfg!(feature = "lz4"),
+            CompressionType::Snappy => cfg!(feature = "snappy"),
+            CompressionType::Zstd(_) => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// compress `data`; None when the codec isn't worth it for this block
+    pub(crate) fn compress(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        if *self == CompressionType::None {
+            return Ok(None);
+        }
+
+        let compressed = self.encode(data)?;
+        Ok((compressed.len() < data.len()).then_some(compressed))
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionType::None => Ok(data.to_vec()),
+            #[cfg(feature = "lz4")]
+            CompressionType::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[cfg(feature = "snappy")]
+            CompressionType::Snappy => snap::raw::Encoder::new()
+                .compress_vec(data)
+                .map_err(|e| SSTableError::Compression(e.to_string())),
+            #[cfg(feature = "zstd")]
+            CompressionType::Zstd(level) => zstd::bulk::compress(data, *level)
+                .map_err(|e| SSTableError::Compression(e.to_string())),
+            #[allow(unreachable_patterns)]
+            codec => Err(unavailable(codec.id())),
+        }
+    }
+}
+
+/// undo `compress` for a block tagged with codec `id`
+pub(crate) fn decompress(id: u8, data: &[u8]) -> Result<Vec<u8>> {
+    match id {
+        BLOCK_COMPRESSION_NONE => Ok(data.to_vec()),
+        #[cfg(feature = "lz4")]
+        BLOCK_COMPRESSION_LZ4 => lz4_flex::decompress_size_prepended(data)
+            .map_err(|e| SSTableError::Compression(e.to_string())),
+        #[cfg(feature = "snappy")]
+        BLOCK_COMPRESSION_SNAPPY => snap::raw::Decoder::new()
+            .decompress_vec(data)
+            .map_err(|e| SSTableError::Compression(e.to_string())),
+        #[cfg(feature = "zstd")]
+        BLOCK_COMPRESSION_ZSTD => {
+            zstd::decode_all(data).map_err(|e| SSTableError::Compression(e.to_string()))
+        }
+        #[allow(unreachable_patterns)]
+        BLOCK_COMPRESSION_LZ4 | BLOCK_COMPRESSION_SNAPPY | BLOCK_COMPRESSION_ZSTD => {
+            Err(unavailable(id))
+        }
+        _ => Err(SSTableError::Corrupted(format!(
+            "Unknown block compression: {}",
+            id
+        ))),
+    }
+}
+
+fn unavailable(id: u8) -> SSTableError {
+    SSTableError::Compression(format!(
+        "codec {} is not compiled in; enable its cargo feature",
+        id
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_available_codecs_roundtrip() {
+        let data = b"abcdefgh".repeat(512);
+
+        for codec in [
+            CompressionType::None,
+            CompressionType::Lz4,
+            CompressionType::Snappy,
+            CompressionType::Zstd(3),
+        ] {
+            if !codec.is_available() {
+                assert!(codec.compress(&data).is_err());
+                assert!(decompress(codec.id(), &data).is_err());
+                continue;
+            }
+
+            match codec.compress(&data).unwrap() {
+                Some(compressed) => {
+                    assert!(compressed.len() < data.len());
+                    assert_eq!(decompress(codec.id(), &compressed).unwrap(), data);
+                }
+                None => assert_eq!(codec, CompressionType::None),
+            }
+        }
+
+        assert!(matches!(
+            decompress(0xee, &data),
+            Err(SSTableError::Corrupted(_))
+        ));
+    }
+}
@@ -1,5 +1,57 @@
 pub mod block;
 pub mod bloom;
+pub mod compression;
+pub mod reader;
+pub(crate) mod table;
+pub mod writer;
 
-pub use block::Block;
+use std::io;
+
+pub use block::{Block, EntryType};
 pub use bloom::BloomFilter;
+pub use compression::CompressionType;
+pub use reader::{SSTableIterator, SSTableReader, SSTableRevIterator};
+pub use writer::SSTableWriter;
+
+use block::BlockError;
+
+#[derive(Debug)]
+pub enum SSTableError {
+    Io(io::Error),
+    Block(BlockError),
+    Corrupted(String),
+    OutOfOrder(Vec<u8>),
+    Compression(String),
+}
+
+impl From<io::Error> for SSTableError {
+    fn from(err: io::Error) -> Self {
+        SSTableError::Io(err)
+    }
+}
+
+impl From<BlockError> for SSTableError {
+    fn from(err: BlockError) -> Self {
+        SSTableError::Block(err)
+    }
+}
+
+impl std::fmt::Display for SSTableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SSTableError::Io(e) => write!(f, "SSTable I/O error: {}", e),
+            SSTableError::Block(e) => write!(f, "{}", e),
+            SSTableError::Corrupted(msg) => write!(f, "SSTable corrupted: {}", msg),
+            SSTableError::OutOfOrder(key) => write!(
+                f,
+                "SSTable key {:?} added out of order",
+                String::from_utf8_lossy(key)
+            ),
+            SSTableError::Compression(msg) => write!(f, "SSTable compression error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SSTableError {}
+
+pub type Result<T> = std::result::Result<T, SSTableError>;
@@ -0,0 +1,876 @@
+use std::fs::File;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::block::{Block, BlockIterator, BlockRevIterator, EntryType, KeyEncoding};
+use super::bloom::BloomFilter;
+use super::compression::decompress;
+use super::writer::{
+    BlockHandle, BlockIndex, Footer, InlineValues, decode_index, decode_typed_value, decode_value,
+};
+use super::{Result, SSTableError};
+use crate::format::{
+    FOOTER_SIZE, TABLE_VERSION_COMPRESSED, TABLE_VERSION_PREFIX_KEYS, TABLE_VERSION_TYPED_ENTRIES,
+    get_u32,
+};
+use crate::lsm::cache::BlockCache;
+use crate::lsm::iterator::{above_lower, below_upper};
+use crate::lsm::merge::StoredValue;
+use crate::lsm::range_del::RangeTombstones;
+
+/// one stored version: key, sequence number and value
+pub type TableEntry = (Vec<u8>, u64, StoredValue);
+
+/// SSTableReader: point lookups and scans over a table written by `SSTableWriter`
+///    - open() reads only the tail of the file: the footer, index, bloom
+///      filter and range tombstones
+///    - get(key) asks the bloom filter first, then the inline values in the
+///      index, then binary-searches the index and reads a single data block
+///    - iter() walks every stored version in key order, newest first per key;
+///      iter_rev_to() walks keys backwards, still newest first per key
+///    - data blocks are read one at a time at their offset, through the
+///      block cache when the reader came from a TableCache
+///    - compressed blocks are inflated as they are read
+///    - the footer's version picks the block layout, so files written by
+///      older versions stay readable
+///    - the open file is shared, so iterators outlive the reader cheaply
+#[derive(Clone)]
+pub struct SSTableReader {
+    path: PathBuf,
+    file: Arc<TableFile>,
+    index: Arc<BlockIndex>,
+    inline: Arc<InlineValues>,
+    bloom: BloomFilter,
+    range_tombstones: Arc<RangeTombstones>,
+    file_size: u64,
+
+    /// bytes read at open: everything from the index to the footer
+    metadata_size: u64,
+}
+
+/// the open table file behind a reader and its iterators
+struct TableFile {
+    file: File,
+    version: u32,
+    cache: Option<BlockCache>,
+}
+
+/// iterator over every version in one table, starting at a lower bound
+/// - blocks ending below the bound are skipped through the index
+/// - with_upper() stops it at the first key past an upper bound, before
+///   reading the block after it
+pub struct SSTableIterator {
+    file: Arc<TableFile>,
+    index: Arc<BlockIndex>,
+    next_block: usize,
+    block: Option<BlockIterator>,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    blocks_read: u64,
+}
+
+/// iterator over every version in one table, last key first, starting at an
+/// upper bound
+/// - blocks starting above the bound are skipped through the index
+/// - with_lower() stops it at the first key below a lower bound
+/// - versions of a key are collected before it is yielded, so they still come
+///   newest first
+pub struct SSTableRevIterator {
+    file: Arc<TableFile>,
+    index: Arc<BlockIndex>,
+
+    /// blocks before this one are still to be read
+    next_block: usize,
+    block: Option<BlockRevIterator>,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+
+    /// versions of the current key not yet yielded, oldest first
+    run: Vec<TableEntry>,
+
+    /// first version of the key before the current one
+    peeked: Option<TableEntry>,
+}
+
+impl SSTableReader {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path.as_ref(), None)
+    }
+
+    /// open, keeping the data blocks it reads in `cache`
+    pub(crate) fn open_cached(path: impl AsRef<Path>, cache: BlockCache) -> Result<Self> {
+        Self::open_with(path.as_ref(), Some(cache))
+    }
+
+    fn open_with(path: &Path, cache: Option<BlockCache>) -> Result<Self> {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        if file_size < FOOTER_SIZE as u64 {
+            return Err(SSTableError::Corrupted(
+                "Table too small for footer".to_string(),
+            ));
+        }
+        let mut footer = [0; FOOTER_SIZE];
+        read_at(&file, &mut footer, file_size - FOOTER_SIZE as u64)?;
+        let footer = Footer::decode(&footer)?;
+
+        // the index, bloom filter and range tombstones sit together in front
+        // of the footer; read them in one go and slice them out of it
+        let start = footer.index.offset.min(footer.bloom.offset);
+        if start > file_size - FOOTER_SIZE as u64 {
+            return Err(SSTableError::Corrupted(
+                "Index beyond end of table".to_string(),
+            ));
+        }
+        let mut tail = vec![0; (file_size - start) as usize];
+        read_at(&file, &mut tail, start)?;
+        let within = |handle: &BlockHandle| BlockHandle {
+            offset: handle.offset - start,
+            size: handle.size,
+        };
+        let (index, inline) = decode_index(within(&footer.index).slice(&tail)?)?;
+
+        let bloom_bytes = within(&footer.bloom).slice(&tail)?;
+        let num_hashes = get_u32(bloom_bytes, 0)
+            .ok_or_else(|| SSTableError::Corrupted("Truncated bloom filter".to_string()))?;
+        if bloom_bytes.len() == 4 {
+            return Err(SSTableError::Corrupted("Empty bloom filter".to_string()));
+        }
+        let bloom = BloomFilter::with_bytes(bloom_bytes[4..].to_vec(), num_hashes);
+
+        // whatever lies between the bloom filter and the footer
+        let bloom_end = (footer.bloom.offset + footer.bloom.size - start) as usize;
+        let range_tombstones = tail
+            .get(bloom_end..tail.len() - FOOTER_SIZE)
+            .and_then(RangeTombstones::decode)
+            .ok_or_else(|| SSTableError::Corrupted("Bad range tombstone block".to_string()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Arc::new(TableFile {
+                file,
+                version: footer.version,
+                cache,
+            }),
+            index: Arc::new(index),
+            inline: Arc::new(inline),
+            bloom,
+            range_tombstones: Arc::new(range_tombstones),
+            file_size,
+            metadata_size: tail.len() as u64,
+        })
+    }
+
+    /// the table's range tombstones; the bloom filter doesn't know the
+    /// keys they delete
+    pub fn range_tombstones(&self) -> &Arc<RangeTombstones> {
+        &self.range_tombstones
+    }
+
+    /// false means the key is definitely not in this table
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bloom.may_contain(key)
+    }
+
+    /// newest version of `key`: its sequence number and value
+    pub fn get(&self, key: &[u8]) -> Result<Option<(u64, StoredValue)>> {
+        self.get_counting(key, &mut 0)
+    }
+
+    fn get_counting(&self, key: &[u8], blocks: &mut u64) -> Result<Option<(u64, StoredValue)>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        if let Ok(i) = self.inline.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+            return Ok(Some(decode_value(&self.inline[i].1)?));
+        }
+
+        // the first block ending at or after the key holds its newest version
+        let idx = self
+            .index
+            .partition_point(|(last_key, _)| last_key.as_slice() < key);
+        let Some((_, handle)) = self.index.get(idx) else {
+            return Ok(None);
+        };
+
+        *blocks += 1;
+        match self.read_block(handle)?.get_entry(key)? {
+            Some((entry_type, value)) => {
+                Ok(Some(decode_entry(self.file.version, entry_type, &value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// newest version of `key` with a sequence number <= `seq`
+    pub fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<(u64, StoredValue)>> {
+        self.get_at_counting(key, seq, &mut 0)
+    }
+
+    /// get_at, adding the number of data blocks it read to `blocks`
+    pub fn get_at_counting(
+        &self,
+        key: &[u8],
+        seq: u64,
+        blocks: &mut u64,
+    ) -> Result<Option<(u64, StoredValue)>> {
+        if seq == u64::MAX {
+            return self.get_counting(key, blocks);
+        }
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+
+        // older versions may continue into later blocks, so walk them in order
+        let mut iter = self.iter_from(Bound::Included(key.to_vec()));
+        let mut visible = None;
+        for entry in iter.by_ref() {
+            let (found, version, value) = entry?;
+            if found != key {
+                break;
+            }
+            if version <= seq {
+                visible = Some((version, value));
+                break;
+            }
+        }
+
+        *blocks += iter.blocks_read;
+        Ok(visible)
+    }
+
+    /// versions of `key` a read at `seq` folds with their sequence numbers,
+    /// newest first: the newest visible one, then older ones while the newer
+    /// are merge operands
+    pub fn versions_at_counting(
+        &self,
+        key: &[u8],
+        seq: u64,
+        blocks: &mut u64,
+    ) -> Result<Vec<(u64, StoredValue)>> {
+        match self.get_at_counting(key, seq, blocks)? {
+            None => return Ok(Vec::new()),
+            Some((version, value)) if !value.is_merge() => return Ok(vec![(version, value)]),
+            Some(_) => {}
+        }
+
+        let mut iter = self.iter_from(Bound::Included(key.to_vec()));
+        let mut versions = Vec::new();
+        for entry in iter.by_ref() {
+            let (found, version, value) = entry?;
+            if found != key {
+                break;
+            }
+            if version > seq {
+                continue;
+            }
+            let merge = value.is_merge();
+            versions.push((version, value));
+            if !merge {
+                break;
+            }
+        }
+
+        *blocks += iter.blocks_read;
+        Ok(versions)
+    }
+
+    /// versions_at_counting for each of `keys`, which must be sorted
+    ///
+    /// keys in the same data block share one read of it; only a key whose
+    /// versions run on into the next block is looked up on its own
+    pub fn multi_versions_at_counting(
+        &self,
+        keys: &[&[u8]],
+        seq: u64,
+        blocks: &mut u64,
+    ) -> Result<Vec<Vec<(u64, StoredValue)>>> {
+        let mut found = Vec::with_capacity(keys.len());
+        // the last block read, decoded
+        let mut block: Option<(usize, Vec<TableEntry>)> = None;
+        for &key in keys {
+            if !self.may_contain(key) {
+                found.push(Vec::new());
+                continue;
+            }
+            let inline = self.inline.binary_search_by(|(k, _)| k.as_slice().cmp(key));
+            if seq == u64::MAX
+                && let Ok(i) = inline
+            {
+                found.push(vec![decode_value(&self.inline[i].1)?]);
+                continue;
+            }
+            let idx = self
+                .index
+                .partition_point(|(last_key, _)| last_key.as_slice() < key);
+            let Some((_, handle)) = self.index.get(idx) else {
+                found.push(Vec::new());
+                continue;
+            };
+            if block.as_ref().is_none_or(|(read, _)| *read != idx) {
+                *blocks += 1;
+                let entries = self.read_block(handle)?.iter().map(|entry| {
+                    let (key, entry_type, value) = entry?;
+                    let (seq, value) = decode_entry(self.file.version, entry_type, &value)?;
+                    Ok((key, seq, value))
+                });
+                block = Some((idx, entries.collect::<Result<_>>()?));
+            }
+            let Some((_, entries)) = &block else {
+                continue;
+            };
+
+            let start = entries.partition_point(|(found, _, _)| found.as_slice() < key);
+            let mut versions = Vec::new();
+            let mut settled = false;
+            for (_, version, value) in entries[start..].iter().take_while(|e| e.0 == key) {
+                if *version > seq {
+                    continue;
+                }
+                versions.push((*version, value.clone()));
+                if !value.is_merge() {
+                    settled = true;
+                    break;
+                }
+            }
+            let ends_block = entries[start..].iter().all(|e| e.0 == key);
+            if settled || !ends_block || idx + 1 == self.index.len() {
+                found.push(versions);
+            } else {
+                found.push(self.versions_at_counting(key, seq, blocks)?);
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn iter(&self) -> SSTableIterator {
+        self.iter_from(Bound::Unbounded)
+    }
+
+    pub fn iter_from(&self, lower: Bound<Vec<u8>>) -> SSTableIterator {
+        // versions of one key may continue into the next block, so start at the
+        // first block whose last key reaches the bound
+        let next_block = self
+            .index
+            .partition_point(|(last_key, _)| !above_lower(last_key, &lower));
+
+        SSTableIterator {
+            file: Arc::clone(&self.file),
+            index: Arc::clone(&self.index),
+            next_block,
+            block: None,
+            lower,
+            upper: Bound::Unbounded,
+            blocks_read: 0,
+        }
+    }
+
+    pub fn iter_rev_to(&self, upper: Bound<Vec<u8>>) -> SSTableRevIterator {
+        // blocks past the first one whose last key reaches beyond the bound
+        // hold no key within it
+        let end = self.index.partition_point(|(last_key, _)| below_upper(last_key, &upper));
+
+        SSTableRevIterator {
+            file: Arc::clone(&self.file),
+            index: Arc::clone(&self.index),
+            next_block: (end + 1).min(self.index.len()),
+            block: None,
+            lower: Bound::Unbounded,
+            upper,
+            run: Vec::new(),
+            peeked: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// bytes of the file held in memory: the index, bloom filter and range
+    /// tombstones, but no data blocks
+    pub fn metadata_size(&self) -> u64 {
+        self.metadata_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.index.len()
+    }
+
+    /// last key and stored size of every data block, in key order
+    pub fn block_sizes(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.index.iter().map(|(last_key, handle)| (last_key.as_slice(), handle.size))
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> Result<Arc<Block>> {
+        self.file.read_block(handle)
+    }
+}
+
+impl TableFile {
+    /// the data block at `handle`, from the block cache or the file
+    fn read_block(&self, handle: &BlockHandle) -> Result<Arc<Block>> {
+        if let Some(block) = self.cache.as_ref().and_then(|cache| cache.get(handle.offset)) {
+            return Ok(block);
+        }
+        let mut stored = vec![0; handle.size as usize];
+        read_at(&self.file, &mut stored, handle.offset).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => SSTableError::Corrupted(format!(
+                "Block {}+{} beyond end of table",
+                handle.offset, handle.size
+            )),
+            _ => e.into(),
+        })?;
+        let block = Arc::new(decode_block(&stored, self.version)?);
+        if let Some(cache) = &self.cache {
+            cache.insert(handle.offset, Arc::clone(&block));
+        }
+        Ok(block)
+    }
+}
+
+impl Iterator for SSTableIterator {
+    type Item = Result<TableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(block) = &mut self.block {
+                match block.next() {
+                    Some(Ok((key, entry_type, value))) => {
+                        if !above_lower(&key, &self.lower) {
+                            continue;
+                        }
+                        if !below_upper(&key, &self.upper) {
+                            self.block = None;
+                            self.next_block = self.index.len();
+                            return None;
+                        }
+                        let decoded = decode_entry(self.file.version, entry_type, &value);
+                        return Some(decoded.map(|(seq, value)| (key, seq, value)));
+                    }
+                    Some(Err(e)) => return Some(Err(e.into())),
+                    None => self.block = None,
+                }
+            }
+
+            let (_, handle) = self.index.get(self.next_block)?;
+            self.next_block += 1;
+            self.blocks_read += 1;
+            match self.file.read_block(handle) {
+                Ok(block) => self.block = Some(block.iter()),
+                Err(e) => {
+                    self.next_block = self.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl SSTableIterator {
+    /// end the scan at `upper`
+    pub fn with_upper(mut self, upper: Bound<Vec<u8>>) -> Self {
+        self.upper = upper;
+        self
+    }
+}
+
+impl SSTableRevIterator {
+    /// end the scan at `lower`
+    pub fn with_lower(mut self, lower: Bound<Vec<u8>>) -> Self {
+        self.lower = lower;
+        self
+    }
+
+    /// the version before the last one read, in table order
+    fn prev_entry(&mut self) -> Option<Result<TableEntry>> {
+        loop {
+            if let Some(block) = &mut self.block {
+                match block.next() {
+                    Some(Ok((key, entry_type, value))) => {
+                        if !below_upper(&key, &self.upper) {
+                            continue;
+                        }
+                        if !above_lower(&key, &self.lower) {
+                            self.block = None;
+                            self.next_block = 0;
+                            return None;
+                        }
+                        let decoded = decode_entry(self.file.version, entry_type, &value);
+                        return Some(decoded.map(|(seq, value)| (key, seq, value)));
+                    }
+                    Some(Err(e)) => return Some(Err(e.into())),
+                    None => self.block = None,
+                }
+            }
+
+            if self.next_block == 0 {
+                return None;
+            }
+            self.next_block -= 1;
+            let (_, handle) = &self.index[self.next_block];
+            match self.file.read_block(handle) {
+                Ok(block) => self.block = Some(block.iter_rev()),
+                Err(e) => {
+                    self.next_block = 0;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for SSTableRevIterator {
+    type Item = Result<TableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.run.pop() {
+            return Some(Ok(entry));
+        }
+
+        let first = match self.peeked.take() {
+            Some(entry) => entry,
+            None => match self.prev_entry()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        self.run.push(first);
+        loop {
+            match self.prev_entry() {
+                Some(Ok(entry)) if entry.0 == self.run[0].0 => self.run.push(entry),
+                Some(Ok(entry)) => {
+                    self.peeked = Some(entry);
+                    break;
+                }
+                Some(Err(e)) => {
+                    self.run.clear();
+                    return Some(Err(e));
+                }
+                None => break,
+            }
+        }
+        self.run.pop().map(Ok)
+    }
+}
+
+/// decode a block entry's value in the layout its table version uses
+fn decode_entry(
+    version: u32,
+    entry_type: EntryType,
+    value: &[u8],
+) -> Result<(u64, StoredValue)> {
+    if version < TABLE_VERSION_TYPED_ENTRIES {
+        decode_value(value)
+    } else {
+        decode_typed_value(entry_type, value)
+    }
+}
+
+/// decode a stored data block in the layout its table version uses
+pub(crate) fn decode_block(stored: &[u8], version: u32) -> Result<Block> {
+    let encoding = if version < TABLE_VERSION_PREFIX_KEYS {
+        KeyEncoding::Full
+    } else if version < TABLE_VERSION_TYPED_ENTRIES {
+        KeyEncoding::Prefix
+    } else {
+        KeyEncoding::Typed
+    };
+    if version < TABLE_VERSION_COMPRESSED {
+        return Ok(Block::from_bytes_with_encoding(stored.to_vec(), encoding)?);
+    }
+
+    let (&codec, payload) = stored
+        .split_last()
+        .ok_or_else(|| SSTableError::Corrupted("Empty data block".to_string()))?;
+    Ok(Block::from_bytes_with_encoding(
+        decompress(codec, payload)?,
+        encoding,
+    )?)
+}
+
+/// fill `buf` from `offset` without moving the file's cursor, so readers on
+/// other threads can share the file
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut std::mem::take(&mut buf)[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::merge::StoredValue::{Delete, Merge, Put};
+    use crate::lsm::sstable::{CompressionType, SSTableWriter};
+    use std::env;
+    use std::fs;
+
+    fn write_table(dir: &Path) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+
+        // enough data for several blocks, plus one key with many versions
+        let value = vec![b'v'; 200];
+        let mut writer = SSTableWriter::create(dir, Path::new("1.sst"), 1, 0, 16, 10).unwrap();
+        for i in 0..100u64 {
+            let key = format!("key{:03}", i);
+            writer.add(key.as_bytes(), 1000 + i, Some(&value)).unwrap();
+            if i == 50 {
+                for seq in (1..40).rev() {
+                    writer
+                        .add(key.as_bytes(), seq, Some(&seq.to_le_bytes()))
+                        .unwrap();
+                }
+                writer.add(key.as_bytes(), 0, None).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+
+        dir.join("1.sst")
+    }
+
+    #[test]
+    fn test_reader_get() {
+        let dir = env::temp_dir().join("test_sstable_reader_get");
+        let reader = SSTableReader::open(write_table(&dir)).unwrap();
+        assert!(reader.num_blocks() > 1);
+
+        for i in 0..100u64 {
+            let (seq, value) = reader
+                .get(format!("key{:03}", i).as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(seq, 1000 + i);
+            assert!(matches!(value, Put(value) if value.len() == 200));
+        }
+        assert_eq!(reader.get(b"key100").unwrap(), None);
+        assert_eq!(reader.get(b"a").unwrap(), None);
+
+        assert_eq!(
+            reader.get_at(b"key050", 20).unwrap(),
+            Some((20, Put(20u64.to_le_bytes().to_vec())))
+        );
+        assert_eq!(reader.get_at(b"key050", 0).unwrap(), Some((0, Delete)));
+        assert_eq!(reader.get_at(b"key049", 999).unwrap(), None);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reader_multi_versions() {
+        let dir = env::temp_dir().join("test_sstable_reader_multi_versions");
+        let reader = SSTableReader::open(write_table(&dir)).unwrap();
+
+        let keys: Vec<String> = (0..110).map(|i| format!("key{:03}", i)).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+        for seq in [u64::MAX, 1049, 20, 0] {
+            let (mut batched, mut single) = (0, 0);
+            let found = reader.multi_versions_at_counting(&keys, seq, &mut batched).unwrap();
+            for (key, versions) in keys.iter().zip(&found) {
+                let expected = reader.versions_at_counting(key, seq, &mut single).unwrap();
+                assert_eq!(versions, &expected);
+            }
+            assert!(batched < single);
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reader_iter() {
+        let dir = env::temp_dir().join("test_sstable_reader_iter");
+        let reader = SSTableReader::open(write_table(&dir)).unwrap();
+
+        let all: Vec<_> = reader.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(all.len(), 140);
+        assert!(
+            all.windows(2)
+                .all(|w| w[0].0 < w[1].0 || (w[0].0 == w[1].0 && w[0].1 > w[1].1))
+        );
+
+        let from: Vec<_> = reader
+            .iter_from(Bound::Excluded(b"key050".to_vec()))
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(from.len(), 49);
+        assert_eq!(from[0], b"key051");
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reader_compressed_blocks() {
+        let dir = env::temp_dir().join("test_sstable_reader_compressed");
+        fs::create_dir_all(&dir).unwrap();
+
+        let value = vec![b'v'; 200];
+        for (id, codec) in [
+            CompressionType::Lz4,
+            CompressionType::Snappy,
+            CompressionType::Zstd(3),
+        ]
+        .into_iter()
+        .enumerate()
+        .filter(|(_, codec)| codec.is_available())
+        {
+            let name = PathBuf::from(format!("{}.sst", id));
+            let mut writer = SSTableWriter::create(&dir, &name, id as u64, 0, 16, 10)
+                .unwrap()
+                .with_compression(codec);
+            for i in 0..100u64 {
+                writer
+                    .add(format!("key{:03}", i).as_bytes(), i, Some(&value))
+                    .unwrap();
+            }
+            let sst = writer.finish().unwrap();
+            assert!(sst.size < 100 * 200);
+
+            let reader = SSTableReader::open(dir.join(&name)).unwrap();
+            assert_eq!(reader.get(b"key042").unwrap(), Some((42, Put(value.clone()))));
+            assert_eq!(reader.iter().count(), 100);
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reader_inline_values_skip_data_blocks() {
+        let dir = env::temp_dir().join("test_sstable_reader_inline");
+        fs::create_dir_all(&dir).unwrap();
+
+        let big = vec![b'v'; 200];
+        let mut writer = SSTableWriter::create(&dir, Path::new("1.sst"), 1, 0, 16, 10)
+            .unwrap()
+            .with_inline_values(8);
+        for i in 0..100u64 {
+            let key = format!("key{:03}", i);
+            match i % 3 {
+                0 => writer.add(key.as_bytes(), i, Some(b"small")).unwrap(),
+                1 => writer.add(key.as_bytes(), i, None).unwrap(),
+                _ => writer.add(key.as_bytes(), i, Some(&big)).unwrap(),
+            }
+            // only the newest version is inlined
+            writer.add(key.as_bytes(), 0, Some(&big)).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // wreck every data block: inlined keys must still resolve
+        let path = dir.join("1.sst");
+        let reader = SSTableReader::open(&path).unwrap();
+        let data_end = reader.index.last().map(|(_, h)| h.offset + h.size).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[..data_end as usize].fill(0xff);
+        fs::write(&path, &data).unwrap();
+
+        let reader = SSTableReader::open(&path).unwrap();
+        assert_eq!(
+            reader.get(b"key000").unwrap(),
+            Some((0, Put(b"small".to_vec())))
+        );
+        assert_eq!(reader.get(b"key001").unwrap(), Some((1, Delete)));
+        assert!(reader.get(b"key002").is_err());
+        assert_eq!(reader.get(b"key100").unwrap(), None);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reader_untyped_tables() {
+        use crate::format::{BLOCK_COMPRESSION_NONE, TABLE_VERSION_PREFIX_KEYS, put_u32, put_u64};
+        use crate::lsm::sstable::block::BlockBuilder;
+        use crate::lsm::sstable::writer::encode_value;
+
+        let dir = env::temp_dir().join("test_sstable_reader_untyped");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        // a version 3 table: untyped block, tags in front of the values
+        let mut builder = BlockBuilder::new();
+        builder.add(b"a", &encode_value(2, EntryType::Value, b"1")).unwrap();
+        builder.add(b"b", &encode_value(1, EntryType::Tombstone, b"")).unwrap();
+        let mut data = builder.finish().as_bytes().to_vec();
+        data.push(BLOCK_COMPRESSION_NONE);
+        let block = BlockHandle {
+            offset: 0,
+            size: data.len() as u64,
+        };
+        let index_offset = data.len() as u64;
+        put_u32(&mut data, 1);
+        put_u32(&mut data, 1);
+        data.push(b'b');
+        put_u64(&mut data, block.offset);
+        put_u64(&mut data, block.size);
+        let bloom_offset = data.len() as u64;
+        let mut bloom = BloomFilter::new(2, 10);
+        bloom.add(b"a");
+        bloom.add(b"b");
+        put_u32(&mut data, bloom.num_hashes());
+        data.extend_from_slice(bloom.as_bytes());
+        let footer = Footer {
+            index: BlockHandle {
+                offset: index_offset,
+                size: bloom_offset - index_offset,
+            },
+            bloom: BlockHandle {
+                offset: bloom_offset,
+                size: data.len() as u64 - bloom_offset,
+            },
+            version: TABLE_VERSION_PREFIX_KEYS,
+        };
+        data.extend_from_slice(&footer.encode());
+        fs::write(dir.join("3.sst"), &data).unwrap();
+
+        let reader = SSTableReader::open(dir.join("3.sst")).unwrap();
+        assert_eq!(reader.get(b"a").unwrap(), Some((2, Put(b"1".to_vec()))));
+        assert_eq!(reader.get(b"b").unwrap(), Some((1, Delete)));
+        assert_eq!(reader.iter().count(), 2);
+
+        // typed tables hold entries older readers could not express
+        let mut writer = SSTableWriter::create(&dir, Path::new("4.sst"), 4, 0, 16, 10).unwrap();
+        writer.add(b"a", 3, Some(b"1")).unwrap();
+        writer.add_entry(b"b", 2, EntryType::MergeOperand, b"+1").unwrap();
+        writer.add_value(b"c", 1, &Merge(vec![b"+1".to_vec()])).unwrap();
+        writer.finish().unwrap();
+        let reader = SSTableReader::open(dir.join("4.sst")).unwrap();
+        assert_eq!(reader.get(b"a").unwrap(), Some((3, Put(b"1".to_vec()))));
+        assert!(reader.get(b"b").is_err());
+        assert_eq!(reader.get(b"c").unwrap(), Some((1, Merge(vec![b"+1".to_vec()]))));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_reader_rejects_truncated_file() {
+        let dir = env::temp_dir().join("test_sstable_reader_truncated");
+        let path = write_table(&dir);
+
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(SSTableReader::open(&path).is_err());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
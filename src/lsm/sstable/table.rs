@@ -0,0 +1,218 @@
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::reader::SSTableReader;
+use crate::lsm::db::Result;
+use crate::lsm::iterator::VersionEntry;
+use crate::lsm::merge::StoredValue;
+use crate::lsm::range_del::RangeTombstones;
+
+pub(crate) use super::reader::TableEntry;
+
+/// the versions of a table in scan order
+type Scanner = Box<dyn Iterator<Item = super::Result<TableEntry>> + Send>;
+
+/// restart interval used unless the writer has a reason to pick another
+pub(crate) const DEFAULT_RESTART_INTERVAL: usize = 16;
+
+/// sequential iterator over one table between two bounds, forwards or
+/// backwards
+///
+/// yields the newest version of each key with a sequence number <= `seq`,
+/// then older ones while the newer are merge operands
+pub(crate) struct TableIterator {
+    scanner: Scanner,
+    seq: u64,
+    last_key: Option<Vec<u8>>,
+
+    /// the last version yielded was a merge operand
+    merging: bool,
+
+    /// versions these delete come out as tombstones
+    range_tombstones: Option<Arc<RangeTombstones>>,
+}
+
+impl TableIterator {
+    pub(crate) fn new(
+        reader: &SSTableReader,
+        (lower, upper): (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        seq: u64,
+    ) -> Self {
+        Self::with_scanner(Box::new(reader.iter_from(lower).with_upper(upper)), seq)
+    }
+
+    /// keys from `upper` down; each key's versions still come newest first
+    pub(crate) fn new_rev(
+        reader: &SSTableReader,
+        (lower, upper): (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        seq: u64,
+    ) -> Self {
+        Self::with_scanner(Box::new(reader.iter_rev_to(upper).with_lower(lower)), seq)
+    }
+
+    fn with_scanner(scanner: Scanner, seq: u64) -> Self {
+        Self {
+            scanner,
+            seq,
+            last_key: None,
+            merging: false,
+            range_tombstones: None,
+        }
+    }
+
+    /// apply the range tombstones of a whole read, not just this table's
+    pub(crate) fn with_range_tombstones(mut self, tombstones: Arc<RangeTombstones>) -> Self {
+        self.range_tombstones = Some(tombstones);
+        self
+    }
+}
+
+impl Iterator for TableIterator {
+    type Item = Result<VersionEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, seq, value) = match self.scanner.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if seq > self.seq || (self.last_key.as_ref() == Some(&key) && !self.merging) {
+                continue;
+            }
+            let tombstones = self.range_tombstones.as_deref();
+            let value = if tombstones.is_some_and(|t| t.covers(&key, seq, self.seq)) {
+                StoredValue::Delete
+            } else {
+                value
+            };
+            self.merging = value.is_merge();
+            self.last_key = Some(key.clone());
+            return Some(Ok((key, value)));
+        }
+    }
+}
+
+/// file name of table `id`, relative to the database directory
+pub(crate) fn table_file_name(id: u64) -> PathBuf {
+    PathBuf::from(format!("{:06}.sst", id))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::merge::StoredValue::{Delete, Merge, Put};
+    use crate::lsm::sstable::SSTableWriter;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_write_and_scan_versions() {
+        let dir = env::temp_dir().join("test_table_versions");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut writer = SSTableWriter::create(&dir, Path::new("1.sst"), 1, 2, 16, 10).unwrap();
+        writer.add(b"a", 5, Some(b"new")).unwrap();
+        writer.add(b"a", 2, Some(b"old")).unwrap();
+        writer.add(b"b", 4, None).unwrap();
+        writer.add_value(b"c", 6, &Merge(vec![b"+".to_vec()])).unwrap();
+        writer.add(b"c", 3, Some(b"c")).unwrap();
+        writer.add(b"c", 1, Some(b"older")).unwrap();
+        let sst = writer.finish().unwrap();
+
+        assert_eq!(sst.level, 2);
+        assert_eq!(sst.num_entries, 6);
+        assert_eq!(
+            (sst.min_key.as_slice(), sst.max_key.as_slice()),
+            (&b"a"[..], &b"c"[..])
+        );
+
+        let reader = SSTableReader::open(dir.join(&sst.path)).unwrap();
+        let versions: Vec<_> = reader
+            .iter()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(versions.len(), 6);
+        assert_eq!(versions[1], (b"a".to_vec(), 2, Put(b"old".to_vec())));
+
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        let latest: Vec<_> = TableIterator::new(&reader, all, u64::MAX)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            latest,
+            vec![
+                (b"a".to_vec(), Put(b"new".to_vec())),
+                (b"b".to_vec(), Delete),
+                (b"c".to_vec(), Merge(vec![b"+".to_vec()])),
+                (b"c".to_vec(), Put(b"c".to_vec())),
+            ]
+        );
+
+        let bounds = (Bound::Included(b"a".to_vec()), Bound::Unbounded);
+        let at_3: Vec<_> = TableIterator::new(&reader, bounds, 3)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            at_3,
+            vec![
+                (b"a".to_vec(), Put(b"old".to_vec())),
+                (b"c".to_vec(), Put(b"c".to_vec()))
+            ]
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_scan_versions_backwards() {
+        let dir = env::temp_dir().join("test_table_versions_backwards");
+        fs::create_dir_all(&dir).unwrap();
+
+        // three versions per key, over many blocks
+        let mut writer = SSTableWriter::create(&dir, Path::new("1.sst"), 1, 0, 16, 10).unwrap();
+        for i in 0..300 {
+            let key = format!("key{:03}", i);
+            for seq in (1..=3).rev() {
+                writer.add(key.as_bytes(), seq, Some(&[seq as u8; 20])).unwrap();
+            }
+        }
+        let sst = writer.finish().unwrap();
+        let reader = SSTableReader::open(dir.join(&sst.path)).unwrap();
+        assert!(reader.num_blocks() > 2);
+
+        let mut forward: Vec<_> = reader.iter().map(|r| r.unwrap()).collect();
+        forward.reverse();
+        let backward: Vec<_> = reader.iter_rev_to(Bound::Unbounded).map(|r| r.unwrap()).collect();
+        assert_eq!(backward.len(), forward.len());
+        for (run, expected) in backward.chunks(3).zip(forward.chunks(3)) {
+            assert!(run.iter().map(|e| e.1).eq([3, 2, 1]));
+            assert!(run.iter().eq(expected.iter().rev()));
+        }
+
+        let bounds = (Bound::Excluded(b"key100".to_vec()), Bound::Included(b"key150".to_vec()));
+        let latest: Vec<_> = TableIterator::new_rev(&reader, bounds.clone(), 2)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(latest.len(), 50);
+        assert_eq!(latest[0], (b"key150".to_vec(), Put(vec![2; 20])));
+        assert_eq!(latest[49].0, b"key101");
+
+        let oldest: Vec<_> = TableIterator::new(&reader, bounds, 1)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(oldest.len(), 50);
+        assert_eq!(oldest[0], (b"key101".to_vec(), Put(vec![1; 20])));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
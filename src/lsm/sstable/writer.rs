@@ -0,0 +1,574 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::block::{BlockBuilder, EntryType};
+use super::bloom::BloomFilter;
+use super::compression::CompressionType;
+use super::{Result, SSTableError};
+use crate::format::{
+    get_u32, get_u64, put_u32, put_u64, BLOCK_COMPRESSION_NONE, FOOTER_SIZE, TABLE_MAGIC,
+    TABLE_VERSION, TYPED_VALUE_HEADER_SIZE, VALUE_HEADER_SIZE,
+};
+use crate::lsm::manifest::{PrefixFilter, SSTableMetadata};
+use crate::lsm::merge::{StoredValue, decode_operands, encode_operands};
+use crate::lsm::range_del::{RangeTombstone, RangeTombstones};
+
+/// SSTableWriter: streams sorted entries into a table file
+///    - layout: [data blocks...][index block][bloom filter][range tombstones][footer]
+///    - data blocks are typed `Block`s cut at BLOCK_SIZE, each stored with a
+///      trailing compression byte; every entry records its EntryType
+///    - the index maps each block's last key to its handle, so a lookup
+///      binary-searches the index and reads one block
+///    - with inline values on, the index also carries the newest version of
+///      every key whose value is at most the threshold, so point lookups for
+///      those keys never read a data block
+///    - the bloom filter covers every distinct key in the table; an optional
+///      prefix filter over the keys goes into the returned metadata instead
+///      of the file; tables with range tombstones get none, since it can't
+///      speak for the keys they delete
+///    - range tombstones go into their own block and widen the table's key
+///      range to cover what they delete
+///    - keys must be added in order; versions of one key newest first
+pub struct SSTableWriter {
+    file_name: PathBuf,
+    id: u64,
+    level: usize,
+    writer: BufWriter<File>,
+    offset: u64,
+    data_block: BlockBuilder,
+    restart_interval: usize,
+    index: BlockIndex,
+    keys: Vec<Vec<u8>>,
+    bloom_bits_per_key: usize,
+    num_entries: u64,
+    num_tombstones: u64,
+    compression: CompressionType,
+    inline_threshold: Option<usize>,
+    inline: InlineValues,
+    /// prefix length and bits per prefix of the manifest prefix filter
+    prefix_filter: Option<(usize, usize)>,
+    range_tombstones: RangeTombstones,
+}
+
+/// last key of each data block and where the block lives
+pub(crate) type BlockIndex = Vec<(Vec<u8>, BlockHandle)>;
+
+/// newest encoded version of each key stored in the index, sorted by key
+pub(crate) type InlineValues = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// where a block lives inside a table file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHandle {
+    pub offset: u64,
+
+    pub size: u64,
+}
+
+/// fixed-size trailer locating the index and the bloom filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub index: BlockHandle,
+
+    pub bloom: BlockHandle,
+
+    pub version: u32,
+}
+
+impl SSTableWriter {
+    /// create `file_name` in `dir` for table `id`
+    pub fn create(
+        dir: &Path,
+        file_name: &Path,
+        id: u64,
+        level: usize,
+        restart_interval: usize,
+        bloom_bits_per_key: usize,
+    ) -> Result<Self> {
+        let file = File::create(dir.join(file_name))?;
+
+        Ok(Self {
+            file_name: file_name.to_path_buf(),
+            id,
+            level,
+            writer: BufWriter::new(file),
+            offset: 0,
+            data_block: BlockBuilder::with_restart_interval(restart_interval).typed(),
+            restart_interval,
+            index: Vec::new(),
+            keys: Vec::new(),
+            bloom_bits_per_key,
+            num_entries: 0,
+            num_tombstones: 0,
+            compression: CompressionType::None,
+            inline_threshold: None,
+            inline: Vec::new(),
+            prefix_filter: None,
+            range_tombstones: RangeTombstones::new(),
+        })
+    }
+
+    /// compress data blocks with `compression`; the index and bloom filter stay raw
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// copy values of at most `threshold` bytes (and tombstones) into the index
+    pub fn with_inline_values(mut self, threshold: usize) -> Self {
+        self.inline_threshold = Some(threshold);
+        self
+    }
+
+    /// describe the table's first `prefix_len` key bytes in a PrefixFilter
+    pub fn with_prefix_filter(mut self, prefix_len: usize, bits_per_prefix: usize) -> Self {
+        self.prefix_filter = Some((prefix_len, bits_per_prefix));
+        self
+    }
+
+    /// add one version of `key`; a None value is a tombstone
+    pub fn add(&mut self, key: &[u8], seq: u64, value: Option<&[u8]>) -> Result<()> {
+        match value {
+            Some(value) => self.add_entry(key, seq, EntryType::Value, value),
+            None => self.add_entry(key, seq, EntryType::Tombstone, &[]),
+        }
+    }
+
+    /// add one stored version of `key`
+    pub fn add_value(&mut self, key: &[u8], seq: u64, value: &StoredValue) -> Result<()> {
+        match value {
+            StoredValue::Put(value) => self.add_entry(key, seq, EntryType::Value, value),
+            StoredValue::Delete => self.add_entry(key, seq, EntryType::Tombstone, &[]),
+            StoredValue::SingleDelete => {
+                self.add_entry(key, seq, EntryType::SingleTombstone, &[])
+            }
+            StoredValue::Merge(operands) => {
+                self.add_entry(key, seq, EntryType::MergeOperand, &encode_operands(operands))
+            }
+        }
+    }
+
+    /// add a range tombstone; unlike keys these may come in any order
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.range_tombstones.add(tombstone);
+    }
+
+    /// add one entry of any type for `key`
+    pub fn add_entry(
+        &mut self,
+        key: &[u8],
+        seq: u64,
+        entry_type: EntryType,
+        value: &[u8],
+    ) -> Result<()> {
+        let new_key = match self.keys.last() {
+            Some(last) if key < last.as_slice() => {
+                return Err(SSTableError::OutOfOrder(key.to_vec()));
+            }
+            Some(last) => key != last.as_slice(),
+            None => true,
+        };
+
+        let mut stored = Vec::with_capacity(TYPED_VALUE_HEADER_SIZE + value.len());
+        put_u64(&mut stored, seq);
+        stored.extend_from_slice(value);
+        if !self.data_block.add_entry(key, entry_type, &stored)? {
+            self.finish_data_block()?;
+            self.data_block.add_entry(key, entry_type, &stored)?;
+        }
+
+        if new_key {
+            self.keys.push(key.to_vec());
+            // a lookup answered from the index never sees the older versions
+            // a merge operand or range tombstone would need
+            let plain = matches!(entry_type, EntryType::Value | EntryType::Tombstone);
+            let small = self
+                .inline_threshold
+                .is_some_and(|threshold| value.len() <= threshold);
+            if plain && small {
+                self.inline.push((key.to_vec(), encode_value(seq, entry_type, value)));
+            }
+        }
+        self.num_entries += 1;
+        if matches!(entry_type, EntryType::Tombstone | EntryType::SingleTombstone) {
+            self.num_tombstones += 1;
+        }
+
+        Ok(())
+    }
+
+    /// bytes written so far plus the pending data block
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.data_block.current_size() as u64
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// data blocks written so far, not counting the pending one
+    pub fn num_blocks(&self) -> usize {
+        self.index.len()
+    }
+
+    /// write the index, bloom filter, range tombstones and footer, sync, and
+    /// describe the table
+    pub fn finish(mut self) -> Result<SSTableMetadata> {
+        if !self.data_block.is_empty() {
+            self.finish_data_block()?;
+        }
+
+        let index = encode_index(&self.index, &self.inline);
+        let index_handle = self.write_raw(&index)?;
+
+        let mut bloom = BloomFilter::new(self.keys.len(), self.bloom_bits_per_key);
+        for key in &self.keys {
+            bloom.add(key);
+        }
+        let mut bloom_bytes = Vec::with_capacity(4 + bloom.size());
+        put_u32(&mut bloom_bytes, bloom.num_hashes());
+        bloom_bytes.extend_from_slice(bloom.as_bytes());
+        let bloom_handle = self.write_raw(&bloom_bytes)?;
+        if !self.range_tombstones.is_empty() {
+            self.write_raw(&self.range_tombstones.encode())?;
+        }
+
+        let footer = Footer {
+            index: index_handle,
+            bloom: bloom_handle,
+            version: TABLE_VERSION,
+        };
+        self.write_raw(&footer.encode())?;
+
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        let keys = self.keys.iter().map(Vec::as_slice);
+        let prefix_filter = self
+            .prefix_filter
+            .filter(|_| self.range_tombstones.is_empty())
+            .and_then(|(prefix_len, bits)| PrefixFilter::build(keys, prefix_len, bits));
+
+        let mut min_key = self.keys.first().cloned();
+        let mut max_key = self.keys.pop();
+        if let Some((start, end)) = self.range_tombstones.bounds() {
+            min_key = Some(min_key.map_or(start.to_vec(), |min| min.min(start.to_vec())));
+            max_key = Some(max_key.map_or(end.to_vec(), |max| max.max(end.to_vec())));
+        }
+        let entries = self.num_entries + self.range_tombstones.len() as u64;
+        Ok(SSTableMetadata {
+            id: self.id,
+            level: self.level,
+            path: self.file_name,
+            size: self.offset,
+            num_entries: self.num_entries,
+            min_key: min_key.unwrap_or_default(),
+            max_key: max_key.unwrap_or_default(),
+            created_at: crate::lsm::sstable::table::unix_now(),
+            tombstone_only: entries > 0 && self.num_tombstones == self.num_entries,
+            prefix_filter,
+        })
+    }
+
+    fn finish_data_block(&mut self) -> Result<()> {
+        let fresh = BlockBuilder::with_restart_interval(self.restart_interval).typed();
+        let block = std::mem::replace(&mut self.data_block, fresh).finish();
+
+        let (mut stored, codec) = match self.compression.compress(block.as_bytes())? {
+            Some(compressed) => (compressed, self.compression.id()),
+            None => (block.as_bytes().to_vec(), BLOCK_COMPRESSION_NONE),
+        };
+        stored.push(codec);
+
+        let handle = self.write_raw(&stored)?;
+        let last_key = self.keys.last().cloned().unwrap_or_default();
+        self.index.push((last_key, handle));
+
+        Ok(())
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<BlockHandle> {
+        self.writer.write_all(bytes)?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: bytes.len() as u64,
+        };
+        self.offset += bytes.len() as u64;
+        Ok(handle)
+    }
+}
+
+impl BlockHandle {
+    /// the bytes of this block within a whole table file
+    pub fn slice<'a>(&self, data: &'a [u8]) -> Result<&'a [u8]> {
+        let end = self.offset.checked_add(self.size);
+        match end {
+            Some(end) if end <= data.len() as u64 => Ok(&data[self.offset as usize..end as usize]),
+            _ => Err(SSTableError::Corrupted(format!(
+                "Block {}+{} beyond end of table",
+                self.offset, self.size
+            ))),
+        }
+    }
+}
+
+impl Footer {
+    pub fn encode(&self) -> [u8; FOOTER_SIZE] {
+        let mut buf = [0u8; FOOTER_SIZE];
+        buf[0..8].copy_from_slice(&self.index.offset.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.index.size.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.bloom.offset.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.bloom.size.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.version.to_le_bytes());
+        buf[36..44].copy_from_slice(&TABLE_MAGIC.to_le_bytes());
+        buf
+    }
+
+    /// parse the footer at the end of a whole table file
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < FOOTER_SIZE {
+            return Err(SSTableError::Corrupted(
+                "Table too small for footer".to_string(),
+            ));
+        }
+        let footer = &data[data.len() - FOOTER_SIZE..];
+        let u64_at = |i: usize| get_u64(footer, i).unwrap();
+
+        if u64_at(36) != TABLE_MAGIC {
+            return Err(SSTableError::Corrupted("Bad table magic".to_string()));
+        }
+        let version = get_u32(footer, 32).unwrap();
+        if !(1..=TABLE_VERSION).contains(&version) {
+            return Err(SSTableError::Corrupted(format!(
+                "Unsupported table version {}",
+                version
+            )));
+        }
+
+        Ok(Self {
+            index: BlockHandle {
+                offset: u64_at(0),
+                size: u64_at(8),
+            },
+            bloom: BlockHandle {
+                offset: u64_at(16),
+                size: u64_at(24),
+            },
+            version,
+        })
+    }
+}
+
+/// index layout: [count(4B)] then per block [Key Len(4B)][Key][Offset(8B)][Size(8B)]
+/// - followed by [count(4B)] then per inline value [Key Len(4B)][Key][Val Len(4B)][Value]
+/// - tables written without inline values may end right after the blocks
+fn encode_index(index: &[(Vec<u8>, BlockHandle)], inline: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_u32(&mut buf, index.len() as u32);
+    for (key, handle) in index {
+        put_u32(&mut buf, key.len() as u32);
+        buf.extend_from_slice(key);
+        put_u64(&mut buf, handle.offset);
+        put_u64(&mut buf, handle.size);
+    }
+
+    if !inline.is_empty() {
+        put_u32(&mut buf, inline.len() as u32);
+        for (key, value) in inline {
+            put_u32(&mut buf, key.len() as u32);
+            buf.extend_from_slice(key);
+            put_u32(&mut buf, value.len() as u32);
+            buf.extend_from_slice(value);
+        }
+    }
+    buf
+}
+
+pub(crate) fn decode_index(data: &[u8]) -> Result<(BlockIndex, InlineValues)> {
+    let truncated = || SSTableError::Corrupted("Truncated index block".to_string());
+    let slice = |start: usize, len: usize| {
+        data.get(start..start.saturating_add(len))
+            .map(|bytes| bytes.to_vec())
+            .ok_or_else(truncated)
+    };
+
+    let count = get_u32(data, 0).ok_or_else(truncated)? as usize;
+    let mut cursor = 4;
+    let mut index = Vec::with_capacity(count.min(data.len() / 20));
+
+    for _ in 0..count {
+        let key_len = get_u32(data, cursor).ok_or_else(truncated)? as usize;
+        cursor += 4;
+        let key = slice(cursor, key_len)?;
+        cursor += key_len;
+        let offset = get_u64(data, cursor).ok_or_else(truncated)?;
+        let size = get_u64(data, cursor + 8).ok_or_else(truncated)?;
+        cursor += 16;
+
+        index.push((key, BlockHandle { offset, size }));
+    }
+
+    let mut inline = Vec::new();
+    if cursor < data.len() {
+        let count = get_u32(data, cursor).ok_or_else(truncated)? as usize;
+        cursor += 4;
+        inline.reserve(count.min(data.len() / 8));
+
+        for _ in 0..count {
+            let key_len = get_u32(data, cursor).ok_or_else(truncated)? as usize;
+            let key = slice(cursor + 4, key_len)?;
+            cursor += 4 + key_len;
+            let value_len = get_u32(data, cursor).ok_or_else(truncated)? as usize;
+            let value = slice(cursor + 4, value_len)?;
+            cursor += 4 + value_len;
+
+            inline.push((key, value));
+        }
+    }
+
+    Ok((index, inline))
+}
+
+/// value layout: [tag(1B)][seq(8B)][value]
+pub(crate) fn encode_value(seq: u64, entry_type: EntryType, value: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(VALUE_HEADER_SIZE + value.len());
+    encoded.push(entry_type.tag());
+    put_u64(&mut encoded, seq);
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+/// decode a value that leads with its tag, as in untyped blocks and the index
+pub(crate) fn decode_value(encoded: &[u8]) -> Result<(u64, StoredValue)> {
+    let (&tag, rest) = encoded
+        .split_first()
+        .ok_or_else(|| SSTableError::Corrupted("Truncated table value".to_string()))?;
+    let entry_type = EntryType::from_tag(tag)
+        .ok_or_else(|| SSTableError::Corrupted(format!("Unknown value tag: {}", tag)))?;
+    decode_typed_value(entry_type, rest)
+}
+
+/// decode the [seq(8B)][value] of an entry whose type is known
+pub(crate) fn decode_typed_value(
+    entry_type: EntryType,
+    encoded: &[u8],
+) -> Result<(u64, StoredValue)> {
+    let seq = get_u64(encoded, 0)
+        .ok_or_else(|| SSTableError::Corrupted("Truncated table value".to_string()))?;
+
+    let value = &encoded[TYPED_VALUE_HEADER_SIZE..];
+    match entry_type {
+        EntryType::Value => Ok((seq, StoredValue::Put(value.to_vec()))),
+        EntryType::Tombstone => Ok((seq, StoredValue::Delete)),
+        EntryType::SingleTombstone => Ok((seq, StoredValue::SingleDelete)),
+        EntryType::MergeOperand => match decode_operands(value) {
+            Some(operands) => Ok((seq, StoredValue::Merge(operands))),
+            None => Err(SSTableError::Corrupted("Truncated merge operands".to_string())),
+        },
+        other => Err(SSTableError::Corrupted(format!(
+            "{:?} entries are not supported by this reader",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::sstable::reader::decode_block;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_writer_layout() {
+        let dir = env::temp_dir().join("test_sstable_writer");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let value = vec![b'v'; 100];
+        let mut writer = SSTableWriter::create(&dir, Path::new("1.sst"), 1, 0, 16, 10).unwrap();
+        for i in 0..200 {
+            writer
+                .add(format!("key{:03}", i).as_bytes(), 500 - i, Some(&value))
+                .unwrap();
+        }
+        writer.add(b"key199", 1, None).unwrap();
+        let sst = writer.finish().unwrap();
+
+        let data = fs::read(dir.join("1.sst")).unwrap();
+        assert_eq!(sst.size, data.len() as u64);
+        assert_eq!(sst.num_entries, 201);
+        assert_eq!(sst.min_key, b"key000");
+        assert_eq!(sst.max_key, b"key199");
+
+        let footer = Footer::decode(&data).unwrap();
+        let (index, inline) = decode_index(footer.index.slice(&data).unwrap()).unwrap();
+        assert!(inline.is_empty());
+        assert!(index.len() > 1);
+        assert!(index.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(index.last().unwrap().0, b"key199");
+
+        // every block ends with the key the index names for it
+        for (last_key, handle) in &index {
+            let block = decode_block(handle.slice(&data).unwrap(), footer.version).unwrap();
+            let (key, _, _) = block.iter().last().unwrap().unwrap();
+            assert_eq!(&key, last_key);
+        }
+
+        let bloom_bytes = footer.bloom.slice(&data).unwrap();
+        let num_hashes = get_u32(bloom_bytes, 0).unwrap();
+        let bloom = BloomFilter::with_bytes(bloom_bytes[4..].to_vec(), num_hashes);
+        assert!(bloom.may_contain(b"key042"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_writer_rejects_unsorted_keys() {
+        let dir = env::temp_dir().join("test_sstable_writer_order");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut writer = SSTableWriter::create(&dir, Path::new("1.sst"), 1, 0, 16, 10).unwrap();
+        writer.add(b"b", 2, Some(b"2")).unwrap();
+        writer.add(b"b", 1, Some(b"1")).unwrap();
+        assert!(matches!(
+            writer.add(b"a", 3, None),
+            Err(SSTableError::OutOfOrder(_))
+        ));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_writer_flags_tombstone_only_tables() {
+        let dir = env::temp_dir().join("test_sstable_writer_tombstones");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut writer = SSTableWriter::create(&dir, Path::new("1.sst"), 1, 0, 16, 10).unwrap();
+        writer.add(b"a", 2, None).unwrap();
+        writer.add(b"b", 1, None).unwrap();
+        assert!(writer.finish().unwrap().tombstone_only);
+
+        let mut writer = SSTableWriter::create(&dir, Path::new("2.sst"), 2, 0, 16, 10).unwrap();
+        writer.add(b"a", 2, None).unwrap();
+        writer.add(b"b", 1, Some(b"1")).unwrap();
+        assert!(!writer.finish().unwrap().tombstone_only);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_footer_rejects_bad_magic() {
+        let footer = Footer {
+            index: BlockHandle { offset: 1, size: 2 },
+            bloom: BlockHandle { offset: 3, size: 4 },
+            version: TABLE_VERSION,
+        };
+        let mut bytes = footer.encode().to_vec();
+        assert_eq!(Footer::decode(&bytes).unwrap(), footer);
+
+        bytes[FOOTER_SIZE - 1] ^= 0xff;
+        assert!(Footer::decode(&bytes).is_err());
+        assert!(Footer::decode(&bytes[1..]).is_err());
+    }
+}
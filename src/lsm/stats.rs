@@ -0,0 +1,537 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// default precision: 32 linear sub-buckets per power of two, ~3% error
+const DEFAULT_PRECISION_BITS: u32 = 5;
+
+/// default largest tracked value: one hour in microseconds
+const DEFAULT_HIGHEST: u64 = 3_600_000_000;
+
+/// Histogram: lock-free HDR-style histogram for latencies and sizes
+///    - values below 2^precision_bits get a bucket each; above that every
+///      power of two is split into 2^precision_bits linear sub-buckets, so
+///      the relative error stays below 2^-precision_bits at any magnitude
+///    - memory is one counter per bucket: under 1000 buckets for an hour in
+///      microseconds at the default precision
+///    - values above `highest` are counted in the last bucket; min, max and
+///      sum stay exact
+///    - record() only does relaxed atomic adds, so one histogram can be shared
+///      by every thread; snapshot() copies it for percentile queries
+#[derive(Debug)]
+pub struct Histogram {
+    precision_bits: u32,
+    highest: u64,
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+/// point-in-time copy of a histogram
+/// - snapshots from histograms with the same bounds merge bucket by bucket;
+///   otherwise each bucket is re-recorded at its midpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    precision_bits: u32,
+    highest: u64,
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::with_bounds(DEFAULT_HIGHEST, DEFAULT_PRECISION_BITS)
+    }
+
+    /// track values up to `highest` with 2^`precision_bits` sub-buckets per
+    /// power of two; precision is clamped to 1..=16 bits
+    pub fn with_bounds(highest: u64, precision_bits: u32) -> Self {
+        let precision_bits = precision_bits.clamp(1, 16);
+        let highest = highest.max(1);
+        let num_buckets = bucket_index(highest, precision_bits) + 1;
+
+        Self {
+            precision_bits,
+            highest,
+            buckets: (0..num_buckets).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        let index = bucket_index(value.min(self.highest), self.precision_bits);
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.min.fetch_min(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// record a latency in microseconds
+    pub fn record_duration(&self, duration: Duration) {
+        self.record(duration.as_micros().min(u64::MAX as u128) as u64);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        self.collect(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn reset(&self) {
+        self.snapshot_and_reset();
+    }
+
+    /// snapshot and zero in one pass, so exporters see every value exactly
+    /// once across intervals even while other threads keep recording
+    pub fn snapshot_and_reset(&self) -> HistogramSnapshot {
+        let mut snapshot = self.collect(|counter| counter.swap(0, Ordering::Relaxed));
+        snapshot.min = self.min.swap(u64::MAX, Ordering::Relaxed);
+        snapshot.max = self.max.swap(0, Ordering::Relaxed);
+        snapshot
+    }
+
+    fn collect(&self, read: impl Fn(&AtomicU64) -> u64) -> HistogramSnapshot {
+        HistogramSnapshot {
+            precision_bits: self.precision_bits,
+            highest: self.highest,
+            buckets: self.buckets.iter().map(&read).collect(),
+            count: read(&self.count),
+            sum: read(&self.sum),
+            min: self.min.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// smallest recorded value, 0 when empty
+    pub fn min(&self) -> u64 {
+        if self.count == 0 { 0 } else { self.min }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// value at or below which `percentile` percent of the values fall,
+    /// reported as the top of its bucket; 0 when empty
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * self.count as f64).ceil() as u64;
+        let rank = rank.max(1);
+
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let (_, high) = bucket_range(index, self.precision_bits);
+                return high.clamp(self.min(), self.max);
+            }
+        }
+        self.max
+    }
+
+    /// fold another snapshot into this one, e.g. one histogram per thread
+    pub fn merge(&mut self, other: &HistogramSnapshot) {
+        if other.count == 0 {
+            return;
+        }
+
+        if (self.precision_bits, self.highest) == (other.precision_bits, other.highest) {
+            for (mine, theirs) in self.buckets.iter_mut().zip(&other.buckets) {
+                *mine += theirs;
+            }
+        } else {
+            for (index, &count) in other.buckets.iter().enumerate() {
+                if count == 0 {
+                    continue;
+                }
+                let (low, high) = bucket_range(index, other.precision_bits);
+                let value = (low + (high - low) / 2).min(self.highest);
+                self.buckets[bucket_index(value, self.precision_bits)] += count;
+            }
+        }
+
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// counters kept by Statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ticker {
+    /// keys and values handed to writes
+    BytesWritten,
+    /// puts, deletes, merges and range deletes written
+    KeysWritten,
+    /// bytes of the values gets found
+    BytesRead,
+    /// keys looked up by get and multi_get
+    KeysRead,
+    /// bloom filters consulted by point reads
+    BloomChecked,
+    /// bloom filter probes that ruled a table out without a block read
+    BloomUseful,
+    /// table reader lookups the table cache served
+    CacheHit,
+    /// table reader lookups that opened the file
+    CacheMiss,
+    WalBytesWritten,
+    FlushBytesWritten,
+    /// table bytes compactions read as inputs
+    CompactionBytesRead,
+    CompactionBytesWritten,
+    /// time writes waited for a flush because too many memtables were full
+    StallMicros,
+}
+
+const NUM_TICKERS: usize = Ticker::StallMicros as usize + 1;
+
+/// latencies kept by Statistics, in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Latency {
+    /// get and get_opt, from the call to the value
+    Get,
+    /// a write batch, from taking the write lock to returning
+    Write,
+    /// one memtable flush, including its manifest commit
+    Flush,
+    /// one compaction task
+    Compaction,
+}
+
+const NUM_LATENCIES: usize = Latency::Compaction as usize + 1;
+
+/// Statistics: opt-in counters and latency histograms for tuning a
+/// database, shared through LSMConfig::statistics
+///    - one Statistics can serve several databases, which then add up
+///    - counting is relaxed atomic adds, so recording never takes a lock
+///    - snapshot() copies everything into a StatisticsSnapshot, whose
+///      Display is a report for logs
+pub struct Statistics {
+    tickers: [AtomicU64; NUM_TICKERS],
+    latencies: [Histogram; NUM_LATENCIES],
+}
+
+/// point-in-time copy of a Statistics; latencies are in microseconds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatisticsSnapshot {
+    pub bytes_written: u64,
+    pub keys_written: u64,
+    pub bytes_read: u64,
+    pub keys_read: u64,
+    pub bloom_checked: u64,
+    pub bloom_useful: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub wal_bytes_written: u64,
+    pub flush_bytes_written: u64,
+    pub compaction_bytes_read: u64,
+    pub compaction_bytes_written: u64,
+    pub stall_micros: u64,
+
+    pub get_micros: HistogramSnapshot,
+    pub write_micros: HistogramSnapshot,
+    pub flush_micros: HistogramSnapshot,
+    pub compaction_micros: HistogramSnapshot,
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            tickers: std::array::from_fn(|_| AtomicU64::new(0)),
+            latencies: std::array::from_fn(|_| Histogram::new()),
+        }
+    }
+
+    pub fn add(&self, ticker: Ticker, value: u64) {
+        self.tickers[ticker as usize].fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn ticker(&self, ticker: Ticker) -> u64 {
+        self.tickers[ticker as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn record_latency(&self, latency: Latency, duration: Duration) {
+        self.latencies[latency as usize].record_duration(duration);
+    }
+
+    pub fn latency(&self, latency: Latency) -> HistogramSnapshot {
+        self.latencies[latency as usize].snapshot()
+    }
+
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        let ticker = |ticker| self.ticker(ticker);
+        let latency = |latency| self.latency(latency);
+        StatisticsSnapshot {
+            bytes_written: ticker(Ticker::BytesWritten),
+            keys_written: ticker(Ticker::KeysWritten),
+            bytes_read: ticker(Ticker::BytesRead),
+            keys_read: ticker(Ticker::KeysRead),
+            bloom_checked: ticker(Ticker::BloomChecked),
+            bloom_useful: ticker(Ticker::BloomUseful),
+            cache_hits: ticker(Ticker::CacheHit),
+            cache_misses: ticker(Ticker::CacheMiss),
+            wal_bytes_written: ticker(Ticker::WalBytesWritten),
+            flush_bytes_written: ticker(Ticker::FlushBytesWritten),
+            compaction_bytes_read: ticker(Ticker::CompactionBytesRead),
+            compaction_bytes_written: ticker(Ticker::CompactionBytesWritten),
+            stall_micros: ticker(Ticker::StallMicros),
+            get_micros: latency(Latency::Get),
+            write_micros: latency(Latency::Write),
+            flush_micros: latency(Latency::Flush),
+            compaction_micros: latency(Latency::Compaction),
+        }
+    }
+
+    /// zero every counter and histogram
+    pub fn reset(&self) {
+        for ticker in &self.tickers {
+            ticker.store(0, Ordering::Relaxed);
+        }
+        for histogram in &self.latencies {
+            histogram.reset();
+        }
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Statistics").finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for StatisticsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tickers = [
+            ("bytes.written", self.bytes_written),
+            ("keys.written", self.keys_written),
+            ("bytes.read", self.bytes_read),
+            ("keys.read", self.keys_read),
+            ("bloom.checked", self.bloom_checked),
+            ("bloom.useful", self.bloom_useful),
+            ("cache.hit", self.cache_hits),
+            ("cache.miss", self.cache_misses),
+            ("wal.bytes.written", self.wal_bytes_written),
+            ("flush.bytes.written", self.flush_bytes_written),
+            ("compaction.bytes.read", self.compaction_bytes_read),
+            ("compaction.bytes.written", self.compaction_bytes_written),
+            ("stall.micros", self.stall_micros),
+        ];
+        for (name, value) in tickers {
+            writeln!(f, "{:<26} {}", name, value)?;
+        }
+        let latencies = [
+            ("get.micros", &self.get_micros),
+            ("write.micros", &self.write_micros),
+            ("flush.micros", &self.flush_micros),
+            ("compaction.micros", &self.compaction_micros),
+        ];
+        for (name, histogram) in latencies {
+            writeln!(
+                f,
+                "{:<26} count {} p50 {} p95 {} p99 {} max {}",
+                name,
+                histogram.count(),
+                histogram.percentile(50.0),
+                histogram.percentile(95.0),
+                histogram.percentile(99.0),
+                histogram.max()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// bucket of `value`: linear below 2^precision_bits, then precision_bits of
+/// mantissa per power of two
+fn bucket_index(value: u64, precision_bits: u32) -> usize {
+    let sub_buckets = 1u64 << precision_bits;
+    if value < sub_buckets {
+        return value as usize;
+    }
+
+    let shift = (63 - value.leading_zeros()) - precision_bits;
+    let mantissa = value >> shift;
+    ((shift as u64) * sub_buckets + mantissa) as usize
+}
+
+/// smallest and largest value that land in bucket `index`
+fn bucket_range(index: usize, precision_bits: u32) -> (u64, u64) {
+    let sub_buckets = 1usize << precision_bits;
+    if index < sub_buckets {
+        return (index as u64, index as u64);
+    }
+
+    let shift = (index >> precision_bits) - 1;
+    let mantissa = (index - (shift << precision_bits)) as u64;
+    let low = mantissa << shift;
+    (low, low + ((1u64 << shift) - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_bucket_ranges_cover_every_value() {
+        for value in (0..5000).chain([u32::MAX as u64, DEFAULT_HIGHEST]) {
+            let index = bucket_index(value, 5);
+            let (low, high) = bucket_range(index, 5);
+            assert!(low <= value && value <= high, "value {}", value);
+            // relative error bounded by the precision
+            assert!((high - low) * 32 <= low.max(1), "value {}", value);
+        }
+        assert_eq!(bucket_index(63, 5) + 1, bucket_index(64, 5));
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let histogram = Histogram::new();
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 1000);
+        assert_eq!(snapshot.min(), 1);
+        assert_eq!(snapshot.max(), 1000);
+        assert_eq!(snapshot.mean(), 500.5);
+        for (percentile, expected) in [(50.0, 500), (99.0, 990), (100.0, 1000)] {
+            let found = snapshot.percentile(percentile);
+            assert!(
+                found.abs_diff(expected) <= expected / 32,
+                "p{}: {}",
+                percentile,
+                found
+            );
+        }
+
+        // out-of-range values are counted without growing the histogram
+        let small = Histogram::with_bounds(100, 3);
+        small.record(1_000_000);
+        assert_eq!(small.snapshot().percentile(50.0), 1_000_000);
+    }
+
+    #[test]
+    fn test_snapshot_and_reset_intervals() {
+        let histogram = Histogram::new();
+        histogram.record(10);
+        histogram.record(20);
+
+        let first = histogram.snapshot_and_reset();
+        assert_eq!(first.count(), 2);
+        assert_eq!(histogram.snapshot().count(), 0);
+        assert_eq!(histogram.snapshot().percentile(99.0), 0);
+
+        histogram.record(5);
+        let second = histogram.snapshot();
+        assert_eq!((second.count(), second.min(), second.max()), (1, 5, 5));
+
+        histogram.reset();
+        assert_eq!(histogram.snapshot().count(), 0);
+    }
+
+    #[test]
+    fn test_merge_across_threads() {
+        let histograms: Vec<Arc<Histogram>> = (0..4).map(|_| Arc::new(Histogram::new())).collect();
+        let handles: Vec<_> = histograms
+            .iter()
+            .enumerate()
+            .map(|(t, histogram)| {
+                let histogram = Arc::clone(histogram);
+                thread::spawn(move || {
+                    for value in 0..1000 {
+                        histogram.record(t as u64 * 1000 + value);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut merged = histograms[0].snapshot();
+        for histogram in &histograms[1..] {
+            merged.merge(&histogram.snapshot());
+        }
+        assert_eq!(merged.count(), 4000);
+        assert_eq!((merged.min(), merged.max()), (0, 3999));
+        assert!(merged.percentile(50.0).abs_diff(2000) <= 2000 / 32);
+
+        // different bounds re-bucket instead of adding counts blindly
+        let mut coarse = Histogram::with_bounds(10_000, 2).snapshot();
+        coarse.merge(&merged);
+        assert_eq!(coarse.count(), 4000);
+        assert!(coarse.percentile(50.0).abs_diff(2000) <= 2000 / 4);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let stats = Statistics::new();
+        stats.add(Ticker::BytesWritten, 100);
+        stats.add(Ticker::BytesWritten, 20);
+        stats.add(Ticker::CacheMiss, 1);
+        stats.record_latency(Latency::Get, Duration::from_micros(40));
+        stats.record_latency(Latency::Get, Duration::from_micros(60));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.bytes_written, 120);
+        assert_eq!(snapshot.cache_misses, 1);
+        assert_eq!(snapshot.cache_hits, 0);
+        assert_eq!(snapshot.get_micros.count(), 2);
+        assert_eq!(snapshot.get_micros.max(), 60);
+        assert_eq!(snapshot.flush_micros.count(), 0);
+
+        let report = snapshot.to_string();
+        assert!(report.contains("bytes.written"));
+        let get_line = report.lines().find(|line| line.starts_with("get.micros")).unwrap();
+        assert!(get_line.contains("count 2"));
+
+        stats.reset();
+        assert_eq!(stats.ticker(Ticker::BytesWritten), 0);
+        assert_eq!(stats.latency(Latency::Get).count(), 0);
+    }
+}
@@ -0,0 +1,387 @@
+//! Operator status page
+//! - DB::status() gathers the level layout, flush queue and background state,
+//!   over every column family and for each
+//! - StatusServer serves it over HTTP: `/status` as HTML, `/metrics` as JSON
+//! - meant to be spawned by a server binary next to its client listener;
+//!   it handles one request at a time and is not meant for public exposure
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::cache::CacheStats;
+use super::db::DB;
+
+/// longest request head the status server reads before giving up
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// point-in-time view of a database for operators
+#[derive(Debug, Clone, Serialize)]
+pub struct DbStatus {
+    pub path: PathBuf,
+
+    /// each level summed over every column family
+    pub levels: Vec<LevelStatus>,
+
+    /// bytes buffered in the active memtable
+    pub memtable_bytes: usize,
+
+    /// bytes in every table plus every memtable, as counted against the quota
+    pub disk_bytes: u64,
+
+    pub disk_quota: Option<u64>,
+
+    /// bytes compaction has to rewrite before every level of every column
+    /// family is within its limit
+    pub compaction_debt_bytes: u64,
+
+    /// the default family first, then the others oldest first
+    pub families: Vec<FamilyStatus>,
+
+    /// full memtables waiting for a flush
+    pub immutable_memtables: usize,
+
+    pub max_immutable_memtables: usize,
+
+    /// writers are blocked until the flush queue drains
+    pub write_stalled: bool,
+
+    pub active_compaction: Option<CompactionStatus>,
+
+    pub last_sequence: u64,
+
+    /// most recent flush and compaction failures, oldest first
+    pub background_errors: Vec<String>,
+
+    pub cache: CacheStats,
+}
+
+/// one column family's share of DbStatus
+#[derive(Debug, Clone, Serialize)]
+pub struct FamilyStatus {
+    pub id: u32,
+
+    /// None for the default family
+    pub name: Option<String>,
+
+    pub levels: Vec<LevelStatus>,
+
+    pub files: usize,
+
+    /// bytes in its tables plus its memtables, frozen ones included
+    pub disk_bytes: u64,
+
+    /// max_disk_bytes of its LSMConfig::column_families entry
+    pub disk_quota: Option<u64>,
+
+    pub compaction_debt_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LevelStatus {
+    pub level: usize,
+
+    pub files: usize,
+
+    pub bytes: u64,
+
+    pub entries: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompactionStatus {
+    pub reason: String,
+
+    pub level: usize,
+
+    pub output_level: usize,
+
+    pub input_files: usize,
+}
+
+/// write and read amplification, see DB::amplification_report
+#[derive(Debug, Clone, Serialize)]
+pub struct AmplificationReport {
+    /// key and value bytes written by the user since open
+    pub user_bytes: u64,
+
+    /// WAL, flush and compaction bytes written to disk since open
+    pub disk_bytes: u64,
+
+    /// disk_bytes / user_bytes, 0 before the first write
+    pub write_amplification: f64,
+
+    /// recent gets the read amplification averages over
+    pub gets: u64,
+
+    /// data blocks read per get, averaged over the recent gets
+    pub read_amplification: f64,
+}
+
+/// background thread answering status requests for one database
+/// - stops when dropped
+pub struct StatusServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl DbStatus {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut levels = String::new();
+        for level in &self.levels {
+            levels.push_str(&format!(
+                "<tr><td>L{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                level.level, level.files, level.bytes, level.entries
+            ));
+        }
+
+        let mut families = String::new();
+        for family in &self.families {
+            families.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}{}</td><td>{}</td></tr>\n",
+                escape_html(family.name.as_deref().unwrap_or("(default)")),
+                family.files,
+                family.disk_bytes,
+                match family.disk_quota {
+                    Some(quota) => format!(" of {}", quota),
+                    None => String::new(),
+                },
+                family.compaction_debt_bytes
+            ));
+        }
+
+        let compaction = match &self.active_compaction {
+            Some(c) => format!(
+                "{} compaction L{} -> L{} ({} input files)",
+                escape_html(&c.reason),
+                c.level,
+                c.output_level,
+                c.input_files
+            ),
+            None => "idle".to_string(),
+        };
+
+        let busiest: Vec<String> = self
+            .cache
+            .tables
+            .iter()
+            .take(5)
+            .map(|t| format!("#{} {}/{}", t.id, t.hits, t.hits + t.misses))
+            .collect();
+
+        let errors = if self.background_errors.is_empty() {
+            "<p>none</p>".to_string()
+        } else {
+            let items: String = self
+                .background_errors
+                .iter()
+                .map(|e| format!("<li>{}</li>", escape_html(e)))
+                .collect();
+            format!("<ul>{}</ul>", items)
+        };
+
+        format!(
+            "<!DOCTYPE html>\n<html><head><title>kvstore status</title></head><body>\n\
+             <h1>{}</h1>\n\
+             <p>last sequence {} &middot; memtable {} bytes &middot; \
+             flush queue {}/{}{}</p>\n\
+             <p>disk {} bytes{} &middot; compaction debt {} bytes</p>\n\
+             <p>compaction: {}</p>\n\
+             <p>table cache {}/{} bytes &middot; hit rate {:.1}% &middot; \
+             busiest tables (hits/lookups) {}</p>\n\
+             <table border=\"1\">\n<tr><th>level</th><th>files</th><th>bytes</th>\
+             <th>entries</th></tr>\n{}</table>\n\
+             <h2>column families</h2>\n\
+             <table border=\"1\">\n<tr><th>family</th><th>files</th><th>bytes</th>\
+             <th>compaction debt</th></tr>\n{}</table>\n\
+             <h2>background errors</h2>\n{}\n</body></html>\n",
+            escape_html(&self.path.display().to_string()),
+            self.last_sequence,
+            self.memtable_bytes,
+            self.immutable_memtables,
+            self.max_immutable_memtables,
+            if self.write_stalled {
+                " (writes stalled)"
+            } else {
+                ""
+            },
+            self.disk_bytes,
+            match self.disk_quota {
+                Some(quota) => format!(" of {}", quota),
+                None => String::new(),
+            },
+            self.compaction_debt_bytes,
+            compaction,
+            self.cache.usage,
+            self.cache.capacity,
+            self.cache.hit_rate() * 100.0,
+            if busiest.is_empty() {
+                "none".to_string()
+            } else {
+                busiest.join(", ")
+            },
+            levels,
+            families,
+            errors
+        )
+    }
+}
+
+impl StatusServer {
+    /// listen on `addr` (port 0 picks a free one) and serve `db`'s status
+    pub fn start(db: Arc<DB>, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("kvstore-status".to_string())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stop.load(Ordering::Acquire) {
+                            return;
+                        }
+                        if let Ok(stream) = stream {
+                            // a broken client only loses its own response
+                            let _ = handle_request(&db, stream);
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // wake the blocking accept so the thread sees the flag
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn handle_request(db: &DB, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_HEAD as u64);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // drain the headers; nothing in them changes the response
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/status" | "/") => ("200 OK", "text/html; charset=utf-8", db.status().to_html()),
+        ("GET", "/metrics") => ("200 OK", "application/json", db.status().to_json()),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "GET only\n".to_string(),
+        ),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::config::LSMConfig;
+    use std::env;
+    use std::fs;
+
+    fn fetch(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_status_server() {
+        let dir = env::temp_dir().join("test_status_server");
+        fs::remove_dir_all(&dir).ok();
+        let config = LSMConfig {
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        };
+        let db = Arc::new(DB::open(&dir, config).unwrap());
+        db.put(b"key", b"value").unwrap();
+        db.flush().unwrap();
+
+        let server = StatusServer::start(Arc::clone(&db), "127.0.0.1:0").unwrap();
+
+        let metrics = fetch(server.local_addr(), "/metrics");
+        assert!(metrics.starts_with("HTTP/1.1 200 OK"));
+        let body = &metrics[metrics.find("\r\n\r\n").unwrap() + 4..];
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["levels"][0]["files"], 1);
+        assert_eq!(json["last_sequence"], 1);
+        assert_eq!(json["write_stalled"], false);
+        assert_eq!(json["cache"]["capacity"], 4 * 1024 * 1024);
+
+        let html = fetch(server.local_addr(), "/status");
+        assert!(html.contains("text/html"));
+        assert!(html.contains("<td>L0</td><td>1</td>"));
+        assert!(html.contains("<td>(default)</td><td>1</td>"));
+
+        assert!(fetch(server.local_addr(), "/nope").starts_with("HTTP/1.1 404"));
+
+        drop(server);
+        drop(db);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,204 @@
+use super::db::{DB, Result};
+use super::wal::{WalEntry, WalReader, WalRecord};
+
+/// the internal column family consumer offsets are kept in, one key per
+/// consumer name; DB::list_cfs leaves it out
+pub(crate) const CONSUMER_FAMILY: &str = "__consumers";
+
+/// Subscription: a named consumer of the change feed, from DB::subscribe
+///    - yields every write logged after the consumer's last ack, in sequence
+///      order, as the WAL record holding it; a batch comes as one record
+///    - returns None once it has caught up; later calls go on with the
+///      writes logged since
+///    - ack(seq) records that everything up to `seq` was processed; the
+///      offset lives in an internal column family, so a consumer that
+///      restarts resumes right after it
+///    - flushed WAL segments are moved aside rather than deleted while a
+///      consumer hasn't acked every write in them; DB::unsubscribe lets go
+///      of a consumer that won't come back
+pub struct Subscription<'a> {
+    db: &'a DB,
+
+    name: String,
+
+    /// the family acks are written to; its records are not changes
+    family: u32,
+
+    /// every write up to here was yielded, skipped or acked before
+    position: u64,
+
+    /// number and reader of the segment being read
+    segment: Option<(u64, WalReader)>,
+}
+
+impl<'a> Subscription<'a> {
+    pub(crate) fn new(db: &'a DB, name: &str, family: u32, acked: u64) -> Self {
+        Self {
+            db,
+            name: name.to_string(),
+            family,
+            position: acked,
+            segment: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// see DB::ack
+    pub fn ack(&self, seq: u64) -> Result<()> {
+        self.db.ack(&self.name, seq)
+    }
+
+    fn next_record(&mut self) -> Result<Option<WalRecord>> {
+        loop {
+            let Some((number, reader)) = &mut self.segment else {
+                // start at the oldest segment and skip what was acked
+                match self.db.open_wal_segment(None)? {
+                    Some(segment) => self.segment = Some(segment),
+                    None => return Ok(None),
+                }
+                continue;
+            };
+
+            // the segment being written is only read up to its last record
+            let (active, written) = self.db.wal_written()?;
+            if (*number != active || reader.offset() < written)
+                && let Some((_, record)) = reader.next_with_offset()?
+            {
+                let last = record.last_seq().unwrap_or(0);
+                let internal = matches!(
+                    record.entry,
+                    WalEntry::Family { id, .. } if id == self.family
+                );
+                if last <= self.position || internal {
+                    self.position = self.position.max(last);
+                    continue;
+                }
+                self.position = last;
+                return Ok(Some(record));
+            }
+            if *number >= active {
+                return Ok(None);
+            }
+            match self.db.open_wal_segment(Some(*number))? {
+                Some(segment) => self.segment = Some(segment),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Iterator for Subscription<'_> {
+    type Item = Result<WalRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::config::LSMConfig;
+    use crate::lsm::db::DbError;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("event{:04}", i).into_bytes()
+    }
+
+    fn open(dir: &Path) -> DB {
+        let config = LSMConfig {
+            memtable_size: 1024,
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        };
+        DB::open(dir, config).unwrap()
+    }
+
+    /// keys and sequence numbers of the puts read
+    fn puts(subscription: &mut Subscription) -> Vec<(Vec<u8>, u64)> {
+        let records = subscription.map(|record| record.unwrap());
+        records
+            .map(|record| match record.entry {
+                WalEntry::Put { key, .. } => (key, record.seq.unwrap()),
+                entry => panic!("unexpected {:?}", entry),
+            })
+            .collect()
+    }
+
+    fn archived(dir: &Path) -> usize {
+        fs::read_dir(dir.join("archive")).map_or(0, |entries| entries.count())
+    }
+
+    #[test]
+    fn test_subscription_resumes_after_ack() {
+        let dir = env::temp_dir().join("test_db_subscription");
+        fs::remove_dir_all(&dir).ok();
+        let db = open(&dir);
+        db.put(b"before", b"v").unwrap();
+
+        // a new consumer starts after the writes so far
+        let mut feed = db.subscribe("indexer").unwrap();
+        for i in 0..10 {
+            db.put(&key(i), b"v").unwrap();
+        }
+        db.flush().unwrap();
+        for i in 10..20 {
+            db.put(&key(i), b"v").unwrap();
+        }
+        let read = puts(&mut feed);
+        let keys: Vec<_> = read.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, (0..20).map(key).collect::<Vec<_>>());
+        assert!(read.windows(2).all(|w| w[0].1 < w[1].1));
+        assert!(feed.next().is_none());
+
+        // flushed segments wait for the consumer instead of going away, up
+        // to the last one it acked all of
+        assert_eq!(archived(&dir), 1);
+        feed.ack(read[9].1).unwrap();
+        assert_eq!(archived(&dir), 0);
+        db.flush().unwrap();
+        assert_eq!(archived(&dir), 1);
+        assert_eq!(db.list_cfs(), Vec::<String>::new());
+        drop(feed);
+        db.close().unwrap();
+
+        // a restarted consumer goes on right after its ack; archived
+        // segments are never replayed
+        let db = open(&dir);
+        assert_eq!(db.get(&key(5)).unwrap(), Some(b"v".to_vec()));
+        let mut feed = db.subscribe("indexer").unwrap();
+        db.put(&key(20), b"v").unwrap();
+        let read = puts(&mut feed);
+        let keys: Vec<_> = read.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, (10..21).map(key).collect::<Vec<_>>());
+
+        // another consumer holds on to what it hasn't acked
+        let mut late = db.subscribe("audit").unwrap();
+        db.put(&key(21), b"v").unwrap();
+        db.flush().unwrap();
+        feed.ack(read[10].1).unwrap();
+        assert_eq!(archived(&dir), 1);
+        assert_eq!(puts(&mut late)[0].0, key(21));
+
+        // once nobody needs them, the segments go
+        assert!(db.unsubscribe("audit").unwrap());
+        assert!(!db.unsubscribe("audit").unwrap());
+        assert_eq!(archived(&dir), 1);
+        let read = puts(&mut feed);
+        assert_eq!(read[0].0, key(21));
+        feed.ack(read[0].1).unwrap();
+        assert_eq!(archived(&dir), 0);
+        assert!(matches!(db.ack("audit", 1), Err(DbError::NoSuchConsumer(_))));
+
+        drop((feed, late));
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,156 @@
+use std::ops::Bound;
+
+use super::db::{DB, Result, ScanState};
+use super::iterator::{MergeIterator, below_upper};
+
+/// TailingIterator: a scan that keeps up with writes, from DB::tailing_iter
+///    - reads the latest data rather than a snapshot
+///    - returns None once it has caught up; after refresh() it goes on with
+///      the keys written since that sort after the last one it returned
+///    - a refresh reads only the memtables again while no flush, compaction
+///      or range deletion has changed what the tables under the scan hold;
+///      otherwise it rebuilds the scan from the last key returned
+pub struct TailingIterator<'a> {
+    db: &'a DB,
+
+    family: u32,
+
+    lower: Bound<Vec<u8>>,
+
+    upper: Bound<Vec<u8>>,
+
+    inner: MergeIterator,
+
+    state: ScanState,
+
+    /// last key read, deleted or not
+    last_key: Option<Vec<u8>>,
+
+    /// the scan passed its upper bound, so no later write is in range
+    done: bool,
+}
+
+impl<'a> TailingIterator<'a> {
+    pub(crate) fn new(
+        db: &'a DB,
+        family: u32,
+        lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
+    ) -> Result<Self> {
+        let (inner, state) = db.tail_in(family, (&lower, &upper))?;
+        Ok(Self {
+            db,
+            family,
+            lower,
+            upper,
+            inner,
+            state,
+            last_key: None,
+            done: false,
+        })
+    }
+
+    /// let the iterator see what was written since it was created or last
+    /// refreshed
+    pub fn refresh(&mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        let lower = match &self.last_key {
+            Some(key) => Bound::Excluded(key.clone()),
+            None => self.lower.clone(),
+        };
+        let bounds = (&lower, &self.upper);
+        match self
+            .db
+            .tail_memtables_in(self.family, bounds, &self.state)?
+        {
+            Some(memtables) => self.inner.replace_sources(memtables),
+            None => (self.inner, self.state) = self.db.tail_in(self.family, bounds)?,
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for TailingIterator<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let (key, value) = match self.inner.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            if !below_upper(&key, &self.upper) {
+                self.done = true;
+                return None;
+            }
+            self.last_key = Some(key.clone());
+            if let Some(value) = value {
+                return Some(Ok((key, value)));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lsm::config::LSMConfig;
+    use crate::lsm::db::DB;
+    use std::env;
+    use std::fs;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("event{:04}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_tailing_iter() {
+        let dir = env::temp_dir().join("test_db_tailing_iter");
+        fs::remove_dir_all(&dir).ok();
+        let config = LSMConfig {
+            memtable_size: 1024,
+            auto_compaction: false,
+            background_flush: false,
+            ..LSMConfig::default()
+        };
+        let db = DB::open(&dir, config).unwrap();
+        for i in 0..100 {
+            db.put(&key(i), b"v").unwrap();
+        }
+        db.flush().unwrap();
+        db.put(&key(100), b"v").unwrap();
+
+        let mut tail = db.tailing_iter(key(0)..key(1000)).unwrap();
+        assert_eq!(tail.by_ref().count(), 101);
+        assert!(tail.next().is_none());
+
+        // new keys past the last one show up after a refresh, earlier ones don't
+        db.put(&key(101), b"new").unwrap();
+        db.put(&key(102), b"new").unwrap();
+        db.put(&key(50), b"rewritten").unwrap();
+        assert!(tail.next().is_none());
+        tail.refresh().unwrap();
+        let keys: Vec<_> = tail.by_ref().map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, vec![key(101), key(102)]);
+
+        // a flush moves the memtable into a table, which a refresh picks up
+        db.put(&key(103), b"new").unwrap();
+        db.flush().unwrap();
+        db.put(&key(104), b"new").unwrap();
+        db.delete_range(&key(104), &key(105)).unwrap();
+        db.put(&key(105), b"new").unwrap();
+        tail.refresh().unwrap();
+        let keys: Vec<_> = tail.by_ref().map(|r| r.unwrap().0).collect();
+        assert_eq!(keys, vec![key(103), key(105)]);
+
+        // nothing past the upper bound, even after a refresh
+        db.put(&key(2000), b"out of range").unwrap();
+        tail.refresh().unwrap();
+        assert!(tail.next().is_none());
+
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use super::batch::{BatchOp, WriteBatch};
+use super::merge::StoredValue;
+use crate::format::TIMESTAMP_SIZE;
+
+/// `value` followed by its write time, as LSMConfig::ttl stores it
+pub(crate) fn stamp(value: &[u8], now: u64) -> Vec<u8> {
+    let mut stamped = Vec::with_capacity(value.len() + TIMESTAMP_SIZE);
+    stamped.extend_from_slice(value);
+    stamped.extend_from_slice(&now.to_le_bytes());
+    stamped
+}
+
+/// the write time `stamp` gave `value`; None if it is too short to have one
+pub(crate) fn stamp_of(value: &[u8]) -> Option<u64> {
+    let at = value.len().checked_sub(TIMESTAMP_SIZE)?;
+    Some(u64::from_le_bytes(value[at..].try_into().ok()?))
+}
+
+/// `ops` with every value and merge operand stamped with `now`
+pub(crate) fn stamp_batch<'a>(ops: impl Iterator<Item = BatchOp<'a>>, now: u64) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    for op in ops {
+        match op {
+            BatchOp::Put { key, value } => batch.put(key, &stamp(value, now)),
+            BatchOp::Delete { key } => batch.delete(key),
+            BatchOp::SingleDelete { key } => batch.single_delete(key),
+            BatchOp::Merge { key, value } => batch.merge(key, &stamp(value, now)),
+            BatchOp::DeleteRange { start, end } => batch.delete_range(start, end),
+        }
+    }
+    batch
+}
+
+/// Expiry: which stamped versions have outlived the TTL as of one moment
+///    - a version expires once it is more than `ttl` whole seconds old, so
+///      it lives at least as long as the TTL asks
+///    - an expired put reads as a delete, expired merge operands as never
+///      written; values too short to carry a stamp count as expired
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Expiry {
+    ttl: u64,
+
+    now: u64,
+}
+
+impl Expiry {
+    /// None without a TTL; sub-second TTLs round up to a second
+    pub fn new(ttl: Option<Duration>, now: u64) -> Option<Self> {
+        let ttl = ttl?;
+        let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        Some(Self { ttl: secs, now })
+    }
+
+    pub fn is_expired(&self, value: &[u8]) -> bool {
+        stamp_of(value).is_none_or(|at| self.now.saturating_sub(at) > self.ttl)
+    }
+
+    /// `value` as of now: expired puts become deletes and expired operands
+    /// are dropped; stamps stay on what is left
+    pub fn expire(&self, value: StoredValue) -> StoredValue {
+        match value {
+            StoredValue::Put(value) if self.is_expired(&value) => StoredValue::Delete,
+            StoredValue::Merge(mut operands) => {
+                operands.retain(|op| !self.is_expired(op));
+                StoredValue::Merge(operands)
+            }
+            value => value,
+        }
+    }
+}
+
+/// `value` without the stamp at its end
+pub(crate) fn unstamp(value: &[u8]) -> &[u8] {
+    &value[..value.len().saturating_sub(TIMESTAMP_SIZE)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::config::LSMConfig;
+    use crate::lsm::db::DB;
+    use crate::lsm::merge::U64AddOperator;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_expire_versions() {
+        let expiry = Expiry::new(Some(Duration::from_millis(1500)), 1000).unwrap();
+        let fresh = stamp(b"value", 998);
+        let stale = stamp(b"value", 997);
+        assert_eq!(stamp_of(&fresh), Some(998));
+        assert_eq!(unstamp(&fresh), b"value");
+        assert!(!expiry.is_expired(&fresh));
+        assert!(expiry.is_expired(&stale));
+        assert!(expiry.is_expired(b"short"));
+
+        assert_eq!(
+            expiry.expire(StoredValue::Put(stale.clone())),
+            StoredValue::Delete
+        );
+        assert_eq!(
+            expiry.expire(StoredValue::Put(fresh.clone())),
+            StoredValue::Put(fresh.clone())
+        );
+        assert_eq!(
+            expiry.expire(StoredValue::Merge(vec![stale, fresh.clone()])),
+            StoredValue::Merge(vec![fresh])
+        );
+        assert!(Expiry::new(None, 1000).is_none());
+    }
+
+    #[test]
+    fn test_db_ttl() {
+        let dir = env::temp_dir().join("test_db_ttl");
+        fs::remove_dir_all(&dir).ok();
+        let config = LSMConfig {
+            auto_compaction: false,
+            background_flush: false,
+            ttl: Some(Duration::from_secs(1)),
+            merge_operator: Some(Arc::new(U64AddOperator)),
+            ..LSMConfig::default()
+        };
+        let count = |n: u64| Some(n.to_le_bytes().to_vec());
+
+        let db = DB::open(&dir, config.clone()).unwrap();
+        db.put(b"flushed", b"old").unwrap();
+        db.merge(b"hits", &1u64.to_le_bytes()).unwrap();
+        db.flush().unwrap();
+        db.put(b"memtable", b"old").unwrap();
+        assert_eq!(db.get(b"flushed").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(b"hits").unwrap(), count(1));
+
+        // stamps count whole seconds, so two of them always pass the TTL
+        thread::sleep(Duration::from_millis(2100));
+        db.put(b"fresh", b"new").unwrap();
+        db.merge(b"hits", &2u64.to_le_bytes()).unwrap();
+        assert_eq!(db.get(b"flushed").unwrap(), None);
+        assert_eq!(db.get(b"memtable").unwrap(), None);
+        assert_eq!(db.get(b"hits").unwrap(), count(2));
+        let entries: Vec<_> = db.iter().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"fresh".to_vec(), b"new".to_vec()),
+                (b"hits".to_vec(), count(2).unwrap())
+            ]
+        );
+
+        // compaction drops the expired versions for good
+        db.compact_range::<&[u8]>(..).unwrap();
+        assert_eq!(db.get(b"hits").unwrap(), count(2));
+        db.close().unwrap();
+
+        let db = DB::open(&dir, config.clone()).unwrap();
+        assert_eq!(db.get(b"fresh").unwrap(), Some(b"new".to_vec()));
+        let entries = db
+            .status()
+            .levels
+            .iter()
+            .map(|level| level.entries)
+            .sum::<u64>();
+        assert_eq!(entries, 2);
+        db.close().unwrap();
+        fs::remove_dir_all(&dir).ok();
+    }
+}
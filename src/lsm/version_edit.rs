@@ -0,0 +1,750 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::manifest::{Manifest, ManifestError, PrefixFilter, Result, SSTableMetadata, sync_dir};
+use super::sstable::BloomFilter;
+use crate::format::{
+    EDIT_ADD_FILE, EDIT_CREATE_FAMILY, EDIT_DELETE_FILE, EDIT_DROP_FAMILY, EDIT_FAMILY,
+    EDIT_FILE_PREFIX_FILTER, EDIT_FILE_TOMBSTONE_ONLY, EDIT_LAST_SEQUENCE, EDIT_NEXT_FAMILY,
+    EDIT_NEXT_FILE, EDIT_NUM_LEVELS, EDIT_VERSION, EDIT_WAL_SEQ,
+    MANIFEST_RECORD_HEADER_SIZE, crc32, get_u32, get_u64, put_u32, put_u64,
+};
+
+/// most levels a log may declare; anything more is a damaged record
+const MAX_NUM_LEVELS: usize = 64;
+
+/// names the live manifest log of a database directory
+pub const CURRENT_FILE: &str = "CURRENT";
+
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+
+/// VersionEdit: one change to the manifest, as logged in the manifest log
+///    - files added and deleted, plus the counters as of the change
+///    - a log starts with an edit holding the whole manifest; replaying
+///      every edit after it in order rebuilds the current one
+#[derive(Debug, Clone, Default)]
+pub struct VersionEdit {
+    /// only set in the first edit of a log
+    pub num_levels: Option<usize>,
+
+    pub version: Option<u64>,
+
+    pub next_sstable_id: Option<u64>,
+
+    pub wal_seq: Option<u64>,
+
+    pub last_sequence: Option<u64>,
+
+    pub next_family_id: Option<u32>,
+
+    /// (level, id) of every file deleted
+    pub deleted: Vec<(usize, u64)>,
+
+    /// added after the deletions, so a file can move between levels
+    pub added: Vec<SSTableMetadata>,
+
+    /// changes to column families other than the default one, applied
+    /// after the default family's files
+    pub families: Vec<FamilyEdit>,
+}
+
+/// one column family's part of a VersionEdit
+#[derive(Debug, Clone, Default)]
+pub struct FamilyEdit {
+    pub id: u32,
+
+    /// name and number of levels of a family this edit creates
+    pub created: Option<(String, usize)>,
+
+    pub deleted: Vec<(usize, u64)>,
+
+    pub added: Vec<SSTableMetadata>,
+
+    /// applied last, after the family's files
+    pub dropped: bool,
+}
+
+/// ManifestLog: append-only manifest of checksummed version edits
+///    - a change costs one small append and sync, however many files the
+///      database holds, instead of rewriting the whole manifest
+///    - a record torn by a crash is dropped on open; a damaged record with
+///      more after it is corruption
+///    - a database keeps numbered logs named by its CURRENT file; once one
+///      gathers `max_size` bytes of edits, commit starts the next one from a
+///      snapshot and switches CURRENT over, so a crash at any point leaves
+///      CURRENT naming a complete log
+pub struct ManifestLog {
+    path: PathBuf,
+    file: File,
+
+    /// bytes of whole records, where the next one goes
+    len: u64,
+
+    /// bytes of the snapshot the log starts with
+    snapshot_len: u64,
+
+    /// edit bytes after the snapshot before commit rotates, if it does
+    max_size: Option<u64>,
+}
+
+impl VersionEdit {
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty() && self.added.is_empty() && self.families.is_empty()
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        if let Some(levels) = self.num_levels {
+            buf.push(EDIT_NUM_LEVELS);
+            put_u32(buf, levels as u32);
+        }
+        let counters = [
+            (EDIT_VERSION, self.version),
+            (EDIT_NEXT_FILE, self.next_sstable_id),
+            (EDIT_WAL_SEQ, self.wal_seq),
+            (EDIT_LAST_SEQUENCE, self.last_sequence),
+        ];
+        for (tag, value) in counters {
+            if let Some(value) = value {
+                buf.push(tag);
+                put_u64(buf, value);
+            }
+        }
+        if let Some(id) = self.next_family_id {
+            buf.push(EDIT_NEXT_FAMILY);
+            put_u32(buf, id);
+        }
+        encode_files(buf, &self.deleted, &self.added);
+
+        for family in &self.families {
+            if let Some((name, levels)) = &family.created {
+                buf.push(EDIT_CREATE_FAMILY);
+                put_u32(buf, family.id);
+                put_u32(buf, *levels as u32);
+                put_u32(buf, name.len() as u32);
+                buf.extend_from_slice(name.as_bytes());
+            }
+            if !family.deleted.is_empty() || !family.added.is_empty() {
+                buf.push(EDIT_FAMILY);
+                put_u32(buf, family.id);
+                encode_files(buf, &family.deleted, &family.added);
+            }
+            if family.dropped {
+                buf.push(EDIT_DROP_FAMILY);
+                put_u32(buf, family.id);
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut edit = VersionEdit::default();
+        let mut reader = Reader { data, pos: 0 };
+        // files belong to the default family until an EDIT_FAMILY names another
+        let mut family: Option<usize> = None;
+        while reader.pos < data.len() {
+            match reader.u8()? {
+                EDIT_NUM_LEVELS => edit.num_levels = Some(reader.u32()? as usize),
+                EDIT_VERSION => edit.version = Some(reader.u64()?),
+                EDIT_NEXT_FILE => edit.next_sstable_id = Some(reader.u64()?),
+                EDIT_WAL_SEQ => edit.wal_seq = Some(reader.u64()?),
+                EDIT_LAST_SEQUENCE => edit.last_sequence = Some(reader.u64()?),
+                EDIT_NEXT_FAMILY => edit.next_family_id = Some(reader.u32()?),
+                EDIT_DELETE_FILE => {
+                    let level = reader.u32()? as usize;
+                    let deleted = (level, reader.u64()?);
+                    match family {
+                        Some(i) => edit.families[i].deleted.push(deleted),
+                        None => edit.deleted.push(deleted),
+                    }
+                }
+                EDIT_ADD_FILE => {
+                    let added = decode_file(&mut reader)?;
+                    match family {
+                        Some(i) => edit.families[i].added.push(added),
+                        None => edit.added.push(added),
+                    }
+                }
+                EDIT_CREATE_FAMILY => {
+                    let id = reader.u32()?;
+                    let levels = reader.u32()? as usize;
+                    let name = String::from_utf8(reader.bytes()?.to_vec()).map_err(|_| {
+                        ManifestError::Corrupted(format!("Name of family {} is not UTF-8", id))
+                    })?;
+                    edit.family_edit(id).created = Some((name, levels));
+                }
+                EDIT_FAMILY => {
+                    let id = reader.u32()?;
+                    edit.family_edit(id);
+                    family = edit.families.iter().position(|f| f.id == id);
+                }
+                EDIT_DROP_FAMILY => {
+                    let id = reader.u32()?;
+                    edit.family_edit(id).dropped = true;
+                }
+                tag => {
+                    return Err(ManifestError::Corrupted(format!(
+                        "Unknown version edit field {:#04x}",
+                        tag
+                    )));
+                }
+            }
+        }
+        Ok(edit)
+    }
+
+    /// the part of the edit for family `id`, added if there is none yet
+    pub fn family_edit(&mut self, id: u32) -> &mut FamilyEdit {
+        let i = match self.families.iter().position(|family| family.id == id) {
+            Some(i) => i,
+            None => {
+                self.families.push(FamilyEdit {
+                    id,
+                    ..FamilyEdit::default()
+                });
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[i]
+    }
+}
+
+impl ManifestLog {
+    /// start a log at `path` holding `manifest`, replacing any log there
+    ///
+    /// written to a temp file first, so a crash leaves the old log intact
+    pub fn create(path: impl AsRef<Path>, manifest: &Manifest) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let temp_path = path.with_extension("tmp");
+
+        let mut record = Vec::new();
+        encode_record(&mut record, &manifest.snapshot_edit());
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&record)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp_path, &path)?;
+        if let Some(parent) = path.parent() {
+            sync_dir(parent)?;
+        }
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            len: record.len() as u64,
+            snapshot_len: record.len() as u64,
+            max_size: None,
+        })
+    }
+
+    /// start log `number` in `dir` holding `manifest` and point CURRENT at it
+    pub fn create_current(dir: impl AsRef<Path>, number: u64, manifest: &Manifest) -> Result<Self> {
+        let dir = dir.as_ref();
+        let name = manifest_file_name(number);
+        let log = Self::create(dir.join(&name), manifest)?;
+        set_current(dir, &name)?;
+        Ok(log)
+    }
+
+    /// replay the log CURRENT names in `dir`; None if there is no CURRENT
+    pub fn open_current(dir: impl AsRef<Path>) -> Result<Option<(Manifest, Self)>> {
+        match current_manifest(dir)? {
+            Some(path) => Self::open(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// rotate to a fresh log once `bytes` of edits follow the snapshot
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// the manifest the log at `path` holds, leaving the file as it is
+    pub fn read(path: impl AsRef<Path>) -> Result<Manifest> {
+        let data = fs::read(path)?;
+        Ok(replay(&data)?.0)
+    }
+
+    /// replay the log at `path`, dropping a torn last record
+    pub fn open(path: impl AsRef<Path>) -> Result<(Manifest, Self)> {
+        let path = path.as_ref().to_path_buf();
+        let data = fs::read(&path)?;
+        let (manifest, len) = replay(&data)?;
+        // replay found at least the snapshot record
+        let snapshot_len = MANIFEST_RECORD_HEADER_SIZE as u64 + get_u32(&data, 4).unwrap() as u64;
+
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(len as u64)?;
+        drop(file);
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        let log = Self {
+            path,
+            file,
+            len: len as u64,
+            snapshot_len,
+            max_size: None,
+        };
+        Ok((manifest, log))
+    }
+
+    /// log the manifest's pending changes and counters, then sync
+    ///
+    /// rotates afterwards if the log has outgrown its max size
+    pub fn commit(&mut self, manifest: &mut Manifest) -> Result<()> {
+        self.append(&manifest.pending_edit())?;
+        manifest.clear_pending();
+        match self.max_size {
+            Some(max) if self.len - self.snapshot_len >= max => self.rotate(manifest),
+            _ => Ok(()),
+        }
+    }
+
+    /// continue in the next numbered log, which starts from a snapshot of
+    /// `manifest`, point CURRENT at it and delete this one
+    ///
+    /// `manifest` must have nothing pending, or those changes are lost
+    pub fn rotate(&mut self, manifest: &Manifest) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let number = self.number().map_or(1, |number| number + 1);
+        let mut next = Self::create_current(&dir, number, manifest)?;
+        next.max_size = self.max_size;
+
+        let old = std::mem::replace(self, next);
+        drop(old.file);
+        // CURRENT no longer names it; a failed delete only wastes space
+        fs::remove_file(&old.path).ok();
+        Ok(())
+    }
+
+    /// the number in the log's file name, if it is a numbered log
+    pub fn number(&self) -> Option<u64> {
+        let name = self.path.file_name()?.to_str()?;
+        parse_manifest_file_name(name)
+    }
+
+    pub fn append(&mut self, edit: &VersionEdit) -> Result<()> {
+        let mut record = Vec::new();
+        encode_record(&mut record, edit);
+
+        let written = self
+            .file
+            .write_all(&record)
+            .and_then(|()| self.file.sync_data());
+        if let Err(e) = written {
+            // don't leave half a record for the next one to land behind
+            let _ = self.file.set_len(self.len);
+            return Err(e.into());
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// bytes in the log
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+pub fn manifest_file_name(number: u64) -> String {
+    format!("{}{:06}", MANIFEST_PREFIX, number)
+}
+
+/// the number of a numbered manifest log's file name
+pub(crate) fn parse_manifest_file_name(name: &str) -> Option<u64> {
+    let digits = name.strip_prefix(MANIFEST_PREFIX)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// path of the manifest log CURRENT names in `dir`; None if there is no CURRENT
+pub fn current_manifest(dir: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+    let dir = dir.as_ref();
+    let contents = match fs::read_to_string(dir.join(CURRENT_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let name = contents.strip_suffix('\n').unwrap_or(&contents);
+    if parse_manifest_file_name(name).is_none() {
+        return Err(ManifestError::Corrupted(format!(
+            "CURRENT names {:?}, not a manifest log",
+            name
+        )));
+    }
+    Ok(Some(dir.join(name)))
+}
+
+/// point CURRENT in `dir` at the log `name`, replacing it atomically
+fn set_current(dir: &Path, name: &str) -> Result<()> {
+    let temp_path = dir.join(format!("{}.tmp", CURRENT_FILE));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(format!("{}\n", name).as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp_path, dir.join(CURRENT_FILE))?;
+    sync_dir(dir)
+}
+
+/// rebuild a manifest from the bytes of a log; returns it with the length
+/// of the records that made it
+pub(crate) fn replay(data: &[u8]) -> Result<(Manifest, usize)> {
+    let mut manifest: Option<Manifest> = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let (Some(checksum), Some(len)) = (get_u32(data, pos), get_u32(data, pos + 4)) else {
+            break;
+        };
+        let start = pos + MANIFEST_RECORD_HEADER_SIZE;
+        let Some(payload) = data.get(start..start.saturating_add(len as usize)) else {
+            break;
+        };
+        if crc32(payload) != checksum {
+            // the last record may have been cut short mid-write
+            if start + payload.len() == data.len() {
+                break;
+            }
+            return Err(ManifestError::Corrupted(format!(
+                "Checksum mismatch in manifest record at offset {}",
+                pos
+            )));
+        }
+
+        let edit = VersionEdit::decode(payload)?;
+        match &mut manifest {
+            Some(manifest) => manifest.apply(&edit)?,
+            None => {
+                let valid = |levels: &usize| (1..=MAX_NUM_LEVELS).contains(levels);
+                let Some(levels) = edit.num_levels.filter(valid) else {
+                    return Err(ManifestError::Corrupted(
+                        "Manifest log does not start with a snapshot".to_string(),
+                    ));
+                };
+                let mut base = Manifest::new(levels);
+                base.apply(&edit)?;
+                manifest = Some(base);
+            }
+        }
+        pos = start + payload.len();
+    }
+
+    let manifest =
+        manifest.ok_or_else(|| ManifestError::Corrupted("Manifest log is empty".to_string()))?;
+    Ok((manifest, pos))
+}
+
+fn encode_record(buf: &mut Vec<u8>, edit: &VersionEdit) {
+    let mut payload = Vec::new();
+    edit.encode(&mut payload);
+    put_u32(buf, crc32(&payload));
+    put_u32(buf, payload.len() as u32);
+    buf.extend_from_slice(&payload);
+}
+
+fn encode_files(buf: &mut Vec<u8>, deleted: &[(usize, u64)], added: &[SSTableMetadata]) {
+    for &(level, id) in deleted {
+        buf.push(EDIT_DELETE_FILE);
+        put_u32(buf, level as u32);
+        put_u64(buf, id);
+    }
+    for sst in added {
+        buf.push(EDIT_ADD_FILE);
+        encode_file(buf, sst);
+    }
+}
+
+fn encode_file(buf: &mut Vec<u8>, sst: &SSTableMetadata) {
+    put_u32(buf, sst.level as u32);
+    put_u64(buf, sst.id);
+    put_u64(buf, sst.size);
+    put_u64(buf, sst.num_entries);
+    put_u64(buf, sst.created_at);
+
+    let mut flags = 0;
+    if sst.tombstone_only {
+        flags |= EDIT_FILE_TOMBSTONE_ONLY;
+    }
+    if sst.prefix_filter.is_some() {
+        flags |= EDIT_FILE_PREFIX_FILTER;
+    }
+    buf.push(flags);
+
+    let path = sst.path.to_string_lossy();
+    for bytes in [path.as_bytes(), &sst.min_key, &sst.max_key] {
+        put_u32(buf, bytes.len() as u32);
+        buf.extend_from_slice(bytes);
+    }
+
+    if let Some(prefix) = &sst.prefix_filter {
+        put_u32(buf, prefix.prefix_len as u32);
+        put_u32(buf, prefix.filter.num_hashes());
+        put_u32(buf, prefix.filter.as_bytes().len() as u32);
+        buf.extend_from_slice(prefix.filter.as_bytes());
+    }
+}
+
+fn decode_file(reader: &mut Reader) -> Result<SSTableMetadata> {
+    let level = reader.u32()? as usize;
+    let id = reader.u64()?;
+    let size = reader.u64()?;
+    let num_entries = reader.u64()?;
+    let created_at = reader.u64()?;
+    let flags = reader.u8()?;
+    let path = String::from_utf8(reader.bytes()?.to_vec())
+        .map_err(|_| ManifestError::Corrupted(format!("Path of table {} is not UTF-8", id)))?;
+    let min_key = reader.bytes()?.to_vec();
+    let max_key = reader.bytes()?.to_vec();
+
+    let prefix_filter = if flags & EDIT_FILE_PREFIX_FILTER != 0 {
+        let prefix_len = reader.u32()? as usize;
+        let num_hashes = reader.u32()?;
+        let filter = BloomFilter::with_bytes(reader.bytes()?.to_vec(), num_hashes);
+        Some(PrefixFilter { prefix_len, filter })
+    } else {
+        None
+    };
+
+    Ok(SSTableMetadata {
+        id,
+        level,
+        path: PathBuf::from(path),
+        size,
+        num_entries,
+        min_key,
+        max_key,
+        created_at,
+        tombstone_only: flags & EDIT_FILE_TOMBSTONE_ONLY != 0,
+        prefix_filter,
+    })
+}
+
+/// cursor over an edit's fields
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| ManifestError::Corrupted("Truncated version edit".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(get_u32(self.take(4)?, 0).unwrap())
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(get_u64(self.take(8)?, 0).unwrap())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::manifest::Level;
+    use std::env;
+
+    fn sst(id: u64, level: usize, min: &[u8], max: &[u8]) -> SSTableMetadata {
+        SSTableMetadata {
+            id,
+            level,
+            path: PathBuf::from(format!("{:06}.sst", id)),
+            size: 100 * id,
+            num_entries: id,
+            min_key: min.to_vec(),
+            max_key: max.to_vec(),
+            created_at: 1_700_000_000 + id,
+            tombstone_only: id.is_multiple_of(2),
+            prefix_filter: None,
+        }
+    }
+
+    fn files(manifest: &Manifest) -> Vec<Vec<u64>> {
+        let ids = |level: &Level| level.sstables.iter().map(|sst| sst.id).collect();
+        manifest.levels.iter().map(ids).collect()
+    }
+
+    #[test]
+    fn test_edit_roundtrip() {
+        let keys: [&[u8]; 2] = [b"ab1", b"cd2"];
+        let mut with_filter = sst(3, 1, b"ab1", b"cd2");
+        with_filter.prefix_filter = PrefixFilter::build(keys, 2, 10);
+
+        let edit = VersionEdit {
+            num_levels: Some(4),
+            version: Some(9),
+            next_sstable_id: Some(12),
+            wal_seq: Some(5),
+            last_sequence: Some(77),
+            deleted: vec![(0, 1), (1, 2)],
+            added: vec![sst(2, 0, b"a", b"z"), with_filter],
+            ..VersionEdit::default()
+        };
+        let mut buf = Vec::new();
+        edit.encode(&mut buf);
+        let decoded = VersionEdit::decode(&buf).unwrap();
+        assert_eq!(decoded.deleted, edit.deleted);
+        assert_eq!(decoded.num_levels, Some(4));
+        assert_eq!(format!("{:?}", decoded.added), format!("{:?}", edit.added));
+
+        assert!(VersionEdit::decode(&buf[..buf.len() - 1]).is_err());
+        assert!(VersionEdit::decode(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn test_log_replay() {
+        let path = env::temp_dir().join("test_manifest_log");
+        fs::remove_file(&path).ok();
+
+        let mut manifest = Manifest::new(3);
+        manifest.add_sstable(0, sst(1, 0, b"a", b"m"));
+        let mut log = ManifestLog::create(&path, &manifest).unwrap();
+
+        manifest.add_sstable(0, sst(2, 0, b"k", b"z"));
+        manifest.last_sequence = 40;
+        log.commit(&mut manifest).unwrap();
+        let moved = SSTableMetadata {
+            level: 1,
+            ..manifest.get_level(0)[0].clone()
+        };
+        let removed = manifest.get_level(0)[..1].to_vec();
+        manifest.apply_edit(&removed, vec![moved, sst(3, 1, b"n", b"p")]);
+        let next = manifest.next_sstable_id();
+        log.commit(&mut manifest).unwrap();
+        drop(log);
+
+        let (loaded, log) = ManifestLog::open(&path).unwrap();
+        assert_eq!(files(&loaded), vec![vec![2], vec![1, 3], vec![]]);
+        assert_eq!(loaded.version, manifest.version);
+        assert_eq!(loaded.last_sequence, 40);
+        assert_eq!(loaded.next_sstable_id, next + 1);
+        assert!(loaded.get_level(0)[0].tombstone_only);
+
+        // a torn append is dropped, and the next one lands where it began
+        let len = log.len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3, 4, 5]).unwrap();
+        drop(file);
+        let (mut loaded, mut log) = ManifestLog::open(&path).unwrap();
+        assert_eq!(log.len(), len);
+        loaded.remove_sstables(&[sst(2, 0, b"k", b"z")]);
+        log.commit(&mut loaded).unwrap();
+        let (loaded, _) = ManifestLog::open(&path).unwrap();
+        assert_eq!(files(&loaded), vec![vec![], vec![1, 3], vec![]]);
+
+        // damage with records after it is corruption
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[MANIFEST_RECORD_HEADER_SIZE + 2] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            ManifestLog::open(&path),
+            Err(ManifestError::Corrupted(_))
+        ));
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_family_log_replay() {
+        let path = env::temp_dir().join("test_manifest_log_families");
+        fs::remove_file(&path).ok();
+
+        let mut manifest = Manifest::new(3);
+        let mut log = ManifestLog::create(&path, &manifest).unwrap();
+        let users = manifest.create_family("users", 2);
+        let logs = manifest.create_family("logs", 3);
+        manifest.family_mut(users).unwrap().add_sstable(0, sst(1, 0, b"a", b"m"));
+        manifest.family_mut(logs).unwrap().add_sstable(0, sst(2, 0, b"a", b"m"));
+        manifest.add_sstable(0, sst(3, 0, b"a", b"m"));
+        log.commit(&mut manifest).unwrap();
+        manifest.drop_family(logs);
+        let moved = sst(1, 1, b"a", b"m");
+        let removed = [sst(1, 0, b"a", b"m")];
+        manifest.family_mut(users).unwrap().apply_edit(&removed, vec![moved]);
+        log.commit(&mut manifest).unwrap();
+        drop(log);
+
+        let (loaded, log) = ManifestLog::open(&path).unwrap();
+        assert_eq!(files(&loaded), vec![vec![3], vec![], vec![]]);
+        assert_eq!(loaded.families.len(), 1);
+        assert_eq!(loaded.families[0].name, "users");
+        assert_eq!(files(loaded.family(users).unwrap()), vec![vec![], vec![1]]);
+        assert!(loaded.family(logs).is_none());
+        // ids of dropped families aren't handed out again
+        assert_eq!(loaded.next_family_id, logs + 1);
+
+        drop(log);
+
+        // a snapshot, which starts every new log, carries the families over
+        let mut buf = Vec::new();
+        loaded.snapshot_edit().encode(&mut buf);
+        let mut rebuilt = Manifest::new(3);
+        rebuilt.apply(&VersionEdit::decode(&buf).unwrap()).unwrap();
+        assert_eq!(files(rebuilt.family(users).unwrap()), vec![vec![], vec![1]]);
+        assert_eq!(rebuilt.next_family_id, logs + 1);
+        assert_eq!(rebuilt.all_tables().count(), 2);
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = env::temp_dir().join("test_manifest_rotation");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        assert!(ManifestLog::open_current(&dir).unwrap().is_none());
+
+        let mut manifest = Manifest::new(2);
+        let log = ManifestLog::create_current(&dir, 1, &manifest).unwrap();
+        let mut log = log.with_max_size(200);
+        for id in 1..=20 {
+            manifest.add_sstable(0, sst(id, 0, b"a", b"z"));
+            log.commit(&mut manifest).unwrap();
+        }
+        let number = log.number().unwrap();
+        assert!(number > 1);
+        assert_eq!(
+            current_manifest(&dir).unwrap(),
+            Some(dir.join(manifest_file_name(number)))
+        );
+        // older logs are gone
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        let (loaded, log) = ManifestLog::open_current(&dir).unwrap().unwrap();
+        assert_eq!(files(&loaded), files(&manifest));
+        assert_eq!(log.number(), Some(number));
+
+        fs::write(dir.join(CURRENT_FILE), "../elsewhere\n").unwrap();
+        assert!(matches!(
+            ManifestLog::open_current(&dir),
+            Err(ManifestError::Corrupted(_))
+        ));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,26 +1,128 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use crate::format::{
+    crc32, get_u32, get_u64, BATCH_HEADER_SIZE, OP_BATCH, OP_DELETE, OP_DELETE_RANGE, OP_FAMILY,
+    OP_HEADER_SIZE, OP_MERGE, OP_PUT, OP_SINGLE_DELETE, WAL_HEADER_SIZE, WAL_OP_SEQUENCED,
+    WAL_SEQ_SIZE,
+};
 
 pub struct WalWriter {
     file: File,
     path: PathBuf,
     offset: u64,
+    /// record encoding buffer, reused across appends
+    buf: Vec<u8>,
 }
 
+/// reads records front to back, from the start of a log or from any record
+/// boundary a previous reader reported
+///    - offset() is where the next record starts, so a consumer can persist
+///      it and resume later without re-reading the log
+///    - a record that fails to decode leaves the reader in front of it; for a
+///      log still being written, a torn tail reads again once complete
 pub struct WalReader {
     reader: BufReader<File>,
+    offset: u64,
+}
+
+/// GroupCommit: shares one fsync between concurrent synchronous writers
+///    - every logged record gets a position that only grows (the DB uses
+///      sequence numbers); a writer waits until its position is durable
+///    - the first waiter becomes the leader and syncs everything logged so
+///      far, outside the DB lock; writers arriving meanwhile queue behind it
+///      and are usually covered by that one fsync
+///    - if the leader's sync fails it reports the error and the next waiter
+///      takes over
+#[derive(Debug, Default)]
+pub struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    /// every record up to here is on disk
+    durable: u64,
+
+    /// a leader is syncing right now
+    syncing: bool,
+
+    /// fsyncs performed, so callers can see how much was coalesced
+    syncs: u64,
+}
+
+/// how recovery treats damaged WAL records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalRecoveryMode {
+    /// drop a damaged record at the end of the log, as left by a crash
+    /// mid-append, and cut it off the file; damage anywhere else fails
+    #[default]
+    TolerateCorruptedTail,
+
+    /// fail on any damaged record
+    AbsoluteConsistency,
+
+    /// skip every damaged record whose length can still be trusted and
+    /// drop the rest of the log at the first one whose length can't;
+    /// salvages what it can, at the cost of losing writes in the middle
+    SkipAnyCorrupted,
+}
+
+/// what recovering one log found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalRecovery {
+    /// records replayed
+    pub records: u64,
+
+    /// damaged records skipped in the middle of the log
+    pub skipped: u64,
+
+    /// bytes cut off the end of the log
+    pub truncated: u64,
+}
+
+/// one decoded WAL record
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalRecord {
+    /// sequence number of the record's first operation; None for records
+    /// written before WAL_VERSION 2
+    pub seq: Option<u64>,
+
+    pub entry: WalEntry,
+}
+
+impl WalRecord {
+    /// sequence number of the record's last operation
+    pub fn last_seq(&self) -> Option<u64> {
+        let ops = match &self.entry {
+            WalEntry::Batch { entries } | WalEntry::Family { entries, .. } => {
+                entries.len() as u64
+            }
+            _ => 1,
+        };
+        self.seq.map(|seq| seq + ops.saturating_sub(1))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum WalEntry {
     Put { key: Vec<u8>, value: Vec<u8> },
     Delete { key: Vec<u8> },
+    /// a delete that cancels out the single put below it
+    SingleDelete { key: Vec<u8> },
+    /// a merge operand, folded onto the key's value when read
+    Merge { key: Vec<u8>, value: Vec<u8> },
+    /// deletes every key in [start, end)
+    DeleteRange { start: Vec<u8>, end: Vec<u8> },
+    /// operations that must be replayed all-or-nothing (one record, one checksum)
+    Batch { entries: Vec<WalEntry> },
+    /// a batch of operations on column family `id`
+    Family { id: u32, entries: Vec<WalEntry> },
 }
 
-const OP_PUT: u8 = 0x01;
-const OP_DELETE: u8 = 0x02;
-
 #[derive(Debug)]
 pub enum WalError {
     Io(io::Error),
@@ -51,7 +153,6 @@ impl WalWriter {
         let path = path.as_ref().to_path_buf();
         let file = OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
             .open(&path)?;
 
@@ -59,23 +160,40 @@ impl WalWriter {
             file,
             path,
             offset: 0,
+            buf: Vec::new(),
         })
     }
 
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
-        let mut file = OpenOptions::new().write(true).append(true).open(&path)?;
+        let mut file = OpenOptions::new().append(true).open(&path)?;
 
         let offset = file.seek(SeekFrom::End(0))?;
 
-        Ok(Self { file, path, offset })
+        Ok(Self {
+            file,
+            path,
+            offset,
+            buf: Vec::new(),
+        })
+    }
+
+    /// log `entry`, whose first operation has sequence number `seq`
+    pub fn append(&mut self, seq: u64, entry: &WalEntry) -> Result<()> {
+        encode_entry(&mut self.buf, Some(seq), entry);
+        self.write_buf()
     }
 
-    pub fn append(&mut self, entry: &WalEntry) -> Result<()> {
-        let bytes = encode_entry(entry)?;
-        self.file.write_all(&bytes)?;
-        self.offset += bytes.len() as u64;
-        Ok(())
+    /// log an already encoded batch (see `encode_batch`) as one batch record
+    pub fn append_batch(&mut self, seq: u64, batch: &[u8]) -> Result<()> {
+        encode_record(&mut self.buf, OP_BATCH, Some(seq), &[], Some(batch));
+        self.write_buf()
+    }
+
+    /// append_batch for the operations of column family `id`
+    pub fn append_family_batch(&mut self, seq: u64, id: u32, batch: &[u8]) -> Result<()> {
+        encode_record(&mut self.buf, OP_FAMILY, Some(seq), &id.to_le_bytes(), Some(batch));
+        self.write_buf()
     }
 
     pub fn sync(&mut self) -> Result<()> {
@@ -83,6 +201,9 @@ impl WalWriter {
         Ok(())
     }
 
+    /// empty the log in place
+    /// - the DB seals segments instead: a crash before the memtable reaches a
+    ///   table would lose everything truncated here
     pub fn truncate(&mut self) -> Result<()> {
         drop(std::mem::replace(
             &mut self.file,
@@ -99,82 +220,335 @@ impl WalWriter {
         Ok(())
     }
 
+    /// second handle on the log file, for syncing without holding the writer
+    pub fn sync_handle(&self) -> Result<File> {
+        Ok(self.file.try_clone()?)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn offset(&self) -> u64 {
         self.offset
     }
+
+    fn write_buf(&mut self) -> Result<()> {
+        self.file.write_all(&self.buf)?;
+        self.offset += self.buf.len() as u64;
+        Ok(())
+    }
 }
 
 impl WalReader {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_at(path, 0)
+    }
+
+    /// start reading at `offset`, which must be a record boundary
+    pub fn open_at(path: impl AsRef<Path>, offset: u64) -> Result<Self> {
         let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        Ok(Self { reader })
+        let mut reader = Self {
+            reader: BufReader::new(file),
+            offset: 0,
+        };
+        reader.seek_to_offset(offset)?;
+        Ok(reader)
+    }
+
+    /// byte offset of the next record
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn seek_to_offset(&mut self, offset: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// move forward to the first record holding `seq` or anything after it
+    ///
+    /// that record may be a batch starting below `seq`. Records without a
+    /// sequence number are skipped. Returns false at the end of the log
+    pub fn seek_to_sequence(&mut self, seq: u64) -> Result<bool> {
+        while let Some((offset, record)) = self.next_with_offset()? {
+            if record.last_seq().is_some_and(|last| last >= seq) {
+                self.seek_to_offset(offset)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// next record along with the byte offset it starts at
+    pub fn next_with_offset(&mut self) -> Result<Option<(u64, WalRecord)>> {
+        let offset = self.offset;
+        match decode_entry(&mut self.reader) {
+            Ok(Some(record)) => {
+                self.offset = self.reader.stream_position()?;
+                Ok(Some((offset, record)))
+            }
+            Ok(None) => {
+                // a short read of the length prefix is still a clean end
+                self.seek_to_offset(offset)?;
+                Ok(None)
+            }
+            Err(e) => {
+                self.seek_to_offset(offset)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Iterator for WalReader {
+    type Item = Result<WalRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_offset()
+            .transpose()
+            .map(|record| record.map(|(_, record)| record))
+    }
+}
+
+impl GroupCommit {
+    /// start with everything up to `durable` already on disk
+    pub fn new(durable: u64) -> Self {
+        Self {
+            state: Mutex::new(CommitState {
+                durable,
+                ..CommitState::default()
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// block until the record at `position` is durable
+    ///
+    /// `sync` runs on the leader only: it must make every record logged so
+    /// far durable and return the position it covered
+    pub fn wait<E>(
+        &self,
+        position: u64,
+        sync: impl FnOnce() -> std::result::Result<u64, E>,
+    ) -> std::result::Result<(), E> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if state.durable >= position {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.synced.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+
+        state.syncing = true;
+        drop(state);
+        let result = sync();
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.syncing = false;
+        if let Ok(covered) = result {
+            state.durable = state.durable.max(covered);
+            state.syncs += 1;
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+
+    /// largest position known to be durable
+    pub fn durable(&self) -> u64 {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).durable
+    }
+
+    /// fsyncs performed through this group commit
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).syncs
+    }
+}
+
+impl WalRecovery {
+    /// true if anything was lost
+    pub fn is_lossy(&self) -> bool {
+        self.skipped > 0 || self.truncated > 0
+    }
+}
+
+/// replay every record of the log at `path` into `apply`, oldest first
+/// - a record whose header or payload runs past the end of the file, or the
+///   last record failing its checksum, is a torn tail; anything else that
+///   fails to decode is corrupted in the middle
+/// - a dropped tail is cut off the file, so later appends follow the last
+///   good record instead of garbage
+pub fn recover<E: From<WalError>>(
+    path: impl AsRef<Path>,
+    mode: WalRecoveryMode,
+    apply: impl FnMut(WalRecord) -> std::result::Result<(), E>,
+) -> std::result::Result<WalRecovery, E> {
+    let path = path.as_ref();
+    let (recovery, len) = replay_records(path, mode, apply)?;
+    if recovery.truncated > 0 {
+        let file = OpenOptions::new().write(true).open(path).map_err(WalError::from)?;
+        file.set_len(len as u64).map_err(WalError::from)?;
+        file.sync_all().map_err(WalError::from)?;
     }
+    Ok(recovery)
+}
+
+/// like recover, but the file is left as it is; a dropped tail still counts
+/// in WalRecovery::truncated
+pub fn replay<E: From<WalError>>(
+    path: impl AsRef<Path>,
+    mode: WalRecoveryMode,
+    apply: impl FnMut(WalRecord) -> std::result::Result<(), E>,
+) -> std::result::Result<WalRecovery, E> {
+    replay_records(path.as_ref(), mode, apply).map(|(recovery, _)| recovery)
+}
 
-    pub fn next(&mut self) -> Result<Option<WalEntry>> {
-        decode_entry(&mut self.reader)
+/// what recover and replay share: the records applied, and the length of
+/// the file up to the end of the last good one
+fn replay_records<E: From<WalError>>(
+    path: &Path,
+    mode: WalRecoveryMode,
+    mut apply: impl FnMut(WalRecord) -> std::result::Result<(), E>,
+) -> std::result::Result<(WalRecovery, usize), E> {
+    let data = fs::read(path).map_err(WalError::from)?;
+    let mut recovery = WalRecovery::default();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let record_len = get_u32(&data, offset + 4).map(|len| WAL_HEADER_SIZE + len as usize);
+        let end = record_len.and_then(|len| offset.checked_add(len));
+        let Some(end) = end.filter(|&end| end <= data.len()) else {
+            // header or payload cut short
+            if mode == WalRecoveryMode::AbsoluteConsistency {
+                return Err(WalError::Corrupted(format!(
+                    "Truncated record at offset {}",
+                    offset
+                ))
+                .into());
+            }
+            break;
+        };
+
+        match decode_entry(&mut &data[offset..end]) {
+            Ok(Some(record)) => {
+                apply(record)?;
+                recovery.records += 1;
+            }
+            Ok(None) => unreachable!("record bounds checked above"),
+            Err(e) => {
+                let at_tail = end == data.len();
+                match mode {
+                    WalRecoveryMode::AbsoluteConsistency => return Err(e.into()),
+                    WalRecoveryMode::TolerateCorruptedTail if !at_tail => return Err(e.into()),
+                    WalRecoveryMode::SkipAnyCorrupted if !at_tail => recovery.skipped += 1,
+                    _ => break,
+                }
+            }
+        }
+        offset = end;
     }
+
+    recovery.truncated = (data.len() - offset) as u64;
+    Ok((recovery, offset))
 }
 
-/// encode a WAL entry to bytes
+/// encode a WAL entry into `buf`, replacing its contents
 ///
 /// format:
-/// ┌─────────┬────────┬────────┬─────────┬───────────┬─────┬───────┐
-/// │Checksum │ Length │ OpType │ Key Len │ Value Len │ Key │ Value │
-/// │ (4B)    │ (4B)   │ (1B)   │ (4B)    │ (4B)      │ var │ var   │
-/// └─────────┴────────┴────────┴─────────┴───────────┴─────┴───────┘
-fn encode_entry(entry: &WalEntry) -> Result<Vec<u8>> {
-    let (op_type, key, value) = match entry {
-        WalEntry::Put { key, value } => (OP_PUT, key.as_slice(), Some(value.as_slice())),
-        WalEntry::Delete { key } => (OP_DELETE, key.as_slice(), None),
-    };
+/// ┌─────────┬────────┬────────┬───────┬─────────┬───────────┬─────┬───────┐
+/// │Checksum │ Length │ OpType │ Seq   │ Key Len │ Value Len │ Key │ Value │
+/// │ (4B)    │ (4B)   │ (1B)   │ (8B)  │ (4B)    │ (4B)      │ var │ var   │
+/// └─────────┴────────┴────────┴───────┴─────────┴───────────┴─────┴───────┘
+///
+/// - the op type has WAL_OP_SEQUENCED set iff Seq is present; records
+///   written before WAL_VERSION 2 have neither
+/// - a batch has an empty key and its operations packed into the value
+/// - a column family's batch has the family id as its key
+fn encode_entry(buf: &mut Vec<u8>, seq: Option<u64>, entry: &WalEntry) {
+    match entry {
+        WalEntry::Put { key, value } => encode_record(buf, OP_PUT, seq, key, Some(value)),
+        WalEntry::Delete { key } => encode_record(buf, OP_DELETE, seq, key, None),
+        WalEntry::SingleDelete { key } => encode_record(buf, OP_SINGLE_DELETE, seq, key, None),
+        WalEntry::Merge { key, value } => encode_record(buf, OP_MERGE, seq, key, Some(value)),
+        WalEntry::DeleteRange { start, end } => {
+            encode_record(buf, OP_DELETE_RANGE, seq, start, Some(end))
+        }
+        WalEntry::Batch { entries } => {
+            encode_record(buf, OP_BATCH, seq, &[], Some(&encode_batch(entries)))
+        }
+        WalEntry::Family { id, entries } => {
+            let batch = encode_batch(entries);
+            encode_record(buf, OP_FAMILY, seq, &id.to_le_bytes(), Some(&batch))
+        }
+    }
+}
 
+fn encode_record(
+    buf: &mut Vec<u8>,
+    op_type: u8,
+    seq: Option<u64>,
+    key: &[u8],
+    value: Option<&[u8]>,
+) {
     let key_len = key.len() as u32;
     let value_len = value.map(|v| v.len() as u32).unwrap_or(0);
+    let seq_size = if seq.is_some() { WAL_SEQ_SIZE } else { 0 };
 
-    // payload size without checksum
-    let payload_size = 4 + 1 + 4 + 4 + key.len() + value_len as usize;
+    // length excludes the checksum and length fields
+    let length = OP_HEADER_SIZE + seq_size + key.len() + value_len as usize;
 
-    let mut payload = Vec::with_capacity(payload_size);
+    buf.clear();
+    buf.reserve(WAL_HEADER_SIZE + length);
 
-    // write length excluding checksum field
-    payload.extend_from_slice(&(payload_size as u32 - 4).to_le_bytes());
+    // checksum placeholder, filled in once the payload is written
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&(length as u32).to_le_bytes());
 
-    payload.push(op_type);
+    match seq {
+        Some(seq) => {
+            buf.push(op_type | WAL_OP_SEQUENCED);
+            buf.extend_from_slice(&seq.to_le_bytes());
+        }
+        None => buf.push(op_type),
+    }
 
-    payload.extend_from_slice(&key_len.to_le_bytes());
-    payload.extend_from_slice(&value_len.to_le_bytes());
-    payload.extend_from_slice(key);
+    buf.extend_from_slice(&key_len.to_le_bytes());
+    buf.extend_from_slice(&value_len.to_le_bytes());
+    buf.extend_from_slice(key);
 
     if let Some(v) = value {
-        payload.extend_from_slice(v);
+        buf.extend_from_slice(v);
     }
 
-    let checksum = crc32(&payload[4..]);
-
-    // prepend checksum
-    let mut result = Vec::with_capacity(4 + payload.len());
-    result.extend_from_slice(&checksum.to_le_bytes());
-    result.extend_from_slice(&payload);
-
-    Ok(result)
+    let checksum = crc32(&buf[WAL_HEADER_SIZE..]);
+    buf[0..4].copy_from_slice(&checksum.to_le_bytes());
 }
 
-fn decode_entry<R: Read>(reader: &mut R) -> Result<Option<WalEntry>> {
-    let mut checksum_buf = [0u8; 4];
-    match reader.read_exact(&mut checksum_buf) {
+pub(crate) fn decode_entry<R: Read>(reader: &mut R) -> Result<Option<WalRecord>> {
+    let mut header = [0u8; WAL_HEADER_SIZE];
+    match reader.read_exact(&mut header[..4]) {
         Ok(_) => {}
         Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
         Err(e) => return Err(e.into()),
     }
-    let expected_checksum = u32::from_le_bytes(checksum_buf);
-
-    let mut len_buf = [0u8; 4];
-    reader.read_exact(&mut len_buf)?;
-    let length = u32::from_le_bytes(len_buf) as usize;
-
-    let mut payload = vec![0u8; length];
-    reader.read_exact(&mut payload)?;
+    reader.read_exact(&mut header[4..])?;
+    let expected_checksum = get_u32(&header, 0).unwrap();
+    let length = get_u32(&header, 4).unwrap() as usize;
+
+    // read through take() so a garbage length can't allocate gigabytes up front
+    let mut payload = Vec::new();
+    reader.take(length as u64).read_to_end(&mut payload)?;
+    if payload.len() != length {
+        return Err(WalError::Corrupted(format!(
+            "Truncated record: expected {} bytes, got {}",
+            length,
+            payload.len()
+        )));
+    }
 
     let actual_checksum = crc32(&payload);
     if actual_checksum != expected_checksum {
@@ -184,36 +558,51 @@ fn decode_entry<R: Read>(reader: &mut R) -> Result<Option<WalEntry>> {
         )));
     }
 
-    let mut cursor = 0;
-
-    let op_type = payload[cursor];
-    cursor += 1;
+    let truncated = || WalError::Corrupted("Record shorter than its lengths".to_string());
 
-    let key_len = u32::from_le_bytes([
-        payload[cursor],
-        payload[cursor + 1],
-        payload[cursor + 2],
-        payload[cursor + 3],
-    ]) as usize;
-    cursor += 4;
-
-    let value_len = u32::from_le_bytes([
-        payload[cursor],
-        payload[cursor + 1],
-        payload[cursor + 2],
-        payload[cursor + 3],
-    ]) as usize;
-    cursor += 4;
-
-    let key = payload[cursor..cursor + key_len].to_vec();
-    cursor += key_len;
+    let op_byte = *payload.first().ok_or_else(truncated)?;
+    let (op_type, seq, lengths_at) = if op_byte & WAL_OP_SEQUENCED != 0 {
+        let seq = get_u64(&payload, 1).ok_or_else(truncated)?;
+        (op_byte & !WAL_OP_SEQUENCED, Some(seq), 1 + WAL_SEQ_SIZE)
+    } else {
+        (op_byte, None, 1)
+    };
+    let key_len = get_u32(&payload, lengths_at).ok_or_else(truncated)? as usize;
+    let value_len = get_u32(&payload, lengths_at + 4).ok_or_else(truncated)? as usize;
+
+    let key_start = lengths_at + 8;
+    let key_end = key_start + key_len;
+    let value_end = key_end + value_len;
+    if value_end > payload.len() {
+        return Err(truncated());
+    }
+    let key = payload[key_start..key_end].to_vec();
+    let value = &payload[key_end..value_end];
 
     let entry = match op_type {
-        OP_PUT => {
-            let value = payload[cursor..cursor + value_len].to_vec();
-            WalEntry::Put { key, value }
-        }
+        OP_PUT => WalEntry::Put {
+            key,
+            value: value.to_vec(),
+        },
         OP_DELETE => WalEntry::Delete { key },
+        OP_SINGLE_DELETE => WalEntry::SingleDelete { key },
+        OP_MERGE => WalEntry::Merge {
+            key,
+            value: value.to_vec(),
+        },
+        OP_DELETE_RANGE => WalEntry::DeleteRange {
+            start: key,
+            end: value.to_vec(),
+        },
+        OP_BATCH => WalEntry::Batch {
+            entries: decode_batch(value)?,
+        },
+        OP_FAMILY => WalEntry::Family {
+            id: get_u32(&key, 0)
+                .filter(|_| key.len() == 4)
+                .ok_or_else(|| WalError::Corrupted("Bad column family id".to_string()))?,
+            entries: decode_batch(value)?,
+        },
         _ => {
             return Err(WalError::Corrupted(format!(
                 "Unknown operation type: {}",
@@ -222,26 +611,97 @@ fn decode_entry<R: Read>(reader: &mut R) -> Result<Option<WalEntry>> {
         }
     };
 
-    Ok(Some(entry))
+    Ok(Some(WalRecord { seq, entry }))
 }
 
-/// simple CRC32 implementation
-fn crc32(data: &[u8]) -> u32 {
-    const POLYNOMIAL: u32 = 0xEDB88320;
-    let mut crc: u32 = 0xFFFFFFFF;
-
-    for &byte in data {
-        crc ^= byte as u32;
-        for _ in 0..8 {
-            if crc & 1 != 0 {
-                crc = (crc >> 1) ^ POLYNOMIAL;
-            } else {
-                crc >>= 1;
-            }
+/// batch value format: [count(4B)] then per op [OpType(1B)][Key Len(4B)][Value Len(4B)][Key][Value]
+///
+/// nested batches are flattened, which preserves their meaning; a nested
+/// column family batch would lose its family, so the DB never nests one
+fn encode_batch(entries: &[WalEntry]) -> Vec<u8> {
+    fn push_ops(buf: &mut Vec<u8>, entries: &[WalEntry], count: &mut u32) {
+        for entry in entries {
+            let (op_type, key, value) = match entry {
+                WalEntry::Put { key, value } => (OP_PUT, key.as_slice(), value.as_slice()),
+                WalEntry::Delete { key } => (OP_DELETE, key.as_slice(), &[][..]),
+                WalEntry::SingleDelete { key } => (OP_SINGLE_DELETE, key.as_slice(), &[][..]),
+                WalEntry::Merge { key, value } => (OP_MERGE, key.as_slice(), value.as_slice()),
+                WalEntry::DeleteRange { start, end } => {
+                    (OP_DELETE_RANGE, start.as_slice(), end.as_slice())
+                }
+                WalEntry::Batch { entries } | WalEntry::Family { entries, .. } => {
+                    push_ops(buf, entries, count);
+                    continue;
+                }
+            };
+            buf.push(op_type);
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(value);
+            *count += 1;
         }
     }
 
-    !crc
+    let mut buf = vec![0u8; BATCH_HEADER_SIZE];
+    let mut count = 0u32;
+    push_ops(&mut buf, entries, &mut count);
+    buf[0..4].copy_from_slice(&count.to_le_bytes());
+    buf
+}
+
+fn decode_batch(data: &[u8]) -> Result<Vec<WalEntry>> {
+    let read_u32 = |offset: usize| -> Result<usize> {
+        get_u32(data, offset)
+            .map(|v| v as usize)
+            .ok_or_else(|| WalError::Corrupted("Truncated batch".to_string()))
+    };
+
+    let count = read_u32(0)?;
+    let mut cursor = BATCH_HEADER_SIZE;
+    let mut entries = Vec::with_capacity(count.min(data.len() / OP_HEADER_SIZE));
+
+    for _ in 0..count {
+        let op_type = *data
+            .get(cursor)
+            .ok_or_else(|| WalError::Corrupted("Truncated batch".to_string()))?;
+        let key_len = read_u32(cursor + 1)?;
+        let value_len = read_u32(cursor + 5)?;
+        cursor += OP_HEADER_SIZE;
+
+        let key_end = cursor + key_len;
+        let value_end = key_end + value_len;
+        if value_end > data.len() {
+            return Err(WalError::Corrupted("Batch operation out of bounds".to_string()));
+        }
+        let key = data[cursor..key_end].to_vec();
+
+        entries.push(match op_type {
+            OP_PUT => WalEntry::Put {
+                key,
+                value: data[key_end..value_end].to_vec(),
+            },
+            OP_DELETE => WalEntry::Delete { key },
+            OP_SINGLE_DELETE => WalEntry::SingleDelete { key },
+            OP_MERGE => WalEntry::Merge {
+                key,
+                value: data[key_end..value_end].to_vec(),
+            },
+            OP_DELETE_RANGE => WalEntry::DeleteRange {
+                start: key,
+                end: data[key_end..value_end].to_vec(),
+            },
+            _ => {
+                return Err(WalError::Corrupted(format!(
+                    "Unknown batch operation type: {}",
+                    op_type
+                )))
+            }
+        });
+        cursor = value_end;
+    }
+
+    Ok(entries)
 }
 
 #[cfg(test)]
@@ -256,11 +716,12 @@ mod tests {
             value: b"test_value".to_vec(),
         };
 
-        let encoded = encode_entry(&entry).unwrap();
+        let mut encoded = Vec::new();
+        encode_entry(&mut encoded, Some(7), &entry);
         let mut reader = &encoded[..];
         let decoded = decode_entry(&mut reader).unwrap().unwrap();
 
-        assert_eq!(entry, decoded);
+        assert_eq!(decoded, WalRecord { seq: Some(7), entry });
     }
 
     #[test]
@@ -269,11 +730,129 @@ mod tests {
             key: b"test_key".to_vec(),
         };
 
-        let encoded = encode_entry(&entry).unwrap();
+        let mut encoded = Vec::new();
+        encode_entry(&mut encoded, Some(7), &entry);
+        let mut reader = &encoded[..];
+        let decoded = decode_entry(&mut reader).unwrap().unwrap();
+
+        assert_eq!(decoded, WalRecord { seq: Some(7), entry });
+    }
+
+    #[test]
+    fn test_encode_decode_batch() {
+        let entry = WalEntry::Batch {
+            entries: vec![
+                WalEntry::Put {
+                    key: b"key1".to_vec(),
+                    value: b"value1".to_vec(),
+                },
+                WalEntry::Delete {
+                    key: b"key2".to_vec(),
+                },
+                WalEntry::Merge {
+                    key: b"key3".to_vec(),
+                    value: b"+1".to_vec(),
+                },
+                WalEntry::DeleteRange {
+                    start: b"key4".to_vec(),
+                    end: b"key5".to_vec(),
+                },
+                WalEntry::SingleDelete {
+                    key: b"key6".to_vec(),
+                },
+            ],
+        };
+
+        let mut encoded = Vec::new();
+        encode_entry(&mut encoded, Some(7), &entry);
         let mut reader = &encoded[..];
         let decoded = decode_entry(&mut reader).unwrap().unwrap();
 
-        assert_eq!(entry, decoded);
+        assert_eq!(decoded, WalRecord { seq: Some(7), entry });
+    }
+
+    #[test]
+    fn test_encode_decode_family_batch() {
+        let entry = WalEntry::Family {
+            id: 3,
+            entries: vec![
+                WalEntry::Put {
+                    key: b"key1".to_vec(),
+                    value: b"value1".to_vec(),
+                },
+                WalEntry::Delete {
+                    key: b"key2".to_vec(),
+                },
+            ],
+        };
+
+        let mut encoded = Vec::new();
+        encode_entry(&mut encoded, Some(7), &entry);
+        let mut reader = &encoded[..];
+        let record = decode_entry(&mut reader).unwrap().unwrap();
+        assert_eq!(record.last_seq(), Some(8));
+        assert_eq!(record.entry, entry);
+    }
+
+    #[test]
+    fn test_decode_unsequenced_record() {
+        // records written before WAL_VERSION 2 have no sequence number
+        let entry = WalEntry::Delete {
+            key: b"old".to_vec(),
+        };
+        let mut encoded = Vec::new();
+        encode_entry(&mut encoded, None, &entry);
+        assert_eq!(encoded[WAL_HEADER_SIZE], OP_DELETE);
+
+        let decoded = decode_entry(&mut &encoded[..]).unwrap().unwrap();
+        assert_eq!(decoded, WalRecord { seq: None, entry });
+    }
+
+    #[test]
+    fn test_torn_batch_is_not_partially_decoded() {
+        let entry = WalEntry::Batch {
+            entries: (0..10)
+                .map(|i| WalEntry::Put {
+                    key: format!("key{}", i).into_bytes(),
+                    value: b"value".to_vec(),
+                })
+                .collect(),
+        };
+
+        let mut encoded = Vec::new();
+        encode_entry(&mut encoded, Some(1), &entry);
+        let mut reader = &encoded[..encoded.len() - 3];
+
+        assert!(decode_entry(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_append_encoded_batch() {
+        let wal_path = env::temp_dir().join("test_wal_append_batch.log");
+        let entries = vec![
+            WalEntry::Put {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+            },
+            WalEntry::Delete {
+                key: b"key2".to_vec(),
+            },
+        ];
+
+        {
+            let mut writer = WalWriter::create(&wal_path).unwrap();
+            writer.truncate().unwrap();
+            writer.append_batch(1, &encode_batch(&entries)).unwrap();
+            writer.sync().unwrap();
+        }
+
+        let mut reader = WalReader::new(&wal_path).unwrap();
+        let record = reader.next().unwrap().unwrap();
+        assert_eq!(record.seq, Some(1));
+        assert_eq!(record.entry, WalEntry::Batch { entries });
+        assert!(reader.next().is_none());
+
+        std::fs::remove_file(wal_path).ok();
     }
 
     #[test]
@@ -285,21 +864,21 @@ mod tests {
             let mut writer = WalWriter::create(&wal_path).unwrap();
 
             writer
-                .append(&WalEntry::Put {
+                .append(1, &WalEntry::Put {
                     key: b"key1".to_vec(),
                     value: b"value1".to_vec(),
                 })
                 .unwrap();
 
             writer
-                .append(&WalEntry::Put {
+                .append(2, &WalEntry::Put {
                     key: b"key2".to_vec(),
                     value: b"value2".to_vec(),
                 })
                 .unwrap();
 
             writer
-                .append(&WalEntry::Delete {
+                .append(3, &WalEntry::Delete {
                     key: b"key1".to_vec(),
                 })
                 .unwrap();
@@ -310,38 +889,107 @@ mod tests {
         {
             let mut reader = WalReader::new(&wal_path).unwrap();
 
-            let entry1 = reader.next().unwrap().unwrap();
+            let record1 = reader.next().unwrap().unwrap();
+            assert_eq!(record1.seq, Some(1));
             assert_eq!(
-                entry1,
+                record1.entry,
                 WalEntry::Put {
                     key: b"key1".to_vec(),
                     value: b"value1".to_vec()
                 }
             );
 
-            let entry2 = reader.next().unwrap().unwrap();
+            let record2 = reader.next().unwrap().unwrap();
+            assert_eq!(record2.seq, Some(2));
             assert_eq!(
-                entry2,
+                record2.entry,
                 WalEntry::Put {
                     key: b"key2".to_vec(),
                     value: b"value2".to_vec()
                 }
             );
 
-            let entry3 = reader.next().unwrap().unwrap();
+            let record3 = reader.next().unwrap().unwrap();
+            assert_eq!(record3.seq, Some(3));
             assert_eq!(
-                entry3,
+                record3.entry,
                 WalEntry::Delete {
                     key: b"key1".to_vec()
                 }
             );
 
-            assert!(reader.next().unwrap().is_none());
+            assert!(reader.next().is_none());
         }
 
         std::fs::remove_file(wal_path).ok();
     }
 
+    #[test]
+    fn test_reader_offsets_and_seek() {
+        let wal_path = env::temp_dir().join("test_wal_reader_seek.log");
+        let put = |key: &[u8]| WalEntry::Put {
+            key: key.to_vec(),
+            value: b"value".to_vec(),
+        };
+        let batch = WalEntry::Batch {
+            entries: vec![put(b"b"), put(b"c")],
+        };
+        {
+            let mut writer = WalWriter::create(&wal_path).unwrap();
+            writer.truncate().unwrap();
+            writer.append(1, &put(b"a")).unwrap();
+            writer.append(2, &batch).unwrap();
+            writer.append(4, &put(b"d")).unwrap();
+            writer.sync().unwrap();
+        }
+
+        let mut reader = WalReader::new(&wal_path).unwrap();
+        let mut offsets = Vec::new();
+        while let Some((offset, record)) = reader.next_with_offset().unwrap() {
+            offsets.push((offset, record.seq, record.last_seq()));
+        }
+        assert_eq!(offsets[0], (0, Some(1), Some(1)));
+        assert_eq!((offsets[1].1, offsets[1].2), (Some(2), Some(3)));
+        assert_eq!(reader.offset(), fs::metadata(&wal_path).unwrap().len());
+
+        // resume where an earlier consumer stopped
+        let mut reader = WalReader::open_at(&wal_path, offsets[2].0).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().entry, put(b"d"));
+        assert!(reader.next().is_none());
+
+        let mut reader = WalReader::new(&wal_path).unwrap();
+        assert!(reader.seek_to_sequence(3).unwrap());
+        assert_eq!(reader.offset(), offsets[1].0);
+        assert_eq!(reader.next().unwrap().unwrap().entry, batch);
+        assert!(reader.seek_to_sequence(4).unwrap());
+        assert_eq!(reader.offset(), offsets[2].0);
+        assert!(!reader.seek_to_sequence(9).unwrap());
+
+        fs::remove_file(wal_path).ok();
+    }
+
+    #[test]
+    fn test_reader_retries_torn_tail() {
+        let wal_path = env::temp_dir().join("test_wal_reader_tail.log");
+        let entry = WalEntry::Delete {
+            key: b"key".to_vec(),
+        };
+        let mut record = Vec::new();
+        encode_entry(&mut record, Some(1), &entry);
+
+        fs::write(&wal_path, &record[..record.len() - 2]).unwrap();
+        let mut reader = WalReader::new(&wal_path).unwrap();
+        assert!(reader.next().unwrap().is_err());
+        assert_eq!(reader.offset(), 0);
+
+        // the writer finishes the record; the same reader picks it up
+        fs::write(&wal_path, &record).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().entry, entry);
+        assert_eq!(reader.offset(), record.len() as u64);
+
+        fs::remove_file(wal_path).ok();
+    }
+
     #[test]
     fn test_wal_truncate() {
         let temp_dir = env::temp_dir();
@@ -350,7 +998,7 @@ mod tests {
         let mut writer = WalWriter::create(&wal_path).unwrap();
 
         writer
-            .append(&WalEntry::Put {
+            .append(1, &WalEntry::Put {
                 key: b"key1".to_vec(),
                 value: b"value1".to_vec(),
             })
@@ -363,7 +1011,7 @@ mod tests {
         assert_eq!(writer.offset(), 0);
 
         let mut reader = WalReader::new(&wal_path).unwrap();
-        assert!(reader.next().unwrap().is_none());
+        assert!(reader.next().is_none());
 
         std::fs::remove_file(wal_path).ok();
     }
@@ -386,7 +1034,8 @@ mod tests {
             value: b"value".to_vec(),
         };
 
-        let mut encoded = encode_entry(&entry).unwrap();
+        let mut encoded = Vec::new();
+        encode_entry(&mut encoded, Some(1), &entry);
 
         encoded[0] ^= 0xFF;
 
@@ -395,4 +1044,126 @@ mod tests {
 
         assert!(matches!(result, Err(WalError::Corrupted(_))));
     }
+
+    #[test]
+    fn test_short_payload_is_corrupted() {
+        // a valid checksum over a payload too short for its own header
+        let payload = [OP_PUT, 5, 0];
+        let mut record = Vec::new();
+        record.extend_from_slice(&crc32(&payload).to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let result = decode_entry(&mut &record[..]);
+        assert!(matches!(result, Err(WalError::Corrupted(_))));
+
+        // a length running past the end of the log
+        let mut record = vec![0u8; 4];
+        record.extend_from_slice(&u32::MAX.to_le_bytes());
+        let result = decode_entry(&mut &record[..]);
+        assert!(matches!(result, Err(WalError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_group_commit_coalesces_syncs() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+
+        let group = Arc::new(GroupCommit::new(0));
+        let logged = Arc::new(AtomicU64::new(0));
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let (group, logged) = (Arc::clone(&group), Arc::clone(&logged));
+                thread::spawn(move || {
+                    let position = logged.fetch_add(1, Ordering::SeqCst) + 1;
+                    group
+                        .wait(position, || -> std::result::Result<u64, ()> {
+                            let covered = logged.load(Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(5));
+                            Ok(covered)
+                        })
+                        .unwrap();
+                    assert!(group.durable() >= position);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(group.durable(), 16);
+        assert!(group.syncs() >= 1 && group.syncs() <= 16);
+
+        // a failed sync is reported to its leader and retried by the next waiter
+        let group = GroupCommit::new(0);
+        assert_eq!(group.wait(1, || Err("disk gone")), Err("disk gone"));
+        assert_eq!(group.durable(), 0);
+        assert_eq!(group.wait(1, || Ok::<_, ()>(1)), Ok(()));
+        assert_eq!(group.wait(1, || Err(())), Ok(()));
+        assert_eq!(group.syncs(), 1);
+    }
+
+    #[test]
+    fn test_recovery_modes() {
+        let wal_path = env::temp_dir().join("test_wal_recovery_modes.log");
+        let put = |i: u8| WalEntry::Put {
+            key: vec![i],
+            value: vec![i; 10],
+        };
+        let mut clean = Vec::new();
+        let mut record = Vec::new();
+        let mut ends = Vec::new();
+        for i in 0..3 {
+            encode_entry(&mut record, Some(1), &put(i));
+            clean.extend_from_slice(&record);
+            ends.push(clean.len());
+        }
+
+        let run = |bytes: &[u8], mode| {
+            fs::write(&wal_path, bytes).unwrap();
+            let mut keys = Vec::new();
+            let result = recover(&wal_path, mode, |record| -> Result<()> {
+                if let WalEntry::Put { key, .. } = record.entry {
+                    keys.push(key[0]);
+                }
+                Ok(())
+            });
+            (result.ok(), keys, fs::metadata(&wal_path).unwrap().len() as usize)
+        };
+        use WalRecoveryMode::*;
+
+        // torn final record: header only, then half a payload
+        for cut in [ends[1] + 6, ends[2] - 3] {
+            let (recovery, keys, len) = run(&clean[..cut], TolerateCorruptedTail);
+            assert_eq!(keys, vec![0, 1]);
+            assert_eq!(recovery.unwrap().truncated as usize, cut - ends[1]);
+            assert_eq!(len, ends[1]);
+            assert!(run(&clean[..cut], AbsoluteConsistency).0.is_none());
+        }
+
+        // a bad checksum on the last record is a torn tail too
+        let mut bad_tail = clean.clone();
+        *bad_tail.last_mut().unwrap() ^= 0xff;
+        let (recovery, keys, _) = run(&bad_tail, TolerateCorruptedTail);
+        assert_eq!((recovery.map(|r| r.records), keys), (Some(2), vec![0, 1]));
+
+        // damage in the middle only SkipAnyCorrupted gets past
+        let mut bad_middle = clean.clone();
+        bad_middle[ends[0] + WAL_HEADER_SIZE + 2] ^= 0xff;
+        assert!(run(&bad_middle, TolerateCorruptedTail).0.is_none());
+        assert!(run(&bad_middle, AbsoluteConsistency).0.is_none());
+        let (recovery, keys, len) = run(&bad_middle, SkipAnyCorrupted);
+        let recovery = recovery.unwrap();
+        assert_eq!(keys, vec![0, 2]);
+        assert_eq!((recovery.skipped, recovery.truncated), (1, 0));
+        assert!(recovery.is_lossy());
+        assert_eq!(len, clean.len());
+
+        let (recovery, keys, _) = run(&clean, AbsoluteConsistency);
+        assert_eq!(keys, vec![0, 1, 2]);
+        assert!(!recovery.unwrap().is_lossy());
+
+        fs::remove_file(&wal_path).ok();
+    }
 }
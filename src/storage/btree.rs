@@ -0,0 +1,1201 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use super::bucket::Bucket;
+use super::bufferpool::{BufferPool, DEFAULT_POOL_PAGES};
+use super::cursor::Cursor;
+use super::page::{self, META_PAGES, MetaPage, PAGE_SIZE, Page, PageError, PageId, PageType};
+use super::pagemanager::PageManager;
+use super::slotted::SlottedPage;
+use crate::format::{
+    BTREE_BUCKET, BTREE_INTERNAL_CELL_HEADER_SIZE, BTREE_LEAF_CELL_HEADER_SIZE,
+    BTREE_NODE_HEADER_SIZE, BTREE_VALUE, FREE_LIST_HEADER_SIZE, PAGE_HEADER_SIZE, SLOT_SIZE,
+    SLOTTED_HEADER_SIZE, get_u16, get_u64,
+};
+
+/// bytes available for cells and their slots in a node page
+pub(super) const NODE_CAPACITY: usize = PAGE_SIZE - BTREE_NODE_HEADER_SIZE;
+
+/// owner header of a node's slotted page
+const NODE_HEADER_LEN: usize = BTREE_NODE_HEADER_SIZE - SLOTTED_HEADER_SIZE;
+
+/// largest cell, leaf or internal; with at least four cells to a page, a full
+/// node always splits into two halves that fit
+pub const MAX_CELL_SIZE: usize = NODE_CAPACITY / 4;
+
+/// nodes whose cells fill less than this are merged with a sibling or topped
+/// up from it after a delete
+pub(super) const MIN_FILL: usize = NODE_CAPACITY / 4;
+
+/// deeper than any real tree; guards against cycles in a damaged file
+const MAX_DEPTH: usize = 32;
+
+/// page ids one free list page holds
+const FREE_IDS_PER_PAGE: usize = (PAGE_SIZE - FREE_LIST_HEADER_SIZE) / 8;
+
+#[derive(Debug)]
+pub enum BTreeError {
+    Io(io::Error),
+    Page(PageId, PageError),
+    Corrupted(String),
+    TooLarge(usize),
+
+    /// bulk load input out of order at this key
+    NotSorted(Vec<u8>),
+
+    /// bulk load into a tree or bucket that has entries
+    NotEmpty,
+
+    /// create_bucket on a key that already holds a bucket
+    BucketExists(Vec<u8>),
+
+    /// a value operation on a key holding a bucket
+    IsBucket(Vec<u8>),
+
+    /// a bucket operation on a key holding a value
+    NotABucket(Vec<u8>),
+
+    /// the file is open for writing elsewhere
+    AlreadyLocked(PathBuf),
+
+    /// a change to a tree from BTree::open_read_only
+    ReadOnly,
+}
+
+impl fmt::Display for BTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BTreeError::Io(e) => write!(f, "I/O error: {}", e),
+            BTreeError::Page(id, e) => write!(f, "Page {}: {}", id, e),
+            BTreeError::Corrupted(msg) => write!(f, "Corrupted B-tree: {}", msg),
+            BTreeError::TooLarge(size) => write!(
+                f,
+                "Entry of {} bytes does not fit in a page (max {})",
+                size, MAX_CELL_SIZE
+            ),
+            BTreeError::NotSorted(key) => {
+                write!(
+                    f,
+                    "Bulk load keys not sorted at {}",
+                    String::from_utf8_lossy(key)
+                )
+            }
+            BTreeError::NotEmpty => write!(f, "Bulk load needs an empty tree"),
+            BTreeError::BucketExists(name) => {
+                write!(f, "Bucket {} already exists", String::from_utf8_lossy(name))
+            }
+            BTreeError::IsBucket(key) => {
+                write!(f, "Key {} holds a bucket", String::from_utf8_lossy(key))
+            }
+            BTreeError::NotABucket(key) => {
+                write!(
+                    f,
+                    "Key {} does not hold a bucket",
+                    String::from_utf8_lossy(key)
+                )
+            }
+            BTreeError::AlreadyLocked(path) => {
+                write!(f, "Page file {} is already open", path.display())
+            }
+            BTreeError::ReadOnly => write!(f, "B-tree is open read-only"),
+        }
+    }
+}
+
+impl std::error::Error for BTreeError {}
+
+impl From<io::Error> for BTreeError {
+    fn from(e: io::Error) -> Self {
+        BTreeError::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, BTreeError>;
+
+/// BTree: an ordered key-value store in a page file
+///    - leaves hold the entries; internal nodes hold separator keys, every
+///      key in a child at or after a separator being >= it
+///    - a full node splits in two by bytes, a node left under a quarter full
+///      by a delete merges with a sibling or borrows from it, and the root
+///      grows or shrinks a level as needed
+///    - copy-on-write: a change never touches a page the last commit can
+///      reach. Each node on the changed path moves to a fresh page, once per
+///      transaction, and its old page is freed
+///    - commit() writes the new pages and the free list, syncs, then writes
+///      a meta page naming the new root, alternating between pages 0 and 1.
+///      Open takes the valid meta page with the highest txn, so a crash
+///      leaves the last complete commit, and changes not committed are lost.
+///      The meta page also records the file's length, so pages appended
+///      after it are cut off on open. Open locks the file, so a transaction
+///      in progress elsewhere can't lose its pages that way
+///    - pages freed by a transaction are reused only after it commits
+///    - pages go through a BufferPool, so frames are written back when
+///      evicted or at commit
+///    - a bucket is a nested tree whose root is the value of its name in
+///      the parent tree, see Bucket. A change to a bucket that moves its
+///      root updates every parent up to the top-level root
+pub struct BTree {
+    pub(super) pool: BufferPool,
+
+    /// last commit
+    meta: MetaPage,
+
+    /// root as of the changes since the last commit
+    pub(super) root: PageId,
+
+    /// pages this transaction allocated, safe to change in place
+    txn_pages: HashSet<PageId>,
+
+    /// pages neither the last commit nor this transaction uses
+    pub(super) free: BTreeSet<PageId>,
+
+    /// pages the last commit uses but this transaction no longer does
+    pub(super) freed: Vec<PageId>,
+
+    /// pages holding the last commit's free list
+    pub(super) free_list_pages: Vec<PageId>,
+
+    /// from open_read_only: nothing is written, changes fail with ReadOnly
+    read_only: bool,
+}
+
+/// separator and page of the new right half of a node that split
+type Split = (Vec<u8>, PageId);
+
+pub(super) enum Node {
+    Leaf(Leaf),
+    Internal(Internal),
+}
+
+pub(super) struct Leaf {
+    pub(super) cells: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// children are `first` then the child of each cell, in key order
+pub(super) struct Internal {
+    pub(super) first: PageId,
+    pub(super) cells: Vec<(Vec<u8>, PageId)>,
+}
+
+impl BTree {
+    /// open the tree stored at `path`, creating an empty one if the file is
+    /// missing or empty
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_pool_pages(path, DEFAULT_POOL_PAGES)
+    }
+
+    /// open() with a buffer pool of `pool_pages` frames
+    pub fn open_with_pool_pages<P: AsRef<Path>>(path: P, pool_pages: usize) -> Result<Self> {
+        Self::open_with(path.as_ref(), pool_pages, false)
+    }
+
+    /// open the existing tree at `path` as of its last commit, to read or
+    /// check it while a writer may have it open
+    ///    - takes no lock and never changes the file; pages a transaction
+    ///      appended since the commit are left alone and not read
+    ///    - insert, delete, bucket changes and commit fail with ReadOnly
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path.as_ref(), DEFAULT_POOL_PAGES, true)
+    }
+
+    fn open_with(path: &Path, pool_pages: usize, read_only: bool) -> Result<Self> {
+        let pager = if read_only {
+            PageManager::open_read_only(path)?
+        } else {
+            PageManager::open(path).map_err(|e| match e.kind() {
+                io::ErrorKind::WouldBlock => BTreeError::AlreadyLocked(path.to_path_buf()),
+                _ => BTreeError::Io(e),
+            })?
+        };
+        let mut pool = BufferPool::new(pager, pool_pages);
+        let meta = if pool.num_pages() == 0 {
+            if read_only {
+                return Err(BTreeError::Corrupted("page file is empty".to_string()));
+            }
+            // both meta pages valid from the start
+            let meta = MetaPage {
+                num_pages: META_PAGES + 1,
+                ..MetaPage::new(META_PAGES)
+            };
+            let root = Node::Leaf(Leaf { cells: Vec::new() });
+            for id in 0..META_PAGES {
+                pool.write_page(id, &meta.encode())?;
+            }
+            pool.write_page(meta.root, &root.encode())?;
+            pool.checkpoint()?;
+            meta
+        } else {
+            let meta = Self::current_meta(&mut pool)?;
+            // pages a transaction appended but never committed, which a
+            // writer with the file open may still commit
+            if meta.num_pages != 0 {
+                if read_only {
+                    pool.limit(meta.num_pages);
+                } else {
+                    pool.truncate(meta.num_pages)?;
+                }
+            }
+            meta
+        };
+
+        let mut tree = Self {
+            pool,
+            meta,
+            root: meta.root,
+            txn_pages: HashSet::new(),
+            free: BTreeSet::new(),
+            freed: Vec::new(),
+            free_list_pages: Vec::new(),
+            read_only,
+        };
+        tree.load_free_list()?;
+        Ok(tree)
+    }
+
+    /// root page as of the last change, committed or not
+    pub fn root(&self) -> PageId {
+        self.root
+    }
+
+    /// number of the last commit
+    pub fn txn(&self) -> u64 {
+        self.meta.txn
+    }
+
+    /// pages in the file, free ones included
+    pub fn num_pages(&self) -> u64 {
+        self.pool.num_pages()
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_in(&[], key)
+    }
+
+    /// insert or replace `key`, returning the value it replaced
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.insert_in(&[], key, value)
+    }
+
+    /// remove `key`, returning its value if it was present
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.delete_in(&[], key)
+    }
+
+    /// create an empty top-level bucket `name`
+    pub fn create_bucket(&mut self, name: &[u8]) -> Result<Bucket<'_>> {
+        self.create_bucket_in(&[], name)?;
+        Ok(Bucket::new(self, vec![name.to_vec()]))
+    }
+
+    /// the top-level bucket `name`, if there is one
+    pub fn bucket(&mut self, name: &[u8]) -> Result<Option<Bucket<'_>>> {
+        Ok(self
+            .has_bucket(&[], name)?
+            .then(|| Bucket::new(self, vec![name.to_vec()])))
+    }
+
+    /// delete the top-level bucket `name` and everything in it; false if
+    /// there was none
+    pub fn delete_bucket(&mut self, name: &[u8]) -> Result<bool> {
+        self.delete_bucket_in(&[], name)
+    }
+
+    /// fill the empty tree from `entries` in ascending key order, packing
+    /// nodes instead of inserting; returns how many were loaded
+    pub fn bulk_load<I, K, V>(&mut self, entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.bulk_load_in(&[], entries)
+    }
+
+    /// an unpositioned cursor over the tree, see Cursor
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        Cursor::new(self, Vec::new())
+    }
+
+    pub(super) fn get_in(&mut self, bucket: &[Vec<u8>], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root = self.bucket_root(bucket)?;
+        Ok(match self.lookup(root, key)? {
+            Some(mut stored) if stored[0] == BTREE_VALUE => {
+                stored.remove(0);
+                Some(stored)
+            }
+            _ => None,
+        })
+    }
+
+    pub(super) fn insert_in(
+        &mut self,
+        bucket: &[Vec<u8>],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let mut stored = Vec::with_capacity(1 + value.len());
+        stored.push(BTREE_VALUE);
+        stored.extend_from_slice(value);
+        Ok(self.put(bucket, key, &stored)?.map(|mut old| {
+            old.remove(0);
+            old
+        }))
+    }
+
+    pub(super) fn delete_in(&mut self, bucket: &[Vec<u8>], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.remove(bucket, key, BTREE_VALUE)?.map(|mut old| {
+            old.remove(0);
+            old
+        }))
+    }
+
+    pub(super) fn create_bucket_in(&mut self, bucket: &[Vec<u8>], name: &[u8]) -> Result<()> {
+        if self.has_bucket(bucket, name)? {
+            return Err(BTreeError::BucketExists(name.to_vec()));
+        }
+        let root = self.allocate()?;
+        self.write_node(root, &Node::Leaf(Leaf { cells: Vec::new() }))?;
+        if let Err(e) = self.put(bucket, name, &bucket_value(root)) {
+            self.release(root);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// whether `name` holds a bucket; an error if it holds a value
+    pub(super) fn has_bucket(&mut self, bucket: &[Vec<u8>], name: &[u8]) -> Result<bool> {
+        let root = self.bucket_root(bucket)?;
+        match self.lookup(root, name)? {
+            Some(stored) if stored[0] == BTREE_VALUE => Err(BTreeError::NotABucket(name.to_vec())),
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    pub(super) fn delete_bucket_in(&mut self, bucket: &[Vec<u8>], name: &[u8]) -> Result<bool> {
+        match self.remove(bucket, name, BTREE_BUCKET)? {
+            Some(stored) => {
+                self.free_tree(get_u64(&stored, 1).unwrap(), 0)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// root of the bucket at `path`, the top-level root for an empty path
+    pub(super) fn bucket_root(&mut self, path: &[Vec<u8>]) -> Result<PageId> {
+        let mut root = self.root;
+        for name in path {
+            root = match self.lookup(root, name)? {
+                Some(stored) if stored[0] == BTREE_BUCKET => get_u64(&stored, 1).unwrap(),
+                _ => return Err(BTreeError::NotABucket(name.clone())),
+            };
+        }
+        Ok(root)
+    }
+
+    /// the stored value of `key` in the tree at `root`, kind byte included
+    fn lookup(&mut self, mut id: PageId, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        for depth in 0.. {
+            match self.read_node(id, depth)? {
+                Node::Leaf(leaf) => {
+                    return Ok(search(&leaf.cells, key)
+                        .ok()
+                        .map(|i| leaf.cells.into_iter().nth(i).unwrap().1));
+                }
+                Node::Internal(internal) => id = internal.child(internal.child_pos(key)),
+            }
+        }
+        unreachable!()
+    }
+
+    /// insert or replace a stored value in a bucket, returning the one it
+    /// replaced; the kinds of the two must match
+    fn put(&mut self, bucket: &[Vec<u8>], key: &[u8], stored: &[u8]) -> Result<Option<Vec<u8>>> {
+        let size = Leaf::cell_size(key, stored);
+        if size > MAX_CELL_SIZE || Internal::cell_size(key) > MAX_CELL_SIZE {
+            return Err(BTreeError::TooLarge(size));
+        }
+
+        let root = self.bucket_root(bucket)?;
+        let (old, mut new_root, split) = self.insert_into(root, key, stored, 0)?;
+        if let Some(cell) = split {
+            let node = Node::Internal(Internal {
+                first: new_root,
+                cells: vec![cell],
+            });
+            new_root = self.allocate()?;
+            self.write_node(new_root, &node)?;
+        }
+        if new_root != root {
+            self.set_bucket_root(bucket, new_root)?;
+        }
+        Ok(old)
+    }
+
+    /// remove `key` from a bucket if it holds a value of `kind`, returning
+    /// the stored value
+    fn remove(&mut self, bucket: &[Vec<u8>], key: &[u8], kind: u8) -> Result<Option<Vec<u8>>> {
+        let root = self.bucket_root(bucket)?;
+        let (old, mut new_root, underfull) = self.delete_from(root, key, kind, 0)?;
+        if underfull {
+            // a root left with a single child hands the root over to it
+            if let Node::Internal(internal) = self.read_node(new_root, 0)?
+                && internal.cells.is_empty()
+            {
+                self.release(new_root);
+                new_root = internal.first;
+            }
+        }
+        if new_root != root {
+            self.set_bucket_root(bucket, new_root)?;
+        }
+        Ok(old)
+    }
+
+    pub(super) fn set_bucket_root(&mut self, path: &[Vec<u8>], root: PageId) -> Result<()> {
+        match path.split_last() {
+            Some((name, parent)) => {
+                self.put(parent, name, &bucket_value(root))?;
+            }
+            None => self.root = root,
+        }
+        Ok(())
+    }
+
+    /// release every page of the tree at `id`, nested buckets included
+    fn free_tree(&mut self, id: PageId, depth: usize) -> Result<()> {
+        match self.read_node(id, depth)? {
+            Node::Leaf(leaf) => {
+                for (_, stored) in &leaf.cells {
+                    if stored[0] == BTREE_BUCKET {
+                        self.free_tree(get_u64(stored, 1).unwrap(), 0)?;
+                    }
+                }
+            }
+            Node::Internal(internal) => {
+                for pos in 0..=internal.cells.len() {
+                    self.free_tree(internal.child(pos), depth + 1)?;
+                }
+            }
+        }
+        self.release(id);
+        Ok(())
+    }
+
+    /// make every change since the last commit durable, atomically
+    pub fn commit(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(BTreeError::ReadOnly);
+        }
+        if self.root == self.meta.root && self.txn_pages.is_empty() && self.freed.is_empty() {
+            return Ok(());
+        }
+
+        // the new list replaces the pages of the old one, which this commit
+        // frees; pages for it come out of what it lists
+        let mut freed = mem::take(&mut self.freed);
+        freed.append(&mut self.free_list_pages);
+        let mut list_pages = Vec::new();
+        while (self.free.len() + freed.len()).div_ceil(FREE_IDS_PER_PAGE) > list_pages.len() {
+            list_pages.push(self.allocate()?);
+        }
+        let mut ids: Vec<PageId> = self.free.iter().chain(&freed).copied().collect();
+        ids.sort_unstable();
+        let mut chunks = ids.chunks(FREE_IDS_PER_PAGE);
+        for (i, &id) in list_pages.iter().enumerate() {
+            let next = list_pages.get(i + 1).copied().unwrap_or(0);
+            let page = encode_free_list(next, chunks.next().unwrap_or_default());
+            self.pool.write_page(id, &page)?;
+        }
+
+        // every page the new meta page reaches is durable before it is
+        self.pool.checkpoint()?;
+        let meta = MetaPage {
+            root: self.root,
+            free_list: list_pages.first().copied().unwrap_or(0),
+            txn: self.meta.txn + 1,
+            num_pages: self.pool.num_pages(),
+        };
+        self.pool.write_page(meta.page_id(), &meta.encode())?;
+        self.pool.checkpoint()?;
+
+        self.meta = meta;
+        self.free.extend(freed);
+        self.free_list_pages = list_pages;
+        self.txn_pages.clear();
+        Ok(())
+    }
+
+    /// the valid meta page with the highest txn
+    fn current_meta(pool: &mut BufferPool) -> Result<MetaPage> {
+        let mut current: Option<MetaPage> = None;
+        let mut error = None;
+        for id in 0..META_PAGES {
+            let mut buf = [0; PAGE_SIZE];
+            pool.read_page(id, &mut buf)?;
+            match MetaPage::decode(&buf) {
+                Ok(meta) if current.is_none_or(|current| meta.txn > current.txn) => {
+                    current = Some(meta)
+                }
+                Ok(_) => {}
+                Err(e) => error = Some(BTreeError::Page(id, e)),
+            }
+        }
+        let meta = match (current, error) {
+            (Some(meta), _) => meta,
+            (None, Some(error)) => return Err(error),
+            (None, None) => unreachable!(),
+        };
+
+        let pages = pool.num_pages();
+        if meta.root < META_PAGES || meta.root >= pages || meta.free_list >= pages {
+            return Err(BTreeError::Corrupted(format!(
+                "meta page points outside the file ({} pages): root {}, free list {}",
+                pages, meta.root, meta.free_list
+            )));
+        }
+        if meta.num_pages > pages {
+            return Err(BTreeError::Corrupted(format!(
+                "last commit had {} pages, the file has {}",
+                meta.num_pages, pages
+            )));
+        }
+        Ok(meta)
+    }
+
+    /// read the last commit's free list
+    fn load_free_list(&mut self) -> Result<()> {
+        let mut id = self.meta.free_list;
+        while id != 0 {
+            if self.free_list_pages.len() as u64 >= self.pool.num_pages() {
+                return Err(BTreeError::Corrupted("free list loops".to_string()));
+            }
+            let mut buf = [0; PAGE_SIZE];
+            self.pool.read_page(id, &mut buf)?;
+            let count =
+                page::open(&buf, PageType::FreeList).map_err(|e| BTreeError::Page(id, e))? as usize;
+            if count > FREE_IDS_PER_PAGE {
+                let e = PageError::Corrupted(format!("{} free page ids", count));
+                return Err(BTreeError::Page(id, e));
+            }
+            self.free_list_pages.push(id);
+            self.free
+                .extend((0..count).map(|i| get_u64(&buf, FREE_LIST_HEADER_SIZE + i * 8).unwrap()));
+            id = get_u64(&buf, PAGE_HEADER_SIZE).unwrap();
+        }
+        Ok(())
+    }
+
+    /// a page for this transaction, reusing a free one if there is any
+    pub(super) fn allocate(&mut self) -> Result<PageId> {
+        if self.read_only {
+            return Err(BTreeError::ReadOnly);
+        }
+        let id = match self.free.pop_first() {
+            Some(id) => id,
+            None => self.pool.allocate_page()?,
+        };
+        self.txn_pages.insert(id);
+        Ok(id)
+    }
+
+    /// stop using page `id`
+    pub(super) fn release(&mut self, id: PageId) {
+        if self.txn_pages.remove(&id) {
+            // no commit has seen it
+            self.free.insert(id);
+        } else {
+            self.freed.push(id);
+        }
+    }
+
+    /// write `node`, which was read from page `id`, in place if this
+    /// transaction owns that page or else to a fresh one; returns the page
+    fn store(&mut self, id: PageId, node: &Node) -> Result<PageId> {
+        if self.read_only {
+            return Err(BTreeError::ReadOnly);
+        }
+        let id = if self.txn_pages.contains(&id) {
+            id
+        } else {
+            self.freed.push(id);
+            self.allocate()?
+        };
+        self.write_node(id, node)?;
+        Ok(id)
+    }
+
+    /// insert into the subtree at `id`, returning the replaced value, the
+    /// page the subtree's root now lives at, and its split, if any
+    fn insert_into(
+        &mut self,
+        id: PageId,
+        key: &[u8],
+        value: &[u8],
+        depth: usize,
+    ) -> Result<(Option<Vec<u8>>, PageId, Option<Split>)> {
+        let (old, node) = match self.read_node(id, depth)? {
+            Node::Leaf(mut leaf) => {
+                let old = match search(&leaf.cells, key) {
+                    Ok(i) if leaf.cells[i].1[0] != value[0] => {
+                        return Err(kind_mismatch(key, leaf.cells[i].1[0]));
+                    }
+                    Ok(i) => Some(mem::replace(&mut leaf.cells[i].1, value.to_vec())),
+                    Err(i) => {
+                        leaf.cells.insert(i, (key.to_vec(), value.to_vec()));
+                        None
+                    }
+                };
+                (old, Node::Leaf(leaf))
+            }
+            Node::Internal(mut internal) => {
+                let pos = internal.child_pos(key);
+                let child = internal.child(pos);
+                let (old, moved, split) = self.insert_into(child, key, value, depth + 1)?;
+                if moved == child && split.is_none() {
+                    return Ok((old, id, None));
+                }
+                internal.set_child(pos, moved);
+                if let Some(cell) = split {
+                    internal.cells.insert(pos, cell);
+                }
+                (old, Node::Internal(internal))
+            }
+        };
+
+        if node.cells_size() <= NODE_CAPACITY {
+            return Ok((old, self.store(id, &node)?, None));
+        }
+        let (left, separator, right) = match node {
+            Node::Leaf(mut leaf) => {
+                let right = Leaf {
+                    cells: leaf.split(),
+                };
+                let separator = right.cells[0].0.clone();
+                (Node::Leaf(leaf), separator, Node::Leaf(right))
+            }
+            Node::Internal(mut internal) => {
+                let (separator, right) = internal.split();
+                (Node::Internal(internal), separator, Node::Internal(right))
+            }
+        };
+        let left_id = self.store(id, &left)?;
+        let right_id = self.allocate()?;
+        self.write_node(right_id, &right)?;
+        Ok((old, left_id, Some((separator, right_id))))
+    }
+
+    /// delete from the subtree at `id`, returning the removed value, the page
+    /// the subtree's root now lives at, and whether that node is left under
+    /// MIN_FILL
+    fn delete_from(
+        &mut self,
+        id: PageId,
+        key: &[u8],
+        kind: u8,
+        depth: usize,
+    ) -> Result<(Option<Vec<u8>>, PageId, bool)> {
+        match self.read_node(id, depth)? {
+            Node::Leaf(mut leaf) => {
+                let Ok(i) = search(&leaf.cells, key) else {
+                    return Ok((None, id, false));
+                };
+                if leaf.cells[i].1[0] != kind {
+                    return Err(kind_mismatch(key, leaf.cells[i].1[0]));
+                }
+                let (_, old) = leaf.cells.remove(i);
+                let node = Node::Leaf(leaf);
+                let id = self.store(id, &node)?;
+                Ok((Some(old), id, node.cells_size() < MIN_FILL))
+            }
+            Node::Internal(mut internal) => {
+                let pos = internal.child_pos(key);
+                let child = internal.child(pos);
+                let (old, moved, underfull) = self.delete_from(child, key, kind, depth + 1)?;
+                if moved == child && !underfull {
+                    return Ok((old, id, false));
+                }
+                internal.set_child(pos, moved);
+                if underfull {
+                    self.rebalance(&mut internal, pos, depth + 1)?;
+                }
+                let node = Node::Internal(internal);
+                let id = self.store(id, &node)?;
+                Ok((old, id, node.cells_size() < MIN_FILL))
+            }
+        }
+    }
+
+    /// merge the underfull child at `pos` of `parent` with a sibling, or
+    /// share the sibling's cells with it when both don't fit in one page
+    fn rebalance(&mut self, parent: &mut Internal, pos: usize, depth: usize) -> Result<()> {
+        if parent.cells.is_empty() {
+            return Ok(());
+        }
+        // the pair is children `at` and `at + 1`, separated by cells[at]
+        let at = pos.saturating_sub(1);
+        let left_id = parent.child(at);
+        let right_id = parent.child(at + 1);
+        let left = self.read_node(left_id, depth)?;
+        let right = self.read_node(right_id, depth)?;
+
+        let (left, right) = match (left, right) {
+            (Node::Leaf(mut left), Node::Leaf(mut right)) => {
+                left.cells.append(&mut right.cells);
+                if left.cells_size() <= NODE_CAPACITY {
+                    (Node::Leaf(left), None)
+                } else {
+                    right.cells = left.split();
+                    parent.cells[at].0 = right.cells[0].0.clone();
+                    (Node::Leaf(left), Some(Node::Leaf(right)))
+                }
+            }
+            (Node::Internal(mut left), Node::Internal(mut right)) => {
+                let separator = mem::take(&mut parent.cells[at].0);
+                left.cells.push((separator, right.first));
+                left.cells.append(&mut right.cells);
+                if left.cells_size() <= NODE_CAPACITY {
+                    (Node::Internal(left), None)
+                } else {
+                    let (separator, right) = left.split();
+                    parent.cells[at].0 = separator;
+                    (Node::Internal(left), Some(Node::Internal(right)))
+                }
+            }
+            _ => {
+                return Err(BTreeError::Corrupted(format!(
+                    "sibling pages {} and {} are at different depths",
+                    left_id, right_id
+                )));
+            }
+        };
+
+        let left_id = self.store(left_id, &left)?;
+        parent.set_child(at, left_id);
+        match right {
+            Some(right) => {
+                let right_id = self.store(right_id, &right)?;
+                parent.set_child(at + 1, right_id);
+            }
+            None => {
+                self.release(right_id);
+                parent.cells.remove(at);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn read_node(&mut self, id: PageId, depth: usize) -> Result<Node> {
+        if depth > MAX_DEPTH {
+            return Err(BTreeError::Corrupted(format!(
+                "deeper than {} levels at page {}",
+                MAX_DEPTH, id
+            )));
+        }
+        let mut buf = [0; PAGE_SIZE];
+        self.pool.read_page(id, &mut buf)?;
+        Node::decode(&buf).map_err(|e| BTreeError::Page(id, e))
+    }
+
+    pub(super) fn write_node(&mut self, id: PageId, node: &Node) -> Result<()> {
+        Ok(self.pool.write_page(id, &node.encode())?)
+    }
+}
+
+fn encode_free_list(next: PageId, ids: &[PageId]) -> Page {
+    let mut page = [0u8; PAGE_SIZE];
+    page[PAGE_HEADER_SIZE..FREE_LIST_HEADER_SIZE].copy_from_slice(&next.to_le_bytes());
+    for (i, id) in ids.iter().enumerate() {
+        let at = FREE_LIST_HEADER_SIZE + i * 8;
+        page[at..at + 8].copy_from_slice(&id.to_le_bytes());
+    }
+    page::seal(&mut page, PageType::FreeList, ids.len() as u16);
+    page
+}
+
+/// stored value of a bucket with root `root`
+fn bucket_value(root: PageId) -> Vec<u8> {
+    let mut stored = vec![BTREE_BUCKET];
+    stored.extend_from_slice(&root.to_le_bytes());
+    stored
+}
+
+/// the error for an operation on `key` expecting the kind it doesn't hold
+fn kind_mismatch(key: &[u8], found: u8) -> BTreeError {
+    match found {
+        BTREE_BUCKET => BTreeError::IsBucket(key.to_vec()),
+        _ => BTreeError::NotABucket(key.to_vec()),
+    }
+}
+
+fn search<V>(cells: &[(Vec<u8>, V)], key: &[u8]) -> std::result::Result<usize, usize> {
+    cells.binary_search_by(|(k, _)| k.as_slice().cmp(key))
+}
+
+/// index splitting cells of the given sizes into two halves by bytes, with
+/// at least one cell on each side
+pub(super) fn split_point(sizes: impl Iterator<Item = usize>) -> usize {
+    let sizes: Vec<usize> = sizes.collect();
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut total = 0;
+    let at = sizes
+        .iter()
+        .position(|size| {
+            total += size;
+            total >= half
+        })
+        .map_or(sizes.len(), |i| i + 1);
+    at.clamp(1, sizes.len() - 1)
+}
+
+impl Leaf {
+    pub(super) fn cell_size(key: &[u8], value: &[u8]) -> usize {
+        SLOT_SIZE + BTREE_LEAF_CELL_HEADER_SIZE + key.len() + value.len()
+    }
+
+    /// keep the first half of the cells, returning the rest
+    fn split(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let at = split_point(self.cells.iter().map(|(k, v)| Self::cell_size(k, v)));
+        self.cells.split_off(at)
+    }
+
+    fn cells_size(&self) -> usize {
+        self.cells.iter().map(|(k, v)| Self::cell_size(k, v)).sum()
+    }
+}
+
+impl Internal {
+    pub(super) fn cell_size(key: &[u8]) -> usize {
+        SLOT_SIZE + BTREE_INTERNAL_CELL_HEADER_SIZE + key.len()
+    }
+
+    /// position of the child whose range holds `key`
+    pub(super) fn child_pos(&self, key: &[u8]) -> usize {
+        self.cells.partition_point(|(k, _)| k.as_slice() <= key)
+    }
+
+    pub(super) fn child(&self, pos: usize) -> PageId {
+        match pos {
+            0 => self.first,
+            _ => self.cells[pos - 1].1,
+        }
+    }
+
+    fn set_child(&mut self, pos: usize, id: PageId) {
+        match pos {
+            0 => self.first = id,
+            _ => self.cells[pos - 1].1 = id,
+        }
+    }
+
+    /// keep the first half of the cells, returning the separator to move up
+    /// and a node holding the rest
+    fn split(&mut self) -> (Vec<u8>, Internal) {
+        let at = split_point(self.cells.iter().map(|(k, _)| Self::cell_size(k)));
+        let mut rest = self.cells.split_off(at).into_iter();
+        let (separator, first) = rest.next().unwrap();
+        let right = Internal {
+            first,
+            cells: rest.collect(),
+        };
+        (separator, right)
+    }
+
+    fn cells_size(&self) -> usize {
+        self.cells.iter().map(|(k, _)| Self::cell_size(k)).sum()
+    }
+}
+
+impl Node {
+    fn cells_size(&self) -> usize {
+        match self {
+            Node::Leaf(leaf) => leaf.cells_size(),
+            Node::Internal(internal) => internal.cells_size(),
+        }
+    }
+
+    fn encode(&self) -> Page {
+        let mut page = SlottedPage::new(NODE_HEADER_LEN);
+        let mut cell = Vec::new();
+        let page_type = match self {
+            Node::Leaf(leaf) => {
+                for (key, value) in &leaf.cells {
+                    cell.clear();
+                    cell.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    cell.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                    cell.extend_from_slice(key);
+                    cell.extend_from_slice(value);
+                    assert!(page.insert(page.len(), &cell));
+                }
+                PageType::BTreeLeaf
+            }
+            Node::Internal(internal) => {
+                page.header_mut()
+                    .copy_from_slice(&internal.first.to_le_bytes());
+                for (key, child) in &internal.cells {
+                    cell.clear();
+                    cell.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                    cell.extend_from_slice(key);
+                    cell.extend_from_slice(&child.to_le_bytes());
+                    assert!(page.insert(page.len(), &cell));
+                }
+                PageType::BTreeInternal
+            }
+        };
+        page.seal(page_type)
+    }
+
+    fn decode(page: &Page) -> page::Result<Self> {
+        let page_type = match page::page_type(page) {
+            Some(page_type @ (PageType::BTreeLeaf | PageType::BTreeInternal)) => page_type,
+            _ => {
+                return Err(PageError::Corrupted(format!(
+                    "type {:#04x} is not a B-tree node",
+                    page[4]
+                )));
+            }
+        };
+        let page = SlottedPage::open(page, page_type, NODE_HEADER_LEN)?;
+        let bad_cell = |i: usize| PageError::Corrupted(format!("cell {} doesn't decode", i));
+
+        if page_type == PageType::BTreeLeaf {
+            let mut cells = Vec::with_capacity(page.len());
+            for (i, cell) in page.cells().enumerate() {
+                let key_len = get_u16(cell, 0).ok_or_else(|| bad_cell(i))? as usize;
+                let value_len = get_u16(cell, 2).ok_or_else(|| bad_cell(i))? as usize;
+                let at = BTREE_LEAF_CELL_HEADER_SIZE;
+                if cell.len() != at + key_len + value_len {
+                    return Err(bad_cell(i));
+                }
+                match cell.get(at + key_len) {
+                    Some(&BTREE_VALUE) => {}
+                    Some(&BTREE_BUCKET) if value_len == 9 => {}
+                    _ => return Err(bad_cell(i)),
+                }
+                cells.push((
+                    cell[at..at + key_len].to_vec(),
+                    cell[at + key_len..].to_vec(),
+                ));
+            }
+            Ok(Node::Leaf(Leaf { cells }))
+        } else {
+            let first = get_u64(page.header(), 0).unwrap();
+            let mut cells = Vec::with_capacity(page.len());
+            for (i, cell) in page.cells().enumerate() {
+                let key_len = get_u16(cell, 0).ok_or_else(|| bad_cell(i))? as usize;
+                if cell.len() != BTREE_INTERNAL_CELL_HEADER_SIZE + key_len {
+                    return Err(bad_cell(i));
+                }
+                let child = get_u64(cell, 2 + key_len).unwrap();
+                cells.push((cell[2..2 + key_len].to_vec(), child));
+            }
+            Ok(Node::Internal(Internal { first, cells }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(name);
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    /// long enough that internal nodes split too
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:06}{:200}", i, "").into_bytes()
+    }
+
+    /// every key in order and the depth, checking all leaves are level
+    fn scan(tree: &mut BTree) -> (Vec<Vec<u8>>, usize) {
+        fn walk(tree: &mut BTree, id: PageId, depth: usize, keys: &mut Vec<Vec<u8>>) -> usize {
+            match tree.read_node(id, depth).unwrap() {
+                Node::Leaf(leaf) => {
+                    keys.extend(leaf.cells.into_iter().map(|(k, _)| k));
+                    depth
+                }
+                Node::Internal(internal) => {
+                    let depths: Vec<usize> = (0..=internal.cells.len())
+                        .map(|pos| walk(tree, internal.child(pos), depth + 1, keys))
+                        .collect();
+                    assert!(depths.iter().all(|&d| d == depths[0]), "page {}", id);
+                    depths[0]
+                }
+            }
+        }
+        let mut keys = Vec::new();
+        let depth = walk(tree, tree.root(), 0, &mut keys);
+        (keys, depth)
+    }
+
+    #[test]
+    fn test_insert_get_delete() {
+        let path = test_path("test_btree_basic.db");
+        let mut tree = BTree::open(&path).unwrap();
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert_eq!(tree.insert(b"a", b"1").unwrap(), None);
+        assert_eq!(tree.insert(b"b", b"2").unwrap(), None);
+        assert_eq!(tree.insert(b"a", b"3").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(tree.delete(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(tree.delete(b"a").unwrap(), None);
+        assert_eq!(tree.get(b"a").unwrap(), None);
+        assert_eq!(tree.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        let too_large = vec![0; MAX_CELL_SIZE];
+        assert!(matches!(
+            tree.insert(b"big", &too_large),
+            Err(BTreeError::TooLarge(_))
+        ));
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_split_merge_and_reopen() {
+        let path = test_path("test_btree_split_merge.db");
+        // a pool far smaller than the tree, so dirty pages get evicted
+        let mut tree = BTree::open_with_pool_pages(&path, 8).unwrap();
+        let value = [7u8; 100];
+        // a fixed permutation of 0..n
+        let n = 3000;
+        let order: Vec<u32> = (0..n).map(|i| (i * 1237) % n).collect();
+        for &i in &order {
+            tree.insert(&key(i), &value).unwrap();
+        }
+        let (keys, depth) = scan(&mut tree);
+        assert_eq!(keys, (0..n).map(key).collect::<Vec<_>>());
+        assert!(depth >= 2, "depth {}", depth);
+        tree.commit().unwrap();
+        drop(tree);
+
+        let mut tree = BTree::open(&path).unwrap();
+        for i in (0..n).step_by(7) {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(value.to_vec()));
+        }
+        assert_eq!(tree.get(b"key999999").unwrap(), None);
+
+        // delete all but every tenth key
+        for &i in &order {
+            if i % 10 != 0 {
+                assert!(tree.delete(&key(i)).unwrap().is_some());
+            }
+        }
+        let (keys, shallower) = scan(&mut tree);
+        assert_eq!(keys, (0..n).step_by(10).map(key).collect::<Vec<_>>());
+        assert!(shallower < depth);
+
+        for i in (0..n).step_by(10) {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(value.to_vec()));
+            tree.delete(&key(i)).unwrap();
+        }
+        let (keys, depth) = scan(&mut tree);
+        assert!(keys.is_empty());
+        assert_eq!(depth, 0);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_pages_reused() {
+        let path = test_path("test_btree_pages_reused.db");
+        let mut tree = BTree::open(&path).unwrap();
+        let value = [1u8; 200];
+        for i in 0..500 {
+            tree.insert(&key(i), &value).unwrap();
+        }
+        tree.commit().unwrap();
+        let pages = tree.pool.num_pages();
+        for i in 0..500 {
+            tree.delete(&key(i)).unwrap();
+        }
+        tree.commit().unwrap();
+        assert_ne!(tree.meta.free_list, 0);
+        drop(tree);
+
+        // the free list comes back with the meta page
+        let mut tree = BTree::open(&path).unwrap();
+        for i in 0..500 {
+            tree.insert(&key(i), &value).unwrap();
+        }
+        tree.commit().unwrap();
+        // pages of the last commit can't be reused until the next one
+        let grown = tree.pool.num_pages() - pages;
+        assert!(grown <= 2, "grew by {} pages", grown);
+        let (keys, _) = scan(&mut tree);
+        assert_eq!(keys.len(), 500);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_commit_is_atomic() {
+        let path = test_path("test_btree_commit.db");
+        let mut tree = BTree::open(&path).unwrap();
+        for i in 0..100 {
+            tree.insert(&key(i), b"1").unwrap();
+        }
+        tree.commit().unwrap();
+        for i in 0..100 {
+            tree.insert(&key(i), b"2").unwrap();
+        }
+        tree.commit().unwrap();
+        assert_eq!(tree.txn(), 2);
+        drop(tree);
+
+        // with the newest meta page torn, open falls back to the commit
+        // before it, whose pages the newer one did not overwrite
+        let mut pager = PageManager::open(&path).unwrap();
+        let mut buf = [0; PAGE_SIZE];
+        pager.read_page(2 % META_PAGES, &mut buf).unwrap();
+        buf[PAGE_HEADER_SIZE] ^= 1;
+        pager.write_page(2 % META_PAGES, &buf).unwrap();
+        drop(pager);
+        let mut tree = BTree::open(&path).unwrap();
+        assert_eq!(tree.txn(), 1);
+        let (keys, _) = scan(&mut tree);
+        assert_eq!(keys.len(), 100);
+        for i in 0..100 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(b"1".to_vec()));
+        }
+
+        // changes after the last commit are lost
+        tree.insert(&key(0), b"3").unwrap();
+        tree.insert(&key(100), b"3").unwrap();
+        drop(tree);
+        let mut tree = BTree::open(&path).unwrap();
+        assert_eq!(tree.get(&key(0)).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get(&key(100)).unwrap(), None);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_corrupted_node() {
+        let path = test_path("test_btree_corrupted.db");
+        let mut tree = BTree::open(&path).unwrap();
+        tree.insert(b"a", b"1").unwrap();
+        tree.commit().unwrap();
+        let root = tree.root();
+        drop(tree);
+
+        let mut pager = PageManager::open(&path).unwrap();
+        let mut buf = [0; PAGE_SIZE];
+        pager.read_page(root, &mut buf).unwrap();
+        buf[PAGE_SIZE - 1] ^= 1;
+        pager.write_page(root, &buf).unwrap();
+        drop(pager);
+
+        let mut tree = BTree::open(&path).unwrap();
+        assert!(matches!(
+            tree.get(b"a"),
+            Err(BTreeError::Page(_, PageError::Checksum))
+        ));
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,162 @@
+use super::btree::{BTree, Result};
+use super::cursor::Cursor;
+
+/// Bucket: a named tree nested in a BTree or in another bucket
+///    - keys of different buckets never collide, so a bucket is a namespace
+///      without prefixing keys
+///    - its root page is the value of its name in the parent; any change
+///      that moves the root rewrites that value, which may move the parent's
+///      root in turn
+///    - a name holds either a bucket or a value; using it as the other is an
+///      error
+///    - changes are part of the tree's transaction, made durable by
+///      BTree::commit
+pub struct Bucket<'a> {
+    tree: &'a mut BTree,
+
+    /// names from the top-level bucket down to this one
+    path: Vec<Vec<u8>>,
+}
+
+impl<'a> Bucket<'a> {
+    pub(super) fn new(tree: &'a mut BTree, path: Vec<Vec<u8>>) -> Self {
+        Self { tree, path }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        self.path.last().unwrap()
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.get_in(&self.path, key)
+    }
+
+    /// insert or replace `key`, returning the value it replaced
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.insert_in(&self.path, key, value)
+    }
+
+    /// remove `key`, returning its value if it was present
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.delete_in(&self.path, key)
+    }
+
+    /// create an empty bucket `name` inside this one
+    pub fn create_bucket(&mut self, name: &[u8]) -> Result<Bucket<'_>> {
+        self.tree.create_bucket_in(&self.path, name)?;
+        Ok(Bucket::new(self.tree, self.child_path(name)))
+    }
+
+    /// the bucket `name` inside this one, if there is one
+    pub fn bucket(&mut self, name: &[u8]) -> Result<Option<Bucket<'_>>> {
+        Ok(self
+            .tree
+            .has_bucket(&self.path, name)?
+            .then(|| Bucket::new(self.tree, self.child_path(name))))
+    }
+
+    /// delete the bucket `name` inside this one and everything in it; false
+    /// if there was none
+    pub fn delete_bucket(&mut self, name: &[u8]) -> Result<bool> {
+        self.tree.delete_bucket_in(&self.path, name)
+    }
+
+    /// fill this empty bucket from `entries` in ascending key order, see
+    /// BTree::bulk_load
+    pub fn bulk_load<I, K, V>(&mut self, entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.tree.bulk_load_in(&self.path, entries)
+    }
+
+    /// an unpositioned cursor over this bucket
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        Cursor::new(self.tree, self.path.clone())
+    }
+
+    fn child_path(&self, name: &[u8]) -> Vec<Vec<u8>> {
+        let mut path = self.path.clone();
+        path.push(name.to_vec());
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::BTreeError;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_nested_buckets() {
+        let path = env::temp_dir().join("test_btree_buckets.db");
+        fs::remove_file(&path).ok();
+        let mut tree = BTree::open(&path).unwrap();
+        tree.insert(b"key", b"top").unwrap();
+
+        let mut users = tree.create_bucket(b"users").unwrap();
+        assert_eq!(users.name(), b"users");
+        assert_eq!(users.insert(b"key", b"user").unwrap(), None);
+        // enough to split the bucket's root, moving it to a new page
+        for i in 0..500u32 {
+            users
+                .insert(format!("user{:04}", i).as_bytes(), &[1; 50])
+                .unwrap();
+        }
+        let mut admins = users.create_bucket(b"admins").unwrap();
+        admins.insert(b"key", b"admin").unwrap();
+        tree.commit().unwrap();
+        drop(tree);
+
+        let mut tree = BTree::open(&path).unwrap();
+        assert_eq!(tree.get(b"key").unwrap(), Some(b"top".to_vec()));
+        // a bucket's name is not a value
+        assert_eq!(tree.get(b"users").unwrap(), None);
+        assert!(matches!(
+            tree.insert(b"users", b"x"),
+            Err(BTreeError::IsBucket(_))
+        ));
+        assert!(matches!(
+            tree.bucket(b"key"),
+            Err(BTreeError::NotABucket(_))
+        ));
+        assert!(matches!(
+            tree.create_bucket(b"users"),
+            Err(BTreeError::BucketExists(_))
+        ));
+        assert!(tree.bucket(b"missing").unwrap().is_none());
+
+        let mut users = tree.bucket(b"users").unwrap().unwrap();
+        assert_eq!(users.get(b"key").unwrap(), Some(b"user".to_vec()));
+        assert_eq!(users.get(b"user0042").unwrap(), Some(vec![1; 50]));
+        let mut admins = users.bucket(b"admins").unwrap().unwrap();
+        assert_eq!(admins.get(b"key").unwrap(), Some(b"admin".to_vec()));
+
+        let mut cursor = users.cursor();
+        assert!(cursor.first().unwrap());
+        assert!(cursor.is_bucket());
+        assert_eq!(cursor.key().unwrap(), b"admins");
+        assert!(cursor.next().unwrap());
+        assert_eq!(cursor.value().unwrap(), b"user");
+
+        // deleting a bucket frees its pages, nested buckets included
+        tree.commit().unwrap();
+        let pages = tree.num_pages();
+        assert!(tree.delete_bucket(b"users").unwrap());
+        assert!(!tree.delete_bucket(b"users").unwrap());
+        tree.commit().unwrap();
+        let mut users = tree.create_bucket(b"users").unwrap();
+        for i in 0..500u32 {
+            users
+                .insert(format!("user{:04}", i).as_bytes(), &[1; 50])
+                .unwrap();
+        }
+        tree.commit().unwrap();
+        assert!(tree.num_pages() <= pages + 2);
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::io;
+
+use super::page::{PAGE_SIZE, Page, PageId};
+use super::pagemanager::PageManager;
+
+/// frames a pool gets when the caller doesn't choose
+pub const DEFAULT_POOL_PAGES: usize = 256;
+
+/// BufferPool: up to `capacity` pages of a PageManager kept in memory
+///    - pin() loads a page and keeps it resident until the matching unpin();
+///      page() and page_mut() reach pinned (or otherwise cached) frames
+///    - frames changed through page_mut() or write_page() are dirty and only
+///      reach the file when evicted, on checkpoint(), or on drop
+///    - the least recently used unpinned frame is evicted when a new page
+///      needs room; with every frame pinned, loading another page fails
+pub struct BufferPool {
+    pager: PageManager,
+    capacity: usize,
+    frames: HashMap<PageId, Frame>,
+
+    /// bumped on every access, orders frames for eviction
+    clock: u64,
+}
+
+struct Frame {
+    page: Box<Page>,
+    pins: usize,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl BufferPool {
+    pub fn new(pager: PageManager, capacity: usize) -> Self {
+        Self {
+            pager,
+            capacity: capacity.max(1),
+            frames: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// frames currently held, pinned or not
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn num_pages(&self) -> u64 {
+        self.pager.num_pages()
+    }
+
+    pub fn free_list(&self) -> PageId {
+        self.pager.free_list()
+    }
+
+    pub fn set_free_list(&mut self, head: PageId) {
+        self.pager.set_free_list(head);
+    }
+
+    /// load page `id` if needed and keep it resident until unpinned
+    pub fn pin(&mut self, id: PageId) -> io::Result<()> {
+        if !self.frames.contains_key(&id) {
+            self.make_room()?;
+            let mut page = Box::new([0; PAGE_SIZE]);
+            self.pager.read_page(id, &mut page)?;
+            self.insert(id, page, false);
+        }
+        let frame = self.touch(id).unwrap();
+        frame.pins += 1;
+        Ok(())
+    }
+
+    /// release a pin taken by pin(); `dirty` marks the page as changed
+    pub fn unpin(&mut self, id: PageId, dirty: bool) {
+        if let Some(frame) = self.frames.get_mut(&id) {
+            debug_assert!(frame.pins > 0, "page {} unpinned more than pinned", id);
+            frame.pins = frame.pins.saturating_sub(1);
+            frame.dirty |= dirty;
+        }
+    }
+
+    /// a cached page, normally one the caller has pinned
+    pub fn page(&self, id: PageId) -> Option<&Page> {
+        self.frames.get(&id).map(|frame| &*frame.page)
+    }
+
+    /// a cached page to change in place, marking it dirty
+    pub fn page_mut(&mut self, id: PageId) -> Option<&mut Page> {
+        let frame = self.frames.get_mut(&id)?;
+        frame.dirty = true;
+        Some(&mut frame.page)
+    }
+
+    /// copy page `id` into `buf`, through the pool
+    pub fn read_page(&mut self, id: PageId, buf: &mut Page) -> io::Result<()> {
+        self.pin(id)?;
+        buf.copy_from_slice(self.page(id).unwrap());
+        self.unpin(id, false);
+        Ok(())
+    }
+
+    /// replace page `id`; the file sees it on eviction or checkpoint, or at
+    /// once when the page lies past its end
+    pub fn write_page(&mut self, id: PageId, page: &Page) -> io::Result<()> {
+        if id >= self.pager.num_pages() {
+            return self.pager.write_page(id, page);
+        }
+        if let Some(frame) = self.touch(id) {
+            frame.page.copy_from_slice(page);
+            frame.dirty = true;
+            return Ok(());
+        }
+        self.make_room()?;
+        self.insert(id, Box::new(*page), true);
+        Ok(())
+    }
+
+    pub fn allocate_page(&mut self) -> io::Result<PageId> {
+        self.pager.allocate_page()
+    }
+
+    /// hand page `id` back to the free list, dropping its frame unwritten
+    pub fn free_page(&mut self, id: PageId) -> io::Result<()> {
+        if let Some(frame) = self.frames.get(&id) {
+            if frame.pins > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("page {} is pinned and cannot be freed", id),
+                ));
+            }
+            self.frames.remove(&id);
+        }
+        self.pager.free_page(id)
+    }
+
+    /// cut the file back to `num_pages` pages, dropping the frames past them
+    /// unwritten
+    pub fn truncate(&mut self, num_pages: u64) -> io::Result<()> {
+        self.frames.retain(|&id, _| id < num_pages);
+        self.pager.truncate(num_pages)
+    }
+
+    /// read the file as if it ended after `num_pages` pages, see
+    /// PageManager::limit
+    pub fn limit(&mut self, num_pages: u64) {
+        self.frames.retain(|&id, _| id < num_pages);
+        self.pager.limit(num_pages)
+    }
+
+    /// write every dirty frame and sync the file
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.flush_dirty()?;
+        self.pager.sync()
+    }
+
+    fn flush_dirty(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<PageId> = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(&id, _)| id)
+            .collect();
+        // in file order, which is kinder to the disk
+        dirty.sort_unstable();
+        for id in dirty {
+            let frame = self.frames.get_mut(&id).unwrap();
+            self.pager.write_page(id, &frame.page)?;
+            frame.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, id: PageId) -> Option<&mut Frame> {
+        self.clock += 1;
+        let clock = self.clock;
+        let frame = self.frames.get_mut(&id)?;
+        frame.last_used = clock;
+        Some(frame)
+    }
+
+    fn insert(&mut self, id: PageId, page: Box<Page>, dirty: bool) {
+        self.clock += 1;
+        let frame = Frame {
+            page,
+            pins: 0,
+            dirty,
+            last_used: self.clock,
+        };
+        self.frames.insert(id, frame);
+    }
+
+    /// evict frames until one more fits
+    fn make_room(&mut self) -> io::Result<()> {
+        while self.frames.len() >= self.capacity {
+            let Some((&victim, _)) = self
+                .frames
+                .iter()
+                .filter(|(_, frame)| frame.pins == 0)
+                .min_by_key(|(_, frame)| frame.last_used)
+            else {
+                return Err(io::Error::other(format!(
+                    "all {} buffer pool frames are pinned",
+                    self.capacity
+                )));
+            };
+            let frame = self.frames.remove(&victim).unwrap();
+            if frame.dirty {
+                self.pager.write_page(victim, &frame.page)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        // best effort; checkpoint() reports errors
+        let _ = self.flush_dirty();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn filled(byte: u8) -> Page {
+        [byte; PAGE_SIZE]
+    }
+
+    fn on_disk(path: &std::path::Path, id: PageId) -> Page {
+        let mut buf = [0; PAGE_SIZE];
+        PageManager::open_read_only(path)
+            .unwrap()
+            .read_page(id, &mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_pin_evict_checkpoint() {
+        let path = env::temp_dir().join("test_buffer_pool.db");
+        fs::remove_file(&path).ok();
+        let mut pager = PageManager::open(&path).unwrap();
+        for id in 0..4 {
+            pager.write_page(id, &filled(id as u8)).unwrap();
+        }
+
+        let mut pool = BufferPool::new(pager, 2);
+        pool.pin(0).unwrap();
+        pool.pin(1).unwrap();
+        assert_eq!(pool.page(1).unwrap()[0], 1);
+        assert!(pool.pin(2).is_err());
+
+        pool.page_mut(0).unwrap()[0] = 42;
+        pool.unpin(0, true);
+        // page 0 is evicted to make room, writing it back
+        assert_eq!(on_disk(&path, 0)[0], 0);
+        pool.pin(2).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert_eq!(on_disk(&path, 0)[0], 42);
+
+        pool.unpin(1, false);
+        pool.unpin(2, false);
+        pool.write_page(3, &filled(9)).unwrap();
+        assert_eq!(on_disk(&path, 3)[0], 3);
+        pool.checkpoint().unwrap();
+        assert_eq!(on_disk(&path, 3)[0], 9);
+
+        // reads come from the frame
+        let mut buf = [0; PAGE_SIZE];
+        pool.write_page(3, &filled(7)).unwrap();
+        pool.read_page(3, &mut buf).unwrap();
+        assert_eq!(buf[0], 7);
+
+        // dirty frames are written back on drop
+        drop(pool);
+        assert_eq!(on_disk(&path, 3)[0], 7);
+        fs::remove_file(&path).ok();
+    }
+}
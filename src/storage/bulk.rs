@@ -0,0 +1,234 @@
+use std::mem;
+
+use super::btree::{
+    BTree, BTreeError, Internal, Leaf, MAX_CELL_SIZE, MIN_FILL, NODE_CAPACITY, Node, Result,
+    split_point,
+};
+use super::page::PageId;
+use crate::format::BTREE_VALUE;
+
+/// bytes of cells a bulk load packs into a node, leaving room for a few
+/// inserts before the first splits
+const BULK_FILL: usize = NODE_CAPACITY * 9 / 10;
+
+/// one level of a bulk-loaded tree, filled left to right
+///    - the full node before the one being filled is held back, so that
+///      the level's last node can take cells from it instead of ending up
+///      nearly empty
+///    - each entry is a key and a value for a leaf, or a child's smallest
+///      key and page for an internal node
+struct Run<V> {
+    held: Vec<(Vec<u8>, V)>,
+    current: Vec<(Vec<u8>, V)>,
+    size: usize,
+}
+
+impl<V> Run<V> {
+    fn new() -> Self {
+        Self {
+            held: Vec::new(),
+            current: Vec::new(),
+            size: 0,
+        }
+    }
+
+    /// add an entry of `size` bytes; returns the node it pushed out of the
+    /// held slot, ready to write
+    fn push(&mut self, key: Vec<u8>, value: V, size: usize) -> Option<Vec<(Vec<u8>, V)>> {
+        let mut done = None;
+        if self.size + size > BULK_FILL && !self.current.is_empty() {
+            done = Some(mem::replace(&mut self.held, mem::take(&mut self.current)))
+                .filter(|node| !node.is_empty());
+            self.size = 0;
+        }
+        self.current.push((key, value));
+        self.size += size;
+        done
+    }
+
+    /// the level's last nodes, one or two, with the last one topped up
+    /// from the one before it if it is short
+    fn finish(mut self, cell_size: impl Fn(&(Vec<u8>, V)) -> usize) -> Vec<Vec<(Vec<u8>, V)>> {
+        if self.held.is_empty() {
+            return vec![self.current]
+                .into_iter()
+                .filter(|node| !node.is_empty())
+                .collect();
+        }
+        if self.size >= MIN_FILL {
+            return vec![self.held, self.current];
+        }
+        self.held.append(&mut self.current);
+        let sizes: Vec<usize> = self.held.iter().map(&cell_size).collect();
+        if sizes.iter().sum::<usize>() <= NODE_CAPACITY {
+            return vec![self.held];
+        }
+        let right = self.held.split_off(split_point(sizes.into_iter()));
+        vec![self.held, right]
+    }
+}
+
+impl BTree {
+    pub(super) fn bulk_load_in<I, K, V>(&mut self, bucket: &[Vec<u8>], entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let root = self.bucket_root(bucket)?;
+        match self.read_node(root, 0)? {
+            Node::Leaf(leaf) if leaf.cells.is_empty() => {}
+            _ => return Err(BTreeError::NotEmpty),
+        }
+
+        let mut leaves = Run::new();
+        // internal levels from the bottom up
+        let mut levels: Vec<Run<PageId>> = Vec::new();
+        let mut count = 0;
+        for (key, value) in entries {
+            let key = key.as_ref();
+            let mut stored = Vec::with_capacity(1 + value.as_ref().len());
+            stored.push(BTREE_VALUE);
+            stored.extend_from_slice(value.as_ref());
+            let size = Leaf::cell_size(key, &stored);
+            if size > MAX_CELL_SIZE || Internal::cell_size(key) > MAX_CELL_SIZE {
+                return Err(BTreeError::TooLarge(size));
+            }
+            let last = leaves.current.last().or(leaves.held.last());
+            if last.is_some_and(|(last, _)| last.as_slice() >= key) {
+                return Err(BTreeError::NotSorted(key.to_vec()));
+            }
+
+            if let Some(cells) = leaves.push(key.to_vec(), stored, size) {
+                let first = cells[0].0.clone();
+                let id = self.write_new(Node::Leaf(Leaf { cells }))?;
+                self.push_up(&mut levels, 0, first, id)?;
+            }
+            count += 1;
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let leaf_size = |(k, v): &(Vec<u8>, Vec<u8>)| Leaf::cell_size(k, v);
+        let mut last = Vec::new();
+        for cells in leaves.finish(leaf_size) {
+            let first = cells[0].0.clone();
+            last.push((first, self.write_new(Node::Leaf(Leaf { cells }))?));
+        }
+        let mut level = 0;
+        let new_root = loop {
+            if last.len() == 1 && level == levels.len() {
+                break last[0].1;
+            }
+            for (first, id) in mem::take(&mut last) {
+                self.push_up(&mut levels, level, first, id)?;
+            }
+            let run = mem::replace(&mut levels[level], Run::new());
+            for entries in run.finish(|(k, _)| Internal::cell_size(k)) {
+                let first = entries[0].0.clone();
+                last.push((first, self.write_new(internal_node(entries))?));
+            }
+            level += 1;
+        };
+
+        self.release(root);
+        self.set_bucket_root(bucket, new_root)?;
+        Ok(count)
+    }
+
+    /// add a node's smallest key and page to internal level `level`,
+    /// writing any node that fills up into the level above
+    fn push_up(
+        &mut self,
+        levels: &mut Vec<Run<PageId>>,
+        level: usize,
+        first: Vec<u8>,
+        id: PageId,
+    ) -> Result<()> {
+        if levels.len() == level {
+            levels.push(Run::new());
+        }
+        let size = Internal::cell_size(&first);
+        if let Some(entries) = levels[level].push(first, id, size) {
+            let first = entries[0].0.clone();
+            let id = self.write_new(internal_node(entries))?;
+            self.push_up(levels, level + 1, first, id)?;
+        }
+        Ok(())
+    }
+
+    fn write_new(&mut self, node: Node) -> Result<PageId> {
+        let id = self.allocate()?;
+        self.write_node(id, &node)?;
+        Ok(id)
+    }
+}
+
+/// an internal node over children given with their smallest keys; the
+/// first child's key is the parent's business
+fn internal_node(mut entries: Vec<(Vec<u8>, PageId)>) -> Node {
+    let cells = entries.split_off(1);
+    Node::Internal(Internal {
+        first: entries[0].1,
+        cells,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:06}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let path = env::temp_dir().join("test_btree_bulk_load.db");
+        fs::remove_file(&path).ok();
+        let mut tree = BTree::open(&path).unwrap();
+        let n = 20000;
+        let loaded = tree.bulk_load((0..n).map(|i| (key(i), [7u8; 40]))).unwrap();
+        assert_eq!(loaded, n as u64);
+        let report = tree.check();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.entries, n as u64);
+        assert!(report.depth >= 2);
+        tree.commit().unwrap();
+
+        // packed pages: far fewer than the same keys inserted one by one
+        let packed = report.tree_pages;
+        let inserted_path = env::temp_dir().join("test_btree_bulk_load_inserted.db");
+        fs::remove_file(&inserted_path).ok();
+        let mut inserted = BTree::open(&inserted_path).unwrap();
+        for i in 0..n {
+            inserted.insert(&key(i), &[7u8; 40]).unwrap();
+        }
+        assert!(packed * 3 / 2 < inserted.check().tree_pages);
+        fs::remove_file(&inserted_path).ok();
+
+        drop(tree);
+        let mut tree = BTree::open(&path).unwrap();
+        for i in (0..n).step_by(997) {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(vec![7u8; 40]));
+        }
+        assert!(matches!(
+            tree.bulk_load([(b"z", b"1")]),
+            Err(BTreeError::NotEmpty)
+        ));
+
+        // into a bucket, and the inputs it refuses
+        let mut bucket = tree.create_bucket(b"bucket").unwrap();
+        assert!(matches!(
+            bucket.bulk_load([(b"b", b"1"), (b"a", b"1")]),
+            Err(BTreeError::NotSorted(_))
+        ));
+        assert_eq!(bucket.bulk_load([(b"a", b"1")]).unwrap(), 1);
+        assert_eq!(bucket.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(tree.check().is_ok());
+        fs::remove_file(&path).ok();
+    }
+}
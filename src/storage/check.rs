@@ -0,0 +1,360 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::Serialize;
+
+use super::btree::{BTree, Node};
+use super::page::{META_PAGES, PageId};
+use crate::format::{BTREE_BUCKET, get_u64};
+
+/// what BTree::check found
+///    - every page past the meta pages is either in a tree, a bucket's
+///      included, or free; one in neither is leaked, one in both a problem
+///    - checked as of the last change, committed or not
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CheckReport {
+    pub pages: u64,
+
+    /// pages reachable from the root
+    pub tree_pages: u64,
+
+    /// pages free now or once the transaction commits, and the pages of
+    /// the committed free list
+    pub free_pages: u64,
+
+    pub entries: u64,
+
+    pub buckets: u64,
+
+    /// levels below the root of the top-level tree
+    pub depth: usize,
+
+    /// pages neither reachable nor free
+    pub leaked_pages: Vec<PageId>,
+
+    pub problems: Vec<CheckProblem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckProblem {
+    pub page: PageId,
+
+    pub message: String,
+}
+
+impl BTree {
+    /// walk every tree and the free pages, checking that
+    ///    - each page is a readable node of the right type, referenced once
+    ///    - keys ascend within each node and stay between the separators
+    ///      the parent puts around it
+    ///    - all leaves of a tree are at the same depth
+    ///    - no free page is also in a tree
+    pub fn check(&mut self) -> CheckReport {
+        let mut checker = Checker {
+            pages: self.num_pages(),
+            tree: self,
+            seen: HashSet::new(),
+            report: CheckReport::default(),
+        };
+        let root = checker.tree.root;
+        checker.report.depth = checker.walk(root, 0, None, None).unwrap_or(0);
+
+        let tree = checker.tree;
+        let free = tree
+            .free
+            .iter()
+            .chain(&tree.freed)
+            .chain(&tree.free_list_pages);
+        let mut free_pages = HashSet::new();
+        for &id in free {
+            if !free_pages.insert(id) {
+                checker.report.problem(id, "listed as free twice");
+            } else if checker.seen.contains(&id) {
+                checker.report.problem(id, "free but in a tree");
+            } else if id < META_PAGES || id >= checker.pages {
+                checker.report.problem(id, "free page outside the file");
+            }
+        }
+
+        let report = &mut checker.report;
+        report.pages = checker.pages;
+        report.free_pages = free_pages.len() as u64;
+        report.leaked_pages = (META_PAGES..checker.pages)
+            .filter(|id| !checker.seen.contains(id) && !free_pages.contains(id))
+            .collect();
+        checker.report
+    }
+}
+
+struct Checker<'a> {
+    tree: &'a mut BTree,
+    pages: u64,
+    seen: HashSet<PageId>,
+    report: CheckReport,
+}
+
+impl Checker<'_> {
+    /// check the subtree at `id`, whose keys must be in [lo, hi); returns
+    /// the depth of its leaves below it, None if it is broken
+    fn walk(
+        &mut self,
+        id: PageId,
+        depth: usize,
+        lo: Option<&[u8]>,
+        hi: Option<&[u8]>,
+    ) -> Option<usize> {
+        if id < META_PAGES || id >= self.pages {
+            self.report.problem(id, "referenced page outside the file");
+            return None;
+        }
+        if !self.seen.insert(id) {
+            self.report.problem(id, "referenced more than once");
+            return None;
+        }
+        self.report.tree_pages += 1;
+        let node = match self.tree.read_node(id, depth) {
+            Ok(node) => node,
+            Err(e) => {
+                self.report.problem(id, &e.to_string());
+                return None;
+            }
+        };
+
+        let keys: Vec<&[u8]> = match &node {
+            Node::Leaf(leaf) => leaf.cells.iter().map(|(k, _)| k.as_slice()).collect(),
+            Node::Internal(internal) => internal.cells.iter().map(|(k, _)| k.as_slice()).collect(),
+        };
+        if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            self.report.problem(id, "keys out of order");
+        }
+        let below = |key: &&[u8]| lo.is_some_and(|lo| *key < lo);
+        let above = |key: &&[u8]| hi.is_some_and(|hi| *key >= hi);
+        if keys.first().is_some_and(below) || keys.last().is_some_and(above) {
+            self.report
+                .problem(id, "keys outside the range its parent gives it");
+        }
+
+        match node {
+            Node::Leaf(leaf) => {
+                for (_, stored) in &leaf.cells {
+                    if stored[0] == BTREE_BUCKET {
+                        self.report.buckets += 1;
+                        self.walk(get_u64(stored, 1).unwrap(), 0, None, None);
+                    } else {
+                        self.report.entries += 1;
+                    }
+                }
+                Some(0)
+            }
+            Node::Internal(internal) => {
+                if internal.cells.is_empty() {
+                    self.report.problem(id, "internal node with a single child");
+                }
+                let mut depths = Vec::new();
+                for pos in 0..=internal.cells.len() {
+                    let lo = match pos {
+                        0 => lo,
+                        _ => Some(internal.cells[pos - 1].0.as_slice()),
+                    };
+                    let hi = internal.cells.get(pos).map(|(k, _)| k.as_slice()).or(hi);
+                    depths.push(self.walk(internal.child(pos), depth + 1, lo, hi));
+                }
+                let depths: Vec<usize> = depths.into_iter().flatten().collect();
+                if depths.windows(2).any(|pair| pair[0] != pair[1]) {
+                    self.report
+                        .problem(id, "leaves at different depths below it");
+                }
+                depths.first().map(|depth| depth + 1)
+            }
+        }
+    }
+}
+
+impl CheckReport {
+    /// no problems and no leaked pages
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty() && self.leaked_pages.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    fn problem(&mut self, page: PageId, message: &str) {
+        self.problems.push(CheckProblem {
+            page,
+            message: message.to_string(),
+        });
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} pages: {} in trees, {} free, {} leaked",
+            self.pages,
+            self.tree_pages,
+            self.free_pages,
+            self.leaked_pages.len()
+        )?;
+        writeln!(
+            f,
+            "{} entries, {} buckets, depth {}",
+            self.entries, self.buckets, self.depth
+        )?;
+        if !self.leaked_pages.is_empty() {
+            writeln!(f, "leaked: {:?}", self.leaked_pages)?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "page {}: {}", problem.page, problem.message)?;
+        }
+        if self.is_ok() {
+            writeln!(f, "ok")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::btree::{BTreeError, Internal, Leaf};
+    use std::env;
+    use std::fs;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:05}{:100}", i, "").into_bytes()
+    }
+
+    #[test]
+    fn test_check() {
+        let path = env::temp_dir().join("test_btree_check.db");
+        fs::remove_file(&path).ok();
+        let mut tree = BTree::open(&path).unwrap();
+        for i in 0..1000 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.create_bucket(b"bucket")
+            .unwrap()
+            .insert(b"a", b"1")
+            .unwrap();
+        let report = tree.check();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!((report.entries, report.buckets), (1001, 1));
+        assert!(report.depth >= 1);
+        tree.commit().unwrap();
+        for i in 0..500 {
+            tree.delete(&key(i)).unwrap();
+        }
+        assert!(tree.check().is_ok());
+        tree.commit().unwrap();
+        let report = tree.check();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.pages, 2 + report.tree_pages + report.free_pages);
+
+        // a free page that is also in the tree, and a leaked one
+        let Node::Internal(root) = tree.read_node(tree.root, 0).unwrap() else {
+            panic!("root is a leaf");
+        };
+        tree.free.insert(root.first);
+        let leaked = tree.free_list_pages.pop().unwrap();
+        let report = tree.check();
+        assert_eq!(report.leaked_pages, vec![leaked]);
+        assert_eq!(report.problems[0].page, root.first);
+        tree.free.remove(&root.first);
+        tree.free_list_pages.push(leaked);
+
+        // a child whose keys fall outside its separators, referenced twice
+        let bad = Node::Internal(Internal {
+            first: root.first,
+            cells: vec![(key(999), root.first)],
+        });
+        tree.write_node(tree.root, &bad).unwrap();
+        let messages: Vec<String> = tree
+            .check()
+            .problems
+            .into_iter()
+            .map(|p| p.message)
+            .collect();
+        assert!(messages.contains(&"referenced more than once".to_string()));
+        tree.write_node(tree.root, &Node::Internal(root)).unwrap();
+
+        let unsorted = Node::Leaf(Leaf {
+            cells: vec![(key(2), vec![1]), (key(1), vec![1])],
+        });
+        let leaf = tree.free.pop_first().unwrap();
+        tree.write_node(leaf, &unsorted).unwrap();
+        let root = tree.root;
+        tree.root = leaf;
+        assert_eq!(tree.check().problems[0].message, "keys out of order");
+        tree.root = root;
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_check_after_uncommitted() {
+        let path = env::temp_dir().join("test_btree_check_uncommitted.db");
+        fs::remove_file(&path).ok();
+        let mut tree = BTree::open(&path).unwrap();
+        for i in 0..200 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.commit().unwrap();
+        let pages = tree.num_pages();
+        // pages appended and written back, then dropped without a commit
+        for i in 200..1000 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.pool.checkpoint().unwrap();
+        drop(tree);
+
+        let mut tree = BTree::open(&path).unwrap();
+        assert_eq!(tree.num_pages(), pages);
+        let report = tree.check();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(tree.get(&key(200)).unwrap(), None);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_check_beside_writer() {
+        let path = env::temp_dir().join("test_btree_check_beside_writer.db");
+        fs::remove_file(&path).ok();
+        let mut tree = BTree::open(&path).unwrap();
+        for i in 0..200 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.commit().unwrap();
+        let committed = tree.num_pages();
+        for i in 200..1000 {
+            tree.insert(&key(i), b"value").unwrap();
+        }
+        tree.pool.checkpoint().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        assert!(matches!(
+            BTree::open(&path),
+            Err(BTreeError::AlreadyLocked(_))
+        ));
+
+        // checks the last commit and leaves the writer's pages be
+        let mut reader = BTree::open_read_only(&path).unwrap();
+        assert_eq!(reader.num_pages(), committed);
+        let report = reader.check();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(reader.get(&key(0)).unwrap(), Some(b"value".to_vec()));
+        assert!(matches!(
+            reader.insert(b"key", b"value"),
+            Err(BTreeError::ReadOnly)
+        ));
+        drop(reader);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        tree.commit().unwrap();
+        drop(tree);
+        let mut tree = BTree::open(&path).unwrap();
+        assert!(tree.check().is_ok());
+        assert_eq!(tree.get(&key(999)).unwrap(), Some(b"value".to_vec()));
+        fs::remove_file(&path).ok();
+    }
+}
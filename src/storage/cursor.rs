@@ -0,0 +1,265 @@
+use super::btree::{BTree, Internal, Leaf, Node, Result};
+use super::page::PageId;
+use crate::format::BTREE_VALUE;
+
+/// Cursor: a position in a BTree or one of its buckets, moved one entry at
+/// a time
+///    - keeps the nodes on the path from the root to its leaf, so stepping
+///      within a leaf reads nothing and stepping past one reads only the
+///      nodes down to the neighbouring leaf
+///    - leaves carry no sibling links, so stepping past a leaf climbs to the
+///      nearest ancestor with a child on that side
+///    - every move returns whether the cursor is on an entry; moving off
+///      either end leaves it unpositioned
+///    - sees changes made through delete_current only; the tree is borrowed
+///      for the cursor's lifetime, so nothing else can change it
+///    - nested buckets are entries too, with a key but no value
+pub struct Cursor<'a> {
+    tree: &'a mut BTree,
+
+    /// names of the bucket walked, outermost first
+    bucket: Vec<Vec<u8>>,
+
+    /// internal nodes from the root down, each with the child taken
+    path: Vec<(Internal, usize)>,
+
+    /// the current leaf and entry, None when unpositioned
+    leaf: Option<(Leaf, usize)>,
+}
+
+/// which entry of a subtree to land on
+#[derive(Clone, Copy)]
+enum Target<'k> {
+    First,
+    Last,
+    AtLeast(&'k [u8]),
+}
+
+impl<'a> Cursor<'a> {
+    pub(super) fn new(tree: &'a mut BTree, bucket: Vec<Vec<u8>>) -> Self {
+        Self {
+            tree,
+            bucket,
+            path: Vec::new(),
+            leaf: None,
+        }
+    }
+
+    /// move to the first entry at or after `key`
+    pub fn seek(&mut self, key: &[u8]) -> Result<bool> {
+        self.descend_from_root(Target::AtLeast(key))
+    }
+
+    pub fn first(&mut self) -> Result<bool> {
+        self.descend_from_root(Target::First)
+    }
+
+    pub fn last(&mut self) -> Result<bool> {
+        self.descend_from_root(Target::Last)
+    }
+
+    /// move to the next entry; an unpositioned cursor stays unpositioned
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool> {
+        match &mut self.leaf {
+            Some((leaf, i)) if *i + 1 < leaf.cells.len() => {
+                *i += 1;
+                Ok(true)
+            }
+            Some(_) => self.step_leaf(true),
+            None => Ok(false),
+        }
+    }
+
+    /// move to the previous entry; an unpositioned cursor stays unpositioned
+    pub fn prev(&mut self) -> Result<bool> {
+        match &mut self.leaf {
+            Some((_, i)) if *i > 0 => {
+                *i -= 1;
+                Ok(true)
+            }
+            Some(_) => self.step_leaf(false),
+            None => Ok(false),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.leaf.is_some()
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.leaf
+            .as_ref()
+            .map(|(leaf, i)| leaf.cells[*i].0.as_slice())
+    }
+
+    /// the current value; None if unpositioned or on a bucket
+    pub fn value(&self) -> Option<&[u8]> {
+        let (leaf, i) = self.leaf.as_ref()?;
+        match leaf.cells[*i].1.split_first() {
+            Some((&BTREE_VALUE, value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_bucket(&self) -> bool {
+        self.is_valid() && self.value().is_none()
+    }
+
+    /// delete the current entry, a bucket with everything in it, and move to
+    /// the one after it, returning the deleted value; None if unpositioned
+    /// or on a bucket
+    pub fn delete_current(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(key) = self.key().map(<[u8]>::to_vec) else {
+            return Ok(None);
+        };
+        let old = if self.is_bucket() {
+            self.tree.delete_bucket_in(&self.bucket, &key)?;
+            None
+        } else {
+            self.tree.delete_in(&self.bucket, &key)?
+        };
+        // the delete may have merged or moved any node on the path
+        self.seek(&key)?;
+        Ok(old)
+    }
+
+    fn descend_from_root(&mut self, target: Target) -> Result<bool> {
+        self.path.clear();
+        self.leaf = None;
+        let root = self.tree.bucket_root(&self.bucket)?;
+        self.descend(root, target)
+    }
+
+    /// walk down from `id`, a child of the last node on the path, to the
+    /// target entry, moving on to the next leaf if a seek lands past the
+    /// end of one
+    fn descend(&mut self, mut id: PageId, target: Target) -> Result<bool> {
+        loop {
+            match self.tree.read_node(id, self.path.len())? {
+                Node::Internal(internal) => {
+                    let pos = match target {
+                        Target::First => 0,
+                        Target::Last => internal.cells.len(),
+                        Target::AtLeast(key) => internal.child_pos(key),
+                    };
+                    id = internal.child(pos);
+                    self.path.push((internal, pos));
+                }
+                Node::Leaf(leaf) => {
+                    let i = match target {
+                        Target::First => 0,
+                        Target::Last => leaf.cells.len().saturating_sub(1),
+                        Target::AtLeast(key) => {
+                            leaf.cells.partition_point(|(k, _)| k.as_slice() < key)
+                        }
+                    };
+                    if i < leaf.cells.len() {
+                        self.leaf = Some((leaf, i));
+                        return Ok(true);
+                    }
+                    // only an empty root or a seek past a leaf's last key
+                    return match target {
+                        Target::AtLeast(_) => self.step_leaf(true),
+                        _ => Ok(false),
+                    };
+                }
+            }
+        }
+    }
+
+    /// move to the first entry of the next leaf, or the last of the
+    /// previous one
+    fn step_leaf(&mut self, forward: bool) -> Result<bool> {
+        self.leaf = None;
+        while let Some((internal, pos)) = self.path.last_mut() {
+            let sibling = if forward {
+                (*pos < internal.cells.len()).then(|| *pos + 1)
+            } else {
+                pos.checked_sub(1)
+            };
+            if let Some(sibling) = sibling {
+                *pos = sibling;
+                let id = internal.child(sibling);
+                let target = if forward { Target::First } else { Target::Last };
+                return self.descend(id, target);
+            }
+            self.path.pop();
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:05}{:100}", i, "").into_bytes()
+    }
+
+    #[test]
+    fn test_cursor_walks_and_deletes() {
+        let path = env::temp_dir().join("test_btree_cursor.db");
+        fs::remove_file(&path).ok();
+        let mut tree = BTree::open(&path).unwrap();
+        let mut cursor = tree.cursor();
+        assert!(!cursor.first().unwrap());
+        assert!(!cursor.last().unwrap());
+        assert!(!cursor.seek(b"a").unwrap());
+
+        // even keys only, enough for several levels
+        let n = 2000;
+        for i in (0..n).step_by(2) {
+            tree.insert(&key(i), &i.to_le_bytes()).unwrap();
+        }
+
+        let mut cursor = tree.cursor();
+        let mut forward = Vec::new();
+        let mut valid = cursor.first().unwrap();
+        while valid {
+            forward.push(cursor.key().unwrap().to_vec());
+            valid = cursor.next().unwrap();
+        }
+        assert_eq!(forward, (0..n).step_by(2).map(key).collect::<Vec<_>>());
+        assert!(!cursor.is_valid() && !cursor.next().unwrap());
+
+        let mut backward = Vec::new();
+        let mut valid = cursor.last().unwrap();
+        while valid {
+            backward.push(cursor.key().unwrap().to_vec());
+            valid = cursor.prev().unwrap();
+        }
+        backward.reverse();
+        assert_eq!(backward, forward);
+
+        // a seek between keys lands on the next one, including across leaves
+        for i in (1..n - 1).step_by(2) {
+            assert!(cursor.seek(&key(i)).unwrap());
+            assert_eq!(cursor.key().unwrap(), key(i + 1));
+            assert_eq!(cursor.value().unwrap(), (i + 1).to_le_bytes());
+        }
+        assert!(!cursor.seek(&key(n)).unwrap());
+        assert!(cursor.seek(&key(n - 2)).unwrap());
+        assert!(cursor.prev().unwrap());
+        assert_eq!(cursor.key().unwrap(), key(n - 4));
+
+        // delete a range, merging leaves under the cursor as it goes
+        cursor.seek(&key(100)).unwrap();
+        while cursor.key().is_some_and(|k| k < key(1500).as_slice()) {
+            assert!(cursor.delete_current().unwrap().is_some());
+        }
+        assert_eq!(cursor.key().unwrap(), key(1500));
+        assert!(cursor.prev().unwrap());
+        assert_eq!(cursor.key().unwrap(), key(98));
+
+        assert_eq!(tree.get(&key(100)).unwrap(), None);
+        assert_eq!(
+            tree.get(&key(1500)).unwrap(),
+            Some(1500u32.to_le_bytes().to_vec())
+        );
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,20 @@
+//! Page engine: one file of fixed-size pages with a B+tree on top, an ordered
+//! key-value store independent of the LSM engine in `lsm`
+pub mod btree;
+pub mod bucket;
+pub mod bufferpool;
+pub mod bulk;
+pub mod check;
+pub mod cursor;
+pub mod page;
+pub mod pagemanager;
+pub mod slotted;
+
+pub use btree::{BTree, BTreeError};
+pub use bucket::Bucket;
+pub use bufferpool::BufferPool;
+pub use check::{CheckProblem, CheckReport};
+pub use cursor::Cursor;
+pub use page::{MetaPage, Page, PageError, PageId, PageType};
+pub use pagemanager::PageManager;
+pub use slotted::SlottedPage;
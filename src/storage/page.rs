@@ -0,0 +1,209 @@
+use std::fmt;
+
+pub use crate::format::PAGE_SIZE;
+use crate::format::{
+    PAGE_FILE_MAGIC, PAGE_FILE_VERSION, PAGE_HEADER_SIZE, PAGE_TYPE_BTREE_INTERNAL,
+    PAGE_TYPE_BTREE_LEAF, PAGE_TYPE_FREE_LIST, PAGE_TYPE_META, crc32, get_u16, get_u32, get_u64,
+};
+
+/// page number within the file; pages 0 and 1 are meta pages, so 0 also
+/// serves as "no page" in pointers
+pub type PageId = u64;
+
+/// raw contents of one page
+pub type Page = [u8; PAGE_SIZE];
+
+/// pages [0, META_PAGES) hold meta pages
+pub const META_PAGES: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageType {
+    Meta,
+    BTreeLeaf,
+    BTreeInternal,
+    FreeList,
+}
+
+#[derive(Debug)]
+pub enum PageError {
+    Checksum,
+    WrongType { expected: PageType, found: u8 },
+    Corrupted(String),
+}
+
+impl fmt::Display for PageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageError::Checksum => write!(f, "Page checksum mismatch"),
+            PageError::WrongType { expected, found } => {
+                write!(
+                    f,
+                    "Expected a {:?} page, found type {:#04x}",
+                    expected, found
+                )
+            }
+            PageError::Corrupted(msg) => write!(f, "Corrupted page: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PageError {}
+
+pub type Result<T> = std::result::Result<T, PageError>;
+
+impl PageType {
+    pub fn tag(self) -> u8 {
+        match self {
+            PageType::Meta => PAGE_TYPE_META,
+            PageType::BTreeLeaf => PAGE_TYPE_BTREE_LEAF,
+            PageType::BTreeInternal => PAGE_TYPE_BTREE_INTERNAL,
+            PageType::FreeList => PAGE_TYPE_FREE_LIST,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            PAGE_TYPE_META => Some(PageType::Meta),
+            PAGE_TYPE_BTREE_LEAF => Some(PageType::BTreeLeaf),
+            PAGE_TYPE_BTREE_INTERNAL => Some(PageType::BTreeInternal),
+            PAGE_TYPE_FREE_LIST => Some(PageType::FreeList),
+            _ => None,
+        }
+    }
+}
+
+/// fill in the header of a page whose body is already written
+pub fn seal(page: &mut Page, page_type: PageType, count: u16) {
+    page[4] = page_type.tag();
+    page[5] = 0;
+    page[6..8].copy_from_slice(&count.to_le_bytes());
+    let checksum = crc32(&page[4..]);
+    page[0..4].copy_from_slice(&checksum.to_le_bytes());
+}
+
+pub fn page_type(page: &Page) -> Option<PageType> {
+    PageType::from_tag(page[4])
+}
+
+/// check the checksum and type of a page, returning its cell count
+pub fn open(page: &Page, expected: PageType) -> Result<u16> {
+    if get_u32(page, 0) != Some(crc32(&page[4..])) {
+        return Err(PageError::Checksum);
+    }
+    if page[4] != expected.tag() {
+        return Err(PageError::WrongType {
+            expected,
+            found: page[4],
+        });
+    }
+    Ok(get_u16(page, 6).unwrap())
+}
+
+/// MetaPage: one of the two pages describing the rest of the file
+///    - root: page of the B-tree root
+///    - free_list: first page of the free list, 0 when no page is free
+///    - txn: commit number; commits alternate between the two meta pages,
+///      so the valid one with the higher txn is current
+///    - num_pages: pages in the file as of the commit, 0 if not recorded;
+///      pages past it were appended by a transaction that never committed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetaPage {
+    pub root: PageId,
+    pub free_list: PageId,
+    pub txn: u64,
+    pub num_pages: u64,
+}
+
+impl MetaPage {
+    pub fn new(root: PageId) -> Self {
+        Self {
+            root,
+            free_list: 0,
+            txn: 0,
+            num_pages: 0,
+        }
+    }
+
+    /// the meta page this commit is written to
+    pub fn page_id(&self) -> PageId {
+        self.txn % META_PAGES
+    }
+
+    pub fn encode(&self) -> Page {
+        let mut page = [0u8; PAGE_SIZE];
+        let mut at = PAGE_HEADER_SIZE;
+        for field in [
+            &PAGE_FILE_MAGIC.to_le_bytes()[..],
+            &PAGE_FILE_VERSION.to_le_bytes(),
+            &(PAGE_SIZE as u32).to_le_bytes(),
+            &self.root.to_le_bytes(),
+            &self.free_list.to_le_bytes(),
+            &self.txn.to_le_bytes(),
+            &self.num_pages.to_le_bytes(),
+        ] {
+            page[at..at + field.len()].copy_from_slice(field);
+            at += field.len();
+        }
+        seal(&mut page, PageType::Meta, 0);
+        page
+    }
+
+    pub fn decode(page: &Page) -> Result<Self> {
+        open(page, PageType::Meta)?;
+        let at = PAGE_HEADER_SIZE;
+        if get_u64(page, at) != Some(PAGE_FILE_MAGIC) {
+            return Err(PageError::Corrupted("bad magic in meta page".to_string()));
+        }
+        let version = get_u32(page, at + 8).unwrap();
+        if version != PAGE_FILE_VERSION {
+            return Err(PageError::Corrupted(format!(
+                "unsupported page file version {}",
+                version
+            )));
+        }
+        let page_size = get_u32(page, at + 12).unwrap();
+        if page_size as usize != PAGE_SIZE {
+            return Err(PageError::Corrupted(format!(
+                "file uses {} byte pages, expected {}",
+                page_size, PAGE_SIZE
+            )));
+        }
+        Ok(Self {
+            root: get_u64(page, at + 16).unwrap(),
+            free_list: get_u64(page, at + 24).unwrap(),
+            txn: get_u64(page, at + 32).unwrap(),
+            num_pages: get_u64(page, at + 40).unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta_page_roundtrip() {
+        let meta = MetaPage {
+            txn: 3,
+            num_pages: 12,
+            ..MetaPage::new(7)
+        };
+        assert_eq!(meta.page_id(), 1);
+        let mut page = meta.encode();
+        assert_eq!(MetaPage::decode(&page).unwrap(), meta);
+
+        page[PAGE_SIZE - 1] ^= 1;
+        assert!(matches!(MetaPage::decode(&page), Err(PageError::Checksum)));
+
+        let mut leaf = [0u8; PAGE_SIZE];
+        seal(&mut leaf, PageType::BTreeLeaf, 3);
+        assert_eq!(open(&leaf, PageType::BTreeLeaf).unwrap(), 3);
+        assert!(matches!(
+            MetaPage::decode(&leaf),
+            Err(PageError::WrongType {
+                found: PAGE_TYPE_BTREE_LEAF,
+                ..
+            })
+        ));
+    }
+}
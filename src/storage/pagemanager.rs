@@ -0,0 +1,246 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::page::{self, META_PAGES, PAGE_SIZE, Page, PageId, PageType};
+use crate::format::{PAGE_HEADER_SIZE, get_u64};
+
+/// PageManager: a file of PAGE_SIZE pages
+///    - page n lives at byte n * PAGE_SIZE
+///    - pages are read and written whole, with no interpretation of their
+///      contents; sync() makes written pages durable
+///    - free_page() pushes a page onto the free list, each free page linking
+///      to the next; allocate_page() pops it, appending a page only when the
+///      list is empty
+///    - the list head is the owner's to persist, normally in the meta page:
+///      free_list() after changes, set_free_list() after open
+///    - open() holds an exclusive lock on the file until dropped, so only
+///      one PageManager at a time writes it; open_read_only() takes none
+pub struct PageManager {
+    file: File,
+    num_pages: u64,
+
+    /// first free page, 0 for none
+    free_list: PageId,
+}
+
+impl PageManager {
+    /// open the page file at `path`, creating it empty if missing; fails
+    /// with WouldBlock while another PageManager has it open for writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "page file is open for writing elsewhere",
+                ));
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        Self::with_file(file)
+    }
+
+    /// open the existing page file at `path` for reading only, alongside a
+    /// writer if there is one
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_file(File::open(path)?)
+    }
+
+    fn with_file(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        if len % PAGE_SIZE as u64 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "page file length {} is not a multiple of {}",
+                    len, PAGE_SIZE
+                ),
+            ));
+        }
+        Ok(Self {
+            file,
+            num_pages: len / PAGE_SIZE as u64,
+            free_list: 0,
+        })
+    }
+
+    pub fn num_pages(&self) -> u64 {
+        self.num_pages
+    }
+
+    pub fn free_list(&self) -> PageId {
+        self.free_list
+    }
+
+    /// resume the free list persisted from an earlier open
+    pub fn set_free_list(&mut self, head: PageId) {
+        self.free_list = head;
+    }
+
+    pub fn read_page(&mut self, id: PageId, buf: &mut Page) -> io::Result<()> {
+        if id >= self.num_pages {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "page {} is past the end of the file ({} pages)",
+                    id, self.num_pages
+                ),
+            ));
+        }
+        self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        self.file.read_exact(buf)
+    }
+
+    /// write page `id`, growing the file if it lies past the end
+    pub fn write_page(&mut self, id: PageId, page: &Page) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        self.file.write_all(page)?;
+        self.num_pages = self.num_pages.max(id + 1);
+        Ok(())
+    }
+
+    /// a zeroed page, reused from the free list or appended to the file
+    pub fn allocate_page(&mut self) -> io::Result<PageId> {
+        let id = match self.free_list {
+            0 => self.num_pages,
+            head => {
+                let mut buf = [0; PAGE_SIZE];
+                self.read_page(head, &mut buf)?;
+                page::open(&buf, PageType::FreeList).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("free list page {}: {}", head, e),
+                    )
+                })?;
+                self.free_list = get_u64(&buf, PAGE_HEADER_SIZE).unwrap();
+                head
+            }
+        };
+        self.write_page(id, &[0; PAGE_SIZE])?;
+        Ok(id)
+    }
+
+    /// hand page `id` back for reuse; its contents are overwritten
+    pub fn free_page(&mut self, id: PageId) -> io::Result<()> {
+        if id < META_PAGES || id >= self.num_pages {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page {} cannot be freed ({} pages)", id, self.num_pages),
+            ));
+        }
+        let mut buf = [0; PAGE_SIZE];
+        buf[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8].copy_from_slice(&self.free_list.to_le_bytes());
+        page::seal(&mut buf, PageType::FreeList, 0);
+        self.write_page(id, &buf)?;
+        self.free_list = id;
+        Ok(())
+    }
+
+    /// cut the file back to its first `num_pages` pages and sync it; the
+    /// free list must not name a page past them
+    pub fn truncate(&mut self, num_pages: u64) -> io::Result<()> {
+        if num_pages >= self.num_pages {
+            return Ok(());
+        }
+        self.file.set_len(num_pages * PAGE_SIZE as u64)?;
+        self.file.sync_all()?;
+        self.num_pages = num_pages;
+        Ok(())
+    }
+
+    /// read the file as if it ended after `num_pages` pages, leaving it as
+    /// it is; pages past them read as missing
+    pub fn limit(&mut self, num_pages: u64) {
+        self.num_pages = self.num_pages.min(num_pages);
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_read_write_allocate() {
+        let path = env::temp_dir().join("test_page_manager.db");
+        fs::remove_file(&path).ok();
+
+        let mut pager = PageManager::open(&path).unwrap();
+        assert_eq!(pager.num_pages(), 0);
+        assert_eq!(pager.allocate_page().unwrap(), 0);
+        assert_eq!(pager.allocate_page().unwrap(), 1);
+
+        let mut page = [0u8; PAGE_SIZE];
+        page[..5].copy_from_slice(b"hello");
+        pager.write_page(1, &page).unwrap();
+        pager.sync().unwrap();
+        let mut buf = [0u8; PAGE_SIZE];
+        assert!(pager.read_page(2, &mut buf).is_err());
+        drop(pager);
+
+        let mut pager = PageManager::open(&path).unwrap();
+        assert_eq!(pager.num_pages(), 2);
+        pager.read_page(1, &mut buf).unwrap();
+        assert_eq!(&buf[..5], b"hello");
+        pager.read_page(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; PAGE_SIZE]);
+
+        // a torn trailing page is refused
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"partial")
+            .unwrap();
+        assert!(PageManager::open(&path).is_err());
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_free_list() {
+        let path = env::temp_dir().join("test_page_manager_free_list.db");
+        fs::remove_file(&path).ok();
+
+        let mut pager = PageManager::open(&path).unwrap();
+        for _ in 0..5 {
+            pager.allocate_page().unwrap();
+        }
+        // meta pages and pages past the end can't be freed
+        assert!(pager.free_page(1).is_err());
+        assert!(pager.free_page(5).is_err());
+        pager.free_page(2).unwrap();
+        pager.free_page(4).unwrap();
+        assert_eq!(pager.free_list(), 4);
+        drop(pager);
+
+        // the head survives a reopen through whoever persisted it
+        let mut pager = PageManager::open(&path).unwrap();
+        pager.set_free_list(4);
+        assert_eq!(pager.allocate_page().unwrap(), 4);
+        let mut buf = [1u8; PAGE_SIZE];
+        pager.read_page(4, &mut buf).unwrap();
+        assert_eq!(buf, [0; PAGE_SIZE]);
+        assert_eq!(pager.allocate_page().unwrap(), 2);
+        assert_eq!(pager.free_list(), 0);
+        assert_eq!(pager.allocate_page().unwrap(), 5);
+
+        // a head that isn't a free page is refused
+        pager.set_free_list(3);
+        assert!(pager.allocate_page().is_err());
+
+        fs::remove_file(&path).ok();
+    }
+}
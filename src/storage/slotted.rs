@@ -0,0 +1,259 @@
+use super::page::{self, PAGE_SIZE, Page, PageError, PageType, Result};
+use crate::format::{PAGE_HEADER_SIZE, SLOT_SIZE, SLOTTED_HEADER_SIZE, get_u16};
+
+/// SlottedPage: variable-length cells in one page, reached through a slot
+/// directory
+///    - after the fixed header come `header_len` bytes for the page's owner,
+///      then one slot per cell in cell order; cells fill the page from the
+///      end, so the free space sits between the directory and the cells
+///    - removing a cell leaves a hole in the cell area; insert compacts the
+///      page when the cell fits only by reclaiming holes
+///    - the page header's count is the number of cells
+pub struct SlottedPage {
+    page: Page,
+    header_len: usize,
+    count: usize,
+
+    /// offset of the lowest cell
+    content_start: usize,
+
+    /// bytes in holes left by removed cells
+    fragmented: usize,
+}
+
+impl SlottedPage {
+    /// an empty page with `header_len` bytes of owner header, all zero
+    pub fn new(header_len: usize) -> Self {
+        assert!(SLOTTED_HEADER_SIZE + header_len <= PAGE_SIZE);
+        Self {
+            page: [0; PAGE_SIZE],
+            header_len,
+            count: 0,
+            content_start: PAGE_SIZE,
+            fragmented: 0,
+        }
+    }
+
+    /// check and load a sealed page of type `expected`
+    pub fn open(page: &Page, expected: PageType, header_len: usize) -> Result<Self> {
+        let count = page::open(page, expected)? as usize;
+        let content_start = get_u16(page, PAGE_HEADER_SIZE).unwrap() as usize;
+        let fragmented = get_u16(page, PAGE_HEADER_SIZE + 2).unwrap() as usize;
+        let slotted = Self {
+            page: *page,
+            header_len,
+            count,
+            content_start,
+            fragmented,
+        };
+
+        let directory_end = slotted.directory_end();
+        if directory_end > content_start || content_start > PAGE_SIZE {
+            return Err(PageError::Corrupted(format!(
+                "{} slots overlap cells starting at {}",
+                count, content_start
+            )));
+        }
+        let mut used = 0;
+        for i in 0..count {
+            let (offset, len) = slotted.slot(i);
+            if offset < content_start || offset + len > PAGE_SIZE {
+                return Err(PageError::Corrupted(format!(
+                    "slot {} points outside the cell area: {}+{}",
+                    i, offset, len
+                )));
+            }
+            used += len;
+        }
+        if used + fragmented != PAGE_SIZE - content_start {
+            return Err(PageError::Corrupted(format!(
+                "{} bytes of cells and {} of holes in a {} byte cell area",
+                used,
+                fragmented,
+                PAGE_SIZE - content_start
+            )));
+        }
+        Ok(slotted)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.page[SLOTTED_HEADER_SIZE..SLOTTED_HEADER_SIZE + self.header_len]
+    }
+
+    pub fn header_mut(&mut self) -> &mut [u8] {
+        &mut self.page[SLOTTED_HEADER_SIZE..SLOTTED_HEADER_SIZE + self.header_len]
+    }
+
+    pub fn cell(&self, i: usize) -> &[u8] {
+        assert!(i < self.count, "cell {} of {}", i, self.count);
+        let (offset, len) = self.slot(i);
+        &self.page[offset..offset + len]
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.count).map(|i| self.cell(i))
+    }
+
+    /// bytes a new cell and its slot may take, counting holes
+    pub fn free_space(&self) -> usize {
+        self.content_start - self.directory_end() + self.fragmented
+    }
+
+    /// insert `cell` so it becomes cell `i`; false if it doesn't fit
+    pub fn insert(&mut self, i: usize, cell: &[u8]) -> bool {
+        assert!(i <= self.count, "cell {} of {}", i, self.count);
+        if cell.len() + SLOT_SIZE > self.free_space() {
+            return false;
+        }
+        if cell.len() + SLOT_SIZE > self.content_start - self.directory_end() {
+            self.compact();
+        }
+
+        self.content_start -= cell.len();
+        let offset = self.content_start;
+        self.page[offset..offset + cell.len()].copy_from_slice(cell);
+        let (at, end) = (self.slot_offset(i), self.directory_end());
+        self.page.copy_within(at..end, at + SLOT_SIZE);
+        self.count += 1;
+        self.set_slot(i, offset, cell.len());
+        true
+    }
+
+    /// remove cell `i`, leaving a hole unless it was the lowest cell
+    pub fn remove(&mut self, i: usize) {
+        assert!(i < self.count, "cell {} of {}", i, self.count);
+        let (offset, len) = self.slot(i);
+        if offset == self.content_start {
+            self.content_start += len;
+        } else {
+            self.fragmented += len;
+        }
+        let (at, end) = (self.slot_offset(i), self.directory_end());
+        self.page.copy_within(at + SLOT_SIZE..end, at);
+        self.count -= 1;
+        if self.count == 0 {
+            self.content_start = PAGE_SIZE;
+            self.fragmented = 0;
+        }
+    }
+
+    /// move the cells together at the end of the page, closing every hole
+    pub fn compact(&mut self) {
+        if self.fragmented == 0 {
+            return;
+        }
+        let cells: Vec<Vec<u8>> = self.cells().map(<[u8]>::to_vec).collect();
+        let mut offset = PAGE_SIZE;
+        for (i, cell) in cells.iter().enumerate() {
+            offset -= cell.len();
+            self.page[offset..offset + cell.len()].copy_from_slice(cell);
+            self.set_slot(i, offset, cell.len());
+        }
+        let end = self.directory_end();
+        self.page[end..offset].fill(0);
+        self.content_start = offset;
+        self.fragmented = 0;
+    }
+
+    /// the finished page, with its header and checksum filled in
+    pub fn seal(mut self, page_type: PageType) -> Page {
+        let at = PAGE_HEADER_SIZE;
+        self.page[at..at + 2].copy_from_slice(&(self.content_start as u16).to_le_bytes());
+        self.page[at + 2..at + 4].copy_from_slice(&(self.fragmented as u16).to_le_bytes());
+        page::seal(&mut self.page, page_type, self.count as u16);
+        self.page
+    }
+
+    fn directory_end(&self) -> usize {
+        self.slot_offset(self.count)
+    }
+
+    fn slot_offset(&self, i: usize) -> usize {
+        SLOTTED_HEADER_SIZE + self.header_len + i * SLOT_SIZE
+    }
+
+    fn slot(&self, i: usize) -> (usize, usize) {
+        let at = self.slot_offset(i);
+        let offset = get_u16(&self.page, at).unwrap() as usize;
+        let len = get_u16(&self.page, at + 2).unwrap() as usize;
+        (offset, len)
+    }
+
+    fn set_slot(&mut self, i: usize, offset: usize, len: usize) {
+        let at = self.slot_offset(i);
+        self.page[at..at + 2].copy_from_slice(&(offset as u16).to_le_bytes());
+        self.page[at + 2..at + 4].copy_from_slice(&(len as u16).to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(page: &SlottedPage) -> Vec<Vec<u8>> {
+        page.cells().map(<[u8]>::to_vec).collect()
+    }
+
+    #[test]
+    fn test_insert_remove_and_reopen() {
+        let mut page = SlottedPage::new(8);
+        page.header_mut().copy_from_slice(&7u64.to_le_bytes());
+        assert!(page.insert(0, b"bbb"));
+        assert!(page.insert(0, b"a"));
+        assert!(page.insert(2, b"cc"));
+        assert_eq!(
+            cells(&page),
+            vec![b"a".to_vec(), b"bbb".to_vec(), b"cc".to_vec()]
+        );
+        page.remove(1);
+        assert_eq!(cells(&page), vec![b"a".to_vec(), b"cc".to_vec()]);
+
+        let sealed = page.seal(PageType::BTreeLeaf);
+        let page = SlottedPage::open(&sealed, PageType::BTreeLeaf, 8).unwrap();
+        assert_eq!(page.header(), &7u64.to_le_bytes());
+        assert_eq!(cells(&page), vec![b"a".to_vec(), b"cc".to_vec()]);
+
+        let mut damaged = sealed;
+        // first slot's offset, then the checksum to match
+        damaged[SLOTTED_HEADER_SIZE + 8] = 0;
+        damaged[SLOTTED_HEADER_SIZE + 9] = 0;
+        page::seal(&mut damaged, PageType::BTreeLeaf, 2);
+        assert!(matches!(
+            SlottedPage::open(&damaged, PageType::BTreeLeaf, 8),
+            Err(PageError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_holes_are_compacted() {
+        let mut page = SlottedPage::new(0);
+        let cell = [1u8; 100];
+        let mut n = 0;
+        while page.insert(n, &cell) {
+            n += 1;
+        }
+        assert_eq!(n, (PAGE_SIZE - SLOTTED_HEADER_SIZE) / (100 + SLOT_SIZE));
+
+        // every other cell removed leaves holes too small for a larger cell,
+        // but together they have room
+        for i in (0..n / 2).rev() {
+            page.remove(2 * i);
+        }
+        assert!(page.insert(0, &[2u8; 300]));
+        assert_eq!(page.cell(0), &[2u8; 300]);
+        assert!(page.cells().skip(1).all(|cell| cell == [1u8; 100]));
+
+        let free = page.free_space();
+        let page =
+            SlottedPage::open(&page.seal(PageType::BTreeLeaf), PageType::BTreeLeaf, 0).unwrap();
+        assert_eq!(page.free_space(), free);
+    }
+}
@@ -0,0 +1,267 @@
+//! Z-order (Morton) encoding for two-dimensional keys
+//! - interleaves the bits of (x, y) so nearby points share key prefixes
+//! - encoded keys are big-endian, so byte order == z-order in the keyspace
+//! - decompose() turns a bounding box into a few contiguous key ranges
+
+use std::ops::RangeInclusive;
+
+/// length in bytes of an encoded z-order key
+pub const KEY_LEN: usize = 16;
+
+/// inclusive axis-aligned box over two u64 dimensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundingBox {
+    pub min_x: u64,
+
+    pub min_y: u64,
+
+    pub max_x: u64,
+
+    pub max_y: u64,
+}
+
+impl BoundingBox {
+    pub fn new(min_x: u64, min_y: u64, max_x: u64, max_y: u64) -> Self {
+        Self {
+            min_x: min_x.min(max_x),
+            min_y: min_y.min(max_y),
+            max_x: min_x.max(max_x),
+            max_y: min_y.max(max_y),
+        }
+    }
+
+    pub fn contains(&self, x: u64, y: u64) -> bool {
+        x >= self.min_x && x <= self.max_x && y >= self.min_y && y <= self.max_y
+    }
+
+    /// true if the whole box `other` lies inside self
+    fn covers(&self, other: &BoundingBox) -> bool {
+        self.contains(other.min_x, other.min_y) && self.contains(other.max_x, other.max_y)
+    }
+
+    fn intersects(&self, other: &BoundingBox) -> bool {
+        !(other.max_x < self.min_x
+            || other.min_x > self.max_x
+            || other.max_y < self.min_y
+            || other.min_y > self.max_y)
+    }
+}
+
+/// interleave x (even bits) and y (odd bits) into a z-value
+pub fn interleave(x: u64, y: u64) -> u128 {
+    spread(x) | (spread(y) << 1)
+}
+
+/// inverse of interleave
+pub fn deinterleave(z: u128) -> (u64, u64) {
+    (compact(z), compact(z >> 1))
+}
+
+pub fn encode_key(x: u64, y: u64) -> [u8; KEY_LEN] {
+    interleave(x, y).to_be_bytes()
+}
+
+/// returns None if the key is not a z-order key
+pub fn decode_key(key: &[u8]) -> Option<(u64, u64)> {
+    let bytes: [u8; KEY_LEN] = key.try_into().ok()?;
+    Some(deinterleave(u128::from_be_bytes(bytes)))
+}
+
+/// split a bounding box into at most `max_ranges` sorted, disjoint z-ranges
+///
+/// the ranges always cover every point in the box; when the budget is too
+/// small to describe the box exactly they also cover some points outside it,
+/// so scans should still check decoded keys with BoundingBox::contains
+pub fn decompose(bbox: &BoundingBox, max_ranges: usize) -> Vec<RangeInclusive<u128>> {
+    let max_ranges = max_ranges.max(1);
+    let mut ranges = Vec::new();
+
+    // quads at the current depth that straddle the box edge
+    let mut partial = vec![0u128];
+    let mut depth = 0u32;
+
+    while !partial.is_empty() {
+        // refining a level can turn every partial quad into up to four ranges
+        if depth == 64 || ranges.len() + partial.len() * 4 > max_ranges {
+            ranges.extend(partial.iter().map(|&prefix| quad_range(prefix, depth)));
+            break;
+        }
+
+        depth += 1;
+        let mut next = Vec::new();
+        for prefix in partial {
+            for child in 0..4u128 {
+                let child = (prefix << 2) | child;
+                let quad = quad_box(child, depth);
+
+                if bbox.covers(&quad) {
+                    ranges.push(quad_range(child, depth));
+                } else if bbox.intersects(&quad) {
+                    next.push(child);
+                }
+            }
+        }
+        partial = next;
+    }
+
+    ranges.sort_by_key(|r| *r.start());
+    merge_adjacent(ranges)
+}
+
+/// decompose() expressed as inclusive (start, end) key bounds
+pub fn key_ranges(bbox: &BoundingBox, max_ranges: usize) -> Vec<([u8; KEY_LEN], [u8; KEY_LEN])> {
+    decompose(bbox, max_ranges)
+        .into_iter()
+        .map(|r| (r.start().to_be_bytes(), r.end().to_be_bytes()))
+        .collect()
+}
+
+/// z-range of the quad identified by `prefix` at `depth` (2 bits per level)
+fn quad_range(prefix: u128, depth: u32) -> RangeInclusive<u128> {
+    let shift = 2 * (64 - depth);
+    if shift == 128 {
+        return 0..=u128::MAX;
+    }
+    let start = prefix << shift;
+    let end = start | ((1u128 << shift) - 1);
+    start..=end
+}
+
+fn quad_box(prefix: u128, depth: u32) -> BoundingBox {
+    let range = quad_range(prefix, depth);
+    let (min_x, min_y) = deinterleave(*range.start());
+    let (max_x, max_y) = deinterleave(*range.end());
+    BoundingBox {
+        min_x,
+        min_y,
+        max_x,
+        max_y,
+    }
+}
+
+fn merge_adjacent(ranges: Vec<RangeInclusive<u128>>) -> Vec<RangeInclusive<u128>> {
+    let mut merged: Vec<RangeInclusive<u128>> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        if let Some(last) = merged.last_mut()
+            && last.end().checked_add(1) == Some(*range.start())
+        {
+            *last = *last.start()..=*range.end();
+            continue;
+        }
+        merged.push(range);
+    }
+
+    merged
+}
+
+/// spread the 64 bits of v into the even bit positions of a u128
+fn spread(v: u64) -> u128 {
+    let mut x = v as u128;
+    x = (x | (x << 32)) & 0x0000_0000_FFFF_FFFF_0000_0000_FFFF_FFFF;
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF_0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF_00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333_3333_3333_3333_3333;
+    x = (x | (x << 1)) & 0x5555_5555_5555_5555_5555_5555_5555_5555;
+    x
+}
+
+/// gather the even bit positions of z back into a u64
+fn compact(z: u128) -> u64 {
+    let mut x = z & 0x5555_5555_5555_5555_5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333_3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x >> 4)) & 0x00FF_00FF_00FF_00FF_00FF_00FF_00FF_00FF;
+    x = (x | (x >> 8)) & 0x0000_FFFF_0000_FFFF_0000_FFFF_0000_FFFF;
+    x = (x | (x >> 16)) & 0x0000_0000_FFFF_FFFF_0000_0000_FFFF_FFFF;
+    x = (x | (x >> 32)) & 0xFFFF_FFFF_FFFF_FFFF;
+    x as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave_roundtrip() {
+        let points = [(0, 0), (1, 0), (0, 1), (12345, 67890), (u64::MAX, 0), (u64::MAX, u64::MAX)];
+
+        for &(x, y) in &points {
+            assert_eq!(deinterleave(interleave(x, y)), (x, y));
+            assert_eq!(decode_key(&encode_key(x, y)), Some((x, y)));
+        }
+
+        assert_eq!(interleave(1, 0), 0b01);
+        assert_eq!(interleave(0, 1), 0b10);
+        assert_eq!(interleave(3, 3), 0b1111);
+    }
+
+    #[test]
+    fn test_key_order_matches_z_order() {
+        let mut keys: Vec<_> = (0..8u64)
+            .flat_map(|x| (0..8u64).map(move |y| (x, y)))
+            .map(|(x, y)| (encode_key(x, y), interleave(x, y)))
+            .collect();
+
+        keys.sort_by_key(|a| a.0);
+        assert!(keys.windows(2).all(|w| w[0].1 < w[1].1));
+    }
+
+    #[test]
+    fn test_decode_key_rejects_wrong_length() {
+        assert_eq!(decode_key(b"short"), None);
+    }
+
+    #[test]
+    fn test_decompose_exact() {
+        let bbox = BoundingBox::new(2, 3, 9, 6);
+        let ranges = decompose(&bbox, 1000);
+
+        let mut covered = 0u128;
+        for range in &ranges {
+            for z in range.clone() {
+                let (x, y) = deinterleave(z);
+                assert!(bbox.contains(x, y), "({}, {}) outside box", x, y);
+            }
+            covered += range.end() - range.start() + 1;
+        }
+
+        assert_eq!(covered, 8 * 4);
+        assert!(ranges.windows(2).all(|w| w[0].end() < w[1].start()));
+    }
+
+    #[test]
+    fn test_decompose_respects_budget() {
+        let bbox = BoundingBox::new(3, 5, 1000, 777);
+
+        for max_ranges in [1, 4, 16] {
+            let ranges = decompose(&bbox, max_ranges);
+            assert!(!ranges.is_empty());
+            assert!(ranges.len() <= max_ranges);
+
+            // corners must still be covered
+            for (x, y) in [(3, 5), (1000, 777), (3, 777), (1000, 5)] {
+                let z = interleave(x, y);
+                assert!(ranges.iter().any(|r| r.contains(&z)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompose_full_space() {
+        let bbox = BoundingBox::new(0, 0, u64::MAX, u64::MAX);
+        assert_eq!(decompose(&bbox, 8), vec![0..=u128::MAX]);
+    }
+
+    #[test]
+    fn test_key_ranges() {
+        let bbox = BoundingBox::new(4, 4, 7, 7);
+        let ranges = key_ranges(&bbox, 8);
+
+        // an aligned 4x4 block is a single quad
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].0, encode_key(4, 4));
+        assert_eq!(ranges[0].1, encode_key(7, 7));
+    }
+}